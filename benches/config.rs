@@ -0,0 +1,27 @@
+//! Encoding and decoding the runtime configuration, which every new connection loads.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rustbucket::config::{Config, ConfigSource, FileSource, CONFIG_SIZE};
+use rustbucket::testing::TestDir;
+
+fn config(c: &mut Criterion) {
+    let mut group = c.benchmark_group("config");
+    let config = Config { verbosity: 2, max_connections: 500, timeout_seconds: 60, version: 7 };
+    let bytes: [u8; CONFIG_SIZE] = config.to_bytes();
+
+    group.bench_function("to_bytes", |b| b.iter(|| black_box(config).to_bytes()));
+    group.bench_function("from_bytes", |b| b.iter(|| Config::from_bytes(black_box(&bytes))));
+    group.bench_function("to_json", |b| b.iter(|| black_box(config).to_json().to_string()));
+
+    let dir = TestDir::new().unwrap();
+    let source = FileSource::open(dir.join("config.dat")).unwrap();
+    source.store(config).unwrap();
+    group.bench_function("file_source_load", |b| b.iter(|| source.load().unwrap()));
+    group.bench_function("file_source_store", |b| b.iter(|| source.store(black_box(config)).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, config);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+//! The echo hot path: one message in, one reply out, over loopback TCP.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rustbucket::testing::TestServer;
+
+fn echo(c: &mut Criterion) {
+    let mut group = c.benchmark_group("echo");
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+
+    group.bench_function("short_line", |b| b.iter(|| client.send(b"hello\n").unwrap()));
+    let line = format!("{}\n", "x".repeat(900));
+    group.bench_function("900_byte_line", |b| b.iter(|| client.send(line.as_bytes()).unwrap()));
+
+    // Every client opens its own connection, as with short-lived clients
+    group.bench_function("connect_and_send", |b| b.iter(|| server.client().unwrap().send(b"hello\n").unwrap()));
+
+    // The same with each worker accepting on a listener of its own
+    #[cfg(target_os = "linux")]
+    {
+        let server = TestServer::start_with(|builder| builder.reuse_port(true)).unwrap();
+        group.bench_function("connect_and_send_reuse_port", |b| {
+            b.iter(|| server.client().unwrap().send(b"hello\n").unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, echo);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+//! Formatting and appending lines to the server's log file, directly or through the log ring.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rustbucket::logging::{FileSink, LogSink, RingSink, DEFAULT_RING_CAPACITY};
+use rustbucket::testing::TestDir;
+
+fn logging(c: &mut Criterion) {
+    let mut group = c.benchmark_group("logging");
+    let dir = TestDir::new().unwrap();
+    let sink = FileSink::open(dir.join("http.log")).unwrap();
+    let line = "Connection from 127.0.0.1:52144 closed after 12 messages, 512 bytes received, 640 sent";
+    let batch: Vec<String> = (0..100).map(|i| format!("{} ({})", line, i)).collect();
+
+    group.bench_function("file_sink_write", |b| b.iter(|| sink.write(line)));
+    group.bench_function("file_sink_write_batch_100", |b| b.iter(|| sink.write_batch(&batch)));
+
+    let ring = RingSink::open(dir.join("ring.log"), dir.join("log.ring"), DEFAULT_RING_CAPACITY).unwrap();
+    group.bench_function("ring_sink_write", |b| b.iter(|| ring.write(line)));
+    group.bench_function("ring_sink_write_batch_100", |b| b.iter(|| ring.write_batch(&batch)));
+    group.finish();
+}
+
+criterion_group!(benches, logging);
+criterion_main!(benches);
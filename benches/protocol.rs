@@ -0,0 +1,52 @@
+//! Parsing and answering commands in the line protocol shared by KV and queue mode.
+//!
+//! Commands are sent in batches so that parsing, rather than the loopback
+//! round trip, dominates each iteration.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rustbucket::kv::{KvHandler, Store};
+use rustbucket::testing::{TestClient, TestServer};
+
+/// Commands in each batch
+const BATCH: usize = 50;
+
+fn protocol(c: &mut Criterion) {
+    let mut group = c.benchmark_group("protocol");
+    let store = Arc::new(Store::new());
+    let server = TestServer::start_with(|builder| builder.read_buffer_size(64 * 1024).handler(KvHandler::new(store)))
+        .unwrap();
+    let mut client = server.client().unwrap();
+
+    let words = "SET counter 12345\nGET counter\n".repeat(BATCH / 2);
+    let reply_len = "OK\n12345\n".len() * BATCH / 2;
+    group.bench_function("kv_bare_words", |b| b.iter(|| round_trip(&mut client, &words, reply_len)));
+    let quoted = "SET \"greeting key\" \"hello \\\"world\\\"\"\nGET \"greeting key\"\n".repeat(BATCH / 2);
+    let reply_len = "OK\nhello \"world\"\n".len() * BATCH / 2;
+    group.bench_function("kv_quoted", |b| b.iter(|| round_trip(&mut client, &quoted, reply_len)));
+    let long = format!("SET note \"{}\"\nDEL note\n", "a long quoted value ".repeat(50)).repeat(BATCH / 2);
+    let reply_len = "OK\n1\n".len() * BATCH / 2;
+    group.bench_function("kv_long_quoted", |b| b.iter(|| round_trip(&mut client, &long, reply_len)));
+    let prefixed = "SET blob $11\nline1\nline2\nGET blob\n".repeat(BATCH / 2);
+    let reply_len = "OK\n$11\nline1\nline2\n".len() * BATCH / 2;
+    group.bench_function("kv_length_prefixed", |b| b.iter(|| round_trip(&mut client, &prefixed, reply_len)));
+    group.finish();
+}
+
+/// Sends `commands` in one write and reads back `reply_len` bytes of replies
+fn round_trip(client: &mut TestClient, commands: &str, reply_len: usize) -> usize {
+    client.stream().write_all(commands.as_bytes()).unwrap();
+    let mut received = 0;
+    while received < reply_len {
+        let reply = client.read_reply().unwrap();
+        assert!(!reply.is_empty(), "server closed the connection");
+        received += reply.len();
+    }
+    assert_eq!(received, reply_len, "unexpected replies");
+    received
+}
+
+criterion_group!(benches, protocol);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+//! Captures build metadata (git commit, build time, compiler, target, features) for `version`
+//! and the admin interface.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn main() {
+    let commit = command_output("git", &["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let dirty = command_output("git", &["status", "--porcelain", "--untracked-files=no"]).is_some();
+    let commit = if dirty && commit != "unknown" { format!("{}-dirty", commit) } else { commit };
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    let build_epoch = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let target = std::env::var("TARGET").unwrap_or_else(|_| "unknown".to_string());
+    let profile = std::env::var("PROFILE").unwrap_or_else(|_| "unknown".to_string());
+    // Cargo exposes each enabled feature as CARGO_FEATURE_<NAME>, upper-cased with `-` as `_`
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+        .filter(|name| name != "default")
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=RUSTBUCKET_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=RUSTBUCKET_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=RUSTBUCKET_BUILD_EPOCH={}", build_epoch);
+    println!("cargo:rustc-env=RUSTBUCKET_TARGET={}", target);
+    println!("cargo:rustc-env=RUSTBUCKET_PROFILE={}", profile);
+    println!("cargo:rustc-env=RUSTBUCKET_FEATURES={}", features.join(","));
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=src");
+}
@@ -0,0 +1,74 @@
+/*
+ * C interface to the rustbucket server.
+ *
+ * Build the shared library with:
+ *
+ *     cargo rustc --release --lib --no-default-features --features cdylib --crate-type cdylib
+ *
+ * and link against target/release/librustbucket.so. Functions returning int
+ * return 0 on success, or the exit code the `rustbucket` CLI would use for
+ * the failure (78 invalid configuration, 79 unusable TLS certificate or key,
+ * 71 port unavailable, 75 already running, 70 internal error, ...);
+ * rustbucket_last_error() describes it.
+ *
+ * Structs start with their size and only ever grow at the end; always
+ * initialise them with rustbucket_config_init() or by setting `size`. Fields
+ * a newer library adds past the end of your rustbucket_config_t keep their
+ * defaults, and rustbucket_stats() writes only the fields that fit in your
+ * rustbucket_stats_t, setting `size` to how much it wrote.
+ */
+#ifndef RUSTBUCKET_H
+#define RUSTBUCKET_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct rustbucket_config_t {
+    uint32_t size;            /* sizeof(rustbucket_config_t) */
+    uint16_t port;            /* 0 picks a free port */
+    uint16_t admin_port;      /* loopback admin interface; 0 disables it */
+    uint32_t threads;         /* worker threads */
+    int handle_signals;       /* nonzero to handle SIGINT, SIGTERM, SIGHUP, SIGUSR2 */
+    const char *log_path;     /* NULL for http.log in the working directory */
+    const char *config_path;  /* NULL for config.dat in the working directory */
+    const char *pid_path;     /* NULL for rustbucket.pid in the working directory */
+} rustbucket_config_t;
+
+typedef struct rustbucket_stats_t {
+    uint32_t size;            /* sizeof(rustbucket_stats_t), set by the caller */
+    uint16_t port;            /* port the server accepts clients on */
+    uint64_t connections_accepted;
+    uint64_t connections_active;
+    uint64_t connections_closed;
+    uint64_t accept_errors;
+    uint64_t handler_errors;
+    uint64_t messages_received;
+    uint64_t bytes_received;
+    uint64_t bytes_sent;
+} rustbucket_stats_t;
+
+typedef struct rustbucket_server_t rustbucket_server_t;
+
+/* Fills `config` with the defaults of `rustbucket run`, signal handling off. */
+void rustbucket_config_init(rustbucket_config_t *config);
+
+/* Starts a server in the background; returns once it is listening. */
+int rustbucket_start(const rustbucket_config_t *config, rustbucket_server_t **server);
+
+/* Copies as many of the server's current counters as fit in `stats->size`. */
+int rustbucket_stats(const rustbucket_server_t *server, rustbucket_stats_t *stats);
+
+/* Shuts the server down (gracefully if `graceful` is nonzero), waits, and frees it. */
+int rustbucket_stop(rustbucket_server_t *server, int graceful);
+
+/* Describes the last failure on this thread, or NULL. Valid until the next failure. */
+const char *rustbucket_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUSTBUCKET_H */
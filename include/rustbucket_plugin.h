@@ -0,0 +1,52 @@
+/*
+ * Interface for rustbucket handler plugins.
+ *
+ * A plugin is a shared library exporting rustbucket_plugin_init(). Put it in
+ * the server's plugins directory and start the server with
+ *
+ *     rustbucket run --plugin <name>
+ *
+ * on_message() is called for every message a client sends, from several
+ * worker threads at once, so it must be thread-safe. It replies by calling
+ * write() with the `response` it was given, as many times as it likes; a
+ * message it writes nothing for gets no reply.
+ */
+#ifndef RUSTBUCKET_PLUGIN_H
+#define RUSTBUCKET_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RUSTBUCKET_PLUGIN_ABI_VERSION 1
+
+typedef struct rustbucket_message_t {
+    uint32_t size;            /* sizeof(rustbucket_message_t) as compiled by the server */
+    uint64_t connection_id;   /* unique for the server's lifetime */
+    const char *peer;         /* client address, e.g. "127.0.0.1:51234" */
+    const uint8_t *data;      /* the bytes the client sent; not NUL-terminated */
+    size_t len;
+} rustbucket_message_t;
+
+typedef void (*rustbucket_write_fn)(void *response, const uint8_t *data, size_t len);
+
+typedef struct rustbucket_plugin_t {
+    uint32_t size;            /* sizeof(rustbucket_plugin_t), set by the server */
+    uint32_t abi_version;     /* set to RUSTBUCKET_PLUGIN_ABI_VERSION */
+    const char *name;         /* selects the plugin; valid until destroy() */
+    void *state;              /* passed back to on_message() and destroy() */
+    void (*on_message)(void *state, const rustbucket_message_t *message, rustbucket_write_fn write, void *response);
+    void (*destroy)(void *state); /* may be NULL */
+} rustbucket_plugin_t;
+
+/* Fills in `plugin`; returns 0 on success, anything else refuses to load. */
+int rustbucket_plugin_init(rustbucket_plugin_t *plugin);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUSTBUCKET_PLUGIN_H */
@@ -0,0 +1,191 @@
+//! IP allow and deny lists, checked as connections are accepted.
+//!
+//! The rules live in a text file, `access.rules` by default, one per line:
+//! `allow` or `deny` followed by an address or a CIDR block such as
+//! `10.0.0.0/8` or `2001:db8::/32`. Blank lines and everything after a `#` are
+//! ignored. The first rule matching a client decides whether it may connect;
+//! a client matching none is let in unless the list has `allow` rules, in which
+//! case only the clients they cover are.
+//!
+//! The accept loop checks each connection before handing it to a worker, so a
+//! refused client never ties one up. The file is read again on reload, and
+//! each rule counts the connections it decided, carried over a reload for
+//! rules that did not change.
+//!
+//! ```text
+//! # Let the office network in, except for one noisy host
+//! deny 10.1.2.3
+//! allow 10.0.0.0/8
+//! allow 127.0.0.1
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::{Result, RustbucketError};
+
+/// What a rule does with the clients it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// A block of addresses: an address and how many of its leading bits must match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` falls in the block
+    ///
+    /// IPv4 clients reaching an IPv6 socket as mapped addresses match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("{} is not an IP address", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max),
+            None => Some(max),
+        };
+        let prefix = prefix.ok_or_else(|| format!("{} does not have a prefix length from 0 to {}", s, max))?;
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max = if self.addr.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+/// One line of an access list
+#[derive(Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub network: Cidr,
+    hits: AtomicU64,
+}
+
+impl Rule {
+    /// Connections this rule has allowed or refused
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        write!(f, "{} {}", action, self.network)
+    }
+}
+
+/// Ordered allow and deny rules for client addresses
+#[derive(Debug, Default)]
+pub struct AccessList {
+    rules: Vec<Rule>,
+}
+
+impl AccessList {
+    /// A list with no rules, which lets every client in
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses rules in the format of the access file
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid =
+                |reason: String| RustbucketError::InvalidConfig(format!("access rule {}: {}", number + 1, reason));
+            let (action, network) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["allow", network] => (Action::Allow, network),
+                ["deny", network] => (Action::Deny, network),
+                _ => return Err(invalid(format!("expected `allow <address>` or `deny <address>`, got `{}`", line))),
+            };
+            let network = network.parse().map_err(invalid)?;
+            rules.push(Rule { action, network, hits: AtomicU64::new(0) });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Reads the access file at `path`; a missing file is an empty list
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(RustbucketError::ConfigFile { path: path.to_path_buf(), source: e }),
+        }
+    }
+
+    /// Takes over the hit counts of rules in `previous` that this list repeats
+    pub(crate) fn carry_hits(&self, previous: &AccessList) {
+        for rule in &self.rules {
+            let same = |old: &&Rule| old.action == rule.action && old.network == rule.network;
+            if let Some(old) = previous.rules.iter().find(same) {
+                rule.hits.store(old.hits(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether `ip` may connect, counting a hit on the rule that decided
+    pub fn check(&self, ip: IpAddr) -> bool {
+        match self.rules.iter().find(|rule| rule.network.contains(ip)) {
+            Some(rule) => {
+                rule.hits.fetch_add(1, Ordering::Relaxed);
+                rule.action == Action::Allow
+            }
+            None => !self.rules.iter().any(|rule| rule.action == Action::Allow),
+        }
+    }
+
+    /// The rules, in the order they are checked
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
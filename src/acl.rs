@@ -0,0 +1,189 @@
+//! Which protocol commands each client may run, by identity or network.
+//!
+//! The rules live in a text file, `commands.acl` by default, one per line:
+//! `allow` or `deny`, the commands it covers (a comma-separated list such as
+//! `GET,TTL`, or `*` for all), and optionally who it applies to: `from` a
+//! network in the CIDR notation of [`crate::access`], `user` an identity a
+//! client authenticated as, or both. Blank lines and everything after a `#`
+//! are ignored.
+//!
+//! A command is the first word of each command line in a message (see the
+//! KV protocol for how lines and values are told apart), compared without
+//! regard to case. The first rule matching the command and the client decides
+//! whether it may run; a command matching no rule is allowed. A message is
+//! only passed to the handler if every command in it may run; otherwise it is
+//! answered with `ERR <COMMAND> is not permitted`, naming the first refused.
+//! The check sits after the configured middleware, so `AUTH` and rate limits
+//! are handled before it. The file is read again on reload.
+//!
+//! The check relies on messages holding whole command lines, as the built-in
+//! handlers frame them; see [`RequestHandler::frame`](crate::RequestHandler::frame)
+//! for what a handler of its own must do.
+//!
+//! ```text
+//! # Monitoring hosts may only read
+//! allow GET,TTL,LRANGE,SCAN from 10.9.0.0/16
+//! deny * from 10.9.0.0/16
+//! # Only the admin token may delete
+//! allow DEL user admin
+//! deny DEL
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::access::{Action, Cidr};
+use crate::error::{Result, RustbucketError};
+
+/// One line of a command ACL
+#[derive(Debug)]
+pub struct CommandRule {
+    pub action: Action,
+    /// Upper-case command names, or `None` for every command
+    pub commands: Option<Vec<String>>,
+    /// Network the client must connect from, if limited
+    pub network: Option<Cidr>,
+    /// Identity the client must have authenticated as, if limited
+    pub user: Option<String>,
+    hits: AtomicU64,
+}
+
+impl CommandRule {
+    /// Messages this rule has allowed or refused
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn matches(&self, command: &str, ip: Option<IpAddr>, identity: Option<&str>) -> bool {
+        let command = match &self.commands {
+            Some(commands) => commands.iter().any(|name| name.eq_ignore_ascii_case(command)),
+            None => true,
+        };
+        let network = match self.network {
+            Some(network) => ip.is_some_and(|ip| network.contains(ip)),
+            None => true,
+        };
+        let user = match &self.user {
+            Some(user) => identity == Some(user.as_str()),
+            None => true,
+        };
+        command && network && user
+    }
+}
+
+impl fmt::Display for CommandRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        match &self.commands {
+            Some(commands) => write!(f, "{} {}", action, commands.join(","))?,
+            None => write!(f, "{} *", action)?,
+        }
+        if let Some(network) = self.network {
+            write!(f, " from {}", network)?;
+        }
+        if let Some(user) = &self.user {
+            write!(f, " user {}", user)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ordered allow and deny rules for protocol commands
+#[derive(Debug, Default)]
+pub struct CommandAcl {
+    rules: Vec<CommandRule>,
+}
+
+impl CommandAcl {
+    /// An ACL with no rules, which allows every command
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses rules in the format of the ACL file
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid =
+                |reason: String| RustbucketError::InvalidConfig(format!("command rule {}: {}", number + 1, reason));
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (action, commands, conditions) = match words[..] {
+                ["allow", commands, ref conditions @ ..] => (Action::Allow, commands, conditions),
+                ["deny", commands, ref conditions @ ..] => (Action::Deny, commands, conditions),
+                _ => return Err(invalid(format!("expected `allow <commands>` or `deny <commands>`, got `{}`", line))),
+            };
+            let commands = match commands {
+                "*" => None,
+                commands => Some(commands.split(',').filter(|name| !name.is_empty()).map(str::to_uppercase).collect()),
+            };
+            let mut rule = CommandRule { action, commands, network: None, user: None, hits: AtomicU64::new(0) };
+            for condition in conditions.chunks(2) {
+                match *condition {
+                    ["from", network] if rule.network.is_none() => {
+                        rule.network = Some(network.parse().map_err(invalid)?)
+                    }
+                    ["user", user] if rule.user.is_none() => rule.user = Some(user.to_string()),
+                    _ => {
+                        let condition = condition.join(" ");
+                        return Err(invalid(format!("expected `from <network>` or `user <name>`, got `{}`", condition)));
+                    }
+                }
+            }
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Reads the ACL file at `path`; a missing file is an empty ACL
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(RustbucketError::ConfigFile { path: path.to_path_buf(), source: e }),
+        }
+    }
+
+    /// Takes over the hit counts of rules in `previous` that this ACL repeats
+    pub(crate) fn carry_hits(&self, previous: &CommandAcl) {
+        for rule in &self.rules {
+            let same = |old: &&CommandRule| old.to_string() == rule.to_string();
+            if let Some(old) = previous.rules.iter().find(same) {
+                rule.hits.store(old.hits(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether a client at `ip`, authenticated as `identity`, may run `command`
+    ///
+    /// Counts a hit on the rule that decided.
+    pub fn check(&self, command: &str, ip: Option<IpAddr>, identity: Option<&str>) -> bool {
+        match self.rules.iter().find(|rule| rule.matches(command, ip, identity)) {
+            Some(rule) => {
+                rule.hits.fetch_add(1, Ordering::Relaxed);
+                rule.action == Action::Allow
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the ACL has any rules, and so needs checking at all
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rules, in the order they are checked
+    pub fn rules(&self) -> &[CommandRule] {
+        &self.rules
+    }
+}
@@ -0,0 +1,575 @@
+//! Loopback-only admin HTTP endpoint for operational introspection.
+//!
+//! Runs on its own port so metrics, health checks, and connection listings never
+//! share a listener with client traffic. Each request is served sequentially on a
+//! single background thread; the endpoints are cheap and meant for operators.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+
+use crate::events::ServerEvent;
+use crate::hooks::ShutdownPhase;
+use crate::profiling::{self, ProfileError, DEFAULT_PROFILE_SECONDS, MAX_PROFILE_SECONDS};
+use crate::build_info;
+use crate::config::update_source;
+use crate::error::RustbucketError;
+use crate::memory::{heap_stats, process_memory, Pool};
+use crate::server::{self, ServerState};
+
+/// How long a single admin client may take to send its request
+const ADMIN_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A response produced by an admin endpoint
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn text(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; version=0.0.4",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn binary(status: u16, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Binds the admin listener on loopback and serves it on a background thread
+pub fn start_admin_server(port: u16, server_state: Arc<ServerState>) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    println!("Admin interface listening on 127.0.0.1:{}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_admin_request(stream, &server_state) {
+                        log::error!("Error handling admin request: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to accept admin connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_admin_request(stream: TcpStream, server_state: &ServerState) -> io::Result<()> {
+    stream.set_read_timeout(Some(ADMIN_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    // Skip the headers; none of the endpoints need them
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut stream = reader.into_inner();
+
+    // The event stream and profiles are long-lived, so they get their own threads
+    let (route_path, query) = path.split_once('?').unwrap_or((path, ""));
+    if method == "GET" && route_path == "/events" {
+        let events = server_state.events.subscribe();
+        thread::spawn(move || {
+            let _ = stream_events(stream, events);
+        });
+        return Ok(());
+    }
+    if method == "GET" && route_path == "/debug/pprof/profile" {
+        let query = query.to_string();
+        thread::spawn(move || {
+            let _ = cpu_profile(&query).write_to(&mut stream);
+        });
+        return Ok(());
+    }
+
+    let response = match (method, route_path) {
+        ("POST", "/reload") => reload(server_state),
+        ("POST", "/config") => set_config(query, server_state),
+        ("POST", "/drain") => drain(server_state),
+        ("POST", _) if route_path.starts_with("/connections/") => close_connection(route_path, server_state),
+        ("POST", _) if route_path.starts_with("/bans/") => lift_ban(route_path, server_state),
+        (_, "/reload" | "/drain") => Response::json(405, json!({ "error": "method not allowed" })),
+        ("GET", _) => route(path, server_state),
+        _ => Response::json(405, json!({ "error": "method not allowed" })),
+    };
+    response.write_to(&mut stream)
+}
+
+/// Collects a CPU profile lasting `?seconds=N` (default 30) in pprof format
+fn cpu_profile(query: &str) -> Response {
+    let seconds = match query_param(query, "seconds").map(str::parse::<u64>) {
+        None => DEFAULT_PROFILE_SECONDS,
+        Some(Ok(seconds)) if (1..=MAX_PROFILE_SECONDS).contains(&seconds) => seconds,
+        Some(_) => {
+            let message = format!("seconds must be between 1 and {}", MAX_PROFILE_SECONDS);
+            return Response::json(400, json!({ "error": message }));
+        }
+    };
+
+    match profiling::cpu_profile(Duration::from_secs(seconds)) {
+        Ok(profile) => Response::binary(200, profile),
+        Err(e) => {
+            let status = match e {
+                ProfileError::Unsupported => 501,
+                ProfileError::Busy => 409,
+                ProfileError::Failed(_) => 500,
+            };
+            Response::json(status, json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Streams events as newline-delimited JSON until the client goes away or shutdown completes
+fn stream_events(mut stream: TcpStream, events: Receiver<ServerEvent>) -> io::Result<()> {
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n")?;
+    stream.flush()?;
+
+    for event in events {
+        writeln!(stream, "{}", event.to_json())?;
+        stream.flush()?;
+        if event == (ServerEvent::ShuttingDown { phase: ShutdownPhase::Complete }) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn route(path: &str, server_state: &ServerState) -> Response {
+    // Query strings are accepted but ignored
+    let path = path.split('?').next().unwrap_or(path);
+    match path {
+        "/health" => health(server_state),
+        "/metrics" => metrics(server_state),
+        "/stats" => stats(server_state),
+        "/config" => config(server_state),
+        "/connections" => connections(server_state),
+        "/access" => access(server_state),
+        "/acl" => acl(server_state),
+        "/bans" => bans(server_state),
+        "/version" => version(server_state),
+        "/status" => status(server_state),
+        _ => Response::json(404, json!({ "error": "not found" })),
+    }
+}
+
+fn health(server_state: &ServerState) -> Response {
+    if server_state.shutdown_requested.load(Ordering::SeqCst) {
+        Response::json(503, json!({ "status": "shutting_down" }))
+    } else {
+        Response::json(200, json!({ "status": "ok" }))
+    }
+}
+
+/// Renders the counters in the Prometheus text exposition format
+fn metrics(server_state: &ServerState) -> Response {
+    let snapshot = server_state.metrics.snapshot();
+    let pool = server_state.pool.stats();
+    let memory = server_state.memory.usage();
+    let buffers = server_state.buffers.stats();
+    let process = process_memory();
+    let mut metrics = vec![
+        ("rustbucket_connections_accepted_total", "counter", "Connections accepted since startup", snapshot.connections_accepted),
+        ("rustbucket_connections_active", "gauge", "Connections currently open", snapshot.connections_active),
+        ("rustbucket_connections_closed_total", "counter", "Connections closed since startup", snapshot.connections_closed),
+        ("rustbucket_connections_denied_total", "counter", "Connections refused by the access list", snapshot.connections_denied),
+        ("rustbucket_connections_over_ip_cap_total", "counter", "Connections refused by the per-IP cap", snapshot.connections_over_ip_cap),
+        ("rustbucket_connections_banned_total", "counter", "Connections refused from banned addresses", snapshot.connections_banned),
+        ("rustbucket_connections_over_queue_total", "counter", "Connections refused for want of a worker while defending against a flood", snapshot.connections_over_queue),
+        ("rustbucket_floods_total", "counter", "Times the server went into defensive mode for a connection flood", snapshot.floods),
+        ("rustbucket_defensive_mode", "gauge", "1 while the server is defending against a connection flood", u64::from(snapshot.defensive_mode)),
+        ("rustbucket_accept_errors_total", "counter", "Failed accept calls", snapshot.accept_errors),
+        ("rustbucket_accept_fd_exhaustion_total", "counter", "Accepts failed for lack of file descriptors", snapshot.fd_exhaustion_errors),
+        ("rustbucket_handler_errors_total", "counter", "Connections that ended with an error", snapshot.handler_errors),
+        ("rustbucket_messages_received_total", "counter", "Messages read from clients", snapshot.messages_received),
+        ("rustbucket_bytes_received_total", "counter", "Bytes read from clients", snapshot.bytes_received),
+        ("rustbucket_bytes_sent_total", "counter", "Bytes written to clients", snapshot.bytes_sent),
+        ("rustbucket_requests_throttled_total", "counter", "Messages refused by a rate limit", snapshot.requests_throttled),
+        ("rustbucket_commands_denied_total", "counter", "Messages refused by the command ACL", snapshot.commands_denied),
+        ("rustbucket_auth_failures_total", "counter", "AUTH attempts with an unknown token", snapshot.auth_failures),
+        ("rustbucket_auth_lockouts_total", "counter", "Client addresses locked out of AUTH", snapshot.auth_lockouts),
+        ("rustbucket_slow_requests_closed_total", "counter", "Connections closed for slow requests", snapshot.slow_requests_closed),
+        ("rustbucket_connections_reading", "gauge", "Connections partway through a request", server_state.connections_reading.load(Ordering::Relaxed) as u64),
+        ("rustbucket_memory_used_bytes", "gauge", "Estimated bytes held for clients", memory.total() as u64),
+        ("rustbucket_memory_limit_bytes", "gauge", "Memory budget, or 0 if unlimited", memory.limit.unwrap_or_default() as u64),
+        ("rustbucket_memory_rejected_writes_total", "counter", "Writes refused for lack of memory budget", memory.rejected),
+        ("rustbucket_memory_shed_connections_total", "counter", "Idle connections closed while over the memory budget", memory.shed),
+        ("rustbucket_heartbeats_total", "counter", "Heartbeats emitted", snapshot.heartbeats),
+        ("rustbucket_last_heartbeat_timestamp_seconds", "gauge", "Unix time of the last heartbeat", snapshot.last_heartbeat),
+        ("rustbucket_pool_workers", "gauge", "Worker threads running", pool.workers as u64),
+        ("rustbucket_pool_min_workers", "gauge", "Fewest worker threads kept while idle", pool.min_workers as u64),
+        ("rustbucket_pool_max_workers", "gauge", "Most worker threads the pool grows to", pool.max_workers as u64),
+        ("rustbucket_pool_active_workers", "gauge", "Workers currently running a job", pool.active as u64),
+        ("rustbucket_pool_queued_jobs", "gauge", "Jobs waiting for a free worker", pool.queued as u64),
+        ("rustbucket_pool_executed_jobs_total", "counter", "Jobs run to completion", pool.executed),
+        ("rustbucket_pool_panicked_jobs_total", "counter", "Jobs that panicked", pool.panicked as u64),
+        ("rustbucket_buffer_pool_idle", "gauge", "I/O buffers waiting to be reused", buffers.idle as u64),
+        ("rustbucket_buffers_allocated_total", "counter", "I/O buffers allocated because none were idle", buffers.allocated),
+        ("rustbucket_buffers_reused_total", "counter", "I/O buffers taken from the pool instead of allocated", buffers.reused),
+    ];
+    // Left out on platforms that do not report them
+    let process = [
+        ("rustbucket_process_resident_memory_bytes", "Bytes of the process in physical memory", process.resident),
+        ("rustbucket_process_peak_resident_memory_bytes", "Most bytes of the process ever in physical memory", process.peak_resident),
+        ("rustbucket_process_virtual_memory_bytes", "Bytes of address space the process has mapped", process.virtual_size),
+    ];
+    for (name, help, value) in process {
+        if let Some(value) = value {
+            metrics.push((name, "gauge", help, value));
+        }
+    }
+    if let Some(heap) = heap_stats() {
+        metrics.extend([
+            ("rustbucket_heap_allocated_bytes", "gauge", "Heap bytes allocated and not yet freed", heap.allocated as u64),
+            ("rustbucket_heap_peak_allocated_bytes", "gauge", "Most heap bytes allocated at once", heap.peak as u64),
+            ("rustbucket_heap_allocations_total", "counter", "Heap allocations made", heap.allocations),
+            ("rustbucket_heap_frees_total", "counter", "Heap allocations freed", heap.frees),
+        ]);
+    }
+
+    let mut body = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+        let _ = writeln!(body, "{} {}", name, value);
+    }
+    let _ = writeln!(body, "# HELP rustbucket_memory_bytes Estimated bytes held for clients, by what holds them");
+    let _ = writeln!(body, "# TYPE rustbucket_memory_bytes gauge");
+    for (pool, bytes) in [(Pool::Buffers, memory.buffers), (Pool::Queues, memory.queues), (Pool::Kv, memory.kv)] {
+        let _ = writeln!(body, "rustbucket_memory_bytes{{pool=\"{}\"}} {}", pool.name(), bytes);
+    }
+    let access = server_state.access.read().unwrap();
+    if !access.rules().is_empty() {
+        let _ = writeln!(body, "# HELP rustbucket_access_rule_hits_total Connections decided by each access rule");
+        let _ = writeln!(body, "# TYPE rustbucket_access_rule_hits_total counter");
+        for rule in access.rules() {
+            let _ = writeln!(body, "rustbucket_access_rule_hits_total{{rule=\"{}\"}} {}", rule, rule.hits());
+        }
+    }
+    Response::text(200, body)
+}
+
+/// Same counters as `/metrics`, as JSON for tooling
+fn stats(server_state: &ServerState) -> Response {
+    let snapshot = server_state.metrics.snapshot();
+    let pool = server_state.pool.stats();
+    let memory = server_state.memory.usage();
+    let buffers = server_state.buffers.stats();
+    let process = process_memory();
+    let heap = heap_stats().map(|heap| {
+        json!({
+            "allocator": heap.allocator,
+            "allocated": heap.allocated,
+            "peak": heap.peak,
+            "allocations": heap.allocations,
+            "frees": heap.frees,
+            "live": heap.live(),
+        })
+    });
+    Response::json(200, json!({
+        "connections_accepted": snapshot.connections_accepted,
+        "connections_active": snapshot.connections_active,
+        "connections_closed": snapshot.connections_closed,
+        "connections_denied": snapshot.connections_denied,
+        "connections_over_ip_cap": snapshot.connections_over_ip_cap,
+        "connections_banned": snapshot.connections_banned,
+        "connections_over_queue": snapshot.connections_over_queue,
+        "floods": snapshot.floods,
+        "defensive_mode": snapshot.defensive_mode,
+        "bans_active": server_state.bans.list().len(),
+        "accept_errors": snapshot.accept_errors,
+        "fd_exhaustion_errors": snapshot.fd_exhaustion_errors,
+        "handler_errors": snapshot.handler_errors,
+        "messages_received": snapshot.messages_received,
+        "bytes_received": snapshot.bytes_received,
+        "bytes_sent": snapshot.bytes_sent,
+        "requests_throttled": snapshot.requests_throttled,
+        "commands_denied": snapshot.commands_denied,
+        "auth_failures": snapshot.auth_failures,
+        "auth_lockouts": snapshot.auth_lockouts,
+        "slow_requests_closed": snapshot.slow_requests_closed,
+        "connections_reading": server_state.connections_reading.load(Ordering::Relaxed),
+        "events_dropped": server_state.events.dropped(),
+        "heartbeats": snapshot.heartbeats,
+        "last_heartbeat": snapshot.last_heartbeat,
+        "memory": {
+            "limit": memory.limit,
+            "used": memory.total(),
+            "buffers": memory.buffers,
+            "queues": memory.queues,
+            "kv": memory.kv,
+            "rejected": memory.rejected,
+            "shed": memory.shed,
+        },
+        "process": {
+            "resident": process.resident,
+            "peak_resident": process.peak_resident,
+            "virtual": process.virtual_size,
+        },
+        "heap": heap,
+        "pool": {
+            "workers": pool.workers,
+            "min_workers": pool.min_workers,
+            "max_workers": pool.max_workers,
+            "active": pool.active,
+            "queued": pool.queued,
+            "executed": pool.executed,
+            "panicked": pool.panicked,
+        },
+        "buffers": {
+            "size": buffers.buffer_size,
+            "capacity": buffers.capacity,
+            "idle": buffers.idle,
+            "allocated": buffers.allocated,
+            "reused": buffers.reused,
+        },
+    }))
+}
+
+/// Build metadata plus when this process started
+fn version(server_state: &ServerState) -> Response {
+    let mut info = build_info::to_json();
+    let start_time: DateTime<Utc> = server_state.started_at.into();
+    info["start_time"] = json!(start_time.to_rfc3339_opts(SecondsFormat::Secs, true));
+    info["uptime_seconds"] = json!(server_state.started.elapsed().as_secs());
+    Response::json(200, info)
+}
+
+/// Re-reads the configuration and reopens log files, reporting the outcome
+fn reload(server_state: &ServerState) -> Response {
+    match server::reload(server_state) {
+        Ok(config) => Response::json(200, json!({ "status": "reloaded", "config": config.to_json() })),
+        Err(e) => Response::json(500, json!({ "status": "failed", "error": e.to_string() })),
+    }
+}
+
+/// Identifies the running process for `status`
+fn status(server_state: &ServerState) -> Response {
+    Response::json(200, json!({
+        "pid": std::process::id(),
+        "listen_address": server_state.listen_addr.get().map(|addr| addr.to_string()),
+        "uptime_seconds": server_state.started.elapsed().as_secs(),
+        "config_version": server_state.config_version.load(Ordering::SeqCst),
+        "shutting_down": server_state.shutdown_requested.load(Ordering::SeqCst),
+    }))
+}
+
+/// Updates configuration fields given as `?verbosity=&max_connections=&timeout_seconds=`
+fn set_config(query: &str, server_state: &ServerState) -> Response {
+    let mut fields = [None; 3];
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let index = match key {
+            "verbosity" => 0,
+            "max_connections" => 1,
+            "timeout_seconds" => 2,
+            _ => return Response::json(400, json!({ "error": format!("unknown config field: {}", key) })),
+        };
+        match value.parse::<u32>() {
+            Ok(value) => fields[index] = Some(value),
+            Err(_) => return Response::json(400, json!({ "error": format!("invalid value for {}: {}", key, value) })),
+        }
+    }
+    if fields.iter().all(Option::is_none) {
+        return Response::json(400, json!({ "error": "no config fields given" }));
+    }
+
+    match update_source(server_state.config_source.as_ref(), fields[0], fields[1], fields[2]) {
+        Ok(config) => {
+            server::note_config_version(server_state, config.version);
+            Response::json(200, config.to_json())
+        }
+        // e.g. a read-only source
+        Err(e @ RustbucketError::InvalidConfig(_)) => Response::json(409, json!({ "error": e.to_string() })),
+        Err(e) => Response::json(500, json!({ "error": e.to_string() })),
+    }
+}
+
+/// Starts a graceful shutdown, as if the server had received SIGTERM
+fn drain(server_state: &ServerState) -> Response {
+    server::request_shutdown(server_state, "Drain request");
+    Response::json(200, json!({ "status": "draining" }))
+}
+
+/// Handles `POST /connections/<id>/close`
+fn close_connection(path: &str, server_state: &ServerState) -> Response {
+    let id = path
+        .strip_prefix("/connections/")
+        .and_then(|rest| rest.strip_suffix("/close"))
+        .and_then(|id| id.parse::<u64>().ok());
+    let Some(id) = id else {
+        return Response::json(404, json!({ "error": "not found" }));
+    };
+
+    if server_state.connections.close(id) {
+        server_state.log.write(&format!("Connection #{} closed by admin request", id));
+        Response::json(200, json!({ "status": "closed", "id": id }))
+    } else {
+        Response::json(404, json!({ "error": format!("no open connection with id {}", id) }))
+    }
+}
+
+/// Addresses banned for repeated offences, soonest to lift first
+fn bans(server_state: &ServerState) -> Response {
+    let bans: Vec<_> = server_state
+        .bans
+        .list()
+        .iter()
+        .map(|ban| {
+            json!({
+                "ip": ban.ip.to_string(),
+                "reason": ban.reason.name(),
+                "since": ban.since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                "remaining_seconds": ban.remaining.as_secs(),
+            })
+        })
+        .collect();
+    let policy = server_state.bans.policy().map(|policy| {
+        json!({
+            "offences": policy.offences,
+            "window_seconds": policy.window.as_secs(),
+            "duration_seconds": policy.duration.as_secs(),
+        })
+    });
+    Response::json(200, json!({ "enabled": policy.is_some(), "policy": policy, "bans": bans }))
+}
+
+/// Lifts the ban on the address in `/bans/<ip>/lift`
+fn lift_ban(path: &str, server_state: &ServerState) -> Response {
+    let ip = path
+        .strip_prefix("/bans/")
+        .and_then(|rest| rest.strip_suffix("/lift"))
+        .and_then(|ip| ip.parse::<IpAddr>().ok());
+    let Some(ip) = ip else {
+        return Response::json(404, json!({ "error": "not found" }));
+    };
+
+    if server_state.bans.lift(ip) {
+        server_state.log.write(&format!("Ban on {} lifted by admin request", ip));
+        Response::json(200, json!({ "status": "lifted", "ip": ip.to_string() }))
+    } else {
+        Response::json(404, json!({ "error": format!("{} is not banned", ip) }))
+    }
+}
+
+fn config(server_state: &ServerState) -> Response {
+    match server_state.config_source.load() {
+        Ok(config) => Response::json(200, config.to_json()),
+        Err(e) => Response::json(500, json!({ "error": e.to_string() })),
+    }
+}
+
+fn connections(server_state: &ServerState) -> Response {
+    let connections: Vec<_> = server_state
+        .connections
+        .list()
+        .iter()
+        .map(|conn| {
+            json!({
+                "id": conn.id,
+                "peer": conn.peer,
+                "identity": conn.identity(),
+                "connected_at": conn.connected_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                "age_seconds": conn.age().as_secs_f64(),
+                "messages": conn.messages.load(Ordering::Relaxed),
+                "bytes_received": conn.bytes_received.load(Ordering::Relaxed),
+                "bytes_sent": conn.bytes_sent.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    Response::json(200, json!({ "connections": connections }))
+}
+
+/// The access list's rules in order, with how many connections each decided
+fn access(server_state: &ServerState) -> Response {
+    let access = server_state.access.read().unwrap();
+    let rules: Vec<_> =
+        access.rules().iter().map(|rule| json!({ "rule": rule.to_string(), "hits": rule.hits() })).collect();
+    Response::json(200, json!({
+        "file": server_state.paths.access_file.display().to_string(),
+        "rules": rules,
+        "denied": server_state.metrics.connections_denied.load(Ordering::Relaxed),
+    }))
+}
+
+/// The command ACL's rules in order, with how many messages each decided
+fn acl(server_state: &ServerState) -> Response {
+    let acl = server_state.commands.read().unwrap();
+    let rules: Vec<_> =
+        acl.rules().iter().map(|rule| json!({ "rule": rule.to_string(), "hits": rule.hits() })).collect();
+    Response::json(200, json!({
+        "file": server_state.paths.acl_file.display().to_string(),
+        "rules": rules,
+        "denied": server_state.metrics.commands_denied.load(Ordering::Relaxed),
+    }))
+}
@@ -0,0 +1,162 @@
+//! Threshold-based alerting delivered to an HTTP webhook.
+//!
+//! A watcher thread samples the server's counters, evaluates each rule over a
+//! sliding window, and posts a JSON payload when a rule starts firing and again
+//! when it resolves. Alerts are edge-triggered so a sustained problem produces
+//! one notification rather than one per sample. Besides the thresholds below,
+//! `connection_flood` fires while the server is defending against a connection
+//! flood (see [`crate::flood`]).
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde_json::json;
+
+use crate::error::Result;
+use crate::http_client::{self, HttpUrl};
+use crate::telemetry::{Metrics, MetricsSnapshot};
+
+/// Timeout for each webhook delivery
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of samples taken per window
+const SAMPLES_PER_WINDOW: u32 = 12;
+
+/// Settings for threshold alerts
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// Webhook receiving JSON alert payloads
+    pub webhook: String,
+    /// Window over which rates and counts are evaluated
+    pub window: Duration,
+    /// Alert when more than this fraction of closed connections ended in a handler error
+    pub error_rate: f64,
+    /// Alert when more than this many accepts fail within the window
+    pub accept_errors: u64,
+    /// Alert when more than this many accepts fail for lack of file descriptors
+    pub fd_exhaustion: u64,
+}
+
+/// The conditions alerts can be raised for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    HandlerErrorRate,
+    AcceptErrors,
+    FdExhaustion,
+    ConnectionFlood,
+}
+
+impl Rule {
+    const ALL: [Rule; 4] = [Rule::HandlerErrorRate, Rule::AcceptErrors, Rule::FdExhaustion, Rule::ConnectionFlood];
+
+    fn name(self) -> &'static str {
+        match self {
+            Rule::HandlerErrorRate => "handler_error_rate",
+            Rule::AcceptErrors => "accept_errors",
+            Rule::FdExhaustion => "fd_exhaustion",
+            Rule::ConnectionFlood => "connection_flood",
+        }
+    }
+
+    fn threshold(self, config: &AlertConfig) -> f64 {
+        match self {
+            Rule::HandlerErrorRate => config.error_rate,
+            Rule::AcceptErrors => config.accept_errors as f64,
+            Rule::FdExhaustion => config.fd_exhaustion as f64,
+            // Fires whenever the server is in defensive mode
+            Rule::ConnectionFlood => 0.0,
+        }
+    }
+
+    /// Measures the rule over the change between two snapshots
+    fn measure(self, oldest: &MetricsSnapshot, newest: &MetricsSnapshot) -> f64 {
+        match self {
+            Rule::HandlerErrorRate => {
+                let closed = newest.connections_closed.saturating_sub(oldest.connections_closed);
+                let errors = newest.handler_errors.saturating_sub(oldest.handler_errors);
+                if closed == 0 {
+                    0.0
+                } else {
+                    errors as f64 / closed as f64
+                }
+            }
+            Rule::AcceptErrors => newest.accept_errors.saturating_sub(oldest.accept_errors) as f64,
+            Rule::FdExhaustion => newest.fd_exhaustion_errors.saturating_sub(oldest.fd_exhaustion_errors) as f64,
+            Rule::ConnectionFlood => f64::from(u8::from(newest.defensive_mode)),
+        }
+    }
+}
+
+/// Background thread evaluating alert rules
+pub struct AlertWatcher {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl AlertWatcher {
+    /// Validates the webhook URL and starts watching `metrics`
+    pub fn start(config: AlertConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let webhook = HttpUrl::parse(&config.webhook)?;
+        let sample_every = (config.window / SAMPLES_PER_WINDOW).max(Duration::from_millis(100));
+
+        println!("Sending threshold alerts to {}", config.webhook);
+
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut samples: VecDeque<(Instant, MetricsSnapshot)> = VecDeque::new();
+            samples.push_back((Instant::now(), metrics.snapshot()));
+            let mut firing = [false; Rule::ALL.len()];
+
+            // Anything other than a timeout means the watcher is being stopped
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(sample_every) {
+                let now = Instant::now();
+                samples.push_back((now, metrics.snapshot()));
+                // Keep exactly one sample at or before the start of the window
+                while samples.len() > 2 && now.duration_since(samples[1].0) >= config.window {
+                    samples.pop_front();
+                }
+                let (oldest, newest) = (&samples[0].1, &samples[samples.len() - 1].1);
+
+                for (index, rule) in Rule::ALL.into_iter().enumerate() {
+                    let threshold = rule.threshold(&config);
+                    let value = rule.measure(oldest, newest);
+                    let exceeded = value > threshold;
+                    if exceeded != firing[index] {
+                        firing[index] = exceeded;
+                        notify(&webhook, rule, exceeded, value, threshold, config.window);
+                    }
+                }
+            }
+        });
+
+        Ok(Self { stop_tx, handle })
+    }
+
+    /// Stops the watcher thread
+    pub fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn notify(webhook: &HttpUrl, rule: Rule, firing: bool, value: f64, threshold: f64, window: Duration) {
+    let status = if firing { "firing" } else { "resolved" };
+    let payload = json!({
+        "service": "rustbucket",
+        "alert": rule.name(),
+        "status": status,
+        "value": value,
+        "threshold": threshold,
+        "window_seconds": window.as_secs(),
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+
+    println!("Alert {} {} (value {:.3}, threshold {})", rule.name(), status, value, threshold);
+    match http_client::post_json(webhook, &payload.to_string(), WEBHOOK_TIMEOUT) {
+        Ok(code) if (200..300).contains(&code) => {}
+        Ok(code) => log::warn!("Alert webhook rejected {} with status {}", rule.name(), code),
+        Err(e) => log::warn!("Alert webhook delivery for {} failed: {}", rule.name(), e),
+    }
+}
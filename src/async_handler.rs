@@ -0,0 +1,80 @@
+//! Async request handlers.
+//!
+//! The server serves each connection on a worker thread. An
+//! [`AsyncRequestHandler`] is driven to completion there, one message at a
+//! time, by a minimal executor that parks the worker until the handler's future
+//! is woken. That executor has no I/O reactor or timers of its own, so a handler
+//! that needs its application's runtime (tokio sockets, sleeps, ...) should spawn
+//! that work onto the runtime through a handle and await the result:
+//!
+//! ```no_run
+//! use rustbucket::{AsyncRequestHandler, ConnectionCtx, ResponseWriter};
+//!
+//! struct Lookup {
+//!     runtime: tokio::runtime::Handle,
+//! }
+//!
+//! impl AsyncRequestHandler for Lookup {
+//!     async fn on_message(&self, _ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+//!         let key = String::from_utf8_lossy(message).trim().to_string();
+//!         let value = self.runtime.spawn(async move { format!("value of {}\n", key) }).await.unwrap();
+//!         response.write(value.as_bytes());
+//!     }
+//! }
+//! ```
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+
+/// Protocol behavior written as async code
+///
+/// The async counterpart of [`RequestHandler`]; pass it to
+/// [`ServerBuilder::async_handler`](crate::ServerBuilder::async_handler).
+pub trait AsyncRequestHandler: Send + Sync {
+    /// Handles one message from a client, writing the reply to `response`
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) -> impl Future<Output = ()>;
+}
+
+/// Adapts an [`AsyncRequestHandler`] to the server's worker threads
+pub(crate) struct AsyncHandler<H> {
+    handler: H,
+}
+
+impl<H> AsyncHandler<H> {
+    pub(crate) fn new(handler: H) -> Self {
+        Self { handler }
+    }
+}
+
+impl<H: AsyncRequestHandler> RequestHandler for AsyncHandler<H> {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        block_on(self.handler.on_message(ctx, message, response));
+    }
+}
+
+/// Wakes a task by unparking the thread polling it
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread, parking between polls, until it completes
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Temporary bans for clients that keep misbehaving, in the manner of fail2ban.
+//!
+//! Handlers and middleware report offences against the address of the
+//! connection they happened on with [`ConnectionCtx::report`]: protocol
+//! errors, failed authentication, and messages refused by a rate limit. An
+//! address that commits [`BanPolicy::offences`] of them within
+//! [`BanPolicy::window`] is banned for [`BanPolicy::duration`]: its new
+//! connections are refused at accept time, and its open ones are closed after
+//! their next message. Bans can be listed and lifted early through the admin
+//! interface's `/bans` endpoints or `rustbucket bans`.
+//!
+//! [`ConnectionCtx::report`]: crate::handler::ConnectionCtx::report
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Offences an address may commit within the window by default before it is banned
+pub const DEFAULT_BAN_OFFENCES: u32 = 10;
+/// Window offences are counted over by default
+pub const DEFAULT_BAN_WINDOW: Duration = Duration::from_secs(60);
+/// How long a ban lasts by default
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(600);
+
+/// Addresses with recent offences tracked before the ones with none in the window are dropped
+const TRACKED_BEFORE_PRUNING: usize = 4096;
+
+/// Something a client did that counts toward a ban
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// A command that could not be parsed, or that the server does not know
+    ProtocolError,
+    /// An authentication attempt with the wrong credentials
+    AuthFailure,
+    /// A message refused for going over a rate limit
+    RateLimited,
+}
+
+impl Offence {
+    /// Name used in the log and the admin interface, such as `auth_failure`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ProtocolError => "protocol_error",
+            Self::AuthFailure => "auth_failure",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// When addresses are banned, and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    /// Offences within `window` that get an address banned
+    pub offences: u32,
+    pub window: Duration,
+    pub duration: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self { offences: DEFAULT_BAN_OFFENCES, window: DEFAULT_BAN_WINDOW, duration: DEFAULT_BAN_DURATION }
+    }
+}
+
+/// A banned address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// The offence that tipped the address over the limit
+    pub reason: Offence,
+    pub since: SystemTime,
+    /// Time left until the ban lifts on its own
+    pub remaining: Duration,
+}
+
+#[derive(Debug)]
+struct ActiveBan {
+    reason: Offence,
+    since: SystemTime,
+    until: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Times of each address's offences within the window, oldest first
+    offences: HashMap<IpAddr, VecDeque<Instant>>,
+    bans: HashMap<IpAddr, ActiveBan>,
+}
+
+/// Offences and bans by client address
+#[derive(Debug, Default)]
+pub struct BanList {
+    /// `None` when automatic banning is off
+    policy: Option<BanPolicy>,
+    state: Mutex<State>,
+    /// Bans in `state`, so that checks can skip the lock while there are none
+    banned: AtomicUsize,
+}
+
+impl BanList {
+    /// A list banning addresses according to `policy`
+    pub fn new(policy: BanPolicy) -> Self {
+        Self { policy: Some(policy), ..Self::default() }
+    }
+
+    /// A list that ignores offences and never bans anyone
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// The policy in effect, if banning is on
+    pub fn policy(&self) -> Option<BanPolicy> {
+        self.policy
+    }
+
+    /// Counts `offence` against `ip`, returning whether it got the address banned
+    pub fn record(&self, ip: IpAddr, offence: Offence) -> bool {
+        let Some(policy) = self.policy else { return false };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.bans.get(&ip).is_some_and(|ban| ban.until > now) {
+            return false;
+        }
+        if state.offences.len() >= TRACKED_BEFORE_PRUNING && !state.offences.contains_key(&ip) {
+            let recent = |times: &VecDeque<Instant>| times.back().is_some_and(|&last| now - last < policy.window);
+            state.offences.retain(|_, times| recent(times));
+        }
+        let times = state.offences.entry(ip).or_default();
+        times.push_back(now);
+        while times.front().is_some_and(|&first| now.duration_since(first) >= policy.window) {
+            times.pop_front();
+        }
+        let offences = times.len();
+        if offences < policy.offences.max(1) as usize {
+            return false;
+        }
+        state.offences.remove(&ip);
+        state.bans.insert(ip, ActiveBan { reason: offence, since: SystemTime::now(), until: now + policy.duration });
+        self.banned.store(state.bans.len(), Ordering::Relaxed);
+        log::warn!("Banned {} for {:?} after {} offences, the last {}", ip, policy.duration, offences, offence.name());
+        true
+    }
+
+    /// Whether `ip` is banned right now
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        if self.banned.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.bans.get(&ip) {
+            Some(ban) if ban.until > Instant::now() => true,
+            Some(_) => {
+                state.bans.remove(&ip);
+                self.banned.store(state.bans.len(), Ordering::Relaxed);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The bans in effect, soonest to lift first
+    pub fn list(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.bans.retain(|_, ban| ban.until > now);
+        self.banned.store(state.bans.len(), Ordering::Relaxed);
+        let mut bans: Vec<Ban> = state
+            .bans
+            .iter()
+            .map(|(&ip, ban)| Ban { ip, reason: ban.reason, since: ban.since, remaining: ban.until - now })
+            .collect();
+        bans.sort_by_key(|ban| ban.remaining);
+        bans
+    }
+
+    /// Lifts the ban on `ip` early, returning whether there was one
+    pub fn lift(&self, ip: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let lifted = state.bans.remove(&ip).is_some_and(|ban| ban.until > Instant::now());
+        self.banned.store(state.bans.len(), Ordering::Relaxed);
+        if lifted {
+            log::info!("Lifted the ban on {}", ip);
+        }
+        lifted
+    }
+}
@@ -0,0 +1,140 @@
+//! Reusable I/O buffers shared by the worker threads.
+//!
+//! Each connection needs a buffer to read messages into and one to assemble
+//! replies in. Rather than allocating both for every connection and freeing
+//! them when it closes, workers take them from a [`BufferPool`] and give them
+//! back afterwards, so a server handling many short connections settles into
+//! reusing the same few allocations. The pool keeps at most
+//! [`BufferPool::capacity`] idle buffers; a reply buffer that grew well past
+//! the usual size for one large response is freed instead of kept.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Idle buffers kept for reuse by default
+pub const DEFAULT_POOLED_BUFFERS: usize = 256;
+
+/// Multiple of the buffer size past which a returned buffer is freed rather than kept
+const MAX_RETAINED_GROWTH: usize = 4;
+
+/// Point-in-time view of a [`BufferPool`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Bytes in each buffer handed out
+    pub buffer_size: usize,
+    /// Idle buffers the pool may keep
+    pub capacity: usize,
+    /// Idle buffers waiting to be reused
+    pub idle: usize,
+    /// Buffers allocated because none were idle
+    pub allocated: u64,
+    /// Buffers handed out again instead of allocated
+    pub reused: u64,
+}
+
+/// Buffers of one size, handed out to connections and taken back when they close
+#[derive(Debug)]
+pub struct BufferPool {
+    buffer_size: usize,
+    capacity: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl BufferPool {
+    /// A pool of `buffer_size`-byte buffers, keeping up to `capacity` of them idle
+    pub fn new(buffer_size: usize, capacity: usize) -> Self {
+        Self {
+            buffer_size,
+            capacity,
+            idle: Mutex::new(Vec::new()),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+        }
+    }
+
+    /// Bytes in each buffer handed out
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Idle buffers the pool may keep
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the current counters
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            buffer_size: self.buffer_size,
+            capacity: self.capacity,
+            idle: self.idle.lock().unwrap().len(),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+        }
+    }
+
+    /// An empty buffer with room for at least [`buffer_size`](Self::buffer_size) bytes
+    pub(crate) fn take(&self) -> Vec<u8> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(mut buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer.clear();
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(self.buffer_size)
+            }
+        }
+    }
+
+    /// A buffer of [`buffer_size`](Self::buffer_size) bytes to read into, returned to the pool when dropped
+    pub(crate) fn read_buffer(self: &Arc<Self>) -> PooledBuffer {
+        let mut buffer = self.take();
+        buffer.resize(self.buffer_size, 0);
+        PooledBuffer { buffer, pool: Arc::clone(self) }
+    }
+
+    /// Takes `buffer` back for reuse, unless the pool is full or the buffer has grown too large to keep
+    pub(crate) fn put(&self, buffer: Vec<u8>) {
+        let capacity = buffer.capacity();
+        if capacity < self.buffer_size || capacity > self.buffer_size * MAX_RETAINED_GROWTH {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.capacity {
+            idle.push(buffer);
+        }
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`]
+#[derive(Debug)]
+pub(crate) struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
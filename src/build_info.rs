@@ -0,0 +1,50 @@
+//! Version and build metadata embedded at compile time by `build.rs`.
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+/// Crate version from `Cargo.toml`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short git commit hash, suffixed with `-dirty` for uncommitted changes
+pub const GIT_COMMIT: &str = env!("RUSTBUCKET_GIT_COMMIT");
+/// Output of `rustc --version` for the compiler that built the binary
+pub const RUSTC_VERSION: &str = env!("RUSTBUCKET_RUSTC_VERSION");
+/// Target triple the binary was compiled for
+pub const TARGET: &str = env!("RUSTBUCKET_TARGET");
+/// Cargo profile, `debug` or `release`
+pub const PROFILE: &str = env!("RUSTBUCKET_PROFILE");
+const FEATURES: &str = env!("RUSTBUCKET_FEATURES");
+const BUILD_EPOCH: &str = env!("RUSTBUCKET_BUILD_EPOCH");
+
+/// Cargo features enabled in this build, sorted
+pub fn features() -> Vec<&'static str> {
+    FEATURES.split(',').filter(|feature| !feature.is_empty()).collect()
+}
+
+/// Build date, `YYYY-MM-DD`
+pub fn build_date() -> String {
+    build_timestamp().get(..10).unwrap_or("unknown").to_string()
+}
+
+/// When the binary was built, in RFC 3339 format
+pub fn build_timestamp() -> String {
+    BUILD_EPOCH
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Build metadata as JSON with stable field names
+pub fn to_json() -> Value {
+    json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_timestamp": build_timestamp(),
+        "rustc_version": RUSTC_VERSION,
+        "target": TARGET,
+        "profile": PROFILE,
+        "features": features(),
+    })
+}
@@ -0,0 +1,156 @@
+//! A chat room: every line a client sends is relayed to everyone else.
+//!
+//! `rustbucket run --mode chat` serves [`ChatHandler`]. A client joins the
+//! room with its first line and leaves when it disconnects, and the other
+//! members are told about both. Its lines reach every other member as
+//! `<nickname>: <line>`; the sender gets no reply. Nicknames start out as
+//! `guest<connection id>`, and `/nick <name>` picks another. Notices from the
+//! room itself start with `* `.
+//!
+//! Lines are relayed through each member's [`Outbox`], so a member that stops
+//! reading is disconnected rather than holding up the room.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use memchr::memchr_iter;
+
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+use crate::outbox::Outbox;
+use crate::protocol::complete_lines;
+
+/// Longest nickname `/nick` accepts, in bytes
+pub const MAX_NICKNAME_LEN: usize = 32;
+
+/// Relays each line a client sends to every other connected client
+#[derive(Debug, Default)]
+pub struct ChatHandler {
+    members: Mutex<HashMap<u64, Member>>,
+}
+
+#[derive(Debug)]
+struct Member {
+    nickname: String,
+    outbox: Outbox,
+}
+
+impl ChatHandler {
+    /// Creates an empty room
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Nicknames of everyone in the room, in name order
+    pub fn members(&self) -> Vec<String> {
+        let mut nicknames: Vec<String> =
+            self.members.lock().unwrap().values().map(|member| member.nickname.clone()).collect();
+        nicknames.sort();
+        nicknames
+    }
+
+    /// Handles one line from the connection `ctx`
+    fn on_line(&self, ctx: &ConnectionCtx<'_>, line: &[u8], response: &mut ResponseWriter) {
+        let id = ctx.id();
+        let mut members = self.members.lock().unwrap();
+        let requested = nick_command(line);
+        if !members.contains_key(&id) {
+            // A first line of `/nick <name>` joins under that name straight away
+            let accepted = requested.map(|name| check_nickname(&members, id, name));
+            let nickname = match &accepted {
+                Some(Ok(nickname)) => nickname.clone(),
+                _ => guest_nickname(id),
+            };
+            notice(response, &format!("you joined as {}", nickname));
+            send_to_others(&mut members, id, format!("* {} joined\n", nickname).as_bytes());
+            members.insert(id, Member { nickname, outbox: ctx.outbox() });
+            if matches!(accepted, Some(Ok(_))) {
+                return;
+            }
+        }
+
+        let Some(name) = requested else {
+            let mut relayed = format!("{}: ", members[&id].nickname).into_bytes();
+            relayed.extend_from_slice(line);
+            relayed.push(b'\n');
+            return send_to_others(&mut members, id, &relayed);
+        };
+        match check_nickname(&members, id, name) {
+            Ok(nickname) => {
+                let member = members.get_mut(&id).expect("joined above");
+                let old = std::mem::replace(&mut member.nickname, nickname.clone());
+                notice(response, &format!("you are now {}", nickname));
+                send_to_others(&mut members, id, format!("* {} is now {}\n", old, nickname).as_bytes());
+            }
+            Err(reason) => notice(response, &reason),
+        }
+    }
+}
+
+impl RequestHandler for ChatHandler {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        let mut start = 0;
+        for end in memchr_iter(b'\n', message).chain([message.len()]) {
+            let line = &message[start..end];
+            start = end + 1;
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                self.on_line(ctx, line, response);
+            }
+        }
+    }
+
+    fn frame(&self, buffered: &[u8]) -> Option<usize> {
+        Some(complete_lines(buffered))
+    }
+
+    fn on_shutdown(&self, _ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        notice(response, "server closing");
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.remove(&connection_id) {
+            send_to_others(&mut members, connection_id, format!("* {} left\n", member.nickname).as_bytes());
+        }
+    }
+}
+
+/// Sends `message` to every member but `sender`, dropping those whose connection is gone
+fn send_to_others(members: &mut HashMap<u64, Member>, sender: u64, message: &[u8]) {
+    members.retain(|&id, member| id == sender || member.outbox.send(message).is_ok());
+}
+
+fn notice(response: &mut ResponseWriter, text: &str) {
+    response.write(format!("* {}\n", text).as_bytes());
+}
+
+/// The name asked for by a `/nick <name>` line, if that is what `line` is
+fn nick_command(line: &[u8]) -> Option<&[u8]> {
+    let rest = line.strip_prefix(b"/nick")?;
+    if !rest.is_empty() && !rest[0].is_ascii_whitespace() {
+        return None;
+    }
+    Some(rest.trim_ascii())
+}
+
+fn guest_nickname(connection_id: u64) -> String {
+    format!("guest{}", connection_id)
+}
+
+/// Validates `name` as the new nickname of `connection_id`
+fn check_nickname(members: &HashMap<u64, Member>, connection_id: u64, name: &[u8]) -> Result<String, String> {
+    let valid = (1..=MAX_NICKNAME_LEN).contains(&name.len())
+        && name.iter().all(|&byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if !valid {
+        return Err(format!("nicknames are 1 to {} letters, digits, '-', or '_'", MAX_NICKNAME_LEN));
+    }
+    let name = String::from_utf8(name.to_vec()).expect("checked to be ASCII");
+    // Guest names are kept free for the connections they belong to
+    let is_guest_name =
+        name.strip_prefix("guest").is_some_and(|id| !id.is_empty() && id.bytes().all(|byte| byte.is_ascii_digit()));
+    let taken = members.iter().any(|(id, member)| *id != connection_id && member.nickname == name);
+    if taken || (is_guest_name && name != guest_nickname(connection_id)) {
+        return Err(format!("the nickname {} is taken", name));
+    }
+    Ok(name)
+}
@@ -0,0 +1,257 @@
+//! The `backup` and `restore` subcommands.
+//!
+//! A backup is a zstd-compressed tar archive holding the KV snapshot, the
+//! write-ahead log, and the config file, whichever of them exist, plus a
+//! `MANIFEST.json` listing each file's size and CRC-32. `restore` checks every
+//! file against the manifest before touching anything, so a truncated or
+//! corrupted archive is refused whole rather than half applied.
+//!
+//! Backups can be taken while the server runs. The snapshot is read again
+//! after the log, and the files are read over if a snapshot was saved in
+//! between, since that shortens the log; a snapshot read with the log that was
+//! current alongside it always replays to the store's state at that moment.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+use rustbucket::paths::Paths;
+use rustbucket::pidfile;
+use crate::cmd::output::Report;
+
+/// Name of the manifest inside an archive
+const MANIFEST: &str = "MANIFEST.json";
+/// Manifest layout written by this version; restore refuses newer ones
+const FORMAT_VERSION: u64 = 1;
+/// Times to re-read the files when a snapshot is saved mid-backup
+const MAX_ATTEMPTS: usize = 5;
+/// zstd level: favours speed, since backups are often taken on a live server
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Contents of the files in an archive, by name
+type Contents = Vec<(String, Vec<u8>)>;
+
+/// One file in a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackedUpFile {
+    /// Name inside the archive, which is also its default name on disk
+    pub name: String,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+impl BackedUpFile {
+    fn to_json(&self) -> Value {
+        json!({ "name": self.name, "size": self.size, "crc32": format!("{:08x}", self.crc32) })
+    }
+}
+
+/// What `backup` or `restore` did
+#[derive(Debug)]
+pub struct BackupReport {
+    pub archive: PathBuf,
+    pub files: Vec<BackedUpFile>,
+    /// `true` for `restore`, `false` for `backup`
+    pub restore: bool,
+    pub dry_run: bool,
+}
+
+impl Report for BackupReport {
+    fn to_json(&self) -> Value {
+        json!({
+            "archive": self.archive.display().to_string(),
+            "files": self.files.iter().map(BackedUpFile::to_json).collect::<Vec<_>>(),
+            "restored": self.restore && !self.dry_run,
+            "dry_run": self.dry_run,
+        })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        let verb = match (self.restore, self.dry_run) {
+            (false, _) => "Backed up",
+            (true, false) => "Restored",
+            (true, true) => "Would restore",
+        };
+        let direction = if self.restore { "from" } else { "to" };
+        let noun = if self.files.len() == 1 { "file" } else { "files" };
+        writeln!(out, "{} {} {} {} {}", verb, self.files.len(), noun, direction, self.archive.display())?;
+        for file in &self.files {
+            writeln!(out, "  {:<12} {:>10} bytes  crc32 {:08x}", file.name, file.size, file.crc32)?;
+        }
+        Ok(())
+    }
+}
+
+/// The files a backup covers, by name in the archive, in the order they are read
+fn covered_files(paths: &Paths) -> [(&'static str, &Path); 3] {
+    [
+        ("kv.snapshot", &paths.snapshot_file),
+        ("kv.wal", &paths.wal_file),
+        ("config.dat", &paths.config_file),
+    ]
+}
+
+/// Writes the server's data files to a compressed archive at `archive`
+pub fn backup(paths: &Paths, archive: &Path) -> io::Result<BackupReport> {
+    let contents = read_consistent(paths)?;
+    if contents.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "nothing to back up: no snapshot, log, or config file"));
+    }
+    let files: Vec<BackedUpFile> = contents.iter().map(|(name, bytes)| describe(name, bytes)).collect();
+    let manifest = json!({
+        "version": FORMAT_VERSION,
+        "created": chrono::Utc::now().to_rfc3339(),
+        "files": files.iter().map(BackedUpFile::to_json).collect::<Vec<_>>(),
+    });
+
+    let temp = temp_path(archive);
+    let result = write_archive(&temp, &serde_json::to_vec_pretty(&manifest)?, &contents)
+        .and_then(|()| fs::rename(&temp, archive));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    log::info!("Backed up {} files to {}", files.len(), archive.display());
+    Ok(BackupReport { archive: archive.to_path_buf(), files, restore: false, dry_run: false })
+}
+
+/// Replaces the server's data files with those in `archive`, once all of them check out
+///
+/// Files the archive does not hold are removed, so a write-ahead log left
+/// over from before is not replayed over the restored snapshot.
+pub fn restore(paths: &Paths, archive: &Path, dry_run: bool) -> io::Result<BackupReport> {
+    if let Some(pid) = pidfile::read_pid(&paths.pid_file)?.filter(|&pid| pidfile::is_running(pid)) {
+        return Err(io::Error::other(format!("the server (pid {}) is running; stop it before restoring", pid)));
+    }
+    let (files, mut contents) = read_archive(archive)?;
+    if !dry_run {
+        for (name, path) in covered_files(paths) {
+            match contents.iter_mut().find(|(entry, _)| entry == name) {
+                Some((_, bytes)) => write_file(path, &std::mem::take(bytes))?,
+                None => match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                },
+            }
+        }
+        log::info!("Restored {} files from {}", files.len(), archive.display());
+    }
+    Ok(BackupReport { archive: archive.to_path_buf(), files, restore: true, dry_run })
+}
+
+/// Reads the covered files that exist, over again if the snapshot changes meanwhile
+fn read_consistent(paths: &Paths) -> io::Result<Vec<(&'static str, Vec<u8>)>> {
+    for _ in 0..MAX_ATTEMPTS {
+        let mut contents = Vec::new();
+        for (name, path) in covered_files(paths) {
+            if let Some(bytes) = read_if_exists(path)? {
+                contents.push((name, bytes));
+            }
+        }
+        let snapshot = contents.iter().find(|(name, _)| *name == "kv.snapshot").map(|(_, bytes)| bytes);
+        if read_if_exists(&paths.snapshot_file)?.as_ref() == snapshot {
+            return Ok(contents);
+        }
+        log::debug!("{} was saved during the backup; reading again", paths.snapshot_file.display());
+    }
+    Err(io::Error::other(format!(
+        "{} kept changing during the backup; try again when the server is less busy",
+        paths.snapshot_file.display()
+    )))
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+    }
+}
+
+fn describe(name: &str, bytes: &[u8]) -> BackedUpFile {
+    BackedUpFile { name: name.to_string(), size: bytes.len() as u64, crc32: crc32fast::hash(bytes) }
+}
+
+fn write_archive(path: &Path, manifest: &[u8], contents: &[(&str, Vec<u8>)]) -> io::Result<()> {
+    let encoder = zstd::Encoder::new(File::create(path)?, COMPRESSION_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    append(&mut builder, MANIFEST, manifest)?;
+    for (name, bytes) in contents {
+        append(&mut builder, name, bytes)?;
+    }
+    let file = builder.into_inner()?.finish()?;
+    file.sync_all()
+}
+
+fn append(builder: &mut tar::Builder<impl Write>, name: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, name, bytes)
+}
+
+/// Unpacks `archive` into memory and checks each file against the manifest
+fn read_archive(archive: &Path) -> io::Result<(Vec<BackedUpFile>, Contents)> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", archive.display(), reason));
+    let mut manifest = None;
+    let mut contents = Vec::new();
+    let mut entries = tar::Archive::new(zstd::Decoder::new(File::open(archive)?)?);
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes)?;
+        if name == MANIFEST {
+            manifest = Some(serde_json::from_slice::<Value>(&bytes).map_err(|e| invalid(format!("bad manifest: {}", e)))?);
+        } else {
+            contents.push((name, bytes));
+        }
+    }
+    let manifest = manifest.ok_or_else(|| invalid("no manifest; not a rustbucket backup".to_string()))?;
+    let version = manifest["version"].as_u64().unwrap_or(0);
+    if version != FORMAT_VERSION {
+        return Err(invalid(format!("unsupported backup format version {}", version)));
+    }
+
+    let mut files = Vec::new();
+    for listed in manifest["files"].as_array().map(Vec::as_slice).unwrap_or_default() {
+        let name = listed["name"].as_str().ok_or_else(|| invalid("manifest entry without a name".to_string()))?;
+        if !covered_files(&Paths::default()).iter().any(|(covered, _)| *covered == name) {
+            return Err(invalid(format!("unexpected file {} in manifest", name)));
+        }
+        let bytes = contents
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, bytes)| bytes)
+            .ok_or_else(|| invalid(format!("{} is listed but missing", name)))?;
+        let actual = describe(name, bytes);
+        let crc32 = listed["crc32"].as_str().and_then(|crc| u32::from_str_radix(crc, 16).ok());
+        if Some(actual.size) != listed["size"].as_u64() || Some(actual.crc32) != crc32 {
+            return Err(invalid(format!("{} does not match its checksum", name)));
+        }
+        files.push(actual);
+    }
+    if let Some((name, _)) = contents.iter().find(|(name, _)| !files.iter().any(|file| file.name == *name)) {
+        return Err(invalid(format!("{} is not listed in the manifest", name)));
+    }
+    Ok((files, contents))
+}
+
+/// Replaces `path` with `bytes` through a synced temporary file
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp = temp_path(path);
+    let mut file = File::create(&temp)?;
+    let result = file.write_all(bytes).and_then(|()| file.sync_all()).and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
@@ -0,0 +1,210 @@
+//! Load generator for measuring server throughput and latency.
+//!
+//! Opens a number of concurrent connections, each sending a fixed number of
+//! newline-terminated messages and waiting for the echo before sending the next,
+//! then reports overall throughput and round-trip latency percentiles.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+
+use crate::cmd::output::{Output, Report};
+
+/// Parameters for a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub host: String,
+    pub port: u16,
+    /// Concurrent connections
+    pub connections: usize,
+    /// Messages sent on each connection
+    pub messages: usize,
+    /// Payload bytes per message, excluding the newline
+    pub size: usize,
+    /// Target messages per second across all connections, or 0 for as fast as possible
+    pub rate: u64,
+    /// How long to wait for each reply
+    pub timeout: Duration,
+}
+
+/// What a single connection observed
+#[derive(Default)]
+struct WorkerResult {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+/// Summary of a completed run
+struct BenchReport {
+    connections: usize,
+    messages: usize,
+    size: usize,
+    errors: u64,
+    elapsed: Duration,
+    /// Round-trip time of every successful message, sorted
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    fn completed(&self) -> u64 {
+        self.latencies.len() as u64
+    }
+
+    fn bytes_sent(&self) -> u64 {
+        self.completed() * (self.size as u64 + 1)
+    }
+
+    fn seconds(&self) -> f64 {
+        self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Latency at quantile `p` in milliseconds
+    fn percentile(&self, p: f64) -> f64 {
+        if self.latencies.is_empty() {
+            return 0.0;
+        }
+        let index = ((self.latencies.len() as f64 * p).ceil() as usize).clamp(1, self.latencies.len()) - 1;
+        millis(self.latencies[index])
+    }
+
+    fn max(&self) -> f64 {
+        self.latencies.last().copied().map(millis).unwrap_or(0.0)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Report for BenchReport {
+    fn to_json(&self) -> Value {
+        json!({
+            "connections": self.connections,
+            "messages_per_connection": self.messages,
+            "message_size": self.size,
+            "completed": self.completed(),
+            "errors": self.errors,
+            "elapsed_seconds": self.elapsed.as_secs_f64(),
+            "messages_per_second": self.completed() as f64 / self.seconds(),
+            "bytes_sent": self.bytes_sent(),
+            "latency_ms": {
+                "min": self.latencies.first().copied().map(millis).unwrap_or(0.0),
+                "p50": self.percentile(0.50),
+                "p90": self.percentile(0.90),
+                "p99": self.percentile(0.99),
+                "max": self.max(),
+            },
+        })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "Completed:  {} messages in {:.2}s ({} errors)", self.completed(), self.elapsed.as_secs_f64(), self.errors)?;
+        writeln!(
+            out,
+            "Throughput: {:.0} msg/s, {:.1} KiB/s sent",
+            self.completed() as f64 / self.seconds(),
+            self.bytes_sent() as f64 / 1024.0 / self.seconds()
+        )?;
+        writeln!(
+            out,
+            "Latency:    p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            self.percentile(0.50),
+            self.percentile(0.90),
+            self.percentile(0.99),
+            self.max()
+        )
+    }
+}
+
+/// Runs the benchmark and prints a summary
+pub fn run_bench(config: BenchConfig, out: &Output) -> io::Result<()> {
+    let connections = config.connections.max(1);
+    // Each connection paces itself so that together they hit the target rate
+    let pace = (config.rate > 0).then(|| Duration::from_secs_f64(connections as f64 / config.rate as f64));
+
+    out.progress(format_args!(
+        "Benchmarking {}:{} with {} connections x {} messages of {} bytes",
+        config.host, config.port, connections, config.messages, config.size
+    ));
+
+    let started = Instant::now();
+    let workers: Vec<_> = (0..connections)
+        .map(|_| {
+            let config = config.clone();
+            thread::spawn(move || run_connection(&config, pace))
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(connections * config.messages);
+    let mut errors = 0;
+    for worker in workers {
+        let result = worker.join().unwrap_or_else(|_| WorkerResult { errors: 1, ..Default::default() });
+        latencies.extend(result.latencies);
+        errors += result.errors;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+
+    out.emit(&BenchReport {
+        connections,
+        messages: config.messages,
+        size: config.size,
+        errors,
+        elapsed,
+        latencies,
+    })
+}
+
+/// Sends every message on one connection, recording round-trip times
+fn run_connection(config: &BenchConfig, pace: Option<Duration>) -> WorkerResult {
+    let mut result = WorkerResult::default();
+    let mut stream = match TcpStream::connect((config.host.as_str(), config.port)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("Failed to connect to {}:{}: {}", config.host, config.port, e);
+            result.errors = config.messages as u64;
+            return result;
+        }
+    };
+    log::debug!("Connected from {:?}", stream.local_addr());
+    let _ = stream.set_nodelay(true);
+    let reader = stream.set_read_timeout(Some(config.timeout)).and_then(|_| stream.try_clone());
+    let mut reader = match reader {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => {
+            log::warn!("Failed to set up connection: {}", e);
+            result.errors = config.messages as u64;
+            return result;
+        }
+    };
+
+    let mut payload = vec![b'x'; config.size];
+    payload.push(b'\n');
+    let mut reply = Vec::new();
+    let started = Instant::now();
+
+    for sent in 0..config.messages {
+        if let Some(pace) = pace {
+            let due = started + pace * sent as u32;
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+
+        let request_started = Instant::now();
+        reply.clear();
+        let exchanged = stream.write_all(&payload).and_then(|_| reader.read_until(b'\n', &mut reply));
+        match exchanged {
+            Ok(n) if n > 0 => result.latencies.push(request_started.elapsed()),
+            // A failed or closed connection fails every remaining message
+            _ => {
+                result.errors += (config.messages - sent) as u64;
+                break;
+            }
+        }
+    }
+    result
+}
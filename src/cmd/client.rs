@@ -0,0 +1,49 @@
+//! Interactive client for talking to a running server.
+//!
+//! Sends one line per message and prints each reply, so the server can be
+//! exercised without hand-crafting `nc` invocations. The server currently speaks
+//! plain newline-terminated text over TCP, which is the only protocol supported.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use serde_json::json;
+
+use crate::cmd::output::{Message, Output};
+
+/// Connects to `host:port` and exchanges `messages`, or lines read from stdin when empty
+///
+/// Each reply is printed as it arrives; in JSON mode every exchange is one line.
+pub fn run_client(host: &str, port: u16, messages: Vec<String>, timeout: Duration, out: &Output) -> io::Result<()> {
+    let stream = TcpStream::connect((host, port)).map_err(|e| {
+        io::Error::new(e.kind(), format!("could not connect to {}:{}: {}", host, port, e))
+    })?;
+    log::info!("Connected to {}", stream.peer_addr()?);
+    stream.set_read_timeout(Some(timeout))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut exchange = |message: &str| -> io::Result<()> {
+        // The server replies once per read, so send the whole line in one write
+        writer.write_all(format!("{}\n", message).as_bytes())?;
+        writer.flush()?;
+
+        let mut reply = String::new();
+        if reader.read_line(&mut reply)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"));
+        }
+        let reply = reply.trim_end_matches(['\r', '\n']);
+        out.emit(&Message::new(reply, json!({ "sent": message, "reply": reply })))
+    };
+
+    if messages.is_empty() {
+        for line in io::stdin().lock().lines() {
+            exchange(&line?)?;
+        }
+    } else {
+        for message in &messages {
+            exchange(message)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Commands over the shared config file: `show-config` and `update-config`.
+
+use std::io::{self, Write};
+use std::path::Path;
+use serde_json::{json, Value};
+use rustbucket::config::{read_config, update_config, write_config_update, Config, CONFIG_SIZE};
+
+use crate::cmd::output::{Message, Output, Report};
+
+impl Report for Config {
+    fn to_json(&self) -> Value {
+        Config::to_json(*self)
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "Verbosity:       {}", self.verbosity)?;
+        writeln!(out, "Max connections: {}", self.max_connections)?;
+        writeln!(out, "Timeout:         {}s", self.timeout_seconds)?;
+        writeln!(out, "Config version:  {}", self.version)
+    }
+}
+
+/// Prints the configuration stored in the config file
+pub fn show_config(config_file: &Path, out: &Output) -> rustbucket::Result<()> {
+    Ok(out.emit(&read_config(config_file)?)?)
+}
+
+pub fn update_server_config(
+    config_file: &Path,
+    verbosity: Option<u32>,
+    max_connections: Option<u32>,
+    timeout: Option<u32>,
+    out: &Output,
+) -> rustbucket::Result<()> {
+    let config = write_config_update(config_file, verbosity, max_connections, timeout)?;
+    Ok(out.emit(&Message::new(format!("Configuration updated: {:?}", config), config.to_json()))?)
+}
+
+/// Shows which fields an update would change without writing the config file
+pub fn preview_config_update(
+    config_file: &Path,
+    verbosity: Option<u32>,
+    max_connections: Option<u32>,
+    timeout: Option<u32>,
+    out: &Output,
+) -> io::Result<()> {
+    // Read the file the way `write_config_update` maps it: missing or short files are zero-filled
+    let mut bytes = [0u8; CONFIG_SIZE];
+    match std::fs::read(config_file) {
+        Ok(data) => {
+            let len = data.len().min(bytes.len());
+            bytes[..len].copy_from_slice(&data[..len]);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let current = Config::from_bytes(&bytes);
+    let mut updated = current;
+    update_config(&mut updated, verbosity, max_connections, timeout);
+
+    let before = current.to_json();
+    let after = updated.to_json();
+    let mut text = format!("Would update {}:", config_file.display());
+    let mut changes = serde_json::Map::new();
+    for field in ["verbosity", "max_connections", "timeout_seconds", "version"] {
+        if before[field] != after[field] {
+            text.push_str(&format!("\n  {}: {} -> {}", field, before[field], after[field]));
+            changes.insert(field.to_string(), json!({ "from": before[field], "to": after[field] }));
+        }
+    }
+    out.emit(&Message::new(
+        text,
+        json!({ "dry_run": true, "config_file": config_file.display().to_string(), "changes": changes, "config": after }),
+    ))
+}
@@ -0,0 +1,135 @@
+//! Console diagnostics for the CLI itself.
+//!
+//! Separate from the server's `verbosity` setting in `config.dat`, which controls
+//! what a running server records. The global `-v`/`-q` flags pick how much the
+//! process reports on stderr about what it is doing, whichever subcommand runs,
+//! and `--color` picks whether it is highlighted.
+
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// When to highlight console diagnostics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stderr is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    /// Always color, even when piped
+    Always,
+    /// Never color
+    Never,
+}
+
+/// Whether stderr output is colored, decided once by [`init`]
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Highlights applied to console diagnostics
+#[derive(Debug, Clone, Copy)]
+pub enum Style {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Style {
+    fn ansi(self) -> &'static str {
+        match self {
+            Style::Error => "1;31",
+            Style::Warn => "33",
+            Style::Info => "32",
+            Style::Debug => "34",
+            Style::Trace => "2",
+        }
+    }
+}
+
+impl From<Level> for Style {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Error => Style::Error,
+            Level::Warn => Style::Warn,
+            Level::Info => Style::Info,
+            Level::Debug => Style::Debug,
+            Level::Trace => Style::Trace,
+        }
+    }
+}
+
+/// Wraps `text` in the escape codes for `style` when stderr is colored
+pub fn paint(style: Style, text: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", style.ansi(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Resolves `choice` against the environment
+///
+/// `NO_COLOR` (any non-empty value, see <https://no-color.org>) turns off
+/// automatic color but not an explicit `--color always`.
+fn color_enabled(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && std::io::stderr().is_terminal()
+        }
+    }
+}
+
+/// Writes enabled records to stderr, prefixed with their level
+struct ConsoleLogger;
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut stderr = std::io::stderr().lock();
+        let style = Style::from(record.level());
+        let _ = match record.level() {
+            // Warnings and errors read the same as they always have
+            Level::Error | Level::Warn => writeln!(stderr, "{}", paint(style, &record.args().to_string())),
+            level => writeln!(stderr, "{} {}", paint(style, &format!("[{}]", level.as_str().to_lowercase())), record.args()),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Maps the `-v` count and `-q` flag to a level filter
+///
+/// Warnings are shown by default; `-q` keeps only errors, `-v` adds progress
+/// details, `-vv` debugging output, and `-vvv` everything.
+pub fn level_filter(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Installs the console logger; called once before any subcommand runs
+pub fn init(verbose: u8, quiet: bool, color: ColorChoice) {
+    COLOR.store(color_enabled(color), Ordering::Relaxed);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level_filter(verbose, quiet));
+    }
+}
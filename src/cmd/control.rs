@@ -0,0 +1,372 @@
+//! Commands that talk to a running server: `status`, `stop`, `reload`,
+//! `version`, `stats`, and `bans`.
+//!
+//! Most go through the admin interface on the loopback port; `status` and
+//! `stop` also consult the pidfile so they work when the admin interface is off.
+
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use nix::sys::signal::Signal;
+use serde_json::{json, Value};
+use rustbucket::build_info;
+use rustbucket::http_client::{self, HttpUrl};
+use std::path::Path;
+use rustbucket::pidfile;
+
+use crate::cmd::output::{Message, Output, Report};
+
+/// Exit codes reported by `status`, following the LSB init script conventions
+pub const STATUS_RUNNING: i32 = 0;
+pub const STATUS_STALE_PIDFILE: i32 = 1;
+pub const STATUS_NOT_RUNNING: i32 = 3;
+pub const STATUS_UNREACHABLE: i32 = 4;
+
+/// Whether a server is running, as reported by `status`
+pub struct ServerStatus<'a> {
+    state: &'static str,
+    pid_file: &'a Path,
+    pid: Option<i32>,
+    admin_port: u16,
+    /// The server's `/status` document, when its admin interface answered
+    server: Option<Value>,
+}
+
+impl Report for ServerStatus<'_> {
+    fn to_json(&self) -> Value {
+        let mut status = json!({ "state": self.state, "pid": self.pid, "admin_port": self.admin_port });
+        if let Some(server) = &self.server {
+            status["pid"] = server["pid"].clone();
+            status["listen_address"] = server["listen_address"].clone();
+            status["uptime_seconds"] = server["uptime_seconds"].clone();
+            status["config_version"] = server["config_version"].clone();
+        }
+        status
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        match (&self.server, self.pid) {
+            (Some(server), _) => {
+                writeln!(out, "Server is running")?;
+                writeln!(out, "  PID:            {}", server["pid"])?;
+                writeln!(out, "  Listening on:   {}", server["listen_address"].as_str().unwrap_or("(not yet bound)"))?;
+                writeln!(out, "  Uptime:         {}s", server["uptime_seconds"])?;
+                writeln!(out, "  Config version: {}", server["config_version"])
+            }
+            (None, Some(pid)) if self.state == "unreachable" => writeln!(
+                out,
+                "Server process {} is alive but its admin interface on port {} did not respond",
+                pid, self.admin_port
+            ),
+            (None, Some(pid)) => writeln!(out, "Server is not running (stale {} names pid {})", self.pid_file.display(), pid),
+            (None, None) => writeln!(out, "Server is not running"),
+        }
+    }
+}
+
+/// Prints whether a server is running and returns the matching exit code
+pub fn show_status(pid_file: &Path, admin_port: u16, out: &Output) -> io::Result<i32> {
+    let pid = pidfile::read_pid(pid_file)?;
+    log::debug!("{} names pid {:?}", pid_file.display(), pid);
+    let (code, state, server) = match fetch_admin_json(admin_port, "/status") {
+        Ok(server) => (STATUS_RUNNING, "running", Some(server)),
+        Err(e) => {
+            log::debug!("{}", e);
+            match pid {
+                Some(pid) if pidfile::is_running(pid) => (STATUS_UNREACHABLE, "unreachable", None),
+                Some(_) => (STATUS_STALE_PIDFILE, "stale_pidfile", None),
+                None => (STATUS_NOT_RUNNING, "not_running", None),
+            }
+        }
+    };
+    out.emit(&ServerStatus { state, pid_file, pid, admin_port, server })?;
+    Ok(code)
+}
+
+/// Sends the server SIGTERM, or SIGKILL when forced
+#[cfg(unix)]
+fn send_stop(pid: i32, force: bool) -> io::Result<()> {
+    pidfile::signal(pid, if force { Signal::SIGKILL } else { Signal::SIGTERM })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_stop(_pid: i32, _force: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "stop needs Unix signals; use POST /drain on the admin port instead"))
+}
+
+/// Signals the server named in the pidfile and waits for it to exit
+pub fn stop_server(pid_file: &Path, force: bool, timeout: Duration, out: &Output) -> io::Result<()> {
+    let pid = pidfile::read_pid(pid_file)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no server running ({} not found)", pid_file.display()))
+    })?;
+    if !pidfile::is_running(pid) {
+        pidfile::remove_stale(pid_file)?;
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no server running (removed stale {} for pid {})", pid_file.display(), pid),
+        ));
+    }
+
+    send_stop(pid, force)?;
+    if !force {
+        out.progress(format_args!("Waiting up to {}s for server (pid {}) to shut down...", timeout.as_secs(), pid));
+    }
+
+    let deadline = Instant::now() + timeout;
+    while pidfile::is_running(pid) {
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("server (pid {}) did not exit within {}s; use --force to kill it", pid, timeout.as_secs()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // A killed server cannot clean up after itself
+    if force {
+        pidfile::remove_stale(pid_file)?;
+    }
+    out.emit(&Message::new(
+        format!("Server (pid {}) stopped", pid),
+        json!({ "stopped": true, "pid": pid, "forced": force }),
+    ))
+}
+
+/// Fetches a JSON document from the admin interface of a running server
+pub fn fetch_admin_json(admin_port: u16, path: &str) -> io::Result<Value> {
+    admin_request(admin_port, "GET", path)
+}
+
+/// Sends a request to the admin interface and parses the JSON reply
+///
+/// Error responses are reported using the `error` field of their body when present.
+pub fn admin_request(admin_port: u16, method: &str, path: &str) -> io::Result<Value> {
+    let url = HttpUrl::parse(&format!("http://127.0.0.1:{}{}", admin_port, path))?;
+    let timeout = Duration::from_secs(5);
+    log::debug!("{} http://{}:{}{}", method, url.host, url.port, url.path);
+    let response = match method {
+        "POST" => http_client::post(&url, "", timeout),
+        _ => http_client::get(&url, timeout),
+    }
+    .map_err(|e| {
+        io::Error::new(e.kind(), format!("could not reach admin interface on port {}: {}", admin_port, e))
+    })?;
+    log::debug!("Admin interface answered {} with status {}", path, response.status);
+    if !response.is_success() {
+        let detail = serde_json::from_str::<Value>(&response.body)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(str::to_string))
+            .unwrap_or_else(|| format!("status {}", response.status));
+        return Err(io::Error::other(format!("admin interface failed {}: {}", path, detail)));
+    }
+    serde_json::from_str(&response.body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Asks a running server to reload and reports the configuration it picked up
+pub fn reload_server(admin_port: u16, out: &Output) -> io::Result<()> {
+    let result = admin_request(admin_port, "POST", "/reload")?;
+    let text = format!(
+        "Server reloaded: configuration version {}, log files reopened",
+        result["config"]["version"]
+    );
+    out.emit(&Message::new(text, result))
+}
+
+/// Build metadata, and optionally the running server's, as reported by `version`
+pub struct VersionReport {
+    verbose: bool,
+    admin_port: u16,
+    /// The server's `/version` document, when its admin interface answered
+    server: Option<Value>,
+}
+
+impl Report for VersionReport {
+    fn to_json(&self) -> Value {
+        if !self.verbose {
+            return json!({ "version": build_info::VERSION });
+        }
+        let mut info = build_info::to_json();
+        info["server"] = self.server.as_ref().map(|server| json!({
+            "start_time": server["start_time"],
+            "uptime_seconds": server["uptime_seconds"],
+            "version": server["version"],
+            "git_commit": server["git_commit"],
+        })).unwrap_or(Value::Null);
+        info
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "rustbucket {} ({} {} {})",
+            build_info::VERSION,
+            build_info::GIT_COMMIT,
+            build_info::build_date(),
+            build_info::TARGET
+        )?;
+        if !self.verbose {
+            return Ok(());
+        }
+        let features = build_info::features();
+        writeln!(out, "Git commit:  {}", build_info::GIT_COMMIT)?;
+        writeln!(out, "Built:       {} ({} profile)", build_info::build_timestamp(), build_info::PROFILE)?;
+        writeln!(out, "Compiler:    {}", build_info::RUSTC_VERSION)?;
+        writeln!(out, "Target:      {}", build_info::TARGET)?;
+        writeln!(out, "Features:    {}", if features.is_empty() { "(none)".to_string() } else { features.join(", ") })?;
+        match &self.server {
+            Some(server) => writeln!(
+                out,
+                "Server:      running {} ({}) since {}, uptime {}s",
+                server["version"].as_str().unwrap_or("?"),
+                server["git_commit"].as_str().unwrap_or("?"),
+                server["start_time"].as_str().unwrap_or("?"),
+                server["uptime_seconds"].as_u64().unwrap_or(0)
+            ),
+            None => writeln!(out, "Server:      not reachable on admin port {}", self.admin_port),
+        }
+    }
+}
+
+/// Prints the version, plus build metadata and server uptime when verbose
+pub fn show_version(verbose: bool, admin_port: u16, out: &Output) -> io::Result<()> {
+    // A running server is optional; report it only if its admin interface answers
+    let server = if verbose { fetch_admin_json(admin_port, "/version").ok() } else { None };
+    out.emit(&VersionReport { verbose, admin_port, server })
+}
+
+/// Live counters from a running server's `/stats`, as reported by `stats`
+pub struct Stats(Value);
+
+impl Report for Stats {
+    fn to_json(&self) -> Value {
+        self.0.clone()
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        let stats = &self.0;
+        writeln!(
+            out,
+            "Connections: {} active, {} accepted, {} closed",
+            stats["connections_active"], stats["connections_accepted"], stats["connections_closed"]
+        )?;
+        writeln!(out, "Errors:      {} accept, {} handler", stats["accept_errors"], stats["handler_errors"])?;
+        writeln!(
+            out,
+            "Refused:     {} banned, {} denied, {} over the per-IP cap, {} messages throttled, {} commands denied",
+            stats["connections_banned"],
+            stats["connections_denied"],
+            stats["connections_over_ip_cap"],
+            stats["requests_throttled"],
+            stats["commands_denied"]
+        )?;
+        writeln!(out, "Auth:        {} failures, {} lockouts", stats["auth_failures"], stats["auth_lockouts"])?;
+        writeln!(
+            out,
+            "Floods:      {} detected, {} connections refused for want of a worker{}",
+            stats["floods"],
+            stats["connections_over_queue"],
+            if stats["defensive_mode"] == true { " (defending now)" } else { "" }
+        )?;
+        writeln!(
+            out,
+            "Traffic:     {} messages, {} bytes in, {} bytes out",
+            stats["messages_received"], stats["bytes_received"], stats["bytes_sent"]
+        )?;
+        let memory = &stats["memory"];
+        let limit = match memory["limit"].as_u64() {
+            Some(limit) => limit.to_string(),
+            None => "unlimited".to_string(),
+        };
+        writeln!(
+            out,
+            "Memory:      {} of {} bytes ({} buffers, {} queues, {} kv), {} writes rejected, {} idle connections shed",
+            memory["used"], limit, memory["buffers"], memory["queues"], memory["kv"], memory["rejected"], memory["shed"]
+        )?;
+        // Older servers report neither
+        let process = &stats["process"];
+        if let Some(resident) = process["resident"].as_u64() {
+            writeln!(out, "Process:     {} bytes resident, {} at peak", resident, process["peak_resident"])?;
+        } else if let Some(peak) = process["peak_resident"].as_u64() {
+            writeln!(out, "Process:     {} bytes resident at peak", peak)?;
+        }
+        let heap = &stats["heap"];
+        if heap.is_object() {
+            writeln!(
+                out,
+                "Heap:        {} bytes allocated, {} at peak, {} live allocations of {} made",
+                heap["allocated"], heap["peak"], heap["live"], heap["allocations"]
+            )?;
+            // Servers from before the allocator was reported leave it out
+            if let Some(allocator) = heap["allocator"].as_str() {
+                writeln!(out, "Allocator:   {}", allocator)?;
+            }
+        }
+        let pool = &stats["pool"];
+        // Only pools that resize themselves have different bounds; older servers report neither
+        let bounds = match (pool["min_workers"].as_u64(), pool["max_workers"].as_u64()) {
+            (Some(min), Some(max)) if min != max => format!(" (scaling {} to {})", min, max),
+            _ => String::new(),
+        };
+        writeln!(
+            out,
+            "Pool:        {}/{} workers busy{}, {} queued, {} executed, {} panicked",
+            pool["active"], pool["workers"], bounds, pool["queued"], pool["executed"], pool["panicked"]
+        )?;
+        let buffers = &stats["buffers"];
+        writeln!(
+            out,
+            "Buffers:     {} idle of {} kept, {} bytes each, {} allocated, {} reused",
+            buffers["idle"], buffers["capacity"], buffers["size"], buffers["allocated"], buffers["reused"]
+        )
+    }
+}
+
+/// Prints live counters from a running server
+pub fn show_stats(admin_port: u16, out: &Output) -> io::Result<()> {
+    out.emit(&Stats(fetch_admin_json(admin_port, "/stats")?))
+}
+
+/// Addresses a running server has banned, as reported by `bans`
+pub struct Bans(Value);
+
+impl Report for Bans {
+    fn to_json(&self) -> Value {
+        self.0.clone()
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        let bans = self.0["bans"].as_array().map(Vec::as_slice).unwrap_or_default();
+        if !self.0["enabled"].as_bool().unwrap_or(false) {
+            return writeln!(out, "Automatic banning is off (start the server with --ban-after to turn it on)");
+        }
+        if bans.is_empty() {
+            return writeln!(out, "No addresses are banned");
+        }
+        writeln!(out, "{:<40} {:<16} {:>10}", "ADDRESS", "REASON", "REMAINING")?;
+        for ban in bans {
+            writeln!(
+                out,
+                "{:<40} {:<16} {:>9}s",
+                ban["ip"].as_str().unwrap_or("?"),
+                ban["reason"].as_str().unwrap_or("?"),
+                ban["remaining_seconds"].as_u64().unwrap_or(0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the addresses a running server has banned
+pub fn show_bans(admin_port: u16, out: &Output) -> io::Result<()> {
+    out.emit(&Bans(fetch_admin_json(admin_port, "/bans")?))
+}
+
+/// Lifts a running server's ban on `ip`
+pub fn lift_ban(admin_port: u16, ip: IpAddr, out: &Output) -> io::Result<()> {
+    let result = admin_request(admin_port, "POST", &format!("/bans/{}/lift", ip))?;
+    out.emit(&Message::new(format!("Lifted the ban on {}", ip), result))
+}
@@ -0,0 +1,254 @@
+//! Environment checks run by the `doctor` subcommand.
+//!
+//! Each check inspects one thing the server depends on and reports a finding
+//! with a hint when something needs attention, so problems surface before the
+//! server is started rather than as a failure at runtime.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use memmap2::MmapOptions;
+#[cfg(unix)]
+use nix::sys::resource::{getrlimit, Resource};
+use serde_json::{json, Value};
+
+use crate::cmd::output::{Output, Report};
+use rustbucket::config::read_config;
+use rustbucket::pidfile;
+use rustbucket::Paths;
+
+/// File descriptors reserved beyond client connections (log, config, listeners, stdio)
+#[cfg(unix)]
+const RESERVED_FDS: u64 = 32;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+}
+
+/// What a check found, with a suggested fix when it is not `Ok`
+struct Finding {
+    check: &'static str,
+    status: Status,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, detail: impl Into<String>) -> Self {
+        Self { check, status: Status::Ok, detail: detail.into(), hint: None }
+    }
+
+    fn warn(check: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { check, status: Status::Warn, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn fail(check: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { check, status: Status::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+/// Every finding from one run, in the order the checks ran
+struct DoctorReport {
+    findings: Vec<Finding>,
+}
+
+impl DoctorReport {
+    fn worst(&self) -> Status {
+        self.findings.iter().map(|finding| finding.status).max().unwrap_or(Status::Ok)
+    }
+}
+
+impl Report for DoctorReport {
+    fn to_json(&self) -> Value {
+        let checks: Vec<_> = self
+            .findings
+            .iter()
+            .map(|finding| json!({
+                "check": finding.check,
+                "status": finding.status.label(),
+                "detail": finding.detail,
+                "hint": finding.hint,
+            }))
+            .collect();
+        json!({ "status": self.worst().label(), "checks": checks })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        for finding in &self.findings {
+            writeln!(out, "[{:<4}] {:<11} {}", finding.status.label(), finding.check, finding.detail)?;
+            if let Some(hint) = &finding.hint {
+                writeln!(out, "       {:<11} -> {}", "", hint)?;
+            }
+        }
+        match self.worst() {
+            Status::Ok => writeln!(out, "\nNo problems found."),
+            Status::Warn => writeln!(out, "\nWarnings found; the server will start but may misbehave."),
+            Status::Fail => writeln!(out, "\nProblems found that will stop the server from running."),
+        }
+    }
+}
+
+/// Runs every check, prints the findings, and returns whether none failed
+pub fn run_doctor(paths: &Paths, port: u16, admin_port: u16, out: &Output) -> io::Result<bool> {
+    let report = DoctorReport {
+        findings: vec![
+            check_port(paths, "port", port, "--port"),
+            check_port(paths, "admin_port", admin_port, "--admin-port"),
+            check_config(&paths.config_file),
+            check_log_dir(&paths.log_file),
+            check_fd_limit(&paths.config_file),
+            check_mmap(&paths.config_file),
+            Finding::ok("tls", "TLS is not configured; nothing to check"),
+        ],
+    };
+    out.emit(&report)?;
+    Ok(report.worst() != Status::Fail)
+}
+
+/// A scratch file beside `file`, for checking what can be done in its directory
+fn probe_path(file: &Path) -> PathBuf {
+    let name = file.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    file.with_file_name(format!(".{}.doctor", name))
+}
+
+/// The directory holding `file`, for messages
+fn directory_of(file: &Path) -> String {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+        _ => "the working directory".to_string(),
+    }
+}
+
+fn check_port(paths: &Paths, check: &'static str, port: u16, flag: &str) -> Finding {
+    match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+        Ok(_) => Finding::ok(check, format!("127.0.0.1:{} is free", port)),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            let running = pidfile::read_pid(&paths.pid_file).ok().flatten().filter(|pid| pidfile::is_running(*pid));
+            match running {
+                Some(pid) => Finding::warn(
+                    check,
+                    format!("127.0.0.1:{} is in use, probably by the running server (pid {})", port, pid),
+                    "stop it with `rustbucket stop` before starting another",
+                ),
+                None => Finding::fail(
+                    check,
+                    format!("127.0.0.1:{} is already in use by another process", port),
+                    format!("free the port or choose another with {}", flag),
+                ),
+            }
+        }
+        Err(e) => Finding::fail(check, format!("cannot bind 127.0.0.1:{}: {}", port, e), format!("choose another port with {}", flag)),
+    }
+}
+
+fn check_config(config_file: &Path) -> Finding {
+    if !config_file.exists() {
+        return Finding::ok("config", format!("{} not found; defaults will be written on startup", config_file.display()));
+    }
+    match read_config(config_file) {
+        Ok(config) if config.verbosity > 3 => Finding::warn(
+            "config",
+            format!("verbosity is {}, outside the supported 0-3", config.verbosity),
+            "set it with `rustbucket update-config --verbosity 1`",
+        ),
+        Ok(config) if config.max_connections == 0 => Finding::warn(
+            "config",
+            "max_connections is 0",
+            "set it with `rustbucket update-config --max-connections 100`",
+        ),
+        Ok(config) if config.timeout_seconds == 0 => Finding::warn(
+            "config",
+            "timeout is 0; connections will time out after 1 second",
+            "set it with `rustbucket update-config --timeout 30`",
+        ),
+        Ok(config) => Finding::ok("config", format!("{} is valid (version {})", config_file.display(), config.version)),
+        Err(e) => Finding::fail(
+            "config",
+            format!("{} cannot be read: {}", config_file.display(), e),
+            format!("delete {} to have the server recreate it with defaults", config_file.display()),
+        ),
+    }
+}
+
+fn check_log_dir(log_file: &Path) -> Finding {
+    let probe = probe_path(log_file);
+    let result = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&probe)
+        .and_then(|mut file| file.write_all(b"probe"));
+    let _ = fs::remove_file(&probe);
+    match result {
+        Ok(()) => Finding::ok("log_dir", format!("can write {} in {}", log_file.display(), directory_of(log_file))),
+        Err(e) => Finding::fail(
+            "log_dir",
+            format!("cannot write to {}: {}", directory_of(log_file), e),
+            "run the server from a writable directory",
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn check_fd_limit(config_file: &Path) -> Finding {
+    let (soft, _) = match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok(limits) => limits,
+        Err(e) => return Finding::warn("fd_limit", format!("cannot read the file descriptor limit: {}", e), "check `ulimit -n`"),
+    };
+    let max_connections = read_config(config_file).map(|config| config.max_connections as u64).unwrap_or(100);
+    let needed = max_connections + RESERVED_FDS;
+    if soft < needed {
+        Finding::warn(
+            "fd_limit",
+            format!("open file limit is {}, below the {} needed for {} connections", soft, needed, max_connections),
+            format!("raise it with `ulimit -n {}` or lower max_connections", needed),
+        )
+    } else {
+        Finding::ok("fd_limit", format!("open file limit is {} ({} needed)", soft, needed))
+    }
+}
+
+#[cfg(not(unix))]
+fn check_fd_limit(_config_file: &Path) -> Finding {
+    Finding::ok("fd_limit", "no open file limit to check on this platform")
+}
+
+fn check_mmap(config_file: &Path) -> Finding {
+    let probe = probe_path(config_file);
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+        .and_then(|file| {
+            file.set_len(16)?;
+            let mut mmap = unsafe { MmapOptions::new().map_mut(&file)? };
+            mmap[0] = 1;
+            mmap.flush()
+        });
+    let _ = fs::remove_file(&probe);
+    match result {
+        Ok(()) => Finding::ok("mmap", "shared memory-mapped config files are supported"),
+        Err(e) => Finding::fail(
+            "mmap",
+            format!("cannot memory-map a file here: {}", e),
+            "run from a local filesystem; some network and virtual filesystems do not support mmap",
+        ),
+    }
+}
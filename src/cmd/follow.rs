@@ -0,0 +1,133 @@
+//! Live entry counter for `count --follow`.
+//!
+//! Polls the log file and counts the lines appended since the last poll, so it
+//! works on a log written by a server in another process. When the file is
+//! rotated or truncated the count starts over on the new file.
+
+use std::fs::{File, Metadata};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::thread;
+use std::time::{Duration, Instant};
+use memchr::memchr_iter;
+use serde_json::{json, Value};
+
+use crate::cmd::output::{Output, Report};
+
+/// One sample of the log's size and growth
+struct LogRate<'a> {
+    log_file: &'a str,
+    entries: u64,
+    per_second: f64,
+}
+
+impl Report for LogRate<'_> {
+    fn to_json(&self) -> Value {
+        json!({
+            "log_file": self.log_file,
+            "entries": self.entries,
+            "entries_per_second": self.per_second,
+            "timestamp": chrono::Local::now().to_rfc3339(),
+        })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.summary())
+    }
+}
+
+impl LogRate<'_> {
+    fn summary(&self) -> String {
+        format!("Total log entries: {} ({:.1}/s)", self.entries, self.per_second)
+    }
+}
+
+/// Read position in the file being followed
+#[derive(Default)]
+struct Cursor {
+    inode: Option<u64>,
+    offset: u64,
+    entries: u64,
+}
+
+impl Cursor {
+    /// Counts lines appended since the last call, restarting if the file was replaced
+    ///
+    /// Returns the number of new lines; a missing file counts as empty.
+    fn advance(&mut self, path: &str) -> io::Result<u64> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                *self = Cursor::default();
+                return Ok(0);
+            }
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata()?;
+        let inode = inode(&metadata);
+        if self.inode != inode || metadata.len() < self.offset {
+            if self.inode.is_some() || self.offset > 0 {
+                log::info!("{} was rotated or truncated; counting from the start", path);
+            }
+            *self = Cursor { inode, ..Cursor::default() };
+        }
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buffer = [0u8; 64 * 1024];
+        let mut added = 0;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            added += memchr_iter(b'\n', &buffer[..read]).count() as u64;
+            self.offset += read as u64;
+        }
+        self.entries += added;
+        Ok(added)
+    }
+}
+
+/// Identifies the file behind the log's path, so that a rotation that replaced it is noticed
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<u64> {
+    Some(metadata.ino())
+}
+
+/// There is no inode to compare outside Unix
+///
+/// Copy-truncate rotation, the default there, is noticed by the file shrinking instead.
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Prints the entry count of `log_file` and its growth rate every `interval` until interrupted
+///
+/// On a terminal the text line is updated in place; otherwise, and in JSON mode,
+/// each sample is printed on its own line.
+pub fn run_follow(log_file: &str, interval: Duration, out: &Output) -> io::Result<()> {
+    let in_place = !out.is_json() && io::stdout().is_terminal();
+    let show = |sample: &LogRate| -> io::Result<()> {
+        if !in_place {
+            return out.emit(sample);
+        }
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "\r\x1b[K{}", sample.summary())?;
+        stdout.flush()
+    };
+
+    let mut cursor = Cursor::default();
+    cursor.advance(log_file)?;
+    show(&LogRate { log_file, entries: cursor.entries, per_second: 0.0 })?;
+
+    let mut last = Instant::now();
+    loop {
+        thread::sleep(interval);
+        let added = cursor.advance(log_file)?;
+        let elapsed = last.elapsed().as_secs_f64().max(f64::EPSILON);
+        last = Instant::now();
+        show(&LogRate { log_file, entries: cursor.entries, per_second: added as f64 / elapsed })?;
+    }
+}
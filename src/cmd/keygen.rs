@@ -0,0 +1,105 @@
+//! Key material for the `keygen` subcommand.
+//!
+//! Generates what local testing needs without reaching for openssl: a
+//! self-signed certificate and key for TLS, and random tokens for
+//! authentication. Secrets are written readable by the owner only, and their
+//! buffers are zeroed once written.
+
+#[cfg(unix)]
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use chrono::{Datelike, Duration as ChronoDuration, Utc};
+use rand::RngCore;
+use rcgen::{date_time_ymd, CertificateParams, DnType, KeyPair};
+use zeroize::Zeroizing;
+
+/// Permissions for private keys and tokens
+const SECRET_MODE: u32 = 0o600;
+/// Permissions for certificates, which are public
+const PUBLIC_MODE: u32 = 0o644;
+
+/// Names and lifetime of a self-signed certificate
+#[derive(Debug, Clone)]
+pub struct CertConfig {
+    /// DNS names and IP addresses the certificate is valid for
+    pub hosts: Vec<String>,
+    /// Days until the certificate expires
+    pub days: u32,
+}
+
+/// PEM-encoded certificate and private key
+pub struct CertPair {
+    pub cert_pem: String,
+    pub key_pem: Zeroizing<String>,
+}
+
+/// Generates a self-signed certificate covering `config.hosts`
+pub fn generate_cert(config: &CertConfig) -> io::Result<CertPair> {
+    let mut params = CertificateParams::new(config.hosts.clone()).map_err(to_io)?;
+    params.distinguished_name.push(DnType::CommonName, config.hosts.first().map(String::as_str).unwrap_or("rustbucket"));
+    params.distinguished_name.push(DnType::OrganizationName, "rustbucket (self-signed, for testing)");
+
+    let today = Utc::now();
+    let expiry = today + ChronoDuration::days(i64::from(config.days.max(1)));
+    params.not_before = date_time_ymd(today.year(), today.month() as u8, today.day() as u8);
+    params.not_after = date_time_ymd(expiry.year(), expiry.month() as u8, expiry.day() as u8);
+
+    let key = KeyPair::generate().map_err(to_io)?;
+    let cert = params.self_signed(&key).map_err(to_io)?;
+    Ok(CertPair { cert_pem: cert.pem(), key_pem: Zeroizing::new(key.serialize_pem()) })
+}
+
+/// Returns `bytes` random bytes from the OS generator, hex-encoded
+pub fn generate_token(bytes: usize) -> Zeroizing<String> {
+    let mut token = Zeroizing::new(vec![0u8; bytes]);
+    rand::rngs::OsRng.fill_bytes(&mut token);
+    Zeroizing::new(token.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Writes a private key or token so only the owner can read it
+pub fn write_secret(path: &Path, contents: &str, overwrite: bool) -> io::Result<()> {
+    write_with_mode(path, contents, SECRET_MODE, overwrite)
+}
+
+/// Writes a certificate or other public material
+pub fn write_public(path: &Path, contents: &str, overwrite: bool) -> io::Result<()> {
+    write_with_mode(path, contents, PUBLIC_MODE, overwrite)
+}
+
+fn write_with_mode(path: &Path, contents: &str, mode: u32, overwrite: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::new(
+            e.kind(),
+            format!("{} already exists; pass --force to overwrite it", path.display()),
+        ),
+        _ => io::Error::new(e.kind(), format!("could not write {}: {}", path.display(), e)),
+    })?;
+    // The mode only applies to newly created files, so tighten an overwritten one too
+    #[cfg(unix)]
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    if cfg!(unix) {
+        log::info!("Wrote {} (mode {:o})", path.display(), mode);
+    } else {
+        log::info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+fn to_io(error: rcgen::Error) -> io::Error {
+    io::Error::other(format!("certificate generation failed: {}", error))
+}
@@ -0,0 +1,88 @@
+//! Commands over the server's log file: `count` and `logs purge`.
+
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use memchr::memchr_iter;
+use serde_json::{json, Value};
+
+use crate::cmd::output::{Output, Report};
+use crate::cmd::rotation;
+
+/// Deletes rotated logs that break `retention`, after confirming unless `yes`
+pub fn purge_logs(log_file: &str, retention: rotation::Retention, dry_run: bool, yes: bool, out: &Output) -> io::Result<()> {
+    let report = rotation::PurgeReport { purges: rotation::plan_purge(log_file, &retention)?, dry_run };
+    if !dry_run && !report.purges.is_empty() {
+        let prompt = format!("Delete {} rotated logs ({} bytes)?", report.purges.len(), report.total_bytes());
+        if !yes && !confirm(&prompt)? {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "purge cancelled; nothing was deleted"));
+        }
+        rotation::purge(&report.purges)?;
+    }
+    out.emit(&report)
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no
+///
+/// Fails rather than guessing when stdin is not a terminal.
+pub fn confirm(prompt: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "refusing to delete without confirmation; pass --yes when not running interactively",
+        ));
+    }
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Number of entries in the current log file, as reported by `count`
+pub struct LogCount<'a> {
+    log_file: &'a Path,
+    /// `None` when the log file does not exist
+    entries: Option<usize>,
+}
+
+impl Report for LogCount<'_> {
+    fn to_json(&self) -> Value {
+        json!({ "log_file": self.log_file.display().to_string(), "exists": self.entries.is_some(), "entries": self.entries.unwrap_or(0) })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self.entries {
+            Some(count) => writeln!(out, "Total log entries: {}", count),
+            None => writeln!(out, "Log file does not exist. No entries to count."),
+        }
+    }
+}
+
+pub fn count_logs(log_file: &Path, out: &Output) -> io::Result<()> {
+    let entries = if log_file.exists() {
+        let file = File::open(log_file)?;
+        file.lock_shared()?;
+        Some(count_lines(file)?)
+    } else {
+        None
+    };
+    out.emit(&LogCount { log_file, entries })
+}
+
+/// Lines in `reader`, counting a last one without a newline as `BufRead::lines` would
+fn count_lines(mut reader: impl Read) -> io::Result<usize> {
+    let mut buffer = vec![0; 64 * 1024];
+    let (mut lines, mut last) = (0, b'\n');
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        lines += memchr_iter(b'\n', &buffer[..read]).count();
+        last = buffer[read - 1];
+    }
+    Ok(lines + usize::from(last != b'\n'))
+}
@@ -0,0 +1,36 @@
+//! Man page generation for the hidden `mangen` subcommand.
+//!
+//! Pages are rendered from the same clap definitions the binary parses, so they
+//! cannot drift from the real flags. Packagers run it at build time:
+//! `rustbucket mangen target/man`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use clap::Command;
+use clap_mangen::Man;
+
+/// Writes `<name>.1` for `command` and `<name>-<subcommand>.1` for every visible subcommand
+///
+/// Returns the paths written, parents before children.
+pub fn write_man_pages(command: Command, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut command = command.disable_help_subcommand(true);
+    // Propagates global flags such as --output into every subcommand's page and
+    // names subcommand pages after their parents
+    command.build();
+    let mut written = Vec::new();
+    write_pages(command, dir, &mut written)?;
+    Ok(written)
+}
+
+fn write_pages(command: Command, dir: &Path, written: &mut Vec<PathBuf>) -> io::Result<()> {
+    let path = Man::new(command.clone()).generate_to(dir)?;
+    log::info!("Wrote {}", path.display());
+    written.push(path);
+
+    for subcommand in command.get_subcommands().filter(|subcommand| !subcommand.is_hide_set()) {
+        write_pages(subcommand.clone(), dir, written)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,20 @@
+//! Implementations of the CLI subcommands.
+
+pub mod backup;
+pub mod bench;
+pub mod client;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod doctor;
+pub mod follow;
+pub mod keygen;
+pub mod logs;
+pub mod mangen;
+pub mod monitor;
+pub mod output;
+pub mod plugins;
+pub mod rotation;
+pub mod selftest;
+pub mod send;
+pub mod shell;
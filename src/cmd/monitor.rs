@@ -0,0 +1,303 @@
+//! `top`-style terminal monitor for a running server.
+//!
+//! Polls the admin interface for counters and open connections, derives
+//! throughput from successive samples, and tails the log file, all on one screen.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+
+use rustbucket::http_client::{self, HttpUrl};
+
+/// Number of throughput samples kept for the graphs
+const HISTORY_LEN: usize = 120;
+/// Bytes read from the end of the log file for the tail view
+const LOG_TAIL_BYTES: u64 = 16 * 1024;
+/// Timeout for each admin request
+const POLL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Counters from the admin `/stats` endpoint that the monitor displays
+#[derive(Debug, Clone, Copy, Default)]
+struct Sample {
+    connections_active: u64,
+    connections_accepted: u64,
+    handler_errors: u64,
+    messages_received: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    pool_workers: u64,
+    pool_active: u64,
+    pool_queued: u64,
+}
+
+impl Sample {
+    fn from_json(stats: &Value) -> Self {
+        let field = |name: &str| stats[name].as_u64().unwrap_or(0);
+        let pool = |name: &str| stats["pool"][name].as_u64().unwrap_or(0);
+        Self {
+            connections_active: field("connections_active"),
+            connections_accepted: field("connections_accepted"),
+            handler_errors: field("handler_errors"),
+            messages_received: field("messages_received"),
+            bytes_received: field("bytes_received"),
+            bytes_sent: field("bytes_sent"),
+            pool_workers: pool("workers"),
+            pool_active: pool("active"),
+            pool_queued: pool("queued"),
+        }
+    }
+}
+
+/// Everything the monitor knows about the server between redraws
+struct MonitorState<'a> {
+    admin_port: u16,
+    log_file: &'a Path,
+    latest: Option<Sample>,
+    previous: Option<(Sample, Instant)>,
+    messages_per_sec: VecDeque<u64>,
+    bytes_per_sec: VecDeque<u64>,
+    connections: Vec<Value>,
+    log_tail: Vec<String>,
+    error: Option<String>,
+}
+
+impl<'a> MonitorState<'a> {
+    fn new(admin_port: u16, log_file: &'a Path) -> Self {
+        Self {
+            admin_port,
+            log_file,
+            latest: None,
+            previous: None,
+            messages_per_sec: VecDeque::with_capacity(HISTORY_LEN),
+            bytes_per_sec: VecDeque::with_capacity(HISTORY_LEN),
+            connections: Vec::new(),
+            log_tail: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Fetches fresh stats and connections and updates the throughput history
+    fn poll(&mut self) {
+        match self.fetch() {
+            Ok((sample, connections)) => {
+                let now = Instant::now();
+                if let Some((previous, at)) = self.previous {
+                    let elapsed = now.duration_since(at).as_secs_f64().max(0.001);
+                    let rate = |current: u64, before: u64| (current.saturating_sub(before) as f64 / elapsed) as u64;
+                    push_bounded(&mut self.messages_per_sec, rate(sample.messages_received, previous.messages_received));
+                    push_bounded(
+                        &mut self.bytes_per_sec,
+                        rate(sample.bytes_received + sample.bytes_sent, previous.bytes_received + previous.bytes_sent),
+                    );
+                }
+                self.previous = Some((sample, now));
+                self.latest = Some(sample);
+                self.connections = connections;
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+        self.log_tail = read_log_tail(self.log_file).unwrap_or_default();
+    }
+
+    fn fetch(&self) -> io::Result<(Sample, Vec<Value>)> {
+        let base = HttpUrl::parse(&format!("http://127.0.0.1:{}", self.admin_port))?;
+        let stats = fetch_json(&base.join("/stats"))?;
+        let connections = fetch_json(&base.join("/connections"))?;
+        let connections = connections["connections"].as_array().cloned().unwrap_or_default();
+        Ok((Sample::from_json(&stats), connections))
+    }
+}
+
+fn fetch_json(url: &HttpUrl) -> io::Result<Value> {
+    let response = http_client::get(url, POLL_TIMEOUT)?;
+    if !response.is_success() {
+        return Err(io::Error::other(format!("{} returned status {}", url.path, response.status)));
+    }
+    serde_json::from_str(&response.body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn push_bounded(history: &mut VecDeque<u64>, value: u64) {
+    if history.len() >= HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(value);
+}
+
+/// Reads the last few kilobytes of `log_file` as lines
+fn read_log_tail(log_file: &Path) -> io::Result<Vec<String>> {
+    let mut file = File::open(log_file)?;
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(LOG_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    let text = String::from_utf8_lossy(&contents);
+
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    // The first line is likely partial when we started mid-file
+    if start > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    Ok(lines)
+}
+
+/// Runs the monitor until the user presses `q`, `Esc`, or `Ctrl+C`
+pub fn run_monitor(log_file: &Path, admin_port: u16, interval: Duration) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = monitor_loop(&mut terminal, log_file, admin_port, interval);
+    ratatui::try_restore()?;
+    result
+}
+
+fn monitor_loop(terminal: &mut DefaultTerminal, log_file: &Path, admin_port: u16, interval: Duration) -> io::Result<()> {
+    let mut state = MonitorState::new(admin_port, log_file);
+    let mut last_poll: Option<Instant> = None;
+
+    loop {
+        if last_poll.is_none_or(|at| at.elapsed() >= interval) {
+            state.poll();
+            last_poll = Some(Instant::now());
+        }
+        terminal.draw(|frame| draw(frame, &state, interval))?;
+
+        if event::poll(Duration::from_millis(100))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, state: &MonitorState, interval: Duration) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Length(7),
+            Constraint::Min(6),
+            Constraint::Length(10),
+        ])
+        .split(frame.area());
+
+    frame.render_widget(summary(state, interval), rows[0]);
+
+    let graphs = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(rows[1]);
+    let messages: Vec<u64> = state.messages_per_sec.iter().copied().collect();
+    let bytes: Vec<u64> = state.bytes_per_sec.iter().copied().collect();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(format!(
+                " Messages/s (now {}) ",
+                messages.last().copied().unwrap_or(0)
+            )))
+            .data(&messages)
+            .style(Style::default().fg(Color::Cyan)),
+        graphs[0],
+    );
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(format!(
+                " Bytes/s in+out (now {}) ",
+                bytes.last().copied().unwrap_or(0)
+            )))
+            .data(&bytes)
+            .style(Style::default().fg(Color::Green)),
+        graphs[1],
+    );
+
+    frame.render_widget(connections_table(state), rows[2]);
+
+    let height = rows[3].height.saturating_sub(2) as usize;
+    let tail: Vec<Line> = state
+        .log_tail
+        .iter()
+        .skip(state.log_tail.len().saturating_sub(height))
+        .map(|line| Line::from(line.as_str()))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(tail).block(Block::default().borders(Borders::ALL).title(format!(" {} ", state.log_file.display()))),
+        rows[3],
+    );
+}
+
+fn summary(state: &MonitorState, interval: Duration) -> Paragraph<'static> {
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let line = match (&state.error, state.latest) {
+        (Some(error), _) => Line::from(vec![
+            Span::styled("UNREACHABLE ", bold.fg(Color::Red)),
+            Span::raw(format!("admin port {}: {}", state.admin_port, error)),
+        ]),
+        (None, Some(sample)) => Line::from(vec![
+            Span::styled("UP ", bold.fg(Color::Green)),
+            Span::raw(format!(
+                "active {}  accepted {}  errors {}  pool {}/{} busy, {} queued",
+                sample.connections_active,
+                sample.connections_accepted,
+                sample.handler_errors,
+                sample.pool_active,
+                sample.pool_workers,
+                sample.pool_queued
+            )),
+        ]),
+        (None, None) => Line::from("connecting..."),
+    };
+    Paragraph::new(line).block(Block::default().borders(Borders::ALL).title(format!(
+        " rustbucket monitor — admin 127.0.0.1:{} — every {:?} — q to quit ",
+        state.admin_port, interval
+    )))
+}
+
+fn connections_table(state: &MonitorState) -> Table<'static> {
+    let rows: Vec<Row> = state
+        .connections
+        .iter()
+        .map(|conn| {
+            Row::new(vec![
+                conn["id"].to_string(),
+                conn["peer"].as_str().unwrap_or("?").to_string(),
+                format!("{:.1}s", conn["age_seconds"].as_f64().unwrap_or(0.0)),
+                conn["messages"].to_string(),
+                conn["bytes_received"].to_string(),
+                conn["bytes_sent"].to_string(),
+            ])
+        })
+        .collect();
+
+    Table::new(
+        rows,
+        [
+            Constraint::Length(6),
+            Constraint::Min(22),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(
+        Row::new(vec!["ID", "PEER", "AGE", "MSGS", "BYTES IN", "BYTES OUT"])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::default().borders(Borders::ALL).title(format!(" Connections ({}) ", state.connections.len())))
+}
@@ -0,0 +1,111 @@
+//! Formatting of command results for people and for automation.
+//!
+//! Commands build a [`Report`] describing their result and hand it to an
+//! [`Output`], which renders it as text or as a single JSON document depending on
+//! the global `--output` flag. Keeping rendering here means JSON field names stay
+//! stable and text output stays consistent across subcommands.
+
+use std::fmt;
+use std::io::{self, Write};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::cmd::console::{self, Style};
+
+/// Output format for command results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// Machine-readable JSON with stable field names
+    Json,
+}
+
+/// A command result that can be rendered in either output format
+pub trait Report {
+    /// Machine-readable form; field names are part of the CLI's stable interface
+    fn to_json(&self) -> Value;
+
+    /// Human-readable form
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Renders reports to stdout in the format chosen on the command line
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    format: OutputFormat,
+    quiet: bool,
+}
+
+impl Output {
+    /// Creates an output writing in `format`
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format, quiet: false }
+    }
+
+    /// Suppresses progress commentary, as requested with `-q`
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Whether results are written as JSON
+    pub fn is_json(&self) -> bool {
+        self.format == OutputFormat::Json
+    }
+
+    /// Prints a command's result
+    ///
+    /// In JSON mode each report is one line, so commands emitting several
+    /// results produce newline-delimited JSON.
+    pub fn emit(&self, report: &dyn Report) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        match self.format {
+            OutputFormat::Json => writeln!(stdout, "{}", report.to_json())?,
+            OutputFormat::Text => report.write_text(&mut stdout)?,
+        }
+        stdout.flush()
+    }
+
+    /// Prints commentary that only makes sense to a person watching
+    ///
+    /// Suppressed in JSON mode so stdout carries nothing but results, and
+    /// with `-q`.
+    pub fn progress(&self, message: fmt::Arguments<'_>) {
+        if !self.is_json() && !self.quiet {
+            println!("{}", message);
+        }
+    }
+
+    /// Reports a command failure on stderr
+    pub fn error(&self, error: &dyn fmt::Display) {
+        match self.format {
+            OutputFormat::Json => eprintln!("{}", json!({ "error": error.to_string() })),
+            OutputFormat::Text => eprintln!("{} {}", console::paint(Style::Error, "Error:"), error),
+        }
+    }
+}
+
+/// A result that reads as one sentence but carries structured fields for JSON
+pub struct Message {
+    text: String,
+    fields: Value,
+}
+
+impl Message {
+    /// Creates a message shown as `text`, or as `fields` in JSON mode
+    pub fn new(text: impl Into<String>, fields: Value) -> Self {
+        Self { text: text.into(), fields }
+    }
+}
+
+impl Report for Message {
+    fn to_json(&self) -> Value {
+        self.fields.clone()
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.text)
+    }
+}
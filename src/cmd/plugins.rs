@@ -0,0 +1,41 @@
+//! The `plugins` subcommand: lists the handler plugins a server could load.
+
+use std::io::{self, Write};
+use std::path::Path;
+use serde_json::{json, Value};
+use rustbucket::plugins::{self, Plugin};
+
+use crate::cmd::output::{Output, Report};
+
+/// Plugins found in a directory, as reported by `plugins`
+pub struct PluginList<'a> {
+    dir: &'a Path,
+    plugins: Vec<Plugin>,
+}
+
+impl Report for PluginList<'_> {
+    fn to_json(&self) -> Value {
+        let plugins: Vec<Value> = self
+            .plugins
+            .iter()
+            .map(|plugin| json!({ "name": plugin.name(), "path": plugin.path().display().to_string() }))
+            .collect();
+        json!({ "dir": self.dir.display().to_string(), "plugins": plugins })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.plugins.is_empty() {
+            return writeln!(out, "No plugins in {}", self.dir.display());
+        }
+        for plugin in &self.plugins {
+            writeln!(out, "{:<16} {}", plugin.name(), plugin.path().display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads every plugin in `dir` and prints its name and library
+pub fn list_plugins(dir: &Path, out: &Output) -> rustbucket::Result<()> {
+    let plugins = plugins::discover(dir)?;
+    Ok(out.emit(&PluginList { dir, plugins })?)
+}
@@ -0,0 +1,352 @@
+//! Log rotation and retention for the `rotate` and `logs purge` subcommands.
+//!
+//! Changes are planned before anything is touched: the plan lists every file
+//! that will be removed, renamed, or compressed, so `--dry-run` can show it
+//! exactly and a real run applies the same steps in order.
+//!
+//! The current log is moved aside either by renaming it, after which the server
+//! must be told to reopen its log, or by copying it and truncating it in place.
+//! Windows cannot rename a file that another process holds open without sharing
+//! it for deletion, so copying is the default there.
+
+use std::cmp::Reverse;
+use std::fs::{self, remove_file, rename, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+
+use crate::cmd::output::Report;
+
+/// Suffix of compressed backups
+const GZ_SUFFIX: &str = ".gz";
+
+/// How the current log is moved into the first backup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Rename the log; the server keeps writing to the renamed file until it reopens its log
+    #[cfg_attr(not(windows), default)]
+    Rename,
+    /// Copy the log, then truncate it in place; the server keeps its file and needs no reload
+    #[cfg_attr(windows, default)]
+    CopyTruncate,
+}
+
+/// Retention settings for one rotation
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// Numbered backups to keep; older ones are deleted
+    pub keep: u32,
+    /// Gzip backups after shifting them
+    pub compress: bool,
+    /// Skip rotation while the current log is smaller than this
+    pub min_size: u64,
+    /// How the current log becomes the first backup
+    pub strategy: Strategy,
+}
+
+/// One filesystem change made by a rotation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Remove(String),
+    Rename { from: String, to: String },
+    /// Gzips `from` into `to` and removes `from`
+    Compress { from: String, to: String },
+    /// Copies `from` into `to`, then empties `from` without replacing it
+    CopyTruncate { from: String, to: String },
+}
+
+impl Action {
+    fn apply(&self) -> io::Result<()> {
+        match self {
+            Action::Remove(path) => remove_file(path)?,
+            Action::Rename { from, to } => rename(from, to)?,
+            Action::Compress { from, to } => {
+                let mut input = BufReader::new(File::open(from)?);
+                let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.flush()?;
+                remove_file(from)?;
+            }
+            Action::CopyTruncate { from, to } => copy_truncate(from, to)?,
+        }
+        log::info!("{}", self.describe());
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Action::Remove(path) => format!("Removed {}", path),
+            Action::Rename { from, to } => format!("Renamed {} to {}", from, to),
+            Action::Compress { from, to } => format!("Compressed {} into {}", from, to),
+            Action::CopyTruncate { from, to } => format!("Copied {} to {} and truncated it", from, to),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Action::Remove(path) => json!({ "action": "remove", "path": path }),
+            Action::Rename { from, to } => json!({ "action": "rename", "from": from, "to": to }),
+            Action::Compress { from, to } => json!({ "action": "compress", "from": from, "to": to }),
+            Action::CopyTruncate { from, to } => json!({ "action": "copy_truncate", "from": from, "to": to }),
+        }
+    }
+}
+
+/// Copies the log at `from` into `to` and truncates it, holding its lock throughout
+///
+/// Writers that take the lock, as every write does on Windows and batched and
+/// ring-buffered writes do elsewhere, wait rather than write between the copy
+/// and the truncation. A single line written without the lock in that window is
+/// lost, the usual price of rotating by copy.
+fn copy_truncate(from: &str, to: &str) -> io::Result<()> {
+    let mut log = OpenOptions::new().read(true).write(true).open(from)?;
+    log.lock()?;
+    let result = (|| {
+        let mut backup = BufWriter::new(File::create(to)?);
+        io::copy(&mut log, &mut backup)?;
+        backup.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        log.set_len(0)
+    })();
+    log.unlock()?;
+    result
+}
+
+/// Steps a rotation will take, or why it will not run
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+    /// Set when `--min-size` holds the rotation back
+    pub skipped: Option<String>,
+}
+
+/// A numbered backup such as `http.log.3` or `http.log.3.gz`
+struct Backup {
+    index: u32,
+    path: String,
+    compressed: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Finds the numbered backups of `log_file`, newest first
+fn find_backups(log_file: &str) -> io::Result<Vec<Backup>> {
+    let path = Path::new(log_file);
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.", path.file_name().and_then(|name| name.to_str()).unwrap_or(log_file));
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(rest) = name.to_str().and_then(|name| name.strip_prefix(&prefix)) else {
+            continue;
+        };
+        let (number, compressed) = match rest.strip_suffix(GZ_SUFFIX) {
+            Some(number) => (number, true),
+            None => (rest, false),
+        };
+        if let Ok(index) = number.parse::<u32>() {
+            let path = format!("{}.{}{}", log_file, index, if compressed { GZ_SUFFIX } else { "" });
+            let metadata = fs::metadata(&path)?;
+            backups.push(Backup { index, path, compressed, size: metadata.len(), modified: metadata.modified()? });
+        }
+    }
+    backups.sort_by_key(|backup| Reverse(backup.index));
+    Ok(backups)
+}
+
+/// Lists the steps that shift `log_file` into numbered backups under `policy`
+///
+/// Backups are shifted oldest first so each rename targets a name already freed
+/// by an earlier step; anything that would end up past `keep` is deleted.
+pub fn plan(log_file: &str, policy: &Policy) -> io::Result<Plan> {
+    let size = match fs::metadata(log_file) {
+        Ok(metadata) => Some(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if size.unwrap_or(0) < policy.min_size {
+        return Ok(Plan {
+            actions: Vec::new(),
+            skipped: Some(format!(
+                "{} is {} bytes, below the minimum of {}",
+                log_file,
+                size.unwrap_or(0),
+                policy.min_size
+            )),
+        });
+    }
+
+    let numbered = |index: u32, compressed: bool| {
+        format!("{}.{}{}", log_file, index, if compressed { GZ_SUFFIX } else { "" })
+    };
+    let mut actions = Vec::new();
+    let mut uncompressed = Vec::new();
+
+    for backup in find_backups(log_file)? {
+        if backup.index >= policy.keep {
+            actions.push(Action::Remove(backup.path));
+            continue;
+        }
+        let to = numbered(backup.index + 1, backup.compressed);
+        if !backup.compressed {
+            uncompressed.push(to.clone());
+        }
+        actions.push(Action::Rename { from: backup.path, to });
+    }
+
+    // Move the current log file to .1
+    if size.is_some() {
+        let to = numbered(1, false);
+        uncompressed.push(to.clone());
+        let from = log_file.to_string();
+        actions.push(match policy.strategy {
+            Strategy::Rename => Action::Rename { from, to },
+            Strategy::CopyTruncate => Action::CopyTruncate { from, to },
+        });
+    }
+
+    if policy.compress {
+        for from in uncompressed {
+            let to = format!("{}{}", from, GZ_SUFFIX);
+            actions.push(Action::Compress { from, to });
+        }
+    }
+
+    Ok(Plan { actions, skipped: None })
+}
+
+/// Limits enforced by `logs purge`
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Delete backups last modified longer ago than this
+    pub older_than: Option<Duration>,
+    /// Delete the oldest backups until the rest fit in this many bytes
+    pub max_size: Option<u64>,
+}
+
+/// A rotated log chosen for deletion
+#[derive(Debug, Clone)]
+pub struct Purge {
+    pub path: String,
+    pub size: u64,
+    pub reason: &'static str,
+}
+
+/// Lists the rotated logs of `log_file` that break `retention`, oldest last
+///
+/// The current log is never purged; rotate it first if it should be covered.
+pub fn plan_purge(log_file: &str, retention: &Retention) -> io::Result<Vec<Purge>> {
+    let now = SystemTime::now();
+    let mut kept_bytes = 0u64;
+    let mut purges = Vec::new();
+
+    // Newest first, so the size budget is spent on the most recent backups
+    let mut backups = find_backups(log_file)?;
+    backups.reverse();
+    for backup in backups {
+        let age = now.duration_since(backup.modified).unwrap_or_default();
+        let reason = if retention.older_than.is_some_and(|limit| age > limit) {
+            Some("age")
+        } else if retention.max_size.is_some_and(|budget| kept_bytes + backup.size > budget) {
+            Some("size")
+        } else {
+            kept_bytes += backup.size;
+            None
+        };
+        if let Some(reason) = reason {
+            purges.push(Purge { path: backup.path, size: backup.size, reason });
+        }
+    }
+    Ok(purges)
+}
+
+/// Deletes the files chosen by [`plan_purge`], stopping at the first failure
+pub fn purge(purges: &[Purge]) -> io::Result<()> {
+    purges.iter().try_for_each(|purge| Action::Remove(purge.path.clone()).apply())
+}
+
+/// Carries out the steps from [`plan`], stopping at the first failure
+pub fn apply(actions: &[Action]) -> io::Result<()> {
+    actions.iter().try_for_each(Action::apply)
+}
+
+/// Result of `rotate`, or what it would do under `--dry-run`
+pub struct RotationReport {
+    pub log_file: String,
+    pub plan: Plan,
+    pub dry_run: bool,
+}
+
+impl Report for RotationReport {
+    fn to_json(&self) -> Value {
+        json!({
+            "rotated": !self.dry_run && self.plan.skipped.is_none(),
+            "dry_run": self.dry_run,
+            "log_file": self.log_file,
+            "skipped": self.plan.skipped,
+            "actions": self.plan.actions.iter().map(Action::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(reason) = &self.plan.skipped {
+            return writeln!(out, "Rotation skipped: {}", reason);
+        }
+        if !self.dry_run {
+            return writeln!(out, "Log files rotated successfully");
+        }
+        if self.plan.actions.is_empty() {
+            return writeln!(out, "Nothing to rotate");
+        }
+        for action in &self.plan.actions {
+            match action {
+                Action::Remove(path) => writeln!(out, "Would remove {}", path)?,
+                Action::Rename { from, to } => writeln!(out, "Would rename {} -> {}", from, to)?,
+                Action::Compress { from, to } => writeln!(out, "Would compress {} -> {}", from, to)?,
+                Action::CopyTruncate { from, to } => writeln!(out, "Would copy {} -> {} and truncate it", from, to)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Result of `logs purge`, or what it would delete under `--dry-run`
+pub struct PurgeReport {
+    pub purges: Vec<Purge>,
+    pub dry_run: bool,
+}
+
+impl PurgeReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.purges.iter().map(|purge| purge.size).sum()
+    }
+}
+
+impl Report for PurgeReport {
+    fn to_json(&self) -> Value {
+        let files: Vec<_> = self
+            .purges
+            .iter()
+            .map(|purge| json!({ "path": purge.path, "bytes": purge.size, "reason": purge.reason }))
+            .collect();
+        json!({ "dry_run": self.dry_run, "files": files, "bytes": self.total_bytes() })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.purges.is_empty() {
+            return writeln!(out, "No rotated logs to purge");
+        }
+        let verb = if self.dry_run { "Would delete" } else { "Deleted" };
+        for purge in &self.purges {
+            writeln!(out, "{} {} ({} bytes, {})", verb, purge.path, purge.size, purge.reason)?;
+        }
+        writeln!(out, "{} {} files, {} bytes", verb, self.purges.len(), self.total_bytes())
+    }
+}
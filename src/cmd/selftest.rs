@@ -0,0 +1,337 @@
+//! End-to-end self test run by the `selftest` subcommand.
+//!
+//! Starts this binary as a server on free ports inside a scratch directory, so
+//! it never touches a real server's pidfile, log, or config, then exercises it
+//! the way a client and an operator would and reports each check.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use nix::sys::signal::Signal;
+use serde_json::{json, Value};
+
+use rustbucket::http_client::{self, HttpResponse, HttpUrl};
+use crate::cmd::output::{Output, Report};
+use rustbucket::Paths;
+#[cfg(unix)]
+use rustbucket::pidfile;
+
+/// How long to wait for the server to come up or go down
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout for each client exchange
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Concurrent clients in the concurrency check
+const CONCURRENT_CLIENTS: usize = 8;
+
+/// A server process started for the test, killed if the test ends early
+struct TestServer {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+    admin_port: u16,
+}
+
+impl TestServer {
+    fn start(dir: &Path) -> io::Result<Self> {
+        let port = free_port()?;
+        let admin_port = free_port()?;
+        let output = File::create(dir.join("server.out"))?;
+        let child = Command::new(std::env::current_exe()?)
+            .args(["run", "--port", &port.to_string(), "--admin-port", &admin_port.to_string(), "--threads", "4"])
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stdout(output.try_clone()?)
+            .stderr(output)
+            .spawn()?;
+        log::debug!("Spawned test server as pid {}", child.id());
+        Ok(Self { child, dir: dir.to_path_buf(), port, admin_port })
+    }
+
+    fn connect(&self) -> io::Result<(TcpStream, BufReader<TcpStream>)> {
+        let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok((stream, reader))
+    }
+
+    fn admin(&self, method: &str, path: &str) -> io::Result<HttpResponse> {
+        let url = HttpUrl::parse(&format!("http://127.0.0.1:{}{}", self.admin_port, path))?;
+        let response = match method {
+            "POST" => http_client::post(&url, "", IO_TIMEOUT),
+            _ => http_client::get(&url, IO_TIMEOUT),
+        }?;
+        Ok(response)
+    }
+
+    /// Asks the server to shut down gracefully, as an operator would
+    #[cfg(unix)]
+    fn request_shutdown(&self) -> Result<(), String> {
+        pidfile::signal(self.child.id() as i32, Signal::SIGTERM).map_err(|e| e.to_string())
+    }
+
+    /// Asks the server to shut down gracefully through the admin interface, there being no SIGTERM
+    #[cfg(not(unix))]
+    fn request_shutdown(&self) -> Result<(), String> {
+        match self.admin("POST", "/drain") {
+            Ok(response) if response.status == 200 => Ok(()),
+            Ok(response) => Err(format!("/drain answered {}", response.status)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Asks the OS for a port that is currently free
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port())
+}
+
+/// Sends one line and returns the reply without its newline
+fn exchange(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, message: &str) -> io::Result<String> {
+    stream.write_all(format!("{}\n", message).as_bytes())?;
+    let mut reply = String::new();
+    if reader.read_line(&mut reply)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"));
+    }
+    Ok(reply.trim_end().to_string())
+}
+
+fn expect_echo(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, message: &str) -> Result<(), String> {
+    let reply = exchange(stream, reader, message).map_err(|e| e.to_string())?;
+    let expected = format!("Echo: {}", message);
+    if reply == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {:?}, got {:?}", expected, reply))
+    }
+}
+
+fn check_started(server: &mut TestServer) -> Result<(), String> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if let Ok(Some(status)) = server.child.try_wait() {
+            return Err(format!("server exited during startup ({})", status));
+        }
+        if let Ok(response) = server.admin("GET", "/status") {
+            let status: Value = serde_json::from_str(&response.body).unwrap_or_default();
+            if response.is_success() && !status["listen_address"].is_null() {
+                return Ok(());
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("server did not start within {}s", STARTUP_TIMEOUT.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn check_echo(server: &mut TestServer) -> Result<(), String> {
+    let (mut stream, mut reader) = server.connect().map_err(|e| e.to_string())?;
+    expect_echo(&mut stream, &mut reader, "hello")
+}
+
+fn check_multiple_messages(server: &mut TestServer) -> Result<(), String> {
+    let (mut stream, mut reader) = server.connect().map_err(|e| e.to_string())?;
+    for i in 0..10 {
+        expect_echo(&mut stream, &mut reader, &format!("message {}", i))?;
+    }
+    Ok(())
+}
+
+fn check_concurrent_clients(server: &mut TestServer) -> Result<(), String> {
+    let clients: Vec<_> = (0..CONCURRENT_CLIENTS)
+        .map(|i| {
+            let connection = server.connect();
+            thread::spawn(move || {
+                let (mut stream, mut reader) = connection.map_err(|e| e.to_string())?;
+                (0..5).try_for_each(|j| expect_echo(&mut stream, &mut reader, &format!("client {} message {}", i, j)))
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().map_err(|_| "client thread panicked".to_string())??;
+    }
+    Ok(())
+}
+
+fn check_idle_timeout(server: &mut TestServer) -> Result<(), String> {
+    // With a one-second read timeout, an idle connection is polled rather than dropped
+    let response = server.admin("POST", "/config?timeout_seconds=1").map_err(|e| e.to_string())?;
+    if !response.is_success() {
+        return Err(format!("could not lower the timeout: status {}", response.status));
+    }
+    let (mut stream, mut reader) = server.connect().map_err(|e| e.to_string())?;
+    expect_echo(&mut stream, &mut reader, "before idling")?;
+    thread::sleep(Duration::from_millis(1500));
+    expect_echo(&mut stream, &mut reader, "after idling")
+}
+
+fn check_admin(server: &mut TestServer) -> Result<(), String> {
+    let health = server.admin("GET", "/health").map_err(|e| e.to_string())?;
+    if health.status != 200 {
+        return Err(format!("/health returned {}", health.status));
+    }
+    let metrics = server.admin("GET", "/metrics").map_err(|e| e.to_string())?;
+    if !metrics.body.contains("rustbucket_connections_accepted_total") {
+        return Err("/metrics is missing rustbucket_connections_accepted_total".to_string());
+    }
+    Ok(())
+}
+
+fn check_graceful_shutdown(server: &mut TestServer) -> Result<(), String> {
+    let (mut stream, mut reader) = server.connect().map_err(|e| e.to_string())?;
+    expect_echo(&mut stream, &mut reader, "before shutdown")?;
+
+    server.request_shutdown()?;
+    // Connections idle between requests are told the server is closing, then closed
+    let mut notice = String::new();
+    reader.read_line(&mut notice).map_err(|e| e.to_string())?;
+    if notice != "Server closing\n" {
+        return Err(format!("expected a closing notice, got {:?}", notice));
+    }
+    drop(reader);
+    drop(stream);
+
+    // With nothing left to serve, the server exits without waiting for another client to connect
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    loop {
+        match server.child.try_wait() {
+            Ok(Some(status)) if status.success() => break,
+            Ok(Some(status)) => return Err(format!("server exited with {}", status)),
+            Ok(None) if Instant::now() >= deadline => {
+                return Err(format!("server did not exit within {}s", SHUTDOWN_TIMEOUT.as_secs()))
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    let log_file = Paths::in_dir(&server.dir).log_file;
+    let log = fs::read_to_string(&log_file).map_err(|e| e.to_string())?;
+    if !log.contains("Server shutdown complete") {
+        return Err(format!("{} does not record a completed shutdown", log_file.display()));
+    }
+    Ok(())
+}
+
+/// Outcome of one check
+struct CheckResult {
+    name: &'static str,
+    duration: Duration,
+    failure: Option<String>,
+}
+
+/// Outcome of the whole run
+struct SelftestReport {
+    total: usize,
+    results: Vec<CheckResult>,
+    /// Scratch directory, when it was kept for inspection
+    kept_dir: Option<PathBuf>,
+}
+
+impl SelftestReport {
+    fn failures(&self) -> usize {
+        self.results.iter().filter(|result| result.failure.is_some()).count()
+    }
+}
+
+impl Report for SelftestReport {
+    fn to_json(&self) -> Value {
+        let checks: Vec<_> = self
+            .results
+            .iter()
+            .map(|result| json!({
+                "name": result.name,
+                "status": if result.failure.is_some() { "fail" } else { "pass" },
+                "duration_ms": result.duration.as_millis() as u64,
+                "reason": result.failure,
+            }))
+            .collect();
+        json!({
+            "passed": self.failures() == 0,
+            "total": self.total,
+            "checks": checks,
+            "dir": self.kept_dir.as_ref().map(|dir| dir.display().to_string()),
+        })
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self.failures() {
+            0 => writeln!(out, "\nAll {} checks passed", self.total)?,
+            failures => writeln!(out, "\n{} of {} checks failed", failures, self.total)?,
+        }
+        if let Some(dir) = &self.kept_dir {
+            writeln!(out, "Server output and logs kept in {}", dir.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the last check, after how the server is asked to shut down
+const SHUTDOWN_CHECK: &str =
+    if cfg!(unix) { "shuts down gracefully on SIGTERM" } else { "shuts down gracefully on POST /drain" };
+
+/// Runs every check and returns whether all of them passed
+pub fn run_selftest(keep: bool, out: &Output) -> io::Result<bool> {
+    let dir = std::env::temp_dir().join(format!("rustbucket-selftest-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let mut server = TestServer::start(&dir)?;
+    out.progress(format_args!(
+        "Started test server on port {} (admin {}) in {}",
+        server.port,
+        server.admin_port,
+        dir.display()
+    ));
+
+    type Check = fn(&mut TestServer) -> Result<(), String>;
+    let checks: [(&str, Check); 7] = [
+        ("server starts", check_started),
+        ("echoes a message", check_echo),
+        ("echoes several messages on one connection", check_multiple_messages),
+        ("serves concurrent clients", check_concurrent_clients),
+        ("idle connection survives read timeouts", check_idle_timeout),
+        ("admin interface answers", check_admin),
+        (SHUTDOWN_CHECK, check_graceful_shutdown),
+    ];
+
+    let mut results = Vec::with_capacity(checks.len());
+    for (index, (name, check)) in checks.iter().enumerate() {
+        let started = Instant::now();
+        let failure = check(&mut server).err();
+        let duration = started.elapsed();
+        match &failure {
+            None => out.progress(format_args!("PASS  {} ({} ms)", name, duration.as_millis())),
+            Some(reason) => out.progress(format_args!("FAIL  {}: {}", name, reason)),
+        }
+        let failed = failure.is_some();
+        results.push(CheckResult { name, duration, failure });
+        // Nothing else can run without a server
+        if failed && index == 0 {
+            break;
+        }
+    }
+    drop(server);
+
+    let mut report = SelftestReport { total: checks.len(), results, kept_dir: None };
+    let passed = report.failures() == 0;
+    if passed && !keep {
+        fs::remove_dir_all(&dir)?;
+    } else {
+        report.kept_dir = Some(dir);
+    }
+    out.emit(&report)?;
+    Ok(passed)
+}
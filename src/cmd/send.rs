@@ -0,0 +1,183 @@
+//! Streams stdin to a running server for the `send` subcommand.
+//!
+//! Unlike `client`, which formats each exchange for a person, `send` moves bytes:
+//! whatever the server replies is written to stdout untouched, so it composes
+//! with other tools in a shell pipeline. Given Noise keys, it encrypts the
+//! connection as a server started with `--noise-key` expects.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+use clap::ValueEnum;
+use rustbucket::noise::{NoiseKey, NoiseStream, PublicKey};
+
+/// How stdin is split into messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Framing {
+    /// One message per line; waits for each reply before sending the next line
+    #[default]
+    Line,
+    /// Stream bytes as they arrive and copy replies back concurrently
+    Raw,
+}
+
+/// Where and how to send
+#[derive(Debug, Clone)]
+pub struct SendConfig {
+    pub host: String,
+    pub port: u16,
+    pub framing: Framing,
+    pub connect_timeout: Duration,
+    /// Longest wait for the server to reply before giving up
+    pub timeout: Duration,
+    /// The client's Noise key and the server's public key, to encrypt the connection with
+    pub noise: Option<(NoiseKey, PublicKey)>,
+}
+
+/// A connection `send` can split into a reading and a writing half
+trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Tells the server nothing more is coming
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl Connection for NoiseStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        NoiseStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.get_ref().shutdown(Shutdown::Write)
+    }
+}
+
+/// Byte counts for a completed transfer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Transfer {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Sends stdin to the server over one connection and copies its replies to stdout
+pub fn run_send(config: &SendConfig) -> io::Result<Transfer> {
+    let stream = connect(config)?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    log::info!("Connected to {}", stream.peer_addr()?);
+
+    let transfer = match &config.noise {
+        Some((key, server_key)) => send(NoiseStream::connect(stream, key, server_key)?, config)?,
+        None => send(stream, config)?,
+    };
+    log::info!("Sent {} bytes, received {} bytes", transfer.sent, transfer.received);
+    Ok(transfer)
+}
+
+fn send(stream: impl Connection, config: &SendConfig) -> io::Result<Transfer> {
+    match config.framing {
+        Framing::Line => send_lines(stream, io::stdin().lock(), &mut io::stdout().lock(), config.timeout),
+        Framing::Raw => send_raw(stream, &mut io::stdout().lock(), config.timeout),
+    }
+}
+
+fn connect(config: &SendConfig) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in (config.host.as_str(), config.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, config.connect_timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let error = last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses resolved"));
+    Err(io::Error::new(
+        error.kind(),
+        format!("could not connect to {}:{}: {}", config.host, config.port, error),
+    ))
+}
+
+/// Sends each line in its own write and waits for the reply line
+fn send_lines(
+    stream: impl Connection,
+    input: impl BufRead,
+    output: &mut impl Write,
+    timeout: Duration,
+) -> io::Result<Transfer> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut transfer = Transfer::default();
+    let mut reply = Vec::new();
+
+    for line in input.split(b'\n') {
+        let mut line = line?;
+        // The server replies once per read, so send the whole line in one write
+        line.push(b'\n');
+        writer.write_all(&line)?;
+        transfer.sent += line.len() as u64;
+
+        reply.clear();
+        match reader.read_until(b'\n', &mut reply) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")),
+            Ok(n) => transfer.received += n as u64,
+            Err(e) if is_timeout(&e) => return Err(timed_out(timeout)),
+            Err(e) => return Err(e),
+        }
+        output.write_all(&reply)?;
+        output.flush()?;
+    }
+    writer.shutdown_write()?;
+    Ok(transfer)
+}
+
+/// Copies stdin to the server on a separate thread while replies are copied to `output`
+///
+/// Once stdin ends the write side is shut down, and the transfer finishes when
+/// the server closes the connection.
+fn send_raw(stream: impl Connection, output: &mut impl Write, timeout: Duration) -> io::Result<Transfer> {
+    let mut writer = stream.try_clone()?;
+    let sender = thread::spawn(move || -> io::Result<u64> {
+        let sent = io::copy(&mut io::stdin().lock(), &mut writer)?;
+        writer.shutdown_write()?;
+        Ok(sent)
+    });
+
+    let mut reader = stream;
+    let mut buffer = [0u8; 8192];
+    let mut received = 0u64;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                output.write_all(&buffer[..n])?;
+                output.flush()?;
+                received += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Still reading stdin, so the server has had nothing to answer yet
+            Err(e) if is_timeout(&e) && !sender.is_finished() => continue,
+            Err(e) if is_timeout(&e) => return Err(timed_out(timeout)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let sent = sender.join().map_err(|_| io::Error::other("stdin reader panicked"))??;
+    Ok(Transfer { sent, received })
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn timed_out(timeout: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("no reply from the server within {}s", timeout.as_secs()))
+}
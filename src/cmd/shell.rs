@@ -0,0 +1,201 @@
+//! Interactive admin shell for a running server.
+//!
+//! Wraps the admin interface in a prompt with history and tab completion, so
+//! operators can inspect and steer a server without composing curl commands.
+
+use std::io;
+use std::path::PathBuf;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use serde_json::Value;
+
+use crate::cmd::output::{Output, OutputFormat};
+use crate::cmd::control::{admin_request, fetch_admin_json, reload_server, show_stats};
+
+const HISTORY_FILE: &str = ".rustbucket_history";
+
+/// Commands understood by the shell, with their usage text
+const COMMANDS: [(&str, &str); 10] = [
+    ("stats", "Show live counters"),
+    ("connections", "List open connections"),
+    ("config", "Show the live configuration"),
+    ("set", "set <verbosity|max_connections|timeout> <value> - Change a config field"),
+    ("kill", "kill <id> - Close the connection with the given id"),
+    ("drain", "Stop accepting connections and shut down gracefully"),
+    ("reload", "Re-read the configuration and reopen log files"),
+    ("health", "Show the health check result"),
+    ("help", "Show this list"),
+    ("quit", "Leave the shell"),
+];
+
+/// Config fields accepted by `set`, with the admin interface's name for each
+const CONFIG_FIELDS: [(&str, &str); 3] = [
+    ("verbosity", "verbosity"),
+    ("max_connections", "max_connections"),
+    ("timeout", "timeout_seconds"),
+];
+
+/// Tab completion for command names, config fields, and live connection ids
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ShellHelper {
+    admin_port: u16,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let previous: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let options: Vec<String> = match previous.as_slice() {
+            [] => COMMANDS.iter().map(|(name, _)| name.to_string()).collect(),
+            ["set"] => CONFIG_FIELDS.iter().map(|(name, _)| name.to_string()).collect(),
+            ["kill"] => connection_ids(self.admin_port),
+            _ => Vec::new(),
+        };
+        let candidates = options
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .map(|option| Pair { display: option.clone(), replacement: option })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+/// Ids of the server's open connections, or none if it cannot be reached
+fn connection_ids(admin_port: u16) -> Vec<String> {
+    fetch_admin_json(admin_port, "/connections")
+        .ok()
+        .and_then(|body| body["connections"].as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(|conn| conn["id"].to_string())
+        .collect()
+}
+
+/// Runs the shell until the user quits or closes stdin
+pub fn run_shell(admin_port: u16) -> io::Result<()> {
+    let status = fetch_admin_json(admin_port, "/status")?;
+    println!(
+        "Connected to rustbucket (pid {}) on admin port {}. Type `help` for commands.",
+        status["pid"], admin_port
+    );
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(io::Error::other)?;
+    editor.set_helper(Some(ShellHelper { admin_port }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        match editor.readline("rustbucket> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(line);
+                match execute(admin_port, line) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => eprintln!("error: {}", e),
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(io::Error::other(e)),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            log::warn!("Failed to save history to {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+/// Runs one command line, returning `false` when the shell should exit
+fn execute(admin_port: u16, line: &str) -> io::Result<bool> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["help"] => {
+            for (name, usage) in COMMANDS {
+                println!("  {:<12} {}", name, usage);
+            }
+        }
+        ["stats"] => show_stats(admin_port, &Output::new(OutputFormat::Text))?,
+        ["connections"] => print_connections(&fetch_admin_json(admin_port, "/connections")?),
+        ["config"] => {
+            let config = fetch_admin_json(admin_port, "/config")?;
+            if let Some(fields) = config.as_object() {
+                for (key, value) in fields {
+                    println!("  {:<16} {}", key, value);
+                }
+            }
+        }
+        ["set", field, value] => {
+            let Some((_, name)) = CONFIG_FIELDS.iter().find(|(alias, name)| alias == field || name == field) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown config field: {}", field)));
+            };
+            let config = admin_request(admin_port, "POST", &format!("/config?{}={}", name, value))?;
+            println!("{} = {} (config version {})", name, config[name], config["version"]);
+        }
+        ["kill", id] => {
+            admin_request(admin_port, "POST", &format!("/connections/{}/close", id))?;
+            println!("Closed connection #{}", id);
+        }
+        ["drain"] => {
+            admin_request(admin_port, "POST", "/drain")?;
+            println!("Server is draining; it will exit once active connections finish");
+        }
+        ["reload"] => reload_server(admin_port, &Output::new(OutputFormat::Text))?,
+        ["health"] => {
+            // `/health` answers 503 while draining, so read the same flag from `/status`
+            let status = fetch_admin_json(admin_port, "/status")?;
+            let draining = status["shutting_down"].as_bool().unwrap_or(false);
+            println!("{}", if draining { "shutting down" } else { "ok" });
+        }
+        ["quit" | "exit"] => return Ok(false),
+        [command, ..] => {
+            let usage = COMMANDS.iter().find(|(name, _)| name == command).map(|(_, usage)| *usage);
+            match usage {
+                Some(usage) => eprintln!("usage: {}", usage),
+                None => eprintln!("unknown command: {} (try `help`)", command),
+            }
+        }
+        [] => {}
+    }
+    Ok(true)
+}
+
+fn print_connections(body: &Value) {
+    let connections = body["connections"].as_array().cloned().unwrap_or_default();
+    if connections.is_empty() {
+        println!("No open connections");
+        return;
+    }
+    println!("{:>6}  {:<22} {:>8} {:>8} {:>10} {:>10}", "ID", "PEER", "AGE", "MSGS", "IN", "OUT");
+    for conn in connections {
+        println!(
+            "{:>6}  {:<22} {:>7.1}s {:>8} {:>10} {:>10}",
+            conn["id"].as_u64().unwrap_or(0),
+            conn["peer"].as_str().unwrap_or(""),
+            conn["age_seconds"].as_f64().unwrap_or(0.0),
+            conn["messages"].as_u64().unwrap_or(0),
+            conn["bytes_received"].as_u64().unwrap_or(0),
+            conn["bytes_sent"].as_u64().unwrap_or(0)
+        );
+    }
+}
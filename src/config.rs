@@ -0,0 +1,286 @@
+//! Runtime configuration shared between the server and the CLI.
+//!
+//! By default the configuration lives in a small fixed-size binary file that
+//! the server memory-maps, so `update-config` (or the admin interface) can
+//! change it while the server runs and new connections pick the change up.
+//! The server reads it through a [`ConfigSource`], so embedders can take it
+//! from the environment, from memory, or from a service such as etcd or Consul
+//! by implementing the trait.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use memmap2::{MmapMut, MmapOptions};
+use serde_json::{json, Value};
+
+use crate::error::{Result, RustbucketError};
+
+/// Size of the encoded configuration in bytes
+pub const CONFIG_SIZE: usize = 16;
+
+/// Verbosity from which the server prints every message it receives
+pub const VERBOSE: u32 = 2;
+
+/// Server configuration structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub verbosity: u32,
+    pub max_connections: u32,
+    pub timeout_seconds: u32,
+    pub version: u32,  // Used to detect config changes
+}
+
+impl Config {
+    /// Creates a new Config with default values
+    pub fn new() -> Self {
+        Self {
+            verbosity: 1,
+            max_connections: 100,
+            timeout_seconds: 30,
+            version: 0,
+        }
+    }
+
+    /// Encodes the configuration in the layout of the config file
+    pub fn to_bytes(self) -> [u8; CONFIG_SIZE] {
+        let mut bytes = [0u8; CONFIG_SIZE];
+        bytes[0..4].copy_from_slice(&self.verbosity.to_ne_bytes());
+        bytes[4..8].copy_from_slice(&self.max_connections.to_ne_bytes());
+        bytes[8..12].copy_from_slice(&self.timeout_seconds.to_ne_bytes());
+        bytes[12..16].copy_from_slice(&self.version.to_ne_bytes());
+        bytes
+    }
+
+    /// JSON representation with stable field names, shared by the CLI and admin interface
+    pub fn to_json(self) -> Value {
+        json!({
+            "verbosity": self.verbosity,
+            "max_connections": self.max_connections,
+            "timeout_seconds": self.timeout_seconds,
+            "version": self.version,
+        })
+    }
+
+    /// Decodes the configuration from the layout of the config file
+    pub fn from_bytes(bytes: &[u8; CONFIG_SIZE]) -> Self {
+        Self {
+            verbosity: u32::from_ne_bytes(bytes[0..4].try_into().unwrap()),
+            max_connections: u32::from_ne_bytes(bytes[4..8].try_into().unwrap()),
+            timeout_seconds: u32::from_ne_bytes(bytes[8..12].try_into().unwrap()),
+            version: u32::from_ne_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the current configuration from the config file at `path`
+pub fn read_config(path: &Path) -> Result<Config> {
+    let bytes = std::fs::read(path).map_err(|e| config_file_error(path, e))?;
+    let bytes: &[u8; CONFIG_SIZE] = bytes
+        .get(..CONFIG_SIZE)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| config_file_error(path, io::Error::new(io::ErrorKind::InvalidData, "file is truncated")))?;
+    Ok(Config::from_bytes(bytes))
+}
+
+/// Applies the given field changes and bumps the version so servers notice them
+pub fn update_config(config: &mut Config, verbosity: Option<u32>, max_connections: Option<u32>, timeout: Option<u32>) {
+    if let Some(v) = verbosity {
+        config.verbosity = v;
+    }
+    if let Some(m) = max_connections {
+        config.max_connections = m;
+    }
+    if let Some(t) = timeout {
+        config.timeout_seconds = t;
+    }
+    config.version += 1;
+}
+
+/// Applies an update to the shared config file at `path` and returns the new configuration
+pub fn write_config_update(path: &Path, verbosity: Option<u32>, max_connections: Option<u32>, timeout: Option<u32>) -> Result<Config> {
+    let config = update_source(&FileSource::open(path)?, verbosity, max_connections, timeout)?;
+    log::info!("Wrote updated configuration to {}", path.display());
+    Ok(config)
+}
+
+/// Applies the given field changes to whatever `source` holds and returns the new configuration
+pub fn update_source(
+    source: &dyn ConfigSource,
+    verbosity: Option<u32>,
+    max_connections: Option<u32>,
+    timeout: Option<u32>,
+) -> Result<Config> {
+    let mut config = source.load()?;
+    update_config(&mut config, verbosity, max_connections, timeout);
+    source.store(config)?;
+    Ok(config)
+}
+
+/// Where a server gets its configuration from
+///
+/// `load` is called for every new connection, so it should be cheap; sources
+/// backed by a remote service will want to cache and refresh in the background.
+pub trait ConfigSource: Send + Sync {
+    /// Called once as the server starts, returning the configuration it begins with
+    fn initialize(&self) -> Result<Config> {
+        self.load()
+    }
+
+    /// The configuration currently in effect
+    fn load(&self) -> Result<Config>;
+
+    /// Replaces the configuration, for the admin interface's `POST /config`
+    ///
+    /// Sources that cannot be written leave the default, which refuses.
+    fn store(&self, _config: Config) -> Result<()> {
+        Err(RustbucketError::InvalidConfig("this configuration source is read-only".to_string()))
+    }
+}
+
+impl std::fmt::Debug for dyn ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConfigSource")
+    }
+}
+
+/// The memory-mapped config file, shared with `update-config` and other processes
+///
+/// A starting server writes the default configuration to the file, so the
+/// file always describes the server running in its directory.
+pub struct FileSource {
+    path: PathBuf,
+    mmap: Mutex<MmapMut>,
+}
+
+impl FileSource {
+    /// Maps `path`, creating it zero-filled if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let error = |source| RustbucketError::ConfigFile { path: path.clone(), source };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(error)?;
+        file.set_len(CONFIG_SIZE as u64).map_err(error)?; // Ensure file is large enough
+        let mmap = unsafe { MmapOptions::new().map_mut(&file).map_err(error)? };
+        Ok(Self { path, mmap: Mutex::new(mmap) })
+    }
+
+    /// File the source maps
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl ConfigSource for FileSource {
+    fn initialize(&self) -> Result<Config> {
+        let config = Config::new();
+        self.store(config)?;
+        Ok(config)
+    }
+
+    fn load(&self) -> Result<Config> {
+        let mmap = self.mmap.lock().unwrap();
+        let mut config_bytes = [0u8; CONFIG_SIZE];
+        config_bytes.copy_from_slice(&mmap[..CONFIG_SIZE]);
+        Ok(Config::from_bytes(&config_bytes))
+    }
+
+    fn store(&self, config: Config) -> Result<()> {
+        self.mmap.lock().unwrap()[..CONFIG_SIZE].copy_from_slice(&config.to_bytes());
+        Ok(())
+    }
+}
+
+/// Reads the configuration from environment variables, for containers
+///
+/// `<PREFIX>VERBOSITY`, `<PREFIX>MAX_CONNECTIONS`, and `<PREFIX>TIMEOUT_SECONDS`
+/// override the defaults; the prefix is `RUSTBUCKET_` unless changed. Values are
+/// read again for every connection. The source is read-only.
+#[derive(Debug, Clone)]
+pub struct EnvSource {
+    prefix: String,
+}
+
+impl EnvSource {
+    /// Reads variables prefixed with `RUSTBUCKET_`
+    pub fn new() -> Self {
+        Self::with_prefix("RUSTBUCKET_")
+    }
+
+    /// Reads variables prefixed with `prefix` instead
+    pub fn with_prefix(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into() }
+    }
+
+    fn var(&self, name: &str, default: u32) -> Result<u32> {
+        let key = format!("{}{}", self.prefix, name);
+        match std::env::var(&key) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| RustbucketError::InvalidConfig(format!("{} must be a non-negative integer, got {:?}", key, value))),
+            Err(std::env::VarError::NotPresent) => Ok(default),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                Err(RustbucketError::InvalidConfig(format!("{} is not valid UTF-8", key)))
+            }
+        }
+    }
+}
+
+impl Default for EnvSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigSource for EnvSource {
+    fn load(&self) -> Result<Config> {
+        let defaults = Config::new();
+        Ok(Config {
+            verbosity: self.var("VERBOSITY", defaults.verbosity)?,
+            max_connections: self.var("MAX_CONNECTIONS", defaults.max_connections)?,
+            timeout_seconds: self.var("TIMEOUT_SECONDS", defaults.timeout_seconds)?,
+            version: defaults.version,
+        })
+    }
+}
+
+/// Holds the configuration in memory, for tests and embedders that manage it themselves
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    config: Mutex<Config>,
+}
+
+impl MemorySource {
+    /// Starts with `config`
+    pub fn new(config: Config) -> Self {
+        Self { config: Mutex::new(config) }
+    }
+}
+
+impl ConfigSource for MemorySource {
+    fn load(&self) -> Result<Config> {
+        Ok(*self.config.lock().unwrap())
+    }
+
+    fn store(&self, config: Config) -> Result<()> {
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+}
+
+/// Attributes an I/O failure to the config file at `path`
+fn config_file_error(path: &Path, source: io::Error) -> RustbucketError {
+    RustbucketError::ConfigFile { path: path.to_path_buf(), source }
+}
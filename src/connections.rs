@@ -0,0 +1,309 @@
+//! Registry of live client connections, used for operational introspection.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::memory::MemoryBudget;
+use crate::outbox::Outbox;
+use crate::protocol::ConnectionMode;
+
+/// A connection currently being served by a worker
+#[derive(Debug)]
+pub struct ConnectionEntry {
+    pub id: u64,
+    pub peer: String,
+    pub connected_at: SystemTime,
+    started: Instant,
+    pub messages: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    /// Milliseconds after `started` that the client last sent something
+    last_received: AtomicU64,
+    /// Handle to the client socket, used to close the connection from outside its worker
+    socket: Option<TcpStream>,
+    /// Whether the worker is waiting for the client to start its next request
+    waiting: AtomicBool,
+    /// Set once reads are shut off to wake the worker from that wait for a shutdown
+    woken: AtomicBool,
+    /// Held for each write so replies and outbox messages are not interleaved
+    writing: Mutex<()>,
+    /// Created the first time a handler asks for it
+    outbox: OnceLock<Outbox>,
+    /// Who the client authenticated as, once it has
+    identity: OnceLock<String>,
+    /// Mode the client switched to with `MODE`: 0 until it has, then 1 for text or 2 for binary
+    mode: AtomicU8,
+    /// Bytes still to come of a body a handler announced, which are framed apart from commands
+    body_remaining: AtomicU64,
+    /// Whether the message being handled is part of such a body
+    in_body: AtomicBool,
+    /// Session state for encrypted connections, which every write must go through
+    #[cfg(any(feature = "tls", feature = "noise"))]
+    encryption: OnceLock<Encryption>,
+}
+
+/// How an encrypted connection's writes are encrypted
+#[cfg(any(feature = "tls", feature = "noise"))]
+#[derive(Debug)]
+pub(crate) enum Encryption {
+    #[cfg(feature = "tls")]
+    Tls(crate::tls::TlsSession),
+    #[cfg(feature = "noise")]
+    Noise(crate::noise::NoiseSession),
+}
+
+impl ConnectionEntry {
+    /// How long the connection has been open
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// How long since the client last sent something, or since it connected if it has not
+    pub fn idle(&self) -> Duration {
+        self.age().saturating_sub(Duration::from_millis(self.last_received.load(Ordering::Relaxed)))
+    }
+
+    /// Records a message read from the client
+    pub fn record_received(&self, bytes: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_received.store(self.age().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Who the client authenticated as, if it has
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.get().map(String::as_str)
+    }
+
+    /// Records who the client authenticated as; later calls have no effect
+    pub(crate) fn set_identity(&self, identity: String) {
+        let _ = self.identity.set(identity);
+    }
+
+    /// Mode the client switched to, or `None` if it is still in the server's
+    pub fn mode(&self) -> Option<ConnectionMode> {
+        match self.mode.load(Ordering::Relaxed) {
+            1 => Some(ConnectionMode::Text),
+            2 => Some(ConnectionMode::Binary),
+            _ => None,
+        }
+    }
+
+    /// Switches the connection to `mode` for the messages after this one
+    pub(crate) fn set_mode(&self, mode: ConnectionMode) {
+        let value = match mode {
+            ConnectionMode::Text => 1,
+            ConnectionMode::Binary => 2,
+        };
+        self.mode.store(value, Ordering::Relaxed);
+    }
+
+    /// Announces that the next `len` bytes from the client are a body rather than commands
+    pub(crate) fn expect_body(&self, len: u64) {
+        self.body_remaining.store(len, Ordering::Relaxed);
+    }
+
+    /// Bytes still to come of an announced body
+    pub(crate) fn body_remaining(&self) -> u64 {
+        self.body_remaining.load(Ordering::Relaxed)
+    }
+
+    /// Marks the next message handled as `body` bytes of the announced body, or as commands if `body` is 0
+    pub(crate) fn start_message(&self, body: usize) {
+        if body > 0 {
+            self.body_remaining.fetch_sub(body as u64, Ordering::Relaxed);
+        }
+        self.in_body.store(body > 0, Ordering::Relaxed);
+    }
+
+    /// Whether the message being handled is part of an announced body
+    pub(crate) fn in_body(&self) -> bool {
+        self.in_body.load(Ordering::Relaxed)
+    }
+
+    /// Records bytes written back to the client
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Shuts the socket down so the worker serving it sees the connection end
+    pub fn close(&self) -> bool {
+        self.socket.as_ref().is_some_and(|socket| socket.shutdown(Shutdown::Both).is_ok())
+    }
+
+    /// Records whether the worker is waiting for the client to start its next request
+    pub(crate) fn set_waiting(&self, waiting: bool) {
+        self.waiting.store(waiting, Ordering::SeqCst);
+    }
+
+    /// Shuts off reads from an idle client so its worker wakes up, returning whether it was idle
+    ///
+    /// Writes still go through, so the worker can tell the client why it is closing.
+    pub(crate) fn wake_if_waiting(&self) -> bool {
+        if !self.waiting.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.woken.store(true, Ordering::SeqCst);
+        self.socket.as_ref().is_some_and(|socket| socket.shutdown(Shutdown::Read).is_ok())
+    }
+
+    /// Whether reads were shut off by [`wake_if_waiting`](Self::wake_if_waiting)
+    pub(crate) fn woken(&self) -> bool {
+        self.woken.load(Ordering::SeqCst)
+    }
+
+    /// Another writer to the client, encrypting if the connection is encrypted
+    pub(crate) fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        let socket = self.socket.as_ref().ok_or(io::ErrorKind::NotConnected)?.try_clone()?;
+        #[cfg(any(feature = "tls", feature = "noise"))]
+        match self.encryption.get() {
+            #[cfg(feature = "tls")]
+            Some(Encryption::Tls(session)) => {
+                return Ok(Box::new(crate::tls::TlsWriter::new(socket, Arc::clone(session))))
+            }
+            #[cfg(feature = "noise")]
+            Some(Encryption::Noise(session)) => {
+                return Ok(Box::new(crate::noise::NoiseStream::writer(socket, Arc::clone(session))))
+            }
+            None => {}
+        }
+        Ok(Box::new(socket))
+    }
+
+    /// Records the session of a connection that is encrypted
+    #[cfg(any(feature = "tls", feature = "noise"))]
+    pub(crate) fn set_encryption(&self, encryption: Encryption) {
+        let _ = self.encryption.set(encryption);
+    }
+
+    /// Must be held while writing to the client
+    pub(crate) fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.writing.lock().unwrap()
+    }
+
+    /// The connection's outbox, starting its writer thread on first use
+    ///
+    /// Queued messages are counted against `budget`.
+    pub(crate) fn outbox(self: &Arc<Self>, budget: &Arc<MemoryBudget>) -> Outbox {
+        self.outbox.get_or_init(|| Outbox::start(Arc::clone(self), Arc::clone(budget))).clone()
+    }
+
+    /// Whether the connection's outbox has been started
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    pub(crate) fn has_outbox(&self) -> bool {
+        self.outbox.get().is_some()
+    }
+
+    /// Stops the outbox's writer thread, if there is one
+    pub(crate) fn close_outbox(&self) {
+        if let Some(outbox) = self.outbox.get() {
+            outbox.close();
+        }
+    }
+}
+
+/// Tracks every open connection by ID
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<u64, Arc<ConnectionEntry>>>,
+}
+
+impl ConnectionRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new connection from `peer` and returns its entry
+    ///
+    /// `socket` should be a clone of the client stream so the connection can be
+    /// closed through the registry.
+    pub fn register(&self, peer: String, socket: Option<TcpStream>) -> Arc<ConnectionEntry> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(ConnectionEntry {
+            id,
+            peer,
+            connected_at: SystemTime::now(),
+            started: Instant::now(),
+            messages: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            last_received: AtomicU64::new(0),
+            socket,
+            waiting: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+            writing: Mutex::new(()),
+            outbox: OnceLock::new(),
+            identity: OnceLock::new(),
+            mode: AtomicU8::new(0),
+            body_remaining: AtomicU64::new(0),
+            in_body: AtomicBool::new(false),
+            #[cfg(any(feature = "tls", feature = "noise"))]
+            encryption: OnceLock::new(),
+        });
+        self.entries.lock().unwrap().insert(id, Arc::clone(&entry));
+        entry
+    }
+
+    /// Removes a connection once it has closed
+    pub fn unregister(&self, id: u64) {
+        self.entries.lock().unwrap().remove(&id);
+    }
+
+    /// Closes the connection with `id`, returning whether it was open
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn close(&self, id: u64) -> bool {
+        let entry = self.entries.lock().unwrap().get(&id).cloned();
+        entry.is_some_and(|entry| entry.close())
+    }
+
+    /// Returns the open connections, oldest first
+    pub fn list(&self) -> Vec<Arc<ConnectionEntry>> {
+        let mut entries: Vec<_> = self.entries.lock().unwrap().values().cloned().collect();
+        entries.sort_by_key(|entry| entry.id);
+        entries
+    }
+}
+
+/// Open connections per client address, for capping how many one client may hold
+#[derive(Debug, Default)]
+pub(crate) struct PeerCounts {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PeerCounts {
+    /// Counts a new connection from `ip`, unless it already has `cap` open
+    pub(crate) fn acquire(&self, ip: IpAddr, cap: usize) -> Option<PeerSlot> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= cap {
+            return None;
+        }
+        *count += 1;
+        Some(PeerSlot { ip, counts: Arc::clone(&self.counts) })
+    }
+}
+
+/// One counted connection, uncounted when dropped
+#[derive(Debug)]
+pub(crate) struct PeerSlot {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
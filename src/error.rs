@@ -0,0 +1,132 @@
+//! The error type returned by the library's public APIs.
+//!
+//! Failures are grouped by what went wrong (configuration, network, protocol,
+//! logging) rather than by the `io::Error` that happened to surface them, so
+//! callers can react to the cause and the CLI can exit with a matching code.
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Result type of the library's public APIs
+pub type Result<T, E = RustbucketError> = std::result::Result<T, E>;
+
+/// Process exit code once the server has shut down without waiting for its connections to finish
+///
+/// Past the `sysexits.h` range, like the code for TLS failures, so that a
+/// supervisor can tell a forced shutdown from a clean one (0) and from a
+/// failure to start.
+pub const EXIT_FORCED_SHUTDOWN: i32 = 80;
+
+/// What went wrong in the server or one of its subsystems
+#[derive(Debug, Error)]
+pub enum RustbucketError {
+    /// Settings that cannot work, such as zero worker threads or a malformed URL
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// The config file could not be read or written
+    #[error("config file {}: {source}", path.display())]
+    ConfigFile { path: PathBuf, source: io::Error },
+    /// The TLS settings, certificate, or key cannot be used
+    #[error("TLS: {0}")]
+    Tls(String),
+    /// A listening socket could not be bound
+    #[error("could not listen on {addr}: {source}")]
+    Bind { addr: String, source: io::Error },
+    /// Another live server owns the pidfile
+    #[error("server already running with pid {pid} (from {})", path.display())]
+    AlreadyRunning { pid: i32, path: PathBuf },
+    /// Talking to a peer (collector, webhook, admin interface) failed
+    #[error("{0}")]
+    Network(io::Error),
+    /// A peer sent something that does not follow its protocol
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// The log file could not be opened or written
+    #[error("log file {}: {source}", path.display())]
+    Log { path: PathBuf, source: io::Error },
+    /// A data file (such as a KV snapshot) could not be read, written, or trusted
+    #[error("data file {}: {source}", path.display())]
+    Storage { path: PathBuf, source: io::Error },
+    /// A KV command was used on a key holding a different kind of value, such as `GET` on a list
+    #[error("the key holds a different kind of value")]
+    WrongType,
+    /// A time to live too far in the future for the clock to represent
+    #[error("invalid expire time")]
+    InvalidExpireTime,
+    /// A write was refused because the server is over its memory budget
+    #[error("out of memory: the server is over its memory budget")]
+    OutOfMemory,
+    /// A handler plugin could not be loaded
+    #[error("plugin {}: {message}", path.display())]
+    Plugin { path: PathBuf, message: String },
+    /// A request script could not be loaded
+    #[error("script {}: {message}", path.display())]
+    Script { path: PathBuf, message: String },
+    /// The operating system refused to sandbox the server
+    #[error("sandbox: {0}")]
+    Sandbox(String),
+    /// Any other I/O failure
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl RustbucketError {
+    /// The closest `io::ErrorKind`, for callers that branch on kinds
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            RustbucketError::InvalidConfig(_)
+            | RustbucketError::Tls(_)
+            | RustbucketError::WrongType
+            | RustbucketError::InvalidExpireTime => io::ErrorKind::InvalidInput,
+            RustbucketError::AlreadyRunning { .. } => io::ErrorKind::AlreadyExists,
+            RustbucketError::OutOfMemory => io::ErrorKind::OutOfMemory,
+            RustbucketError::Sandbox(_) => io::ErrorKind::Other,
+            RustbucketError::Protocol(_) | RustbucketError::Plugin { .. } | RustbucketError::Script { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            RustbucketError::ConfigFile { source, .. }
+            | RustbucketError::Bind { source, .. }
+            | RustbucketError::Log { source, .. }
+            | RustbucketError::Storage { source, .. }
+            | RustbucketError::Network(source)
+            | RustbucketError::Io(source) => source.kind(),
+        }
+    }
+
+    /// Process exit code for this failure, following the BSD `sysexits.h` conventions
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // EX_CONFIG
+            RustbucketError::InvalidConfig(_)
+            | RustbucketError::ConfigFile { .. }
+            | RustbucketError::Plugin { .. }
+            | RustbucketError::Script { .. } => 78,
+            // Past the sysexits range, so that certificate trouble stands out from other settings
+            RustbucketError::Tls(_) => 79,
+            // EX_OSERR: the address is taken or not ours to bind, or the kernel refused the sandbox
+            RustbucketError::Bind { .. } | RustbucketError::Sandbox(_) => 71,
+            // EX_TEMPFAIL: trying again once the other server exits may work
+            RustbucketError::AlreadyRunning { .. } | RustbucketError::OutOfMemory => 75,
+            // EX_UNAVAILABLE
+            RustbucketError::Network(_) => 69,
+            // EX_PROTOCOL
+            RustbucketError::Protocol(_) => 76,
+            // EX_DATAERR
+            RustbucketError::WrongType | RustbucketError::InvalidExpireTime => 65,
+            // EX_IOERR
+            RustbucketError::Log { .. } | RustbucketError::Storage { .. } => 74,
+            RustbucketError::Io(_) => 1,
+        }
+    }
+}
+
+impl From<RustbucketError> for io::Error {
+    fn from(error: RustbucketError) -> Self {
+        match error {
+            RustbucketError::Io(e) | RustbucketError::Network(e) => e,
+            other => io::Error::new(other.kind(), other),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Typed stream of server events for embedding applications.
+//!
+//! Each subscriber gets its own bounded channel. Publishing never blocks the
+//! server: when a subscriber falls too far behind, events destined for it are
+//! dropped and counted instead.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+use serde_json::{json, Value};
+
+use crate::connections::ConnectionEntry;
+use crate::hooks::{LifecycleHook, ShutdownPhase};
+
+/// Events buffered per subscriber before new ones are dropped
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Something notable that happened in the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    /// A connection was accepted and is being served
+    Accepted { id: u64, peer: String },
+    /// A connection closed normally
+    Closed {
+        id: u64,
+        peer: String,
+        duration: Duration,
+        bytes_received: u64,
+        bytes_sent: u64,
+    },
+    /// A connection or the listener failed
+    Error { id: Option<u64>, message: String },
+    /// A new configuration version was picked up
+    ConfigReloaded { version: u32 },
+    /// The server entered a shutdown phase
+    ShuttingDown { phase: ShutdownPhase },
+    /// The server went into defensive mode for a connection flood, or out of it once the flood subsided
+    Flood {
+        defensive: bool,
+        /// Connections accepted per second over the latest sample
+        rate: f64,
+        /// Connections accepted per second in normal times
+        baseline: f64,
+    },
+}
+
+impl ServerEvent {
+    /// JSON representation with a `type` discriminator
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn to_json(&self) -> Value {
+        match self {
+            ServerEvent::Accepted { id, peer } => json!({ "type": "accepted", "id": id, "peer": peer }),
+            ServerEvent::Closed { id, peer, duration, bytes_received, bytes_sent } => json!({
+                "type": "closed",
+                "id": id,
+                "peer": peer,
+                "duration_seconds": duration.as_secs_f64(),
+                "bytes_received": bytes_received,
+                "bytes_sent": bytes_sent,
+            }),
+            ServerEvent::Error { id, message } => json!({ "type": "error", "id": id, "message": message }),
+            ServerEvent::ConfigReloaded { version } => json!({ "type": "config_reloaded", "version": version }),
+            ServerEvent::ShuttingDown { phase } => json!({
+                "type": "shutting_down",
+                "phase": match phase {
+                    ShutdownPhase::Requested => "requested",
+                    ShutdownPhase::Draining => "draining",
+                    ShutdownPhase::Complete => "complete",
+                },
+            }),
+            ServerEvent::Flood { defensive, rate, baseline } => json!({
+                "type": "flood",
+                "defensive": defensive,
+                "rate": rate,
+                "baseline": baseline,
+            }),
+        }
+    }
+}
+
+/// Fan-out of [`ServerEvent`]s to any number of subscribers
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<SyncSender<ServerEvent>>>,
+    dropped: AtomicU64,
+}
+
+impl EventBus {
+    /// Creates a bus with no subscribers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a receiver for every event published from now on
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn subscribe(&self) -> Receiver<ServerEvent> {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Delivers `event` to every subscriber, forgetting those that hung up
+    pub fn publish(&self, event: ServerEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        subscribers.retain(|tx| match tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    /// Events dropped because a subscriber's buffer was full
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl LifecycleHook for EventBus {
+    fn on_connect(&self, connection: &ConnectionEntry) {
+        self.publish(ServerEvent::Accepted {
+            id: connection.id,
+            peer: connection.peer.clone(),
+        });
+    }
+
+    fn on_disconnect(&self, connection: &ConnectionEntry, error: Option<&io::Error>) {
+        let event = match error {
+            Some(e) => ServerEvent::Error {
+                id: Some(connection.id),
+                message: e.to_string(),
+            },
+            None => ServerEvent::Closed {
+                id: connection.id,
+                peer: connection.peer.clone(),
+                duration: connection.age(),
+                bytes_received: connection.bytes_received.load(Ordering::Relaxed),
+                bytes_sent: connection.bytes_sent.load(Ordering::Relaxed),
+            },
+        };
+        self.publish(event);
+    }
+
+    fn on_shutdown(&self, phase: ShutdownPhase) {
+        self.publish(ServerEvent::ShuttingDown { phase });
+    }
+}
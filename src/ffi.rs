@@ -0,0 +1,281 @@
+//! C bindings for embedding the server in programs not written in Rust.
+//!
+//! Compiled in with the `cdylib` feature; `include/rustbucket.h` declares the
+//! same functions and structs for C callers. Configuration and statistics are
+//! passed in `#[repr(C)]` structs whose first field is their size. Later
+//! versions only append fields, so the size tells which fields a caller knows
+//! about: fields past the end of a caller's `rustbucket_config_t` keep their
+//! defaults, and `rustbucket_stats` fills in only the fields that fit in the
+//! caller's `rustbucket_stats_t`. A config smaller than the first version of the
+//! struct cannot have come from any header and is refused.
+//!
+//! Functions return 0 on success or the CLI's exit code for the failure, with a
+//! description available from [`rustbucket_last_error`]. A panic inside the
+//! library is caught before it can unwind into the caller and reported as
+//! [`EXIT_PANIC`].
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::mem::size_of;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+use crate::error::{Result, RustbucketError};
+use crate::server::{Server, ShutdownHandle, DEFAULT_ADMIN_PORT, DEFAULT_PORT, DEFAULT_THREADS};
+
+/// Settings for `rustbucket_start`, filled with defaults by `rustbucket_config_init`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RustbucketConfig {
+    /// `sizeof(rustbucket_config_t)` as compiled by the caller
+    pub size: u32,
+    /// Port to accept clients on; 0 picks a free port
+    pub port: u16,
+    /// Loopback port of the admin interface; 0 disables it
+    pub admin_port: u16,
+    /// Worker threads serving connections
+    pub threads: u32,
+    /// Nonzero to install handlers for SIGINT, SIGTERM, SIGHUP, and SIGUSR2
+    pub handle_signals: c_int,
+    /// Log file, or NULL for `http.log` in the working directory
+    pub log_path: *const c_char,
+    /// Config file, or NULL for `config.dat` in the working directory
+    pub config_path: *const c_char,
+    /// Pidfile, or NULL for `rustbucket.pid` in the working directory
+    pub pid_path: *const c_char,
+}
+
+/// Counters reported by `rustbucket_stats`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RustbucketStats {
+    /// `sizeof(rustbucket_stats_t)` as compiled by the caller
+    pub size: u32,
+    /// Port the server accepts clients on
+    pub port: u16,
+    pub connections_accepted: u64,
+    pub connections_active: u64,
+    pub connections_closed: u64,
+    pub accept_errors: u64,
+    pub handler_errors: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Status returned when the library panicked (`EX_SOFTWARE`)
+pub const EXIT_PANIC: c_int = 70;
+
+/// Size of the first version of `rustbucket_config_t`, the smallest a caller can pass
+///
+/// Every field of this version fits, so none needs checking against the
+/// caller's size yet; fields appended later will.
+const MIN_CONFIG_SIZE: usize = size_of::<RustbucketConfig>();
+
+/// A running server, owned by the caller until passed to `rustbucket_stop`
+pub struct RustbucketServer {
+    handle: ShutdownHandle,
+}
+
+thread_local! {
+    /// Description of the last failure on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Runs the body of an exported function, converting its result to a status code
+///
+/// Errors and panics are remembered for `rustbucket_last_error`; a panic is
+/// caught here, since unwinding into C is undefined behavior.
+fn guard(body: impl FnOnce() -> Result<()>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(&e.to_string());
+            e.exit_code()
+        }
+        Err(payload) => {
+            set_last_error(&format!("internal error: {}", panic_message(&*payload)));
+            EXIT_PANIC
+        }
+    }
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// What a panic said, if it was given a message
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("panicked", String::as_str),
+    }
+}
+
+fn invalid(message: &str) -> RustbucketError {
+    RustbucketError::InvalidConfig(message.to_string())
+}
+
+/// Reads an optional path argument
+///
+/// # Safety
+/// `path` must be NULL or point to a NUL-terminated string.
+unsafe fn optional_path(path: *const c_char) -> Option<PathBuf> {
+    if path.is_null() {
+        return None;
+    }
+    let bytes = unsafe { CStr::from_ptr(path) }.to_bytes();
+    #[cfg(unix)]
+    let path = PathBuf::from(OsStr::from_bytes(bytes));
+    // Elsewhere paths are taken to be UTF-8
+    #[cfg(not(unix))]
+    let path = PathBuf::from(std::str::from_utf8(bytes).ok()?);
+    Some(path)
+}
+
+/// Fills `config` with the same defaults as `rustbucket run` with no flags
+///
+/// # Safety
+/// `config` must be NULL or point to a writable `rustbucket_config_t`.
+#[no_mangle]
+pub unsafe extern "C" fn rustbucket_config_init(config: *mut RustbucketConfig) {
+    guard(|| {
+        if config.is_null() {
+            return Ok(());
+        }
+        let defaults = RustbucketConfig {
+            size: size_of::<RustbucketConfig>() as u32,
+            port: DEFAULT_PORT,
+            admin_port: DEFAULT_ADMIN_PORT,
+            threads: DEFAULT_THREADS as u32,
+            handle_signals: 0,
+            log_path: ptr::null(),
+            config_path: ptr::null(),
+            pid_path: ptr::null(),
+        };
+        unsafe { config.write(defaults) };
+        Ok(())
+    });
+}
+
+/// Starts a server in the background and stores it in `*server`
+///
+/// Returns once the server is listening. On failure `*server` is left NULL.
+///
+/// # Safety
+/// `config` must point to a `rustbucket_config_t` whose strings are NULL or
+/// NUL-terminated, and `server` to writable storage for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn rustbucket_start(config: *const RustbucketConfig, server: *mut *mut RustbucketServer) -> c_int {
+    guard(|| {
+        if config.is_null() || server.is_null() {
+            return Err(invalid("config and server must not be NULL"));
+        }
+        unsafe { server.write(ptr::null_mut()) };
+        let config = unsafe { &*config };
+        if (config.size as usize) < MIN_CONFIG_SIZE {
+            return Err(invalid("rustbucket_config_t is from an incompatible header"));
+        }
+
+        let mut builder = Server::builder()
+            .port(config.port)
+            .threads(config.threads as usize)
+            .handle_signals(config.handle_signals != 0);
+        builder = match config.admin_port {
+            0 => builder.disable_admin(),
+            port => builder.admin_port(port),
+        };
+        if let Some(path) = unsafe { optional_path(config.log_path) } {
+            builder = builder.log_path(path);
+        }
+        if let Some(path) = unsafe { optional_path(config.config_path) } {
+            builder = builder.config_path(path);
+        }
+        if let Some(path) = unsafe { optional_path(config.pid_path) } {
+            builder = builder.pid_path(path);
+        }
+
+        let handle = builder.build()?.start()?;
+        unsafe { server.write(Box::into_raw(Box::new(RustbucketServer { handle }))) };
+        Ok(())
+    })
+}
+
+/// Copies the server's current counters into `*stats`
+///
+/// Only as much as fits in the caller's `size` is written, and `size` is set
+/// to how much that was.
+///
+/// # Safety
+/// `server` must come from `rustbucket_start` and not yet be stopped; `stats`
+/// must point to a writable `rustbucket_stats_t` of at least `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rustbucket_stats(server: *const RustbucketServer, stats: *mut RustbucketStats) -> c_int {
+    guard(|| {
+        if server.is_null() || stats.is_null() {
+            return Err(invalid("server and stats must not be NULL"));
+        }
+        let len = (unsafe { (*stats).size } as usize).min(size_of::<RustbucketStats>());
+        if len < size_of::<u32>() {
+            return Err(invalid("rustbucket_stats_t is too small to hold its size"));
+        }
+        let handle = &unsafe { &*server }.handle;
+        let metrics = handle.metrics();
+        let snapshot = RustbucketStats {
+            size: len as u32,
+            port: handle.local_addr().port(),
+            connections_accepted: metrics.connections_accepted,
+            connections_active: metrics.connections_active,
+            connections_closed: metrics.connections_closed,
+            accept_errors: metrics.accept_errors,
+            handler_errors: metrics.handler_errors,
+            messages_received: metrics.messages_received,
+            bytes_received: metrics.bytes_received,
+            bytes_sent: metrics.bytes_sent,
+        };
+        // A caller's struct is a prefix of this one, so the first `len` bytes line up
+        let source = (&snapshot as *const RustbucketStats).cast::<u8>();
+        unsafe { ptr::copy_nonoverlapping(source, stats.cast::<u8>(), len) };
+        Ok(())
+    })
+}
+
+/// Shuts the server down, waits for it to finish, and frees it
+///
+/// A nonzero `graceful` lets open connections finish, like SIGTERM; zero
+/// closes them immediately.
+///
+/// # Safety
+/// `server` must be NULL or come from `rustbucket_start`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rustbucket_stop(server: *mut RustbucketServer, graceful: c_int) -> c_int {
+    guard(|| {
+        if server.is_null() {
+            return Err(invalid("server must not be NULL"));
+        }
+        let server = unsafe { Box::from_raw(server) };
+        if graceful != 0 {
+            server.handle.shutdown_graceful();
+        } else {
+            server.handle.shutdown_now();
+        }
+        server.handle.wait();
+        Ok(())
+    })
+}
+
+/// Describes the last failure on the calling thread, or returns NULL if there was none
+///
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn rustbucket_last_error() -> *const c_char {
+    let last_error = || LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()));
+    panic::catch_unwind(last_error).unwrap_or(ptr::null())
+}
@@ -0,0 +1,344 @@
+//! A scratch file drop: `PUT` uploads a file into a storage directory and
+//! `GETFILE` downloads it again.
+//!
+//! `PUT <name> <size> [crc32]` is followed by exactly `size` bytes of file
+//! contents, which may arrive over any number of reads and are written to disk
+//! as they come. Once the last byte is in, the file is synced and renamed into
+//! place and the server replies `OK <crc32>`, the CRC-32 of the contents in
+//! hex; if the client sent a checksum and it does not match, the upload is
+//! discarded with an error instead. An upload cut short by the connection
+//! closing leaves nothing behind. The contents reach the handler apart from
+//! the commands around them, so middleware such as the command ACL never
+//! reads them as commands.
+//!
+//! `GETFILE <name>` replies `FILE <size> <crc32>` on one line followed by
+//! exactly `size` bytes of contents. The contents go out with
+//! [`ResponseWriter::send_file`], so on plain TCP they are never copied through
+//! the server. Checksums are remembered from uploads and earlier downloads, and
+//! only computed again once a file's size or modification time changes.
+//!
+//! File names are 1 to [`MAX_NAME_LEN`] letters, digits, `.`, `-`, or `_`,
+//! not starting with `.`, so a name can never climb out of the storage
+//! directory or clash with an upload in progress. Files over the size limit
+//! are refused before their contents are read; the contents are still
+//! consumed and thrown away so the connection's next command is read
+//! correctly.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use memchr::memchr;
+
+use crate::bans::Offence;
+use crate::error::{Result, RustbucketError};
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+
+/// Largest file accepted by default: 16 MiB
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Longest file name accepted, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Longest command line accepted, in bytes
+const MAX_LINE_LEN: usize = 1024;
+
+/// Stores uploaded files in a directory and serves them back
+#[derive(Debug)]
+pub struct FileHandler {
+    dir: PathBuf,
+    max_file_size: u64,
+    /// Partial lines and uploads in progress, by connection id
+    connections: Mutex<HashMap<u64, Connection>>,
+    /// Checksums of stored files, by name
+    checksums: Mutex<HashMap<String, Checksum>>,
+}
+
+/// A file's CRC-32, valid while its size and modification time stay the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Checksum {
+    len: u64,
+    modified: Option<SystemTime>,
+    crc32: u32,
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    /// The start of a command line whose end has not arrived yet
+    line: Vec<u8>,
+    upload: Option<Upload>,
+}
+
+#[derive(Debug)]
+struct Upload {
+    name: String,
+    temp_path: PathBuf,
+    /// `None` for a refused upload whose contents are being skipped
+    file: Option<File>,
+    remaining: u64,
+    hasher: crc32fast::Hasher,
+    expected: Option<u32>,
+    /// The first write that failed; the rest of the contents are skipped
+    error: Option<io::Error>,
+}
+
+impl FileHandler {
+    /// Stores files in `dir`, creating it if needed
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|source| RustbucketError::Storage { path: dir.clone(), source })?;
+        Ok(Self {
+            dir,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            connections: Mutex::default(),
+            checksums: Mutex::default(),
+        })
+    }
+
+    /// Refuses uploads larger than `bytes`
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Directory the files are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Runs one command line, possibly starting an upload
+    fn execute(
+        &self,
+        ctx: &ConnectionCtx<'_>,
+        line: &[u8],
+        upload: &mut Option<Upload>,
+        response: &mut ResponseWriter,
+    ) {
+        let line = String::from_utf8_lossy(line);
+        let args: Vec<&str> = line.split_ascii_whitespace().collect();
+        let Some((name, args)) = args.split_first() else { return };
+        match (name.to_ascii_uppercase().as_str(), args) {
+            ("PUT", [file_name, size, checksum @ ..]) if checksum.len() <= 1 => {
+                let Ok(size) = size.parse::<u64>() else {
+                    return error(response, "size is not a non-negative integer");
+                };
+                let expected = match checksum.first().map(|checksum| u32::from_str_radix(checksum, 16)) {
+                    None => None,
+                    Some(Ok(checksum)) => Some(checksum),
+                    Some(Err(_)) => return error(response, "checksum is not a hexadecimal CRC-32"),
+                };
+                let refuse = |reason: &str, upload: &mut Option<Upload>, response: &mut ResponseWriter| {
+                    *upload = Some(Upload::skip(size));
+                    error(response, reason);
+                };
+                if !valid_name(file_name) {
+                    return refuse("invalid file name", upload, response);
+                }
+                if size > self.max_file_size {
+                    return refuse(&format!("file is larger than {} bytes", self.max_file_size), upload, response);
+                }
+                let temp_path = self.dir.join(format!(".upload-{}", ctx.id()));
+                match File::create(&temp_path) {
+                    Ok(file) => {
+                        *upload = Some(Upload {
+                            name: file_name.to_string(),
+                            temp_path,
+                            file: Some(file),
+                            remaining: size,
+                            hasher: crc32fast::Hasher::new(),
+                            expected,
+                            error: None,
+                        })
+                    }
+                    Err(e) => return refuse(&e.to_string(), upload, response),
+                }
+                // An empty file is complete as soon as it is announced
+                if size == 0 {
+                    self.finish(upload.take().expect("just started"), response);
+                }
+            }
+            ("GETFILE", [file_name]) => {
+                if !valid_name(file_name) {
+                    return error(response, "invalid file name");
+                }
+                match self.open(file_name) {
+                    Ok((file, checksum)) => {
+                        response.write(format!("FILE {} {:08x}\n", checksum.len, checksum.crc32).as_bytes());
+                        response.send_file(file, checksum.len);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => error(response, "no such file"),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("PUT" | "GETFILE", _) => error(response, &format!("wrong number of arguments for '{}'", name)),
+            _ => {
+                ctx.report(Offence::ProtocolError);
+                error(response, &format!("unknown command '{}'", name))
+            }
+        }
+    }
+
+    /// Opens a stored file for download, with its checksum
+    fn open(&self, name: &str) -> io::Result<(File, Checksum)> {
+        let mut file = File::open(self.dir.join(name))?;
+        let metadata = file.metadata()?;
+        let (len, modified) = (metadata.len(), metadata.modified().ok());
+        let known = self.checksums.lock().unwrap().get(name).copied();
+        if let Some(checksum) = known.filter(|known| known.len == len && known.modified == modified) {
+            return Ok((file, checksum));
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        let mut chunk = [0; 8192];
+        let mut contents = (&file).take(len);
+        let mut read = 0;
+        loop {
+            match contents.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    hasher.update(&chunk[..n]);
+                    read += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if read < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being read"));
+        }
+        file.rewind()?;
+        let checksum = Checksum { len, modified, crc32: hasher.finalize() };
+        self.checksums.lock().unwrap().insert(name.to_string(), checksum);
+        Ok((file, checksum))
+    }
+
+    /// Moves a completely received upload into place and replies
+    fn finish(&self, upload: Upload, response: &mut ResponseWriter) {
+        let Some(file) = upload.file else { return };
+        let checksum = upload.hasher.finalize();
+        let result = match (upload.error, upload.expected) {
+            (Some(e), _) => Err(e.to_string()),
+            (None, Some(expected)) if expected != checksum => {
+                Err(format!("checksum mismatch: expected {:08x}, received {:08x}", expected, checksum))
+            }
+            (None, _) => file
+                .sync_all()
+                .and_then(|()| fs::rename(&upload.temp_path, self.dir.join(&upload.name)))
+                .map_err(|e| e.to_string()),
+        };
+        match result {
+            Ok(()) => {
+                log::info!("Stored file {} in {}", upload.name, self.dir.display());
+                self.remember(&upload.name, checksum);
+                response.write(format!("OK {:08x}\n", checksum).as_bytes());
+            }
+            Err(reason) => {
+                let _ = fs::remove_file(&upload.temp_path);
+                error(response, &reason);
+            }
+        }
+    }
+
+    /// Records the checksum of a file just stored, so its first download need not read it
+    fn remember(&self, name: &str, crc32: u32) {
+        let Ok(metadata) = fs::metadata(self.dir.join(name)) else { return };
+        let checksum = Checksum { len: metadata.len(), modified: metadata.modified().ok(), crc32 };
+        self.checksums.lock().unwrap().insert(name.to_string(), checksum);
+    }
+}
+
+impl Upload {
+    /// An upload whose contents are read and thrown away
+    fn skip(size: u64) -> Self {
+        Self {
+            name: String::new(),
+            temp_path: PathBuf::new(),
+            file: None,
+            remaining: size,
+            hasher: crc32fast::Hasher::new(),
+            expected: None,
+            error: None,
+        }
+    }
+
+    fn write(&mut self, chunk: &[u8]) {
+        self.remaining -= chunk.len() as u64;
+        let Some(file) = &mut self.file else { return };
+        if self.error.is_none() {
+            self.hasher.update(chunk);
+            if let Err(e) = file.write_all(chunk) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl RequestHandler for FileHandler {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        // Only this connection's worker touches its state, so it can be worked on unlocked
+        let mut connection = self.connections.lock().unwrap().remove(&ctx.id()).unwrap_or_default();
+        let mut input = message;
+        while !input.is_empty() {
+            if let Some(upload) = &mut connection.upload {
+                let len = input.len().min(usize::try_from(upload.remaining).unwrap_or(usize::MAX));
+                upload.write(&input[..len]);
+                input = &input[len..];
+                if upload.remaining == 0 {
+                    self.finish(connection.upload.take().expect("checked above"), response);
+                }
+                continue;
+            }
+            let Some(end) = memchr(b'\n', input) else {
+                connection.line.extend_from_slice(input);
+                if connection.line.len() > MAX_LINE_LEN {
+                    connection.line.clear();
+                    error(response, "command line too long");
+                }
+                break;
+            };
+            connection.line.extend_from_slice(&input[..end]);
+            input = &input[end + 1..];
+            let line = std::mem::take(&mut connection.line);
+            self.execute(ctx, line.strip_suffix(b"\r").unwrap_or(&line), &mut connection.upload, response);
+        }
+        // The rest of an upload is framed apart from commands, so the command ACL does not read it
+        ctx.expect_body(connection.upload.as_ref().map_or(0, |upload| upload.remaining));
+        if !connection.line.is_empty() || connection.upload.is_some() {
+            self.connections.lock().unwrap().insert(ctx.id(), connection);
+        }
+    }
+
+    fn frame(&self, buffered: &[u8]) -> Option<usize> {
+        // One command line at a time, so an upload it starts is framed on its own; a
+        // line too long to wait for is passed on to be refused
+        match memchr(b'\n', buffered) {
+            Some(end) => Some(end + 1),
+            None if buffered.len() > MAX_LINE_LEN => Some(buffered.len()),
+            None => Some(0),
+        }
+    }
+
+    fn on_shutdown(&self, _ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        error(response, "server closing");
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        let connection = self.connections.lock().unwrap().remove(&connection_id);
+        if let Some(Upload { file: Some(_), temp_path, .. }) = connection.and_then(|connection| connection.upload) {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+fn error(response: &mut ResponseWriter, reason: &str) {
+    response.write(format!("ERR {}\n", reason).as_bytes());
+}
+
+/// Whether `name` is safe to use as a file name inside the storage directory
+fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && !name.starts_with('.')
+        && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_'))
+}
@@ -0,0 +1,202 @@
+//! Noticing connection floods and weathering them in a defensive mode.
+//!
+//! With a [`FloodPolicy`], a watcher thread counts the connections accepted
+//! every [`FloodPolicy::interval`] (refused ones included) and keeps a
+//! baseline: a moving average of the accept rate in normal times. A rate above
+//! [`FloodPolicy::factor`] times the baseline, and above
+//! [`FloodPolicy::min_rate`] so that a quiet server is not alarmed by a handful
+//! of clients, is a flood. The server logs it, sends a `flood` event to the
+//! admin interface's event stream, raises the `connection_flood` alert if
+//! alerts are configured, and defends itself until the rate has stayed under
+//! the threshold for [`FloodPolicy::calm`]:
+//!
+//! - connections idle for longer than [`FloodPolicy::timeout`] are closed
+//! - each client address may have at most [`FloodPolicy::max_connections_per_ip`]
+//!   connections open, or the usual cap if it is lower
+//! - new connections are refused instead of queued once
+//!   [`FloodPolicy::max_queued`] are already waiting for a worker
+//!
+//! The baseline is left alone while the server defends itself, so that a long
+//! flood does not become the new normal.
+
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::events::ServerEvent;
+use crate::server::ServerState;
+use crate::telemetry::MetricsSnapshot;
+
+/// How many times the baseline accept rate counts as a flood by default
+pub const DEFAULT_FLOOD_FACTOR: f64 = 5.0;
+/// Accepts per second below which nothing counts as a flood by default
+pub const DEFAULT_FLOOD_MIN_RATE: f64 = 50.0;
+/// How long the rate must stay under the threshold before defences are lifted by default
+pub const DEFAULT_FLOOD_CALM: Duration = Duration::from_secs(30);
+/// Idle time after which connections are closed in defensive mode by default
+pub const DEFAULT_DEFENSIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections each client address may have open in defensive mode by default
+pub const DEFAULT_DEFENSIVE_CONNECTIONS_PER_IP: usize = 4;
+
+/// Weight of each new sample in the baseline
+const BASELINE_WEIGHT: f64 = 0.1;
+
+/// What counts as a flood, and how the server defends itself against one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloodPolicy {
+    /// Multiple of the baseline accept rate that counts as a flood
+    pub factor: f64,
+    /// Accepts per second a flood must also exceed
+    pub min_rate: f64,
+    /// How often the accept rate is sampled
+    pub interval: Duration,
+    /// How long the rate must stay under the threshold before defences are lifted
+    pub calm: Duration,
+    /// Idle time after which connections are closed while defending
+    pub timeout: Duration,
+    /// Connections each client address may have open while defending
+    pub max_connections_per_ip: usize,
+    /// Connections that may wait for a worker while defending before more are refused
+    pub max_queued: usize,
+}
+
+impl Default for FloodPolicy {
+    fn default() -> Self {
+        Self {
+            factor: DEFAULT_FLOOD_FACTOR,
+            min_rate: DEFAULT_FLOOD_MIN_RATE,
+            interval: Duration::from_secs(1),
+            calm: DEFAULT_FLOOD_CALM,
+            timeout: DEFAULT_DEFENSIVE_TIMEOUT,
+            max_connections_per_ip: DEFAULT_DEFENSIVE_CONNECTIONS_PER_IP,
+            max_queued: 0,
+        }
+    }
+}
+
+/// Every connection the server accepted, whether or not it was then refused
+fn accepts(snapshot: &MetricsSnapshot) -> u64 {
+    snapshot.connections_accepted
+        + snapshot.connections_denied
+        + snapshot.connections_over_ip_cap
+        + snapshot.connections_banned
+        + snapshot.connections_over_queue
+}
+
+/// A change in whether the server is defending itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Flood { rate: f64, baseline: f64 },
+    Subsided { rate: f64, baseline: f64, lasted: Duration },
+}
+
+/// The baseline accept rate, and whether the latest samples are a flood
+#[derive(Debug)]
+struct Detector {
+    policy: FloodPolicy,
+    /// `None` until the first sample
+    baseline: Option<f64>,
+    /// When defences went up, and since when the rate has been back under the threshold
+    defending: Option<(Instant, Option<Instant>)>,
+}
+
+impl Detector {
+    fn new(policy: FloodPolicy) -> Self {
+        Self { policy, baseline: None, defending: None }
+    }
+
+    fn threshold(&self) -> f64 {
+        (self.baseline.unwrap_or_default() * self.policy.factor).max(self.policy.min_rate)
+    }
+
+    /// Takes in the accept rate over the last interval, ending at `now`
+    fn sample(&mut self, rate: f64, now: Instant) -> Option<Change> {
+        let flooding = rate > self.threshold();
+        match &mut self.defending {
+            None if flooding => {
+                self.defending = Some((now, None));
+                Some(Change::Flood { rate, baseline: self.baseline.unwrap_or_default() })
+            }
+            None => {
+                let baseline = self.baseline.map_or(rate, |baseline| baseline + (rate - baseline) * BASELINE_WEIGHT);
+                self.baseline = Some(baseline);
+                None
+            }
+            Some((_, calm_since)) if flooding => {
+                *calm_since = None;
+                None
+            }
+            Some((since, calm_since)) => {
+                let calm_since = *calm_since.get_or_insert(now);
+                if now.duration_since(calm_since) < self.policy.calm {
+                    return None;
+                }
+                let lasted = calm_since.duration_since(*since);
+                self.defending = None;
+                Some(Change::Subsided { rate, baseline: self.baseline.unwrap_or_default(), lasted })
+            }
+        }
+    }
+}
+
+/// Background thread watching the accept rate and switching defensive mode on and off
+pub(crate) struct FloodWatcher {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl FloodWatcher {
+    /// Starts sampling the server's accepts according to `policy`
+    pub(crate) fn start(policy: FloodPolicy, server_state: Arc<ServerState>) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut detector = Detector::new(policy);
+            let mut last = (Instant::now(), accepts(&server_state.metrics.snapshot()));
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(policy.interval) {
+                let now = (Instant::now(), accepts(&server_state.metrics.snapshot()));
+                let elapsed = now.0.duration_since(last.0).as_secs_f64().max(f64::EPSILON);
+                let rate = now.1.saturating_sub(last.1) as f64 / elapsed;
+                last = now;
+                match detector.sample(rate, now.0) {
+                    Some(Change::Flood { rate, baseline }) => flood(&server_state, &policy, rate, baseline),
+                    Some(Change::Subsided { rate, baseline, lasted }) => {
+                        subsided(&server_state, rate, baseline, lasted)
+                    }
+                    None => {}
+                }
+            }
+        });
+        Self { stop_tx, handle }
+    }
+
+    /// Stops the watcher thread
+    pub(crate) fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn flood(server_state: &ServerState, policy: &FloodPolicy, rate: f64, baseline: f64) {
+    let metrics = &server_state.metrics;
+    metrics.floods.fetch_add(1, Ordering::Relaxed);
+    metrics.defensive_mode.store(true, Ordering::Relaxed);
+    let message = format!(
+        "Flood: {:.0} connections/s against a baseline of {:.1}/s; defending with a {:?} idle timeout, \
+         {} connections per IP, and at most {} queued",
+        rate, baseline, policy.timeout, policy.max_connections_per_ip, policy.max_queued
+    );
+    log::warn!("{}", message);
+    server_state.log.write(&message);
+    server_state.events.publish(ServerEvent::Flood { defensive: true, rate, baseline });
+}
+
+fn subsided(server_state: &ServerState, rate: f64, baseline: f64, lasted: Duration) {
+    server_state.metrics.defensive_mode.store(false, Ordering::Relaxed);
+    let message =
+        format!("Flood: subsided after {}s, down to {:.0} connections/s; defences lifted", lasted.as_secs(), rate);
+    log::warn!("{}", message);
+    server_state.log.write(&message);
+    server_state.events.publish(ServerEvent::Flood { defensive: false, rate, baseline });
+}
@@ -0,0 +1,366 @@
+//! Request handlers: what the server does with each message a client sends.
+//!
+//! The server owns the socket, timeouts, metrics, and hooks; a
+//! [`RequestHandler`] sees the bytes of one message and the [`ConnectionCtx`]
+//! it arrived on, and writes its reply into a [`ResponseWriter`].
+//! [`EchoHandler`] is the default.
+
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::buffers::BufferPool;
+use crate::config::Config;
+use crate::connections::ConnectionEntry;
+use crate::outbox::Outbox;
+use crate::protocol::{ConnectionMode, ECHO_PREFIX, SHUTDOWN_NOTICE};
+use crate::bans::Offence;
+use crate::server::ServerState;
+use crate::telemetry::Metrics;
+
+/// Reply to a single message, sent to the client once the handler returns
+///
+/// A handler that writes nothing sends no reply. A file passed to
+/// [`send_file`](Self::send_file) follows the written bytes; on a plain TCP
+/// connection on Linux it goes from the page cache to the socket without being
+/// copied through the server.
+#[derive(Debug, Default)]
+pub struct ResponseWriter {
+    buffer: Vec<u8>,
+    /// File contents sent after the buffer, and how many bytes of them
+    file: Option<(File, u64)>,
+    close: bool,
+    /// Where the buffer goes back to when the writer is dropped
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl ResponseWriter {
+    /// Creates an empty response
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty response in a buffer from `pool`, given back when the writer is dropped
+    pub(crate) fn pooled(pool: &Arc<BufferPool>) -> Self {
+        Self { buffer: pool.take(), file: None, close: false, pool: Some(Arc::clone(pool)) }
+    }
+
+    /// Appends `bytes` to the response
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Follows the written bytes with the next `len` bytes of `file`, from its current position
+    ///
+    /// The file must have at least `len` bytes left: the connection is closed
+    /// if it runs out early, since the client was promised more. Middleware sees
+    /// only the written bytes, never the file's contents.
+    pub fn send_file(&mut self, file: File, len: u64) {
+        self.file = Some((file, len));
+    }
+
+    /// Bytes of file contents following the written bytes
+    pub fn file_len(&self) -> u64 {
+        self.file.as_ref().map_or(0, |(_, len)| *len)
+    }
+
+    /// The file to send after the written bytes, if any
+    pub(crate) fn take_file(&mut self) -> Option<(File, u64)> {
+        self.file.take()
+    }
+
+    /// Bytes written so far
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Whether nothing has been written
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Discards everything written so far, and any file to follow it
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.file = None;
+    }
+
+    /// Closes the connection once this response has been sent
+    pub fn close(&mut self) {
+        self.close = true;
+    }
+
+    /// Whether the connection closes after this response
+    pub fn closes(&self) -> bool {
+        self.close
+    }
+
+    /// Bytes allocated for the response, which outlast it as the writer is reused
+    pub(crate) fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Empties the writer for the next message on the connection
+    pub(crate) fn reset(&mut self) {
+        self.buffer.clear();
+        self.file = None;
+        self.close = false;
+    }
+}
+
+impl Drop for ResponseWriter {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.put(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+impl io::Write for ResponseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the server knows about the connection a message arrived on
+#[derive(Debug)]
+pub struct ConnectionCtx<'a> {
+    connection: &'a Arc<ConnectionEntry>,
+    config: &'a Config,
+    server: &'a ServerState,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    tls_identity: Option<&'a TlsIdentity>,
+}
+
+impl<'a> ConnectionCtx<'a> {
+    pub(crate) fn new(
+        connection: &'a Arc<ConnectionEntry>,
+        config: &'a Config,
+        server: &'a ServerState,
+        peer_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> Self {
+        Self { connection, config, server, peer_addr, local_addr, tls_identity: None }
+    }
+
+    /// Server-assigned id of the connection, unique for the server's lifetime
+    pub fn id(&self) -> u64 {
+        self.connection.id
+    }
+
+    /// Address of the client as it appears in the log
+    pub fn peer(&self) -> &str {
+        &self.connection.peer
+    }
+
+    /// Address of the client, if the socket could report it
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Address of the server end of the connection
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Identity from the client's certificate, for clients that presented one over TLS
+    ///
+    /// Always `None` on plain TCP connections.
+    pub fn tls_identity(&self) -> Option<&TlsIdentity> {
+        self.tls_identity
+    }
+
+    /// Who the client authenticated as, for connections an authentication layer has accepted
+    pub fn identity(&self) -> Option<&str> {
+        self.connection.identity()
+    }
+
+    /// Records who the client authenticated as, for the command ACL and the admin interface
+    ///
+    /// Called by authentication middleware once a client proves who it is. A
+    /// connection keeps the first identity it is given.
+    pub fn set_identity(&self, identity: impl Into<String>) {
+        self.connection.set_identity(identity.into());
+    }
+
+    /// Whether the command ACL has any rules, so that commands need checking against it
+    pub(crate) fn checks_commands(&self) -> bool {
+        !self.server.commands.read().unwrap().is_empty()
+    }
+
+    /// Whether the command ACL lets this client run `command`; see [`crate::acl`]
+    ///
+    /// The command is only decoded if there are rules to check it against.
+    pub(crate) fn may_run(&self, command: &[u8]) -> bool {
+        let acl = self.server.commands.read().unwrap();
+        let ip = self.peer_addr.map(|addr| addr.ip());
+        acl.is_empty() || acl.check(&String::from_utf8_lossy(command), ip, self.identity())
+    }
+
+    /// Configuration in effect when the connection opened
+    ///
+    /// Changes made while it is open apply to the next connection.
+    pub fn config(&self) -> &Config {
+        self.config
+    }
+
+    /// The server's counters, for layers recording events of their own such as throttled requests
+    pub fn metrics(&self) -> &Metrics {
+        &self.server.metrics
+    }
+
+    /// Records a security-relevant event, such as a failed login, in the server log
+    ///
+    /// The line is prefixed with `Audit:` so the events can be picked out of the rest of the log.
+    pub fn audit(&self, event: &str) {
+        self.server.log.write(&format!("Audit: {}", event));
+    }
+
+    /// Counts `offence` against the client's address toward a temporary ban
+    ///
+    /// Does nothing unless the server bans automatically; see [`crate::bans`].
+    pub fn report(&self, offence: Offence) {
+        if let Some(addr) = self.peer_addr {
+            self.server.bans.record(addr.ip(), offence);
+        }
+    }
+
+    /// Whether the connection's messages must be text; see [`ConnectionMode`]
+    pub fn mode(&self) -> ConnectionMode {
+        self.connection.mode().unwrap_or(self.server.connection_mode)
+    }
+
+    /// Switches the connection to `mode` for the messages after this one
+    pub fn set_mode(&self, mode: ConnectionMode) {
+        self.connection.set_mode(mode);
+    }
+
+    /// Announces that the next `len` bytes from the client are the body of the command just handled
+    ///
+    /// The server passes the body to [`RequestHandler::on_message`] as it
+    /// arrives, in messages of its own that hold nothing else, without asking
+    /// [`RequestHandler::frame`] where they end. Middleware that reads commands,
+    /// such as the command ACL, leaves them alone. Announcing again replaces
+    /// what is left of an earlier body.
+    pub fn expect_body(&self, len: u64) {
+        self.connection.expect_body(len);
+    }
+
+    /// Whether the message being handled is part of a body announced with [`expect_body`](Self::expect_body)
+    pub fn in_body(&self) -> bool {
+        self.connection.in_body()
+    }
+
+    /// Messages received so far, including the one being handled
+    pub fn messages(&self) -> u64 {
+        self.connection.messages.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received so far, including the message being handled
+    pub fn bytes_received(&self) -> u64 {
+        self.connection.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent back so far, not counting the reply being written
+    pub fn bytes_sent(&self) -> u64 {
+        self.connection.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// How long the connection has been open
+    pub fn age(&self) -> Duration {
+        self.connection.age()
+    }
+
+    /// Queue for sending the client messages outside of replies
+    ///
+    /// The first call starts a writer thread for the connection; later calls
+    /// return the same queue. It closes when the connection does.
+    pub fn outbox(&self) -> Outbox {
+        self.connection.outbox(&self.server.memory)
+    }
+}
+
+/// Who a TLS client proved to be with its certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsIdentity {
+    /// Subject distinguished name of the client certificate
+    pub subject: String,
+}
+
+/// Protocol behavior plugged into a server
+///
+/// Called from worker threads, concurrently for different connections.
+pub trait RequestHandler: Send + Sync {
+    /// Handles one message from a client, writing the reply to `response`
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter);
+
+    /// Where the complete messages at the start of `buffered` end, for handlers whose messages can span reads
+    ///
+    /// `buffered` holds what the connection has read and not yet handled; the
+    /// bytes up to the returned length are passed to [`on_message`](Self::on_message)
+    /// together, and the rest wait for more to arrive. `None`, the default,
+    /// leaves it to the server's [`Framing`](crate::protocol::Framing).
+    ///
+    /// The command ACL reads a message as whole command lines, taking the first
+    /// word of each as a command, so a handler whose clients send commands
+    /// should only end messages at the end of a line. Anything else a client
+    /// sends, such as the contents of an upload, should be announced with
+    /// [`ConnectionCtx::expect_body`] so that it is not read as commands.
+    fn frame(&self, _buffered: &[u8]) -> Option<usize> {
+        None
+    }
+
+    /// The server is shutting down gracefully and closing this idle connection;
+    /// write a notice for the client to `response`, or nothing to close silently
+    ///
+    /// By default the notice is [`SHUTDOWN_NOTICE`].
+    fn on_shutdown(&self, _ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        response.write(SHUTDOWN_NOTICE);
+    }
+
+    /// A connection closed; forget any state kept for it
+    fn on_close(&self, _connection_id: u64) {}
+}
+
+impl<H: RequestHandler + ?Sized> RequestHandler for Arc<H> {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        (**self).on_message(ctx, message, response)
+    }
+
+    fn frame(&self, buffered: &[u8]) -> Option<usize> {
+        (**self).frame(buffered)
+    }
+
+    fn on_shutdown(&self, ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        (**self).on_shutdown(ctx, response)
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        (**self).on_close(connection_id)
+    }
+}
+
+/// The default handler: replies with the message prefixed by `Echo: `
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EchoHandler;
+
+impl RequestHandler for EchoHandler {
+    fn on_message(&self, _ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        response.write(ECHO_PREFIX);
+        response.write(message);
+    }
+}
@@ -0,0 +1,63 @@
+//! Periodic heartbeat written to the log and recorded as a metric.
+//!
+//! A heartbeat line appears every interval even when the server is idle, so
+//! log-based monitoring can tell a quiet server from a wedged or dead one. Each
+//! line carries the current connection count and throughput since the last beat.
+
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::logging::LogSink;
+use crate::pool::WorkerPool;
+use crate::telemetry::Metrics;
+
+/// Background thread emitting heartbeats
+pub struct Heartbeat {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Heartbeat {
+    /// Starts emitting a heartbeat every `interval`
+    pub fn start(interval: Duration, log: Arc<dyn LogSink>, metrics: Arc<Metrics>, pool: WorkerPool) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut previous = metrics.snapshot();
+            let mut previous_at = Instant::now();
+
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let current = metrics.snapshot();
+                let elapsed = previous_at.elapsed().as_secs_f64().max(f64::EPSILON);
+                let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / elapsed;
+                let pool = pool.stats();
+
+                log.write(&format!(
+                    "Heartbeat: {} active connections, {} accepted, {:.1} msg/s, {:.0} B/s in, {:.0} B/s out, {} queued jobs",
+                    current.connections_active,
+                    current.connections_accepted.saturating_sub(previous.connections_accepted),
+                    rate(current.messages_received, previous.messages_received),
+                    rate(current.bytes_received, previous.bytes_received),
+                    rate(current.bytes_sent, previous.bytes_sent),
+                    pool.queued
+                ));
+
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                metrics.heartbeats.fetch_add(1, Ordering::Relaxed);
+                metrics.last_heartbeat.store(now, Ordering::Relaxed);
+
+                previous = current;
+                previous_at = Instant::now();
+            }
+        });
+        Self { stop_tx, handle }
+    }
+
+    /// Stops the heartbeat thread
+    pub fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
@@ -0,0 +1,174 @@
+//! Lifecycle hooks invoked as connections come and go and the server shuts down.
+//!
+//! Hooks let embedders and plugins attach behavior such as notifications or quota
+//! accounting without touching the connection handling code.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+
+use crate::connections::ConnectionEntry;
+use crate::logging::LogSink;
+
+/// Phases of a graceful shutdown, reported in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPhase {
+    /// A shutdown signal was received
+    Requested,
+    /// The listener has stopped accepting; waiting for active connections
+    Draining,
+    /// Every connection has finished
+    Complete,
+}
+
+/// Callbacks for server lifecycle events
+///
+/// Every method has a no-op default so implementations only override what they
+/// need. Hooks run on the thread where the event happens (usually a worker), so
+/// they should return quickly.
+pub trait LifecycleHook: Send + Sync {
+    /// A connection has been accepted and is about to be served
+    fn on_connect(&self, _connection: &ConnectionEntry) {}
+
+    /// A connection has closed, with the error that ended it, if any
+    fn on_disconnect(&self, _connection: &ConnectionEntry, _error: Option<&io::Error>) {}
+
+    /// A message from the client has been handled
+    fn on_request(&self, _connection: &ConnectionEntry, _message: &[u8]) {}
+
+    /// The server has entered a new shutdown phase
+    fn on_shutdown(&self, _phase: ShutdownPhase) {}
+
+    /// The server was asked to reload; reopen files and re-read settings
+    fn on_reload(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Ordered collection of registered hooks
+#[derive(Default)]
+pub struct HookRegistry {
+    hooks: RwLock<Vec<Arc<dyn LifecycleHook>>>,
+}
+
+impl std::fmt::Debug for HookRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookRegistry")
+            .field("hooks", &self.hooks.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl HookRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a hook; hooks are invoked in registration order
+    pub fn register(&self, hook: Arc<dyn LifecycleHook>) {
+        self.hooks.write().unwrap().push(hook);
+    }
+
+    /// Notifies every hook that a connection was accepted
+    pub fn connected(&self, connection: &ConnectionEntry) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook.on_connect(connection);
+        }
+    }
+
+    /// Notifies every hook that a connection closed
+    pub fn disconnected(&self, connection: &ConnectionEntry, error: Option<&io::Error>) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook.on_disconnect(connection, error);
+        }
+    }
+
+    /// Notifies every hook that a message was handled
+    pub fn request_handled(&self, connection: &ConnectionEntry, message: &[u8]) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook.on_request(connection, message);
+        }
+    }
+
+    /// Notifies every hook that the server entered a shutdown phase
+    pub fn shutdown(&self, phase: ShutdownPhase) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook.on_shutdown(phase);
+        }
+    }
+
+    /// Asks every hook to reload, returning the first failure
+    ///
+    /// Every hook is given the chance to reload even if an earlier one fails.
+    pub fn reload(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for hook in self.hooks.read().unwrap().iter() {
+            if let Err(e) = hook.on_reload() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Built-in hook that records connections and shutdown phases in the server log
+pub struct LogHook {
+    sink: Arc<dyn LogSink>,
+}
+
+impl LogHook {
+    /// Records events to `sink`
+    pub fn new(sink: Arc<dyn LogSink>) -> Self {
+        Self { sink }
+    }
+
+    fn write(&self, message: &str) {
+        self.sink.write(message);
+    }
+}
+
+impl LifecycleHook for LogHook {
+    fn on_connect(&self, connection: &ConnectionEntry) {
+        self.write(&format!("Connection #{} opened from {}", connection.id, connection.peer));
+    }
+
+    fn on_disconnect(&self, connection: &ConnectionEntry, error: Option<&io::Error>) {
+        let outcome = match error {
+            Some(e) => format!(" with error: {}", e),
+            None => String::new(),
+        };
+        self.write(&format!(
+            "Connection #{} from {} closed after {:.1}s ({} messages, {} bytes in, {} bytes out){}",
+            connection.id,
+            connection.peer,
+            connection.age().as_secs_f64(),
+            connection.messages.load(Ordering::Relaxed),
+            connection.bytes_received.load(Ordering::Relaxed),
+            connection.bytes_sent.load(Ordering::Relaxed),
+            outcome
+        ));
+    }
+
+    fn on_shutdown(&self, phase: ShutdownPhase) {
+        let message = match phase {
+            ShutdownPhase::Requested => "Shutdown requested",
+            ShutdownPhase::Draining => "Stopped accepting connections, draining active connections",
+            ShutdownPhase::Complete => "Server shutdown complete",
+        };
+        self.write(message);
+        // Sinks that hold lines back must write them out before the server returns
+        if phase == ShutdownPhase::Complete {
+            if let Err(e) = self.sink.flush() {
+                log::error!("Failed to flush the log: {}", e);
+            }
+        }
+    }
+
+    /// Reopens the log so writes follow the path after the file has been rotated
+    fn on_reload(&self) -> io::Result<()> {
+        self.sink.reopen()
+    }
+}
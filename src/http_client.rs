@@ -0,0 +1,148 @@
+//! Minimal blocking HTTP/1.1 client used to push telemetry to collectors and to
+//! query the admin interface.
+//!
+//! Only plain `http://` URLs are supported; anything fancier is expected to go
+//! through a local collector or sidecar.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::error::{Result, RustbucketError};
+
+/// A parsed `http://host:port/path` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// Parses a plain HTTP URL, defaulting the port to 80 and the path to `/`
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            RustbucketError::InvalidConfig(format!("unsupported URL (only http:// is supported): {}", url))
+        })?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse().map_err(|_| RustbucketError::InvalidConfig(format!("invalid port in URL: {}", url)))?;
+                (host, port)
+            }
+            None => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(RustbucketError::InvalidConfig(format!("missing host in URL: {}", url)));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Returns a copy of this URL with `suffix` appended to the path
+    pub fn join(&self, suffix: &str) -> Self {
+        let mut path = self.path.trim_end_matches('/').to_string();
+        path.push_str(suffix);
+        Self {
+            host: self.host.clone(),
+            port: self.port,
+            path,
+        }
+    }
+}
+
+/// Maximum response body read back from a server
+const MAX_RESPONSE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Status and body of a completed request
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl HttpResponse {
+    /// Whether the status code is in the 2xx range
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends a JSON body with `POST` and returns the response status code
+pub fn post_json(url: &HttpUrl, body: &str, timeout: Duration) -> Result<u16> {
+    send("POST", url, Some(body), timeout).map(|response| response.status)
+}
+
+/// Sends a JSON body with `POST` and returns the full response
+pub fn post(url: &HttpUrl, body: &str, timeout: Duration) -> Result<HttpResponse> {
+    send("POST", url, Some(body), timeout)
+}
+
+/// Performs a `GET` request and returns the full response
+pub fn get(url: &HttpUrl, timeout: Duration) -> Result<HttpResponse> {
+    send("GET", url, None, timeout)
+}
+
+fn send(method: &str, url: &HttpUrl, body: Option<&str>, timeout: Duration) -> Result<HttpResponse> {
+    let (status_line, body) = exchange(method, url, body, timeout).map_err(RustbucketError::Network)?;
+    Ok(HttpResponse {
+        status: parse_status_line(&status_line)?,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Sends the request and returns the raw status line and body
+fn exchange(method: &str, url: &HttpUrl, body: Option<&str>, timeout: Duration) -> io::Result<(String, Vec<u8>)> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("could not resolve {}", url.host)))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n", method, url.path, url.host, url.port)?;
+    match body {
+        Some(body) => {
+            write!(stream, "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n", body.len())?;
+            stream.write_all(body.as_bytes())?;
+        }
+        None => stream.write_all(b"\r\n")?,
+    }
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+
+    // Skip the headers; every request asks the server to close the connection
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut body = Vec::new();
+    reader.take(MAX_RESPONSE_BYTES).read_to_end(&mut body)?;
+    Ok((status_line, body))
+}
+
+fn parse_status_line(line: &str) -> Result<u16> {
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| RustbucketError::Protocol(format!("malformed HTTP status line: {:?}", line.trim())))
+}
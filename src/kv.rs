@@ -0,0 +1,491 @@
+//! An in-memory key-value store served over the text protocol.
+//!
+//! [`Store`] holds the data and [`KvHandler`] answers commands such as
+//! `SET greeting hello` and `GET greeting` against it. A store opened with a
+//! snapshot file loads it on startup and writes it back on `SAVE`, every
+//! snapshot interval, and on shutdown, so data survives restarts. Adding a
+//! write-ahead log makes every acknowledged change survive a crash as well.
+//! Keys may be given a time to live, after which they disappear. A key holds
+//! either a single value or a list. Connections can also subscribe to channels
+//! and be sent whatever is published to them. Large values can be kept off the
+//! heap in a memory-mapped [`BlobStore`], and the rest counted against a
+//! [`MemoryBudget`] that refuses writes once it runs out.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use rustbucket::kv::{KvHandler, Store};
+//! use rustbucket::Server;
+//!
+//! let store = Arc::new(Store::open("kv.snapshot")?.with_wal("kv.wal")?);
+//! let _snapshots = store.start_snapshots(Duration::from_secs(300));
+//! let _expiry = store.start_expiry(Duration::from_millis(100));
+//! Server::builder().handler(KvHandler::new(Arc::clone(&store))).build()?.run()?;
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::error::{Result, RustbucketError};
+use crate::memory::{MemoryBudget, Pool};
+use expiry::deadline;
+use wal::{Op, Wal};
+
+mod blob;
+mod events;
+mod expiry;
+mod glob;
+mod handler;
+mod info;
+mod list;
+mod pubsub;
+mod scan;
+mod snapshot;
+mod wal;
+pub(crate) mod wire;
+
+pub use blob::{Blob, BlobBytes, BlobStore, BLOB_THRESHOLD, DEFAULT_SLOT_SIZE};
+pub use events::{KeyEvent, KEYEVENT_PREFIX, KEYSPACE_PREFIX};
+pub use expiry::ExpiryTask;
+pub use handler::KvHandler;
+pub use info::KeyspaceInfo;
+pub use list::ListEnd;
+pub use pubsub::{PubSub, MAX_PATTERN_LEN};
+pub use scan::DEFAULT_SCAN_COUNT;
+pub use snapshot::SnapshotTask;
+
+/// Keys and values are arbitrary bytes
+pub type Bytes = Vec<u8>;
+
+/// Expired keys buffered per subscriber before new ones are dropped
+const EXPIRED_CAPACITY: usize = 1024;
+
+/// What a key holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(Bytes),
+    /// A string long enough to be kept in the store's [`BlobStore`]
+    Blob(Arc<Blob>),
+    /// Never empty; a list is removed with its last item
+    List(VecDeque<Bytes>),
+}
+
+/// A stored value and when it expires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub(crate) value: Value,
+    pub(crate) expires_at: Option<SystemTime>,
+}
+
+impl Entry {
+    pub(crate) fn string(value: Bytes, expires_at: Option<SystemTime>) -> Self {
+        Self { value: Value::String(value), expires_at }
+    }
+}
+
+impl Entry {
+    fn is_live(&self, now: SystemTime) -> bool {
+        self.expires_at.is_none_or(|deadline| deadline > now)
+    }
+}
+
+/// Entries plus an index of their deadlines, kept in step under one lock
+#[derive(Debug, Default)]
+struct Data {
+    entries: HashMap<Bytes, Entry>,
+    /// Deadline and key of every entry that expires, soonest first
+    deadlines: BTreeSet<(SystemTime, Bytes)>,
+    /// Estimated heap bytes of the entries, as [`info::footprint`] counts them
+    bytes: usize,
+    /// Told of every change to `bytes`
+    budget: Option<Arc<MemoryBudget>>,
+}
+
+impl Data {
+    /// Indexes `entries`, dropping those that expired while the store was closed
+    fn from_entries(mut entries: HashMap<Bytes, Entry>) -> Self {
+        let now = SystemTime::now();
+        entries.retain(|_, entry| entry.is_live(now));
+        let deadlines = entries
+            .iter()
+            .filter_map(|(key, entry)| Some((entry.expires_at?, key.clone())))
+            .collect();
+        let bytes = entries.iter().map(|(key, entry)| info::footprint(key, &entry.value)).sum();
+        Self { entries, deadlines, bytes, budget: None }
+    }
+
+    fn grow(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        if let Some(budget) = &self.budget {
+            budget.reserve(Pool::Kv, bytes);
+        }
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.bytes -= bytes;
+        if let Some(budget) = &self.budget {
+            budget.release(Pool::Kv, bytes);
+        }
+    }
+
+    /// Fails if storing `bytes` more would take the store over its memory budget
+    fn admit(&self, bytes: usize) -> Result<()> {
+        match &self.budget {
+            Some(budget) if !budget.admit(bytes) => Err(RustbucketError::OutOfMemory),
+            _ => Ok(()),
+        }
+    }
+
+    fn live(&self, key: &[u8], now: SystemTime) -> Option<&Entry> {
+        self.entries.get(key).filter(|entry| entry.is_live(now))
+    }
+
+    fn insert(&mut self, key: Bytes, entry: Entry) {
+        let expires_at = entry.expires_at;
+        self.grow(info::footprint(&key, &entry.value));
+        let old = self.entries.insert(key.clone(), entry);
+        if let Some(old) = old {
+            self.shrink(info::footprint(&key, &old.value));
+            if let Some(deadline) = old.expires_at {
+                self.deadlines.remove(&(deadline, key.clone()));
+            }
+        }
+        if let Some(deadline) = expires_at {
+            self.deadlines.insert((deadline, key));
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.shrink(info::footprint(key, &entry.value));
+        if let Some(deadline) = entry.expires_at {
+            self.deadlines.remove(&(deadline, key.to_vec()));
+        }
+        Some(entry)
+    }
+
+    fn set_deadline(&mut self, key: &[u8], expires_at: Option<SystemTime>) {
+        let Some(entry) = self.entries.get_mut(key) else { return };
+        if let Some(old) = std::mem::replace(&mut entry.expires_at, expires_at) {
+            self.deadlines.remove(&(old, key.to_vec()));
+        }
+        if let Some(deadline) = expires_at {
+            self.deadlines.insert((deadline, key.to_vec()));
+        }
+    }
+
+    /// Removes and returns the keys whose deadline has passed
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<Bytes> {
+        let mut expired = Vec::new();
+        while let Some((deadline, _)) = self.deadlines.first() {
+            if *deadline > now {
+                break;
+            }
+            let (_, key) = self.deadlines.pop_first().expect("checked above");
+            if let Some(entry) = self.entries.remove(&key) {
+                self.shrink(info::footprint(&key, &entry.value));
+            }
+            expired.push(key);
+        }
+        expired
+    }
+}
+
+/// Thread-safe map from keys to values, optionally backed by a snapshot file and write-ahead log
+#[derive(Debug, Default)]
+pub struct Store {
+    data: RwLock<Data>,
+    snapshot_path: Option<PathBuf>,
+    /// Changes are logged while the data lock is held, so the log's order is the order they were applied
+    wal: Option<Wal>,
+    /// Held while saving so concurrent saves do not share the temporary file
+    saving: Mutex<()>,
+    expired_subscribers: Mutex<Vec<SyncSender<Bytes>>>,
+    /// Locked and notified after every push, waking blocked pops
+    pushes: Mutex<()>,
+    pushed: Condvar,
+    pubsub: PubSub,
+    /// Held shared by each command a [`KvHandler`] runs and exclusively while it runs a transaction
+    ///
+    /// It guards no data of its own, so it stays usable after a panic poisons it.
+    commands: RwLock<()>,
+    blobs: Option<BlobStore>,
+    /// Lookups by `GET` that found or missed their key
+    hits: AtomicU64,
+    misses: AtomicU64,
+    keyspace_events: bool,
+}
+
+impl Store {
+    /// Creates an empty store that is never saved
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a store saved to `path`, loading its last snapshot if there is one
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let data = Data::from_entries(snapshot::load(&path)?);
+        log::info!("Loaded {} keys from {}", data.entries.len(), path.display());
+        Ok(Self { data: RwLock::new(data), snapshot_path: Some(path), ..Self::default() })
+    }
+
+    /// Logs every change to `path` before acknowledging it, after replaying the changes already there
+    ///
+    /// The log is replayed over whatever the store holds, normally its last
+    /// snapshot, and shortened each time a snapshot is saved.
+    pub fn with_wal(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let data = self.data.get_mut().unwrap();
+        let wal = Wal::open(path.as_ref(), data)?;
+        data.remove_expired(SystemTime::now());
+        self.wal = Some(wal);
+        Ok(self)
+    }
+
+    /// Keeps values of [`BLOB_THRESHOLD`] bytes or more in `blobs` instead of on the heap
+    ///
+    /// Large values already in the store are moved over. Values that do not
+    /// fit once `blobs` is full stay on the heap.
+    pub fn with_blobs(mut self, blobs: BlobStore) -> Self {
+        let data = self.data.get_mut().unwrap();
+        let mut moved = 0;
+        for entry in data.entries.values_mut() {
+            let Value::String(value) = &entry.value else { continue };
+            if value.len() < BLOB_THRESHOLD {
+                continue;
+            }
+            if let Some(blob) = blobs.store(value) {
+                moved += value.len();
+                entry.value = Value::Blob(Arc::new(blob));
+            }
+        }
+        data.shrink(moved);
+        self.blobs = Some(blobs);
+        self
+    }
+
+    /// Counts the store's keys and values against `budget`, refusing writes while it is exceeded
+    ///
+    /// `SET` and pushes fail with [`RustbucketError::OutOfMemory`] if they
+    /// would take the budget over its limit. Values in the blob store do not
+    /// count, as they are not on the heap.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        let data = self.data.get_mut().unwrap();
+        budget.reserve(Pool::Kv, data.bytes);
+        if let Some(old) = data.budget.replace(budget) {
+            old.release(Pool::Kv, data.bytes);
+        }
+        self
+    }
+
+    /// Channels published to through the store's handlers
+    pub fn pubsub(&self) -> &PubSub {
+        &self.pubsub
+    }
+
+    /// Snapshot file the store is saved to, if any
+    pub fn snapshot_path(&self) -> Option<&Path> {
+        self.snapshot_path.as_deref()
+    }
+
+    /// The value stored under `key`
+    ///
+    /// Fails with [`RustbucketError::WrongType`] if the key holds a list.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_with(key, <[u8]>::to_vec)
+    }
+
+    /// Calls `f` with the value stored under `key`, read in place rather than copied out
+    ///
+    /// The store cannot be changed while `f` runs.
+    pub fn get_with<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>> {
+        let data = self.data.read().unwrap();
+        let entry = data.live(key, SystemTime::now());
+        let counter = if entry.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        match entry.map(|entry| &entry.value) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(f(value))),
+            Some(Value::Blob(blob)) => Ok(Some(f(&blob.bytes()))),
+            Some(Value::List(_)) => Err(RustbucketError::WrongType),
+        }
+    }
+
+    /// Stores `value` under `key`, replacing any previous value and time to live
+    ///
+    /// Fails without changing anything if the change cannot be logged.
+    pub fn set(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.write(key, value, None)
+    }
+
+    /// Stores `value` under `key` until `ttl` has passed
+    ///
+    /// Fails with [`RustbucketError::InvalidExpireTime`] if `ttl` ends past
+    /// what the clock can represent.
+    pub fn set_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        self.write(key, value, Some(deadline(SystemTime::now(), ttl)?))
+    }
+
+    fn write(&self, key: Bytes, value: Bytes, expires_at: Option<SystemTime>) -> Result<()> {
+        // Copied in before taking the lock; dropping the blob frees it again if logging fails
+        let blob = match &self.blobs {
+            Some(blobs) if value.len() >= BLOB_THRESHOLD => blobs.store(&value),
+            _ => None,
+        };
+        let mut data = self.data.write().unwrap();
+        data.admit(key.len() + value.len())?;
+        if let Some(wal) = &self.wal {
+            wal.append(Op::Set(&key, &value, expires_at))?;
+        }
+        let value = match blob {
+            Some(blob) => Value::Blob(Arc::new(blob)),
+            None => Value::String(value),
+        };
+        self.notify(&key, KeyEvent::Set);
+        data.insert(key, Entry { value, expires_at });
+        Ok(())
+    }
+
+    /// Removes `key`, returning whether it existed
+    ///
+    /// Fails without changing anything if the change cannot be logged.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let mut data = self.data.write().unwrap();
+        if data.live(key, SystemTime::now()).is_none() {
+            return Ok(false);
+        }
+        if let Some(wal) = &self.wal {
+            wal.append(Op::Delete(key))?;
+        }
+        self.notify(key, KeyEvent::Del);
+        Ok(data.remove(key).is_some())
+    }
+
+    /// Makes `key` expire after `ttl`, returning whether it exists
+    ///
+    /// Fails like [`set_with_ttl`](Self::set_with_ttl) if `ttl` is too long.
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        let now = SystemTime::now();
+        self.set_deadline(key, Some(deadline(now, ttl)?), KeyEvent::Expire, |entry| entry.is_live(now))
+    }
+
+    /// Removes the time to live of `key`, returning whether it had one
+    pub fn persist(&self, key: &[u8]) -> Result<bool> {
+        let now = SystemTime::now();
+        self.set_deadline(key, None, KeyEvent::Persist, |entry| entry.is_live(now) && entry.expires_at.is_some())
+    }
+
+    /// Changes the deadline of `key` if `applies` to its entry, publishing `event` if it does
+    fn set_deadline(
+        &self,
+        key: &[u8],
+        expires_at: Option<SystemTime>,
+        event: KeyEvent,
+        applies: impl Fn(&Entry) -> bool,
+    ) -> Result<bool> {
+        let mut data = self.data.write().unwrap();
+        if !data.entries.get(key).is_some_and(applies) {
+            return Ok(false);
+        }
+        if let Some(wal) = &self.wal {
+            wal.append(Op::Expire(key, expires_at))?;
+        }
+        data.set_deadline(key, expires_at);
+        self.notify(key, event);
+        Ok(true)
+    }
+
+    /// Time left before `key` expires
+    ///
+    /// `None` if the key does not exist, `Some(None)` if it never expires.
+    pub fn ttl(&self, key: &[u8]) -> Option<Option<Duration>> {
+        let now = SystemTime::now();
+        let data = self.data.read().unwrap();
+        let entry = data.live(key, now)?;
+        Some(entry.expires_at.map(|deadline| deadline.duration_since(now).unwrap_or_default()))
+    }
+
+    /// Number of keys stored
+    pub fn len(&self) -> usize {
+        let now = SystemTime::now();
+        let data = self.data.read().unwrap();
+        let expired = data.deadlines.iter().take_while(|(deadline, _)| *deadline <= now).count();
+        data.entries.len() - expired
+    }
+
+    /// Whether the store holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a receiver for every key that expires from now on
+    ///
+    /// Keys are sent when they are removed, which [`ExpiryTask`] does shortly
+    /// after their deadline. A subscriber that falls behind misses keys rather
+    /// than holding up the store.
+    pub fn subscribe_expired(&self) -> Receiver<Bytes> {
+        let (tx, rx) = mpsc::sync_channel(EXPIRED_CAPACITY);
+        self.expired_subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Removes every key whose time to live has passed, returning how many there were
+    pub fn remove_expired(&self) -> usize {
+        let expired = {
+            let mut data = self.data.write().unwrap();
+            let expired = data.remove_expired(SystemTime::now());
+            for key in &expired {
+                self.notify(key, KeyEvent::Expired);
+            }
+            expired
+        };
+        if expired.is_empty() {
+            return 0;
+        }
+        let mut subscribers = self.expired_subscribers.lock().unwrap();
+        for key in &expired {
+            subscribers.retain(|tx| !matches!(tx.try_send(key.clone()), Err(TrySendError::Disconnected(_))));
+        }
+        expired.len()
+    }
+
+    /// Writes a point-in-time snapshot to the snapshot file
+    ///
+    /// The file is replaced atomically, so a crash mid-save leaves the previous
+    /// snapshot intact. Writers are only blocked while the data is copied. Once
+    /// the snapshot is on disk, the write-ahead log drops what it covers.
+    pub fn save(&self) -> Result<()> {
+        let path = self
+            .snapshot_path
+            .as_deref()
+            .ok_or_else(|| RustbucketError::InvalidConfig("the store has no snapshot file".to_string()))?;
+        let _saving = self.saving.lock().unwrap();
+        let (entries, mark) = {
+            let data = self.data.read().unwrap();
+            (data.entries.clone(), self.wal.as_ref().map(Wal::mark))
+        };
+        snapshot::save(path, &entries)?;
+        log::debug!("Saved {} keys to {}", entries.len(), path.display());
+        if let (Some(wal), Some(mark)) = (&self.wal, mark) {
+            wal.compact(mark)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the store every `interval` in the background, and once more when the task is dropped
+    ///
+    /// A zero interval only saves when the task is dropped.
+    pub fn start_snapshots(self: &Arc<Self>, interval: Duration) -> SnapshotTask {
+        SnapshotTask::start(Arc::clone(self), interval)
+    }
+
+    /// Removes expired keys every `interval` in the background until the task is dropped
+    pub fn start_expiry(self: &Arc<Self>, interval: Duration) -> ExpiryTask {
+        ExpiryTask::start(Arc::clone(self), interval)
+    }
+}
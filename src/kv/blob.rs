@@ -0,0 +1,196 @@
+//! Off-heap storage for large values in a memory-mapped file.
+//!
+//! A [`BlobStore`] divides a file into fixed-size slots and keeps each value
+//! in a run of consecutive slots, found first-fit; freed runs are merged with
+//! their free neighbours. Values kept there live in the page cache rather than
+//! on the heap, so the kernel can write cold ones back to the file under memory
+//! pressure instead of swapping, and replies are copied straight from the
+//! mapping into the response.
+//!
+//! The file is scratch space: it is emptied whenever a store is created on it
+//! and nothing in it survives a restart. Snapshots and the write-ahead log keep
+//! the durable copy of every value.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+use memmap2::MmapMut;
+
+use crate::error::{Result, RustbucketError};
+
+/// Bytes per slot by default: one page
+pub const DEFAULT_SLOT_SIZE: usize = 4096;
+
+/// Values at least this long are moved to a store's blob store, if it has one
+pub const BLOB_THRESHOLD: usize = 16 * 1024;
+
+/// Fixed-size slots in a memory-mapped file, holding values off the heap
+///
+/// Clones share the same file.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    slab: Arc<Slab>,
+}
+
+#[derive(Debug)]
+struct Slab {
+    path: PathBuf,
+    slot_size: usize,
+    slots: usize,
+    /// Written only while storing a value, into slots no [`Blob`] owns yet
+    map: RwLock<MmapMut>,
+    free: Mutex<FreeRuns>,
+}
+
+/// Runs of free slots, by first slot
+#[derive(Debug, Default)]
+struct FreeRuns {
+    runs: BTreeMap<usize, usize>,
+    free_slots: usize,
+}
+
+impl FreeRuns {
+    /// Takes the first run of `count` free slots, returning where it starts
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        let (&start, &len) = self.runs.iter().find(|(_, &len)| len >= count)?;
+        self.runs.remove(&start);
+        if len > count {
+            self.runs.insert(start + count, len - count);
+        }
+        self.free_slots -= count;
+        Some(start)
+    }
+
+    fn release(&mut self, mut start: usize, mut count: usize) {
+        self.free_slots += count;
+        if let Some((&before, &len)) = self.runs.range(..start).next_back() {
+            if before + len == start {
+                self.runs.remove(&before);
+                start = before;
+                count += len;
+            }
+        }
+        if let Some(len) = self.runs.remove(&(start + count)) {
+            count += len;
+        }
+        self.runs.insert(start, count);
+    }
+}
+
+impl BlobStore {
+    /// Creates a store of `slots` slots of `slot_size` bytes each in the file at `path`
+    ///
+    /// The file is created or emptied and then sized to hold every slot.
+    pub fn create(path: impl Into<PathBuf>, slot_size: usize, slots: usize) -> Result<Self> {
+        let path = path.into();
+        let error = |source| RustbucketError::Storage { path: path.clone(), source };
+        if slot_size == 0 || slots == 0 {
+            return Err(RustbucketError::InvalidConfig("a blob store needs at least one non-empty slot".into()));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).map_err(error)?;
+        file.set_len((slot_size * slots) as u64).map_err(error)?;
+        // SAFETY: the file was just created or truncated for this store, which is
+        // the only thing that maps it; other processes are not expected to touch it
+        let map = unsafe { MmapMut::map_mut(&file) }.map_err(error)?;
+        let free = FreeRuns { runs: BTreeMap::from([(0, slots)]), free_slots: slots };
+        Ok(Self {
+            slab: Arc::new(Slab { path, slot_size, slots, map: RwLock::new(map), free: Mutex::new(free) }),
+        })
+    }
+
+    /// Copies `value` into free slots, or returns `None` if no run of them is long enough
+    pub fn store(&self, value: &[u8]) -> Option<Blob> {
+        let slots = value.len().div_ceil(self.slab.slot_size).max(1);
+        let start = self.slab.free.lock().unwrap().allocate(slots)?;
+        let offset = start * self.slab.slot_size;
+        self.slab.map.write().unwrap()[offset..offset + value.len()].copy_from_slice(value);
+        Some(Blob { slab: Arc::clone(&self.slab), start, slots, len: value.len() })
+    }
+
+    /// File the slots are mapped from
+    pub fn path(&self) -> &Path {
+        &self.slab.path
+    }
+
+    /// Bytes the store can hold in all
+    pub fn capacity(&self) -> usize {
+        self.slab.slot_size * self.slab.slots
+    }
+
+    /// Bytes in slots no value occupies
+    pub fn free(&self) -> usize {
+        self.slab.slot_size * self.slab.free.lock().unwrap().free_slots
+    }
+}
+
+/// A value held in a [`BlobStore`]; its slots are freed when it is dropped
+pub struct Blob {
+    slab: Arc<Slab>,
+    start: usize,
+    slots: usize,
+    len: usize,
+}
+
+impl Blob {
+    /// Length of the value in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The value, read in place from the mapping
+    pub fn bytes(&self) -> BlobBytes<'_> {
+        let offset = self.start * self.slab.slot_size;
+        BlobBytes { map: self.slab.map.read().unwrap(), range: offset..offset + self.len }
+    }
+}
+
+impl Drop for Blob {
+    fn drop(&mut self) {
+        self.slab.free.lock().unwrap().release(self.start, self.slots);
+    }
+}
+
+impl fmt::Debug for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blob").field("start", &self.start).field("len", &self.len).finish()
+    }
+}
+
+impl PartialEq for Blob {
+    fn eq(&self, other: &Self) -> bool {
+        if !Arc::ptr_eq(&self.slab, &other.slab) {
+            return *self.bytes() == *other.bytes();
+        }
+        // One read guard for both, since taking a second could wait behind a writer
+        let bytes = self.bytes();
+        let offset = other.start * other.slab.slot_size;
+        *bytes == bytes.map[offset..offset + other.len]
+    }
+}
+
+impl Eq for Blob {}
+
+/// Borrowed contents of a [`Blob`]
+///
+/// Values cannot be stored in the same [`BlobStore`] while this is held.
+pub struct BlobBytes<'a> {
+    map: RwLockReadGuard<'a, MmapMut>,
+    range: Range<usize>,
+}
+
+impl Deref for BlobBytes<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
@@ -0,0 +1,79 @@
+//! Keyspace events: changes to keys published on reserved channels.
+//!
+//! A store with events enabled publishes each change twice through its
+//! [`PubSub`](crate::kv::PubSub): the event's name on `__keyspace__:<key>`,
+//! for watching particular keys, and the key on `__keyevent__:<event>`, for
+//! watching one kind of change. Events are published while the change holds
+//! the store's lock, so subscribers see them in the order the changes were
+//! made.
+
+use crate::kv::{ListEnd, Store};
+
+/// Channel prefix for events about one key; the message is the event's name
+pub const KEYSPACE_PREFIX: &[u8] = b"__keyspace__:";
+/// Channel prefix for one kind of event; the message is the key
+pub const KEYEVENT_PREFIX: &[u8] = b"__keyevent__:";
+
+/// A change to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// A value was stored, with or without a time to live
+    Set,
+    /// The key was deleted, or its list was popped empty
+    Del,
+    /// The key was given a time to live
+    Expire,
+    /// The key's time to live was removed
+    Persist,
+    /// The key's time to live ran out and it was removed
+    Expired,
+    /// Items were pushed onto the list at the key
+    Push(ListEnd),
+    /// Items were popped from the list at the key
+    Pop(ListEnd),
+}
+
+impl KeyEvent {
+    /// Name published for the event, such as `set` or `lpush`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Set => "set",
+            Self::Del => "del",
+            Self::Expire => "expire",
+            Self::Persist => "persist",
+            Self::Expired => "expired",
+            Self::Push(ListEnd::Front) => "lpush",
+            Self::Push(ListEnd::Back) => "rpush",
+            Self::Pop(ListEnd::Front) => "lpop",
+            Self::Pop(ListEnd::Back) => "rpop",
+        }
+    }
+}
+
+/// Whether `channel` is one only the store publishes to
+pub(crate) fn is_reserved(channel: &[u8]) -> bool {
+    channel.starts_with(KEYSPACE_PREFIX) || channel.starts_with(KEYEVENT_PREFIX)
+}
+
+impl Store {
+    /// Publishes every change to a key on the keyspace event channels
+    pub fn with_keyspace_events(mut self) -> Self {
+        self.keyspace_events = true;
+        self
+    }
+
+    /// Whether changes are published on the keyspace event channels
+    pub fn keyspace_events(&self) -> bool {
+        self.keyspace_events
+    }
+
+    /// Publishes `event` for `key`, if events are enabled
+    pub(crate) fn notify(&self, key: &[u8], event: KeyEvent) {
+        if !self.keyspace_events {
+            return;
+        }
+        let name = event.name().as_bytes();
+        self.pubsub.publish(&[KEYSPACE_PREFIX, key].concat(), name);
+        self.pubsub.publish(&[KEYEVENT_PREFIX, name].concat(), key);
+    }
+}
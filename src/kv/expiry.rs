@@ -0,0 +1,64 @@
+//! Key expiration.
+//!
+//! Keys set with a time to live carry an absolute wall-clock deadline, so it
+//! means the same thing after the store is saved and reloaded. An expired key
+//! is invisible to readers the moment its deadline passes; [`ExpiryTask`]
+//! removes it from memory shortly after and tells subscribers it is gone.
+
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{Result, RustbucketError};
+use crate::kv::Store;
+
+/// Milliseconds since the Unix epoch, as deadlines are written to disk
+pub(crate) fn to_unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Inverse of [`to_unix_millis`]
+pub(crate) fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// The deadline `ttl` after `now`, refused if the clock or the files on disk cannot hold it
+pub(crate) fn deadline(now: SystemTime, ttl: Duration) -> Result<SystemTime> {
+    let fits_on_disk =
+        |deadline: &SystemTime| deadline.duration_since(UNIX_EPOCH).map_or(true, |since| since.as_millis() <= u64::MAX.into());
+    now.checked_add(ttl).filter(fits_on_disk).ok_or(RustbucketError::InvalidExpireTime)
+}
+
+/// Background thread removing expired keys at a fixed interval
+///
+/// Dropping the task stops the thread.
+#[derive(Debug)]
+pub struct ExpiryTask {
+    stop_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ExpiryTask {
+    pub(crate) fn start(store: Arc<Store>, interval: Duration) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let removed = store.remove_expired();
+                if removed > 0 {
+                    log::debug!("Removed {} expired keys", removed);
+                }
+            }
+        });
+        Self { stop_tx: Some(stop_tx), handle: Some(handle) }
+    }
+}
+
+impl Drop for ExpiryTask {
+    fn drop(&mut self) {
+        self.stop_tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Glob-style patterns over bytes, as used by `PSUBSCRIBE`.
+//!
+//! `*` matches any run of bytes, `?` any single byte, and `[...]` one byte
+//! from a set such as `[abc]`, `[a-z]`, or `[^0-9]`. A backslash makes the
+//! next byte literal.
+
+/// Whether `pattern` matches all of `text`
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and the text position it is currently standing in for
+    let mut backtrack = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, t));
+            continue;
+        }
+        if let Some(len) = match_token(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        // Let the last `*` swallow one more byte and retry from there
+        let Some((star_p, star_t)) = backtrack else { return false };
+        p = star_p;
+        t = star_t + 1;
+        backtrack = Some((star_p, t));
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// The bytes every match of `pattern` starts with
+pub(crate) fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern.iter().position(|byte| matches!(byte, b'*' | b'?' | b'[' | b'\\')).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Matches the single-byte token at the start of `pattern` against `byte`
+///
+/// Returns the token's length if it matches.
+fn match_token(pattern: &[u8], byte: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'\\', escaped, ..] => (*escaped == byte).then_some(2),
+        [b'[', class @ ..] => match match_class(class, byte) {
+            Some((matched, len)) => matched.then_some(len + 1),
+            // An unterminated class is a literal `[`
+            None => (byte == b'[').then_some(1),
+        },
+        [literal, ..] => (*literal == byte).then_some(1),
+    }
+}
+
+/// Matches `byte` against the class body after a `[`
+///
+/// Returns whether it matched and the length of the body including the
+/// closing `]`, or `None` if the class is never closed.
+fn match_class(class: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some(b'^' | b'!'));
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    loop {
+        let mut low = *class.get(i)?;
+        if low == b']' && i > usize::from(negated) {
+            return Some((matched != negated, i + 1));
+        }
+        if low == b'\\' {
+            i += 1;
+            low = *class.get(i)?;
+        }
+        if class.get(i + 1) == Some(&b'-') && class.get(i + 2).is_some_and(|&high| high != b']') {
+            let high = class[i + 2];
+            matched |= (low..=high).contains(&byte);
+            i += 3;
+        } else {
+            matched |= low == byte;
+            i += 1;
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! The KV command protocol.
+//!
+//! Each line of a message is one command: a command name followed by
+//! arguments separated by spaces. Arguments containing spaces are written in
+//! double quotes, with `\"` and `\\` escapes, and arbitrary bytes as
+//! `$<length>`, a newline, and the bytes. Every command gets a
+//! one-line reply: `OK`, a value, `(nil)` for a missing key, an integer, or
+//! `ERR <reason>`; a value that is not a plain line comes back with the same
+//! `$<length>` prefix. Commands returning several values, such as `LRANGE`,
+//! reply with `*<count>` on one line followed by one value per line.
+//!
+//! `SET key value EX seconds` (or `PX milliseconds`) sets a key that expires;
+//! `EXPIRE`, `PERSIST`, and `TTL` change and inspect the deadline of an
+//! existing key. `LPUSH`, `RPUSH`, `LPOP`, `RPOP`, and `LRANGE` work on
+//! lists, and `BLPOP`/`BRPOP` wait for a list to be pushed to. `SUBSCRIBE`
+//! makes the connection receive everything later sent to a channel with
+//! `PUBLISH`, in between the replies to its own commands; `PSUBSCRIBE` does
+//! the same for every channel matching a glob pattern of up to
+//! [`MAX_PATTERN_LEN`](crate::kv::MAX_PATTERN_LEN) bytes. A store with keyspace events enabled publishes
+//! its own changes on `__keyspace__:<key>` and `__keyevent__:<event>`, which
+//! clients may subscribe to but not publish on.
+//!
+//! `MULTI` starts a transaction: the commands after it are only queued,
+//! each replied to with `QUEUED`, until `EXEC` runs them all without another
+//! connection's commands in between and replies with `*<count>` followed by
+//! each command's reply. `DISCARD` drops the queue instead. A command failing
+//! inside a transaction does not undo the ones before it; a line that could
+//! not even be parsed while queueing makes `EXEC` discard the whole
+//! transaction.
+//!
+//! `SCAN cursor [MATCH pattern] [COUNT n]` walks the keys a few at a time:
+//! it replies with `*<count>`, the cursor to pass to the next call, and the
+//! keys it found, and a returned cursor of `0` means the walk is over.
+//!
+//! `INFO [section]` describes the server and its keyspace as `name:value`
+//! lines grouped under `# Section` headings, in the style of other cache
+//! servers: uptime, key counts, estimated memory, `GET` hits and misses, and
+//! replication. rustbucket does not replicate, so it always reports itself as
+//! a primary with no replicas.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::bans::Offence;
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+use crate::kv::wire::{self, encode_array};
+use crate::kv::{events, Bytes, ListEnd, Store, DEFAULT_SCAN_COUNT, MAX_PATTERN_LEN};
+use crate::memory::{heap_stats, process_memory};
+
+/// Answers KV commands against a [`Store`]
+#[derive(Debug)]
+pub struct KvHandler {
+    store: Arc<Store>,
+    /// Open transactions, by connection id
+    transactions: Mutex<HashMap<u64, Transaction>>,
+    /// When the handler was created, for the uptime `INFO` reports
+    started: Instant,
+}
+
+/// Commands queued after `MULTI`
+#[derive(Debug, Default)]
+struct Transaction {
+    commands: Vec<Vec<Bytes>>,
+    /// Set when a line could not be queued, so that `EXEC` runs nothing
+    aborted: bool,
+}
+
+impl Clone for KvHandler {
+    /// Serves the same store, without the transactions open on this handler's connections
+    fn clone(&self) -> Self {
+        Self { started: self.started, ..Self::new(Arc::clone(&self.store)) }
+    }
+}
+
+impl KvHandler {
+    /// Serves `store`, which may be shared with other handlers or servers
+    pub fn new(store: Arc<Store>) -> Self {
+        Self { store, transactions: Mutex::default(), started: Instant::now() }
+    }
+
+    /// The store being served
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    fn transactions(&self) -> MutexGuard<'_, HashMap<u64, Transaction>> {
+        // Commands run outside this lock, so a panic cannot leave a transaction half-queued
+        self.transactions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs or queues one command line, writing its reply
+    fn execute(
+        &self,
+        ctx: &ConnectionCtx<'_>,
+        args: Result<Vec<Bytes>, &'static str>,
+        response: &mut ResponseWriter,
+    ) {
+        let args = match args {
+            Ok(args) => args,
+            Err(reason) => {
+                if let Some(transaction) = self.transactions().get_mut(&ctx.id()) {
+                    transaction.aborted = true;
+                }
+                ctx.report(Offence::ProtocolError);
+                return error(response, reason);
+            }
+        };
+        let Some((name, rest)) = args.split_first() else { return };
+        let name = command_name(name);
+        match (name.as_str(), rest) {
+            ("MULTI", []) => match self.transactions().entry(ctx.id()) {
+                Entry::Occupied(_) => error(response, "MULTI calls can not be nested"),
+                Entry::Vacant(entry) => {
+                    entry.insert(Transaction::default());
+                    ok(response, "OK")
+                }
+            },
+            ("EXEC", []) => self.exec(ctx, response),
+            ("DISCARD", []) => match self.transactions().remove(&ctx.id()) {
+                Some(_) => ok(response, "OK"),
+                None => error(response, "DISCARD without MULTI"),
+            },
+            ("MULTI" | "EXEC" | "DISCARD", _) => {
+                error(response, &format!("wrong number of arguments for '{}'", name))
+            }
+            _ => {
+                if let Some(transaction) = self.transactions().get_mut(&ctx.id()) {
+                    transaction.commands.push(args);
+                    return ok(response, "QUEUED");
+                }
+                // A blocking pop waits without the lock: holding it would stall a
+                // transaction waiting for the lock, and with it the push being waited for
+                let blocking = matches!(name.as_str(), "BLPOP" | "BRPOP");
+                let _shared = (!blocking).then(|| self.store.commands.read().unwrap_or_else(|e| e.into_inner()));
+                self.run(ctx, &name, rest, false, response);
+            }
+        }
+    }
+
+    /// Runs the connection's queued commands while no other command can
+    fn exec(&self, ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        let transaction = match self.transactions().remove(&ctx.id()) {
+            Some(transaction) if transaction.aborted => {
+                return error(response, "transaction discarded because of previous errors");
+            }
+            Some(transaction) => transaction,
+            None => return error(response, "EXEC without MULTI"),
+        };
+        let _exclusive = self.store.commands.write().unwrap_or_else(|e| e.into_inner());
+        response.write(format!("*{}\n", transaction.commands.len()).as_bytes());
+        for args in &transaction.commands {
+            let (name, rest) = args.split_first().expect("blank lines are never queued");
+            self.run(ctx, &command_name(name), rest, true, response);
+        }
+    }
+
+    /// Runs one command, writing its reply
+    ///
+    /// Inside a transaction, blocking pops return at once rather than wait.
+    fn run(
+        &self,
+        ctx: &ConnectionCtx<'_>,
+        name: &str,
+        args: &[Bytes],
+        in_transaction: bool,
+        response: &mut ResponseWriter,
+    ) {
+        match (name, args) {
+            ("PING", []) => ok(response, "PONG"),
+            ("GET", [key]) => match self.store.get_with(key, |value| write_value(response, value)) {
+                Ok(Some(())) => {}
+                Ok(None) => ok(response, "(nil)"),
+                Err(e) => error(response, &e.to_string()),
+            },
+            ("SET", [key, value, options @ ..]) => {
+                let result = match parse_ttl(options) {
+                    Ok(None) => self.store.set(key.clone(), value.clone()),
+                    Ok(Some(ttl)) => self.store.set_with_ttl(key.clone(), value.clone(), ttl),
+                    Err(reason) => return error(response, reason),
+                };
+                match result {
+                    Ok(()) => ok(response, "OK"),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("DEL", keys) if !keys.is_empty() => {
+                let mut deleted = 0;
+                for key in keys {
+                    match self.store.delete(key) {
+                        Ok(existed) => deleted += usize::from(existed),
+                        Err(e) => return error(response, &e.to_string()),
+                    }
+                }
+                ok(response, &deleted.to_string());
+            }
+            ("EXPIRE", [key, seconds]) => {
+                let Some(seconds) = parse_number(seconds) else {
+                    return error(response, "invalid expire time");
+                };
+                integer_result(response, self.store.expire(key, Duration::from_secs(seconds)));
+            }
+            ("PERSIST", [key]) => integer_result(response, self.store.persist(key)),
+            ("TTL", [key]) => match self.store.ttl(key) {
+                None => ok(response, "-2"),
+                Some(None) => ok(response, "-1"),
+                Some(Some(left)) => ok(response, &left.as_secs_f64().round().to_string()),
+            },
+            ("LPUSH" | "RPUSH", [key, values @ ..]) if !values.is_empty() => {
+                match self.store.push(key, list_end(name), values) {
+                    Ok(len) => ok(response, &len.to_string()),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("LPOP" | "RPOP", [key, count @ ..]) if count.len() <= 1 => {
+                let Some(n) = count.first().map_or(Some(1), |count| parse_number(count)) else {
+                    return error(response, "value is not an integer or out of range");
+                };
+                match self.store.pop(key, list_end(name), n as usize) {
+                    Ok(popped) if count.is_empty() => match popped.first() {
+                        Some(value) => write_value(response, value),
+                        None => ok(response, "(nil)"),
+                    },
+                    Ok(popped) => array(response, &popped),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("LRANGE", [key, start, stop]) => {
+                let (Some(start), Some(stop)) = (parse_index(start), parse_index(stop)) else {
+                    return error(response, "value is not an integer or out of range");
+                };
+                match self.store.range(key, start, stop) {
+                    Ok(items) => array(response, &items),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("BLPOP" | "BRPOP", [keys @ .., timeout]) if !keys.is_empty() => {
+                let Some(timeout) = parse_timeout(timeout) else {
+                    return error(response, "timeout is not a non-negative number");
+                };
+                let timeout = if in_transaction { Some(Duration::ZERO) } else { timeout };
+                match self.store.blocking_pop(keys, list_end(name), timeout) {
+                    Ok(Some((key, value))) => array(response, &[key, value]),
+                    Ok(None) => ok(response, "(nil)"),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("PSUBSCRIBE", patterns) if patterns.iter().any(|pattern| pattern.len() > MAX_PATTERN_LEN) => {
+                error(response, &format!("pattern longer than {MAX_PATTERN_LEN} bytes"));
+            }
+            ("SUBSCRIBE" | "PSUBSCRIBE", targets) if !targets.is_empty() => {
+                let pubsub = self.store.pubsub();
+                let outbox = ctx.outbox();
+                for target in targets {
+                    let count = if name == "SUBSCRIBE" {
+                        pubsub.subscribe(target, &outbox)
+                    } else {
+                        pubsub.psubscribe(target, &outbox)
+                    };
+                    subscription_reply(response, name, target, count);
+                }
+            }
+            ("UNSUBSCRIBE" | "PUNSUBSCRIBE", targets) => {
+                let pubsub = self.store.pubsub();
+                let patterns = name == "PUNSUBSCRIBE";
+                let targets = match (targets.is_empty(), patterns) {
+                    (false, _) => targets.to_vec(),
+                    (true, false) => pubsub.channels_of(ctx.id()),
+                    (true, true) => pubsub.patterns_of(ctx.id()),
+                };
+                if targets.is_empty() {
+                    return subscription_reply(response, name, b"(nil)", 0);
+                }
+                for target in targets {
+                    let count = if patterns {
+                        pubsub.punsubscribe(&target, ctx.id())
+                    } else {
+                        pubsub.unsubscribe(&target, ctx.id())
+                    };
+                    subscription_reply(response, name, &target, count);
+                }
+            }
+            ("PUBLISH", [channel, _]) if events::is_reserved(channel) => {
+                error(response, "channels starting with __keyspace__ or __keyevent__ are reserved")
+            }
+            ("PUBLISH", [channel, message]) => ok(response, &self.store.pubsub().publish(channel, message).to_string()),
+            ("SAVE", []) => match self.store.save() {
+                Ok(()) => ok(response, "OK"),
+                Err(e) => error(response, &e.to_string()),
+            },
+            ("SCAN", [cursor, options @ ..]) => {
+                let Some(cursor) = parse_number(cursor) else {
+                    return error(response, "invalid cursor");
+                };
+                let (pattern, count) = match parse_scan_options(options) {
+                    Ok(options) => options,
+                    Err(reason) => return error(response, reason),
+                };
+                let (next, keys) = self.store.scan(cursor, count, pattern);
+                response.write(format!("*{}\n{}\n", keys.len() + 1, next).as_bytes());
+                for key in &keys {
+                    write_value(response, key);
+                }
+            }
+            ("INFO", section) if section.len() <= 1 => {
+                let section = section.first().map(|section| command_name(section));
+                match self.info(section.as_deref()) {
+                    Some(lines) => array(response, &lines),
+                    None => error(response, "unknown INFO section"),
+                }
+            }
+            (
+                "PING" | "GET" | "SET" | "DEL" | "EXPIRE" | "PERSIST" | "TTL" | "LPUSH" | "RPUSH" | "LPOP" | "RPOP"
+                | "LRANGE" | "BLPOP" | "BRPOP" | "SUBSCRIBE" | "PSUBSCRIBE" | "PUBLISH" | "SAVE" | "SCAN" | "INFO",
+                _,
+            ) => {
+                error(response, &format!("wrong number of arguments for '{}'", name))
+            }
+            _ => {
+                ctx.report(Offence::ProtocolError);
+                error(response, &format!("unknown command '{}'", name))
+            }
+        }
+    }
+
+    /// Lines of `INFO` for one section, or every section if `section` is `None`
+    ///
+    /// `None` if there is no such section.
+    fn info(&self, section: Option<&str>) -> Option<Vec<Bytes>> {
+        let keyspace = self.store.info();
+        let hit_ratio = keyspace.hit_ratio().map_or_else(|| "-".to_string(), |ratio| format!("{:.4}", ratio));
+        let mut memory = vec![
+            format!("used_memory_estimate:{}", keyspace.memory),
+            format!("blob_memory:{}", keyspace.blob_memory),
+            format!("blob_capacity:{}", keyspace.blob_capacity),
+        ];
+        // The whole process, unlike the estimates above
+        let process = process_memory();
+        if let Some(resident) = process.resident {
+            memory.push(format!("used_memory_rss:{}", resident));
+        }
+        if let Some(peak) = process.peak_resident {
+            memory.push(format!("used_memory_peak_rss:{}", peak));
+        }
+        if let Some(heap) = heap_stats() {
+            memory.push(format!("mem_allocator:{}", heap.allocator.to_lowercase()));
+            memory.push(format!("used_memory_heap:{}", heap.allocated));
+            memory.push(format!("used_memory_heap_peak:{}", heap.peak));
+        }
+        let sections = [
+            ("Server", vec![format!("uptime_in_seconds:{}", self.started.elapsed().as_secs())]),
+            (
+                "Keyspace",
+                vec![
+                    format!("keys:{}", keyspace.keys),
+                    format!("expires:{}", keyspace.expiring),
+                    format!("lists:{}", keyspace.lists),
+                ],
+            ),
+            ("Memory", memory),
+            (
+                "Stats",
+                vec![
+                    format!("keyspace_hits:{}", keyspace.hits),
+                    format!("keyspace_misses:{}", keyspace.misses),
+                    format!("hit_ratio:{}", hit_ratio),
+                ],
+            ),
+            ("Replication", vec!["role:primary".to_string(), "connected_replicas:0".to_string()]),
+        ];
+        let mut lines = Vec::new();
+        for (name, fields) in sections {
+            if section.is_some_and(|section| !section.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            lines.push(format!("# {}", name).into_bytes());
+            lines.extend(fields.into_iter().map(String::into_bytes));
+        }
+        (!lines.is_empty()).then_some(lines)
+    }
+}
+
+impl RequestHandler for KvHandler {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        for args in wire::commands(message) {
+            self.execute(ctx, args, response);
+        }
+    }
+
+    fn frame(&self, buffered: &[u8]) -> Option<usize> {
+        Some(wire::complete_len(buffered))
+    }
+
+    fn on_shutdown(&self, _ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        error(response, "server closing");
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        self.transactions().remove(&connection_id);
+        self.store.pubsub().remove_connection(connection_id);
+    }
+}
+
+fn command_name(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).to_ascii_uppercase()
+}
+
+fn ok(response: &mut ResponseWriter, reply: &str) {
+    write_line(response, reply.as_bytes());
+}
+
+fn write_line(response: &mut ResponseWriter, value: &[u8]) {
+    response.write(value);
+    response.write(b"\n");
+}
+
+/// Writes one value, length-prefixed if it would not survive as a plain line
+fn write_value(response: &mut ResponseWriter, value: &[u8]) {
+    wire::write_value(value, |bytes| response.write(bytes));
+}
+
+/// Replies with the number of values and then each value on its own line
+fn array(response: &mut ResponseWriter, values: &[Bytes]) {
+    response.write(&encode_array(values));
+}
+
+/// Confirms a change of subscription: the command, the channel or pattern, and the subscriptions left
+fn subscription_reply(response: &mut ResponseWriter, command: &str, target: &[u8], count: usize) {
+    let command = command.to_ascii_lowercase();
+    response.write(&encode_array(&[command.as_bytes(), target, count.to_string().as_bytes()]));
+}
+
+/// `LPUSH`, `LPOP`, and `BLPOP` work on the front; their `R` counterparts on the back
+fn list_end(command: &str) -> ListEnd {
+    if command.trim_start_matches('B').starts_with('R') {
+        ListEnd::Back
+    } else {
+        ListEnd::Front
+    }
+}
+
+fn error(response: &mut ResponseWriter, reason: &str) {
+    response.write(b"ERR ");
+    ok(response, reason);
+}
+
+/// Replies `1` or `0` for a change that did or did not apply
+fn integer_result(response: &mut ResponseWriter, result: crate::error::Result<bool>) {
+    match result {
+        Ok(applied) => ok(response, if applied { "1" } else { "0" }),
+        Err(e) => error(response, &e.to_string()),
+    }
+}
+
+fn parse_number(arg: &[u8]) -> Option<u64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn parse_index(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Parses a blocking timeout in seconds, where zero waits forever
+fn parse_timeout(arg: &[u8]) -> Option<Option<Duration>> {
+    let seconds: f64 = std::str::from_utf8(arg).ok()?.parse().ok()?;
+    if seconds == 0.0 {
+        return Some(None);
+    }
+    Duration::try_from_secs_f64(seconds).ok().map(Some)
+}
+
+/// Parses the options after `SET key value`: nothing, `EX seconds`, or `PX milliseconds`
+fn parse_ttl(options: &[Bytes]) -> Result<Option<Duration>, &'static str> {
+    let [unit, amount] = options else {
+        return if options.is_empty() { Ok(None) } else { Err("syntax error") };
+    };
+    let amount = parse_number(amount).filter(|&amount| amount > 0).ok_or("invalid expire time in 'SET'")?;
+    match unit.to_ascii_uppercase().as_slice() {
+        b"EX" => Ok(Some(Duration::from_secs(amount))),
+        b"PX" => Ok(Some(Duration::from_millis(amount))),
+        _ => Err("syntax error"),
+    }
+}
+
+/// Parses the options after `SCAN cursor`: `MATCH pattern` and `COUNT n`, in either order
+fn parse_scan_options(options: &[Bytes]) -> Result<(Option<&[u8]>, usize), &'static str> {
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    for option in options.chunks(2) {
+        let [name, value] = option else { return Err("syntax error") };
+        match name.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(value.as_slice()),
+            b"COUNT" => {
+                let n = parse_number(value).filter(|&n| n > 0).ok_or("COUNT is not a positive integer")?;
+                count = usize::try_from(n).unwrap_or(usize::MAX);
+            }
+            _ => return Err("syntax error"),
+        }
+    }
+    Ok((pattern, count))
+}
@@ -0,0 +1,89 @@
+//! Keyspace statistics for `INFO`.
+//!
+//! Memory is an estimate: the bytes of every key and value plus a fixed
+//! allowance per key and per list item for the bookkeeping around them. It is
+//! meant for watching trends and comparing stores, not for exact accounting.
+
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use crate::kv::{BlobStore, Store, Value};
+
+/// Bytes assumed per key for its map slot, entry, and buffers
+const ENTRY_OVERHEAD: usize = 64;
+/// Bytes assumed per list item for its buffer
+const LIST_ITEM_OVERHEAD: usize = 24;
+
+/// Estimated heap bytes taken by `key` and its value, as counted by `INFO` and the memory budget
+///
+/// Values in the blob store are not on the heap and count for nothing.
+pub(crate) fn footprint(key: &[u8], value: &Value) -> usize {
+    ENTRY_OVERHEAD
+        + key.len()
+        + match value {
+            Value::String(value) => value.len(),
+            Value::Blob(_) => 0,
+            Value::List(items) => items.iter().map(|item| item_footprint(item)).sum(),
+        }
+}
+
+/// Estimated heap bytes taken by one list item
+pub(crate) fn item_footprint(item: &[u8]) -> usize {
+    LIST_ITEM_OVERHEAD + item.len()
+}
+
+/// Counts describing a [`Store`] at one moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceInfo {
+    /// Keys stored, not counting expired ones awaiting removal
+    pub keys: usize,
+    /// Keys with a time to live
+    pub expiring: usize,
+    /// Keys holding lists
+    pub lists: usize,
+    /// Estimated heap bytes taken by keys and values
+    pub memory: usize,
+    /// Bytes of values kept in the blob store
+    pub blob_memory: usize,
+    /// Bytes the blob store can hold, or zero if the store has none
+    pub blob_capacity: usize,
+    /// `GET`s that found their key
+    pub hits: u64,
+    /// `GET`s that did not
+    pub misses: u64,
+}
+
+impl KeyspaceInfo {
+    /// Share of lookups that found their key, or `None` before the first lookup
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+impl Store {
+    /// Counts the keys and estimates the memory they take
+    ///
+    /// Walks every key, so it takes time in proportion to the store's size.
+    pub fn info(&self) -> KeyspaceInfo {
+        let now = SystemTime::now();
+        let data = self.data.read().unwrap();
+        let mut info = KeyspaceInfo {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            blob_capacity: self.blobs.as_ref().map_or(0, BlobStore::capacity),
+            ..KeyspaceInfo::default()
+        };
+        for (key, entry) in data.entries.iter().filter(|(_, entry)| entry.is_live(now)) {
+            info.keys += 1;
+            info.expiring += usize::from(entry.expires_at.is_some());
+            info.memory += footprint(key, &entry.value);
+            match &entry.value {
+                Value::String(_) => {}
+                Value::Blob(blob) => info.blob_memory += blob.len(),
+                Value::List(_) => info.lists += 1,
+            }
+        }
+        info
+    }
+}
@@ -0,0 +1,168 @@
+//! Lists: `LPUSH`, `RPUSH`, `LPOP`, `RPOP`, `LRANGE`, and the blocking `BLPOP`.
+//!
+//! A list is created by its first push and removed with its last item, so an
+//! empty list never exists. Blocking pops park the calling thread on a
+//! condition variable that every push notifies.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::{Result, RustbucketError};
+use crate::kv::wal::Op;
+use crate::kv::{info, Bytes, Data, Entry, KeyEvent, Store, Value};
+
+/// Which end of a list to push to or pop from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    /// The head, where `LPUSH` and `LPOP` work
+    Front,
+    /// The tail, where `RPUSH` and `RPOP` work
+    Back,
+}
+
+impl Data {
+    /// Pushes `values` one at a time onto the list at `key`, creating it if needed
+    ///
+    /// Returns the list's new length. A string stored under `key` is replaced;
+    /// [`Store`] checks the type before getting this far.
+    pub(crate) fn push(&mut self, key: &[u8], end: ListEnd, values: &[&[u8]]) -> usize {
+        if !matches!(self.entries.get(key), Some(Entry { value: Value::List(_), .. })) {
+            self.insert(key.to_vec(), Entry { value: Value::List(VecDeque::new()), expires_at: None });
+        }
+        let Some(Entry { value: Value::List(list), .. }) = self.entries.get_mut(key) else {
+            unreachable!("a list was just inserted");
+        };
+        let added: usize = values.iter().map(|value| info::item_footprint(value)).sum();
+        for value in values {
+            match end {
+                ListEnd::Front => list.push_front(value.to_vec()),
+                ListEnd::Back => list.push_back(value.to_vec()),
+            }
+        }
+        let len = list.len();
+        self.grow(added);
+        len
+    }
+
+    /// Pops up to `count` items from the list at `key`, removing the key if that empties it
+    pub(crate) fn pop(&mut self, key: &[u8], end: ListEnd, count: usize) -> Vec<Bytes> {
+        let Some(Entry { value: Value::List(list), .. }) = self.entries.get_mut(key) else {
+            return Vec::new();
+        };
+        let count = count.min(list.len());
+        let popped: Vec<Bytes> = match end {
+            ListEnd::Front => list.drain(..count).collect(),
+            ListEnd::Back => list.drain(list.len() - count..).rev().collect(),
+        };
+        let emptied = list.is_empty();
+        self.shrink(popped.iter().map(|item| info::item_footprint(item)).sum());
+        if emptied {
+            self.remove(key);
+        }
+        popped
+    }
+}
+
+impl Store {
+    /// Pushes `values` one at a time onto the list at `key`, returning its new length
+    ///
+    /// `LPUSH key a b` leaves `b` at the head, as if `a` and then `b` were pushed.
+    pub fn push(&self, key: &[u8], end: ListEnd, values: &[Bytes]) -> Result<usize> {
+        let mut data = self.data.write().unwrap();
+        let now = SystemTime::now();
+        match data.entries.get(key) {
+            Some(entry) if !entry.is_live(now) => {
+                // Logged so that replaying the push below does not land on the expired entry
+                if let Some(wal) = &self.wal {
+                    wal.append(Op::Delete(key))?;
+                }
+                data.remove(key);
+                self.notify(key, KeyEvent::Expired);
+            }
+            Some(Entry { value: Value::String(_) | Value::Blob(_), .. }) => return Err(RustbucketError::WrongType),
+            _ => {}
+        }
+        data.admit(key.len() + values.iter().map(Vec::len).sum::<usize>())?;
+        let values: Vec<&[u8]> = values.iter().map(Vec::as_slice).collect();
+        if let Some(wal) = &self.wal {
+            wal.append(Op::Push(key, end, values.clone()))?;
+        }
+        let len = data.push(key, end, &values);
+        self.notify(key, KeyEvent::Push(end));
+        drop(data);
+
+        let _pushes = self.pushes.lock().unwrap();
+        self.pushed.notify_all();
+        Ok(len)
+    }
+
+    /// Pops up to `count` items from the list at `key`; a missing key pops nothing
+    pub fn pop(&self, key: &[u8], end: ListEnd, count: usize) -> Result<Vec<Bytes>> {
+        let mut data = self.data.write().unwrap();
+        match data.live(key, SystemTime::now()).map(|entry| &entry.value) {
+            None => return Ok(Vec::new()),
+            Some(Value::String(_) | Value::Blob(_)) => return Err(RustbucketError::WrongType),
+            Some(Value::List(_)) => {}
+        }
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if let Some(wal) = &self.wal {
+            wal.append(Op::Pop(key, end, count as u64))?;
+        }
+        let popped = data.pop(key, end, count);
+        self.notify(key, KeyEvent::Pop(end));
+        if !data.entries.contains_key(key) {
+            self.notify(key, KeyEvent::Del);
+        }
+        Ok(popped)
+    }
+
+    /// Items `start` through `stop` of the list at `key`, inclusive
+    ///
+    /// Negative indexes count from the tail, so `range(key, 0, -1)` is the
+    /// whole list. Out-of-range indexes are clamped rather than rejected.
+    pub fn range(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Bytes>> {
+        let data = self.data.read().unwrap();
+        let list = match data.live(key, SystemTime::now()).map(|entry| &entry.value) {
+            None => return Ok(Vec::new()),
+            Some(Value::String(_) | Value::Blob(_)) => return Err(RustbucketError::WrongType),
+            Some(Value::List(list)) => list,
+        };
+        let len = list.len() as i64;
+        let resolve = |index: i64| if index < 0 { len + index } else { index };
+        let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        Ok(list.range(start as usize..=stop as usize).cloned().collect())
+    }
+
+    /// Pops one item from the first of `keys` holding a list, waiting for a push if none does
+    ///
+    /// Returns the key popped from and the item, or `None` once `timeout`
+    /// passes; with no timeout, or one too long for the clock to reach, it
+    /// waits indefinitely. The calling thread is parked while waiting, so a
+    /// server handling the command has one worker fewer until it returns.
+    pub fn blocking_pop(&self, keys: &[Bytes], end: ListEnd, timeout: Option<Duration>) -> Result<Option<(Bytes, Bytes)>> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut pushes = self.pushes.lock().unwrap();
+        loop {
+            for key in keys {
+                if let Some(value) = self.pop(key, end, 1)?.pop() {
+                    return Ok(Some((key.clone(), value)));
+                }
+            }
+            pushes = match deadline {
+                None => self.pushed.wait(pushes).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(None);
+                    }
+                    self.pushed.wait_timeout(pushes, left).unwrap().0
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Publish/subscribe channels: `SUBSCRIBE`, `PSUBSCRIBE`, and `PUBLISH`.
+//!
+//! Subscribers are connections, reached through their
+//! [`Outbox`](crate::outbox::Outbox), so a publish only queues the message
+//! and never waits for a subscriber to read it. A subscriber too slow to keep
+//! up with its channels is disconnected and dropped from all of them.
+//!
+//! Pattern subscriptions are indexed by the literal prefix every match starts
+//! with (`events.` for `events.*`), in a trie walked along the published
+//! channel's name. A publish only tries the patterns whose prefix the channel
+//! actually starts with, however many other patterns are subscribed.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+
+use crate::kv::glob::{glob_match, literal_prefix};
+use crate::kv::wire::encode_array;
+use crate::kv::Bytes;
+use crate::outbox::Outbox;
+
+/// Longest pattern `PSUBSCRIBE` accepts, in bytes
+pub const MAX_PATTERN_LEN: usize = 512;
+
+/// Connections subscribed to one channel or pattern, by connection id
+type Subscribers = HashMap<u64, Outbox>;
+
+/// Channels and the connections subscribed to them
+#[derive(Debug, Default)]
+pub struct PubSub {
+    subscriptions: RwLock<Subscriptions>,
+}
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    channels: HashMap<Bytes, Subscribers>,
+    patterns: PrefixNode,
+    by_connection: HashMap<u64, ConnectionSubscriptions>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionSubscriptions {
+    channels: BTreeSet<Bytes>,
+    patterns: BTreeSet<Bytes>,
+}
+
+impl ConnectionSubscriptions {
+    fn len(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+/// Trie node for the patterns whose literal prefix leads to it
+#[derive(Debug, Default)]
+struct PrefixNode {
+    patterns: HashMap<Bytes, Subscribers>,
+    children: HashMap<u8, PrefixNode>,
+}
+
+impl PrefixNode {
+    fn subscribers_mut(&mut self, pattern: &[u8]) -> &mut Subscribers {
+        let node = literal_prefix(pattern)
+            .iter()
+            .fold(self, |node, byte| node.children.entry(*byte).or_default());
+        node.patterns.entry(pattern.to_vec()).or_default()
+    }
+
+    /// Removes a subscriber, pruning nodes left without patterns or children
+    ///
+    /// Walks down in a loop rather than recursing, as a prefix is as long as
+    /// the pattern it comes from.
+    fn remove(&mut self, prefix: &[u8], pattern: &[u8], connection_id: u64) {
+        // Depth of the deepest node on the way that holds more than the path
+        // down, below which everything goes once the pattern's node is empty
+        let mut keep = 0;
+        let mut node = &mut *self;
+        for (depth, byte) in prefix.iter().enumerate() {
+            if !node.patterns.is_empty() || node.children.len() > 1 {
+                keep = depth;
+            }
+            match node.children.get_mut(byte) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+        let Some(subscribers) = node.patterns.get_mut(pattern) else { return };
+        subscribers.remove(&connection_id);
+        if !subscribers.is_empty() {
+            return;
+        }
+        node.patterns.remove(pattern);
+        if prefix.is_empty() || !node.patterns.is_empty() || !node.children.is_empty() {
+            return;
+        }
+        let mut parent = &mut *self;
+        for byte in &prefix[..keep] {
+            match parent.children.get_mut(byte) {
+                Some(child) => parent = child,
+                None => return,
+            }
+        }
+        parent.children.remove(&prefix[keep]);
+    }
+
+    /// Calls `f` with every pattern matching `channel` and its subscribers
+    fn for_each_match(&self, channel: &[u8], mut f: impl FnMut(&[u8], &Subscribers)) {
+        let mut node = Some(self);
+        let mut rest = channel;
+        while let Some(current) = node {
+            for (pattern, subscribers) in &current.patterns {
+                if glob_match(pattern, channel) {
+                    f(pattern, subscribers);
+                }
+            }
+            let Some((byte, tail)) = rest.split_first() else { break };
+            node = current.children.get(byte);
+            rest = tail;
+        }
+    }
+}
+
+/// Tears the trie down one node at a time, where the derived drop would
+/// recurse once for every byte of the longest prefix
+impl Drop for PrefixNode {
+    fn drop(&mut self) {
+        let mut nodes: Vec<PrefixNode> = self.children.drain().map(|(_, child)| child).collect();
+        while let Some(mut node) = nodes.pop() {
+            nodes.extend(node.children.drain().map(|(_, child)| child));
+        }
+    }
+}
+
+impl Subscriptions {
+    fn remove_channel(&mut self, channel: &[u8], connection_id: u64) {
+        if let Some(subscribers) = self.channels.get_mut(channel) {
+            subscribers.remove(&connection_id);
+            if subscribers.is_empty() {
+                self.channels.remove(channel);
+            }
+        }
+        if let Some(connection) = self.by_connection.get_mut(&connection_id) {
+            connection.channels.remove(channel);
+        }
+        self.forget_if_idle(connection_id);
+    }
+
+    fn remove_pattern(&mut self, pattern: &[u8], connection_id: u64) {
+        self.patterns.remove(literal_prefix(pattern), pattern, connection_id);
+        if let Some(connection) = self.by_connection.get_mut(&connection_id) {
+            connection.patterns.remove(pattern);
+        }
+        self.forget_if_idle(connection_id);
+    }
+
+    fn forget_if_idle(&mut self, connection_id: u64) {
+        if self.by_connection.get(&connection_id).is_some_and(|connection| connection.len() == 0) {
+            self.by_connection.remove(&connection_id);
+        }
+    }
+
+    /// Channels plus patterns the connection is subscribed to
+    fn count(&self, connection_id: u64) -> usize {
+        self.by_connection.get(&connection_id).map_or(0, ConnectionSubscriptions::len)
+    }
+}
+
+impl PubSub {
+    /// Creates a hub with no channels
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes the connection behind `outbox` to `channel`
+    ///
+    /// Returns how many channels and patterns the connection is now subscribed to.
+    pub fn subscribe(&self, channel: &[u8], outbox: &Outbox) -> usize {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        let id = outbox.connection_id();
+        subscriptions.channels.entry(channel.to_vec()).or_default().insert(id, outbox.clone());
+        subscriptions.by_connection.entry(id).or_default().channels.insert(channel.to_vec());
+        subscriptions.count(id)
+    }
+
+    /// Subscribes the connection behind `outbox` to every channel matching the glob `pattern`
+    ///
+    /// Returns how many channels and patterns the connection is now subscribed to.
+    pub fn psubscribe(&self, pattern: &[u8], outbox: &Outbox) -> usize {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        let id = outbox.connection_id();
+        subscriptions.patterns.subscribers_mut(pattern).insert(id, outbox.clone());
+        subscriptions.by_connection.entry(id).or_default().patterns.insert(pattern.to_vec());
+        subscriptions.count(id)
+    }
+
+    /// Unsubscribes a connection from `channel`, returning how many subscriptions it has left
+    pub fn unsubscribe(&self, channel: &[u8], connection_id: u64) -> usize {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        subscriptions.remove_channel(channel, connection_id);
+        subscriptions.count(connection_id)
+    }
+
+    /// Unsubscribes a connection from `pattern`, returning how many subscriptions it has left
+    pub fn punsubscribe(&self, pattern: &[u8], connection_id: u64) -> usize {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        subscriptions.remove_pattern(pattern, connection_id);
+        subscriptions.count(connection_id)
+    }
+
+    /// Drops every subscription of a connection, such as one that closed
+    pub fn remove_connection(&self, connection_id: u64) {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        let Some(connection) = subscriptions.by_connection.remove(&connection_id) else { return };
+        for channel in &connection.channels {
+            subscriptions.remove_channel(channel, connection_id);
+        }
+        for pattern in &connection.patterns {
+            subscriptions.remove_pattern(pattern, connection_id);
+        }
+    }
+
+    /// Channels a connection is subscribed to, in name order
+    pub fn channels_of(&self, connection_id: u64) -> Vec<Bytes> {
+        let subscriptions = self.subscriptions.read().unwrap();
+        subscriptions.by_connection.get(&connection_id).into_iter().flat_map(|c| c.channels.iter().cloned()).collect()
+    }
+
+    /// Patterns a connection is subscribed to, in order
+    pub fn patterns_of(&self, connection_id: u64) -> Vec<Bytes> {
+        let subscriptions = self.subscriptions.read().unwrap();
+        subscriptions.by_connection.get(&connection_id).into_iter().flat_map(|c| c.patterns.iter().cloned()).collect()
+    }
+
+    /// Sends `message` to every subscriber of `channel`, returning how many times it was queued
+    ///
+    /// Channel subscribers are sent `*3`, `message`, the channel, and the
+    /// message, one per line; pattern subscribers `*4`, `pmessage`, the
+    /// pattern, the channel, and the message. A connection subscribed both
+    /// ways gets both. Subscribers whose connection has closed or fallen too
+    /// far behind are dropped.
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut delivered = 0;
+        let mut gone = Vec::new();
+        let mut deliver = |subscribers: &Subscribers, encoded: &[u8]| {
+            for (id, outbox) in subscribers {
+                match outbox.send(encoded) {
+                    Ok(()) => delivered += 1,
+                    Err(_) => gone.push(*id),
+                }
+            }
+        };
+        {
+            let subscriptions = self.subscriptions.read().unwrap();
+            if let Some(subscribers) = subscriptions.channels.get(channel) {
+                deliver(subscribers, &encode_array(&[b"message", channel, message]));
+            }
+            subscriptions.patterns.for_each_match(channel, |pattern, subscribers| {
+                deliver(subscribers, &encode_array(&[b"pmessage", pattern, channel, message]));
+            });
+        }
+        for id in gone {
+            self.remove_connection(id);
+        }
+        delivered
+    }
+}
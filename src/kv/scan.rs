@@ -0,0 +1,60 @@
+//! `SCAN`: walking the keys a few at a time.
+//!
+//! Keys are visited in the order of a fixed hash of their bytes, and a cursor
+//! is the hash to carry on from, so it stays valid however the map underneath
+//! is rearranged between calls. A scan returns every key that exists from its
+//! start to its end at least once; keys added or removed along the way may or
+//! may not be returned.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::SystemTime;
+
+use crate::kv::glob::glob_match;
+use crate::kv::{Bytes, Store};
+
+/// Keys a `SCAN` looks at when not given a `COUNT`
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Where a key falls in the scan order
+fn position(key: &[u8]) -> u64 {
+    // Built with fixed keys, unlike the map's own hasher, so positions are the same for every call
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Store {
+    /// Looks at about `count` keys from `cursor` on, returning the next cursor and those matching `pattern`
+    ///
+    /// Start with cursor 0 and pass back each cursor returned until it is 0
+    /// again. A call may return fewer than `count` keys, or none, before the
+    /// scan is over, since keys not matching `pattern` are skipped after being
+    /// looked at.
+    pub fn scan(&self, cursor: u64, count: usize, pattern: Option<&[u8]>) -> (u64, Vec<Bytes>) {
+        let now = SystemTime::now();
+        let mut keys: Vec<(u64, &Bytes)> = Vec::new();
+        let data = self.data.read().unwrap();
+        for (key, entry) in &data.entries {
+            let at = position(key);
+            if at >= cursor && entry.is_live(now) {
+                keys.push((at, key));
+            }
+        }
+        keys.sort_unstable();
+        // Keys sharing a position are returned together, since the cursor cannot point between them
+        let mut end = count.max(1).min(keys.len());
+        while end < keys.len() && keys[end].0 == keys[end - 1].0 {
+            end += 1;
+        }
+        let next = match keys.get(end) {
+            Some(&(at, _)) => at,
+            None => 0,
+        };
+        let matching = keys[..end]
+            .iter()
+            .filter(|(_, key)| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|(_, key)| key.to_vec())
+            .collect();
+        (next, matching)
+    }
+}
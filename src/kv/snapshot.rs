@@ -0,0 +1,233 @@
+//! Point-in-time snapshots of the KV store.
+//!
+//! A snapshot is a small binary file: a magic number and format version, the
+//! number of entries, the entries themselves, and a CRC-32 of everything
+//! before it. Each entry is its key prefixed with its length, a type byte, the
+//! value (one length-prefixed string, or an item count followed by that many
+//! length-prefixed items for a list), and its deadline in milliseconds since
+//! the Unix epoch (zero if it never expires). Snapshots from older versions,
+//! which predate lists or expiry, are still read. It is written to a temporary file, synced,
+//! and renamed over the previous snapshot, so readers only ever see a complete
+//! one.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::{Result, RustbucketError};
+use crate::kv::expiry::{from_unix_millis, to_unix_millis};
+use crate::kv::{Bytes, Entry, Store, Value};
+
+/// First bytes of every snapshot
+const MAGIC: &[u8; 4] = b"RBKV";
+/// Version of the layout written by this build
+const VERSION: u32 = 3;
+/// Layout with only string values
+const VERSION_WITHOUT_LISTS: u32 = 2;
+/// Layout with only string values and no deadlines
+const VERSION_WITHOUT_EXPIRY: u32 = 1;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+
+fn storage_error(path: &Path, source: io::Error) -> RustbucketError {
+    RustbucketError::Storage { path: path.to_path_buf(), source }
+}
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the snapshot at `path`; a missing file is an empty store
+pub(crate) fn load(path: &Path) -> Result<HashMap<Bytes, Entry>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(storage_error(path, e)),
+    };
+    decode(&bytes).map_err(|e| storage_error(path, e))
+}
+
+/// Atomically replaces the snapshot at `path` with `entries`
+pub(crate) fn save(path: &Path, entries: &HashMap<Bytes, Entry>) -> Result<()> {
+    let temp = temp_path(path);
+    let result = write_synced(&temp, entries).and_then(|()| fs::rename(&temp, path)).and_then(|()| sync_parent(path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|e| storage_error(path, e))
+}
+
+/// Sibling of `path` the next snapshot is written to before it replaces `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn write_synced(path: &Path, entries: &HashMap<Bytes, Entry>) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(&file);
+    writer.write_all(&encode(entries))?;
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()
+}
+
+/// Makes the rename durable by syncing the directory that holds `path`
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn encode(entries: &HashMap<Bytes, Entry>) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for (key, entry) in entries {
+        push_prefixed(&mut out, key);
+        match &entry.value {
+            Value::String(value) => {
+                out.push(TYPE_STRING);
+                push_prefixed(&mut out, value);
+            }
+            Value::Blob(blob) => {
+                out.push(TYPE_STRING);
+                push_prefixed(&mut out, &blob.bytes());
+            }
+            Value::List(items) => {
+                out.push(TYPE_LIST);
+                out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+                for item in items {
+                    push_prefixed(&mut out, item);
+                }
+            }
+        }
+        out.extend_from_slice(&entry.expires_at.map_or(0, to_unix_millis).to_le_bytes());
+    }
+    let checksum = crc32fast::hash(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn push_prefixed(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn decode(bytes: &[u8]) -> io::Result<HashMap<Bytes, Entry>> {
+    let (body, checksum) = bytes.split_at_checked(bytes.len().saturating_sub(4)).ok_or_else(|| corrupt("file is truncated"))?;
+    if body.len() < MAGIC.len() + 12 || &body[..MAGIC.len()] != MAGIC {
+        return Err(corrupt("not a rustbucket snapshot"));
+    }
+    if crc32fast::hash(body).to_le_bytes() != checksum {
+        return Err(corrupt("checksum mismatch; the file is corrupt"));
+    }
+
+    let mut reader = Reader { bytes: &body[MAGIC.len()..] };
+    let version = u32::from_le_bytes(reader.take_array()?);
+    if !(VERSION_WITHOUT_EXPIRY..=VERSION).contains(&version) {
+        return Err(corrupt(&format!("unsupported snapshot version {}", version)));
+    }
+    let count = u64::from_le_bytes(reader.take_array()?);
+    let mut entries = HashMap::new();
+    for _ in 0..count {
+        let key = reader.take_prefixed()?;
+        let value = if version > VERSION_WITHOUT_LISTS { reader.take_value()? } else { Value::String(reader.take_prefixed()?) };
+        let mut expires_at = None;
+        if version > VERSION_WITHOUT_EXPIRY {
+            let millis = u64::from_le_bytes(reader.take_array()?);
+            expires_at = (millis > 0).then(|| from_unix_millis(millis));
+        }
+        entries.insert(key, Entry { value, expires_at });
+    }
+    if !reader.bytes.is_empty() {
+        return Err(corrupt("trailing data after the last entry"));
+    }
+    Ok(entries)
+}
+
+/// Cursor over a snapshot's body
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        let (taken, rest) = self.bytes.split_at_checked(len).ok_or_else(|| corrupt("file is truncated"))?;
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took exactly N bytes"))
+    }
+
+    fn take_prefixed(&mut self) -> io::Result<Bytes> {
+        let len = u32::from_le_bytes(self.take_array()?) as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn take_value(&mut self) -> io::Result<Value> {
+        match self.take_array()? {
+            [TYPE_STRING] => Ok(Value::String(self.take_prefixed()?)),
+            [TYPE_LIST] => {
+                let count = u32::from_le_bytes(self.take_array()?);
+                let items = (0..count).map(|_| self.take_prefixed()).collect::<io::Result<_>>()?;
+                Ok(Value::List(items))
+            }
+            [other] => Err(corrupt(&format!("unknown value type {}", other))),
+        }
+    }
+}
+
+/// Background thread saving a store at a fixed interval
+///
+/// Dropping the task stops the thread and saves one last time, so a server
+/// shutting down cleanly loses nothing written before it stopped. With a zero
+/// interval there is no thread, only the final save.
+#[derive(Debug)]
+pub struct SnapshotTask {
+    stop_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    store: Arc<Store>,
+}
+
+impl SnapshotTask {
+    pub(crate) fn start(store: Arc<Store>, interval: Duration) -> Self {
+        if interval.is_zero() {
+            return Self { stop_tx: None, handle: None, store };
+        }
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let background = Arc::clone(&store);
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                if let Err(e) = background.save() {
+                    log::error!("Periodic snapshot failed: {}", e);
+                }
+            }
+        });
+        Self { stop_tx: Some(stop_tx), handle: Some(handle), store }
+    }
+}
+
+impl Drop for SnapshotTask {
+    fn drop(&mut self) {
+        self.stop_tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        if let Err(e) = self.store.save() {
+            log::error!("Final snapshot failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Append-only write-ahead log of changes to the KV store.
+//!
+//! Every `SET`, `DEL`, list push and pop, and change of a key's deadline is appended and synced to the log before the client
+//! is told it succeeded, so a crash between snapshots loses nothing that was
+//! acknowledged. On startup the log is replayed over the last snapshot. Once a
+//! snapshot is durable, the records it already covers are dropped from the log
+//! so it does not grow without bound.
+//!
+//! Each record is its payload length and CRC-32 followed by the payload: an
+//! operation byte, the length-prefixed key, and for `SET` the length-prefixed
+//! value. List operations add which end they work on. Deadlines are stored as
+//! milliseconds since the Unix epoch. Replaying records that a snapshot already covers is harmless, since
+//! applying the same sets and deletes again in order ends in the same state.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::error::{Result, RustbucketError};
+use crate::kv::expiry::{from_unix_millis, to_unix_millis};
+use crate::kv::{Data, Entry, ListEnd};
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_SET_EXPIRING: u8 = 3;
+/// Followed by the new deadline, or zero to remove it
+const OP_EXPIRE: u8 = 4;
+/// Followed by the end, the key, and every value pushed
+const OP_PUSH: u8 = 5;
+/// Followed by the end, the key, and how many items were popped
+const OP_POP: u8 = 6;
+const END_FRONT: u8 = 0;
+const END_BACK: u8 = 1;
+/// Length and checksum in front of every payload
+const HEADER_LEN: usize = 8;
+
+/// A change recorded in the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Op<'a> {
+    Set(&'a [u8], &'a [u8], Option<SystemTime>),
+    Delete(&'a [u8]),
+    Expire(&'a [u8], Option<SystemTime>),
+    Push(&'a [u8], ListEnd, Vec<&'a [u8]>),
+    Pop(&'a [u8], ListEnd, u64),
+}
+
+/// An open write-ahead log
+#[derive(Debug)]
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: Mutex<WalFile>,
+}
+
+#[derive(Debug)]
+struct WalFile {
+    file: File,
+    /// Bytes of complete records in the file
+    len: u64,
+}
+
+fn storage_error(path: &Path, source: io::Error) -> RustbucketError {
+    RustbucketError::Storage { path: path.to_path_buf(), source }
+}
+
+impl Wal {
+    /// Opens the log at `path`, replaying its records into `data`
+    ///
+    /// A record cut short by a crash, or failing its checksum, ends the
+    /// replay; the log is truncated there so new records follow the last good one.
+    pub(crate) fn open(path: &Path, data: &mut Data) -> Result<Self> {
+        let error = |e| storage_error(path, e);
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(error(e)),
+        };
+        let (replayed, valid) = replay(&bytes, data);
+        if valid < bytes.len() {
+            log::warn!(
+                "Discarding {} bytes of incomplete or corrupt records at the end of {}",
+                bytes.len() - valid,
+                path.display()
+            );
+        }
+        log::info!("Replayed {} changes from {}", replayed, path.display());
+
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(error)?;
+        file.set_len(valid as u64).map_err(error)?;
+        Ok(Self { path: path.to_path_buf(), file: Mutex::new(WalFile { file, len: valid as u64 }) })
+    }
+
+    /// Appends `op` and waits until it is on disk
+    pub(crate) fn append(&self, op: Op<'_>) -> Result<()> {
+        let record = encode(op);
+        let mut wal = self.file.lock().unwrap();
+        let result = wal.file.write_all(&record).and_then(|()| wal.file.sync_data());
+        match result {
+            Ok(()) => {
+                wal.len += record.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                // Drop whatever part of the record made it out, so later records stay readable
+                let len = wal.len;
+                let _ = wal.file.set_len(len);
+                Err(storage_error(&self.path, e))
+            }
+        }
+    }
+
+    /// Bytes of records written so far; records before this mark are covered by a snapshot taken now
+    pub(crate) fn mark(&self) -> u64 {
+        self.file.lock().unwrap().len
+    }
+
+    /// Drops the records before `mark`, which a durable snapshot now covers
+    pub(crate) fn compact(&self, mark: u64) -> Result<()> {
+        let error = |e| storage_error(&self.path, e);
+        let mut wal = self.file.lock().unwrap();
+        if mark == 0 {
+            return Ok(());
+        }
+        let bytes = fs::read(&self.path).map_err(error)?;
+        let rest = bytes.get(mark as usize..wal.len as usize).unwrap_or_default();
+
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp = self.path.with_file_name(temp_name);
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(rest).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temp, &self.path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(error(e));
+        }
+        wal.file = OpenOptions::new().append(true).open(&self.path).map_err(error)?;
+        wal.len = rest.len() as u64;
+        log::debug!("Compacted {} to {} bytes", self.path.display(), wal.len);
+        Ok(())
+    }
+}
+
+fn encode(op: Op<'_>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let mut push = |bytes: &[u8]| {
+        payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        payload.extend_from_slice(bytes);
+    };
+    match op {
+        Op::Set(key, value, None) => {
+            push(&[OP_SET]);
+            push(key);
+            push(value);
+        }
+        Op::Set(key, value, Some(deadline)) => {
+            push(&[OP_SET_EXPIRING]);
+            push(key);
+            push(value);
+            push(&to_unix_millis(deadline).to_le_bytes());
+        }
+        Op::Delete(key) => {
+            push(&[OP_DELETE]);
+            push(key);
+        }
+        Op::Expire(key, deadline) => {
+            push(&[OP_EXPIRE]);
+            push(key);
+            push(&deadline.map_or(0, to_unix_millis).to_le_bytes());
+        }
+        Op::Push(key, end, values) => {
+            push(&[OP_PUSH, encode_end(end)]);
+            push(key);
+            for value in values {
+                push(value);
+            }
+        }
+        Op::Pop(key, end, count) => {
+            push(&[OP_POP, encode_end(end)]);
+            push(key);
+            push(&count.to_le_bytes());
+        }
+    }
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn encode_end(end: ListEnd) -> u8 {
+    match end {
+        ListEnd::Front => END_FRONT,
+        ListEnd::Back => END_BACK,
+    }
+}
+
+fn decode_end(end: u8) -> Option<ListEnd> {
+    match end {
+        END_FRONT => Some(ListEnd::Front),
+        END_BACK => Some(ListEnd::Back),
+        _ => None,
+    }
+}
+
+/// Applies every intact record in `bytes` to `data`
+///
+/// Returns the number of records applied and the length of the intact prefix.
+fn replay(bytes: &[u8], data: &mut Data) -> (usize, usize) {
+    let mut offset = 0;
+    let mut applied = 0;
+    while let Some((op, len)) = decode(&bytes[offset..]) {
+        match op {
+            Op::Set(key, value, expires_at) => {
+                data.insert(key.to_vec(), Entry::string(value.to_vec(), expires_at));
+            }
+            Op::Delete(key) => {
+                data.remove(key);
+            }
+            Op::Expire(key, expires_at) => data.set_deadline(key, expires_at),
+            Op::Push(key, end, values) => {
+                data.push(key, end, &values);
+            }
+            Op::Pop(key, end, count) => {
+                data.pop(key, end, count as usize);
+            }
+        }
+        offset += len;
+        applied += 1;
+    }
+    (applied, offset)
+}
+
+/// Decodes the record at the start of `bytes` and its length, if it is complete and intact
+fn decode(bytes: &[u8]) -> Option<(Op<'_>, usize)> {
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(bytes.get(4..HEADER_LEN)?.try_into().ok()?);
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    let mut fields = Fields(payload);
+    let op = match fields.take()? {
+        [OP_PUSH, end] => {
+            let end = decode_end(*end)?;
+            let key = fields.take()?;
+            let mut values = Vec::new();
+            while !fields.0.is_empty() {
+                values.push(fields.take()?);
+            }
+            Op::Push(key, end, values)
+        }
+        [OP_POP, end] => {
+            let end = decode_end(*end)?;
+            Op::Pop(fields.take()?, end, fields.take_u64()?)
+        }
+        [OP_SET] => Op::Set(fields.take()?, fields.take()?, None),
+        [OP_SET_EXPIRING] => Op::Set(fields.take()?, fields.take()?, Some(from_unix_millis(fields.take_u64()?))),
+        [OP_DELETE] => Op::Delete(fields.take()?),
+        [OP_EXPIRE] => Op::Expire(fields.take()?, Some(fields.take_u64()?).filter(|&millis| millis > 0).map(from_unix_millis)),
+        _ => return None,
+    };
+    fields.0.is_empty().then_some((op, HEADER_LEN + len))
+}
+
+/// Length-prefixed fields of a payload
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self) -> Option<&'a [u8]> {
+        let len = u32::from_le_bytes(self.0.get(..4)?.try_into().ok()?) as usize;
+        let field = self.0.get(4..4 + len)?;
+        self.0 = &self.0[4 + len..];
+        Some(field)
+    }
+
+    fn take_u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take()?.try_into().ok()?))
+    }
+}
@@ -0,0 +1,199 @@
+//! Encoding of the line-based command protocol shared by KV and queue mode.
+//!
+//! A message holds one or more commands, each ending at a newline. Arguments
+//! are separated by spaces and come in three forms: bare words; double-quoted
+//! strings, with `\"` and `\\` escapes; and length-prefixed values, written as
+//! `$<length>`, a newline, and then exactly that many bytes, which may include
+//! newlines or anything else. A `$` and digits at the end of a line therefore
+//! always start a length-prefixed value; quote them to send them literally.
+//!
+//! Replies use the same idea: a value that could not be read back as a line
+//! (one containing a newline or carriage return, or one that looks like an
+//! array header, a length prefix, `(nil)`, or an error) is sent as
+//! `$<length>`, a newline, the bytes, and a newline. Every other value is sent
+//! as a plain line, so text values read the same as ever.
+
+use memchr::{memchr, memchr2, memchr3};
+
+use crate::kv::Bytes;
+
+/// Splits `message` into commands, each a list of arguments
+///
+/// Blank lines are skipped. A command that cannot be parsed is returned as
+/// an error, and parsing carries on after the line it is on.
+pub(crate) fn commands(message: &[u8]) -> Commands<'_> {
+    Commands { message, position: 0, ended: false }
+}
+
+/// How many bytes at the start of `buffered` hold whole commands, the rest waiting for more to arrive
+///
+/// A command is whole once its line ends, after the last byte of any
+/// length-prefixed value in it; a line that cannot be parsed is whole at its
+/// newline, so its error is reported as soon as it has arrived.
+pub(crate) fn complete_len(buffered: &[u8]) -> usize {
+    let mut commands = commands(buffered);
+    let mut complete = 0;
+    while commands.position < buffered.len() {
+        if commands.command().is_err() {
+            commands.skip_line();
+        }
+        if !commands.ended {
+            break;
+        }
+        complete = commands.position;
+    }
+    complete
+}
+
+/// Iterator returned by [`commands`]
+pub(crate) struct Commands<'a> {
+    message: &'a [u8],
+    position: usize,
+    /// Whether the last command parsed, or the line skipped, ended with its newline
+    ended: bool,
+}
+
+impl Iterator for Commands<'_> {
+    type Item = Result<Vec<Bytes>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.position < self.message.len() {
+            match self.command() {
+                Ok(args) if args.is_empty() => continue,
+                Ok(args) => return Some(Ok(args)),
+                Err(reason) => {
+                    self.skip_line();
+                    return Some(Err(reason));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Commands<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.message.get(self.position).copied()
+    }
+
+    fn skip_line(&mut self) {
+        let newline = memchr(b'\n', &self.message[self.position..]);
+        self.ended = newline.is_some();
+        match newline {
+            Some(newline) => self.position += newline + 1,
+            None => self.position = self.message.len(),
+        }
+    }
+
+    /// Parses arguments up to and including the end of the current line
+    fn command(&mut self) -> Result<Vec<Bytes>, &'static str> {
+        let mut args = Vec::new();
+        self.ended = false;
+        loop {
+            while self.peek().is_some_and(|byte| byte.is_ascii_whitespace() && byte != b'\n') {
+                self.position += 1;
+            }
+            match self.peek() {
+                None => return Ok(args),
+                Some(b'\n') => {
+                    self.position += 1;
+                    self.ended = true;
+                    return Ok(args);
+                }
+                Some(b'"') => args.push(self.quoted()?),
+                Some(b'$') if self.length_prefix().is_some() => args.push(self.length_prefixed()?),
+                Some(_) => {
+                    let start = self.position;
+                    while self.peek().is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                        self.position += 1;
+                    }
+                    args.push(self.message[start..self.position].to_vec());
+                }
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<Bytes, &'static str> {
+        let mut arg = Vec::new();
+        self.position += 1;
+        loop {
+            // Copy everything up to the next byte that means something inside quotes in one go
+            let rest = &self.message[self.position..];
+            let special = memchr3(b'"', b'\\', b'\n', rest).ok_or("unterminated quoted argument")?;
+            arg.extend_from_slice(&rest[..special]);
+            self.position += special;
+            match rest[special] {
+                b'"' => {
+                    self.position += 1;
+                    break;
+                }
+                b'\\' => {
+                    self.position += 1;
+                    arg.push(self.peek().filter(|&byte| byte != b'\n').ok_or("unterminated quoted argument")?);
+                    self.position += 1;
+                }
+                _ => return Err("unterminated quoted argument"),
+            }
+        }
+        if self.peek().is_some_and(|byte| !byte.is_ascii_whitespace()) {
+            return Err("closing quote must be followed by a space");
+        }
+        Ok(arg)
+    }
+
+    /// The length and header size of a `$<length>` header at the current position, if there is one
+    fn length_prefix(&self) -> Option<(usize, usize)> {
+        let rest = &self.message[self.position + 1..];
+        let digits = rest.iter().take_while(|byte| byte.is_ascii_digit()).count();
+        let newline = match rest.get(digits..)? {
+            [b'\n', ..] => 1,
+            [b'\r', b'\n', ..] => 2,
+            _ => return None,
+        };
+        let len = std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()?;
+        Some((len, 1 + digits + newline))
+    }
+
+    fn length_prefixed(&mut self) -> Result<Bytes, &'static str> {
+        let (len, header) = self.length_prefix().expect("checked by the caller");
+        let start = self.position + header;
+        let end = start.checked_add(len).filter(|&end| end <= self.message.len());
+        let Some(end) = end else {
+            // The rest of the message is the value, not commands
+            self.position = self.message.len();
+            return Err("length-prefixed argument is cut short");
+        };
+        self.position = end;
+        if self.peek().is_some_and(|byte| !byte.is_ascii_whitespace()) {
+            return Err("length-prefixed argument must be followed by a space or newline");
+        }
+        Ok(self.message[start..end].to_vec())
+    }
+}
+
+/// Whether `value` would be misread if sent as a plain line
+fn needs_length_prefix(value: &[u8]) -> bool {
+    memchr2(b'\n', b'\r', value).is_some()
+        || value.starts_with(b"$")
+        || value.starts_with(b"*")
+        || value.starts_with(b"ERR")
+        || value == b"(nil)"
+}
+
+/// Writes `value` through `write` as one reply value, length-prefixed if it needs to be
+pub(crate) fn write_value(value: &[u8], mut write: impl FnMut(&[u8])) {
+    if needs_length_prefix(value) {
+        write(format!("${}\n", value.len()).as_bytes());
+    }
+    write(value);
+    write(b"\n");
+}
+
+/// `*<count>` and then each value, one per line
+pub(crate) fn encode_array<V: AsRef<[u8]>>(values: &[V]) -> Vec<u8> {
+    let mut out = format!("*{}\n", values.len()).into_bytes();
+    for value in values {
+        write_value(value.as_ref(), |bytes| out.extend_from_slice(bytes));
+    }
+    out
+}
@@ -0,0 +1,82 @@
+//! A TCP server with thread-based concurrency, signal handling, and graceful shutdown capabilities.
+//!
+//! This server implements a simple TCP protocol where clients can connect and send commands.
+//! The server uses a thread pool to handle multiple connections concurrently and implements
+//! graceful shutdown on receiving SIGINT/SIGTERM signals.
+//!
+//! The `rustbucket` binary is a thin CLI over this crate; other programs can embed the
+//! server directly:
+//!
+//! ```no_run
+//! use rustbucket::Server;
+//!
+//! Server::builder().port(7000).build()?.run()?;
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+pub mod access;
+pub mod acl;
+#[cfg(feature = "admin")]
+mod admin;
+#[cfg(feature = "http")]
+pub mod alerts;
+pub mod async_handler;
+pub mod bans;
+pub mod buffers;
+pub mod build_info;
+pub mod chat;
+pub mod config;
+mod connections;
+pub mod error;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+mod events;
+pub mod files;
+pub mod flood;
+pub mod handler;
+mod heartbeat;
+mod hooks;
+#[cfg(feature = "http")]
+pub mod http_client;
+pub mod kv;
+pub mod logging;
+pub mod memory;
+pub mod middleware;
+pub mod outbox;
+pub mod paths;
+pub mod pidfile;
+#[cfg(feature = "noise")]
+pub mod noise;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+mod pool;
+#[cfg(feature = "admin")]
+mod profiling;
+pub mod protocol;
+pub mod queue;
+#[cfg(feature = "sandbox")]
+pub mod sandbox;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod secrets;
+pub mod server;
+#[cfg(target_os = "linux")]
+mod shard;
+pub mod socket;
+#[cfg(feature = "metrics")]
+pub mod statsd;
+pub mod telemetry;
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+
+pub use async_handler::AsyncRequestHandler;
+pub use config::{Config, ConfigSource};
+pub use error::{Result, RustbucketError};
+pub use handler::{ConnectionCtx, EchoHandler, RequestHandler, ResponseWriter};
+pub use logging::LogSink;
+pub use middleware::{Middleware, Next, Request};
+pub use paths::Paths;
+pub use server::{Server, ServerBuilder, ShutdownHandle};
@@ -0,0 +1,230 @@
+//! The server's own log.
+//!
+//! Connection events, lifecycle changes, and state dumps go to a [`LogSink`].
+//! By default that is a [`FileSink`] appending timestamped lines to `http.log`,
+//! the file the CLI's `count`, `rotate`, and `logs` commands work on; embedders
+//! can send the same lines to stdout, syslog, or their own logging framework.
+//! A [`RingSink`] writes the same file through a memory-mapped ring buffer, for
+//! runs where appending each line as it is logged costs too much.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::Local;
+
+use crate::error::{Result, RustbucketError};
+
+mod ring;
+
+pub use ring::{RingSink, DEFAULT_RING_CAPACITY, FLUSH_INTERVAL, MIN_RING_CAPACITY};
+
+/// How the local time is written at the start of every line in the log file
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Destination for the server's log lines
+///
+/// Called from many threads at once; implementations report their own write
+/// failures rather than returning them, since a lost log line must not take a
+/// connection down with it.
+pub trait LogSink: Send + Sync {
+    /// Records one line
+    fn write(&self, message: &str);
+
+    /// Records several lines without lines from other threads in between
+    fn write_batch(&self, messages: &[String]) {
+        for message in messages {
+            self.write(message);
+        }
+    }
+
+    /// Reopens whatever the sink writes to, e.g. after the log was rotated
+    ///
+    /// Called when the server reloads.
+    fn reopen(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes out lines the sink is still holding on to
+    ///
+    /// Called once the server has shut down, so that its last lines are in
+    /// place by the time [`Server::run`](crate::Server::run) returns.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for dyn LogSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LogSink")
+    }
+}
+
+/// Prefixes a message with the local time, as every line in the log file is
+fn timestamped(message: &str) -> String {
+    format!("[{}] {}", Local::now().format(TIMESTAMP_FORMAT), message)
+}
+
+/// Appends timestamped lines to a file, reopening it on reload
+pub struct FileSink {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        Ok(Self { path, file: Mutex::new(file) })
+    }
+
+    /// File the sink appends to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| RustbucketError::Log { path: path.to_path_buf(), source })
+}
+
+impl LogSink for FileSink {
+    fn write(&self, message: &str) {
+        let mut file = self.file.lock().unwrap();
+        let line = timestamped(message);
+        let result = if cfg!(windows) {
+            // Windows locks are mandatory: writing while another process, such as
+            // `rotate`, holds the lock fails instead of waiting, so wait for it first
+            file.lock().and_then(|_| {
+                let written = writeln!(file, "{}", line).and_then(|_| file.flush());
+                file.unlock()?;
+                written
+            })
+        } else {
+            writeln!(file, "{}", line).and_then(|_| file.flush())
+        };
+        if let Err(e) = result {
+            log::error!("Failed to write to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn write_batch(&self, messages: &[String]) {
+        let mut file = self.file.lock().unwrap();
+        // Also keep out writers in other processes, such as a second server on the same file
+        let result = file.lock().and_then(|_| {
+            for message in messages {
+                writeln!(file, "{}", timestamped(message))?;
+            }
+            file.flush()?;
+            file.unlock()
+        });
+        if let Err(e) = result {
+            log::error!("Failed to write to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn reopen(&self) -> io::Result<()> {
+        *self.file.lock().unwrap() = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Prints timestamped lines to stdout, for containers and supervisors that collect it
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+impl LogSink for StdoutSink {
+    fn write(&self, message: &str) {
+        println!("{}", timestamped(message));
+    }
+
+    fn write_batch(&self, messages: &[String]) {
+        let mut stdout = io::stdout().lock();
+        for message in messages {
+            let _ = writeln!(stdout, "{}", timestamped(message));
+        }
+    }
+}
+
+/// Sends lines to the local syslog daemon over its Unix socket
+///
+/// Messages use the daemon facility at info severity; the daemon adds the timestamp.
+#[cfg(unix)]
+pub struct SyslogSink {
+    socket: UnixDatagram,
+    tag: String,
+}
+
+/// Where syslog daemons listen on Linux and macOS respectively
+#[cfg(unix)]
+const SYSLOG_SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+/// `LOG_DAEMON | LOG_INFO`
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+#[cfg(unix)]
+impl SyslogSink {
+    /// Connects to the local syslog socket, tagging messages with `tag`
+    pub fn connect(tag: impl Into<String>) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        let mut last_error = None;
+        for path in SYSLOG_SOCKETS {
+            match socket.connect(path) {
+                Ok(()) => return Ok(Self { socket, tag: tag.into() }),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let source = last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound));
+        Err(RustbucketError::Log { path: PathBuf::from(SYSLOG_SOCKETS[0]), source })
+    }
+}
+
+#[cfg(unix)]
+impl LogSink for SyslogSink {
+    fn write(&self, message: &str) {
+        let line = format!("<{}>{}[{}]: {}", SYSLOG_PRIORITY, self.tag, std::process::id(), message);
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            log::error!("Failed to send to syslog: {}", e);
+        }
+    }
+}
+
+/// Keeps lines in memory so tests can assert on what the server logged
+#[derive(Debug, Default)]
+pub struct CaptureSink {
+    lines: Mutex<Vec<String>>,
+}
+
+impl CaptureSink {
+    /// Creates an empty capture
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lines logged so far, without timestamps
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    /// Whether any line logged so far contains `text`
+    pub fn contains(&self, text: &str) -> bool {
+        self.lines.lock().unwrap().iter().any(|line| line.contains(text))
+    }
+}
+
+impl LogSink for CaptureSink {
+    fn write(&self, message: &str) {
+        self.lines.lock().unwrap().push(message.to_string());
+    }
+
+    fn write_batch(&self, messages: &[String]) {
+        self.lines.lock().unwrap().extend_from_slice(messages);
+    }
+}
@@ -0,0 +1,341 @@
+//! Logging through a memory-mapped ring buffer.
+//!
+//! Appending each line to the log file as it is logged costs a system call per
+//! line, which under benchmark load can take more CPU than serving the
+//! requests. A [`RingSink`] instead copies each timestamped line into a ring
+//! buffer in a memory-mapped file and returns; a background thread moves what
+//! has gathered to the log file in one write every [`FLUSH_INTERVAL`], or
+//! sooner once the ring is half full. When lines arrive faster than the log
+//! file takes them and the ring fills, new lines are dropped rather than made
+//! to wait, and the log says how many.
+//!
+//! The ring's read and write positions live in a header at the start of its
+//! file, so lines still in the ring when the process dies are not lost: the
+//! next sink opened on the same file appends them to the log first.
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::Local;
+use memmap2::MmapMut;
+
+use super::{open_append, timestamped, LogSink, TIMESTAMP_FORMAT};
+use crate::error::{Result, RustbucketError};
+
+/// Bytes in a ring by default
+pub const DEFAULT_RING_CAPACITY: usize = 8 * 1024 * 1024;
+/// Fewest bytes a ring may hold
+pub const MIN_RING_CAPACITY: usize = 4096;
+/// Longest a line waits in the ring before the background thread writes it out
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Marks a ring file, and the version of its layout
+const MAGIC: &[u8; 8] = b"RBLOGRG1";
+/// Bytes before the ring's data: the magic, capacity, head, and tail, padded to a cache line
+const HEADER_LEN: usize = 64;
+const CAPACITY_AT: usize = 8;
+const HEAD_AT: usize = 16;
+const TAIL_AT: usize = 24;
+/// Formatting space a thread keeps between lines; more is freed after a large batch
+const MAX_SCRATCH: usize = 64 * 1024;
+
+/// Copies timestamped lines into a memory-mapped ring that a background thread flushes to a file
+///
+/// Dropping the sink writes out whatever is left in the ring.
+pub struct RingSink {
+    shared: Arc<Shared>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    log_path: PathBuf,
+    ring_path: PathBuf,
+    ring: Mutex<Ring>,
+    /// Held for the whole of a flush, so flushes take turns
+    file: Mutex<File>,
+    /// Wakes the flusher when the ring is half full or the sink is closing
+    wake: Condvar,
+}
+
+/// The mapped ring file and where the lines in it start and end
+struct Ring {
+    map: MmapMut,
+    /// Bytes ever written to the ring; the lines end at `head` modulo the capacity
+    head: u64,
+    /// Bytes ever flushed from the ring; the lines start at `tail` modulo the capacity
+    tail: u64,
+    /// Lines dropped because the ring was full
+    dropped: u64,
+    /// Of `dropped`, those the log already says were dropped
+    reported: u64,
+    closing: bool,
+}
+
+impl Ring {
+    fn capacity(&self) -> usize {
+        self.map.len() - HEADER_LEN
+    }
+
+    /// Bytes waiting to be flushed
+    fn len(&self) -> usize {
+        (self.head - self.tail) as usize
+    }
+
+    /// Copies `bytes` in after the last line, unless there is no room for all of them
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        let capacity = self.capacity();
+        if bytes.len() > capacity - self.len() {
+            return false;
+        }
+        let start = (self.head % capacity as u64) as usize;
+        let (first, wrapped) = bytes.split_at(bytes.len().min(capacity - start));
+        let data = &mut self.map[HEADER_LEN..];
+        data[start..start + first.len()].copy_from_slice(first);
+        data[..wrapped.len()].copy_from_slice(wrapped);
+        self.head += bytes.len() as u64;
+        self.map[HEAD_AT..HEAD_AT + 8].copy_from_slice(&self.head.to_le_bytes());
+        true
+    }
+
+    /// Takes the first `len` bytes out of the ring, once they are in the log file
+    fn consume(&mut self, len: usize) {
+        self.tail += len as u64;
+        self.map[TAIL_AT..TAIL_AT + 8].copy_from_slice(&self.tail.to_le_bytes());
+    }
+}
+
+/// Appends the bytes from position `tail` to `head` of the ring `data` to `out`
+fn extend_wrapped(data: &[u8], tail: u64, head: u64, out: &mut Vec<u8>) {
+    let start = (tail % data.len() as u64) as usize;
+    let len = (head - tail) as usize;
+    let first = len.min(data.len() - start);
+    out.extend_from_slice(&data[start..start + first]);
+    out.extend_from_slice(&data[..len - first]);
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().expect("eight bytes"))
+}
+
+/// Lines an earlier sink left unflushed in the ring file at `path`
+///
+/// Anything that is not an intact ring, including a missing file, holds none.
+fn leftover_lines(path: &Path) -> io::Result<Vec<u8>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = Vec::new();
+    if contents.len() > HEADER_LEN && contents.starts_with(MAGIC) {
+        let (capacity, head, tail) =
+            (read_u64(&contents, CAPACITY_AT), read_u64(&contents, HEAD_AT), read_u64(&contents, TAIL_AT));
+        if capacity == (contents.len() - HEADER_LEN) as u64 && tail <= head && head - tail <= capacity {
+            extend_wrapped(&contents[HEADER_LEN..], tail, head, &mut lines);
+        }
+    }
+    Ok(lines)
+}
+
+impl RingSink {
+    /// Logs to `log_path` through a ring of `capacity` bytes kept in the file at `ring_path`
+    ///
+    /// Lines an earlier sink left in `ring_path` are appended to the log first;
+    /// the file is then emptied and sized for the new ring.
+    pub fn open(log_path: impl AsRef<Path>, ring_path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        let (log_path, ring_path) = (log_path.as_ref().to_path_buf(), ring_path.as_ref().to_path_buf());
+        if capacity < MIN_RING_CAPACITY {
+            let reason = format!("a log ring needs at least {} bytes", MIN_RING_CAPACITY);
+            return Err(RustbucketError::InvalidConfig(reason));
+        }
+        let ring_error = |source| RustbucketError::Log { path: ring_path.clone(), source };
+        let mut file = open_append(&log_path)?;
+        let leftover = leftover_lines(&ring_path).map_err(ring_error)?;
+        file.write_all(&leftover).map_err(|source| RustbucketError::Log { path: log_path.clone(), source })?;
+
+        let ring_file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&ring_path).map_err(ring_error)?;
+        ring_file.set_len((HEADER_LEN + capacity) as u64).map_err(ring_error)?;
+        // SAFETY: the file was just truncated for this sink, which is the only
+        // thing that maps it; other processes are not expected to touch it
+        let mut map = unsafe { MmapMut::map_mut(&ring_file) }.map_err(ring_error)?;
+        map[..MAGIC.len()].copy_from_slice(MAGIC);
+        map[CAPACITY_AT..CAPACITY_AT + 8].copy_from_slice(&(capacity as u64).to_le_bytes());
+
+        let ring = Ring { map, head: 0, tail: 0, dropped: 0, reported: 0, closing: false };
+        let shared = Arc::new(Shared {
+            log_path,
+            ring_path,
+            ring: Mutex::new(ring),
+            file: Mutex::new(file),
+            wake: Condvar::new(),
+        });
+        let flusher = thread::Builder::new().name("rustbucket-log-ring".to_string()).spawn({
+            let shared = Arc::clone(&shared);
+            move || shared.run_flusher()
+        })?;
+        Ok(Self { shared, flusher: Some(flusher) })
+    }
+
+    /// File the lines end up in
+    pub fn log_path(&self) -> &Path {
+        &self.shared.log_path
+    }
+
+    /// File the ring is mapped from
+    pub fn ring_path(&self) -> &Path {
+        &self.shared.ring_path
+    }
+
+    /// Bytes the ring holds
+    pub fn capacity(&self) -> usize {
+        self.shared.ring.lock().unwrap().capacity()
+    }
+
+    /// Lines dropped so far because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.ring.lock().unwrap().dropped
+    }
+
+    /// Formats `messages` and copies them into the ring together, or drops them all
+    fn push<'a>(&self, messages: impl ExactSizeIterator<Item = &'a str>) {
+        SCRATCH.with_borrow_mut(|scratch| {
+            let count = messages.len() as u64;
+            for message in messages {
+                scratch.append(message);
+            }
+            let mut ring = self.shared.ring.lock().unwrap();
+            if !ring.push(scratch.lines.as_bytes()) {
+                ring.dropped += count;
+            }
+            if ring.len() >= ring.capacity() / 2 {
+                self.shared.wake.notify_one();
+            }
+            drop(ring);
+            scratch.clear();
+        });
+    }
+}
+
+impl Shared {
+    /// Moves every line in the ring to the log file, noting any that were dropped
+    fn flush(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let mut pending = Vec::new();
+        let (flushed, dropped, unreported) = {
+            let ring = self.ring.lock().unwrap();
+            extend_wrapped(&ring.map[HEADER_LEN..], ring.tail, ring.head, &mut pending);
+            (pending.len(), ring.dropped, ring.dropped - ring.reported)
+        };
+        if pending.is_empty() && unreported == 0 {
+            return Ok(());
+        }
+        if unreported > 0 {
+            let note = timestamped(&format!("Log ring full: dropped {} lines", unreported));
+            pending.extend_from_slice(note.as_bytes());
+            pending.push(b'\n');
+        }
+        // Also keep out writers in other processes, as FileSink does
+        file.lock()?;
+        let result = file.write_all(&pending).and_then(|_| file.flush());
+        file.unlock()?;
+        result?;
+
+        let mut ring = self.ring.lock().unwrap();
+        ring.consume(flushed);
+        ring.reported = dropped;
+        Ok(())
+    }
+
+    /// Flushes whenever the ring is half full or has waited long enough, until the sink closes
+    fn run_flusher(&self) {
+        loop {
+            let closing = {
+                let ring = self.ring.lock().unwrap();
+                let half = ring.capacity() / 2;
+                let idle = |ring: &mut Ring| !ring.closing && ring.len() < half;
+                self.wake.wait_timeout_while(ring, FLUSH_INTERVAL, idle).unwrap().0.closing
+            };
+            if let Err(e) = self.flush() {
+                log::error!("Failed to write to {}: {}", self.log_path.display(), e);
+                if !closing {
+                    // Retrying at once would spin while the ring stays full
+                    thread::sleep(FLUSH_INTERVAL);
+                }
+            }
+            if closing {
+                return;
+            }
+        }
+    }
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
+/// Where a thread formats its lines before copying them into the ring
+#[derive(Default)]
+struct Scratch {
+    /// Lines formatted so far
+    lines: String,
+    /// The timestamp lines get, formatted once per second rather than once per line
+    stamp: String,
+    second: u64,
+}
+
+impl Scratch {
+    fn append(&mut self, message: &str) {
+        let second = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        if self.stamp.is_empty() || second != self.second {
+            self.second = second;
+            self.stamp.clear();
+            let _ = write!(self.stamp, "{}", Local::now().format(TIMESTAMP_FORMAT));
+        }
+        let _ = writeln!(self.lines, "[{}] {}", self.stamp, message);
+    }
+
+    fn clear(&mut self) {
+        if self.lines.capacity() > MAX_SCRATCH {
+            self.lines = String::new();
+        }
+        self.lines.clear();
+    }
+}
+
+impl LogSink for RingSink {
+    fn write(&self, message: &str) {
+        self.push(std::iter::once(message));
+    }
+
+    fn write_batch(&self, messages: &[String]) {
+        self.push(messages.iter().map(String::as_str));
+    }
+
+    fn reopen(&self) -> io::Result<()> {
+        // Lines logged before the log was moved belong in the old file
+        self.shared.flush()?;
+        *self.shared.file.lock().unwrap() = open_append(&self.shared.log_path)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.shared.flush()
+    }
+}
+
+impl Drop for RingSink {
+    fn drop(&mut self) {
+        self.shared.ring.lock().unwrap().closing = true;
+        self.shared.wake.notify_one();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+}
@@ -1,82 +1,115 @@
-//! A TCP server with thread-based concurrency, signal handling, and graceful shutdown capabilities.
-//! 
-//! This server implements a simple TCP protocol where clients can connect and send commands.
-//! The server uses a thread pool to handle multiple connections concurrently and implements
-//! graceful shutdown on receiving SIGINT/SIGTERM signals.
+//! Command-line interface for the rustbucket server.
+//!
+//! `rustbucket run` starts the server from the library crate; every other
+//! subcommand operates on a running server or on the files it leaves in the
+//! working directory.
 
-use std::fs::{File, OpenOptions, rename, remove_file};
-use std::io::{self, Write, BufRead, BufReader, Read};
-use std::path::Path;
-use std::thread;
-use std::time::Duration;
-use chrono::Local;
-use clap::{Parser, Subcommand};
-use fs2::FileExt;
-use memmap2::{MmapMut, MmapOptions};
-use std::sync::atomic::{AtomicU32, Ordering, AtomicBool};
+mod cmd;
+
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::net::{TcpListener, TcpStream};
-use std::str;
-use threadpool::ThreadPool;
-use ctrlc;
+use std::time::Duration;
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use serde_json::json;
+use zeroize::Zeroizing;
+use rustbucket::alerts::AlertConfig;
+use rustbucket::bans::{BanPolicy, DEFAULT_BAN_DURATION, DEFAULT_BAN_WINDOW};
+use rustbucket::buffers::DEFAULT_POOLED_BUFFERS;
+use rustbucket::chat::ChatHandler;
+use rustbucket::error::EXIT_FORCED_SHUTDOWN;
+use rustbucket::files::{FileHandler, DEFAULT_MAX_FILE_SIZE};
+use rustbucket::flood::{FloodPolicy, DEFAULT_FLOOD_CALM, DEFAULT_FLOOD_MIN_RATE};
+use rustbucket::flood::{DEFAULT_DEFENSIVE_CONNECTIONS_PER_IP, DEFAULT_DEFENSIVE_TIMEOUT};
+use rustbucket::kv::{BlobStore, KvHandler, Store, DEFAULT_SLOT_SIZE};
+use rustbucket::logging::RingSink;
+use rustbucket::memory::MemoryBudget;
+use rustbucket::middleware::{IpRateLimit, LockoutPolicy, TokenAuth, DEFAULT_IDENTITY, DEFAULT_THROTTLE_CLOSE_AFTER};
+use rustbucket::middleware::{DEFAULT_LOCKOUT, DEFAULT_LOCKOUT_FAILURES, DEFAULT_MAX_LOCKOUT};
+use rustbucket::noise::{self, NoiseKey, NoiseSettings};
+use rustbucket::queue::{MessageQueue, QueueHandler};
+use rustbucket::sandbox::Sandbox;
+use rustbucket::secrets::Secret;
+use rustbucket::scripting::ScriptLayer;
+use rustbucket::plugins;
+use rustbucket::protocol::{ConnectionMode, Framing, MAX_MESSAGE_SIZE, READ_BUFFER_SIZE};
+use rustbucket::server::{DEFAULT_ADMIN_PORT, DEFAULT_PORT, DEFAULT_THREADS, DEFAULT_THREAD_IDLE_TIMEOUT};
+use rustbucket::socket::{Keepalive, SocketOptions, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_RETRIES};
+use rustbucket::statsd::StatsdConfig;
+use rustbucket::telemetry::OtlpConfig;
+use rustbucket::tls::{TlsSettings, TlsVersion};
+use rustbucket::{Paths, Server};
 
-const LOG_FILE: &str = "http.log";
-const MAX_LOG_FILES: u32 = 5;
-const CONFIG_FILE: &str = "config.dat";
-const DEFAULT_PORT: u16 = 8080;
-const NUM_THREADS: usize = 4;
+use cmd::console::{self, ColorChoice};
+use cmd::output::{Message, Output, OutputFormat};
+use cmd::{backup, bench, client, config, control, doctor, follow, keygen, logs, mangen, monitor, rotation, selftest, send, shell};
 
-/// Server configuration structure
-#[derive(Debug, Clone, Copy)]
-struct Config {
-    verbosity: u32,
-    max_connections: u32,
-    timeout_seconds: u32,
-    version: u32,  // Used to detect config changes
-    port: u16,
-}
+/// Counts heap allocations for `stats` and `/metrics`
+#[cfg(all(feature = "alloc-stats", not(feature = "jemalloc"), not(feature = "mimalloc")))]
+#[global_allocator]
+static ALLOCATOR: rustbucket::memory::CountingAllocator =
+    rustbucket::memory::CountingAllocator::new(std::alloc::System);
 
-impl Config {
-    /// Creates a new Config with default values
-    fn new() -> Self {
-        Self {
-            verbosity: 1,
-            max_connections: 100,
-            timeout_seconds: 30,
-            version: 0,
-            port: DEFAULT_PORT,
-        }
-    }
+/// Serves the heap from jemalloc, counting it for `stats` and `/metrics`
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: rustbucket::memory::CountingAllocator<tikv_jemallocator::Jemalloc> =
+    rustbucket::memory::CountingAllocator::new(tikv_jemallocator::Jemalloc);
 
-    fn to_bytes(&self) -> [u8; 16] {
-        let mut bytes = [0u8; 16];
-        bytes[0..4].copy_from_slice(&self.verbosity.to_ne_bytes());
-        bytes[4..8].copy_from_slice(&self.max_connections.to_ne_bytes());
-        bytes[8..12].copy_from_slice(&self.timeout_seconds.to_ne_bytes());
-        bytes[12..16].copy_from_slice(&self.version.to_ne_bytes());
-        bytes
-    }
+/// Serves the heap from mimalloc, counting it for `stats` and `/metrics`; jemalloc wins if both are enabled
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static ALLOCATOR: rustbucket::memory::CountingAllocator<mimalloc::MiMalloc> =
+    rustbucket::memory::CountingAllocator::new(mimalloc::MiMalloc);
 
-    fn from_bytes(bytes: &[u8; 16]) -> Self {
-        Self {
-            verbosity: u32::from_ne_bytes(bytes[0..4].try_into().unwrap()),
-            max_connections: u32::from_ne_bytes(bytes[4..8].try_into().unwrap()),
-            timeout_seconds: u32::from_ne_bytes(bytes[8..12].try_into().unwrap()),
-            version: u32::from_ne_bytes(bytes[12..16].try_into().unwrap()),
-            port: DEFAULT_PORT,
-        }
-    }
-}
+const DEFAULT_OTLP_INTERVAL_SECS: u64 = 10;
+const DEFAULT_STATSD_PREFIX: &str = "rustbucket";
+const DEFAULT_STATSD_INTERVAL_SECS: u64 = 10;
+const DEFAULT_ALERT_WINDOW_SECS: u64 = 60;
+const DEFAULT_ALERT_ERROR_RATE: f64 = 0.05;
+const DEFAULT_ALERT_ACCEPT_ERRORS: u64 = 10;
+const DEFAULT_ALERT_FD_EXHAUSTION: u64 = 0;
+const DEFAULT_MONITOR_INTERVAL_MS: u64 = 1000;
+const DEFAULT_FOLLOW_INTERVAL_MS: u64 = 1000;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_PLUGIN_DIR: &str = "plugins";
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 300;
+const DEFAULT_RATE_BURST: u32 = 20;
+/// How often kv mode removes expired keys
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_CLIENT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_BENCH_CONNECTIONS: usize = 10;
+const DEFAULT_BENCH_MESSAGES: usize = 1000;
+const DEFAULT_BENCH_MESSAGE_SIZE: usize = 64;
+const DEFAULT_STOP_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_CERT_DAYS: u32 = 365;
+const DEFAULT_TOKEN_BYTES: u16 = 32;
 
 /// Command-line interface arguments
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Format for command results
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    /// Report more about what the command is doing on stderr (-v, -vv, -vvv)
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only report errors on stderr and skip progress messages
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// When to color console diagnostics; `NO_COLOR` turns off `auto`
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
     #[command(subcommand)]
     command: Commands,
 }
 
 /// Available subcommands for the CLI
+// Parsed once at startup, so `Run` carrying every server option is not worth boxing
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Start the web server
@@ -84,18 +117,474 @@ enum Commands {
         /// Port to listen on
         #[arg(short, long, default_value_t = DEFAULT_PORT)]
         port: u16,
-        /// Number of worker threads
-        #[arg(short, long, default_value_t = NUM_THREADS)]
+        /// Number of worker threads; with --max-threads, the fewest kept while idle
+        #[arg(short, long, default_value_t = DEFAULT_THREADS)]
         threads: usize,
+        /// Add worker threads under load, up to this many, and retire them once idle
+        #[arg(long, value_name = "THREADS")]
+        max_threads: Option<usize>,
+        /// Seconds a worker beyond --threads waits for a connection before exiting
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = DEFAULT_THREAD_IDLE_TIMEOUT.as_secs(),
+            requires = "max_threads"
+        )]
+        thread_idle: u64,
+        /// OTLP/HTTP collector endpoint for traces and metrics (e.g. http://localhost:4318)
+        #[arg(long)]
+        otlp_endpoint: Option<String>,
+        /// Seconds between OTLP exports
+        #[arg(long, default_value_t = DEFAULT_OTLP_INTERVAL_SECS)]
+        otlp_interval: u64,
+        /// StatsD/DogStatsD agent address for UDP metrics (e.g. 127.0.0.1:8125)
+        #[arg(long)]
+        statsd_addr: Option<String>,
+        /// Prefix for StatsD metric names
+        #[arg(long, default_value = DEFAULT_STATSD_PREFIX)]
+        statsd_prefix: String,
+        /// DogStatsD tag attached to every metric, as key:value (repeatable)
+        #[arg(long = "statsd-tag")]
+        statsd_tags: Vec<String>,
+        /// Seconds between StatsD counter flushes
+        #[arg(long, default_value_t = DEFAULT_STATSD_INTERVAL_SECS)]
+        statsd_interval: u64,
+        /// Webhook URL receiving JSON alerts when thresholds are crossed
+        #[arg(long)]
+        alert_webhook: Option<String>,
+        /// Seconds over which alert thresholds are evaluated
+        #[arg(long, default_value_t = DEFAULT_ALERT_WINDOW_SECS)]
+        alert_window: u64,
+        /// Alert when more than this fraction of connections end in a handler error
+        #[arg(long, default_value_t = DEFAULT_ALERT_ERROR_RATE)]
+        alert_error_rate: f64,
+        /// Alert when more accepts than this fail within the window
+        #[arg(long, default_value_t = DEFAULT_ALERT_ACCEPT_ERRORS)]
+        alert_accept_errors: u64,
+        /// Alert when more accepts than this fail for lack of file descriptors within the window
+        #[arg(long, default_value_t = DEFAULT_ALERT_FD_EXHAUSTION)]
+        alert_fd_exhaustion: u64,
+        /// Seconds between heartbeat log lines (0 disables)
+        #[arg(long, default_value_t = DEFAULT_HEARTBEAT_INTERVAL_SECS)]
+        heartbeat_interval: u64,
+        /// Loopback port for the admin interface (metrics, health, config, connections)
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+        /// Disable the admin interface
+        #[arg(long)]
+        no_admin: bool,
+        /// Require clients to send `AUTH <TOKEN>` before any other message (visible to other users in `ps`)
+        #[arg(long, value_name = "TOKEN", conflicts_with_all = ["auth_token_file", "auth_token_env"])]
+        auth_token: Option<String>,
+        /// Like --auth-token, reading the token from a file
+        #[arg(long, value_name = "PATH", conflicts_with = "auth_token_env")]
+        auth_token_file: Option<PathBuf>,
+        /// Like --auth-token, reading the token from an environment variable
+        #[arg(long, value_name = "VAR")]
+        auth_token_env: Option<String>,
+        /// Also accept the tokens in this file of `<name> <token>` lines, naming the user for commands.acl
+        #[arg(long, value_name = "PATH")]
+        auth_users_file: Option<PathBuf>,
+        /// Lock a client IP out of AUTH after this many failed attempts (0 never locks it out)
+        #[arg(long, value_name = "FAILURES", default_value_t = DEFAULT_LOCKOUT_FAILURES)]
+        auth_lockout_after: u32,
+        /// Seconds the first lockout lasts; each further failure doubles it
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_LOCKOUT.as_secs())]
+        auth_lockout: u64,
+        /// Seconds a lockout lasts at most
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_MAX_LOCKOUT.as_secs())]
+        auth_lockout_max: u64,
+        /// Connections each client IP may have open at once
+        #[arg(long, value_name = "CONNECTIONS")]
+        max_connections_per_ip: Option<usize>,
+        /// Close a connection that takes longer than this to finish sending a request (one ending in a newline)
+        #[arg(long, value_name = "SECONDS")]
+        request_deadline: Option<u64>,
+        /// Connections that may be partway through a request at once; more are closed
+        #[arg(long, value_name = "CONNECTIONS")]
+        max_reading_connections: Option<usize>,
+        /// Bytes of buffers, queued messages, and KV data to hold before refusing writes and shedding idle clients
+        #[arg(long, value_name = "BYTES")]
+        memory_limit: Option<usize>,
+        /// Bytes read from a client at once, from 256 to 1048576; each connection holds a buffer this large
+        #[arg(long, value_name = "BYTES", default_value_t = READ_BUFFER_SIZE)]
+        read_buffer_size: usize,
+        /// Echo only once a whole line has arrived, however the client's bytes were split across reads
+        #[arg(long)]
+        line_framing: bool,
+        /// Bytes kept toward a message split across reads before the client is disconnected
+        #[arg(long, value_name = "BYTES", default_value_t = MAX_MESSAGE_SIZE)]
+        max_message_size: usize,
+        /// Start connections in text mode, refusing messages that are not UTF-8 (clients switch with MODE BINARY)
+        #[arg(long)]
+        text_mode: bool,
+        /// Idle read and reply buffers to keep for new connections to reuse
+        #[arg(long, value_name = "BUFFERS", default_value_t = DEFAULT_POOLED_BUFFERS)]
+        buffer_pool: usize,
+        /// Serve connections through io_uring, a ring per worker thread (Linux 5.6 or later; not with TLS or Noise)
+        #[arg(long, conflicts_with_all = ["tls_cert", "noise_key"])]
+        io_uring: bool,
+        /// Give each worker its own listener on the port (SO_REUSEPORT), serving what it accepts (Linux)
+        #[arg(long, conflicts_with = "io_uring")]
+        reuse_port: bool,
+        /// Set TCP_NODELAY on client sockets, so small replies go out without waiting to be coalesced
+        #[arg(long)]
+        nodelay: bool,
+        /// Kernel receive buffer for each client socket (SO_RCVBUF)
+        #[arg(long, value_name = "BYTES")]
+        recv_buffer: Option<usize>,
+        /// Kernel send buffer for each client socket (SO_SNDBUF)
+        #[arg(long, value_name = "BYTES")]
+        send_buffer: Option<usize>,
+        /// Seconds closing a client socket waits for unsent data (SO_LINGER); 0 resets the connection instead
+        #[arg(long, value_name = "SECONDS")]
+        linger: Option<u64>,
+        /// Send keepalive probes once a client connection has been idle this long
+        #[arg(long, value_name = "SECONDS")]
+        keepalive: Option<u64>,
+        /// Seconds between keepalive probes
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = DEFAULT_KEEPALIVE_INTERVAL.as_secs(),
+            requires = "keepalive"
+        )]
+        keepalive_interval: u64,
+        /// Unanswered keepalive probes before a client is dropped
+        #[arg(long, value_name = "PROBES", default_value_t = DEFAULT_KEEPALIVE_RETRIES, requires = "keepalive")]
+        keepalive_retries: u32,
+        /// Log through a memory-mapped ring buffer of this many bytes (at least 4096), flushed in the background
+        #[arg(long, value_name = "BYTES")]
+        log_ring: Option<usize>,
+        /// Ban a client IP for --ban-duration after this many protocol errors, failed AUTHs, or throttled messages
+        #[arg(long, value_name = "OFFENCES")]
+        ban_after: Option<u32>,
+        /// Seconds over which offences count toward a ban
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_BAN_WINDOW.as_secs(), requires = "ban_after")]
+        ban_window: u64,
+        /// Seconds a ban lasts
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_BAN_DURATION.as_secs(), requires = "ban_after")]
+        ban_duration: u64,
+        /// Go into defensive mode when the accept rate climbs past this multiple of its usual level
+        #[arg(long, value_name = "FACTOR")]
+        flood_factor: Option<f64>,
+        /// Connections accepted per second below which no spike counts as a flood
+        #[arg(long, value_name = "PER_SECOND", default_value_t = DEFAULT_FLOOD_MIN_RATE, requires = "flood_factor")]
+        flood_min_rate: f64,
+        /// Seconds the accept rate must stay back to normal before defensive mode ends
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_FLOOD_CALM.as_secs(), requires = "flood_factor")]
+        flood_calm: u64,
+        /// In defensive mode, close connections idle for this many seconds
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = DEFAULT_DEFENSIVE_TIMEOUT.as_secs(),
+            requires = "flood_factor"
+        )]
+        defensive_timeout: u64,
+        /// In defensive mode, connections each client IP may have open at once
+        #[arg(
+            long,
+            value_name = "CONNECTIONS",
+            default_value_t = DEFAULT_DEFENSIVE_CONNECTIONS_PER_IP,
+            requires = "flood_factor"
+        )]
+        defensive_max_connections_per_ip: usize,
+        /// In defensive mode, connections that may wait for a worker before more are refused
+        #[arg(long, value_name = "CONNECTIONS", default_value_t = 0, requires = "flood_factor")]
+        defensive_max_queued: usize,
+        /// Messages per second each client IP may send, across all its connections
+        #[arg(long, value_name = "PER_SECOND")]
+        rate_limit: Option<f64>,
+        /// Messages a client IP may send at once before --rate-limit applies
+        #[arg(long, value_name = "MESSAGES", default_value_t = DEFAULT_RATE_BURST, requires = "rate_limit")]
+        rate_burst: u32,
+        /// Close a connection after this many messages in a row over the rate limit (0 never closes)
+        #[arg(long, value_name = "MESSAGES", default_value_t = DEFAULT_THROTTLE_CLOSE_AFTER, requires = "rate_limit")]
+        throttle_close_after: u32,
+        /// What the server does with the messages clients send
+        #[arg(long, value_enum, default_value_t = Mode::Echo)]
+        mode: Mode,
+        /// Seconds between KV snapshots in kv mode (0 saves only on SAVE and at shutdown)
+        #[arg(long, default_value_t = DEFAULT_SNAPSHOT_INTERVAL_SECS)]
+        snapshot_interval: u64,
+        /// In kv mode, acknowledge writes without logging them to the write-ahead log first
+        #[arg(long)]
+        no_wal: bool,
+        /// In kv mode, keep large values in a memory-mapped file of this many bytes (0 keeps them on the heap)
+        #[arg(long, value_name = "BYTES", default_value_t = 0)]
+        blob_store_size: usize,
+        /// In kv mode, publish every change to a key on the __keyspace__ and __keyevent__ channels
+        #[arg(long)]
+        keyspace_events: bool,
+        /// In files mode, refuse uploads larger than this many bytes
+        #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FILE_SIZE)]
+        max_file_size: u64,
+        /// Answer clients with the named handler plugin instead of echoing
+        #[arg(long, value_name = "NAME", conflicts_with = "mode")]
+        plugin: Option<String>,
+        /// Directory to load handler plugins from
+        #[arg(long, value_name = "DIR", default_value = DEFAULT_PLUGIN_DIR)]
+        plugin_dir: PathBuf,
+        /// Run the Lua request scripts in the scripts directory on every message
+        #[arg(long)]
+        scripts: bool,
+        /// On Linux, limit the server to its own files and to the syscalls serving clients needs
+        #[arg(long)]
+        sandbox: bool,
+        /// Speak TLS to clients, serving the certificate chain in this PEM file
+        #[arg(long, value_name = "PATH", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM file with the private key for --tls-cert
+        #[arg(long, value_name = "PATH", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Decrypt --tls-key with the passphrase in this file
+        #[arg(long, value_name = "PATH", requires = "tls_key", conflicts_with = "tls_key_passphrase_env")]
+        tls_key_passphrase_file: Option<PathBuf>,
+        /// Decrypt --tls-key with the passphrase in this environment variable
+        #[arg(long, value_name = "VAR", requires = "tls_key")]
+        tls_key_passphrase_env: Option<String>,
+        /// Lowest TLS version clients may negotiate (1.2 or 1.3)
+        #[arg(long, value_name = "VERSION", default_value = "1.2", requires = "tls_cert")]
+        tls_min_version: TlsVersion,
+        /// Comma-separated cipher suites to offer, such as TLS13_AES_256_GCM_SHA384 (default: all supported)
+        #[arg(long, value_name = "SUITES", value_delimiter = ',', requires = "tls_cert")]
+        tls_ciphers: Vec<String>,
+        /// Comma-separated ALPN protocols to negotiate, most preferred first
+        #[arg(long, value_name = "PROTOCOLS", value_delimiter = ',', requires = "tls_cert")]
+        tls_alpn: Vec<String>,
+        /// Encrypt connections with Noise_XX, using the private key in this file (see `keygen noise`)
+        #[arg(long, value_name = "PATH", requires = "noise_authorized_keys", conflicts_with = "tls_cert")]
+        noise_key: Option<PathBuf>,
+        /// File of `<public key> <name>` lines naming the clients --noise-key accepts
+        #[arg(long, value_name = "PATH", requires = "noise_key")]
+        noise_authorized_keys: Option<PathBuf>,
+    },
+    /// Live terminal view of a running server's connections, throughput, and log
+    Monitor {
+        /// Admin port of the server to monitor
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+        /// Milliseconds between polls
+        #[arg(short, long, default_value_t = DEFAULT_MONITOR_INTERVAL_MS)]
+        interval: u64,
+    },
+    /// Connect to a running server and exchange messages
+    Client {
+        /// Server port to connect to
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Server host to connect to
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        /// Message to send instead of reading lines from stdin (repeatable)
+        #[arg(short, long = "message")]
+        messages: Vec<String>,
+        /// Seconds to wait for each reply
+        #[arg(long, default_value_t = DEFAULT_CLIENT_TIMEOUT_SECS)]
+        timeout: u64,
+    },
+    /// Stream stdin to a running server and write its replies to stdout
+    ///
+    /// For example: `rustbucket send < requests.txt > replies.txt`
+    Send {
+        /// Server port to connect to
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Server host to connect to
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        /// How stdin is split into messages
+        #[arg(long, value_enum, default_value_t = send::Framing::Line)]
+        framing: send::Framing,
+        /// Seconds to wait for the connection to be established
+        #[arg(long, default_value_t = DEFAULT_CLIENT_TIMEOUT_SECS)]
+        connect_timeout: u64,
+        /// Seconds to wait for the server to reply before giving up
+        #[arg(long, default_value_t = DEFAULT_CLIENT_TIMEOUT_SECS)]
+        timeout: u64,
+        /// Encrypt the connection with Noise, using the private key in this file
+        #[arg(long, value_name = "PATH", requires = "noise_server_key")]
+        noise_key: Option<PathBuf>,
+        /// The server's Noise public key, in hex; the connection fails if the server holds another
+        #[arg(long, value_name = "HEX", value_parser = noise::parse_public_key, requires = "noise_key")]
+        noise_server_key: Option<noise::PublicKey>,
+    },
+    /// Generate load against a running server and report throughput and latency
+    Bench {
+        /// Server port to connect to
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Server host to connect to
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        /// Number of concurrent connections
+        #[arg(short, long, default_value_t = DEFAULT_BENCH_CONNECTIONS)]
+        connections: usize,
+        /// Messages sent on each connection
+        #[arg(short = 'n', long, default_value_t = DEFAULT_BENCH_MESSAGES)]
+        messages: usize,
+        /// Payload size of each message in bytes (at most 1023, so each fits in one server read)
+        #[arg(short, long, default_value_t = DEFAULT_BENCH_MESSAGE_SIZE)]
+        size: usize,
+        /// Target messages per second across all connections (0 sends as fast as possible)
+        #[arg(short, long, default_value_t = 0)]
+        rate: u64,
+        /// Seconds to wait for each reply
+        #[arg(long, default_value_t = DEFAULT_CLIENT_TIMEOUT_SECS)]
+        timeout: u64,
     },
     /// Count the number of log entries
-    Count,
+    Count {
+        /// Keep running and print the count and entries per second as the log grows
+        #[arg(short, long)]
+        follow: bool,
+        /// Milliseconds between updates with --follow
+        #[arg(short, long, default_value_t = DEFAULT_FOLLOW_INTERVAL_MS, requires = "follow")]
+        interval: u64,
+    },
+    /// Show live counters from a running server
+    Stats {
+        /// Admin port of the running server
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// List the client addresses a running server has banned, or lift a ban
+    Bans {
+        /// Lift the ban on this address instead of listing bans
+        #[arg(long, value_name = "IP")]
+        lift: Option<IpAddr>,
+        /// Admin port of the running server
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// Show version information
+    ///
+    /// With the global `--verbose` flag, includes build metadata and, if a server
+    /// is running, its uptime.
+    Version {
+        /// Admin port of a running server to report uptime for
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// Show the current server configuration
+    ShowConfig,
     /// Rotate log files
-    Rotate,
+    Rotate {
+        /// Number of rotated files to keep; older ones are deleted
+        #[arg(long, default_value_t = Paths::default().max_log_files, value_parser = clap::value_parser!(u32).range(1..))]
+        keep: u32,
+        /// Gzip rotated files
+        #[arg(long)]
+        compress: bool,
+        /// Only rotate once the current log has reached this many bytes
+        #[arg(long, value_name = "BYTES", default_value_t = 0)]
+        min_size: u64,
+        /// How to move the current log aside; copy-truncate needs no reload afterwards
+        #[arg(long, value_enum, default_value_t = rotation::Strategy::default())]
+        strategy: rotation::Strategy,
+        /// Show which files would be removed, renamed, or compressed without touching them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manage rotated log files
+    Logs {
+        #[command(subcommand)]
+        command: LogsCommand,
+    },
+    /// Tell the running server to re-read its configuration and reopen log files
+    Reload {
+        /// Admin port of the running server
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// Report whether a server is running, with its pid, address, and uptime
+    ///
+    /// Exits 0 when running, 1 when a stale pidfile remains, 3 when not running,
+    /// and 4 when the process is alive but its admin interface does not answer.
+    Status {
+        /// Admin port of the running server
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// Interactive shell for inspecting and controlling a running server
+    Admin {
+        /// Admin port of the running server
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// Check the environment for problems that would stop the server from running
+    Doctor {
+        /// Port the server will listen on
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Port the admin interface will listen on
+        #[arg(long, default_value_t = DEFAULT_ADMIN_PORT)]
+        admin_port: u16,
+    },
+    /// Start a throwaway server and check it end to end
+    Selftest {
+        /// Keep the scratch directory with the server's output and logs
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Print a shell completion script
+    ///
+    /// For example: `rustbucket completions bash > /etc/bash_completion.d/rustbucket`
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Generate a self-signed TLS certificate or a random auth token for local testing
+    Keygen {
+        #[command(subcommand)]
+        command: KeygenCommand,
+    },
+    /// List the handler plugins in the plugins directory
+    Plugins {
+        /// Directory to load handler plugins from
+        #[arg(long, value_name = "DIR", default_value = DEFAULT_PLUGIN_DIR)]
+        dir: PathBuf,
+    },
+    /// Write roff man pages for rustbucket and each subcommand
+    #[command(hide = true)]
+    Mangen {
+        /// Directory to write the pages into
+        #[arg(default_value = "man")]
+        out_dir: PathBuf,
+    },
+    /// Stop the running server and wait for it to exit
+    Stop {
+        /// Kill the server immediately instead of letting connections drain
+        #[arg(short, long)]
+        force: bool,
+        /// Seconds to wait for a graceful shutdown before giving up
+        #[arg(long, default_value_t = DEFAULT_STOP_TIMEOUT_SECS)]
+        timeout: u64,
+    },
+    /// Save the KV snapshot, write-ahead log, and config file to a compressed archive
+    ///
+    /// Safe to run while the server is running.
+    Backup {
+        /// Archive to write, conventionally ending in .tar.zst
+        archive: PathBuf,
+    },
+    /// Replace the KV snapshot, write-ahead log, and config file with those in a backup
+    ///
+    /// Every file is checked against the backup's checksums before anything is
+    /// replaced. The server must be stopped first.
+    Restore {
+        /// Archive written by `backup`
+        archive: PathBuf,
+        /// Check the archive and list its files without restoring them
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Update server configuration
     UpdateConfig {
         /// Verbosity level (0-3)
-        #[arg(short, long)]
+        #[arg(long)]
         verbosity: Option<u32>,
         /// Maximum number of connections
         #[arg(short, long)]
@@ -103,252 +592,559 @@ enum Commands {
         /// Connection timeout in seconds
         #[arg(short, long)]
         timeout: Option<u32>,
+        /// Show which fields would change without writing the config file
+        #[arg(long)]
+        dry_run: bool,
     },
 }
 
-fn rotate_logs() -> io::Result<()> {
-    // Delete the oldest log file if it exists
-    let oldest_log = format!("{}.{}", LOG_FILE, MAX_LOG_FILES);
-    if Path::new(&oldest_log).exists() {
-        remove_file(&oldest_log)?;
-    }
-
-    // Rotate existing log files
-    for i in (1..MAX_LOG_FILES).rev() {
-        let old_name = format!("{}.{}", LOG_FILE, i);
-        let new_name = format!("{}.{}", LOG_FILE, i + 1);
-        if Path::new(&old_name).exists() {
-            rename(&old_name, &new_name)?;
-        }
-    }
-
-    // Rename current log file to .1
-    if Path::new(LOG_FILE).exists() {
-        rename(LOG_FILE, format!("{}.1", LOG_FILE))?;
-    }
-
-    Ok(())
-}
-
-/// Appends a message to the log file with timestamp
-fn append_log(file: &mut File, message: &str) -> io::Result<()> {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    writeln!(file, "[{}] {}", timestamp, message)?;
-    file.flush()?;
-    Ok(())
+/// Protocols `run` can serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Reply to every message with `Echo: ` and the message
+    Echo,
+    /// A key-value store answering GET, SET, DEL, and SAVE
+    Kv,
+    /// A chat room relaying every line to all other connected clients
+    Chat,
+    /// A scratch file drop answering PUT and GETFILE
+    Files,
+    /// A persistent job queue answering ENQUEUE, DEQUEUE, and ACK
+    Queue,
 }
 
-fn count_logs() -> io::Result<()> {
-    if !Path::new(LOG_FILE).exists() {
-        println!("Log file does not exist. No entries to count.");
-        return Ok(());
-    }
-
-    let file = File::open(LOG_FILE)?;
-    file.lock_shared()?;
-    let reader = BufReader::new(file);
-    let count = reader.lines().count();
-    println!("Total log entries: {}", count);
-    Ok(())
+/// Subcommands of `logs`
+#[derive(Subcommand)]
+enum LogsCommand {
+    /// Delete rotated logs that are too old or exceed a size budget
+    ///
+    /// The current log is never deleted. Asks for confirmation unless `--yes` is
+    /// given, which is required when stdin is not a terminal (e.g. from cron).
+    #[command(group(ArgGroup::new("limit").required(true).multiple(true).args(["older_than", "max_size"])))]
+    Purge {
+        /// Delete rotated logs last modified longer ago than this (e.g. 90m, 12h, 7d, 2w)
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: Option<Duration>,
+        /// Delete the oldest rotated logs until the rest fit in this many bytes
+        #[arg(long, value_name = "BYTES")]
+        max_size: Option<u64>,
+        /// Show which files would be deleted without deleting them
+        #[arg(long)]
+        dry_run: bool,
+        /// Delete without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
 }
 
-fn update_config(config: &mut Config, verbosity: Option<u32>, max_connections: Option<u32>, timeout: Option<u32>) {
-    if let Some(v) = verbosity {
-        config.verbosity = v;
-    }
-    if let Some(m) = max_connections {
-        config.max_connections = m;
-    }
-    if let Some(t) = timeout {
-        config.timeout_seconds = t;
-    }
-    config.version += 1;
+/// Subcommands of `keygen`
+#[derive(Subcommand)]
+enum KeygenCommand {
+    /// Write a self-signed certificate (`<NAME>.crt`) and private key (`<NAME>.key`)
+    Cert {
+        /// Directory to write the files into
+        #[arg(long, default_value = ".")]
+        out_dir: PathBuf,
+        /// Base name of the certificate and key files
+        #[arg(long, default_value = "rustbucket")]
+        name: String,
+        /// Host name or IP address the certificate is valid for (repeatable)
+        #[arg(long = "host", default_values_t = ["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()])]
+        hosts: Vec<String>,
+        /// Days until the certificate expires
+        #[arg(long, default_value_t = DEFAULT_CERT_DAYS)]
+        days: u32,
+        /// Overwrite existing files
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Print a random token, or write it to a file readable only by its owner
+    Token {
+        /// Random bytes in the token (printed as twice as many hex digits)
+        #[arg(long, default_value_t = DEFAULT_TOKEN_BYTES, value_parser = clap::value_parser!(u16).range(16..))]
+        bytes: u16,
+        /// File to write the token to instead of printing it
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Overwrite an existing file
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Write a Noise private key to a file readable only by its owner, and print its public key
+    Noise {
+        /// File to write the private key to
+        #[arg(long)]
+        out: PathBuf,
+        /// Overwrite an existing file
+        #[arg(short, long)]
+        force: bool,
+    },
 }
 
-/// Server state shared across threads
-#[derive(Debug)]
-struct ServerState {
-    /// Flag indicating if a shutdown has been requested
-    shutdown_requested: AtomicBool,
-    /// Flag for forcing immediate shutdown
-    force_shutdown: AtomicBool,
+/// Parses an age such as `90s`, `30m`, `12h`, `7d`, or `2w`; a bare number is seconds
+fn parse_age(value: &str) -> Result<Duration, String> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid age: {}", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {:?} in age {} (use s, m, h, d, or w)", unit, value)),
+    };
+    Ok(Duration::from_secs(number.saturating_mul(seconds)))
 }
 
-impl ServerState {
-    /// Creates a new ServerState with default values
-    fn new() -> Self {
-        Self {
-            shutdown_requested: AtomicBool::new(false),
-            force_shutdown: AtomicBool::new(false),
+/// Generates the key material asked for and reports where it went
+fn run_keygen(command: KeygenCommand, out: &Output) -> io::Result<()> {
+    match command {
+        KeygenCommand::Cert { out_dir, name, hosts, days, force } => {
+            let pair = keygen::generate_cert(&keygen::CertConfig { hosts: hosts.clone(), days })?;
+            std::fs::create_dir_all(&out_dir)?;
+            let cert_path = out_dir.join(format!("{}.crt", name));
+            let key_path = out_dir.join(format!("{}.key", name));
+            keygen::write_secret(&key_path, &pair.key_pem, force)?;
+            keygen::write_public(&cert_path, &pair.cert_pem, force)?;
+            out.emit(&Message::new(
+                format!(
+                    "Wrote {} and {} (valid for {} days for {})",
+                    cert_path.display(),
+                    key_path.display(),
+                    days,
+                    hosts.join(", ")
+                ),
+                json!({
+                    "cert": cert_path.display().to_string(),
+                    "key": key_path.display().to_string(),
+                    "hosts": hosts,
+                    "days": days,
+                }),
+            ))
+        }
+        KeygenCommand::Token { bytes, out: None, .. } => {
+            let token = keygen::generate_token(bytes.into());
+            out.emit(&Message::new(token.to_string(), json!({ "token": token.as_str() })))
+        }
+        KeygenCommand::Token { bytes, out: Some(path), force } => {
+            let token = Zeroizing::new(format!("{}\n", keygen::generate_token(bytes.into()).as_str()));
+            keygen::write_secret(&path, &token, force)?;
+            out.emit(&Message::new(
+                format!("Wrote a {}-byte token to {}", bytes, path.display()),
+                json!({ "path": path.display().to_string(), "bytes": bytes }),
+            ))
+        }
+        KeygenCommand::Noise { out: path, force } => {
+            let key = NoiseKey::generate();
+            keygen::write_secret(&path, &Zeroizing::new(format!("{}\n", key.private_hex().as_str())), force)?;
+            let public = noise::to_hex(&key.public_key());
+            out.emit(&Message::new(
+                format!("Wrote a Noise private key to {}; its public key is {}", path.display(), public),
+                json!({ "path": path.display().to_string(), "public_key": public }),
+            ))
         }
     }
 }
 
-/// Sets up signal handlers for graceful shutdown
-fn setup_signal_handlers(server_state: Arc<ServerState>) -> io::Result<()> {
-    let server_state_clone = Arc::clone(&server_state);
-    
-    // Handle SIGINT (Ctrl+C) and SIGTERM
-    ctrlc::set_handler(move || {
-        if server_state_clone.shutdown_requested.load(Ordering::SeqCst) {
-            println!("Second SIGTERM received, forcing shutdown...");
-            server_state_clone.force_shutdown.store(true, Ordering::SeqCst);
-        } else {
-            println!("SIGTERM received, initiating graceful shutdown...");
-            server_state_clone.shutdown_requested.store(true, Ordering::SeqCst);
-        }
-    }).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    
-    Ok(())
-}
-
-/// Runs the TCP server with the specified configuration
-fn run_server(port: u16, num_threads: usize) -> io::Result<()> {
-    // Initialize server state
-    let server_state = Arc::new(ServerState::new());
-    
-    // Set up signal handlers
-    setup_signal_handlers(Arc::clone(&server_state))?;
-
-    // Create memory-mapped config file
-    let config_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(CONFIG_FILE)?;
-    config_file.set_len(16)?;
-
-    let mut mmap = unsafe { MmapOptions::new().map_mut(&config_file)? };
-
-    // Initialize config
-    let config = Arc::new(Config::new());
-    mmap[..16].copy_from_slice(&config.to_bytes());
-
-    // Create thread pool
-    let pool = ThreadPool::new(num_threads);
-    println!("Created thread pool with {} workers", num_threads);
-
-    // Main server loop
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-    println!("Server listening on port {} with {} worker threads", port, num_threads);
+/// Main entry point
+fn main() {
+    let args = Cli::parse();
+    console::init(args.verbose, args.quiet, args.color);
+    let out = Output::new(args.output).quiet(args.quiet);
 
-    for stream in listener.incoming() {
-        // Check for shutdown request
-        if server_state.shutdown_requested.load(Ordering::SeqCst) {
-            println!("Shutdown requested, stopping new connections...");
-            break;
+    match run_command(args.command, args.verbose, &out) {
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            out.error(&e);
+            std::process::exit(e.exit_code());
         }
+    }
+}
 
-        match stream {
-            Ok(stream) => {
-                // Read current config for this connection
-                let mut config_bytes = [0u8; 16];
-                config_bytes.copy_from_slice(&mmap[..16]);
-                let current_config = Config::from_bytes(&config_bytes);
-                let config = Arc::new(current_config);
-
-                // Clone the Arc for the thread
-                let config_clone = Arc::clone(&config);
-                let server_state_clone = Arc::clone(&server_state);
-                
-                // Spawn a new thread to handle the connection
-                pool.execute(move || {
-                    if let Err(e) = handle_connection(stream, config_clone, server_state_clone) {
-                        eprintln!("Error handling connection: {}", e);
-                    }
+/// Runs a subcommand, returning the process exit code
+fn run_command(command: Commands, verbose: u8, out: &Output) -> rustbucket::Result<i32> {
+    let paths = Paths::default();
+    let log_file = paths.log_file.to_string_lossy();
+    match command {
+        Commands::Run {
+            port,
+            threads,
+            max_threads,
+            thread_idle,
+            otlp_endpoint,
+            otlp_interval,
+            statsd_addr,
+            statsd_prefix,
+            statsd_tags,
+            statsd_interval,
+            alert_webhook,
+            alert_window,
+            alert_error_rate,
+            alert_accept_errors,
+            alert_fd_exhaustion,
+            heartbeat_interval,
+            admin_port,
+            no_admin,
+            auth_token,
+            auth_token_file,
+            auth_token_env,
+            auth_users_file,
+            auth_lockout_after,
+            auth_lockout,
+            auth_lockout_max,
+            max_connections_per_ip,
+            request_deadline,
+            max_reading_connections,
+            memory_limit,
+            read_buffer_size,
+            line_framing,
+            max_message_size,
+            text_mode,
+            buffer_pool,
+            io_uring,
+            reuse_port,
+            nodelay,
+            recv_buffer,
+            send_buffer,
+            linger,
+            keepalive,
+            keepalive_interval,
+            keepalive_retries,
+            log_ring,
+            ban_after,
+            ban_window,
+            ban_duration,
+            flood_factor,
+            flood_min_rate,
+            flood_calm,
+            defensive_timeout,
+            defensive_max_connections_per_ip,
+            defensive_max_queued,
+            rate_limit,
+            rate_burst,
+            throttle_close_after,
+            mode,
+            snapshot_interval,
+            no_wal,
+            blob_store_size,
+            keyspace_events,
+            max_file_size,
+            plugin,
+            plugin_dir,
+            scripts,
+            sandbox,
+            tls_cert,
+            tls_key,
+            tls_key_passphrase_file,
+            tls_key_passphrase_env,
+            tls_min_version,
+            tls_ciphers,
+            tls_alpn,
+            noise_key,
+            noise_authorized_keys,
+        } => {
+            let mut server = Server::builder().paths(paths.clone()).port(port).threads(threads).admin_port(admin_port);
+            if no_admin {
+                server = server.disable_admin();
+            }
+            if let Some(max) = max_threads {
+                server = server.max_threads(max).thread_idle_timeout(Duration::from_secs(thread_idle.max(1)));
+            }
+            if let Some(endpoint) = otlp_endpoint {
+                server = server.otlp(OtlpConfig {
+                    endpoint,
+                    interval: Duration::from_secs(otlp_interval.max(1)),
                 });
             }
-            Err(e) => {
-                eprintln!("Failed to accept connection: {}", e);
+            if let Some(addr) = statsd_addr {
+                server = server.statsd(StatsdConfig {
+                    addr,
+                    prefix: statsd_prefix,
+                    tags: statsd_tags,
+                    interval: Duration::from_secs(statsd_interval.max(1)),
+                });
             }
-        }
-    }
-
-    // Wait for all active connections to complete
-    println!("Waiting for active connections to complete...");
-    pool.join();
-
-    println!("Server shutdown complete");
-    Ok(())
-}
-
-/// Handles a single client connection
-fn handle_connection(mut stream: TcpStream, config: Arc<Config>, server_state: Arc<ServerState>) -> io::Result<()> {
-    let mut buffer = [0; 1024];
-    
-    // Set read timeout to prevent hanging on inactive connections
-    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-    
-    while !server_state.force_shutdown.load(Ordering::SeqCst) {
-        match stream.read(&mut buffer) {
-            Ok(0) => break, // Connection closed by client
-            Ok(n) => {
-                let message = String::from_utf8_lossy(&buffer[..n]);
-                println!("Received: {}", message.trim());
-                
-                // Simple echo server response
-                stream.write_all(b"Echo: ")?;
-                stream.write_all(&buffer[..n])?;
+            if let Some(webhook) = alert_webhook {
+                server = server.alerts(AlertConfig {
+                    webhook,
+                    window: Duration::from_secs(alert_window.max(1)),
+                    error_rate: alert_error_rate,
+                    accept_errors: alert_accept_errors,
+                    fd_exhaustion: alert_fd_exhaustion,
+                });
+            }
+            if heartbeat_interval > 0 {
+                server = server.heartbeat(Duration::from_secs(heartbeat_interval));
+            }
+            if let Some(cap) = max_connections_per_ip {
+                server = server.max_connections_per_ip(cap);
+            }
+            if let Some(seconds) = request_deadline {
+                server = server.request_deadline(Duration::from_secs(seconds.max(1)));
+            }
+            if let Some(cap) = max_reading_connections {
+                server = server.max_reading_connections(cap);
+            }
+            server = server.read_buffer_size(read_buffer_size).buffer_pool(buffer_pool).io_uring(io_uring);
+            server = server.max_message_size(max_message_size);
+            if line_framing {
+                server = server.framing(Framing::Line);
+            }
+            if text_mode {
+                server = server.connection_mode(ConnectionMode::Text);
+            }
+            server = server.reuse_port(reuse_port);
+            server = server.socket_options(SocketOptions {
+                nodelay,
+                recv_buffer,
+                send_buffer,
+                linger: linger.map(Duration::from_secs),
+                keepalive: keepalive.map(|idle| Keepalive {
+                    idle: Duration::from_secs(idle),
+                    interval: Duration::from_secs(keepalive_interval),
+                    retries: keepalive_retries,
+                }),
+            });
+            if let Some(capacity) = log_ring {
+                server = server.log_sink(RingSink::open(&paths.log_file, &paths.log_ring_file, capacity)?);
+            }
+            let memory = memory_limit.map(|limit| Arc::new(MemoryBudget::new(limit)));
+            if let Some(budget) = &memory {
+                server = server.memory_budget(Arc::clone(budget));
+            }
+            if let Some(offences) = ban_after {
+                server = server.auto_ban(BanPolicy {
+                    offences,
+                    window: Duration::from_secs(ban_window.max(1)),
+                    duration: Duration::from_secs(ban_duration.max(1)),
+                });
+            }
+            if let Some(factor) = flood_factor {
+                server = server.flood_protection(FloodPolicy {
+                    factor,
+                    min_rate: flood_min_rate,
+                    calm: Duration::from_secs(flood_calm),
+                    timeout: Duration::from_secs(defensive_timeout.max(1)),
+                    max_connections_per_ip: defensive_max_connections_per_ip.max(1),
+                    max_queued: defensive_max_queued,
+                    ..FloodPolicy::default()
+                });
+            }
+            // Outside authentication, so that guessing tokens is throttled too
+            if let Some(per_second) = rate_limit {
+                server = server.middleware(IpRateLimit::new(per_second, rate_burst).close_after(throttle_close_after));
+            }
+            let auth_token = match (auth_token, auth_token_file, auth_token_env) {
+                (Some(token), _, _) => Some(Secret::new(token)),
+                (_, Some(path), _) => Some(Secret::from_file(path)?),
+                (_, _, Some(name)) => Some(Secret::from_env(&name)?),
+                _ => None,
+            };
+            let auth = match (auth_users_file, auth_token) {
+                (Some(path), token) => {
+                    let users = TokenAuth::load_users(path)?;
+                    Some(match token {
+                        Some(token) => users.user(DEFAULT_IDENTITY, token),
+                        None => users,
+                    })
+                }
+                (None, token) => token.map(TokenAuth::new),
+            };
+            if let Some(auth) = auth {
+                server = server.middleware(auth.lockout(LockoutPolicy {
+                    failures: auth_lockout_after,
+                    initial: Duration::from_secs(auth_lockout.max(1)),
+                    max: Duration::from_secs(auth_lockout_max.max(1)),
+                }));
+            }
+            if scripts {
+                for layer in ScriptLayer::load_dir(&paths.scripts_dir)? {
+                    server = server.middleware(layer);
+                }
+            }
+            if let Some(name) = plugin {
+                server = server.handler(plugins::find(&plugin_dir, &name)?);
+            }
+            if sandbox {
+                server = server.sandbox(Sandbox::for_paths(&paths));
+            }
+            if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                let mut tls = TlsSettings::new(cert, key).min_version(tls_min_version);
+                let passphrase = match (tls_key_passphrase_file, tls_key_passphrase_env) {
+                    (Some(path), _) => Some(Secret::from_file(path)?),
+                    (_, Some(name)) => Some(Secret::from_env(&name)?),
+                    _ => None,
+                };
+                if let Some(passphrase) = passphrase {
+                    tls = tls.key_passphrase(passphrase);
+                }
+                server = server.tls(tls.cipher_suites(tls_ciphers).alpn(tls_alpn));
+            }
+            if let (Some(key), Some(authorized)) = (noise_key, noise_authorized_keys) {
+                server = server.noise(NoiseSettings::new(NoiseKey::from_file(key)?).load_authorized_keys(authorized)?);
+            }
+            let mut kv_store = None;
+            if mode == Mode::Kv {
+                let mut store = Store::open(&paths.snapshot_file)?;
+                if !no_wal {
+                    store = store.with_wal(&paths.wal_file)?;
+                }
+                if blob_store_size > 0 {
+                    let slots = blob_store_size.div_ceil(DEFAULT_SLOT_SIZE);
+                    store = store.with_blobs(BlobStore::create(&paths.blob_file, DEFAULT_SLOT_SIZE, slots)?);
+                }
+                if keyspace_events {
+                    store = store.with_keyspace_events();
+                }
+                if let Some(budget) = &memory {
+                    store = store.with_memory_budget(Arc::clone(budget));
+                }
+                let store = Arc::new(store);
+                kv_store = Some(Arc::clone(&store));
+                server = server.handler(KvHandler::new(store));
+            }
+            if mode == Mode::Chat {
+                server = server.handler(ChatHandler::new());
             }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // Check for shutdown request during timeout
-                if server_state.shutdown_requested.load(Ordering::SeqCst) {
-                    break;
+            if mode == Mode::Files {
+                server = server.handler(FileHandler::new(&paths.storage_dir)?.max_file_size(max_file_size));
+            }
+            if mode == Mode::Queue {
+                let mut queue = MessageQueue::open(&paths.queue_file)?;
+                if let Some(budget) = &memory {
+                    queue = queue.with_memory_budget(Arc::clone(budget));
                 }
-                continue;
+                server = server.handler(QueueHandler::new(Arc::new(queue)));
+            }
+            let handle = server.build()?.start()?;
+            // Started after the server so that the sandbox covers them too; dropped once the
+            // server has stopped, taking a final snapshot
+            let _tasks = kv_store.map(|store| {
+                let snapshots = store.start_snapshots(Duration::from_secs(snapshot_interval));
+                (snapshots, store.start_expiry(EXPIRY_SWEEP_INTERVAL))
+            });
+            handle.wait();
+            if handle.was_forced() {
+                return Ok(EXIT_FORCED_SHUTDOWN);
             }
-            Err(e) => return Err(e),
         }
-    }
-    
-    Ok(())
-}
-
-fn update_server_config(verbosity: Option<u32>, max_connections: Option<u32>, timeout: Option<u32>) -> io::Result<()> {
-    // Open memory-mapped config file
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(CONFIG_FILE)?;
-    file.set_len(16)?; // Ensure file is large enough
-
-    let mut mmap = unsafe { MmapOptions::new().map_mut(&file)? };
-
-    // Read current config
-    let mut config_bytes = [0u8; 16];
-    config_bytes.copy_from_slice(&mmap[..16]);
-    let mut config = Config::from_bytes(&config_bytes);
-
-    // Update config
-    update_config(&mut config, verbosity, max_connections, timeout);
-
-    // Write updated config
-    mmap[..16].copy_from_slice(&config.to_bytes());
-
-    println!("Configuration updated: {:?}", config);
-    Ok(())
-}
-
-/// Main entry point
-fn main() -> io::Result<()> {
-    let args = Cli::parse();
-    
-    match args.command {
-        Commands::Run { port, threads } => {
-            run_server(port, threads)?;
+        Commands::Monitor { admin_port, interval } => {
+            monitor::run_monitor(&paths.log_file, admin_port, Duration::from_millis(interval.max(100)))?;
+        }
+        Commands::Client { port, host, messages, timeout } => {
+            client::run_client(&host, port, messages, Duration::from_secs(timeout.max(1)), out)?;
+        }
+        Commands::Send { port, host, framing, connect_timeout, timeout, noise_key, noise_server_key } => {
+            let noise = match (noise_key, noise_server_key) {
+                (Some(path), Some(server_key)) => Some((NoiseKey::from_file(path)?, server_key)),
+                _ => None,
+            };
+            send::run_send(&send::SendConfig {
+                host,
+                port,
+                framing,
+                connect_timeout: Duration::from_secs(connect_timeout.max(1)),
+                timeout: Duration::from_secs(timeout.max(1)),
+                noise,
+            })?;
+        }
+        Commands::Bench { port, host, connections, messages, size, rate, timeout } => {
+            let config = bench::BenchConfig {
+                host,
+                port,
+                connections,
+                messages,
+                size: size.clamp(1, 1023),
+                rate,
+                timeout: Duration::from_secs(timeout.max(1)),
+            };
+            bench::run_bench(config, out)?;
+        }
+        Commands::Count { follow: true, interval } => {
+            follow::run_follow(&log_file, Duration::from_millis(interval.max(100)), out)?;
+        }
+        Commands::Count { follow: false, .. } => {
+            logs::count_logs(&paths.log_file, out)?;
+        }
+        Commands::Stats { admin_port } => {
+            control::show_stats(admin_port, out)?;
         }
-        Commands::Count => {
-            count_logs()?;
+        Commands::Bans { lift: None, admin_port } => {
+            control::show_bans(admin_port, out)?;
         }
-        Commands::Rotate => {
-            rotate_logs()?;
-            println!("Log files rotated successfully");
+        Commands::Bans { lift: Some(ip), admin_port } => {
+            control::lift_ban(admin_port, ip, out)?;
         }
-        Commands::UpdateConfig { verbosity, max_connections, timeout } => {
-            update_server_config(verbosity, max_connections, timeout)?;
+        Commands::Version { admin_port } => {
+            control::show_version(verbose > 0, admin_port, out)?;
+        }
+        Commands::ShowConfig => {
+            config::show_config(&paths.config_file, out)?;
+        }
+        Commands::Reload { admin_port } => {
+            control::reload_server(admin_port, out)?;
+        }
+        Commands::Status { admin_port } => {
+            return Ok(control::show_status(&paths.pid_file, admin_port, out)?);
+        }
+        Commands::Admin { admin_port } => {
+            shell::run_shell(admin_port)?;
+        }
+        Commands::Doctor { port, admin_port } => {
+            if !doctor::run_doctor(&paths, port, admin_port, out)? {
+                return Ok(1);
+            }
+        }
+        Commands::Selftest { keep } => {
+            if !selftest::run_selftest(keep, out)? {
+                return Ok(1);
+            }
+        }
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "rustbucket", &mut io::stdout());
+        }
+        Commands::Plugins { dir } => {
+            cmd::plugins::list_plugins(&dir, out)?;
+        }
+        Commands::Keygen { command } => {
+            run_keygen(command, out)?;
+        }
+        Commands::Mangen { out_dir } => {
+            let pages = mangen::write_man_pages(Cli::command(), &out_dir)?;
+            out.emit(&Message::new(
+                format!("Wrote {} man pages to {}", pages.len(), out_dir.display()),
+                json!({ "dir": out_dir.display().to_string(), "pages": pages.iter().map(|page| page.display().to_string()).collect::<Vec<_>>() }),
+            ))?;
+        }
+        Commands::Stop { force, timeout } => {
+            control::stop_server(&paths.pid_file, force, Duration::from_secs(timeout), out)?;
+        }
+        Commands::Rotate { keep, compress, min_size, strategy, dry_run } => {
+            let plan = rotation::plan(&log_file, &rotation::Policy { keep, compress, min_size, strategy })?;
+            if !dry_run {
+                rotation::apply(&plan.actions)?;
+            }
+            out.emit(&rotation::RotationReport { log_file: log_file.to_string(), plan, dry_run })?;
+        }
+        Commands::Logs { command: LogsCommand::Purge { older_than, max_size, dry_run, yes } } => {
+            logs::purge_logs(&log_file, rotation::Retention { older_than, max_size }, dry_run, yes, out)?;
+        }
+        Commands::Backup { archive } => {
+            out.emit(&backup::backup(&paths, &archive)?)?;
+        }
+        Commands::Restore { archive, dry_run } => {
+            out.emit(&backup::restore(&paths, &archive, dry_run)?)?;
+        }
+        Commands::UpdateConfig { verbosity, max_connections, timeout, dry_run } => {
+            if dry_run {
+                config::preview_config_update(&paths.config_file, verbosity, max_connections, timeout, out)?;
+            } else {
+                config::update_server_config(&paths.config_file, verbosity, max_connections, timeout, out)?;
+            }
         }
     }
 
-    Ok(())
+    Ok(0)
 } 
\ No newline at end of file
@@ -0,0 +1,251 @@
+//! A budget for the memory the server holds on behalf of its clients.
+//!
+//! Connection buffers, outboxes, message queues, and the KV store report the
+//! bytes they hold to a shared [`MemoryBudget`]. The figures are estimates of
+//! the data kept for clients, in the same spirit as `INFO`'s, not a measure of
+//! the whole process. Once they add up to more than the limit, the server sheds
+//! load rather than growing until the operating system kills it: KV writes and
+//! enqueues fail with `ERR out of memory`, and connections that have gone
+//! quiet are closed so that their buffers are freed. Reads, deletes, pops, and
+//! acknowledgements still work, so clients can bring usage back down.
+//!
+//! To check the estimates against the real thing, [`process_memory`] reads the
+//! resident size the operating system counts for the whole process, and
+//! [`heap_stats`] what the `CountingAllocator` of the `alloc-stats` feature
+//! counted, if it is the global allocator.
+//!
+//! Give the same budget to the stores and the server:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use rustbucket::kv::{KvHandler, Store};
+//! use rustbucket::memory::MemoryBudget;
+//! use rustbucket::Server;
+//!
+//! let budget = Arc::new(MemoryBudget::new(512 * 1024 * 1024));
+//! let store = Arc::new(Store::open("kv.snapshot")?.with_memory_budget(Arc::clone(&budget)));
+//! Server::builder().memory_budget(budget).handler(KvHandler::new(store)).build()?.run()?;
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::server::ServerState;
+
+mod heap;
+mod process;
+
+#[cfg(feature = "alloc-stats")]
+pub use heap::CountingAllocator;
+pub use heap::{heap_stats, HeapStats};
+pub use process::{process_memory, ProcessMemory};
+
+/// How long a connection must have gone without sending anything to be closed when over budget
+pub const IDLE_BEFORE_SHEDDING: Duration = Duration::from_secs(1);
+
+/// Time between checks of whether the budget is exceeded
+const SHED_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What a share of the budget is spent on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pool {
+    /// Connections' read and response buffers and their outboxes
+    Buffers,
+    /// Messages in message queues
+    Queues,
+    /// Keys and values in the KV store
+    Kv,
+}
+
+impl Pool {
+    const ALL: [Pool; 3] = [Pool::Buffers, Pool::Queues, Pool::Kv];
+
+    /// Name used in metrics, such as `buffers`
+    pub fn name(self) -> &'static str {
+        match self {
+            Pool::Buffers => "buffers",
+            Pool::Queues => "queues",
+            Pool::Kv => "kv",
+        }
+    }
+}
+
+/// Bytes held in each pool at one moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// `None` if the budget is unlimited
+    pub limit: Option<usize>,
+    pub buffers: usize,
+    pub queues: usize,
+    pub kv: usize,
+    /// Writes refused for going over the limit
+    pub rejected: u64,
+    /// Idle connections closed to get back under the limit
+    pub shed: u64,
+}
+
+impl MemoryUsage {
+    /// Bytes held across all pools
+    pub fn total(&self) -> usize {
+        self.buffers + self.queues + self.kv
+    }
+}
+
+/// Bytes held for clients, against an optional limit
+#[derive(Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: [AtomicUsize; 3],
+    rejected: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        Self { limit: Some(limit), ..Self::default() }
+    }
+
+    /// A budget that only keeps count and never refuses anything
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Bytes the pools may hold between them, if limited
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Bytes held across all pools
+    pub fn used(&self) -> usize {
+        self.used.iter().map(|used| used.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Whether the pools hold more than the limit
+    pub fn is_exceeded(&self) -> bool {
+        self.limit.is_some_and(|limit| self.used() > limit)
+    }
+
+    /// Whether `bytes` more can be stored without going over the limit, counting a rejection if not
+    ///
+    /// Only checks; the caller reserves the bytes once it has stored them.
+    pub fn admit(&self, bytes: usize) -> bool {
+        let Some(limit) = self.limit else { return true };
+        if self.used().saturating_add(bytes) <= limit {
+            return true;
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Counts `bytes` as held in `pool`
+    pub fn reserve(&self, pool: Pool, bytes: usize) {
+        self.used[pool as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` in `pool` as freed
+    pub fn release(&self, pool: Pool, bytes: usize) {
+        self.used[pool as usize].fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Reserves `bytes` in `pool` until the returned charge is dropped
+    pub fn charge(&self, pool: Pool, bytes: usize) -> Charge<'_> {
+        self.reserve(pool, bytes);
+        Charge { budget: self, pool, bytes }
+    }
+
+    /// Bytes held in each pool, and what has been done to stay under the limit
+    pub fn usage(&self) -> MemoryUsage {
+        let used = |pool: Pool| self.used[pool as usize].load(Ordering::Relaxed);
+        MemoryUsage {
+            limit: self.limit,
+            buffers: used(Pool::Buffers),
+            queues: used(Pool::Queues),
+            kv: used(Pool::Kv),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("MemoryBudget");
+        debug.field("limit", &self.limit);
+        for pool in Pool::ALL {
+            debug.field(pool.name(), &self.used[pool as usize].load(Ordering::Relaxed));
+        }
+        debug.finish()
+    }
+}
+
+/// Bytes reserved in a [`MemoryBudget`], released when dropped
+#[derive(Debug)]
+pub struct Charge<'a> {
+    budget: &'a MemoryBudget,
+    pool: Pool,
+    bytes: usize,
+}
+
+impl Charge<'_> {
+    /// Changes the bytes reserved to `bytes`
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.budget.reserve(self.pool, bytes - self.bytes);
+        } else {
+            self.budget.release(self.pool, self.bytes - bytes);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Charge<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.pool, self.bytes);
+    }
+}
+
+/// Background thread closing idle connections while the server is over its memory budget
+pub(crate) struct Shedder {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Shedder {
+    /// Starts checking the server's budget every [`SHED_INTERVAL`]
+    pub(crate) fn start(server_state: Arc<ServerState>) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(SHED_INTERVAL) {
+                let budget = &server_state.memory;
+                if !budget.is_exceeded() {
+                    continue;
+                }
+                let connections = server_state.connections.list();
+                let idle = connections.iter().filter(|entry| entry.idle() >= IDLE_BEFORE_SHEDDING);
+                let closed = idle.filter(|entry| entry.close()).count();
+                if closed > 0 {
+                    budget.shed.fetch_add(closed as u64, Ordering::Relaxed);
+                    log::warn!(
+                        "Over the memory budget ({} of {} bytes); closed {} idle connections",
+                        budget.used(),
+                        budget.limit.unwrap_or_default(),
+                        closed
+                    );
+                }
+            }
+        });
+        Self { stop_tx, handle }
+    }
+
+    /// Stops the shedding thread
+    pub(crate) fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
@@ -0,0 +1,142 @@
+//! Heap statistics kept by a counting global allocator.
+//!
+//! With the `alloc-stats` feature, [`CountingAllocator`] wraps a global
+//! allocator and counts what goes through it. The `rustbucket` binary installs
+//! it over the system allocator, or over jemalloc or mimalloc when built with
+//! the `jemalloc` or `mimalloc` feature; a program embedding the server can
+//! install it the same way around an allocator of its own choosing:
+//!
+//! ```ignore
+//! use rustbucket::memory::CountingAllocator;
+//! use tikv_jemallocator::Jemalloc;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator<Jemalloc> = CountingAllocator::new(Jemalloc);
+//! ```
+//!
+//! Every allocation and free then updates a few shared atomics, which is why
+//! the counting is left off unless asked for.
+
+#[cfg(feature = "alloc-stats")]
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "alloc-stats")]
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "alloc-stats")]
+use std::sync::OnceLock;
+
+/// What the counting allocator has seen since the process started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Type of the allocator being counted, such as `System` or `Jemalloc`
+    pub allocator: &'static str,
+    /// Bytes allocated and not yet freed
+    pub allocated: usize,
+    /// Most bytes allocated at once
+    pub peak: usize,
+    /// Allocations made; growing or shrinking one counts as a new allocation and a free
+    pub allocations: u64,
+    /// Allocations freed
+    pub frees: u64,
+}
+
+impl HeapStats {
+    /// Allocations not yet freed
+    pub fn live(&self) -> u64 {
+        self.allocations.saturating_sub(self.frees)
+    }
+}
+
+#[cfg(feature = "alloc-stats")]
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "alloc-stats")]
+static WRAPPED: OnceLock<&'static str> = OnceLock::new();
+#[cfg(feature = "alloc-stats")]
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "alloc-stats")]
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "alloc-stats")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-stats")]
+static FREES: AtomicU64 = AtomicU64::new(0);
+
+/// A global allocator that counts the allocations it passes on to `A`
+#[cfg(feature = "alloc-stats")]
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+#[cfg(feature = "alloc-stats")]
+impl<A> CountingAllocator<A> {
+    /// Counts the allocations made through `inner`
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+#[cfg(feature = "alloc-stats")]
+fn allocated<A>(bytes: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        let name = std::any::type_name::<A>();
+        let _ = WRAPPED.set(name.rsplit("::").next().unwrap_or(name));
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let total = ALLOCATED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK.fetch_max(total, Ordering::Relaxed);
+}
+
+#[cfg(feature = "alloc-stats")]
+fn freed(bytes: usize) {
+    FREES.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+// SAFETY: every call is passed on to `inner` unchanged; only the counters are added
+#[cfg(feature = "alloc-stats")]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            allocated::<A>(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated::<A>(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.inner.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            freed(layout.size());
+            allocated::<A>(new_size);
+        }
+        new
+    }
+}
+
+/// What the counting allocator has seen, or `None` unless it is the global allocator
+pub fn heap_stats() -> Option<HeapStats> {
+    #[cfg(feature = "alloc-stats")]
+    if INSTALLED.load(Ordering::Relaxed) {
+        return Some(HeapStats {
+            allocator: WRAPPED.get().copied().unwrap_or_default(),
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+            peak: PEAK.load(Ordering::Relaxed),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            frees: FREES.load(Ordering::Relaxed),
+        });
+    }
+    None
+}
@@ -0,0 +1,49 @@
+//! Memory the operating system reports for the whole process.
+
+/// Resident and virtual memory of the server process, as the operating system counts it
+///
+/// Unlike [`MemoryUsage`](super::MemoryUsage), which estimates the data held
+/// for clients, these figures cover everything: code, stacks, allocator
+/// overhead, and memory freed but not yet handed back. Figures a platform does
+/// not report are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessMemory {
+    /// Bytes currently in physical memory
+    pub resident: Option<u64>,
+    /// Most bytes ever in physical memory at once
+    pub peak_resident: Option<u64>,
+    /// Bytes of address space mapped
+    pub virtual_size: Option<u64>,
+}
+
+/// Reads the process's memory figures, from `/proc/self/status` on Linux
+#[cfg(target_os = "linux")]
+pub fn process_memory() -> ProcessMemory {
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return ProcessMemory::default();
+    };
+    // Lines such as `VmRSS:     5420 kB`
+    let field = |name: &str| {
+        let line = status.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?;
+        let kib: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+        Some(kib * 1024)
+    };
+    ProcessMemory { resident: field("VmRSS"), peak_resident: field("VmHWM"), virtual_size: field("VmSize") }
+}
+
+/// Reads the process's memory figures; on other Unix systems only the peak is known
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn process_memory() -> ProcessMemory {
+    use nix::sys::resource::{getrusage, UsageWho};
+
+    // macOS reports the peak in bytes, the BSDs in kilobytes
+    let scale = if cfg!(any(target_os = "macos", target_os = "ios")) { 1 } else { 1024 };
+    let peak = getrusage(UsageWho::RUSAGE_SELF).ok().map(|usage| usage.max_rss() as u64 * scale);
+    ProcessMemory { peak_resident: peak, ..ProcessMemory::default() }
+}
+
+/// Reads the process's memory figures, none of which are known on this platform
+#[cfg(not(unix))]
+pub fn process_memory() -> ProcessMemory {
+    ProcessMemory::default()
+}
@@ -0,0 +1,599 @@
+//! Middleware wrapped around the request handler.
+//!
+//! Cross-cutting behavior (logging, metrics, authentication, rate limiting)
+//! lives in [`Middleware`] layers instead of the connection loop. Layers form an
+//! onion around the [`RequestHandler`]: each one sees the request on the way in,
+//! decides whether to pass it on by calling [`Next::run`], and can inspect or
+//! rewrite the response on the way out. A layer that does not call `next`
+//! answers the request itself.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use memchr::memchr;
+
+use crate::bans::Offence;
+use crate::config::VERBOSE;
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+use crate::error::Result;
+use crate::kv::wire;
+use crate::protocol::ConnectionMode;
+use crate::secrets::{self, Secret};
+use crate::telemetry::Metrics;
+
+/// A message from a client, with the connection it arrived on
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    /// The connection the message arrived on
+    pub connection: &'a ConnectionCtx<'a>,
+    /// Bytes of the message
+    pub message: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// The same request carrying a different message, for layers that rewrite it
+    pub fn with_message(self, message: &'a [u8]) -> Self {
+        Self { message, ..self }
+    }
+}
+
+/// A layer of request processing wrapped around the handler
+///
+/// Called from worker threads, concurrently for different connections.
+pub trait Middleware: Send + Sync {
+    /// Processes a request, calling `next.run` to pass it further in
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>);
+
+    /// A connection closed; forget any state kept for it
+    fn on_close(&self, _connection_id: u64) {}
+}
+
+/// The rest of the chain below a middleware layer
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn RequestHandler,
+}
+
+impl Next<'_> {
+    /// Passes the request to the next layer, or to the handler after the last one
+    pub fn run(self, request: &Request<'_>, response: &mut ResponseWriter) {
+        match self.middleware.split_first() {
+            Some((layer, middleware)) => layer.handle(request, response, Next { middleware, handler: self.handler }),
+            None => self.handler.on_message(request.connection, request.message, response),
+        }
+    }
+}
+
+/// Middleware layers and the handler they wrap, outermost layer first
+pub(crate) struct Pipeline {
+    middleware: Vec<Arc<dyn Middleware>>,
+    handler: Arc<dyn RequestHandler>,
+}
+
+impl Pipeline {
+    pub(crate) fn new(middleware: Vec<Arc<dyn Middleware>>, handler: Arc<dyn RequestHandler>) -> Self {
+        Self { middleware, handler }
+    }
+
+    /// Runs a request through every layer and the handler
+    pub(crate) fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter) {
+        Next { middleware: &self.middleware, handler: self.handler.as_ref() }.run(request, response)
+    }
+
+    /// Asks the handler where the complete messages in `buffered` end
+    pub(crate) fn frame(&self, buffered: &[u8]) -> Option<usize> {
+        self.handler.frame(buffered)
+    }
+
+    /// Asks the handler for the notice sent to a client closed by a graceful shutdown
+    pub(crate) fn shutting_down(&self, ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        self.handler.on_shutdown(ctx, response);
+    }
+
+    /// Tells every layer and the handler that a connection closed
+    pub(crate) fn closed(&self, connection_id: u64) {
+        for layer in &self.middleware {
+            layer.on_close(connection_id);
+        }
+        self.handler.on_close(connection_id);
+    }
+}
+
+/// Built-in layer counting messages and bytes in the server's metrics
+pub(crate) struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl Middleware for MetricsLayer {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        self.metrics.messages_received.add(1);
+        self.metrics.bytes_received.add(request.message.len() as u64);
+        next.run(request, response);
+        self.metrics.bytes_sent.add(response.len() as u64 + response.file_len());
+    }
+}
+
+/// Built-in layer refusing commands the command ACL does not allow the client to run
+///
+/// Handlers such as KV run every command in a message, so every command is
+/// checked, and the whole message is refused if any one of them is denied.
+/// Bodies announced with [`ConnectionCtx::expect_body`] are not commands and
+/// pass unchecked.
+pub(crate) struct CommandAclLayer;
+
+impl Middleware for CommandAclLayer {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        if !request.connection.checks_commands() || request.connection.in_body() {
+            return next.run(request, response);
+        }
+        // Lines that do not parse are left for the handler to report
+        let denied = wire::commands(request.message)
+            .flatten()
+            .filter_map(|args| args.into_iter().next())
+            .find(|command| !request.connection.may_run(command));
+        let Some(command) = denied else {
+            return next.run(request, response);
+        };
+        request.connection.metrics().commands_denied.fetch_add(1, Ordering::Relaxed);
+        response.write(format!("ERR {} is not permitted\n", Lossy(&command).to_string().to_uppercase()).as_bytes());
+    }
+}
+
+/// Built-in layer answering `MODE` and refusing messages that are not UTF-8 from connections in text mode
+///
+/// `MODE` is only recognised as the first line of a message; the rest of the
+/// message is passed on as usual, in the mode it asked for.
+pub(crate) struct ModeLayer;
+
+impl Middleware for ModeLayer {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        let connection = request.connection;
+        let (line, rest) = first_line(request.message);
+        let mut words = line.split(u8::is_ascii_whitespace).filter(|word| !word.is_empty());
+        let request = match (words.next(), words.next(), words.next()) {
+            (Some(command), mode, None) if command.eq_ignore_ascii_case(b"MODE") => {
+                match mode {
+                    None => response.write(format!("{}\n", connection.mode().name()).as_bytes()),
+                    Some(mode) if mode.eq_ignore_ascii_case(b"TEXT") => {
+                        connection.set_mode(ConnectionMode::Text);
+                        response.write(b"OK\n");
+                    }
+                    Some(mode) if mode.eq_ignore_ascii_case(b"BINARY") => {
+                        connection.set_mode(ConnectionMode::Binary);
+                        response.write(b"OK\n");
+                    }
+                    Some(_) => response.write(b"ERR unknown mode; use MODE TEXT or MODE BINARY\n"),
+                }
+                if rest.is_empty() {
+                    return;
+                }
+                request.with_message(rest)
+            }
+            _ => *request,
+        };
+        if connection.mode() == ConnectionMode::Text {
+            if let Err(e) = std::str::from_utf8(request.message) {
+                let position = e.valid_up_to();
+                let reason = match e.error_len() {
+                    Some(_) => format!("invalid UTF-8 at byte {} (0x{:02x})", position, request.message[position]),
+                    None => format!("message ends partway through a UTF-8 character at byte {}", position),
+                };
+                return response.write(format!("ERR {}; send MODE BINARY to pass raw bytes\n", reason).as_bytes());
+            }
+        }
+        next.run(&request, response);
+    }
+}
+
+/// Splits `message` after its first newline, into the first line and the rest
+fn first_line(message: &[u8]) -> (&[u8], &[u8]) {
+    match memchr(b'\n', message) {
+        Some(newline) => message.split_at(newline + 1),
+        None => (message, &[]),
+    }
+}
+
+/// Built-in layer printing each message that reaches the handler to stdout, from verbosity [`VERBOSE`]
+///
+/// Printing takes a lock on stdout and a write for every message, so quieter
+/// configurations skip it, and with it any UTF-8 decoding of the message.
+pub(crate) struct LoggingLayer;
+
+impl Middleware for LoggingLayer {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        if request.connection.config().verbosity >= VERBOSE {
+            let message = request.message.trim_ascii();
+            // Text mode has already checked the message is UTF-8; binary messages are escaped rather than mangled
+            match request.connection.mode() {
+                ConnectionMode::Text => println!("Received: {}", Lossy(message)),
+                ConnectionMode::Binary => println!("Received: {}", message.escape_ascii()),
+            }
+        }
+        next.run(request, response);
+    }
+}
+
+/// Displays bytes as UTF-8, with replacement characters for invalid sequences, without copying them
+struct Lossy<'a>(&'a [u8]);
+
+impl fmt::Display for Lossy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                f.write_char(char::REPLACEMENT_CHARACTER)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Identity of clients presenting the token given to [`TokenAuth::new`]
+pub const DEFAULT_IDENTITY: &str = "default";
+
+/// Failed `AUTH` attempts from one address, by default, before it is locked out
+pub const DEFAULT_LOCKOUT_FAILURES: u32 = 5;
+/// How long the first lockout lasts by default
+pub const DEFAULT_LOCKOUT: Duration = Duration::from_secs(1);
+/// Longest a lockout lasts by default, however many attempts have failed
+pub const DEFAULT_MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Addresses with failed attempts tracked before [`TokenAuth`] drops the ones it has forgiven
+const LOCKOUTS_BEFORE_PRUNING: usize = 4096;
+
+/// When [`TokenAuth`] stops checking tokens from an address that keeps getting them wrong
+///
+/// After `failures` failed attempts, the address is locked out for `initial`;
+/// each failure after that doubles the lockout, up to `max`. An address's
+/// failures are forgotten once it authenticates, or after `max` passes
+/// without another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Failed attempts before the first lockout; 0 never locks an address out
+    pub failures: u32,
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self { failures: DEFAULT_LOCKOUT_FAILURES, initial: DEFAULT_LOCKOUT, max: DEFAULT_MAX_LOCKOUT }
+    }
+}
+
+impl LockoutPolicy {
+    /// How long to lock an address out after its `failures`th failed attempt, if at all
+    fn lockout(&self, failures: u32) -> Option<Duration> {
+        if self.failures == 0 || failures < self.failures {
+            return None;
+        }
+        let doublings = (failures - self.failures).min(31);
+        Some(self.initial.saturating_mul(1 << doublings).min(self.max))
+    }
+}
+
+/// Failed attempts from one address
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Requires each connection to send `AUTH <token>` before anything else
+///
+/// Other messages on a connection that has not authenticated are answered with
+/// an error instead of reaching the handler. `AUTH` is read from the first line
+/// of a message; whatever follows it in the same message is passed on once the
+/// token is accepted. Each token belongs to a user,
+/// whose name becomes the connection's identity for the command ACL.
+///
+/// Tokens are compared in constant time. An address that keeps presenting
+/// wrong ones is locked out as its [`LockoutPolicy`] says: its `AUTH` attempts
+/// are refused without being checked until the lockout ends. Failures and
+/// lockouts are counted in the server's metrics and recorded in its log with
+/// [`ConnectionCtx::audit`], as are successful logins.
+pub struct TokenAuth {
+    /// User names and their tokens
+    users: Vec<(String, Secret)>,
+    authenticated: Mutex<HashSet<u64>>,
+    lockout: LockoutPolicy,
+    failures: Mutex<HashMap<Option<IpAddr>, Failures>>,
+}
+
+impl TokenAuth {
+    /// Accepts connections that present `token`, as the user [`DEFAULT_IDENTITY`]
+    pub fn new(token: impl Into<Secret>) -> Self {
+        Self::users(Vec::new()).user(DEFAULT_IDENTITY, token)
+    }
+
+    /// Accepts connections that present any of the users' tokens, as that user
+    pub fn users(users: Vec<(String, Secret)>) -> Self {
+        Self {
+            users,
+            authenticated: Mutex::new(HashSet::new()),
+            lockout: LockoutPolicy::default(),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Locks out addresses that fail to authenticate as `policy` says, instead of [`LockoutPolicy::default`]
+    pub fn lockout(mut self, policy: LockoutPolicy) -> Self {
+        self.lockout = policy;
+        self
+    }
+
+    /// Also accepts connections that present `token`, as `name`
+    pub fn user(mut self, name: impl Into<String>, token: impl Into<Secret>) -> Self {
+        self.users.push((name.into(), token.into()));
+        self
+    }
+
+    /// Reads users from a file with one `<name> <token>` pair per line
+    ///
+    /// Blank lines and lines starting with `#` are skipped.
+    pub fn load_users(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = secrets::read_file(path)?;
+        let mut users = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, token] => users.push((name.to_string(), Secret::new(token))),
+                _ => return Err(secrets::invalid_file(path, &format!("line {} is not `<name> <token>`", number + 1))),
+            }
+        }
+        if users.is_empty() {
+            return Err(secrets::invalid_file(path, "the file has no users"));
+        }
+        Ok(Self::users(users))
+    }
+
+    /// The user whose token is `token`, checking every user's so the time taken does not say which matched
+    fn user_with_token(&self, token: &str) -> Option<&str> {
+        let mut user = None;
+        for (name, secret) in &self.users {
+            if secret.matches(token) && user.is_none() {
+                user = Some(name.as_str());
+            }
+        }
+        user
+    }
+
+    /// How much longer `ip` is locked out, if it is
+    fn locked_out(&self, ip: Option<IpAddr>) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let until = failures.get(&ip)?.locked_until?;
+        until.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Counts a failed attempt from `ip`, returning the lockout it earned, if any
+    fn record_failure(&self, ip: Option<IpAddr>) -> (u32, Option<Duration>) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= LOCKOUTS_BEFORE_PRUNING && !failures.contains_key(&ip) {
+            failures.retain(|_, failures| now.duration_since(failures.last) < self.lockout.max);
+        }
+        let entry = failures.entry(ip).or_insert(Failures { count: 0, last: now, locked_until: None });
+        if now.duration_since(entry.last) >= self.lockout.max {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        let lockout = self.lockout.lockout(entry.count);
+        entry.locked_until = lockout.map(|lockout| now + lockout);
+        (entry.count, lockout)
+    }
+}
+
+impl Middleware for TokenAuth {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        let connection = request.connection;
+        if self.authenticated.lock().unwrap().contains(&connection.id()) {
+            return next.run(request, response);
+        }
+        // Commands pipelined behind `AUTH` in the same message run once it succeeds
+        let (line, rest) = first_line(request.message);
+        let line = String::from_utf8_lossy(line);
+        let Some(token) = line.trim().strip_prefix("AUTH ") else {
+            return response.write(b"ERR authentication required\n");
+        };
+        let ip = connection.peer_addr().map(|addr| addr.ip());
+        // Refuse without checking, so that guesses made during a lockout learn nothing
+        if let Some(remaining) = self.locked_out(ip) {
+            let seconds = remaining.as_secs_f64().ceil();
+            return response.write(format!("ERR too many failed attempts; try again in {}s\n", seconds).as_bytes());
+        }
+        match self.user_with_token(token.trim()) {
+            Some(name) => {
+                self.failures.lock().unwrap().remove(&ip);
+                self.authenticated.lock().unwrap().insert(connection.id());
+                connection.set_identity(name);
+                let id = connection.id();
+                connection.audit(&format!("connection #{} from {} authenticated as {}", id, connection.peer(), name));
+                response.write(b"OK\n");
+                if !rest.is_empty() {
+                    next.run(&request.with_message(rest), response);
+                }
+            }
+            None => {
+                connection.metrics().auth_failures.fetch_add(1, Ordering::Relaxed);
+                connection.report(Offence::AuthFailure);
+                let (failures, lockout) = self.record_failure(ip);
+                connection.audit(&format!(
+                    "connection #{} from {} failed to authenticate ({} in a row from its address)",
+                    connection.id(),
+                    connection.peer(),
+                    failures
+                ));
+                if let Some(lockout) = lockout {
+                    connection.metrics().auth_lockouts.fetch_add(1, Ordering::Relaxed);
+                    let address = ip.map_or_else(|| connection.peer().to_string(), |ip| ip.to_string());
+                    let event = format!("locked {} out of AUTH for {:?} after {} failures", address, lockout, failures);
+                    connection.audit(&event);
+                }
+                response.write(b"ERR invalid token\n");
+            }
+        }
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        self.authenticated.lock().unwrap().remove(&connection_id);
+    }
+}
+
+/// Limits how fast each connection may send messages, using a token bucket
+///
+/// A connection may send `burst` messages at once and `per_second` messages per
+/// second after that; messages over the limit are answered with an error.
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<u64, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn full(burst: f64) -> Self {
+        Self { tokens: burst, refilled: Instant::now() }
+    }
+
+    /// Refills the bucket for the time since it was last used, then takes a token if there is one
+    fn take(&mut self, per_second: f64, burst: f64) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * per_second;
+        self.tokens = (self.tokens + refill).min(burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has refilled completely, making it no different from a new one
+    fn is_full(&self, per_second: f64, burst: f64) -> bool {
+        self.tokens + self.refilled.elapsed().as_secs_f64() * per_second >= burst
+    }
+}
+
+impl RateLimit {
+    /// Allows `per_second` messages per second per connection, in bursts of up to `burst`
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst: f64::from(burst.max(1)), buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn allow(&self, connection_id: u64) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.entry(connection_id).or_insert_with(|| Bucket::full(self.burst)).take(self.per_second, self.burst)
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        if self.allow(request.connection.id()) {
+            next.run(request, response);
+        } else {
+            request.connection.report(Offence::RateLimited);
+            response.write(b"ERR rate limit exceeded\n");
+        }
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        self.buckets.lock().unwrap().remove(&connection_id);
+    }
+}
+
+/// Messages refused in a row on one connection, by default, before [`IpRateLimit`] closes it
+pub const DEFAULT_THROTTLE_CLOSE_AFTER: u32 = 10;
+
+/// Client addresses tracked before [`IpRateLimit`] drops the ones whose buckets have refilled
+const IP_BUCKETS_BEFORE_PRUNING: usize = 4096;
+
+/// Limits how fast each client address may send messages, across all its connections
+///
+/// Like [`RateLimit`], but every connection from the same IP address draws on
+/// one token bucket, so opening more connections does not buy a client more
+/// throughput. Messages over the limit are answered with an error and counted
+/// in the server's `requests_throttled` metric; a connection that keeps
+/// sending after being refused [`close_after`](Self::close_after) times in a
+/// row is closed.
+pub struct IpRateLimit {
+    per_second: f64,
+    burst: f64,
+    close_after: u32,
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+    /// Messages refused in a row, by connection
+    strikes: Mutex<HashMap<u64, u32>>,
+}
+
+impl IpRateLimit {
+    /// Allows `per_second` messages per second per client address, in bursts of up to `burst`
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self {
+            per_second,
+            burst: f64::from(burst.max(1)),
+            close_after: DEFAULT_THROTTLE_CLOSE_AFTER,
+            buckets: Mutex::new(HashMap::new()),
+            strikes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Closes a connection after `refusals` messages in a row over the limit; 0 never closes it
+    pub fn close_after(mut self, refusals: u32) -> Self {
+        self.close_after = refusals;
+        self
+    }
+
+    fn allow(&self, ip: Option<IpAddr>) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= IP_BUCKETS_BEFORE_PRUNING && !buckets.contains_key(&ip) {
+            buckets.retain(|_, bucket| !bucket.is_full(self.per_second, self.burst));
+        }
+        buckets.entry(ip).or_insert_with(|| Bucket::full(self.burst)).take(self.per_second, self.burst)
+    }
+}
+
+impl Middleware for IpRateLimit {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        let connection = request.connection;
+        if self.allow(connection.peer_addr().map(|addr| addr.ip())) {
+            self.strikes.lock().unwrap().remove(&connection.id());
+            return next.run(request, response);
+        }
+        connection.metrics().requests_throttled.fetch_add(1, Ordering::Relaxed);
+        connection.report(Offence::RateLimited);
+        let mut strikes = self.strikes.lock().unwrap();
+        let strikes = strikes.entry(connection.id()).or_insert(0);
+        *strikes += 1;
+        if self.close_after > 0 && *strikes >= self.close_after {
+            log::warn!("Closing connection from {} after {} messages over the rate limit", connection.peer(), strikes);
+            response.write(b"ERR rate limit exceeded; closing connection\n");
+            response.close();
+        } else {
+            response.write(b"ERR rate limit exceeded\n");
+        }
+    }
+
+    fn on_close(&self, connection_id: u64) {
+        self.strikes.lock().unwrap().remove(&connection_id);
+    }
+}
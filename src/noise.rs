@@ -0,0 +1,393 @@
+//! Noise_XX encryption for client connections, where TLS is not an option.
+//!
+//! Both ends hold a static X25519 key pair, such as those `rustbucket keygen
+//! noise` writes. The server reads its private key from a file and the clients
+//! it accepts from an authorized keys file, one `<public key> <name>` line per
+//! client with the key in hex; blank lines and lines starting with `#` are
+//! skipped. Each connection starts with a `Noise_XX_25519_ChaChaPoly_BLAKE2s`
+//! handshake in which each side proves it holds its private key and learns the
+//! other's public key. The client checks that the server's is the one it was
+//! configured with, and the server disconnects clients whose key it does not
+//! list; an accepted client's name becomes the connection's identity for the
+//! command ACL. After the handshake, every message in either direction travels
+//! as Noise transport messages framed by a two-byte big-endian length.
+//!
+//! ```no_run
+//! use std::net::TcpStream;
+//! use rustbucket::noise::{NoiseKey, NoiseSettings, NoiseStream};
+//! use rustbucket::Server;
+//!
+//! // The server
+//! let settings = NoiseSettings::new(NoiseKey::from_file("server.noise")?).load_authorized_keys("clients.noise")?;
+//! let handle = Server::builder().noise(settings).build()?.start()?;
+//!
+//! // A client
+//! let server_key = rustbucket::noise::parse_public_key("9c41...").unwrap();
+//! let socket = TcpStream::connect("127.0.0.1:8080")?;
+//! let stream = NoiseStream::connect(socket, &NoiseKey::from_file("client.noise")?, &server_key)?;
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::fmt;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use rand::RngCore;
+use snow::{Builder, HandshakeState, TransportState};
+use zeroize::Zeroizing;
+
+use crate::error::{Result, RustbucketError};
+use crate::protocol::Transport;
+use crate::secrets;
+
+/// The Noise protocol both ends speak
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Longest the handshake may take before the connection is given up on
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes in a public or private key
+pub const KEY_LEN: usize = 32;
+
+/// Longest Noise message, and so the longest frame
+const MAX_MESSAGE_LEN: usize = 65535;
+/// Bytes the cipher adds to every transport message
+const TAG_LEN: usize = 16;
+/// Ciphertext read from the socket at once
+const NOISE_READ_BUFFER_SIZE: usize = 4096;
+
+/// A static X25519 public key
+pub type PublicKey = [u8; KEY_LEN];
+
+/// A static X25519 key pair
+#[derive(Clone)]
+pub struct NoiseKey {
+    private: Zeroizing<[u8; KEY_LEN]>,
+    public: PublicKey,
+}
+
+impl NoiseKey {
+    /// A new random key pair
+    pub fn generate() -> Self {
+        let mut private = Zeroizing::new([0; KEY_LEN]);
+        rand::rngs::OsRng.fill_bytes(&mut *private);
+        Self::from_private(private)
+    }
+
+    fn from_private(private: Zeroizing<[u8; KEY_LEN]>) -> Self {
+        let public = MontgomeryPoint::mul_base_clamped(*private).to_bytes();
+        Self { private, public }
+    }
+
+    /// Reads the private key, in hex, from the file at `path`
+    ///
+    /// Logs a warning if users other than the owner may read the file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = secrets::read_file(path)?;
+        let mut private = Zeroizing::new([0; KEY_LEN]);
+        decode_hex(contents.trim(), &mut private).map_err(|reason| secrets::invalid_file(path, &reason))?;
+        Ok(Self::from_private(private))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    /// The private key in hex, as [`from_file`](Self::from_file) reads it
+    pub fn private_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(to_hex(&*self.private))
+    }
+}
+
+impl fmt::Debug for NoiseKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseKey").field("public", &to_hex(&self.public)).finish_non_exhaustive()
+    }
+}
+
+/// Hex encoding of `bytes`, as keys are written in files
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses a public key written in hex
+pub fn parse_public_key(hex: &str) -> std::result::Result<PublicKey, String> {
+    let mut key = [0; KEY_LEN];
+    decode_hex(hex, &mut key)?;
+    Ok(key)
+}
+
+fn decode_hex(hex: &str, out: &mut [u8; KEY_LEN]) -> std::result::Result<(), String> {
+    if hex.len() != KEY_LEN * 2 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(format!("expected a key of {} hex digits", KEY_LEN * 2));
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).expect("checked to be ASCII");
+        *byte = u8::from_str_radix(pair, 16).expect("checked to be hex digits");
+    }
+    Ok(())
+}
+
+/// The server's key pair and the clients it accepts
+#[derive(Debug, Clone)]
+pub struct NoiseSettings {
+    key: NoiseKey,
+    /// Names and public keys of the clients allowed to connect
+    authorized: Vec<(String, PublicKey)>,
+}
+
+impl NoiseSettings {
+    /// Serves with `key`, accepting no clients until some are authorized
+    pub fn new(key: NoiseKey) -> Self {
+        Self { key, authorized: Vec::new() }
+    }
+
+    /// Accepts the client holding the private key for `public`, as `name`
+    pub fn authorize(mut self, name: impl Into<String>, public: PublicKey) -> Self {
+        self.authorized.push((name.into(), public));
+        self
+    }
+
+    /// Accepts the clients listed in the authorized keys file at `path`
+    pub fn load_authorized_keys(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|source| RustbucketError::ConfigFile { path: path.to_path_buf(), source })?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| {
+                RustbucketError::InvalidConfig(format!("{} line {}: {}", path.display(), number + 1, reason))
+            };
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [key, name] => self.authorized.push((name.to_string(), parse_public_key(key).map_err(invalid)?)),
+                _ => return Err(invalid("expected `<public key> <name>`".to_string())),
+            }
+        }
+        Ok(self)
+    }
+
+    /// The server's public key, for configuring clients
+    pub fn public_key(&self) -> PublicKey {
+        self.key.public
+    }
+
+    /// Names and public keys of the clients allowed to connect
+    pub fn authorized(&self) -> &[(String, PublicKey)] {
+        &self.authorized
+    }
+
+    /// Checks that the settings let anyone connect at all
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.authorized.is_empty() {
+            return Err(RustbucketError::InvalidConfig("Noise: no client keys are authorized".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Noise state of one connection, shared by its worker and its outbox
+pub(crate) type NoiseSession = Arc<Mutex<TransportState>>;
+
+/// A connection encrypted with Noise, from either end
+///
+/// Reads should all go through one stream; streams from
+/// [`try_clone`](Self::try_clone) are for writing from other threads.
+pub struct NoiseStream {
+    socket: TcpStream,
+    session: NoiseSession,
+    /// Ciphertext read but not yet decrypted
+    incoming: Vec<u8>,
+    /// Plaintext decrypted but not yet read, from `consumed` on
+    plaintext: Vec<u8>,
+    consumed: usize,
+}
+
+impl NoiseStream {
+    fn new(socket: TcpStream, session: NoiseSession) -> Self {
+        Self { socket, session, incoming: Vec::new(), plaintext: Vec::new(), consumed: 0 }
+    }
+
+    /// Performs the client side of the handshake on `socket`, requiring the server to hold `server_key`
+    pub fn connect(socket: TcpStream, key: &NoiseKey, server_key: &PublicKey) -> io::Result<Self> {
+        let mut handshake = builder(key).build_initiator().map_err(noise_error)?;
+        let session = with_handshake_timeout(&socket, |mut socket| {
+            // -> e
+            write_handshake(&mut handshake, &mut socket)?;
+            // <- e, ee, s, es
+            read_handshake(&mut handshake, &mut socket)?;
+            if handshake.get_remote_static() != Some(&server_key[..]) {
+                let reason = "the server's Noise key is not the expected one";
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+            }
+            // -> s, se
+            write_handshake(&mut handshake, &mut socket)?;
+            handshake.into_transport_mode().map_err(noise_error)
+        })?;
+        Ok(Self::new(socket, Arc::new(Mutex::new(session))))
+    }
+
+    /// Another stream for writing to the same connection
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self::new(self.socket.try_clone()?, Arc::clone(&self.session)))
+    }
+
+    /// The socket underneath
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// The other end's static public key
+    pub fn remote_key(&self) -> PublicKey {
+        let session = self.session.lock().unwrap();
+        session.get_remote_static().and_then(|key| key.try_into().ok()).expect("XX transmits both static keys")
+    }
+
+    pub(crate) fn session(&self) -> &NoiseSession {
+        &self.session
+    }
+
+    /// A writer for a connection whose handshake another stream completed
+    pub(crate) fn writer(socket: TcpStream, session: NoiseSession) -> Self {
+        Self::new(socket, session)
+    }
+
+    /// Decrypts the first frame in `incoming` into `plaintext`, if a whole one has arrived
+    fn decrypt_frame(&mut self) -> io::Result<bool> {
+        let Some(header) = self.incoming.get(..2) else {
+            return Ok(false);
+        };
+        let len = usize::from(u16::from_be_bytes([header[0], header[1]]));
+        let Some(frame) = self.incoming.get(2..2 + len) else {
+            return Ok(false);
+        };
+        self.plaintext.resize(len, 0);
+        let n = self.session.lock().unwrap().read_message(frame, &mut self.plaintext).map_err(noise_error)?;
+        self.plaintext.truncate(n);
+        self.consumed = 0;
+        self.incoming.drain(..2 + len);
+        Ok(true)
+    }
+}
+
+/// Performs the server side of the handshake on `socket`, returning the stream and the client's name
+pub(crate) fn accept(socket: TcpStream, settings: &NoiseSettings) -> io::Result<(NoiseStream, &str)> {
+    let mut handshake = builder(&settings.key).build_responder().map_err(noise_error)?;
+    let (session, name) = with_handshake_timeout(&socket, |mut socket| {
+        // -> e
+        read_handshake(&mut handshake, &mut socket)?;
+        // <- e, ee, s, es
+        write_handshake(&mut handshake, &mut socket)?;
+        // -> s, se
+        read_handshake(&mut handshake, &mut socket)?;
+        let client_key = handshake.get_remote_static().unwrap_or_default();
+        let Some((name, _)) = settings.authorized.iter().find(|(_, key)| key[..] == *client_key) else {
+            let message = format!("client Noise key {} is not authorized", to_hex(client_key));
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        };
+        Ok((handshake.into_transport_mode().map_err(noise_error)?, name.as_str()))
+    })?;
+    Ok((NoiseStream::new(socket, Arc::new(Mutex::new(session))), name))
+}
+
+fn builder(key: &NoiseKey) -> Builder<'_> {
+    Builder::new(NOISE_PARAMS.parse().expect("valid Noise parameters")).local_private_key(&*key.private)
+}
+
+/// Runs a handshake on `socket` with [`HANDSHAKE_TIMEOUT`] as its read timeout
+fn with_handshake_timeout<T>(socket: &TcpStream, handshake: impl FnOnce(&TcpStream) -> io::Result<T>) -> io::Result<T> {
+    let previous = socket.read_timeout()?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let result = handshake(socket);
+    socket.set_read_timeout(previous)?;
+    result
+}
+
+fn write_handshake(handshake: &mut HandshakeState, socket: &mut &TcpStream) -> io::Result<()> {
+    let mut frame = vec![0; 2 + MAX_MESSAGE_LEN];
+    let len = handshake.write_message(&[], &mut frame[2..]).map_err(noise_error)?;
+    frame[..2].copy_from_slice(&(len as u16).to_be_bytes());
+    socket.write_all(&frame[..2 + len])
+}
+
+fn read_handshake(handshake: &mut HandshakeState, socket: &mut &TcpStream) -> io::Result<()> {
+    let closed = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(e.kind(), "connection closed during the Noise handshake"),
+        _ => e,
+    };
+    let mut header = [0; 2];
+    socket.read_exact(&mut header).map_err(closed)?;
+    let mut message = vec![0; usize::from(u16::from_be_bytes(header))];
+    socket.read_exact(&mut message).map_err(closed)?;
+    let mut payload = vec![0; MAX_MESSAGE_LEN];
+    handshake.read_message(&message, &mut payload).map_err(noise_error)?;
+    Ok(())
+}
+
+fn noise_error(error: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Noise: {}", error))
+}
+
+impl Read for NoiseStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.consumed < self.plaintext.len() {
+                let n = buf.len().min(self.plaintext.len() - self.consumed);
+                buf[..n].copy_from_slice(&self.plaintext[self.consumed..self.consumed + n]);
+                self.consumed += n;
+                return Ok(n);
+            }
+            if self.decrypt_frame()? {
+                continue;
+            }
+            // A timeout here leaves a partial frame in `incoming` for the next call
+            let mut chunk = [0; NOISE_READ_BUFFER_SIZE];
+            match self.socket.read(&mut chunk)? {
+                0 if self.incoming.is_empty() => return Ok(0),
+                0 => {
+                    let reason = "connection closed partway through a frame";
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, reason));
+                }
+                n => self.incoming.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl Write for NoiseStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut frame = vec![0; 2 + MAX_MESSAGE_LEN];
+        for chunk in buf.chunks(MAX_MESSAGE_LEN - TAG_LEN) {
+            // Frames must go out in the order they were encrypted in, so write while holding the session
+            let mut session = self.session.lock().unwrap();
+            let len = session.write_message(chunk, &mut frame[2..]).map_err(noise_error)?;
+            frame[..2].copy_from_slice(&(len as u16).to_be_bytes());
+            self.socket.write_all(&frame[..2 + len])?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Encrypts the slices together, so that small ones share frames
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut plaintext = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+        for buf in bufs {
+            plaintext.extend_from_slice(buf);
+        }
+        self.write(&plaintext)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for NoiseStream {
+    fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+}
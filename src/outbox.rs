@@ -0,0 +1,184 @@
+//! Messages sent to a client outside of any reply.
+//!
+//! A reply is written when the handler returns, but some protocols also push
+//! data the client did not just ask for, such as pub/sub messages.
+//! [`ConnectionCtx::outbox`](crate::ConnectionCtx::outbox) gives a handler an
+//! [`Outbox`] it can keep and send through from any thread. Each outbox is a
+//! bounded queue drained by a writer thread of its own, so senders never wait
+//! on a slow client: a client that falls so far behind that its queue fills up
+//! is disconnected instead. Queued bytes count against the server's
+//! [`MemoryBudget`] until they are written. Messages that piled up while the
+//! writer was busy go out together, in a single vectored write.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{IoSlice, Write};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+
+use crate::connections::ConnectionEntry;
+use crate::memory::{MemoryBudget, Pool};
+use crate::protocol::write_all_vectored;
+
+/// Bytes queued for a client before it counts as too slow and is disconnected
+pub const OUTBOX_CAPACITY: usize = 1024 * 1024;
+/// Queued messages written to the client in one go at most
+const WRITE_BATCH: usize = 64;
+
+/// Why a message could not be queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The client fell too far behind; it is being disconnected
+    Full,
+    /// The connection has closed
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full => write!(f, "the client's outbox is full"),
+            SendError::Closed => write!(f, "the connection is closed"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Queue of messages for one connection, written in the order they were sent
+///
+/// Clones share the same queue.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    connection_id: u64,
+    /// Weak because the connection holds its outbox
+    connection: Weak<ConnectionEntry>,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    budget: Arc<MemoryBudget>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<Vec<u8>>,
+    bytes: usize,
+    closed: bool,
+}
+
+impl Outbox {
+    /// Starts the writer thread for `connection`, writing to its socket
+    pub(crate) fn start(connection: Arc<ConnectionEntry>, budget: Arc<MemoryBudget>) -> Self {
+        let outbox = Self {
+            shared: Arc::new(Shared {
+                connection_id: connection.id,
+                connection: Arc::downgrade(&connection),
+                queue: Mutex::default(),
+                ready: Condvar::new(),
+                budget,
+            }),
+        };
+        match connection.writer() {
+            Ok(writer) => {
+                let shared = Arc::clone(&outbox.shared);
+                thread::spawn(move || write_queued(&shared, &connection, writer));
+            }
+            Err(_) => outbox.close(),
+        }
+        outbox
+    }
+
+    /// Id of the connection the outbox writes to
+    pub fn connection_id(&self) -> u64 {
+        self.shared.connection_id
+    }
+
+    /// Queues `message` to be written to the client
+    ///
+    /// Never blocks. If the message would take the queue past
+    /// [`OUTBOX_CAPACITY`], the client is disconnected and the message dropped.
+    pub fn send(&self, message: impl Into<Vec<u8>>) -> Result<(), SendError> {
+        let message = message.into();
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(SendError::Closed);
+        }
+        if queue.bytes + message.len() > OUTBOX_CAPACITY {
+            queue.closed = true;
+            self.shared.drop_queued(&mut queue);
+            self.shared.ready.notify_all();
+            drop(queue);
+            // Shutting the socket down also unblocks a writer stuck on the full socket buffer
+            if let Some(connection) = self.shared.connection.upgrade() {
+                log::warn!("Disconnecting {}: it is not reading messages fast enough", connection.peer);
+                connection.close();
+            }
+            return Err(SendError::Full);
+        }
+        queue.bytes += message.len();
+        self.shared.budget.reserve(Pool::Buffers, message.len());
+        queue.messages.push_back(message);
+        self.shared.ready.notify_all();
+        Ok(())
+    }
+
+    /// Whether messages can no longer be sent
+    pub fn is_closed(&self) -> bool {
+        self.shared.queue.lock().unwrap().closed
+    }
+
+    /// Stops the writer thread, dropping anything still queued
+    pub(crate) fn close(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.closed = true;
+        self.shared.drop_queued(&mut queue);
+        self.shared.ready.notify_all();
+    }
+}
+
+impl Shared {
+    /// Discards the queued messages, releasing their share of the budget
+    fn drop_queued(&self, queue: &mut Queue) {
+        self.budget.release(Pool::Buffers, queue.bytes);
+        queue.messages.clear();
+        queue.bytes = 0;
+    }
+}
+
+/// Writes queued messages to `writer` until the outbox closes or a write fails
+fn write_queued(shared: &Shared, connection: &ConnectionEntry, mut writer: Box<dyn Write + Send>) {
+    let mut batch = Vec::with_capacity(WRITE_BATCH);
+    loop {
+        let bytes = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.messages.is_empty() && !queue.closed {
+                queue = shared.ready.wait(queue).unwrap();
+            }
+            if queue.closed {
+                shared.drop_queued(&mut queue);
+                return;
+            }
+            let count = queue.messages.len().min(WRITE_BATCH);
+            batch.extend(queue.messages.drain(..count));
+            let bytes = batch.iter().map(Vec::len).sum();
+            queue.bytes -= bytes;
+            shared.budget.release(Pool::Buffers, bytes);
+            bytes
+        };
+
+        let mut slices: Vec<IoSlice<'_>> = batch.iter().map(|message| IoSlice::new(message)).collect();
+        let _writing = connection.lock_writes();
+        if write_all_vectored(&mut writer, &mut slices).is_err() {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.closed = true;
+            shared.drop_queued(&mut queue);
+            return;
+        }
+        connection.record_sent(bytes);
+        batch.clear();
+    }
+}
@@ -0,0 +1,109 @@
+//! Where a server keeps its files.
+//!
+//! Every file a server reads or writes is named by a [`Paths`] handed to it
+//! rather than by a global constant, so several servers can run in one
+//! process, each with its own directory. The defaults are the names the
+//! `rustbucket` CLI has always used, relative to the working directory.
+
+use std::path::{Path, PathBuf};
+
+/// Default log file name
+const LOG_FILE: &str = "http.log";
+/// Default name of the ring buffer a [`RingSink`](crate::logging::RingSink) maps
+const LOG_RING_FILE: &str = "log.ring";
+/// Default config file name
+const CONFIG_FILE: &str = "config.dat";
+/// Default pidfile name
+const PID_FILE: &str = "rustbucket.pid";
+/// Default KV snapshot name
+const SNAPSHOT_FILE: &str = "kv.snapshot";
+/// Default KV write-ahead log name
+const WAL_FILE: &str = "kv.wal";
+/// Default KV blob store name
+const BLOB_FILE: &str = "kv.blobs";
+/// Default message queue journal name
+const QUEUE_FILE: &str = "queue.journal";
+/// Default directory for files uploaded in files mode
+const STORAGE_DIR: &str = "files";
+/// Default directory for request scripts
+const SCRIPTS_DIR: &str = "scripts";
+/// Default IP allow and deny list
+const ACCESS_FILE: &str = "access.rules";
+/// Default per-command allow and deny list
+const ACL_FILE: &str = "commands.acl";
+/// Rotated log files kept by default
+const MAX_LOG_FILES: u32 = 5;
+
+/// The files belonging to one server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    /// Log the server appends lifecycle and connection events to
+    pub log_file: PathBuf,
+    /// Memory-mapped ring buffer lines pass through on their way to `log_file`, when logging through one
+    pub log_ring_file: PathBuf,
+    /// Memory-mapped configuration shared with `update-config`
+    pub config_file: PathBuf,
+    /// Pidfile recording the running server's process id
+    pub pid_file: PathBuf,
+    /// Snapshot the KV store is saved to and loaded from
+    pub snapshot_file: PathBuf,
+    /// Write-ahead log of KV changes made since the last snapshot
+    pub wal_file: PathBuf,
+    /// Memory-mapped scratch file large KV values are kept in
+    pub blob_file: PathBuf,
+    /// Journal of the messages in queue mode
+    pub queue_file: PathBuf,
+    /// Directory files uploaded with `PUT` are stored in
+    pub storage_dir: PathBuf,
+    /// Directory the Lua request scripts are loaded from
+    pub scripts_dir: PathBuf,
+    /// Allow and deny rules for client addresses, read at startup and on reload
+    pub access_file: PathBuf,
+    /// Allow and deny rules for protocol commands, read at startup and on reload
+    pub acl_file: PathBuf,
+    /// Numbered backups of `log_file` kept when rotating
+    pub max_log_files: u32,
+}
+
+impl Paths {
+    /// The default file names inside `dir`
+    pub fn in_dir(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        Self {
+            log_file: dir.join(LOG_FILE),
+            log_ring_file: dir.join(LOG_RING_FILE),
+            config_file: dir.join(CONFIG_FILE),
+            pid_file: dir.join(PID_FILE),
+            snapshot_file: dir.join(SNAPSHOT_FILE),
+            wal_file: dir.join(WAL_FILE),
+            blob_file: dir.join(BLOB_FILE),
+            queue_file: dir.join(QUEUE_FILE),
+            storage_dir: dir.join(STORAGE_DIR),
+            scripts_dir: dir.join(SCRIPTS_DIR),
+            access_file: dir.join(ACCESS_FILE),
+            acl_file: dir.join(ACL_FILE),
+            max_log_files: MAX_LOG_FILES,
+        }
+    }
+}
+
+impl Default for Paths {
+    /// The default file names in the working directory
+    fn default() -> Self {
+        Self {
+            log_file: PathBuf::from(LOG_FILE),
+            log_ring_file: PathBuf::from(LOG_RING_FILE),
+            config_file: PathBuf::from(CONFIG_FILE),
+            pid_file: PathBuf::from(PID_FILE),
+            snapshot_file: PathBuf::from(SNAPSHOT_FILE),
+            wal_file: PathBuf::from(WAL_FILE),
+            blob_file: PathBuf::from(BLOB_FILE),
+            queue_file: PathBuf::from(QUEUE_FILE),
+            storage_dir: PathBuf::from(STORAGE_DIR),
+            scripts_dir: PathBuf::from(SCRIPTS_DIR),
+            access_file: PathBuf::from(ACCESS_FILE),
+            acl_file: PathBuf::from(ACL_FILE),
+            max_log_files: MAX_LOG_FILES,
+        }
+    }
+}
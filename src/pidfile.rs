@@ -0,0 +1,85 @@
+//! Pidfile recording the running server's process id.
+//!
+//! The server writes its pid on startup and removes the file when it exits, so
+//! control commands like `stop` can find it without operators hunting for the
+//! process. A pidfile left behind by a crashed server is detected and replaced.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use nix::errno::Errno;
+#[cfg(unix)]
+use nix::sys::signal::{kill, Signal};
+#[cfg(unix)]
+use nix::unistd::Pid;
+
+use crate::error::{Result, RustbucketError};
+
+/// A pidfile owned by this process, removed again on drop
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Writes this process's pid to `path`, refusing if another live server owns it
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(pid) = read_pid(path)? {
+            if is_running(pid) {
+                return Err(RustbucketError::AlreadyRunning { pid, path: path.to_path_buf() });
+            }
+        }
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads the pid stored in `path`, or `None` if there is no pidfile
+pub fn read_pid(path: impl AsRef<Path>) -> Result<Option<i32>> {
+    let path = path.as_ref();
+    match fs::read_to_string(path) {
+        Ok(contents) => contents.trim().parse().map(Some).map_err(|_| {
+            RustbucketError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{} does not contain a pid", path.display())))
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a process with `pid` exists
+#[cfg(unix)]
+pub fn is_running(pid: i32) -> bool {
+    // Signal 0 only checks for existence; EPERM means it exists but belongs to someone else
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// Whether a process with `pid` exists
+///
+/// Outside Unix this cannot be checked, so the process is taken to be running;
+/// a pidfile left behind by a crashed server has to be removed by hand.
+#[cfg(not(unix))]
+pub fn is_running(_pid: i32) -> bool {
+    true
+}
+
+/// Sends `signal` to `pid`
+#[cfg(unix)]
+pub fn signal(pid: i32, signal: Signal) -> Result<()> {
+    kill(Pid::from_raw(pid), signal).map_err(|e| io::Error::from(e).into())
+}
+
+/// Removes a pidfile whose process is gone
+pub fn remove_stale(path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
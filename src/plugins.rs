@@ -0,0 +1,239 @@
+//! Request handlers loaded from shared libraries at runtime.
+//!
+//! A plugin is a shared library (`.so` on Linux, `.dylib` on macOS) exporting
+//! `rustbucket_plugin_init`, declared with the structs it fills in by
+//! `include/rustbucket_plugin.h`. Because the interface is plain C, plugins can
+//! be written in any language that builds a shared library, and a server picks
+//! up new protocol behavior by dropping one into its plugins directory instead
+//! of being recompiled.
+//!
+//! Plugins run inside the server process with its privileges; only load
+//! libraries you would link into the server yourself.
+
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+use libloading::Library;
+
+use crate::error::{Result, RustbucketError};
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+
+/// Version of the plugin interface this server implements
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Symbol every plugin exports
+const INIT_SYMBOL: &[u8] = b"rustbucket_plugin_init\0";
+
+/// Appends `len` bytes at `data` to the reply being built
+pub type WriteFn = unsafe extern "C" fn(response: *mut c_void, data: *const u8, len: usize);
+
+/// One message, as passed to a plugin's `on_message`
+#[repr(C)]
+#[derive(Debug)]
+pub struct PluginMessage {
+    /// `sizeof(rustbucket_message_t)`
+    pub size: u32,
+    /// Server-assigned id of the connection
+    pub connection_id: u64,
+    /// Address of the client as it appears in the log, NUL-terminated
+    pub peer: *const c_char,
+    /// The bytes the client sent
+    pub data: *const u8,
+    pub len: usize,
+}
+
+/// What a plugin fills in from `rustbucket_plugin_init`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PluginVtable {
+    /// `sizeof(rustbucket_plugin_t)` as compiled by the server
+    pub size: u32,
+    /// [`PLUGIN_ABI_VERSION`] as compiled by the plugin
+    pub abi_version: u32,
+    /// Name the plugin is selected by, NUL-terminated and valid until `destroy`
+    pub name: *const c_char,
+    /// Passed back to `on_message` and `destroy`
+    pub state: *mut c_void,
+    /// Handles one message, calling `write` with `response` for each piece of the reply
+    pub on_message: Option<unsafe extern "C" fn(state: *mut c_void, message: *const PluginMessage, write: WriteFn, response: *mut c_void)>,
+    /// Frees `state` when the server unloads the plugin; may be NULL
+    pub destroy: Option<unsafe extern "C" fn(state: *mut c_void)>,
+}
+
+type InitFn = unsafe extern "C" fn(plugin: *mut PluginVtable) -> c_int;
+
+/// A handler loaded from a shared library
+///
+/// The library stays loaded for as long as the plugin exists.
+#[derive(Debug)]
+pub struct Plugin {
+    name: String,
+    path: PathBuf,
+    vtable: PluginVtable,
+    // Dropped after `destroy` has run, so the plugin's code is still mapped for it
+    _library: Library,
+}
+
+// Plugins promise in the header that `on_message` may be called from several
+// threads at once, as the server does with every handler.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    /// Loads the plugin in the shared library at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let error = |message: String| RustbucketError::Plugin { path: path.to_path_buf(), message };
+
+        // Loading runs the library's initializers, which is why only trusted plugins may be used
+        let library = unsafe { Library::new(path) }.map_err(|e| error(e.to_string()))?;
+        let init = unsafe { library.get::<InitFn>(INIT_SYMBOL) }.map_err(|e| error(e.to_string()))?;
+
+        let mut vtable = PluginVtable {
+            size: size_of::<PluginVtable>() as u32,
+            abi_version: 0,
+            name: ptr::null(),
+            state: ptr::null_mut(),
+            on_message: None,
+            destroy: None,
+        };
+        let status = unsafe { init(&mut vtable) };
+        if status != 0 {
+            return Err(error(format!("rustbucket_plugin_init failed with {}", status)));
+        }
+        // The plugin may have allocated its state by now, so a refused plugin still gets to free it
+        let refuse = |message: String| {
+            if let Some(destroy) = vtable.destroy {
+                unsafe { destroy(vtable.state) };
+            }
+            Err(error(message))
+        };
+        if vtable.abi_version != PLUGIN_ABI_VERSION {
+            return refuse(format!(
+                "built for plugin interface version {}, this server implements {}",
+                vtable.abi_version, PLUGIN_ABI_VERSION
+            ));
+        }
+        if vtable.on_message.is_none() || vtable.name.is_null() {
+            return refuse("rustbucket_plugin_init did not set name and on_message".to_string());
+        }
+        let name = unsafe { CStr::from_ptr(vtable.name) }.to_string_lossy().into_owned();
+
+        Ok(Self { name, path: path.to_path_buf(), vtable, _library: library })
+    }
+
+    /// Name the plugin registered itself under
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Library the plugin was loaded from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl RequestHandler for Plugin {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        let Some(on_message) = self.vtable.on_message else { return };
+        let peer = CString::new(ctx.peer().replace('\0', "")).unwrap_or_default();
+        let message = PluginMessage {
+            size: size_of::<PluginMessage>() as u32,
+            connection_id: ctx.id(),
+            peer: peer.as_ptr(),
+            data: message.as_ptr(),
+            len: message.len(),
+        };
+        unsafe { on_message(self.vtable.state, &message, write_response, response as *mut ResponseWriter as *mut c_void) };
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.vtable.destroy {
+            unsafe { destroy(self.vtable.state) };
+        }
+    }
+}
+
+/// The `write` callback handed to plugins
+unsafe extern "C" fn write_response(response: *mut c_void, data: *const u8, len: usize) {
+    if response.is_null() || (data.is_null() && len > 0) {
+        return;
+    }
+    let response = unsafe { &mut *(response as *mut ResponseWriter) };
+    if len > 0 {
+        response.write(unsafe { slice::from_raw_parts(data, len) });
+    }
+}
+
+/// Whether `path` looks like a shared library for this platform
+fn is_library(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|ext| ext == std::env::consts::DLL_EXTENSION)
+}
+
+/// The shared libraries in `dir`, in file name order; a missing directory holds none
+fn libraries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if is_library(&path) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Loads every plugin in `dir`, in file name order
+///
+/// A missing directory holds no plugins; a library that fails to load is an error.
+pub fn discover(dir: impl AsRef<Path>) -> Result<Vec<Plugin>> {
+    libraries(dir.as_ref())?.into_iter().map(Plugin::load).collect()
+}
+
+/// Loads the plugin called `name` from `dir`
+///
+/// A library named after the plugin (`libupper.so` or `upper.so` for `upper`)
+/// is tried first, so usually no other library is loaded. Failing that, the
+/// libraries in `dir` are loaded one at a time, in file name order, until one
+/// registers as `name`; each one passed over is unloaded again, and one that
+/// fails to load is skipped with a warning rather than stopping the search.
+pub fn find(dir: impl AsRef<Path>, name: &str) -> Result<Plugin> {
+    let dir = dir.as_ref();
+    let mut paths: Vec<PathBuf> = [DLL_PREFIX, ""]
+        .into_iter()
+        .map(|prefix| dir.join(format!("{}{}{}", prefix, name, DLL_SUFFIX)))
+        .filter(|path| is_library(path))
+        .collect();
+    paths.dedup();
+    for path in libraries(dir)? {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    let mut found = Vec::new();
+    for path in paths {
+        match Plugin::load(&path) {
+            Ok(plugin) if plugin.name() == name => return Ok(plugin),
+            Ok(plugin) => found.push(plugin.name().to_string()),
+            Err(e) => {
+                log::warn!("Skipping {}", e);
+                found.push(format!("{} failed to load", path.display()));
+            }
+        }
+    }
+    let found = if found.is_empty() { "none".to_string() } else { found.join(", ") };
+    Err(RustbucketError::InvalidConfig(format!("no plugin named {} in {} (found: {})", name, dir.display(), found)))
+}
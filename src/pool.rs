@@ -0,0 +1,231 @@
+//! Worker pool that exposes utilization counters and can size itself to its load.
+//!
+//! Each connection holds a worker for as long as it is open, so a fixed pool
+//! makes operators guess how many clients will be connected at once: too few
+//! workers and new connections queue behind open ones, too many and idle
+//! threads sit on their stacks. A pool with room to grow starts another worker
+//! whenever a job is queued with every worker already busy, up to its maximum,
+//! and workers above the minimum leave once they have gone without a job for
+//! the idle timeout.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::telemetry::Counter;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Point-in-time view of the worker pool's utilization
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolStats {
+    /// Worker threads running now
+    pub workers: usize,
+    /// Fewest workers the pool keeps, even when idle
+    pub min_workers: usize,
+    /// Most workers the pool grows to under load
+    pub max_workers: usize,
+    /// Workers currently running a job
+    pub active: usize,
+    /// Jobs waiting for a free worker
+    pub queued: usize,
+    /// Jobs that have run to completion since startup
+    pub executed: u64,
+    /// Jobs that panicked since startup
+    pub panicked: usize,
+}
+
+/// Thread pool that counts the jobs it has executed, growing and shrinking between its bounds
+///
+/// Clones share the pool; once every clone is dropped, the workers finish the
+/// queued jobs and exit.
+#[derive(Clone)]
+pub struct WorkerPool {
+    handle: Arc<Handle>,
+}
+
+/// Closes the pool when the last [`WorkerPool`] goes away; the workers only hold [`Shared`]
+struct Handle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a job is queued or the pool is closed
+    work: Condvar,
+    /// Signalled when the last job finishes
+    done: Condvar,
+    min: usize,
+    max: usize,
+    /// How long a worker above the minimum waits for a job before leaving
+    idle_timeout: Duration,
+    executed: Counter,
+    panicked: AtomicUsize,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Job>,
+    workers: usize,
+    active: usize,
+    closed: bool,
+}
+
+impl WorkerPool {
+    /// Creates a pool with `num_threads` workers
+    pub fn new(num_threads: usize) -> Self {
+        Self::scaling(num_threads, num_threads, Duration::MAX)
+    }
+
+    /// Creates a pool of `min` workers that grows to `max` under load, and
+    /// shrinks back as workers go `idle_timeout` without a job
+    pub fn scaling(min: usize, max: usize, idle_timeout: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            work: Condvar::new(),
+            done: Condvar::new(),
+            min,
+            max: max.max(min),
+            idle_timeout,
+            executed: Counter::new(),
+            panicked: AtomicUsize::new(0),
+        });
+        shared.lock().workers = min;
+        for _ in 0..min {
+            spawn_worker(&shared);
+        }
+        Self { handle: Arc::new(Handle { shared }) }
+    }
+
+    fn shared(&self) -> &Arc<Shared> {
+        &self.handle.shared
+    }
+
+    /// Queues a job to run on the next free worker, starting another if every worker is busy
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = self.shared();
+        let mut state = shared.lock();
+        state.queue.push_back(Box::new(job));
+        // Counted before the worker starts, so that concurrent calls do not grow the pool past its maximum
+        let grow = state.active + state.queue.len() > state.workers && state.workers < shared.max;
+        if grow {
+            state.workers += 1;
+        }
+        drop(state);
+        if grow {
+            spawn_worker(shared);
+        } else {
+            shared.work.notify_one();
+        }
+    }
+
+    /// Runs `job` on the calling worker, counting it as if it had been queued
+    ///
+    /// For workers that find jobs of their own, such as connections accepted on
+    /// their own listener; a panicking job is counted and does not unwind further.
+    pub fn run<F: FnOnce()>(&self, job: F) {
+        run(self.shared(), job);
+    }
+
+    /// Blocks until every queued and running job has finished
+    pub fn join(&self) {
+        let shared = self.shared();
+        let mut state = shared.lock();
+        while !state.queue.is_empty() || state.active > 0 {
+            state = shared.done.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Returns the current utilization counters
+    pub fn stats(&self) -> PoolStats {
+        let shared = self.shared();
+        let state = shared.lock();
+        PoolStats {
+            workers: state.workers,
+            min_workers: shared.min,
+            max_workers: shared.max,
+            active: state.active,
+            queued: state.queue.len(),
+            executed: shared.executed.get(),
+            panicked: shared.panicked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerPool").field("stats", &self.stats()).finish()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.work.notify_all();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Jobs run outside the lock, so a panic cannot leave the state half-updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Starts a worker already counted in the pool's workers
+fn spawn_worker(shared: &Arc<Shared>) {
+    let worker = Arc::clone(shared);
+    let spawned = thread::Builder::new().name("rustbucket-worker".to_string()).spawn(move || work(&worker));
+    if let Err(e) = spawned {
+        // Queued jobs wait for a worker that is already running
+        shared.lock().workers -= 1;
+        log::warn!("Failed to start a worker thread: {}", e);
+    }
+}
+
+fn run<F: FnOnce()>(shared: &Shared, job: F) {
+    match panic::catch_unwind(AssertUnwindSafe(job)) {
+        Ok(()) => shared.executed.add(1),
+        Err(_) => {
+            shared.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Runs jobs until the pool is closed, or until this worker is one above the minimum left idle
+fn work(shared: &Shared) {
+    let mut state = shared.lock();
+    loop {
+        if let Some(job) = state.queue.pop_front() {
+            state.active += 1;
+            drop(state);
+            run(shared, job);
+            state = shared.lock();
+            state.active -= 1;
+            if state.queue.is_empty() && state.active == 0 {
+                shared.done.notify_all();
+            }
+            continue;
+        }
+        if state.closed {
+            break;
+        }
+        if state.workers > shared.min {
+            let (guard, wait) = shared.work.wait_timeout(state, shared.idle_timeout).unwrap_or_else(|e| e.into_inner());
+            state = guard;
+            if wait.timed_out() && state.queue.is_empty() && state.workers > shared.min {
+                break;
+            }
+        } else {
+            state = shared.work.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+    state.workers -= 1;
+}
@@ -0,0 +1,88 @@
+//! On-demand CPU profiling for the admin interface.
+//!
+//! Profiles are collected by sampling every thread's stack for a fixed duration
+//! and encoded in the pprof protobuf format, so `go tool pprof` and other pprof
+//! tooling can render flamegraphs from them. Sampling support is only compiled in
+//! with the `profiling` cargo feature.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Default profile length when the request does not specify one
+pub const DEFAULT_PROFILE_SECONDS: u64 = 30;
+/// Longest profile that may be requested
+pub const MAX_PROFILE_SECONDS: u64 = 300;
+/// Stack samples taken per second
+#[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+const SAMPLE_FREQUENCY: i32 = 99;
+
+/// Only one profiler can be attached to the process at a time
+static PROFILE_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Why a profile could not be produced
+#[derive(Debug)]
+pub enum ProfileError {
+    /// The binary was built without the `profiling` feature
+    Unsupported,
+    /// Another profile is already being collected
+    Busy,
+    /// The profiler failed to start or to encode its report
+    #[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+    Failed(String),
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::Unsupported => write!(f, "profiling support not compiled in (rebuild with --features profiling)"),
+            ProfileError::Busy => write!(f, "a profile is already being collected"),
+            ProfileError::Failed(e) => write!(f, "profiling failed: {}", e),
+        }
+    }
+}
+
+/// Whether this build can collect CPU profiles
+pub fn supported() -> bool {
+    cfg!(feature = "profiling")
+}
+
+/// Samples the whole process for `duration` and returns a pprof protobuf profile
+///
+/// Blocks the calling thread for the length of the profile.
+pub fn cpu_profile(duration: Duration) -> Result<Vec<u8>, ProfileError> {
+    if !supported() {
+        return Err(ProfileError::Unsupported);
+    }
+    if PROFILE_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(ProfileError::Busy);
+    }
+    let result = collect(duration);
+    PROFILE_RUNNING.store(false, Ordering::SeqCst);
+    result
+}
+
+#[cfg(feature = "profiling")]
+fn collect(duration: Duration) -> Result<Vec<u8>, ProfileError> {
+    use pprof::protos::Message;
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| ProfileError::Failed(e.to_string()))?;
+    std::thread::sleep(duration);
+
+    let profile = guard
+        .report()
+        .build()
+        .and_then(|report| report.pprof())
+        .map_err(|e| ProfileError::Failed(e.to_string()))?;
+    profile
+        .write_to_bytes()
+        .map_err(|e| ProfileError::Failed(e.to_string()))
+}
+
+#[cfg(not(feature = "profiling"))]
+fn collect(_duration: Duration) -> Result<Vec<u8>, ProfileError> {
+    Err(ProfileError::Unsupported)
+}
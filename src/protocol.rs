@@ -0,0 +1,532 @@
+//! The wire protocol spoken to TCP clients.
+//!
+//! Messages read from the client are passed through the server's
+//! [middleware](crate::middleware) to its
+//! [`RequestHandler`](crate::handler::RequestHandler), whose reply is written back; the default
+//! [`EchoHandler`](crate::handler::EchoHandler) answers with the same bytes
+//! prefixed by `Echo: `.
+//!
+//! A client's bytes can arrive split across reads however the network
+//! delivered them, so a handler whose messages have a shape of their own says
+//! where complete messages end with [`RequestHandler::frame`](crate::handler::RequestHandler::frame);
+//! the KV, queue, and chat handlers do. Bytes past the last complete message
+//! are kept for the next read, up to the server's largest message size. For
+//! other handlers the server's [`Framing`] decides: by default a message is
+//! whatever a single read returned, and with [`Framing::Line`] it is made of
+//! whole lines. Whatever is left when the client closes its end is handled as
+//! a last message.
+//!
+//! For the server's slow-client protections, a request is complete once a
+//! read ends with a newline. A connection whose last read did not is partway
+//! through a request; the server can limit how long it may stay that way and
+//! how many connections may be in that state at once, so that clients
+//! trickling bytes cannot hold every worker.
+//!
+//! A file in a reply is sent with `sendfile` on plain TCP connections on
+//! Linux, straight from the page cache to the socket. Over TLS or Noise, which
+//! have to encrypt it, and on other platforms it is read and written in chunks.
+
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use memchr::memrchr;
+
+use crate::config::Config;
+use crate::connections::ConnectionEntry;
+use crate::handler::{ConnectionCtx, ResponseWriter};
+use crate::memory::Pool;
+use crate::middleware::{Pipeline, Request};
+use crate::server::ServerState;
+
+/// Prefix of every reply from the default handler
+pub const ECHO_PREFIX: &[u8] = b"Echo: ";
+/// Line the default handler sends clients the server closes while shutting down gracefully
+pub const SHUTDOWN_NOTICE: &[u8] = b"Server closing\n";
+/// Largest message read from a client at once, unless the server is configured otherwise
+pub const READ_BUFFER_SIZE: usize = 1024;
+/// Smallest read buffer a server may be configured with
+pub const MIN_READ_BUFFER_SIZE: usize = 256;
+/// Largest read buffer a server may be configured with: 1 MiB
+pub const MAX_READ_BUFFER_SIZE: usize = 1024 * 1024;
+/// Most bytes kept toward a message split across reads, unless the server is configured otherwise: 64 KiB
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// How the server cuts what it reads into messages, for handlers that leave it to the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Each read is a message, however much of what the client sent it holds
+    #[default]
+    Read,
+    /// A message is one or more whole lines; a line split across reads is put back together first
+    Line,
+}
+
+/// Whether a connection's messages must be text
+///
+/// Clients switch with `MODE TEXT` and `MODE BINARY`, and `MODE` on its own
+/// asks which is in effect; see [`ServerBuilder::connection_mode`](crate::ServerBuilder::connection_mode).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionMode {
+    /// Messages are passed on byte for byte, whatever they hold
+    #[default]
+    Binary,
+    /// Messages must be valid UTF-8; any other is refused with the position of its first bad byte
+    Text,
+}
+
+impl ConnectionMode {
+    /// The mode as `MODE` reports it
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionMode::Binary => "BINARY",
+            ConnectionMode::Text => "TEXT",
+        }
+    }
+}
+
+/// Where the whole lines at the start of `buffered` end, for handlers whose messages are lines
+pub fn complete_lines(buffered: &[u8]) -> usize {
+    memrchr(b'\n', buffered).map_or(0, |newline| newline + 1)
+}
+
+/// Most bytes Linux moves in one `sendfile` call
+#[cfg(target_os = "linux")]
+const MAX_SENDFILE_CHUNK: usize = 0x7fff_f000;
+
+/// A connection the protocol is spoken over: a TCP stream, or TLS on top of one
+pub(crate) trait Transport: Read + Write {
+    /// The socket underneath, for addresses and timeouts
+    fn socket(&self) -> &TcpStream;
+
+    /// Sends up to `len` bytes of `file` from its current position, returning how many there were
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<u64> {
+        io::copy(&mut file.take(len), self)
+    }
+}
+
+impl Transport for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<u64> {
+        use nix::errno::Errno;
+
+        let mut sent = 0;
+        while sent < len {
+            let count = usize::try_from(len - sent).unwrap_or(usize::MAX).min(MAX_SENDFILE_CHUNK);
+            // Without an offset, sendfile reads from the file's position and moves it along
+            match nix::sys::sendfile::sendfile(&*self, &*file, None, count) {
+                Ok(0) => break,
+                Ok(n) => sent += n as u64,
+                Err(Errno::EINTR) => {}
+                // Some files, such as those on a few network file systems, cannot be sent this way
+                Err(Errno::EINVAL | Errno::ENOSYS) if sent == 0 => return io::copy(&mut file.take(len), self),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// Writes all of `bufs` to `writer`, in as few `write_vectored` calls as it will take them in
+pub(crate) fn write_all_vectored(writer: &mut (impl Write + ?Sized), mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    // Skip leading empty slices, so an empty write is not mistaken for a closed socket
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Where the next message to handle is, as found by [`Framer::frame`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Frame {
+    /// Nothing complete yet
+    Incomplete,
+    /// The first `n` bytes of the read
+    Read(usize),
+    /// The first `n` bytes kept from earlier reads, with the latest added
+    Buffered(usize),
+}
+
+/// Bytes read from a connection that do not yet make up a complete message
+///
+/// Reads holding nothing but complete messages are handled straight from the
+/// read buffer; only the rest of a message split across reads is copied here.
+pub(crate) struct Framer {
+    framing: Framing,
+    max_message_size: usize,
+    /// Mode of connections whose clients have not chosen one with `MODE`
+    mode: ConnectionMode,
+    partial: Vec<u8>,
+}
+
+impl Framer {
+    pub(crate) fn new(server_state: &ServerState) -> Self {
+        Self {
+            framing: server_state.framing,
+            max_message_size: server_state.max_message_size,
+            mode: server_state.connection_mode,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Adds `read` to the bytes kept from earlier reads and finds the complete messages
+    ///
+    /// Called again with an empty `read` once a message is handled, to find
+    /// the next one among the bytes kept. Gives the reason to refuse the client
+    /// once it has sent more than the largest message allowed without
+    /// completing one.
+    pub(crate) fn frame(
+        &mut self,
+        pipeline: &Pipeline,
+        connection: &ConnectionEntry,
+        read: &[u8],
+    ) -> Result<Frame, String> {
+        let body = connection.body_remaining();
+        let frame = if self.partial.is_empty() {
+            match self.complete(pipeline, connection, body, read) {
+                0 => {
+                    self.partial.extend_from_slice(read);
+                    Frame::Incomplete
+                }
+                n => Frame::Read(n),
+            }
+        } else {
+            self.partial.extend_from_slice(read);
+            match self.complete(pipeline, connection, body, &self.partial) {
+                0 => Frame::Incomplete,
+                n => Frame::Buffered(n),
+            }
+        };
+        if frame == Frame::Incomplete && self.partial.len() > self.max_message_size {
+            return Err(format!("message longer than {} bytes", self.max_message_size));
+        }
+        let len = match frame {
+            Frame::Incomplete => 0,
+            Frame::Read(n) | Frame::Buffered(n) => n,
+        };
+        connection.start_message(if body > 0 { len } else { 0 });
+        Ok(frame)
+    }
+
+    fn complete(&self, pipeline: &Pipeline, connection: &ConnectionEntry, body: u64, buffered: &[u8]) -> usize {
+        // An announced body goes to the handler as it arrives, in messages of its own
+        if body > 0 {
+            return buffered.len().min(usize::try_from(body).unwrap_or(usize::MAX));
+        }
+        let mode = connection.mode().unwrap_or(self.mode);
+        let framed = pipeline.frame(buffered).unwrap_or_else(|| match self.framing {
+            Framing::Read => buffered.len(),
+            Framing::Line => complete_lines(buffered),
+        });
+        let framed = framed.min(buffered.len());
+        // In text mode a character split across reads waits for the rest of its bytes
+        match std::str::from_utf8(&buffered[..framed]) {
+            Err(e) if mode == ConnectionMode::Text && e.error_len().is_none() => e.valid_up_to(),
+            _ => framed,
+        }
+    }
+
+    /// The message `frame` found, out of `read` or the bytes kept
+    pub(crate) fn message<'a>(&'a self, frame: Frame, read: &'a [u8]) -> &'a [u8] {
+        match frame {
+            Frame::Incomplete => &[],
+            Frame::Read(n) => &read[..n],
+            Frame::Buffered(n) => &self.partial[..n],
+        }
+    }
+
+    /// Drops the message `frame` found once it has been handled, keeping whatever of `read` followed it
+    pub(crate) fn consume(&mut self, frame: Frame, read: &[u8]) {
+        match frame {
+            Frame::Incomplete => {}
+            Frame::Read(n) => self.partial.extend_from_slice(&read[n..]),
+            Frame::Buffered(n) => {
+                self.partial.drain(..n);
+            }
+        }
+        if self.partial.is_empty() {
+            self.partial.shrink_to_fit();
+        }
+    }
+
+    /// Everything kept, as a last message once the client has closed its end
+    pub(crate) fn remainder(&self) -> Frame {
+        match self.partial.len() {
+            0 => Frame::Incomplete,
+            n => Frame::Buffered(n),
+        }
+    }
+
+    /// Bytes allocated for the messages kept
+    pub(crate) fn capacity(&self) -> usize {
+        self.partial.capacity()
+    }
+}
+
+/// Passes messages through `pipeline` until the client disconnects or the server shuts down
+pub(crate) fn serve_connection(
+    mut stream: impl Transport,
+    config: &Config,
+    pipeline: &Pipeline,
+    server_state: &ServerState,
+    connection: &Arc<ConnectionEntry>,
+) -> io::Result<()> {
+    let mut buffer = server_state.buffers.read_buffer();
+    let mut response = ResponseWriter::pooled(&server_state.buffers);
+    let mut framer = Framer::new(server_state);
+    let buffer_size = server_state.buffers.buffer_size();
+    let mut buffers = server_state.memory.charge(Pool::Buffers, buffer_size);
+    let ctx = ConnectionCtx::new(
+        connection,
+        config,
+        server_state,
+        stream.socket().peer_addr().ok(),
+        stream.socket().local_addr().ok(),
+    );
+    
+    // Set read timeout to prevent hanging on inactive connections
+    let idle_timeout = Duration::from_secs(config.timeout_seconds.max(1) as u64);
+    // With flood protection on, wake often enough to close idle clients once the server is defending
+    let idle_timeout = server_state.flood.map_or(idle_timeout, |policy| idle_timeout.min(policy.timeout));
+    stream.socket().set_read_timeout(Some(idle_timeout))?;
+    let mut read_timeout = idle_timeout;
+    let mut pending: Option<Pending<'_>> = None;
+    // Set when the connection ends because the server is shutting down
+    let mut closing = false;
+    
+    while !server_state.force_shutdown.load(Ordering::SeqCst) {
+        // Between requests a graceful shutdown closes the connection at once, rather than at the read timeout
+        let idle = pending.is_none();
+        connection.set_waiting(idle);
+        if idle && server_state.shutdown_requested.load(Ordering::SeqCst) {
+            closing = true;
+            break;
+        }
+        // Wake up in time to enforce the deadline on a request in progress
+        let timeout = match (&pending, server_state.request_deadline) {
+            (Some(pending), Some(deadline)) => {
+                deadline.saturating_sub(pending.since.elapsed()).clamp(MIN_READ_TIMEOUT, idle_timeout)
+            }
+            _ => idle_timeout,
+        };
+        if timeout != read_timeout {
+            stream.socket().set_read_timeout(Some(timeout))?;
+            read_timeout = timeout;
+        }
+        let read = stream.read(&mut buffer);
+        connection.set_waiting(false);
+        // Reads were shut off while idle; depending on the transport that looks like an end of stream or an error
+        if connection.woken() && !matches!(read, Ok(n) if n > 0) {
+            closing = true;
+            break;
+        }
+        match read {
+            Ok(0) => {
+                // Connection closed by client; a message it did not finish is handled as it stands
+                let frame = framer.remainder();
+                if frame != Frame::Incomplete {
+                    response.reset();
+                    pipeline.handle(&Request { connection: &ctx, message: framer.message(frame, &[]) }, &mut response);
+                    let _ = write_reply(&mut stream, connection, &mut response);
+                }
+                break;
+            }
+            Ok(n) => {
+                connection.record_received(n);
+                if let Err(reason) = track_request(&mut pending, server_state, &buffer[..n]) {
+                    return refuse_slow_request(&mut stream, server_state, &ctx, &reason);
+                }
+                // Every complete message is handled before reading on, since one may change where the next ends
+                let mut read = &buffer[..n];
+                let mut open = true;
+                while open {
+                    let frame = match framer.frame(pipeline, connection, read) {
+                        Ok(Frame::Incomplete) => break,
+                        Ok(frame) => frame,
+                        Err(reason) => return refuse(&mut stream, &ctx, &reason),
+                    };
+                    response.reset();
+                    let message = framer.message(frame, read);
+                    pipeline.handle(&Request { connection: &ctx, message }, &mut response);
+                    write_reply(&mut stream, connection, &mut response)?;
+                    open = reply_sent(&ctx, server_state, connection, message, &response);
+                    framer.consume(frame, read);
+                    read = &[];
+                }
+                buffers.resize(buffer_size + response.capacity() + framer.capacity());
+                if !open {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // A request left unfinished for a whole timeout is not waited for during shutdown
+                if server_state.shutdown_requested.load(Ordering::SeqCst) {
+                    closing = true;
+                    break;
+                }
+                if let Some(reason) = overdue(&pending, server_state) {
+                    return refuse_slow_request(&mut stream, server_state, &ctx, &reason);
+                }
+                if server_state.defending().is_some_and(|policy| connection.idle() >= policy.timeout) {
+                    log::info!("Closing idle connection from {} while defending against a flood", ctx.peer());
+                    break;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if closing {
+        let _ = send_shutdown_notice(&mut stream, connection, pipeline, server_state, &ctx, &mut response);
+    }
+    
+    Ok(())
+}
+
+/// Tells a client the server is closing its connection as it shuts down, in the words of its handler
+///
+/// Sent in place of a reply, so clients can tell a server going away from a
+/// dropped connection and reconnect elsewhere. The client may already be gone;
+/// the connection is closed either way. Nothing is sent once shutdown is forced.
+pub(crate) fn send_shutdown_notice(
+    stream: &mut impl Transport,
+    connection: &ConnectionEntry,
+    pipeline: &Pipeline,
+    server_state: &ServerState,
+    ctx: &ConnectionCtx<'_>,
+    response: &mut ResponseWriter,
+) -> io::Result<()> {
+    if server_state.force_shutdown.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    response.reset();
+    pipeline.shutting_down(ctx, response);
+    if response.is_empty() {
+        return Ok(());
+    }
+    write_reply(stream, connection, response)
+}
+
+/// Notes whether `message` left a request in progress, giving the reason to refuse the client if it must be
+///
+/// A read that does not end with a newline starts a request in progress, or
+/// continues one; the connection is refused if the server already has as many
+/// requests in progress as it allows, or if this one is overdue.
+pub(crate) fn track_request<'a>(
+    pending: &mut Option<Pending<'a>>,
+    server_state: &'a ServerState,
+    message: &[u8],
+) -> Result<(), String> {
+    if message.last() == Some(&b'\n') {
+        *pending = None;
+    } else if pending.is_none() {
+        *pending = Some(Pending::start(server_state).ok_or("too many requests in progress")?);
+    } else if let Some(reason) = overdue(pending, server_state) {
+        return Err(reason);
+    }
+    Ok(())
+}
+
+/// Writes `response`, and the file following it if there is one, while holding the connection's write lock
+pub(crate) fn write_reply(
+    stream: &mut impl Transport,
+    connection: &ConnectionEntry,
+    response: &mut ResponseWriter,
+) -> io::Result<()> {
+    let _writing = connection.lock_writes();
+    stream.write_all(response.as_bytes())?;
+    let mut sent = response.len();
+    if let Some((mut file, len)) = response.take_file() {
+        let body = stream.send_file(&mut file, len)?;
+        sent += usize::try_from(body).unwrap_or(usize::MAX);
+        if body < len {
+            connection.record_sent(sent);
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("file ended {} bytes short of the {} promised", len - body, len),
+            ));
+        }
+    }
+    connection.record_sent(sent);
+    Ok(())
+}
+
+/// Finishes with a message whose reply has been sent; whether to go on reading from the connection
+pub(crate) fn reply_sent(
+    ctx: &ConnectionCtx<'_>,
+    server_state: &ServerState,
+    connection: &Arc<ConnectionEntry>,
+    message: &[u8],
+    response: &ResponseWriter,
+) -> bool {
+    server_state.hooks.request_handled(connection, message);
+    if response.closes() {
+        return false;
+    }
+    if ctx.peer_addr().is_some_and(|addr| server_state.bans.is_banned(addr.ip())) {
+        log::info!("Closing connection from {}: its address is banned", ctx.peer());
+        return false;
+    }
+    true
+}
+
+/// Shortest read timeout used while waiting out a request deadline
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A request in progress on one connection, counted among the server's until dropped
+pub(crate) struct Pending<'a> {
+    since: Instant,
+    reading: &'a AtomicUsize,
+}
+
+impl<'a> Pending<'a> {
+    /// Counts a new request in progress, unless the server already has as many as it allows
+    fn start(server_state: &'a ServerState) -> Option<Self> {
+        let reading = &server_state.connections_reading;
+        let cap = server_state.max_reading_connections.unwrap_or(usize::MAX);
+        reading.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| (count < cap).then_some(count + 1)).ok()?;
+        Some(Self { since: Instant::now(), reading })
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.reading.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Why the request in progress should be given up on, if it has run past the deadline
+pub(crate) fn overdue(pending: &Option<Pending<'_>>, server_state: &ServerState) -> Option<String> {
+    let deadline = server_state.request_deadline?;
+    let since = pending.as_ref()?.since;
+    (since.elapsed() >= deadline).then(|| format!("request not completed within {:?}", deadline))
+}
+
+/// Tells a slow client why its connection is being closed, and counts it
+pub(crate) fn refuse_slow_request(
+    stream: &mut impl Write,
+    server_state: &ServerState,
+    ctx: &ConnectionCtx<'_>,
+    reason: &str,
+) -> io::Result<()> {
+    server_state.metrics.slow_requests_closed.fetch_add(1, Ordering::Relaxed);
+    refuse(stream, ctx, reason)
+}
+
+/// Tells a client why its connection is being closed
+pub(crate) fn refuse(stream: &mut impl Write, ctx: &ConnectionCtx<'_>, reason: &str) -> io::Result<()> {
+    log::info!("Closing connection from {}: {}", ctx.peer(), reason);
+    stream.write_all(format!("ERR {}\n", reason).as_bytes())
+}
@@ -0,0 +1,286 @@
+//! Named message queues with acknowledgements, kept on disk.
+//!
+//! [`MessageQueue`] holds any number of queues by name and [`QueueHandler`]
+//! answers `ENQUEUE`, `DEQUEUE`, and `ACK` against it. A dequeued message is
+//! not removed but hidden for a visibility timeout; the consumer acknowledges
+//! it once done, and if it does not in time (say, it crashed) the message
+//! becomes visible again and is redelivered. Every message is therefore
+//! delivered at least once, and consumers should cope with seeing one again.
+//!
+//! A queue opened with a journal file appends every enqueue and
+//! acknowledgement to it and syncs before replying, so messages survive
+//! restarts and crashes. Which messages are currently being worked on is not
+//! recorded: after a restart, every unacknowledged message is ready again.
+//! Messages can be counted against a [`MemoryBudget`], which refuses enqueues
+//! once it runs out.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use rustbucket::queue::{MessageQueue, QueueHandler};
+//! use rustbucket::Server;
+//!
+//! let queue = Arc::new(MessageQueue::open("queue.journal")?);
+//! Server::builder().handler(QueueHandler::new(queue)).build()?.run()?;
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{Result, RustbucketError};
+use crate::memory::{MemoryBudget, Pool};
+use journal::{Journal, Record};
+
+mod handler;
+mod journal;
+
+pub use handler::QueueHandler;
+
+/// Queue names and message bodies are arbitrary bytes
+pub type Bytes = Vec<u8>;
+
+/// How long a dequeued message stays hidden when the consumer does not say
+pub const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest a dequeued message can be hidden for: twelve hours
+pub const MAX_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Bytes assumed per message for its map slot and bookkeeping, when counting it against a memory budget
+const MESSAGE_OVERHEAD: usize = 64;
+
+/// Journal records about acknowledged messages tolerated before it is rewritten without them
+const COMPACT_AFTER: usize = 1024;
+
+/// A message handed to a consumer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Identifies the message when acknowledging it; never reused
+    pub id: u64,
+    pub body: Bytes,
+    /// How many times the message has been dequeued, including this time
+    pub deliveries: u32,
+}
+
+/// Named queues of messages, optionally journaled to disk
+#[derive(Debug, Default)]
+pub struct MessageQueue {
+    state: Mutex<State>,
+    /// Told of every message added and removed
+    budget: Option<Arc<MemoryBudget>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_id: u64,
+    queues: HashMap<Bytes, Queue>,
+    /// Appended to while the state lock is held, so records are in the order changes were made
+    journal: Option<Journal>,
+    /// Records in the journal that only concern acknowledged messages
+    dead_records: usize,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: HashMap<u64, Message>,
+    /// Ids of the messages that can be dequeued, oldest first
+    ready: VecDeque<u64>,
+    /// Ids of dequeued messages, by when they become visible again
+    in_flight: BTreeSet<(Instant, u64)>,
+}
+
+#[derive(Debug)]
+struct Message {
+    body: Bytes,
+    deliveries: u32,
+    /// When a dequeued message becomes visible again; `None` while it is ready
+    visible_at: Option<Instant>,
+}
+
+impl Queue {
+    fn insert(&mut self, id: u64, body: Bytes) {
+        self.messages.insert(id, Message { body, deliveries: 0, visible_at: None });
+        self.ready.push_back(id);
+    }
+
+    /// Makes messages whose visibility timeout has passed ready again, ahead of the rest
+    fn requeue_expired(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+        while self.in_flight.first().is_some_and(|&(visible_at, _)| visible_at <= now) {
+            let (_, id) = self.in_flight.pop_first().expect("checked above");
+            if let Some(message) = self.messages.get_mut(&id) {
+                message.visible_at = None;
+            }
+            expired.push(id);
+        }
+        for id in expired.into_iter().rev() {
+            self.ready.push_front(id);
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        let Some(message) = self.messages.remove(&id) else { return false };
+        match message.visible_at {
+            Some(visible_at) => {
+                self.in_flight.remove(&(visible_at, id));
+            }
+            None => self.ready.retain(|&ready| ready != id),
+        }
+        true
+    }
+}
+
+impl MessageQueue {
+    /// Creates queues that live only in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens queues journaled to `path`, replaying the messages not yet acknowledged
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut state = State::default();
+        let journal = Journal::open(path, |record| match record {
+            Record::Enqueue { queue, id, body } => {
+                state.queues.entry(queue.to_vec()).or_default().insert(id, body.to_vec());
+                state.next_id = state.next_id.max(id + 1);
+            }
+            Record::Ack { queue, id } => {
+                if let Some(messages) = state.queues.get_mut(queue) {
+                    messages.remove(id);
+                }
+                state.dead_records += 2;
+            }
+            Record::NextId(id) => state.next_id = state.next_id.max(id),
+        })?;
+        state.queues.retain(|_, queue| !queue.messages.is_empty());
+        log::info!("Loaded {} unacknowledged messages from {}", state.len(), path.display());
+        state.journal = Some(journal);
+        Ok(Self { state: Mutex::new(state), budget: None })
+    }
+
+    /// Counts the messages held against `budget`, refusing enqueues that would exceed it
+    ///
+    /// [`enqueue`](Self::enqueue) fails with [`RustbucketError::OutOfMemory`]
+    /// while the budget has no room for the message.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        let state = self.state.get_mut().unwrap();
+        let messages = state.queues.values().flat_map(|queue| queue.messages.values());
+        let bytes = messages.map(|message| footprint(&message.body)).sum();
+        budget.reserve(Pool::Queues, bytes);
+        if let Some(old) = self.budget.replace(budget) {
+            old.release(Pool::Queues, bytes);
+        }
+        self
+    }
+
+    /// Adds `body` to the back of `queue`, returning the new message's id
+    pub fn enqueue(&self, queue: &[u8], body: &[u8]) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if self.budget.as_ref().is_some_and(|budget| !budget.admit(footprint(body))) {
+            return Err(RustbucketError::OutOfMemory);
+        }
+        let id = state.next_id;
+        if let Some(journal) = &mut state.journal {
+            journal.append(&Record::Enqueue { queue, id, body })?;
+        }
+        state.next_id += 1;
+        state.queues.entry(queue.to_vec()).or_default().insert(id, body.to_vec());
+        if let Some(budget) = &self.budget {
+            budget.reserve(Pool::Queues, footprint(body));
+        }
+        Ok(id)
+    }
+
+    /// Takes the oldest ready message from `queue`, hiding it for `visibility`
+    ///
+    /// Unless it is acknowledged before then, the message is handed out again
+    /// once `visibility` has passed, which is capped at
+    /// [`MAX_VISIBILITY_TIMEOUT`]. Returns `None` if no message is ready.
+    pub fn dequeue(&self, queue: &[u8], visibility: Duration) -> Option<Delivery> {
+        let mut state = self.state.lock().unwrap();
+        let queue = state.queues.get_mut(queue)?;
+        let now = Instant::now();
+        queue.requeue_expired(now);
+        let id = queue.ready.pop_front()?;
+        let visible_at = now + visibility.min(MAX_VISIBILITY_TIMEOUT);
+        queue.in_flight.insert((visible_at, id));
+        let message = queue.messages.get_mut(&id).expect("ready messages exist");
+        message.visible_at = Some(visible_at);
+        message.deliveries += 1;
+        Some(Delivery { id, body: message.body.clone(), deliveries: message.deliveries })
+    }
+
+    /// Removes a message for good, returning whether it was still in `queue`
+    ///
+    /// A message can be acknowledged even after its visibility timeout has
+    /// passed, as long as it was not acknowledged already.
+    pub fn ack(&self, queue: &[u8], id: u64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(message) = state.queues.get(queue).and_then(|messages| messages.messages.get(&id)) else {
+            return Ok(false);
+        };
+        let bytes = footprint(&message.body);
+        if let Some(journal) = &mut state.journal {
+            journal.append(&Record::Ack { queue, id })?;
+        }
+        let messages = state.queues.get_mut(queue).expect("checked above");
+        messages.remove(id);
+        if messages.messages.is_empty() {
+            state.queues.remove(queue);
+        }
+        if let Some(budget) = &self.budget {
+            budget.release(Pool::Queues, bytes);
+        }
+        state.dead_records += 2;
+        if state.dead_records >= COMPACT_AFTER && state.dead_records > state.len() {
+            // The acknowledgement is already durable; a failed compaction only leaves the journal longer
+            if let Err(e) = state.compact() {
+                log::warn!("Could not compact the queue journal: {}", e);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Messages in `queue` that have not been acknowledged, including those being worked on
+    pub fn len(&self, queue: &[u8]) -> usize {
+        self.state.lock().unwrap().queues.get(queue).map_or(0, |queue| queue.messages.len())
+    }
+
+    /// Whether `queue` has no unacknowledged messages
+    pub fn is_empty(&self, queue: &[u8]) -> bool {
+        self.len(queue) == 0
+    }
+}
+
+/// Estimated heap bytes taken by a message with `body`
+fn footprint(body: &[u8]) -> usize {
+    MESSAGE_OVERHEAD + body.len()
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.queues.values().map(|queue| queue.messages.len()).sum()
+    }
+
+    /// Rewrites the journal with only the messages not yet acknowledged
+    fn compact(&mut self) -> Result<()> {
+        let Some(journal) = &mut self.journal else { return Ok(()) };
+        let mut records = vec![Record::NextId(self.next_id)];
+        let mut messages: Vec<_> = self
+            .queues
+            .iter()
+            .flat_map(|(name, queue)| queue.messages.iter().map(move |(&id, message)| (id, name, message)))
+            .collect();
+        messages.sort_by_key(|&(id, ..)| id);
+        records.extend(messages.into_iter().map(|(id, queue, message)| Record::Enqueue {
+            queue,
+            id,
+            body: &message.body,
+        }));
+        journal.rewrite(&records)?;
+        self.dead_records = 0;
+        Ok(())
+    }
+}
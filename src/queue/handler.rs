@@ -0,0 +1,132 @@
+//! The queue command protocol.
+//!
+//! Commands are lines split like KV commands, with double quotes around
+//! arguments containing spaces and `$<length>` prefixes before binary
+//! messages, which `DEQUEUE` returns with the same prefix:
+//!
+//! - `ENQUEUE queue message` replies with the new message's id.
+//! - `DEQUEUE queue [seconds]` replies `*3` followed by the id, how many times
+//!   the message has been delivered, and the message, one per line; or
+//!   `(nil)` if nothing is ready. The message stays hidden for `seconds`
+//!   (default 30, at most 43200) and is then redelivered unless acknowledged.
+//! - `ACK queue id` replies `1` if the message was removed, or `0` if it was
+//!   already acknowledged or never existed.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bans::Offence;
+use crate::handler::{ConnectionCtx, RequestHandler, ResponseWriter};
+use crate::kv::wire::{self, encode_array};
+use crate::queue::{MessageQueue, DEFAULT_VISIBILITY_TIMEOUT, MAX_VISIBILITY_TIMEOUT};
+
+/// Answers queue commands against a [`MessageQueue`]
+#[derive(Debug, Clone)]
+pub struct QueueHandler {
+    queue: Arc<MessageQueue>,
+}
+
+impl QueueHandler {
+    /// Serves `queue`, which may be shared with other handlers or servers
+    pub fn new(queue: Arc<MessageQueue>) -> Self {
+        Self { queue }
+    }
+
+    /// The queues being served
+    pub fn queue(&self) -> &Arc<MessageQueue> {
+        &self.queue
+    }
+
+    /// Runs one command line, writing its reply
+    fn execute(
+        &self,
+        ctx: &ConnectionCtx<'_>,
+        args: Result<Vec<Vec<u8>>, &'static str>,
+        response: &mut ResponseWriter,
+    ) {
+        let args = match args {
+            Ok(args) => args,
+            Err(reason) => {
+                ctx.report(Offence::ProtocolError);
+                return error(response, reason);
+            }
+        };
+        let Some((name, args)) = args.split_first() else { return };
+        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+        match (name.as_str(), args) {
+            ("PING", []) => ok(response, "PONG"),
+            ("ENQUEUE", [queue, message]) => match self.queue.enqueue(queue, message) {
+                Ok(id) => ok(response, &id.to_string()),
+                Err(e) => error(response, &e.to_string()),
+            },
+            ("DEQUEUE", [queue, timeout @ ..]) if timeout.len() <= 1 => {
+                let visibility = match timeout.first() {
+                    None => DEFAULT_VISIBILITY_TIMEOUT,
+                    Some(seconds) => match parse_seconds(seconds) {
+                        Some(visibility) if visibility <= MAX_VISIBILITY_TIMEOUT => visibility,
+                        Some(_) => {
+                            let max = MAX_VISIBILITY_TIMEOUT.as_secs();
+                            return error(response, &format!("visibility timeout is longer than {max} seconds"));
+                        }
+                        None => return error(response, "visibility timeout is not a positive number"),
+                    },
+                };
+                match self.queue.dequeue(queue, visibility) {
+                    Some(delivery) => response.write(&encode_array(&[
+                        delivery.id.to_string().as_bytes(),
+                        delivery.deliveries.to_string().as_bytes(),
+                        &delivery.body,
+                    ])),
+                    None => ok(response, "(nil)"),
+                }
+            }
+            ("ACK", [queue, id]) => {
+                let Some(id) = std::str::from_utf8(id).ok().and_then(|id| id.parse().ok()) else {
+                    return error(response, "message id is not an integer");
+                };
+                match self.queue.ack(queue, id) {
+                    Ok(removed) => ok(response, if removed { "1" } else { "0" }),
+                    Err(e) => error(response, &e.to_string()),
+                }
+            }
+            ("PING" | "ENQUEUE" | "DEQUEUE" | "ACK", _) => {
+                error(response, &format!("wrong number of arguments for '{}'", name))
+            }
+            _ => {
+                ctx.report(Offence::ProtocolError);
+                error(response, &format!("unknown command '{}'", name))
+            }
+        }
+    }
+}
+
+impl RequestHandler for QueueHandler {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        for args in wire::commands(message) {
+            self.execute(ctx, args, response);
+        }
+    }
+
+    fn frame(&self, buffered: &[u8]) -> Option<usize> {
+        Some(wire::complete_len(buffered))
+    }
+
+    fn on_shutdown(&self, _ctx: &ConnectionCtx<'_>, response: &mut ResponseWriter) {
+        error(response, "server closing");
+    }
+}
+
+fn ok(response: &mut ResponseWriter, reply: &str) {
+    response.write(reply.as_bytes());
+    response.write(b"\n");
+}
+
+fn error(response: &mut ResponseWriter, reason: &str) {
+    response.write(b"ERR ");
+    ok(response, reason);
+}
+
+fn parse_seconds(arg: &[u8]) -> Option<Duration> {
+    let seconds: f64 = std::str::from_utf8(arg).ok()?.parse().ok()?;
+    Duration::try_from_secs_f64(seconds).ok().filter(|timeout| !timeout.is_zero())
+}
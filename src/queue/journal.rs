@@ -0,0 +1,178 @@
+//! Append-only journal of enqueued and acknowledged messages.
+//!
+//! Each record is its payload length and CRC-32 followed by the payload: a
+//! record type byte and length-prefixed fields. Replaying the records in order
+//! rebuilds the messages not yet acknowledged. Once acknowledged messages make
+//! up most of the journal it is rewritten with only the rest, starting with a
+//! record of the next message id so that ids are never handed out twice.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, RustbucketError};
+
+/// Followed by the queue, the message id, and the body
+const RECORD_ENQUEUE: u8 = 1;
+/// Followed by the queue and the message id
+const RECORD_ACK: u8 = 2;
+/// Followed by the id the next message will get
+const RECORD_NEXT_ID: u8 = 3;
+/// Length and checksum in front of every payload
+const HEADER_LEN: usize = 8;
+
+/// A change recorded in the journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Record<'a> {
+    Enqueue { queue: &'a [u8], id: u64, body: &'a [u8] },
+    Ack { queue: &'a [u8], id: u64 },
+    NextId(u64),
+}
+
+/// An open journal; callers serialize access
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Bytes of complete records in the file
+    len: u64,
+}
+
+fn storage_error(path: &Path, source: io::Error) -> RustbucketError {
+    RustbucketError::Storage { path: path.to_path_buf(), source }
+}
+
+impl Journal {
+    /// Opens the journal at `path`, passing each of its records to `apply` in order
+    ///
+    /// A record cut short by a crash, or failing its checksum, ends the
+    /// replay; the journal is truncated there so new records follow the last good one.
+    pub(crate) fn open(path: &Path, mut apply: impl FnMut(Record<'_>)) -> Result<Self> {
+        let error = |e| storage_error(path, e);
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(error(e)),
+        };
+        let mut valid = 0;
+        while let Some((record, len)) = decode(&bytes[valid..]) {
+            apply(record);
+            valid += len;
+        }
+        if valid < bytes.len() {
+            log::warn!(
+                "Discarding {} bytes of incomplete or corrupt records at the end of {}",
+                bytes.len() - valid,
+                path.display()
+            );
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(error)?;
+        file.set_len(valid as u64).map_err(error)?;
+        Ok(Self { path: path.to_path_buf(), file, len: valid as u64 })
+    }
+
+    /// Appends `record` and waits until it is on disk
+    pub(crate) fn append(&mut self, record: &Record<'_>) -> Result<()> {
+        let encoded = encode(record);
+        match self.file.write_all(&encoded).and_then(|()| self.file.sync_data()) {
+            Ok(()) => {
+                self.len += encoded.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                // Drop whatever part of the record made it out, so later records stay readable
+                let _ = self.file.set_len(self.len);
+                Err(storage_error(&self.path, e))
+            }
+        }
+    }
+
+    /// Replaces the journal's contents with `records`
+    ///
+    /// The new journal is written to a temporary file and renamed into place,
+    /// so a crash midway leaves the old one intact.
+    pub(crate) fn rewrite(&mut self, records: &[Record<'_>]) -> Result<()> {
+        let error = |e| storage_error(&self.path, e);
+        let bytes: Vec<u8> = records.iter().flat_map(encode).collect();
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp = self.path.with_file_name(temp_name);
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temp, &self.path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(error(e));
+        }
+        self.file = OpenOptions::new().append(true).open(&self.path).map_err(error)?;
+        self.len = bytes.len() as u64;
+        log::debug!("Compacted {} to {} bytes", self.path.display(), self.len);
+        Ok(())
+    }
+}
+
+fn encode(record: &Record<'_>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let mut push = |bytes: &[u8]| {
+        payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        payload.extend_from_slice(bytes);
+    };
+    match record {
+        Record::Enqueue { queue, id, body } => {
+            push(&[RECORD_ENQUEUE]);
+            push(queue);
+            push(&id.to_le_bytes());
+            push(body);
+        }
+        Record::Ack { queue, id } => {
+            push(&[RECORD_ACK]);
+            push(queue);
+            push(&id.to_le_bytes());
+        }
+        Record::NextId(id) => {
+            push(&[RECORD_NEXT_ID]);
+            push(&id.to_le_bytes());
+        }
+    }
+    let mut encoded = Vec::with_capacity(HEADER_LEN + payload.len());
+    encoded.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    encoded.extend_from_slice(&payload);
+    encoded
+}
+
+/// Decodes the record at the start of `bytes` and its length, if it is complete and intact
+fn decode(bytes: &[u8]) -> Option<(Record<'_>, usize)> {
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(bytes.get(4..HEADER_LEN)?.try_into().ok()?);
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    let mut fields = Fields(payload);
+    let record = match fields.take()? {
+        [RECORD_ENQUEUE] => Record::Enqueue { queue: fields.take()?, id: fields.take_u64()?, body: fields.take()? },
+        [RECORD_ACK] => Record::Ack { queue: fields.take()?, id: fields.take_u64()? },
+        [RECORD_NEXT_ID] => Record::NextId(fields.take_u64()?),
+        _ => return None,
+    };
+    fields.0.is_empty().then_some((record, HEADER_LEN + len))
+}
+
+/// Length-prefixed fields of a payload
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self) -> Option<&'a [u8]> {
+        let len = u32::from_le_bytes(self.0.get(..4)?.try_into().ok()?) as usize;
+        let field = self.0.get(4..4 + len)?;
+        self.0 = &self.0[4 + len..];
+        Some(field)
+    }
+
+    fn take_u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take()?.try_into().ok()?))
+    }
+}
@@ -0,0 +1,246 @@
+//! Optional Linux sandboxing of the server process.
+//!
+//! A server built with a [`Sandbox`] gives up abilities it has no use for once
+//! it is running, to limit what a bug in a handler, plugin, or script could be
+//! made to do:
+//!
+//! - Filesystem access is restricted with Landlock to the directories holding
+//!   the server's files (log, config, pidfile, data files, file storage), plus
+//!   read-only access to `/etc` and `/proc` for name resolution and process
+//!   statistics. This applies to the thread calling
+//!   [`Server::start`](crate::Server::start) and every thread the
+//!   server starts, so start other threads that should be covered afterwards.
+//! - Once the server is listening, a seccomp filter makes syscalls no server
+//!   needs fail with `EPERM` for every thread in the process: running
+//!   programs, tracing other processes, mounting, loading kernel modules,
+//!   changing user or group, and the like.
+//!
+//! Both need a Linux kernel that supports them (Landlock arrived in 5.13).
+//! Elsewhere, or on older kernels, the server runs unsandboxed and logs a
+//! warning; [`SandboxStatus`] says how much was enforced.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+use crate::paths::Paths;
+
+/// Directories the server may read but not write, when they exist
+#[cfg(target_os = "linux")]
+const READ_ONLY: [&str; 2] = ["/etc", "/proc"];
+
+/// How much of a restriction the running system could enforce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enforcement {
+    Full,
+    /// The kernel supports only some of the restrictions asked for
+    Partial,
+    /// The kernel or platform does not support the restriction at all
+    Unavailable,
+}
+
+impl fmt::Display for Enforcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Enforcement::Full => write!(f, "enforced"),
+            Enforcement::Partial => write!(f, "partially enforced"),
+            Enforcement::Unavailable => write!(f, "unavailable"),
+        }
+    }
+}
+
+/// What a sandboxed server ended up restricted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxStatus {
+    pub filesystem: Enforcement,
+    pub syscalls: Enforcement,
+}
+
+/// The filesystem access a sandboxed server keeps
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    writable: Vec<PathBuf>,
+    readable: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Access to the directories of every file in `paths`, and nothing else of the caller's
+    pub fn for_paths(paths: &Paths) -> Self {
+        let files = [
+            &paths.log_file,
+            &paths.config_file,
+            &paths.pid_file,
+            &paths.snapshot_file,
+            &paths.wal_file,
+            &paths.blob_file,
+            &paths.queue_file,
+            &paths.access_file,
+            &paths.acl_file,
+        ];
+        let mut sandbox = Self::default().writable(&paths.storage_dir).readable(&paths.scripts_dir);
+        for file in files {
+            sandbox = sandbox.writable(directory_of(file));
+        }
+        sandbox
+    }
+
+    /// Also allows reading and writing anything under `path`
+    pub fn writable(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if !self.writable.contains(&path) {
+            self.writable.push(path);
+        }
+        self
+    }
+
+    /// Also allows reading anything under `path`
+    pub fn readable(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if !self.readable.contains(&path) {
+            self.readable.push(path);
+        }
+        self
+    }
+
+    /// Paths that may be read and written
+    pub fn writable_paths(&self) -> &[PathBuf] {
+        &self.writable
+    }
+
+    /// Restricts the calling thread, and the threads it starts from now on, to the allowed paths
+    ///
+    /// Paths that do not exist yet are left out, as Landlock can only allow
+    /// access beneath something it can open.
+    pub fn restrict_filesystem(&self) -> Result<Enforcement> {
+        imp::restrict_filesystem(self)
+    }
+
+    /// Makes syscalls the server never needs fail with `EPERM`, in every thread of the process
+    pub fn restrict_syscalls(&self) -> Result<Enforcement> {
+        imp::restrict_syscalls()
+    }
+}
+
+/// The directory `file` is in, `.` for a bare file name
+fn directory_of(file: &Path) -> PathBuf {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::collections::BTreeMap;
+
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI,
+    };
+    use nix::libc;
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+
+    use super::{Enforcement, Sandbox, READ_ONLY};
+    use crate::error::{Result, RustbucketError};
+
+    /// Landlock ABI whose filesystem rights are asked for; older kernels enforce what they can
+    const LANDLOCK_ABI: ABI = ABI::V3;
+
+    /// Syscalls refused once the server is running
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        // Running other programs
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        // Reaching into other processes
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        // Changing the filesystem layout or the system
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_adjtimex,
+        libc::SYS_syslog,
+        // Changing who the process is
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setgroups,
+        libc::SYS_personality,
+        // Kernel interfaces with a history of exploits
+        libc::SYS_bpf,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+    ];
+
+    fn error(what: &str, e: impl std::fmt::Display) -> RustbucketError {
+        RustbucketError::Sandbox(format!("{}: {}", what, e))
+    }
+
+    pub(super) fn restrict_filesystem(sandbox: &Sandbox) -> Result<Enforcement> {
+        let (all, read) = (AccessFs::from_all(LANDLOCK_ABI), AccessFs::from_read(LANDLOCK_ABI));
+        let status = Ruleset::default()
+            .handle_access(all)
+            .and_then(|ruleset| ruleset.create())
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(READ_ONLY, read)))
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(&sandbox.readable, read)))
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(&sandbox.writable, all)))
+            .and_then(|ruleset| ruleset.restrict_self())
+            .map_err(|e| error("could not restrict filesystem access", e))?;
+        Ok(match status.ruleset {
+            RulesetStatus::FullyEnforced => Enforcement::Full,
+            RulesetStatus::PartiallyEnforced => Enforcement::Partial,
+            RulesetStatus::NotEnforced => Enforcement::Unavailable,
+        })
+    }
+
+    pub(super) fn restrict_syscalls() -> Result<Enforcement> {
+        // `c_long` is narrower than seccompiler's syscall numbers on 32-bit targets
+        #[allow(clippy::unnecessary_cast)]
+        let rules = DENIED_SYSCALLS.iter().map(|&syscall| (syscall as i64, Vec::new())).collect::<BTreeMap<_, _>>();
+        let arch = std::env::consts::ARCH.try_into().map_err(|e| error("unsupported architecture", e))?;
+        let filter = SeccompFilter::new(rules, SeccompAction::Allow, SeccompAction::Errno(libc::EPERM as u32), arch)
+            .map_err(|e| error("invalid syscall filter", e))?;
+        let program: BpfProgram = filter.try_into().map_err(|e| error("could not compile the syscall filter", e))?;
+        match seccompiler::apply_filter_all_threads(&program) {
+            Ok(()) => Ok(Enforcement::Full),
+            // Kernels built without seccomp filtering
+            Err(seccompiler::Error::Seccomp(e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+                Ok(Enforcement::Unavailable)
+            }
+            Err(e) => Err(error("could not install the syscall filter", e)),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::{Enforcement, Sandbox};
+    use crate::error::Result;
+
+    pub(super) fn restrict_filesystem(_sandbox: &Sandbox) -> Result<Enforcement> {
+        Ok(Enforcement::Unavailable)
+    }
+
+    pub(super) fn restrict_syscalls() -> Result<Enforcement> {
+        Ok(Enforcement::Unavailable)
+    }
+}
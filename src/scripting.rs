@@ -0,0 +1,274 @@
+//! Request scripts: small Lua programs run on every message, inside the server.
+//!
+//! A script is a `.lua` file defining either or both of two global functions,
+//! which a [`ScriptLayer`] calls around the handler:
+//!
+//! - `on_request(message, connection_id)` runs before the handler. Returning
+//!   nothing passes the message on unchanged; returning a string passes that
+//!   instead; returning `false` and a reason answers `ERR <reason>` without
+//!   calling the handler, so scripts can validate commands.
+//! - `on_response(message, response)` runs after the handler. Returning a
+//!   string replaces the response; returning nothing keeps it.
+//!
+//! Scripts are sandboxed. Only the `string`, `table`, `math`, and `utf8`
+//! libraries are available, with no access to files, processes, or the
+//! network, and `print` writes to the server log. Each interpreter is capped
+//! at a number of bytes of memory, and each call at a number of Lua
+//! instructions; a script over either limit fails. `pcall`, `xpcall`, and
+//! coroutines are left out so that a script cannot catch those failures and
+//! carry on. The instruction limit does not count time spent inside library
+//! functions such as `string.find`.
+//!
+//! A script that fails answers the request with `ERR script failed` and logs
+//! why. Calls run in parallel on separate interpreters, each of which runs the
+//! whole script once when created, so globals set at the top level act as
+//! per-interpreter caches rather than state shared across requests.
+//!
+//! ```no_run
+//! use rustbucket::scripting::ScriptLayer;
+//! use rustbucket::Server;
+//!
+//! let mut server = Server::builder();
+//! for layer in ScriptLayer::load_dir("scripts")? {
+//!     server = server.middleware(layer);
+//! }
+//! server.build()?.run()?;
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mlua::{Function, HookTriggers, IntoLuaMulti, Lua, LuaOptions, MultiValue, StdLib, Value};
+
+use crate::error::{Result, RustbucketError};
+use crate::handler::ResponseWriter;
+use crate::middleware::{Middleware, Next, Request};
+
+/// Bytes of memory each interpreter may use by default
+pub const DEFAULT_MEMORY_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Lua instructions each call may run by default
+pub const DEFAULT_INSTRUCTION_LIMIT: u64 = 1_000_000;
+
+/// Instructions between checks of the instruction limit
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Base library functions removed from the sandbox
+const REMOVED_GLOBALS: [&str; 6] = ["collectgarbage", "dofile", "load", "loadfile", "pcall", "xpcall"];
+
+/// Runs a Lua script on every request passing through it
+pub struct ScriptLayer {
+    path: PathBuf,
+    source: String,
+    memory_limit: usize,
+    instruction_limit: u64,
+    /// Interpreters not running a call, reused so each request does not start its own
+    idle: Mutex<Vec<Interpreter>>,
+}
+
+/// One Lua state with the script loaded into it
+struct Interpreter {
+    lua: Lua,
+    /// Instructions the current call has left, counted down by the hook
+    budget: Arc<AtomicU64>,
+}
+
+impl ScriptLayer {
+    /// Loads the script at `path`, failing if it does not compile or its top level fails
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let source = fs::read_to_string(&path).map_err(|e| RustbucketError::Script {
+            path: path.clone(),
+            message: e.to_string(),
+        })?;
+        let layer = Self {
+            path,
+            source,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            instruction_limit: DEFAULT_INSTRUCTION_LIMIT,
+            idle: Mutex::new(Vec::new()),
+        };
+        let interpreter = layer.interpreter().map_err(|e| layer.error(e))?;
+        layer.idle.lock().unwrap().push(interpreter);
+        log::info!("Loaded script {}", layer.path.display());
+        Ok(layer)
+    }
+
+    /// Loads every `.lua` file in `dir`, in order of name
+    ///
+    /// Layers added in this order run the first script outermost. A missing
+    /// directory loads no scripts.
+    pub fn load_dir(dir: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let dir = dir.as_ref();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(RustbucketError::Script { path: dir.to_path_buf(), message: e.to_string() }),
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "lua"))
+            .collect();
+        paths.sort();
+        paths.into_iter().map(Self::load).collect()
+    }
+
+    /// Caps each interpreter at `bytes` of memory
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        for interpreter in self.idle.get_mut().unwrap().iter() {
+            // Only fails for Lua builds without custom allocators, which this crate does not use
+            let _ = interpreter.lua.set_memory_limit(bytes);
+        }
+        self
+    }
+
+    /// Stops each call after about `instructions` Lua instructions
+    pub fn instruction_limit(mut self, instructions: u64) -> Self {
+        self.instruction_limit = instructions;
+        self
+    }
+
+    /// The script file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates a sandboxed interpreter and runs the script's top level in it
+    fn interpreter(&self) -> mlua::Result<Interpreter> {
+        let lua = Lua::new_with(StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8, LuaOptions::default())?;
+        lua.set_memory_limit(self.memory_limit)?;
+        {
+            let globals = lua.globals();
+            for name in REMOVED_GLOBALS {
+                globals.set(name, Value::Nil)?;
+            }
+            let name = self.path.display().to_string();
+            globals.set(
+                "print",
+                lua.create_function(move |_, values: MultiValue| {
+                    let line: Vec<String> = values.iter().map(|value| value.to_string().unwrap_or_default()).collect();
+                    log::info!("[{}] {}", name, line.join("\t"));
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        let budget = Arc::new(AtomicU64::new(self.instruction_limit));
+        let left = Arc::clone(&budget);
+        lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |_, _| {
+            let step = u64::from(HOOK_INTERVAL);
+            if left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(step)).is_err() {
+                return Err(mlua::Error::runtime("instruction limit exceeded"));
+            }
+            Ok(())
+        });
+        lua.load(self.source.as_str()).set_name(self.path.display().to_string()).exec()?;
+        Ok(Interpreter { lua, budget })
+    }
+
+    /// Calls the script's global function `name` with `args` on an idle interpreter
+    ///
+    /// `Ok(None)` if the script does not define the function.
+    fn call<R>(
+        &self,
+        name: &str,
+        args: impl FnOnce(&Lua) -> mlua::Result<MultiValue>,
+        result: impl FnOnce(MultiValue) -> mlua::Result<R>,
+    ) -> mlua::Result<Option<R>> {
+        let idle = self.idle.lock().unwrap().pop();
+        let interpreter = match idle {
+            Some(interpreter) => interpreter,
+            None => self.interpreter()?,
+        };
+        let outcome = (|| {
+            let Some(function) = interpreter.lua.globals().get::<_, Option<Function>>(name)? else {
+                return Ok(None);
+            };
+            interpreter.budget.store(self.instruction_limit, Ordering::Relaxed);
+            let returned = function.call(args(&interpreter.lua)?)?;
+            result(returned).map(Some)
+        })();
+        self.idle.lock().unwrap().push(interpreter);
+        outcome
+    }
+
+    fn error(&self, error: mlua::Error) -> RustbucketError {
+        RustbucketError::Script { path: self.path.clone(), message: error.to_string() }
+    }
+
+    fn failed(&self, error: mlua::Error, response: &mut ResponseWriter) {
+        log::warn!("{}", self.error(error));
+        response.clear();
+        response.write(b"ERR script failed\n");
+    }
+}
+
+/// What `on_request` decided
+enum Verdict {
+    Pass,
+    Replace(Vec<u8>),
+    Reject(String),
+}
+
+impl Middleware for ScriptLayer {
+    fn handle(&self, request: &Request<'_>, response: &mut ResponseWriter, next: Next<'_>) {
+        let verdict = self.call(
+            "on_request",
+            |lua| (lua.create_string(request.message)?, request.connection.id()).into_lua_multi(lua),
+            |returned| {
+                let mut returned = returned.into_iter();
+                match returned.next().unwrap_or(Value::Nil) {
+                    Value::Nil | Value::Boolean(true) => Ok(Verdict::Pass),
+                    Value::String(message) => Ok(Verdict::Replace(message.as_bytes().to_vec())),
+                    Value::Boolean(false) => Ok(Verdict::Reject(match returned.next() {
+                        Some(Value::String(reason)) => reason.to_string_lossy().into_owned(),
+                        _ => "rejected by script".to_string(),
+                    })),
+                    other => Err(mlua::Error::runtime(format!(
+                        "on_request returned a {}, not a string, false, or nothing",
+                        other.type_name()
+                    ))),
+                }
+            },
+        );
+        let replaced;
+        let request = match verdict {
+            Ok(None | Some(Verdict::Pass)) => *request,
+            Ok(Some(Verdict::Replace(message))) => {
+                replaced = message;
+                request.with_message(&replaced)
+            }
+            Ok(Some(Verdict::Reject(reason))) => {
+                response.write(format!("ERR {}\n", reason).as_bytes());
+                return;
+            }
+            Err(e) => return self.failed(e, response),
+        };
+        next.run(&request, response);
+
+        let rewritten = self.call(
+            "on_response",
+            |lua| (lua.create_string(request.message)?, lua.create_string(response.as_bytes())?).into_lua_multi(lua),
+            |returned| match returned.into_iter().next().unwrap_or(Value::Nil) {
+                Value::Nil => Ok(None),
+                Value::String(reply) => Ok(Some(reply.as_bytes().to_vec())),
+                other => Err(mlua::Error::runtime(format!(
+                    "on_response returned a {}, not a string or nothing",
+                    other.type_name()
+                ))),
+            },
+        );
+        match rewritten {
+            Ok(None | Some(None)) => {}
+            Ok(Some(Some(reply))) => {
+                response.clear();
+                response.write(&reply);
+            }
+            Err(e) => self.failed(e, response),
+        }
+    }
+}
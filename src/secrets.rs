@@ -0,0 +1,123 @@
+//! Secrets such as auth tokens, kept off the command line and out of memory once dropped.
+//!
+//! A flag like `--auth-token` puts its value where any user on the host can
+//! read it, in `ps` output and `/proc/<pid>/cmdline`, and often in shell
+//! history. A [`Secret`] can instead be read from a file (`--auth-token-file`,
+//! `--tls-key-passphrase-file`) or an environment variable (`--auth-token-env`,
+//! `--tls-key-passphrase-env`). Its buffer is zeroed when it is dropped, it
+//! never shows up in `Debug` output or logs, and comparing two takes as long
+//! wherever they differ.
+//!
+//! ```no_run
+//! use rustbucket::middleware::TokenAuth;
+//! use rustbucket::secrets::Secret;
+//!
+//! let token = Secret::from_file("/etc/rustbucket/token")?;
+//! let auth = TokenAuth::new(token);
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::env;
+use std::fmt;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::error::{Result, RustbucketError};
+
+/// Permission bits that let users other than the owner read a file
+#[cfg(unix)]
+const READABLE_BY_OTHERS: u32 = 0o077;
+
+/// A value that must not leak: zeroed on drop and redacted when printed
+#[derive(Clone)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    /// Wraps a secret the caller already holds
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(Zeroizing::new(value.into()))
+    }
+
+    /// Reads a secret from the file at `path`, ignoring surrounding whitespace such as a trailing newline
+    ///
+    /// Logs a warning if users other than the owner may read the file, as
+    /// `rustbucket keygen token --out` never leaves it.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = read_file(path)?;
+        Self::parse(&contents).ok_or_else(|| invalid_file(path, "the file is empty"))
+    }
+
+    /// Reads a secret from the environment variable `name`
+    pub fn from_env(name: &str) -> Result<Self> {
+        let invalid =
+            |reason: &str| RustbucketError::InvalidConfig(format!("secret variable {}: {}", name, reason));
+        let value = Zeroizing::new(env::var(name).map_err(|_| invalid("not set, or not valid UTF-8"))?);
+        Self::parse(&value).ok_or_else(|| invalid("the variable is empty"))
+    }
+
+    /// The secret without surrounding whitespace, or `None` if nothing is left
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        (!value.is_empty()).then(|| Self::new(value))
+    }
+
+    /// The secret itself, for handing to a library
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether `candidate` is the secret, taking as long to say no wherever the first difference is
+    ///
+    /// Only a difference in length returns early, which reveals the length
+    /// but nothing about the contents.
+    pub fn matches(&self, candidate: &str) -> bool {
+        self.0.as_bytes().ct_eq(candidate.as_bytes()).into()
+    }
+}
+
+/// Reads a file holding secrets, warning if users other than the owner may read it
+pub(crate) fn read_file(path: &Path) -> Result<Zeroizing<String>> {
+    let contents = Zeroizing::new(fs::read_to_string(path).map_err(|e| invalid_file(path, &e.to_string()))?);
+    #[cfg(unix)]
+    if fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & READABLE_BY_OTHERS != 0) {
+        log::warn!("Secret file {} can be read by users other than its owner", path.display());
+    }
+    Ok(contents)
+}
+
+pub(crate) fn invalid_file(path: &Path, reason: &str) -> RustbucketError {
+    RustbucketError::InvalidConfig(format!("secret file {}: {}", path.display(), reason))
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+/// Compares in constant time, like [`Secret::matches`]
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.matches(other.expose())
+    }
+}
+
+impl Eq for Secret {}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
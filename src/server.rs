@@ -0,0 +1,1785 @@
+//! The TCP server: accepting clients, sharing state across workers, and
+//! shutting down gracefully.
+//!
+//! [`Server::run`] binds the listener, starts the optional subsystems (admin
+//! interface, exporters, alerts, heartbeat), and serves connections on a worker
+//! pool until a signal or the admin interface asks it to stop.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::fd::{AsFd, BorrowedFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(unix)]
+use nix::errno::Errno;
+#[cfg(unix)]
+use nix::poll::{poll, PollFd, PollFlags};
+#[cfg(unix)]
+use signal_hook::consts::{SIGHUP, SIGTERM, SIGUSR2};
+#[cfg(unix)]
+use signal_hook::iterator::Signals;
+
+#[cfg(feature = "admin")]
+use crate::admin;
+#[cfg(feature = "http")]
+use crate::alerts::{AlertConfig, AlertWatcher};
+use crate::access::AccessList;
+use crate::acl::CommandAcl;
+use crate::bans::{BanList, BanPolicy};
+use crate::buffers::{BufferPool, BufferPoolStats, DEFAULT_POOLED_BUFFERS};
+use crate::config::{Config, ConfigSource, FileSource};
+use crate::error::{Result, RustbucketError};
+use crate::connections::{ConnectionEntry, ConnectionRegistry, PeerCounts, PeerSlot};
+use crate::events::{EventBus, ServerEvent};
+use crate::flood::{FloodPolicy, FloodWatcher};
+use crate::async_handler::{AsyncHandler, AsyncRequestHandler};
+use crate::handler::{EchoHandler, RequestHandler};
+use crate::middleware::{CommandAclLayer, LoggingLayer, MetricsLayer, Middleware, ModeLayer, Pipeline};
+use crate::heartbeat::Heartbeat;
+use crate::memory::{MemoryBudget, MemoryUsage, Shedder};
+use crate::hooks::{HookRegistry, LifecycleHook, LogHook, ShutdownPhase};
+use crate::logging::{FileSink, LogSink};
+use crate::paths::Paths;
+use crate::pidfile::Pidfile;
+use crate::pool::WorkerPool;
+use crate::protocol::{serve_connection, ConnectionMode, Framing, MAX_MESSAGE_SIZE, MAX_READ_BUFFER_SIZE};
+use crate::protocol::{MIN_READ_BUFFER_SIZE, READ_BUFFER_SIZE};
+use crate::socket::SocketOptions;
+#[cfg(feature = "sandbox")]
+use crate::sandbox::{Enforcement, Sandbox, SandboxStatus};
+#[cfg(feature = "metrics")]
+use crate::statsd::{StatsdClient, StatsdConfig, StatsdReporter};
+use crate::telemetry::{ConnectionSpan, Metrics, MetricsSnapshot, SpanBuffer};
+#[cfg(feature = "metrics")]
+use crate::telemetry::{OtlpConfig, OtlpExporter};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsSettings};
+#[cfg(feature = "noise")]
+use crate::noise::{self, NoiseSettings};
+#[cfg(any(feature = "tls", feature = "noise"))]
+use crate::connections::Encryption;
+
+pub use crate::pool::PoolStats;
+
+/// Port clients connect to unless configured otherwise
+pub const DEFAULT_PORT: u16 = 8080;
+/// Loopback port of the admin interface unless configured otherwise
+pub const DEFAULT_ADMIN_PORT: u16 = 9090;
+/// Worker threads unless configured otherwise
+pub const DEFAULT_THREADS: usize = 4;
+/// How long a worker above the minimum waits for a connection before exiting, unless configured otherwise
+pub const DEFAULT_THREAD_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Everything a [`Server`] is configured with, collected by [`ServerBuilder`]
+#[derive(Clone)]
+struct Settings {
+    port: u16,
+    handler: Arc<dyn RequestHandler>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    threads: usize,
+    max_threads: Option<usize>,
+    thread_idle_timeout: Duration,
+    read_buffer_size: usize,
+    framing: Framing,
+    max_message_size: usize,
+    connection_mode: ConnectionMode,
+    pooled_buffers: usize,
+    #[cfg(feature = "admin")]
+    admin_port: Option<u16>,
+    #[cfg(feature = "metrics")]
+    otlp: Option<OtlpConfig>,
+    #[cfg(feature = "metrics")]
+    statsd: Option<StatsdConfig>,
+    #[cfg(feature = "http")]
+    alerts: Option<AlertConfig>,
+    heartbeat: Option<Duration>,
+    max_connections_per_ip: Option<usize>,
+    request_deadline: Option<Duration>,
+    max_reading_connections: Option<usize>,
+    socket_options: SocketOptions,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    ban_policy: Option<BanPolicy>,
+    flood_policy: Option<FloodPolicy>,
+    #[cfg(feature = "sandbox")]
+    sandbox: Option<Sandbox>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsSettings>,
+    #[cfg(feature = "noise")]
+    noise: Option<NoiseSettings>,
+    #[cfg(feature = "uring")]
+    io_uring: bool,
+    reuse_port: bool,
+    paths: Paths,
+    log_sink: Option<Arc<dyn LogSink>>,
+    config_source: Option<Arc<dyn ConfigSource>>,
+    handle_signals: bool,
+}
+
+/// Fluent construction of a [`Server`]
+///
+/// Starts from the same defaults as `rustbucket run` with no flags; optional
+/// subsystems stay off unless their method is called.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use rustbucket::ServerBuilder;
+///
+/// let server = ServerBuilder::new()
+///     .port(7000)
+///     .threads(8)
+///     .heartbeat(Duration::from_secs(30))
+///     .log_path("/var/log/rustbucket.log")
+///     .build()?;
+/// server.run()?;
+/// # Ok::<(), rustbucket::RustbucketError>(())
+/// ```
+#[derive(Clone)]
+pub struct ServerBuilder {
+    settings: Settings,
+}
+
+impl ServerBuilder {
+    /// Creates a builder with the default port, thread count, admin port, and log file
+    pub fn new() -> Self {
+        Self {
+            settings: Settings {
+                port: DEFAULT_PORT,
+                handler: Arc::new(EchoHandler),
+                middleware: Vec::new(),
+                threads: DEFAULT_THREADS,
+                max_threads: None,
+                thread_idle_timeout: DEFAULT_THREAD_IDLE_TIMEOUT,
+                read_buffer_size: READ_BUFFER_SIZE,
+                framing: Framing::default(),
+                max_message_size: MAX_MESSAGE_SIZE,
+                connection_mode: ConnectionMode::default(),
+                pooled_buffers: DEFAULT_POOLED_BUFFERS,
+                #[cfg(feature = "admin")]
+                admin_port: Some(DEFAULT_ADMIN_PORT),
+                #[cfg(feature = "metrics")]
+                otlp: None,
+                #[cfg(feature = "metrics")]
+                statsd: None,
+                #[cfg(feature = "http")]
+                alerts: None,
+                heartbeat: None,
+                max_connections_per_ip: None,
+                request_deadline: None,
+                max_reading_connections: None,
+                socket_options: SocketOptions::default(),
+                memory_budget: None,
+                ban_policy: None,
+                flood_policy: None,
+                #[cfg(feature = "sandbox")]
+                sandbox: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+                #[cfg(feature = "noise")]
+                noise: None,
+                #[cfg(feature = "uring")]
+                io_uring: false,
+                reuse_port: false,
+                paths: Paths::default(),
+                log_sink: None,
+                config_source: None,
+                handle_signals: true,
+            },
+        }
+    }
+
+    /// Port to accept clients on; 0 picks a free port
+    pub fn port(mut self, port: u16) -> Self {
+        self.settings.port = port;
+        self
+    }
+
+    /// Protocol behavior for client messages, replacing the default [`EchoHandler`]
+    pub fn handler(mut self, handler: impl RequestHandler + 'static) -> Self {
+        self.settings.handler = Arc::new(handler);
+        self
+    }
+
+    /// Async protocol behavior for client messages, replacing the default [`EchoHandler`]
+    ///
+    /// See [`AsyncRequestHandler`] for how its futures are driven.
+    pub fn async_handler(self, handler: impl AsyncRequestHandler + 'static) -> Self {
+        self.handler(AsyncHandler::new(handler))
+    }
+
+    /// Wraps the handler in another middleware layer
+    ///
+    /// Layers run in the order they are added, so the first one added sees each
+    /// request first and its response last. The server's metrics layer always
+    /// runs outside them and its logging layer inside them.
+    pub fn middleware(mut self, layer: impl Middleware + 'static) -> Self {
+        self.settings.middleware.push(Arc::new(layer));
+        self
+    }
+
+    /// Number of worker threads serving connections
+    ///
+    /// With [`max_threads`](Self::max_threads), the fewest workers kept while
+    /// the server is quiet.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.settings.threads = threads;
+        self
+    }
+
+    /// Lets the worker pool grow to `max` threads under load, instead of staying at [`threads`](Self::threads)
+    ///
+    /// Every open connection holds a worker, so a connection accepted while
+    /// all of them are busy starts another, up to `max`. Workers beyond
+    /// `threads` exit once they have gone
+    /// [`thread_idle_timeout`](Self::thread_idle_timeout) without a connection.
+    /// `max` must be at least `threads`, or the server refuses to start.
+    pub fn max_threads(mut self, max: usize) -> Self {
+        self.settings.max_threads = Some(max);
+        self
+    }
+
+    /// How long a worker beyond [`threads`](Self::threads) waits for a
+    /// connection before exiting, instead of [`DEFAULT_THREAD_IDLE_TIMEOUT`]
+    pub fn thread_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.thread_idle_timeout = timeout;
+        self
+    }
+
+    /// Reads up to `bytes` from a client at once, instead of [`READ_BUFFER_SIZE`]
+    ///
+    /// Larger buffers take bulk transfers in fewer reads, at the cost of that
+    /// much memory for every open connection. The size must be between
+    /// [`MIN_READ_BUFFER_SIZE`] and [`MAX_READ_BUFFER_SIZE`], or the server
+    /// refuses to start.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.settings.read_buffer_size = bytes;
+        self
+    }
+
+    /// How messages are cut out of what the server reads, for handlers that do not frame their own
+    ///
+    /// [`Framing::Read`], the default, handles each read as a message;
+    /// [`Framing::Line`] waits for whole lines. See [`crate::protocol`].
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.settings.framing = framing;
+        self
+    }
+
+    /// Keeps up to `bytes` of a message split across reads, instead of [`MAX_MESSAGE_SIZE`]
+    ///
+    /// A client that sends more without completing a message is sent an error
+    /// and disconnected. Must be at least [`MIN_READ_BUFFER_SIZE`], or the
+    /// server refuses to start.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.settings.max_message_size = bytes;
+        self
+    }
+
+    /// Starts every connection in `mode`, instead of [`ConnectionMode::Binary`]
+    ///
+    /// In [`ConnectionMode::Text`] a message that is not valid UTF-8 is answered
+    /// with an error saying where it goes wrong, and never reaches the handler.
+    /// Clients can switch their own connection with `MODE TEXT` or `MODE BINARY`.
+    pub fn connection_mode(mut self, mode: ConnectionMode) -> Self {
+        self.settings.connection_mode = mode;
+        self
+    }
+
+    /// Keeps up to `buffers` idle I/O buffers for new connections to reuse, as described in [`crate::buffers`]
+    pub fn buffer_pool(mut self, buffers: usize) -> Self {
+        self.settings.pooled_buffers = buffers;
+        self
+    }
+
+    /// Refuses connections from a client address that already has `connections` open
+    ///
+    /// Keeps one client from occupying every worker. A refused client is sent
+    /// an error line and disconnected without reaching a worker.
+    pub fn max_connections_per_ip(mut self, connections: usize) -> Self {
+        self.settings.max_connections_per_ip = Some(connections);
+        self
+    }
+
+    /// Closes a connection that takes longer than `deadline` to finish sending a request
+    ///
+    /// A request is finished by a newline; see [`crate::protocol`]. The
+    /// timeout between reads still applies to idle connections, but a client
+    /// trickling a request a byte at a time keeps resetting it, so this bounds
+    /// the request as a whole.
+    pub fn request_deadline(mut self, deadline: Duration) -> Self {
+        self.settings.request_deadline = Some(deadline);
+        self
+    }
+
+    /// Closes connections that start a request while `connections` others are partway through one
+    ///
+    /// Keeps slow or stalled clients from holding every worker at once.
+    pub fn max_reading_connections(mut self, connections: usize) -> Self {
+        self.settings.max_reading_connections = Some(connections);
+        self
+    }
+
+    /// Sets `options` on the socket of every connection accepted, as described in [`crate::socket`]
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.settings.socket_options = options;
+        self
+    }
+
+    /// Counts connection buffers and outboxes against `budget`, closing idle connections while it is exceeded
+    ///
+    /// Give the same budget to the KV store or message queue the handler uses,
+    /// so that their data counts too and their writes are refused once it runs
+    /// out; see [`crate::memory`].
+    pub fn memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.settings.memory_budget = Some(budget);
+        self
+    }
+
+    /// Bans client addresses that commit too many offences, as described in [`crate::bans`]
+    pub fn auto_ban(mut self, policy: BanPolicy) -> Self {
+        self.settings.ban_policy = Some(policy);
+        self
+    }
+
+    /// Goes into defensive mode when the accept rate spikes, as described in [`crate::flood`]
+    pub fn flood_protection(mut self, policy: FloodPolicy) -> Self {
+        self.settings.flood_policy = Some(policy);
+        self
+    }
+
+    /// Restricts the process to what `sandbox` allows once the server starts, as described in [`crate::sandbox`]
+    ///
+    /// Filesystem rules cover the thread calling [`Server::start`] and the
+    /// threads started after it; the syscall filter covers the whole process.
+    #[cfg(feature = "sandbox")]
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.settings.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Speaks TLS to clients, with the certificate, versions, and cipher suites in `settings`; see [`crate::tls`]
+    ///
+    /// The settings are checked when the server starts, which fails with
+    /// [`RustbucketError::InvalidConfig`] if they cannot be used.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, settings: TlsSettings) -> Self {
+        self.settings.tls = Some(settings);
+        self
+    }
+
+    /// Encrypts connections with Noise_XX, accepting the clients `settings` authorizes; see [`crate::noise`]
+    ///
+    /// Cannot be combined with [`tls`](Self::tls).
+    #[cfg(feature = "noise")]
+    pub fn noise(mut self, settings: NoiseSettings) -> Self {
+        self.settings.noise = Some(settings);
+        self
+    }
+
+    /// Serves plain TCP connections through io_uring instead of blocking reads and writes; see `src/uring.rs`
+    ///
+    /// Each worker thread runs a ring serving many connections, so handlers
+    /// that block hold up the other connections on their ring. Starting fails
+    /// with [`RustbucketError::InvalidConfig`] off Linux, when the kernel does
+    /// not allow io_uring, or alongside TLS or Noise.
+    #[cfg(feature = "uring")]
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.settings.io_uring = enabled;
+        self
+    }
+
+    /// Gives each worker a listener of its own on the port, bound with `SO_REUSEPORT`; see `src/shard.rs`
+    ///
+    /// The kernel spreads connections across the listeners, and each worker
+    /// serves the connections it accepts itself, instead of one thread
+    /// accepting them all and handing them to the pool. A connection given to a
+    /// busy worker waits for it, so this suits short-lived connections. The
+    /// pool stays at [`threads`](Self::threads) workers. Starting fails with
+    /// [`RustbucketError::InvalidConfig`] off Linux or alongside io_uring.
+    pub fn reuse_port(mut self, enabled: bool) -> Self {
+        self.settings.reuse_port = enabled;
+        self
+    }
+
+    /// Loopback port for the admin interface
+    #[cfg(feature = "admin")]
+    pub fn admin_port(mut self, port: u16) -> Self {
+        self.settings.admin_port = Some(port);
+        self
+    }
+
+    /// Runs without the admin interface
+    #[cfg(feature = "admin")]
+    pub fn disable_admin(mut self) -> Self {
+        self.settings.admin_port = None;
+        self
+    }
+
+    /// Exports traces and metrics to an OTLP/HTTP collector
+    #[cfg(feature = "metrics")]
+    pub fn otlp(mut self, otlp: OtlpConfig) -> Self {
+        self.settings.otlp = Some(otlp);
+        self
+    }
+
+    /// Emits metrics to a StatsD agent
+    #[cfg(feature = "metrics")]
+    pub fn statsd(mut self, statsd: StatsdConfig) -> Self {
+        self.settings.statsd = Some(statsd);
+        self
+    }
+
+    /// Posts webhook alerts when error thresholds are crossed
+    #[cfg(feature = "http")]
+    pub fn alerts(mut self, alerts: AlertConfig) -> Self {
+        self.settings.alerts = Some(alerts);
+        self
+    }
+
+    /// Writes a heartbeat line to the log every `interval`
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.settings.heartbeat = Some(interval);
+        self
+    }
+
+    /// Every file the server uses, in place of the defaults in the working directory
+    ///
+    /// A log sink or config source set on the builder still takes precedence
+    /// over the log and config files named here.
+    pub fn paths(mut self, paths: Paths) -> Self {
+        self.settings.paths = paths;
+        self
+    }
+
+    /// File the server appends its log to
+    pub fn log_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.paths.log_file = path.into();
+        self.settings.log_sink = None;
+        self
+    }
+
+    /// Sends the server log to `sink` instead of a file
+    pub fn log_sink(mut self, sink: impl LogSink + 'static) -> Self {
+        self.settings.log_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Memory-mapped file the server reads its configuration from
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.paths.config_file = path.into();
+        self.settings.config_source = None;
+        self
+    }
+
+    /// Where the server reads its configuration, instead of the memory-mapped config file
+    pub fn config_source(mut self, source: impl ConfigSource + 'static) -> Self {
+        self.settings.config_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Where the server records its pid for `stop` and `status`
+    pub fn pid_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.paths.pid_file = path.into();
+        self
+    }
+
+    /// Whether the server installs handlers for SIGINT, SIGTERM, SIGHUP, and SIGUSR2
+    ///
+    /// On by default; on Windows, only Ctrl+C is handled. Embedders that handle signals themselves, or run several
+    /// servers in one process, turn it off and stop servers with a [`ShutdownHandle`].
+    pub fn handle_signals(mut self, enabled: bool) -> Self {
+        self.settings.handle_signals = enabled;
+        self
+    }
+
+    /// Checks the settings and returns a server ready to run
+    pub fn build(self) -> Result<Server> {
+        if self.settings.threads == 0 {
+            return Err(RustbucketError::InvalidConfig("a server needs at least one worker thread".to_string()));
+        }
+        if let Some(max) = self.settings.max_threads.filter(|&max| max < self.settings.threads) {
+            return Err(RustbucketError::InvalidConfig(format!(
+                "the most worker threads ({}) must be at least the fewest ({})",
+                max, self.settings.threads
+            )));
+        }
+        Ok(Server { settings: self.settings })
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A TCP echo server with its operational subsystems
+///
+/// Created with [`ServerBuilder`].
+pub struct Server {
+    settings: Settings,
+}
+
+impl Server {
+    /// Shorthand for [`ServerBuilder::new`]
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Runs the server until it is shut down by a signal, the admin interface,
+    /// or a [`ShutdownHandle`]
+    ///
+    /// Blocks the calling thread; connections still open when shutdown is
+    /// requested are drained before this returns.
+    pub fn run(self) -> Result<()> {
+        self.start()?.wait();
+        Ok(())
+    }
+
+    /// Runs the server until it is shut down, as a future for async applications
+    ///
+    /// The server binds when the future is first polled and keeps its own
+    /// worker threads, so it works on any executor; the future resolves once
+    /// shutdown has drained every connection.
+    pub async fn run_async(self) -> Result<()> {
+        self.start()?.finished().await;
+        Ok(())
+    }
+
+    /// Starts the server on a background thread and returns a handle to stop it
+    ///
+    /// Returns once the server is listening, so failures to bind or to start a
+    /// subsystem are reported here rather than on the background thread.
+    pub fn start(self) -> Result<ShutdownHandle> {
+        let Settings {
+            port,
+            handler,
+            middleware,
+            threads: num_threads,
+            max_threads,
+            thread_idle_timeout,
+            read_buffer_size,
+            framing,
+            max_message_size,
+            connection_mode,
+            pooled_buffers,
+            #[cfg(feature = "admin")]
+            admin_port,
+            #[cfg(feature = "metrics")]
+            otlp,
+            #[cfg(feature = "metrics")]
+            statsd,
+            #[cfg(feature = "http")]
+            alerts,
+            heartbeat,
+            max_connections_per_ip,
+            request_deadline,
+            max_reading_connections,
+            socket_options,
+            memory_budget,
+            ban_policy,
+            flood_policy,
+            #[cfg(feature = "sandbox")]
+            sandbox,
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(feature = "noise")]
+            noise,
+            #[cfg(feature = "uring")]
+            io_uring,
+            reuse_port,
+            paths,
+            log_sink,
+            config_source,
+            handle_signals,
+        } = self.settings;
+
+        if !(MIN_READ_BUFFER_SIZE..=MAX_READ_BUFFER_SIZE).contains(&read_buffer_size) {
+            return Err(RustbucketError::InvalidConfig(format!(
+                "read buffer size must be between {} and {} bytes, got {}",
+                MIN_READ_BUFFER_SIZE, MAX_READ_BUFFER_SIZE, read_buffer_size
+            )));
+        }
+        if max_message_size < MIN_READ_BUFFER_SIZE {
+            return Err(RustbucketError::InvalidConfig(format!(
+                "largest message size must be at least {} bytes, got {}",
+                MIN_READ_BUFFER_SIZE, max_message_size
+            )));
+        }
+
+        // Load the certificate and key first, as the sandbox may not let the server read them
+        #[cfg(feature = "tls")]
+        let tls = tls.as_ref().map(|settings| settings.server_config().map(|config| (settings, config))).transpose()?;
+        #[cfg(feature = "noise")]
+        if let Some(settings) = &noise {
+            settings.validate()?;
+        }
+        #[cfg(all(feature = "tls", feature = "noise"))]
+        if tls.is_some() && noise.is_some() {
+            return Err(RustbucketError::InvalidConfig("TLS and Noise cannot both be enabled".to_string()));
+        }
+
+        #[cfg(feature = "uring")]
+        if io_uring {
+            #[cfg(feature = "tls")]
+            let encrypted = tls.is_some();
+            #[cfg(not(feature = "tls"))]
+            let encrypted = false;
+            #[cfg(feature = "noise")]
+            let encrypted = encrypted || noise.is_some();
+            check_io_uring(encrypted)?;
+        }
+        #[cfg(feature = "uring")]
+        if io_uring && reuse_port {
+            let reason = "io_uring rings share one listener and cannot be combined with --reuse-port";
+            return Err(RustbucketError::InvalidConfig(reason.to_string()));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if reuse_port {
+            return Err(RustbucketError::InvalidConfig("a listener per worker is only available on Linux".to_string()));
+        }
+
+        // Restrict filesystem access before any of the server's threads start, so they inherit it
+        #[cfg(feature = "sandbox")]
+        let filesystem = sandbox.as_ref().map(Sandbox::restrict_filesystem).transpose()?;
+
+        // Initialize server state
+        // Record our pid for `stop`; removed once the server has shut down
+        let pidfile = Pidfile::create(&paths.pid_file)?;
+
+        let log: Arc<dyn LogSink> = match log_sink {
+            Some(sink) => sink,
+            None => Arc::new(FileSink::open(&paths.log_file)?),
+        };
+        let config_source: Arc<dyn ConfigSource> = match config_source {
+            Some(source) => source,
+            None => Arc::new(FileSource::open(&paths.config_file)?),
+        };
+        let access = AccessList::load(&paths.access_file)?;
+        let commands = CommandAcl::load(&paths.acl_file)?;
+        let mut server_state = ServerState::new(num_threads, paths, log, config_source)?;
+        server_state.access = RwLock::new(Arc::new(access));
+        server_state.commands = RwLock::new(Arc::new(commands));
+        server_state.max_connections_per_ip = max_connections_per_ip;
+        server_state.request_deadline = request_deadline;
+        server_state.max_reading_connections = max_reading_connections;
+        server_state.framing = framing;
+        server_state.max_message_size = max_message_size;
+        server_state.connection_mode = connection_mode;
+        server_state.socket_options = socket_options;
+        if let Some(max) = max_threads {
+            server_state.pool = WorkerPool::scaling(num_threads, max, thread_idle_timeout);
+        }
+        if let Some(budget) = memory_budget {
+            server_state.memory = budget;
+        }
+        if let Some(policy) = ban_policy {
+            server_state.bans = BanList::new(policy);
+        }
+        server_state.flood = flood_policy;
+        server_state.buffers = Arc::new(BufferPool::new(read_buffer_size, pooled_buffers));
+        #[cfg(feature = "tls")]
+        if let Some((settings, config)) = tls {
+            let message = format!(
+                "TLS: version {} or later, {} cipher suites, certificate {}",
+                settings.min_version,
+                config.crypto_provider().cipher_suites.len(),
+                settings.cert_file.display()
+            );
+            println!("{}", message);
+            server_state.log.write(&message);
+            server_state.tls = Some(config);
+        }
+        #[cfg(feature = "noise")]
+        if let Some(settings) = noise {
+            let message = format!(
+                "Noise: public key {}, {} authorized clients",
+                noise::to_hex(&settings.public_key()),
+                settings.authorized().len()
+            );
+            println!("{}", message);
+            server_state.log.write(&message);
+            server_state.noise = Some(Arc::new(settings));
+        }
+        #[cfg(feature = "uring")]
+        {
+            server_state.io_uring = io_uring;
+        }
+        server_state.reuse_port = reuse_port;
+        let server_state = Arc::new(server_state);
+
+        // Metrics count every message; only messages that get through the
+        // configured middleware (e.g. not `AUTH` lines) are printed, and only
+        // at verbosity 2 and above
+        let mut layers: Vec<Arc<dyn Middleware>> = vec![Arc::new(MetricsLayer::new(Arc::clone(&server_state.metrics)))];
+        layers.extend(middleware);
+        layers.push(Arc::new(ModeLayer));
+        layers.push(Arc::new(CommandAclLayer));
+        layers.push(Arc::new(LoggingLayer));
+        let pipeline = Arc::new(Pipeline::new(layers, handler));
+    
+        // Set up signal handlers
+        if handle_signals {
+            setup_signal_handlers(Arc::clone(&server_state))?;
+        }
+
+        // Record connections and shutdown progress in the log file
+        server_state.hooks.register(Arc::new(LogHook::new(Arc::clone(&server_state.log))));
+        // Publish connection and shutdown events to subscribers
+        server_state.hooks.register(Arc::clone(&server_state.events) as Arc<dyn LifecycleHook>);
+
+        // Initialize config
+        let config = server_state.config_source.initialize()?;
+        server_state.config_version.store(config.version, Ordering::SeqCst);
+
+        // Start the telemetry exporter, if one was requested
+        #[cfg(feature = "metrics")]
+        let exporter = match otlp {
+            Some(otlp) => Some(OtlpExporter::start(
+                otlp,
+                Arc::clone(&server_state.metrics),
+                Arc::clone(&server_state.spans),
+                server_state.pool.clone(),
+            )?),
+            None => None,
+        };
+
+        // Emit StatsD counters periodically and connection timings as they close
+        #[cfg(feature = "metrics")]
+        let statsd_reporter = match statsd {
+            Some(statsd) => {
+                let client = Arc::new(StatsdClient::connect(&statsd)?);
+                server_state.hooks.register(Arc::clone(&client) as Arc<dyn LifecycleHook>);
+                println!("Emitting StatsD metrics to {} every {:?}", statsd.addr, statsd.interval);
+                Some(StatsdReporter::start(
+                    client,
+                    statsd.interval,
+                    Arc::clone(&server_state.metrics),
+                    server_state.pool.clone(),
+                ))
+            }
+            None => None,
+        };
+
+        // Watch error thresholds and notify the webhook when they are crossed
+        #[cfg(feature = "http")]
+        let alert_watcher = match alerts {
+            Some(alerts) => Some(AlertWatcher::start(alerts, Arc::clone(&server_state.metrics))?),
+            None => None,
+        };
+
+        // Log a heartbeat so monitoring can spot a wedged server
+        let heartbeat = heartbeat.map(|interval| {
+            Heartbeat::start(
+                interval,
+                Arc::clone(&server_state.log),
+                Arc::clone(&server_state.metrics),
+                server_state.pool.clone(),
+            )
+        });
+
+        // Close idle connections while over the memory budget
+        let shedder = server_state.memory.limit().map(|_| Shedder::start(Arc::clone(&server_state)));
+
+        // Watch the accept rate and defend against floods
+        let flood_watcher = flood_policy.map(|policy| FloodWatcher::start(policy, Arc::clone(&server_state)));
+
+        // Serve operational endpoints on their own loopback port
+        #[cfg(feature = "admin")]
+        if let Some(admin_port) = admin_port {
+            admin::start_admin_server(admin_port, Arc::clone(&server_state))
+                .map_err(|source| RustbucketError::Bind { addr: format!("127.0.0.1:{}", admin_port), source })?;
+        }
+
+        let workers = match max_threads {
+            Some(max) if max > num_threads => format!("{} to {}", num_threads, max),
+            _ => num_threads.to_string(),
+        };
+        println!("Created thread pool with {} workers", workers);
+
+        // Main server loop
+        let addr = format!("127.0.0.1:{}", port);
+        // With a listener per worker, they all share the port the first one binds
+        #[cfg(target_os = "linux")]
+        let listeners = match reuse_port {
+            true => crate::shard::bind(port, num_threads),
+            false => TcpListener::bind(&addr).map(|listener| vec![listener]),
+        };
+        #[cfg(not(target_os = "linux"))]
+        let listeners = TcpListener::bind(&addr).map(|listener| vec![listener]);
+        let listeners = listeners.map_err(|source| RustbucketError::Bind { addr, source })?;
+        let local_addr = listeners[0].local_addr()?;
+        let _ = server_state.listen_addr.set(local_addr);
+
+        // Everything is set up; drop the syscalls serving clients never needs
+        #[cfg(feature = "sandbox")]
+        if let (Some(sandbox), Some(filesystem)) = (&sandbox, filesystem) {
+            let status = SandboxStatus { filesystem, syscalls: sandbox.restrict_syscalls()? };
+            let message =
+                format!("Sandbox: filesystem rules {}, syscall filter {}", status.filesystem, status.syscalls);
+            println!("{}", message);
+            server_state.log.write(&message);
+            if status.filesystem != Enforcement::Full || status.syscalls != Enforcement::Full {
+                log::warn!("The kernel does not support the whole sandbox; the server is running less restricted");
+            }
+            let _ = server_state.sandbox.set(status);
+        }
+        // Report the port actually bound, which differs from `port` when it was 0
+        let port = local_addr.port();
+        println!("Server listening on port {} with {} worker threads", port, workers);
+        server_state.log.write(&format!("Server started on port {} with {} worker threads", port, workers));
+
+        let handle = ShutdownHandle::new(Arc::clone(&server_state), local_addr);
+        let finished = handle.clone();
+        let subsystems = Subsystems {
+            pidfile,
+            #[cfg(feature = "metrics")]
+            exporter,
+            #[cfg(feature = "metrics")]
+            statsd_reporter,
+            heartbeat,
+            shedder,
+            flood_watcher,
+            #[cfg(feature = "http")]
+            alert_watcher,
+        };
+        thread::Builder::new().name("rustbucket-accept".to_string()).spawn(move || {
+            let _finished = FinishOnDrop(finished);
+            serve(listeners, config, pipeline, server_state, subsystems);
+        })?;
+        Ok(handle)
+    }
+}
+
+/// Subsystems that run alongside the accept loop and stop after the drain
+struct Subsystems {
+    pidfile: Pidfile,
+    #[cfg(feature = "metrics")]
+    exporter: Option<OtlpExporter>,
+    #[cfg(feature = "metrics")]
+    statsd_reporter: Option<StatsdReporter>,
+    heartbeat: Option<Heartbeat>,
+    shedder: Option<Shedder>,
+    flood_watcher: Option<FloodWatcher>,
+    #[cfg(feature = "http")]
+    alert_watcher: Option<AlertWatcher>,
+}
+
+impl Subsystems {
+    /// Stops every subsystem, flushing whatever telemetry was collected during the drain
+    ///
+    /// Returns the pidfile, which is only removed once shutdown is complete.
+    fn shutdown(self) -> Pidfile {
+        #[cfg(feature = "metrics")]
+        if let Some(exporter) = self.exporter {
+            exporter.shutdown();
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(reporter) = self.statsd_reporter {
+            reporter.shutdown();
+        }
+        if let Some(heartbeat) = self.heartbeat {
+            heartbeat.shutdown();
+        }
+        if let Some(shedder) = self.shedder {
+            shedder.shutdown();
+        }
+        if let Some(watcher) = self.flood_watcher {
+            watcher.shutdown();
+        }
+        #[cfg(feature = "http")]
+        if let Some(watcher) = self.alert_watcher {
+            watcher.shutdown();
+        }
+        self.pidfile
+    }
+}
+
+/// Decides whether a new connection may be served
+///
+/// Refuses banned addresses, addresses the access list denies, and addresses
+/// at their connection cap, returning `None`; while defending against a flood,
+/// also refuses connections that would have to wait for a worker, and applies
+/// the stricter defensive cap. An admitted connection gets the place it takes
+/// up under the cap, if there is one, to hold until it closes.
+pub(crate) fn admit(server_state: &ServerState, stream: &TcpStream) -> Option<Option<PeerSlot>> {
+    let Ok(peer) = stream.peer_addr() else { return Some(None) };
+    let refuse = |counter: &AtomicU64, reason: String| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        server_state.log.write(&format!("Refused connection from {}: {}", peer, reason));
+        None
+    };
+    // Best effort: the write must not hold up the accept loop, so it is
+    // skipped if the socket cannot take the line straight away
+    let reply = |reply: String| {
+        let mut writer = stream;
+        let _ = writer.set_nonblocking(true).and_then(|()| writer.write_all(reply.as_bytes()));
+    };
+    let metrics = &server_state.metrics;
+    if server_state.bans.is_banned(peer.ip()) {
+        return refuse(&metrics.connections_banned, "the address is banned".to_string());
+    }
+    if !server_state.access.read().unwrap().check(peer.ip()) {
+        return refuse(&metrics.connections_denied, "denied by the access list".to_string());
+    }
+    let defending = server_state.defending();
+    // io_uring rings and workers with listeners of their own take every
+    // connection they accept, so nothing waits in the pool's queue
+    #[cfg(feature = "uring")]
+    let queued = defending.filter(|_| !server_state.io_uring && !server_state.reuse_port);
+    #[cfg(not(feature = "uring"))]
+    let queued = defending.filter(|_| !server_state.reuse_port);
+    if let Some(policy) = queued {
+        let stats = server_state.pool.stats();
+        // A connection will wait if every worker the pool may grow to is busy and the queue is full
+        if stats.active + stats.queued >= stats.max_workers + policy.max_queued {
+            reply("ERR server busy; try again later\n".to_string());
+            let reason = "no worker free while defending against a flood".to_string();
+            return refuse(&metrics.connections_over_queue, reason);
+        }
+    }
+    let cap = match (server_state.max_connections_per_ip, defending) {
+        (Some(cap), Some(policy)) => cap.min(policy.max_connections_per_ip),
+        (None, Some(policy)) => policy.max_connections_per_ip,
+        (Some(cap), None) => cap,
+        // Count every address's connections, so the defensive cap covers those opened before the flood
+        (None, None) if server_state.flood.is_some() => usize::MAX,
+        (None, None) => return Some(None),
+    };
+    match server_state.peer_counts.acquire(peer.ip(), cap) {
+        Some(slot) => Some(Some(slot)),
+        None => {
+            reply(format!("ERR too many connections from your address (limit {})\n", cap));
+            refuse(&metrics.connections_over_ip_cap, format!("{} connections already open from the address", cap))
+        }
+    }
+}
+
+/// Sets the configured socket options on an admitted client's socket
+///
+/// A client whose socket refuses an option is still served, with the kernel's default.
+pub(crate) fn apply_socket_options(server_state: &ServerState, stream: &TcpStream) {
+    if let Err(e) = server_state.socket_options.apply(stream) {
+        let peer = stream.peer_addr().map_or_else(|_| "a client".to_string(), |peer| peer.to_string());
+        log::warn!("Failed to set socket options for {}: {}", peer, e);
+    }
+}
+
+/// Checks that connections can be served through io_uring, given whether they are to be encrypted
+#[cfg(feature = "uring")]
+fn check_io_uring(encrypted: bool) -> Result<()> {
+    if encrypted {
+        let reason = "io_uring serves plain TCP only and cannot be combined with TLS or Noise";
+        return Err(RustbucketError::InvalidConfig(reason.to_string()));
+    }
+    #[cfg(target_os = "linux")]
+    return crate::uring::probe()
+        .map_err(|e| RustbucketError::InvalidConfig(format!("io_uring is unavailable: {}", e)));
+    #[cfg(not(target_os = "linux"))]
+    Err(RustbucketError::InvalidConfig("io_uring is only available on Linux".to_string()))
+}
+
+/// Reads the configuration for a new connection into `config`, keeping the last good one if the source fails
+pub(crate) fn refresh_config(server_state: &ServerState, config: &mut Config) {
+    match server_state.config_source.load() {
+        Ok(current_config) => {
+            note_config_version(server_state, current_config.version);
+            *config = current_config;
+        }
+        Err(e) => log::error!("Failed to read configuration, keeping version {}: {}", config.version, e),
+    }
+}
+
+/// Counts and reports a failed `accept`
+pub(crate) fn accept_failed(server_state: &ServerState, e: &io::Error) {
+    server_state.metrics.accept_errors.fetch_add(1, Ordering::Relaxed);
+    if out_of_fds(e) {
+        server_state.metrics.fd_exhaustion_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    server_state.events.publish(ServerEvent::Error {
+        id: None,
+        message: format!("accept failed: {}", e),
+    });
+    log::error!("Failed to accept connection: {}", e);
+}
+
+/// Whether `e` says the process or the system ran out of file descriptors
+#[cfg(unix)]
+fn out_of_fds(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(code) if code == Errno::EMFILE as i32 || code == Errno::ENFILE as i32)
+}
+
+#[cfg(not(unix))]
+fn out_of_fds(_e: &io::Error) -> bool {
+    false
+}
+
+/// Connections accepted at each wakeup of the accept loop before it checks for shutdown again
+///
+/// Taking every waiting client in one go gets through a connection storm with
+/// fewer wakeups, while the cap keeps a steady stream of them from holding off
+/// shutdown.
+const ACCEPT_BATCH: usize = 64;
+
+/// Accepts connections until shutdown is requested, then drains them and stops the subsystems
+///
+/// `listeners` holds one listener, or one per worker when they each accept for themselves.
+fn serve(
+    mut listeners: Vec<TcpListener>,
+    mut config: Config,
+    pipeline: Arc<Pipeline>,
+    server_state: Arc<ServerState>,
+    subsystems: Subsystems,
+) {
+    #[cfg(target_os = "linux")]
+    if server_state.reuse_port {
+        crate::shard::serve(listeners, config, &pipeline, &server_state);
+        return drain(&server_state, subsystems);
+    }
+    let listener = listeners.swap_remove(0);
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    if server_state.io_uring {
+        crate::uring::serve(listener, config, &pipeline, &server_state);
+        return drain(&server_state, subsystems);
+    }
+
+    // Without blocking, each wakeup can take every client already waiting, up to a batch
+    let batch = match listener.set_nonblocking(true) {
+        Ok(()) => ACCEPT_BATCH,
+        Err(e) => {
+            log::error!("Failed to make the listener non-blocking, accepting one connection at a time: {}", e);
+            1
+        }
+    };
+    while !server_state.shutdown_requested.load(Ordering::SeqCst) {
+        // Sleep until a client is waiting or shutdown is requested
+        if let Err(e) = wait_for_client(&listener, &server_state) {
+            accept_failed(&server_state, &e);
+            continue;
+        }
+        // Clients who connected as shutdown was requested are not served
+        if server_state.shutdown_requested.load(Ordering::SeqCst) {
+            break;
+        }
+        for _ in 0..batch {
+            match listener.accept() {
+                Ok((stream, _)) => dispatch(stream, &mut config, &pipeline, &server_state),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    accept_failed(&server_state, &e);
+                    break;
+                }
+            }
+        }
+    }
+    println!("Shutdown requested, stopping new connections...");
+
+    drain(&server_state, subsystems);
+}
+
+/// Sleeps until a client is waiting on `listener` or [`Wakeup::wake`] is called
+///
+/// An interrupted wait returns early, as if woken with nothing to accept.
+#[cfg(unix)]
+fn wait_for_client(listener: &TcpListener, server_state: &ServerState) -> io::Result<()> {
+    let mut fds = [PollFd::new(listener, PollFlags::POLLIN), PollFd::new(&server_state.wakeup, PollFlags::POLLIN)];
+    match poll(&mut fds, -1) {
+        Ok(_) | Err(Errno::EINTR) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How long the accept loop sleeps between looking for clients where it cannot `poll` for them
+#[cfg(not(unix))]
+const ACCEPT_TICK: Duration = Duration::from_millis(10);
+
+/// Sleeps for a tick, after which the accept loop looks for clients and shutdown again
+#[cfg(not(unix))]
+fn wait_for_client(_listener: &TcpListener, _server_state: &ServerState) -> io::Result<()> {
+    thread::sleep(ACCEPT_TICK);
+    Ok(())
+}
+
+/// Hands a newly accepted client to the worker pool, unless it is refused
+fn dispatch(stream: TcpStream, config: &mut Config, pipeline: &Arc<Pipeline>, server_state: &Arc<ServerState>) {
+    if let Some(serve) = prepare_connection(stream, config, pipeline, server_state) {
+        server_state.pool.execute(serve);
+    }
+}
+
+/// Admits a newly accepted client and returns the job serving it, or `None` if it is refused
+pub(crate) fn prepare_connection(
+    stream: TcpStream,
+    config: &mut Config,
+    pipeline: &Arc<Pipeline>,
+    server_state: &Arc<ServerState>,
+) -> Option<impl FnOnce() + Send + 'static> {
+    // Elsewhere, clients accepted from a non-blocking listener start out non-blocking too
+    #[cfg(not(target_os = "linux"))]
+    if let Err(e) = stream.set_nonblocking(false) {
+        accept_failed(server_state, &e);
+        return None;
+    }
+    // Refuse unwelcome clients before they take up a worker
+    let slot = admit(server_state, &stream)?;
+    apply_socket_options(server_state, &stream);
+    server_state.metrics.connection_opened();
+
+    refresh_config(server_state, config);
+
+    // Clone the Arc for the thread
+    let config_clone = Arc::new(*config);
+    let pipeline_clone = Arc::clone(pipeline);
+    let server_state_clone = Arc::clone(server_state);
+
+    Some(move || {
+        let _slot = slot;
+        let result = handle_connection(stream, config_clone, pipeline_clone, Arc::clone(&server_state_clone));
+        connection_served(&server_state_clone, result);
+    })
+}
+
+/// Waits for the connections still open to complete, then stops the subsystems
+fn drain(server_state: &ServerState, subsystems: Subsystems) {
+    println!("Waiting for active connections to complete...");
+    server_state.hooks.shutdown(ShutdownPhase::Draining);
+    server_state.pool.join();
+
+    let pidfile = subsystems.shutdown();
+
+    println!("Server shutdown complete");
+    server_state.hooks.shutdown(ShutdownPhase::Complete);
+    drop(pidfile);
+}
+
+/// Stops a server started with [`Server::start`] and waits for it to finish
+///
+/// Clones control the same server, so one can be handed to another thread (or a
+/// test) while the original waits.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<HandleInner>,
+}
+
+struct HandleInner {
+    server_state: Arc<ServerState>,
+    local_addr: SocketAddr,
+    finished: Mutex<bool>,
+    finished_changed: Condvar,
+    /// Tasks awaiting [`ShutdownHandle::finished`]
+    finished_wakers: Mutex<Vec<Waker>>,
+}
+
+impl ShutdownHandle {
+    fn new(server_state: Arc<ServerState>, local_addr: SocketAddr) -> Self {
+        Self {
+            inner: Arc::new(HandleInner {
+                server_state,
+                local_addr,
+                finished: Mutex::new(false),
+                finished_changed: Condvar::new(),
+                finished_wakers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Address the server accepts clients on, useful when it was started on port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    /// Stops accepting connections and lets open ones finish, like SIGTERM
+    pub fn shutdown_graceful(&self) {
+        let server_state = &self.inner.server_state;
+        if !server_state.shutdown_requested.swap(true, Ordering::SeqCst) {
+            println!("Shutdown requested, initiating graceful shutdown...");
+            server_state.hooks.shutdown(ShutdownPhase::Requested);
+        }
+        server_state.close_idle_connections();
+        server_state.wakeup.wake();
+    }
+
+    /// Stops accepting connections and closes open ones without waiting for them
+    pub fn shutdown_now(&self) {
+        let server_state = &self.inner.server_state;
+        // Forced first, so that idle clients woken by the graceful part are not sent a closing notice
+        server_state.force_shutdown.store(true, Ordering::SeqCst);
+        self.shutdown_graceful();
+        for connection in server_state.connections.list() {
+            connection.close();
+        }
+    }
+
+    /// Blocks until the server has shut down and drained its connections
+    pub fn wait(&self) {
+        let mut finished = self.inner.finished.lock().unwrap();
+        while !*finished {
+            finished = self.inner.finished_changed.wait(finished).unwrap();
+        }
+    }
+
+    /// Whether the server has finished shutting down
+    pub fn is_finished(&self) -> bool {
+        *self.inner.finished.lock().unwrap()
+    }
+
+    /// Whether the shutdown was forced, cutting off connections rather than letting them finish
+    ///
+    /// Set by [`shutdown_now`](Self::shutdown_now) and by a second shutdown
+    /// signal or admin drain request.
+    pub fn was_forced(&self) -> bool {
+        self.inner.server_state.force_shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the server has shut down; the async counterpart of [`wait`](Self::wait)
+    pub fn finished(&self) -> Finished {
+        Finished { handle: self.clone() }
+    }
+
+    /// Current values of the server's activity counters
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.inner.server_state.metrics.snapshot()
+    }
+
+    /// Re-reads the configuration, access list, and command ACL and reopens log files, as SIGHUP does
+    pub fn reload(&self) -> Result<Config> {
+        reload(&self.inner.server_state)
+    }
+
+    /// Bytes held for clients, by what holds them, against the memory budget
+    pub fn memory(&self) -> MemoryUsage {
+        self.inner.server_state.memory.usage()
+    }
+
+    /// How many workers there are, and how busy they are
+    pub fn workers(&self) -> PoolStats {
+        self.inner.server_state.pool.stats()
+    }
+
+    /// How the connections' I/O buffers are being reused
+    pub fn buffers(&self) -> BufferPoolStats {
+        self.inner.server_state.buffers.stats()
+    }
+
+    /// What the server was sandboxed by, or `None` if it was started without a sandbox
+    #[cfg(feature = "sandbox")]
+    pub fn sandbox_status(&self) -> Option<SandboxStatus> {
+        self.inner.server_state.sandbox.get().copied()
+    }
+
+    /// The command ACL in effect, with each rule's hit count
+    pub fn command_acl(&self) -> Arc<CommandAcl> {
+        Arc::clone(&self.inner.server_state.commands.read().unwrap())
+    }
+
+    /// The access list in effect, with each rule's hit count
+    pub fn access_list(&self) -> Arc<AccessList> {
+        Arc::clone(&self.inner.server_state.access.read().unwrap())
+    }
+}
+
+/// Marks a [`ShutdownHandle`] finished when the accept thread ends, even by panicking
+struct FinishOnDrop(ShutdownHandle);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        *self.0.inner.finished.lock().unwrap() = true;
+        self.0.inner.finished_changed.notify_all();
+        for waker in self.0.inner.finished_wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by [`ShutdownHandle::finished`]
+pub struct Finished {
+    handle: ShutdownHandle,
+}
+
+impl Future for Finished {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Registering under the `finished` lock means the waker cannot miss the wake-up
+        let finished = self.handle.inner.finished.lock().unwrap();
+        if *finished {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.handle.inner.finished_wakers.lock().unwrap();
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Wakes threads sleeping in `poll` for clients, so that they see shutdown was requested
+///
+/// A byte written to one end of a socket pair leaves the other end readable for
+/// good, since nothing reads it: every thread polling it wakes, however many
+/// there are and whenever they start to wait. Unlike a flag, it reaches a thread
+/// already asleep; unlike connecting to the listener, it works whatever the
+/// listener is bound to and leaves no connection behind to be accepted.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct Wakeup {
+    readable: UnixStream,
+    writable: UnixStream,
+}
+
+#[cfg(unix)]
+impl Wakeup {
+    fn new() -> io::Result<Self> {
+        let (readable, writable) = UnixStream::pair()?;
+        writable.set_nonblocking(true)?;
+        Ok(Self { readable, writable })
+    }
+
+    /// Wakes every thread polling for it, now and from now on
+    pub(crate) fn wake(&self) {
+        // Once one byte is waiting, a full buffer is as good as another byte
+        let _ = (&self.writable).write(&[1]);
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for Wakeup {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.readable.as_fd()
+    }
+}
+
+/// Without a socket pair to poll, the accept loop notices shutdown on its next tick instead
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub(crate) struct Wakeup;
+
+#[cfg(not(unix))]
+impl Wakeup {
+    fn new() -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    pub(crate) fn wake(&self) {}
+}
+
+/// Server state shared across threads
+#[derive(Debug)]
+pub(crate) struct ServerState {
+    /// Flag indicating if a shutdown has been requested
+    pub(crate) shutdown_requested: AtomicBool,
+    /// Flag for forcing immediate shutdown
+    pub(crate) force_shutdown: AtomicBool,
+    /// Wakes threads waiting for clients once shutdown is requested
+    pub(crate) wakeup: Wakeup,
+    /// Activity counters reported by the exporters
+    pub(crate) metrics: Arc<Metrics>,
+    /// Finished connection spans awaiting export
+    pub(crate) spans: Arc<SpanBuffer>,
+    /// Connections currently being served
+    pub(crate) connections: ConnectionRegistry,
+    /// Workers handling client connections
+    pub(crate) pool: WorkerPool,
+    /// Callbacks for connection and shutdown events
+    pub(crate) hooks: HookRegistry,
+    /// Typed event stream for subscribers
+    pub(crate) events: Arc<EventBus>,
+    /// Version of the configuration the server last picked up
+    pub(crate) config_version: AtomicU32,
+    /// Address of the client listener, once bound
+    pub(crate) listen_addr: OnceLock<SocketAddr>,
+    /// Files the server was configured with
+    pub(crate) paths: Paths,
+    /// Where lifecycle events and state dumps are logged
+    pub(crate) log: Arc<dyn LogSink>,
+    /// Where connections, reloads, and the admin interface read the configuration
+    pub(crate) config_source: Arc<dyn ConfigSource>,
+    /// Rules deciding which client addresses may connect, replaced on reload
+    pub(crate) access: RwLock<Arc<AccessList>>,
+    /// Which commands each client may run, replaced on reload
+    pub(crate) commands: RwLock<Arc<CommandAcl>>,
+    /// Connections one client address may have open at once, if capped
+    pub(crate) max_connections_per_ip: Option<usize>,
+    /// Open connections by client address
+    pub(crate) peer_counts: PeerCounts,
+    /// Time a connection may take over one request, if limited
+    pub(crate) request_deadline: Option<Duration>,
+    /// Connections that may be partway through a request at once, if capped
+    pub(crate) max_reading_connections: Option<usize>,
+    /// Connections partway through a request
+    pub(crate) connections_reading: AtomicUsize,
+    /// How messages are cut out of reads for handlers that leave it to the server
+    pub(crate) framing: Framing,
+    /// Most bytes kept toward a message split across reads
+    pub(crate) max_message_size: usize,
+    /// Mode connections start in, until they switch with `MODE`
+    pub(crate) connection_mode: ConnectionMode,
+    /// Options set on every accepted socket
+    pub(crate) socket_options: SocketOptions,
+    /// Bytes held for clients, unlimited unless the builder was given a budget
+    pub(crate) memory: Arc<MemoryBudget>,
+    /// Read and reply buffers for connections to reuse
+    pub(crate) buffers: Arc<BufferPool>,
+    /// Offences and temporary bans by client address
+    pub(crate) bans: BanList,
+    /// How to defend against connection floods, if the server watches for them
+    pub(crate) flood: Option<FloodPolicy>,
+    /// What the server was sandboxed by, if it was
+    #[cfg(feature = "sandbox")]
+    pub(crate) sandbox: OnceLock<SandboxStatus>,
+    /// How to speak TLS to clients, if the server does
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Arc<rustls::ServerConfig>>,
+    /// How to speak Noise to clients, if the server does
+    #[cfg(feature = "noise")]
+    pub(crate) noise: Option<Arc<NoiseSettings>>,
+    /// Whether connections are served through io_uring
+    #[cfg(feature = "uring")]
+    pub(crate) io_uring: bool,
+    /// Whether each worker accepts on a listener of its own
+    pub(crate) reuse_port: bool,
+    /// Wall-clock time the server started
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) started_at: SystemTime,
+    /// Monotonic start time, for computing uptime
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) started: Instant,
+}
+
+impl ServerState {
+    /// Creates a new ServerState with default values, a pool of `num_threads` workers, and `log` as the server log
+    pub(crate) fn new(
+        num_threads: usize,
+        paths: Paths,
+        log: Arc<dyn LogSink>,
+        config_source: Arc<dyn ConfigSource>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            shutdown_requested: AtomicBool::new(false),
+            force_shutdown: AtomicBool::new(false),
+            wakeup: Wakeup::new()?,
+            metrics: Arc::new(Metrics::new()),
+            spans: Arc::new(SpanBuffer::new()),
+            connections: ConnectionRegistry::new(),
+            pool: WorkerPool::new(num_threads),
+            hooks: HookRegistry::new(),
+            events: Arc::new(EventBus::new()),
+            config_version: AtomicU32::new(0),
+            listen_addr: OnceLock::new(),
+            paths,
+            log,
+            config_source,
+            access: RwLock::new(Arc::new(AccessList::new())),
+            commands: RwLock::new(Arc::new(CommandAcl::new())),
+            max_connections_per_ip: None,
+            peer_counts: PeerCounts::default(),
+            request_deadline: None,
+            max_reading_connections: None,
+            connections_reading: AtomicUsize::new(0),
+            framing: Framing::default(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            connection_mode: ConnectionMode::default(),
+            socket_options: SocketOptions::default(),
+            memory: Arc::new(MemoryBudget::unlimited()),
+            buffers: Arc::new(BufferPool::new(READ_BUFFER_SIZE, DEFAULT_POOLED_BUFFERS)),
+            bans: BanList::disabled(),
+            flood: None,
+            #[cfg(feature = "sandbox")]
+            sandbox: OnceLock::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "noise")]
+            noise: None,
+            #[cfg(feature = "uring")]
+            io_uring: false,
+            reuse_port: false,
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+        })
+    }
+
+    /// How the server is defending itself, while it is in defensive mode for a connection flood
+    pub(crate) fn defending(&self) -> Option<&FloodPolicy> {
+        self.flood.as_ref().filter(|_| self.metrics.defensive_mode.load(Ordering::Relaxed))
+    }
+
+    /// Wakes the workers waiting for their client's next request, so that idle
+    /// connections close now rather than at their read timeout
+    ///
+    /// Connections partway through a request are left to finish it.
+    pub(crate) fn close_idle_connections(&self) {
+        // Rings never block in a read, and close their idle connections on their next tick
+        #[cfg(feature = "uring")]
+        if self.io_uring {
+            return;
+        }
+        for connection in self.connections.list() {
+            connection.wake_if_waiting();
+        }
+    }
+}
+
+/// Sets up signal handlers for graceful shutdown
+fn setup_signal_handlers(server_state: Arc<ServerState>) -> io::Result<()> {
+    let server_state_clone = Arc::clone(&server_state);
+    
+    // Handle SIGINT (Ctrl+C)
+    ctrlc::set_handler(move || request_shutdown(&server_state_clone, "SIGINT")).map_err(io::Error::other)?;
+
+    #[cfg(unix)]
+    handle_unix_signals(server_state)?;
+    Ok(())
+}
+
+/// Handles SIGTERM like SIGINT, SIGHUP by reloading, and SIGUSR2 by dumping a state snapshot to the log
+#[cfg(unix)]
+fn handle_unix_signals(server_state: Arc<ServerState>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGHUP, SIGUSR2])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGTERM => request_shutdown(&server_state, "SIGTERM"),
+                SIGHUP => {
+                    println!("SIGHUP received, reloading");
+                    if let Err(e) = reload(&server_state) {
+                        log::error!("Reload failed: {}", e);
+                    }
+                }
+                _ => {
+                    println!("SIGUSR2 received, dumping server state to the log");
+                    dump_state(&server_state);
+                }
+            }
+        }
+    });
+    
+    Ok(())
+}
+
+/// Starts a graceful shutdown, or forces one if a shutdown is already underway
+///
+/// `source` names what asked for the shutdown, e.g. the signal.
+pub(crate) fn request_shutdown(server_state: &ServerState, source: &str) {
+    if server_state.shutdown_requested.load(Ordering::SeqCst) {
+        println!("Second {} received, forcing shutdown...", source);
+        server_state.force_shutdown.store(true, Ordering::SeqCst);
+        for connection in server_state.connections.list() {
+            connection.close();
+        }
+    } else {
+        println!("{} received, initiating graceful shutdown...", source);
+        server_state.shutdown_requested.store(true, Ordering::SeqCst);
+        server_state.hooks.shutdown(ShutdownPhase::Requested);
+        server_state.close_idle_connections();
+        server_state.wakeup.wake();
+    }
+}
+
+/// Re-reads the configuration, access list, and command ACL and reopens log files
+///
+/// Triggered by SIGHUP or the admin `/reload` endpoint. Returns the
+/// configuration now in effect. An access list or ACL that fails to parse
+/// leaves the previous ones in place.
+pub(crate) fn reload(server_state: &ServerState) -> Result<Config> {
+    let result = server_state.config_source.load().and_then(|config| {
+        note_config_version(server_state, config.version);
+        reload_access(server_state)?;
+        server_state.hooks.reload()?;
+        Ok(config)
+    });
+    match &result {
+        Ok(config) => server_state.log.write(&format!(
+            "Reloaded configuration (version {}), access list, and command ACL and reopened log files",
+            config.version
+        )),
+        Err(e) => server_state.log.write(&format!("Reload failed: {}", e)),
+    }
+    result
+}
+
+/// Replaces the access list and command ACL with the rules now in their files
+///
+/// Both files are parsed before either is replaced, so a mistake in one leaves both as they were.
+fn reload_access(server_state: &ServerState) -> Result<()> {
+    let access = AccessList::load(&server_state.paths.access_file)?;
+    let commands = CommandAcl::load(&server_state.paths.acl_file)?;
+    let mut current = server_state.access.write().unwrap();
+    access.carry_hits(&current);
+    *current = Arc::new(access);
+    let mut current = server_state.commands.write().unwrap();
+    commands.carry_hits(&current);
+    *current = Arc::new(commands);
+    Ok(())
+}
+
+/// Records the configuration version in use, announcing it if it changed
+pub(crate) fn note_config_version(server_state: &ServerState, version: u32) {
+    if server_state.config_version.swap(version, Ordering::SeqCst) != version {
+        server_state.events.publish(ServerEvent::ConfigReloaded { version });
+    }
+}
+
+/// Writes a full snapshot of the server's state to the log file
+///
+/// Covers flags, configuration, counters, the worker pool, and every open
+/// connection, so a wedged server can be inspected without attaching a debugger.
+#[cfg(unix)]
+fn dump_state(server_state: &ServerState) {
+    let mut lines = vec!["=== BEGIN STATE DUMP ===".to_string()];
+
+    lines.push(format!(
+        "state: shutdown_requested={} force_shutdown={}",
+        server_state.shutdown_requested.load(Ordering::SeqCst),
+        server_state.force_shutdown.load(Ordering::SeqCst)
+    ));
+
+    lines.push(format!(
+        "paths: log={} config={} pid={}",
+        server_state.paths.log_file.display(),
+        server_state.paths.config_file.display(),
+        server_state.paths.pid_file.display()
+    ));
+
+    match server_state.config_source.load() {
+        Ok(config) => lines.push(format!(
+            "config: verbosity={} max_connections={} timeout_seconds={} version={}",
+            config.verbosity, config.max_connections, config.timeout_seconds, config.version
+        )),
+        Err(e) => lines.push(format!("config: unavailable ({})", e)),
+    }
+
+    let metrics = server_state.metrics.snapshot();
+    lines.push(format!(
+        "counters: accepted={} active={} closed={} accept_errors={} fd_exhaustion_errors={} handler_errors={} messages={} bytes_received={} bytes_sent={} throttled={}",
+        metrics.connections_accepted,
+        metrics.connections_active,
+        metrics.connections_closed,
+        metrics.accept_errors,
+        metrics.fd_exhaustion_errors,
+        metrics.handler_errors,
+        metrics.messages_received,
+        metrics.bytes_received,
+        metrics.bytes_sent,
+        metrics.requests_throttled
+    ));
+
+    let pool = server_state.pool.stats();
+    lines.push(format!(
+        "pool: workers={} active={} queued={} executed={} panicked={}",
+        pool.workers, pool.active, pool.queued, pool.executed, pool.panicked
+    ));
+
+    let memory = server_state.memory.usage();
+    lines.push(format!(
+        "memory: used={} limit={} buffers={} queues={} kv={} rejected={} shed={}",
+        memory.total(),
+        memory.limit.map_or_else(|| "none".to_string(), |limit| limit.to_string()),
+        memory.buffers,
+        memory.queues,
+        memory.kv,
+        memory.rejected,
+        memory.shed
+    ));
+
+    let connections = server_state.connections.list();
+    lines.push(format!("connections: {} open", connections.len()));
+    for conn in &connections {
+        lines.push(format!(
+            "  #{} peer={} age={:.1}s idle={:.1}s messages={} bytes_received={} bytes_sent={}",
+            conn.id,
+            conn.peer,
+            conn.age().as_secs_f64(),
+            conn.idle().as_secs_f64(),
+            conn.messages.load(Ordering::Relaxed),
+            conn.bytes_received.load(Ordering::Relaxed),
+            conn.bytes_sent.load(Ordering::Relaxed)
+        ));
+    }
+
+    lines.push("=== END STATE DUMP ===".to_string());
+
+    // Written as one batch so the dump isn't interleaved with other writers
+    server_state.log.write_batch(&lines);
+}
+
+/// Handles a single client connection, registering it and recording it as a span
+fn handle_connection(
+    stream: TcpStream,
+    config: Arc<Config>,
+    pipeline: Arc<Pipeline>,
+    server_state: Arc<ServerState>,
+) -> io::Result<()> {
+    let connection = OpenConnection::open(&stream, &server_state);
+    let result = serve_client(stream, &config, &pipeline, &server_state, &connection.entry);
+    connection.close(&pipeline, &server_state, result.as_ref().err());
+    result
+}
+
+/// A registered client connection, recorded as a span until it closes
+pub(crate) struct OpenConnection {
+    pub(crate) entry: Arc<ConnectionEntry>,
+    span: ConnectionSpan,
+}
+
+impl OpenConnection {
+    /// Registers the client on `stream` and tells the hooks it connected
+    pub(crate) fn open(stream: &TcpStream, server_state: &ServerState) -> Self {
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown".to_string());
+        let span = ConnectionSpan::start(peer.clone());
+        let entry = server_state.connections.register(peer, stream.try_clone().ok());
+        server_state.hooks.connected(&entry);
+        Self { entry, span }
+    }
+
+    /// Unregisters the connection once it is no longer served, with the error that ended it if any
+    pub(crate) fn close(mut self, pipeline: &Pipeline, server_state: &ServerState, error: Option<&io::Error>) {
+        self.entry.close_outbox();
+        pipeline.closed(self.entry.id);
+
+        server_state.connections.unregister(self.entry.id);
+        server_state.hooks.disconnected(&self.entry, error);
+        self.span.finish(&self.entry, error.map(|e| e.to_string()));
+        server_state.spans.record(self.span);
+    }
+}
+
+/// Counts a connection that is done with, logging the error that ended it if any
+pub(crate) fn connection_served(server_state: &ServerState, result: io::Result<()>) {
+    if let Err(e) = result {
+        server_state.metrics.handler_errors.fetch_add(1, Ordering::Relaxed);
+        log::error!("Error handling connection: {}", e);
+    }
+    server_state.metrics.connection_closed();
+}
+
+/// Serves `stream`, first wrapping it in whatever encryption the server speaks
+fn serve_client(
+    stream: TcpStream,
+    config: &Config,
+    pipeline: &Pipeline,
+    server_state: &ServerState,
+    connection: &Arc<ConnectionEntry>,
+) -> io::Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(tls_config) = &server_state.tls {
+        let stream = tls::accept(tls_config, stream)?;
+        connection.set_encryption(Encryption::Tls(Arc::clone(stream.session())));
+        return serve_connection(stream, config, pipeline, server_state, connection);
+    }
+    #[cfg(feature = "noise")]
+    if let Some(settings) = &server_state.noise {
+        let (stream, name) = noise::accept(stream, settings)?;
+        connection.set_identity(name.to_string());
+        connection.set_encryption(Encryption::Noise(Arc::clone(stream.session())));
+        return serve_connection(stream, config, pipeline, server_state, connection);
+    }
+    serve_connection(stream, config, pipeline, server_state, connection)
+}
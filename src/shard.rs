@@ -0,0 +1,129 @@
+//! Accepting connections on a listener per worker, with `SO_REUSEPORT`.
+//!
+//! By default one thread accepts every connection and queues it for the
+//! worker pool, so each connection passes between two threads before it is
+//! served. With [`ServerBuilder::reuse_port`](crate::ServerBuilder::reuse_port)
+//! each worker instead binds a listener of its own to the same port, and the
+//! kernel spreads incoming connections across the listeners. A worker accepts
+//! from its own listener and serves what it accepted itself, so a connection
+//! stays on the thread, and the CPU caches, that took it from the kernel.
+//!
+//! The kernel picks a listener by hashing the client's address and port, not
+//! by which worker is free: a connection given to a worker that is busy with
+//! another waits in that worker's backlog until it is done. Sharding therefore
+//! suits clients that connect, make a few requests, and close, rather than
+//! long-lived connections such as chat sessions or subscriptions.
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use nix::errno::Errno;
+use nix::libc::{self, c_int};
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::config::Config;
+use crate::middleware::Pipeline;
+use crate::server::{accept_failed, prepare_connection, ServerState};
+
+/// Connections the kernel queues on each listener before refusing more
+const BACKLOG: c_int = 128;
+
+/// Binds `count` listeners to `port` on loopback, the first picking the port if it is 0 and the rest sharing it
+pub(crate) fn bind(port: u16, count: usize) -> io::Result<Vec<TcpListener>> {
+    let first = bind_one(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))?;
+    let SocketAddr::V4(addr) = first.local_addr()? else { unreachable!("bound to an IPv4 address") };
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind_one(addr)?);
+    }
+    Ok(listeners)
+}
+
+/// Binds a listener that shares its port with the others bound the same way
+fn bind_one(addr: SocketAddrV4) -> io::Result<TcpListener> {
+    // SAFETY: plain system calls on a descriptor owned from creation
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+        for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            let on: c_int = 1;
+            let len = mem::size_of::<c_int>() as libc::socklen_t;
+            if libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, option, (&on as *const c_int).cast(), len) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let sockaddr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: addr.port().to_be(),
+            sin_addr: libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() },
+            ..mem::zeroed()
+        };
+        let len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        if libc::bind(fd.as_raw_fd(), (&sockaddr as *const libc::sockaddr_in).cast(), len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::listen(fd.as_raw_fd(), BACKLOG) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TcpListener::from(fd))
+    }
+}
+
+/// Accepts and serves connections on a worker per listener, returning once they have all stopped accepting
+///
+/// Each worker finishes the connection it is serving before it notices shutdown.
+pub(crate) fn serve(
+    listeners: Vec<TcpListener>,
+    config: Config,
+    pipeline: &Arc<Pipeline>,
+    server_state: &Arc<ServerState>,
+) {
+    let (accepting_tx, accepting_rx) = mpsc::channel::<()>();
+    for listener in listeners {
+        let pipeline = Arc::clone(pipeline);
+        let state = Arc::clone(server_state);
+        let accepting = accepting_tx.clone();
+        server_state.pool.execute(move || {
+            let _accepting = accepting;
+            accept(listener, config, &pipeline, &state);
+        });
+    }
+    drop(accepting_tx);
+    // Each worker drops its sender once it stops accepting
+    for () in accepting_rx {}
+    println!("Shutdown requested, stopping new connections...");
+}
+
+/// Serves the connections accepted on `listener`, one at a time, until shutdown is requested
+fn accept(listener: TcpListener, mut config: Config, pipeline: &Arc<Pipeline>, server_state: &Arc<ServerState>) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        return log::error!("Failed to make a worker's listener non-blocking: {}", e);
+    }
+    while !server_state.shutdown_requested.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Some(serve) = prepare_connection(stream, &mut config, pipeline, server_state) {
+                    server_state.pool.run(serve);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // Sleep until a client is waiting or shutdown is requested
+                let wakeup = PollFd::new(&server_state.wakeup, PollFlags::POLLIN);
+                let mut fds = [PollFd::new(&listener, PollFlags::POLLIN), wakeup];
+                match poll(&mut fds, -1) {
+                    Ok(_) | Err(Errno::EINTR) => {}
+                    Err(e) => accept_failed(server_state, &e.into()),
+                }
+            }
+            Err(e) => accept_failed(server_state, &e),
+        }
+    }
+}
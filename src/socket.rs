@@ -0,0 +1,138 @@
+//! Socket options set on every connection the server accepts.
+//!
+//! The kernel's defaults suit most clients but not every workload: a client
+//! trading small requests and replies waits on Nagle's algorithm unless
+//! `TCP_NODELAY` is set, bulk transfers go faster with larger socket buffers,
+//! and a client that vanished without closing is only noticed by keepalive
+//! probes. The [`SocketOptions`] given to
+//! [`ServerBuilder::socket_options`](crate::ServerBuilder::socket_options) are
+//! set on each accepted socket before it is served; options left unset keep
+//! the kernel's default.
+
+use std::io;
+#[cfg(unix)]
+use std::mem;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+#[cfg(unix)]
+use nix::libc::{self, c_int};
+
+/// Idle time before the first keepalive probe by default
+pub const DEFAULT_KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+/// Time between keepalive probes by default
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Unanswered keepalive probes before a connection is dropped by default
+pub const DEFAULT_KEEPALIVE_RETRIES: u32 = 5;
+
+/// The option setting the idle time before the first keepalive probe
+#[cfg(all(unix, any(target_os = "macos", target_os = "ios")))]
+const TCP_KEEPIDLE: c_int = libc::TCP_KEEPALIVE;
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+const TCP_KEEPIDLE: c_int = libc::TCP_KEEPIDLE;
+
+/// Options for the sockets of accepted connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sends small writes straight away rather than holding them to coalesce (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// Bytes the kernel may buffer for reading (`SO_RCVBUF`); Linux doubles it for its own bookkeeping
+    pub recv_buffer: Option<usize>,
+    /// Bytes the kernel may buffer for sending (`SO_SNDBUF`); Linux doubles it for its own bookkeeping
+    pub send_buffer: Option<usize>,
+    /// How long closing waits for unsent data to go out (`SO_LINGER`)
+    ///
+    /// Zero discards unsent data and resets the connection rather than closing
+    /// it cleanly, except when serving through io_uring, which shuts every
+    /// connection down before closing it.
+    pub linger: Option<Duration>,
+    /// Probes for clients that went away without closing (`SO_KEEPALIVE`)
+    pub keepalive: Option<Keepalive>,
+}
+
+/// When to probe an idle connection, and when to give up on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe, in whole seconds (`TCP_KEEPIDLE`)
+    pub idle: Duration,
+    /// Time between probes, in whole seconds (`TCP_KEEPINTVL`)
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped (`TCP_KEEPCNT`)
+    pub retries: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self { idle: DEFAULT_KEEPALIVE_IDLE, interval: DEFAULT_KEEPALIVE_INTERVAL, retries: DEFAULT_KEEPALIVE_RETRIES }
+    }
+}
+
+impl SocketOptions {
+    /// Sets the options on `stream`, stopping at the first the kernel refuses
+    ///
+    /// Outside Unix only `nodelay` can be set; asking for any other option fails.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        self.apply_socket_level(stream)
+    }
+
+    #[cfg(unix)]
+    fn apply_socket_level(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(bytes) = self.recv_buffer {
+            set_option(stream, libc::SOL_SOCKET, libc::SO_RCVBUF, clamp(bytes))?;
+        }
+        if let Some(bytes) = self.send_buffer {
+            set_option(stream, libc::SOL_SOCKET, libc::SO_SNDBUF, clamp(bytes))?;
+        }
+        if let Some(linger) = self.linger {
+            let linger = libc::linger { l_onoff: 1, l_linger: clamp(linger.as_secs()) };
+            set_option(stream, libc::SOL_SOCKET, libc::SO_LINGER, linger)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            set_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1 as c_int)?;
+            // The kernel rejects zero for any of these
+            set_option(stream, libc::IPPROTO_TCP, TCP_KEEPIDLE, clamp(keepalive.idle.as_secs().max(1)))?;
+            set_option(stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, clamp(keepalive.interval.as_secs().max(1)))?;
+            set_option(stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, clamp(keepalive.retries.max(1)))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply_socket_level(&self, _stream: &TcpStream) -> io::Result<()> {
+        let buffers = self.recv_buffer.is_some() || self.send_buffer.is_some();
+        if buffers || self.linger.is_some() || self.keepalive.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "only TCP_NODELAY can be set on this platform"));
+        }
+        Ok(())
+    }
+}
+
+/// `value` as a C int, saturating at the largest one
+#[cfg(unix)]
+fn clamp(value: impl TryInto<c_int>) -> c_int {
+    value.try_into().unwrap_or(c_int::MAX)
+}
+
+#[cfg(unix)]
+fn set_option<T>(stream: &TcpStream, level: c_int, name: c_int, value: T) -> io::Result<()> {
+    // SAFETY: the pointer and length describe `value`, which outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            (&value as *const T).cast(),
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,154 @@
+//! Optional StatsD/DogStatsD metric emission over UDP.
+//!
+//! Counters and gauges are flushed periodically as deltas from the in-process
+//! [`Metrics`]; connection durations are sent as timings when each connection
+//! closes. Tags use the DogStatsD `|#key:value` extension and are omitted when
+//! none are configured, so plain StatsD servers work too.
+
+use std::io;
+use std::net::UdpSocket;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::connections::ConnectionEntry;
+use crate::error::{Result, RustbucketError};
+use crate::hooks::LifecycleHook;
+use crate::pool::WorkerPool;
+use crate::telemetry::{Metrics, MetricsSnapshot};
+
+/// Settings for the StatsD emitter
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// Address of the StatsD agent, e.g. `127.0.0.1:8125`
+    pub addr: String,
+    /// Prefix prepended to every metric name
+    pub prefix: String,
+    /// Tags attached to every metric, as `key:value`
+    pub tags: Vec<String>,
+    /// How often counters and gauges are flushed
+    pub interval: Duration,
+}
+
+/// Fire-and-forget UDP client formatting StatsD lines
+#[derive(Debug)]
+pub struct StatsdClient {
+    socket: UdpSocket,
+    prefix: String,
+    tag_suffix: String,
+}
+
+impl StatsdClient {
+    /// Binds an ephemeral UDP socket and connects it to the agent
+    pub fn connect(config: &StatsdConfig) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(RustbucketError::Network)?;
+        socket.connect(&config.addr).map_err(RustbucketError::Network)?;
+        let tag_suffix = if config.tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", config.tags.join(","))
+        };
+        Ok(Self {
+            socket,
+            prefix: config.prefix.trim_end_matches('.').to_string(),
+            tag_suffix,
+        })
+    }
+
+    fn send(&self, name: &str, value: impl std::fmt::Display, kind: &str) {
+        let line = if self.prefix.is_empty() {
+            format!("{}:{}|{}{}", name, value, kind, self.tag_suffix)
+        } else {
+            format!("{}.{}:{}|{}{}", self.prefix, name, value, kind, self.tag_suffix)
+        };
+        // UDP metrics are best-effort; a missing agent must never affect the server
+        let _ = self.socket.send(line.as_bytes());
+    }
+
+    /// Increments a counter by `value`
+    pub fn count(&self, name: &str, value: u64) {
+        if value > 0 {
+            self.send(name, value, "c");
+        }
+    }
+
+    /// Sets a gauge to `value`
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(name, value, "g");
+    }
+
+    /// Records a timing in milliseconds
+    pub fn timing(&self, name: &str, millis: u64) {
+        self.send(name, millis, "ms");
+    }
+}
+
+impl LifecycleHook for StatsdClient {
+    fn on_disconnect(&self, connection: &ConnectionEntry, error: Option<&io::Error>) {
+        self.timing("connection.duration", connection.age().as_millis() as u64);
+        if error.is_some() {
+            self.count("connection.errors", 1);
+        }
+    }
+}
+
+/// Background thread flushing counter deltas and gauges to StatsD
+pub struct StatsdReporter {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl StatsdReporter {
+    /// Starts flushing `metrics` and `pool` through `client` every `interval`
+    pub fn start(client: Arc<StatsdClient>, interval: Duration, metrics: Arc<Metrics>, pool: WorkerPool) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut previous = MetricsSnapshot::default();
+            let mut previous_executed = 0;
+            loop {
+                let stopping = match stop_rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+                };
+
+                let current = metrics.snapshot();
+                let delta = |now: u64, before: u64| now.saturating_sub(before);
+                client.count("connections.accepted", delta(current.connections_accepted, previous.connections_accepted));
+                client.count("connections.closed", delta(current.connections_closed, previous.connections_closed));
+                client.count("accept.errors", delta(current.accept_errors, previous.accept_errors));
+                client.count("accept.fd_exhaustion", delta(current.fd_exhaustion_errors, previous.fd_exhaustion_errors));
+                client.count("handler.errors", delta(current.handler_errors, previous.handler_errors));
+                client.count("messages.received", delta(current.messages_received, previous.messages_received));
+                client.count("bytes.received", delta(current.bytes_received, previous.bytes_received));
+                client.count("bytes.sent", delta(current.bytes_sent, previous.bytes_sent));
+                client.count("requests.throttled", delta(current.requests_throttled, previous.requests_throttled));
+                client.count("commands.denied", delta(current.commands_denied, previous.commands_denied));
+                client.count("auth.failures", delta(current.auth_failures, previous.auth_failures));
+                client.count("auth.lockouts", delta(current.auth_lockouts, previous.auth_lockouts));
+                client.count("floods", delta(current.floods, previous.floods));
+                client.count("heartbeats", delta(current.heartbeats, previous.heartbeats));
+                client.gauge("connections.active", current.connections_active);
+                client.gauge("defensive_mode", u64::from(current.defensive_mode));
+                previous = current;
+
+                let pool = pool.stats();
+                client.gauge("pool.active", pool.active as u64);
+                client.gauge("pool.queued", pool.queued as u64);
+                client.count("pool.executed", delta(pool.executed, previous_executed));
+                previous_executed = pool.executed;
+
+                if stopping {
+                    break;
+                }
+            }
+        });
+        Self { stop_tx, handle }
+    }
+
+    /// Flushes one last time and waits for the thread to exit
+    pub fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
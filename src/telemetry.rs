@@ -0,0 +1,226 @@
+//! Server telemetry: in-process counters, per-connection spans, and the optional
+//! OTLP/HTTP exporter that ships both to an OpenTelemetry collector.
+//!
+//! The counters bumped for every connection and message are [`Counter`]s,
+//! sharded across threads so that busy workers do not contend over them. The
+//! exporter is only compiled in with the `metrics` cargo feature.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use rand::RngCore;
+
+use crate::connections::ConnectionEntry;
+
+mod counter;
+#[cfg(feature = "metrics")]
+mod otlp;
+
+pub use counter::Counter;
+#[cfg(feature = "metrics")]
+pub use otlp::{OtlpConfig, OtlpExporter};
+
+/// Maximum number of finished spans buffered between exports
+const SPAN_BUFFER_CAPACITY: usize = 2048;
+
+/// Counters describing server activity since startup
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub connections_accepted: Counter,
+    pub connections_active: Counter,
+    pub connections_closed: Counter,
+    /// Connections refused by the access list, which are not counted as accepted
+    pub connections_denied: AtomicU64,
+    /// Connections refused because their address had as many open as allowed
+    pub connections_over_ip_cap: AtomicU64,
+    /// Connections refused because their address was banned
+    pub connections_banned: AtomicU64,
+    /// Connections refused in defensive mode because others were already waiting for a worker
+    pub connections_over_queue: AtomicU64,
+    pub accept_errors: AtomicU64,
+    /// Accept failures caused by running out of file descriptors (subset of `accept_errors`)
+    pub fd_exhaustion_errors: AtomicU64,
+    pub handler_errors: AtomicU64,
+    pub messages_received: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    /// Messages refused for going over a rate limit
+    pub requests_throttled: AtomicU64,
+    /// Messages refused by the command ACL
+    pub commands_denied: AtomicU64,
+    /// `AUTH` attempts with a token that matched no user
+    pub auth_failures: AtomicU64,
+    /// Times a client address was locked out of `AUTH` for failing too often
+    pub auth_lockouts: AtomicU64,
+    /// Connections closed for taking too long over a request, or for starting one when too many were in progress
+    pub slow_requests_closed: AtomicU64,
+    /// Times the server went into defensive mode for a connection flood
+    pub floods: AtomicU64,
+    /// Whether the server is defending itself against a connection flood right now
+    pub defensive_mode: AtomicBool,
+    pub heartbeats: AtomicU64,
+    /// Unix time of the most recent heartbeat, or 0 before the first one
+    pub last_heartbeat: AtomicU64,
+}
+
+/// Point-in-time copy of [`Metrics`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsSnapshot {
+    pub connections_accepted: u64,
+    pub connections_active: u64,
+    pub connections_closed: u64,
+    pub connections_denied: u64,
+    pub connections_over_ip_cap: u64,
+    pub connections_banned: u64,
+    pub connections_over_queue: u64,
+    pub accept_errors: u64,
+    pub fd_exhaustion_errors: u64,
+    pub handler_errors: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub requests_throttled: u64,
+    pub commands_denied: u64,
+    pub auth_failures: u64,
+    pub auth_lockouts: u64,
+    pub slow_requests_closed: u64,
+    pub floods: u64,
+    pub defensive_mode: bool,
+    pub heartbeats: u64,
+    pub last_heartbeat: u64,
+}
+
+impl Metrics {
+    /// Creates a new set of zeroed counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a newly accepted connection
+    pub fn connection_opened(&self) {
+        self.connections_accepted.add(1);
+        self.connections_active.add(1);
+    }
+
+    /// Records a connection that has finished, successfully or not
+    pub fn connection_closed(&self) {
+        self.connections_active.sub(1);
+        self.connections_closed.add(1);
+    }
+
+    /// Takes a consistent-enough copy of every counter for reporting
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections_accepted: self.connections_accepted.get(),
+            connections_active: self.connections_active.get(),
+            connections_closed: self.connections_closed.get(),
+            connections_denied: self.connections_denied.load(Ordering::Relaxed),
+            connections_over_ip_cap: self.connections_over_ip_cap.load(Ordering::Relaxed),
+            connections_banned: self.connections_banned.load(Ordering::Relaxed),
+            connections_over_queue: self.connections_over_queue.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            fd_exhaustion_errors: self.fd_exhaustion_errors.load(Ordering::Relaxed),
+            handler_errors: self.handler_errors.load(Ordering::Relaxed),
+            messages_received: self.messages_received.get(),
+            bytes_received: self.bytes_received.get(),
+            bytes_sent: self.bytes_sent.get(),
+            requests_throttled: self.requests_throttled.load(Ordering::Relaxed),
+            commands_denied: self.commands_denied.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            auth_lockouts: self.auth_lockouts.load(Ordering::Relaxed),
+            slow_requests_closed: self.slow_requests_closed.load(Ordering::Relaxed),
+            floods: self.floods.load(Ordering::Relaxed),
+            defensive_mode: self.defensive_mode.load(Ordering::Relaxed),
+            heartbeats: self.heartbeats.load(Ordering::Relaxed),
+            last_heartbeat: self.last_heartbeat.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A finished connection, recorded as a single span
+#[derive(Debug, Clone)]
+pub struct ConnectionSpan {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub peer: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub messages: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub error: Option<String>,
+}
+
+impl ConnectionSpan {
+    /// Starts a span for a connection from `peer` with fresh random IDs
+    pub fn start(peer: String) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut trace_id = [0u8; 16];
+        let mut span_id = [0u8; 8];
+        rng.fill_bytes(&mut trace_id);
+        rng.fill_bytes(&mut span_id);
+
+        let now = SystemTime::now();
+        Self {
+            trace_id,
+            span_id,
+            peer,
+            start: now,
+            end: now,
+            messages: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+            error: None,
+        }
+    }
+
+    /// Marks the span as finished, copying the connection's final counters
+    pub fn finish(&mut self, connection: &ConnectionEntry, error: Option<String>) {
+        self.end = SystemTime::now();
+        self.messages = connection.messages.load(Ordering::Relaxed);
+        self.bytes_received = connection.bytes_received.load(Ordering::Relaxed);
+        self.bytes_sent = connection.bytes_sent.load(Ordering::Relaxed);
+        self.error = error;
+    }
+}
+
+/// Bounded buffer of finished spans waiting to be exported
+///
+/// Spans are only retained once an exporter has enabled the buffer, so servers
+/// running without tracing don't pay for it.
+#[derive(Debug, Default)]
+pub struct SpanBuffer {
+    enabled: AtomicBool,
+    spans: Mutex<VecDeque<ConnectionSpan>>,
+}
+
+impl SpanBuffer {
+    /// Creates a new, disabled span buffer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts retaining recorded spans
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Adds a finished span, dropping the oldest one if the buffer is full
+    pub fn record(&self, span: ConnectionSpan) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() >= SPAN_BUFFER_CAPACITY {
+            spans.pop_front();
+        }
+        spans.push_back(span);
+    }
+
+    /// Removes and returns every buffered span
+    pub fn drain(&self) -> Vec<ConnectionSpan> {
+        self.spans.lock().unwrap().drain(..).collect()
+    }
+}
+
@@ -0,0 +1,118 @@
+//! Counters sharded across threads.
+//!
+//! Every message bumps the same few counters, and with one shared atomic for
+//! each, the workers spend their time passing its cache line back and forth.
+//! A [`Counter`] instead keeps a shard per thread, each on a cache line of its
+//! own: threads only ever add to their own shard, and reads add the shards up.
+//! A read racing with updates may miss the newest of them, just as separate
+//! loads of shared atomics could.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+/// Most shards a counter is split into
+const MAX_SHARDS: usize = 64;
+
+/// Shard the next thread to update a counter is given
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// This thread's shard in every counter, before wrapping to the counter's size
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Shards in each counter: the number of CPUs, rounded up to a power of two
+fn shard_count() -> usize {
+    static SHARDS: OnceLock<usize> = OnceLock::new();
+    *SHARDS.get_or_init(|| {
+        let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        cpus.next_power_of_two().min(MAX_SHARDS)
+    })
+}
+
+/// One thread's part of a counter, aligned to keep it off its neighbours' cache lines
+///
+/// 128 bytes rather than 64, as some CPUs prefetch cache lines in pairs.
+#[derive(Debug, Default)]
+#[repr(align(128))]
+struct Shard(AtomicU64);
+
+/// A count updated from many threads at once, such as the messages received by every worker
+#[derive(Debug)]
+pub struct Counter {
+    shards: Box<[Shard]>,
+}
+
+impl Counter {
+    /// A counter at zero
+    pub fn new() -> Self {
+        Self { shards: (0..shard_count()).map(|_| Shard::default()).collect() }
+    }
+
+    fn shard(&self) -> &AtomicU64 {
+        // The shard count is a power of two
+        let index = SHARD.with(|shard| *shard) & (self.shards.len() - 1);
+        &self.shards[index].0
+    }
+
+    /// Adds `n` to the count
+    pub fn add(&self, n: u64) {
+        self.shard().fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Takes `n` off the count, for counts that go down as well as up
+    ///
+    /// This thread's shard may wrap below zero; the total is still right.
+    pub fn sub(&self, n: u64) {
+        self.shard().fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// The count, adding up every thread's share
+    pub fn get(&self) -> u64 {
+        self.shards.iter().fold(0, |total, shard| total.wrapping_add(shard.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::Counter;
+
+    #[test]
+    fn counters_add_up_every_threads_share() {
+        let counter = Arc::new(Counter::new());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || (0..1000).for_each(|_| counter.add(2)))
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        assert_eq!(counter.get(), 16_000);
+    }
+
+    #[test]
+    fn counts_taken_down_on_other_threads_still_add_up() {
+        let gauge = Arc::new(Counter::new());
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let gauge = Arc::clone(&gauge);
+                thread::spawn(move || (0..1000).for_each(|_| if i % 2 == 0 { gauge.add(3) } else { gauge.sub(1) }))
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        assert_eq!(gauge.get(), 8000);
+    }
+}
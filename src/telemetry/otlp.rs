@@ -0,0 +1,192 @@
+//! OTLP/HTTP (JSON) export of the server's metrics and connection spans.
+
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+
+use super::{ConnectionSpan, Metrics, MetricsSnapshot, SpanBuffer};
+use crate::error::Result;
+use crate::http_client::{self, HttpUrl};
+use crate::pool::{PoolStats, WorkerPool};
+
+/// Timeout applied to each request sent to the collector
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+const SERVICE_NAME: &str = "rustbucket";
+
+/// Settings for the OTLP exporter
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// Collector base URL, e.g. `http://localhost:4318`
+    pub endpoint: String,
+    /// How often to push metrics and spans
+    pub interval: Duration,
+}
+
+/// Background thread periodically exporting metrics and spans over OTLP/HTTP (JSON)
+pub struct OtlpExporter {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl OtlpExporter {
+    /// Validates the endpoint and starts the export thread
+    pub fn start(config: OtlpConfig, metrics: Arc<Metrics>, spans: Arc<SpanBuffer>, pool: WorkerPool) -> Result<Self> {
+        let base = HttpUrl::parse(&config.endpoint)?;
+        let metrics_url = base.join("/v1/metrics");
+        let traces_url = base.join("/v1/traces");
+        let started_at = SystemTime::now();
+        spans.enable();
+
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            loop {
+                let stopping = match stop_rx.recv_timeout(config.interval) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+                };
+
+                let body = metrics_payload(&metrics.snapshot(), &pool.stats(), started_at, SystemTime::now());
+                export(&metrics_url, &body);
+
+                let finished = spans.drain();
+                if !finished.is_empty() {
+                    export(&traces_url, &traces_payload(&finished));
+                }
+
+                if stopping {
+                    break;
+                }
+            }
+        });
+
+        println!("Exporting telemetry over OTLP to {} every {:?}", config.endpoint, config.interval);
+        Ok(Self { stop_tx, handle })
+    }
+
+    /// Performs a final export and waits for the thread to exit
+    pub fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn export(url: &HttpUrl, body: &Value) {
+    match http_client::post_json(url, &body.to_string(), EXPORT_TIMEOUT) {
+        Ok(status) if (200..300).contains(&status) => {}
+        Ok(status) => log::warn!("OTLP export to {} rejected with status {}", url.path, status),
+        Err(e) => log::warn!("OTLP export to {} failed: {}", url.path, e),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn resource() -> Value {
+    json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": SERVICE_NAME } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+        ]
+    })
+}
+
+fn sum(name: &str, unit: &str, value: u64, start: &str, now: &str) -> Value {
+    json!({
+        "name": name,
+        "unit": unit,
+        "sum": {
+            "aggregationTemporality": 2,
+            "isMonotonic": true,
+            "dataPoints": [{ "asInt": value.to_string(), "startTimeUnixNano": start, "timeUnixNano": now }],
+        }
+    })
+}
+
+fn gauge(name: &str, unit: &str, value: u64, now: &str) -> Value {
+    json!({
+        "name": name,
+        "unit": unit,
+        "gauge": {
+            "dataPoints": [{ "asInt": value.to_string(), "timeUnixNano": now }],
+        }
+    })
+}
+
+/// Builds an OTLP `ExportMetricsServiceRequest` for the given snapshot
+fn metrics_payload(snapshot: &MetricsSnapshot, pool: &PoolStats, started_at: SystemTime, now: SystemTime) -> Value {
+    let start = unix_nanos(started_at);
+    let now = unix_nanos(now);
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(),
+            "scopeMetrics": [{
+                "scope": { "name": SERVICE_NAME },
+                "metrics": [
+                    sum("rustbucket.connections.accepted", "{connection}", snapshot.connections_accepted, &start, &now),
+                    sum("rustbucket.connections.closed", "{connection}", snapshot.connections_closed, &start, &now),
+                    gauge("rustbucket.connections.active", "{connection}", snapshot.connections_active, &now),
+                    sum("rustbucket.accept.errors", "{error}", snapshot.accept_errors, &start, &now),
+                    sum("rustbucket.accept.fd_exhaustion", "{error}", snapshot.fd_exhaustion_errors, &start, &now),
+                    sum("rustbucket.handler.errors", "{error}", snapshot.handler_errors, &start, &now),
+                    sum("rustbucket.messages.received", "{message}", snapshot.messages_received, &start, &now),
+                    sum("rustbucket.bytes.received", "By", snapshot.bytes_received, &start, &now),
+                    sum("rustbucket.bytes.sent", "By", snapshot.bytes_sent, &start, &now),
+                    sum("rustbucket.requests.throttled", "{message}", snapshot.requests_throttled, &start, &now),
+                    sum("rustbucket.heartbeats", "{heartbeat}", snapshot.heartbeats, &start, &now),
+                    gauge("rustbucket.pool.workers", "{thread}", pool.workers as u64, &now),
+                    gauge("rustbucket.pool.active", "{thread}", pool.active as u64, &now),
+                    gauge("rustbucket.pool.queued", "{job}", pool.queued as u64, &now),
+                    sum("rustbucket.pool.executed", "{job}", pool.executed, &start, &now),
+                    sum("rustbucket.pool.panicked", "{job}", pool.panicked as u64, &start, &now),
+                ],
+            }],
+        }]
+    })
+}
+
+/// Builds an OTLP `ExportTraceServiceRequest` with one span per connection
+fn traces_payload(spans: &[ConnectionSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            // Status codes: 1 = OK, 2 = ERROR
+            let status = match &span.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({ "code": 1 }),
+            };
+            json!({
+                "traceId": hex(&span.trace_id),
+                "spanId": hex(&span.span_id),
+                "name": "connection",
+                "kind": 2, // SPAN_KIND_SERVER
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": [
+                    { "key": "net.peer.name", "value": { "stringValue": span.peer } },
+                    { "key": "rustbucket.messages", "value": { "intValue": span.messages.to_string() } },
+                    { "key": "rustbucket.bytes_received", "value": { "intValue": span.bytes_received.to_string() } },
+                    { "key": "rustbucket.bytes_sent", "value": { "intValue": span.bytes_sent.to_string() } },
+                ],
+                "status": status,
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(),
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME },
+                "spans": spans,
+            }],
+        }]
+    })
+}
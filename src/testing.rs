@@ -0,0 +1,210 @@
+//! Helpers for testing against a real server.
+//!
+//! [`TestServer`] starts a server on an ephemeral loopback port with its log,
+//! config, and pidfile in a private temporary directory, so tests can run in
+//! parallel without touching the working directory. [`TestClient`] speaks the
+//! one-read-per-message protocol with a timeout, so a broken server fails a
+//! test instead of hanging it.
+//!
+//! ```no_run
+//! use rustbucket::testing::TestServer;
+//!
+//! let server = TestServer::start()?;
+//! let mut client = server.client()?;
+//! assert_eq!(client.request("hello\n")?, "Echo: hello\n");
+//! server.shutdown();
+//! # Ok::<(), rustbucket::RustbucketError>(())
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::paths::Paths;
+use crate::protocol::READ_BUFFER_SIZE;
+use crate::server::{Server, ServerBuilder, ShutdownHandle};
+
+/// How long a test client waits for a reply before giving up
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Distinguishes directories created by the same process
+static NEXT_DIR: AtomicU32 = AtomicU32::new(0);
+
+/// A temporary directory, removed with everything in it on drop
+#[derive(Debug)]
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Creates an empty directory under the system temp directory
+    pub fn new() -> io::Result<Self> {
+        let name = format!("rustbucket-test-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    /// The directory itself
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A path inside the directory
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A server running in the background for the length of a test
+///
+/// Dropping it stops the server immediately and removes its directory.
+pub struct TestServer {
+    handle: ShutdownHandle,
+    dir: TestDir,
+}
+
+impl TestServer {
+    /// Starts a server with the default handler
+    pub fn start() -> Result<Self> {
+        Self::start_with(|builder| builder)
+    }
+
+    /// Starts a server after letting `configure` adjust the builder
+    ///
+    /// The builder comes set up for testing: port 0, two workers, no signal
+    /// handlers, no admin interface, and its log, config file, and pidfile in
+    /// the test directory. `configure` may override any of these.
+    pub fn start_with(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> Result<Self> {
+        let dir = TestDir::new()?;
+        let builder = Server::builder()
+            .port(0)
+            .threads(2)
+            .handle_signals(false)
+            .paths(Paths::in_dir(dir.path()));
+        #[cfg(feature = "admin")]
+        let builder = builder.disable_admin();
+        let handle = configure(builder).build()?.start()?;
+        Ok(Self { handle, dir })
+    }
+
+    /// Address the server accepts clients on
+    pub fn addr(&self) -> SocketAddr {
+        self.handle.local_addr()
+    }
+
+    /// Connects a new client
+    pub fn client(&self) -> io::Result<TestClient> {
+        TestClient::connect(self.addr())
+    }
+
+    /// Handle for stopping the server or waiting for it
+    pub fn handle(&self) -> &ShutdownHandle {
+        &self.handle
+    }
+
+    /// Directory holding the server's files
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Files the server uses, unless `configure` changed them
+    pub fn paths(&self) -> Paths {
+        Paths::in_dir(self.dir.path())
+    }
+
+    /// Log file the server writes, unless `configure` changed it
+    pub fn log_path(&self) -> PathBuf {
+        self.paths().log_file
+    }
+
+    /// Config file the server reads, unless `configure` changed it
+    pub fn config_path(&self) -> PathBuf {
+        self.paths().config_file
+    }
+
+    /// Everything in the log file so far, or nothing if it does not exist yet
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.log_path()).unwrap_or_default()
+    }
+
+    /// Shuts the server down gracefully and waits for it to finish
+    pub fn shutdown(&self) {
+        self.handle.shutdown_graceful();
+        self.handle.wait();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if !self.handle.is_finished() {
+            self.handle.shutdown_now();
+            self.handle.wait();
+        }
+    }
+}
+
+/// A client connection that fails rather than blocks when the server goes quiet
+#[derive(Debug)]
+pub struct TestClient {
+    stream: TcpStream,
+}
+
+impl TestClient {
+    /// Connects to `addr` with [`CLIENT_TIMEOUT`] on reads and writes
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, CLIENT_TIMEOUT)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(Self { stream })
+    }
+
+    /// Sends one message and returns the reply
+    pub fn send(&mut self, message: &[u8]) -> io::Result<Vec<u8>> {
+        self.stream.write_all(message)?;
+        self.read_reply()
+    }
+
+    /// Sends one text message and returns the reply as text
+    pub fn request(&mut self, message: &str) -> io::Result<String> {
+        let reply = self.send(message.as_bytes())?;
+        String::from_utf8(reply).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads whatever the server sends next; empty once it closed the connection
+    pub fn read_reply(&mut self) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        let n = self.stream.read(&mut buffer)?;
+        buffer.truncate(n);
+        Ok(buffer)
+    }
+
+    /// Waits up to [`CLIENT_TIMEOUT`] for the server to close the connection
+    pub fn is_closed_by_server(&mut self) -> bool {
+        match self.read_reply() {
+            Ok(reply) => reply.is_empty(),
+            Err(e) => e.kind() == io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    /// Closes the client's side of the connection
+    pub fn close(self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// The underlying socket, for tests that need finer control
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+}
@@ -0,0 +1,648 @@
+//! An io_uring backend for plain TCP connections on Linux.
+//!
+//! With [`ServerBuilder::io_uring`](crate::ServerBuilder::io_uring), every
+//! worker thread runs a ring of its own instead of serving one connection at a
+//! time with blocking reads. Each ring keeps an accept outstanding on the
+//! shared listener and a receive outstanding on each of its connections, and
+//! sends replies through the ring as well, so a busy worker hands the kernel a
+//! batch of operations and collects their completions in one system call where
+//! the blocking backend makes one per read and one per write.
+//!
+//! Messages are handled on their ring's thread as their receives complete, so
+//! a handler that blocks, such as `BLPOP` or an async handler awaiting I/O,
+//! holds up every connection on the same ring. Replies carrying a file, and
+//! replies on connections with an [`Outbox`](crate::outbox::Outbox), are
+//! written directly under the connection's write lock instead, so they cannot
+//! interleave with outbox messages. TLS and Noise are not supported.
+//!
+//! Request deadlines, flood defences, and shutdown are checked every [`TICK`].
+//! Once shutdown is requested, connections waiting for a request are closed
+//! straight away, while those partway through sending a reply finish it first.
+//! The backend needs Linux 5.6 or later.
+
+use std::ffi::c_void;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustix::io::Errno;
+use rustix::io_uring::{io_uring_cqe, io_uring_enter, io_uring_params, io_uring_ptr, io_uring_setup, io_uring_sqe};
+use rustix::io_uring::{IoringEnterFlags, IoringFeatureFlags, IoringOp, IoringSetupFlags, Timespec};
+use rustix::io_uring::{IORING_OFF_CQ_RING, IORING_OFF_SQES, IORING_OFF_SQ_RING};
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+use rustix::net::{SendFlags, SocketFlags};
+
+use crate::buffers::PooledBuffer;
+use crate::config::Config;
+use crate::connections::PeerSlot;
+use crate::handler::{ConnectionCtx, ResponseWriter};
+use crate::memory::{Charge, Pool};
+use crate::middleware::{Pipeline, Request};
+use crate::protocol::{overdue, refuse, refuse_slow_request, reply_sent, send_shutdown_notice, track_request};
+use crate::protocol::{write_reply, Frame, Framer, Pending};
+use crate::server::{accept_failed, admit, apply_socket_options, connection_served, refresh_config};
+use crate::server::{OpenConnection, ServerState};
+
+/// Submission queue entries in each ring
+const RING_ENTRIES: u32 = 1024;
+/// Completion queue entries in each ring; a ring has one operation outstanding per connection
+const COMPLETION_ENTRIES: u32 = 16 * 1024;
+/// Completions handled between submissions, so the entries they queue always fit
+const COMPLETION_BATCH: usize = RING_ENTRIES as usize / 2;
+/// How often each ring checks deadlines, flood defences, and shutdown
+pub const TICK: Duration = Duration::from_millis(100);
+
+/// `user_data` of a ring's accept; connections use their slot index
+const ACCEPT: u64 = u64::MAX;
+/// `user_data` of a ring's tick
+const TICKED: u64 = u64::MAX - 1;
+
+/// Checks that the kernel lets the server set up a ring
+///
+/// The ring is set up on a thread of its own: tearing one down leaves work
+/// queued for the thread that created it, which interrupts that thread's next
+/// blocking call on a socket with a timeout.
+pub(crate) fn probe() -> io::Result<()> {
+    thread::spawn(|| Ring::new(8, 16).map(drop)).join().unwrap_or_else(|_| Err(io::Error::other("probe panicked")))
+}
+
+/// Serves connections from `listener` on a ring per worker thread, returning once they have all stopped accepting
+///
+/// The rings keep running on the worker pool until their last connection
+/// closes, so joining the pool waits for them.
+pub(crate) fn serve(listener: TcpListener, config: Config, pipeline: &Arc<Pipeline>, server_state: &Arc<ServerState>) {
+    let listener = Arc::new(listener);
+    let (accepting_tx, accepting_rx) = mpsc::channel::<()>();
+    for _ in 0..server_state.pool.stats().workers {
+        let listener = Arc::clone(&listener);
+        let pipeline = Arc::clone(pipeline);
+        let state = Arc::clone(server_state);
+        let accepting = accepting_tx.clone();
+        server_state.pool.execute(move || {
+            let worker = match Worker::new(&listener, config, &pipeline, &state, accepting) {
+                Ok(worker) => worker,
+                Err(e) => return log::error!("Failed to set up an io_uring ring: {}", e),
+            };
+            worker.run();
+        });
+    }
+    drop(accepting_tx);
+    // Each ring drops its sender once it stops accepting
+    for () in accepting_rx {}
+    println!("Shutdown requested, stopping new connections...");
+}
+
+/// One io_uring instance, with its submission and completion queues mapped into memory
+struct Ring {
+    // Unmapped before the ring's file descriptor is closed
+    _sq_ring: Mapping,
+    _cq_ring: Option<Mapping>,
+    _sqes: Mapping,
+    fd: OwnedFd,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sqes: *mut io_uring_sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+    /// Entries queued since the last `io_uring_enter`
+    unsubmitted: u32,
+}
+
+/// Memory shared with the kernel, unmapped when dropped
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> io::Result<Self> {
+        let flags = MapFlags::SHARED | MapFlags::POPULATE;
+        // SAFETY: maps a region of the ring the kernel sized through the setup parameters
+        let ptr = unsafe { mmap(std::ptr::null_mut(), len, ProtFlags::READ | ProtFlags::WRITE, flags, fd, offset)? };
+        Ok(Self { ptr, len })
+    }
+
+    /// The `T` at `offset` bytes into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: the kernel gives offsets within the mapping it sized
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping came from mmap and nothing refers to it once the ring is dropped
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+impl Ring {
+    fn new(entries: u32, completion_entries: u32) -> io::Result<Self> {
+        let mut params = io_uring_params::default();
+        params.flags = IoringSetupFlags::CQSIZE;
+        params.cq_entries = completion_entries;
+        // SAFETY: params is a valid, zeroed parameter block
+        let fd = unsafe { io_uring_setup(entries, &mut params)? };
+        let (sq_off, cq_off) = (params.sq_off, params.cq_off);
+        let sq_len = sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = cq_off.cqes as usize + params.cq_entries as usize * size_of::<io_uring_cqe>();
+        // Since Linux 5.4 both queues share one mapping
+        let single_mmap = params.features.contains(IoringFeatureFlags::SINGLE_MMAP);
+        let sq_ring = Mapping::new(&fd, if single_mmap { sq_len.max(cq_len) } else { sq_len }, IORING_OFF_SQ_RING)?;
+        let cq_ring = if single_mmap { None } else { Some(Mapping::new(&fd, cq_len, IORING_OFF_CQ_RING)?) };
+        let sqes = Mapping::new(&fd, params.sq_entries as usize * size_of::<io_uring_sqe>(), IORING_OFF_SQES)?;
+        let cq = cq_ring.as_ref().unwrap_or(&sq_ring);
+
+        // Entries are submitted in order, so the indirection array maps each slot to itself
+        let array = sq_ring.at::<u32>(sq_off.array);
+        for index in 0..params.sq_entries {
+            // SAFETY: the array has sq_entries elements
+            unsafe { array.add(index as usize).write(index) };
+        }
+        // SAFETY: the masks are plain integers the kernel wrote during setup
+        let (sq_mask, cq_mask) = unsafe { (*sq_ring.at::<u32>(sq_off.ring_mask), *cq.at::<u32>(cq_off.ring_mask)) };
+        Ok(Self {
+            sq_head: sq_ring.at(sq_off.head),
+            sq_tail: sq_ring.at(sq_off.tail),
+            sq_mask,
+            sq_entries: params.sq_entries,
+            sqes: sqes.at(0),
+            cq_head: cq.at(cq_off.head),
+            cq_tail: cq.at(cq_off.tail),
+            cq_mask,
+            cqes: cq.at(cq_off.cqes),
+            unsubmitted: 0,
+            _sq_ring: sq_ring,
+            _cq_ring: cq_ring,
+            _sqes: sqes,
+            fd,
+        })
+    }
+
+    /// Queues `sqe`, submitting what is already queued first if the queue is full
+    ///
+    /// Whatever `sqe` points to must stay in place until its completion arrives.
+    fn push(&mut self, sqe: io_uring_sqe) -> io::Result<()> {
+        for _ in 0..2 {
+            // SAFETY: the head and tail are in the mapped ring; only this thread moves the tail
+            let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+            let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+            if tail.wrapping_sub(head) < self.sq_entries {
+                // SAFETY: the slot is free, as the kernel has consumed everything up to head
+                unsafe {
+                    self.sqes.add((tail & self.sq_mask) as usize).write(sqe);
+                    (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+                }
+                self.unsubmitted += 1;
+                return Ok(());
+            }
+            self.submit(0)?;
+        }
+        Err(io::Error::other("io_uring submission queue is full"))
+    }
+
+    /// Submits the queued entries and waits until at least `wait` completions are ready
+    fn submit(&mut self, wait: u32) -> io::Result<()> {
+        let flags = if wait > 0 { IoringEnterFlags::GETEVENTS } else { IoringEnterFlags::empty() };
+        loop {
+            // SAFETY: every queued entry points at memory kept alive until its completion
+            match unsafe { io_uring_enter(&self.fd, self.unsubmitted, wait, flags) } {
+                Ok(submitted) => {
+                    self.unsubmitted -= submitted.min(self.unsubmitted);
+                    return Ok(());
+                }
+                Err(Errno::INTR) => {}
+                // Completions have to be collected before more can be submitted
+                Err(Errno::BUSY | Errno::AGAIN) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Moves up to `max` completions into `completions`, as `user_data` and result pairs
+    fn complete(&mut self, completions: &mut Vec<(u64, i32)>, max: usize) {
+        // SAFETY: the head and tail are in the mapped ring; only this thread moves the head
+        let tail = unsafe { (*self.cq_tail).load(Ordering::Acquire) };
+        let mut head = unsafe { (*self.cq_head).load(Ordering::Relaxed) };
+        while head != tail && completions.len() < max {
+            // SAFETY: entries between head and tail have been written by the kernel
+            let cqe = unsafe { &*self.cqes.add((head & self.cq_mask) as usize) };
+            completions.push((cqe.user_data.u64_(), cqe.res));
+            head = head.wrapping_add(1);
+        }
+        // SAFETY: as above; this hands the consumed entries back to the kernel
+        unsafe { (*self.cq_head).store(head, Ordering::Release) };
+    }
+}
+
+/// What a connection has outstanding on its ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Waiting {
+    /// A receive into the read buffer
+    Request,
+    /// A send of the reply to `frame`, from a read of `received` bytes, `sent` bytes of it done
+    Reply { received: usize, frame: Frame, sent: usize },
+}
+
+/// A client connection served by one ring
+struct Connection<'a> {
+    stream: TcpStream,
+    open: OpenConnection,
+    config: Config,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    buffer: PooledBuffer,
+    framer: Framer,
+    response: ResponseWriter,
+    buffers: Charge<'a>,
+    pending: Option<Pending<'a>>,
+    waiting: Waiting,
+    /// Whether the server has shut the socket down, ending the outstanding receive
+    closing: bool,
+    /// The connection's place under the per-address cap
+    _slot: Option<PeerSlot>,
+}
+
+impl<'a> Connection<'a> {
+    fn ctx(&'a self, server_state: &'a ServerState) -> ConnectionCtx<'a> {
+        ConnectionCtx::new(&self.open.entry, &self.config, server_state, self.peer_addr, self.local_addr)
+    }
+
+    /// Shuts the socket down, so the outstanding operation completes and the connection is closed
+    fn close(&mut self) {
+        self.closing = true;
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// One worker thread's ring and the connections it serves
+struct Worker<'a> {
+    ring: Ring,
+    listener: &'a TcpListener,
+    pipeline: &'a Pipeline,
+    server_state: &'a ServerState,
+    /// Configuration given to the last connection, kept if the source fails
+    config: Config,
+    connections: Vec<Option<Connection<'a>>>,
+    free: Vec<usize>,
+    /// Dropped once the ring stops accepting
+    accepting: Option<Sender<()>>,
+    /// Read by the kernel for every tick, so it must not move
+    tick: Box<Timespec>,
+}
+
+impl<'a> Worker<'a> {
+    fn new(
+        listener: &'a TcpListener,
+        config: Config,
+        pipeline: &'a Pipeline,
+        server_state: &'a ServerState,
+        accepting: Sender<()>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            ring: Ring::new(RING_ENTRIES, COMPLETION_ENTRIES)?,
+            listener,
+            pipeline,
+            server_state,
+            config,
+            connections: Vec::new(),
+            free: Vec::new(),
+            accepting: Some(accepting),
+            tick: Box::new(Timespec { tv_sec: 0, tv_nsec: TICK.as_nanos() as _ }),
+        })
+    }
+
+    /// Serves connections until shutdown is requested and the last of them has closed
+    fn run(mut self) {
+        let mut completions = Vec::with_capacity(COMPLETION_BATCH);
+        let result = (|| -> io::Result<()> {
+            self.accept()?;
+            self.tick()?;
+            loop {
+                if self.server_state.shutdown_requested.load(Ordering::SeqCst) {
+                    self.accepting = None;
+                    if self.connections.iter().all(Option::is_none) {
+                        return Ok(());
+                    }
+                }
+                self.ring.submit(1)?;
+                self.ring.complete(&mut completions, COMPLETION_BATCH);
+                for (user_data, result) in completions.drain(..) {
+                    match user_data {
+                        ACCEPT => self.accepted(result)?,
+                        TICKED => self.ticked()?,
+                        index => self.completed(index as usize, result),
+                    }
+                }
+            }
+        })();
+        if let Err(e) = result {
+            log::error!("io_uring ring failed, closing its connections: {}", e);
+            for index in 0..self.connections.len() {
+                if self.connections[index].is_some() {
+                    self.finish(index, Err(io::Error::new(e.kind(), e.to_string())));
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        let mut sqe = io_uring_sqe { opcode: IoringOp::Accept, fd: self.listener.as_raw_fd(), ..Default::default() };
+        sqe.op_flags.accept_flags = SocketFlags::CLOEXEC;
+        sqe.user_data = ACCEPT.into();
+        self.ring.push(sqe)
+    }
+
+    fn tick(&mut self) -> io::Result<()> {
+        let mut sqe = io_uring_sqe { opcode: IoringOp::Timeout, ..Default::default() };
+        sqe.addr_or_splice_off_in.addr = io_uring_ptr::new((&*self.tick as *const Timespec).cast_mut().cast());
+        sqe.len.len = 1;
+        sqe.user_data = TICKED.into();
+        self.ring.push(sqe)
+    }
+
+    /// Queues a receive into connection `index`'s read buffer
+    fn receive(&mut self, index: usize) {
+        let connection = self.connections[index].as_mut().expect("receiving on an open connection");
+        connection.waiting = Waiting::Request;
+        let buffer = &mut connection.buffer;
+        let sqe = entry(IoringOp::Recv, connection.stream.as_raw_fd(), buffer.as_mut_ptr(), buffer.len(), index);
+        if let Err(e) = self.ring.push(sqe) {
+            self.finish(index, Err(e));
+        }
+    }
+
+    /// Queues a send of the rest of connection `index`'s reply
+    fn send(&mut self, index: usize, sent: usize) {
+        let connection = self.connections[index].as_mut().expect("sending on an open connection");
+        let fd = connection.stream.as_raw_fd();
+        let reply = &connection.response.as_bytes()[sent..];
+        let mut sqe = entry(IoringOp::Send, fd, reply.as_ptr().cast_mut(), reply.len(), index);
+        sqe.op_flags.send_flags = SendFlags::NOSIGNAL;
+        if let Err(e) = self.ring.push(sqe) {
+            self.finish(index, Err(e));
+        }
+    }
+
+    fn accepted(&mut self, result: i32) -> io::Result<()> {
+        let stopping = self.server_state.shutdown_requested.load(Ordering::SeqCst);
+        match result {
+            // SAFETY: a successful accept returns a new socket that nothing else owns
+            fd if fd >= 0 => {
+                let stream = unsafe { TcpStream::from_raw_fd(fd) };
+                if !stopping {
+                    self.open(stream);
+                }
+            }
+            error => accept_failed(self.server_state, &io::Error::from_raw_os_error(-error)),
+        }
+        if stopping {
+            return Ok(());
+        }
+        self.accept()
+    }
+
+    /// Starts serving a newly accepted client, unless it is refused
+    fn open(&mut self, stream: TcpStream) {
+        let server_state = self.server_state;
+        // Refuse unwelcome clients before they take up any buffers
+        let Some(slot) = admit(server_state, &stream) else { return };
+        apply_socket_options(server_state, &stream);
+        server_state.metrics.connection_opened();
+        refresh_config(server_state, &mut self.config);
+        let connection = Connection {
+            open: OpenConnection::open(&stream, server_state),
+            config: self.config,
+            peer_addr: stream.peer_addr().ok(),
+            local_addr: stream.local_addr().ok(),
+            buffer: server_state.buffers.read_buffer(),
+            framer: Framer::new(server_state),
+            response: ResponseWriter::pooled(&server_state.buffers),
+            buffers: server_state.memory.charge(Pool::Buffers, server_state.buffers.buffer_size()),
+            pending: None,
+            waiting: Waiting::Request,
+            closing: false,
+            _slot: slot,
+            stream,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.connections[index] = Some(connection);
+                index
+            }
+            None => {
+                self.connections.push(Some(connection));
+                self.connections.len() - 1
+            }
+        };
+        self.receive(index);
+    }
+
+    fn completed(&mut self, index: usize, result: i32) {
+        let connection = self.connections[index].as_mut().expect("completion for an open connection");
+        match (connection.waiting, result) {
+            (_, error) if error < 0 => self.finish(index, Err(io::Error::from_raw_os_error(-error))),
+            // Connection closed by client, or shut down by the server
+            (Waiting::Request, 0) => self.ended(index),
+            (Waiting::Request, received) => self.received(index, received as usize),
+            (Waiting::Reply { .. }, 0) => self.finish(index, Err(io::ErrorKind::WriteZero.into())),
+            (Waiting::Reply { received, frame, sent }, written) => {
+                let sent = sent + written as usize;
+                if sent < connection.response.len() {
+                    connection.waiting = Waiting::Reply { received, frame, sent };
+                    self.send(index, sent);
+                } else {
+                    connection.open.entry.record_sent(sent);
+                    self.replied(index, received, frame);
+                }
+            }
+        }
+    }
+
+    /// Finds the first complete message once a read completes, and handles it
+    fn received(&mut self, index: usize, received: usize) {
+        let server_state = self.server_state;
+        let connection = self.connections[index].as_mut().expect("message for an open connection");
+        connection.open.entry.record_received(received);
+        if let Err(reason) = track_request(&mut connection.pending, server_state, &connection.buffer[..received]) {
+            let ctx = ConnectionCtx::new(
+                &connection.open.entry,
+                &connection.config,
+                server_state,
+                connection.peer_addr,
+                connection.local_addr,
+            );
+            let result = refuse_slow_request(&mut connection.stream, server_state, &ctx, &reason);
+            return self.finish(index, result);
+        }
+        let read = &connection.buffer[..received];
+        let frame = match connection.framer.frame(self.pipeline, &connection.open.entry, read) {
+            Ok(Frame::Incomplete) => {
+                connection.buffers.resize(buffer_charge(connection, server_state));
+                return self.receive(index);
+            }
+            Ok(frame) => frame,
+            Err(reason) => return self.refuse_client(index, &reason),
+        };
+        self.handle(index, received, frame);
+    }
+
+    /// Refuses the client on connection `index` for `reason` and closes it
+    fn refuse_client(&mut self, index: usize, reason: &str) {
+        let server_state = self.server_state;
+        let connection = self.connections[index].as_mut().expect("refusing an open connection");
+        let ctx = ConnectionCtx::new(
+            &connection.open.entry,
+            &connection.config,
+            server_state,
+            connection.peer_addr,
+            connection.local_addr,
+        );
+        let result = refuse(&mut connection.stream, &ctx, reason);
+        self.finish(index, result);
+    }
+
+    /// Passes the message `frame` found through the pipeline and starts sending the reply
+    fn handle(&mut self, index: usize, received: usize, frame: Frame) {
+        let server_state = self.server_state;
+        let connection = self.connections[index].as_mut().expect("message for an open connection");
+        connection.response.reset();
+        let ctx = ConnectionCtx::new(
+            &connection.open.entry,
+            &connection.config,
+            server_state,
+            connection.peer_addr,
+            connection.local_addr,
+        );
+        let message = connection.framer.message(frame, &connection.buffer[..received]);
+        self.pipeline.handle(&Request { connection: &ctx, message }, &mut connection.response);
+        // Outbox messages and files are written directly, so replies go the same way to stay in order
+        if connection.response.file_len() > 0 || connection.open.entry.has_outbox() {
+            match write_reply(&mut connection.stream, &connection.open.entry, &mut connection.response) {
+                Ok(()) => self.replied(index, received, frame),
+                Err(e) => self.finish(index, Err(e)),
+            }
+        } else if connection.response.is_empty() {
+            self.replied(index, received, frame);
+        } else {
+            connection.waiting = Waiting::Reply { received, frame, sent: 0 };
+            self.send(index, 0);
+        }
+    }
+
+    /// Finishes with a message once its reply is sent, and waits for the next one
+    fn replied(&mut self, index: usize, received: usize, frame: Frame) {
+        let server_state = self.server_state;
+        let connection = self.connections[index].as_mut().expect("reply on an open connection");
+        let ctx = connection.ctx(server_state);
+        let message = connection.framer.message(frame, &connection.buffer[..received]);
+        let open = reply_sent(&ctx, server_state, &connection.open.entry, message, &connection.response);
+        connection.framer.consume(frame, &connection.buffer[..received]);
+        connection.buffers.resize(buffer_charge(connection, server_state));
+        if !open {
+            return self.finish(index, Ok(()));
+        }
+        // Every complete message kept is handled before reading on, since one may change where the next ends
+        match connection.framer.frame(self.pipeline, &connection.open.entry, &[]) {
+            Ok(Frame::Incomplete) => self.receive(index),
+            Ok(frame) => self.handle(index, 0, frame),
+            Err(reason) => self.refuse_client(index, &reason),
+        }
+    }
+
+    /// Closes a connection whose client closed its end, first handling any message it did not finish
+    fn ended(&mut self, index: usize) {
+        let server_state = self.server_state;
+        let connection = self.connections[index].as_mut().expect("end of an open connection");
+        let frame = connection.framer.remainder();
+        if !connection.closing && frame != Frame::Incomplete {
+            connection.response.reset();
+            let ctx = ConnectionCtx::new(
+                &connection.open.entry,
+                &connection.config,
+                server_state,
+                connection.peer_addr,
+                connection.local_addr,
+            );
+            let message = connection.framer.message(frame, &[]);
+            self.pipeline.handle(&Request { connection: &ctx, message }, &mut connection.response);
+            let _ = write_reply(&mut connection.stream, &connection.open.entry, &mut connection.response);
+        }
+        self.finish(index, Ok(()));
+    }
+
+    /// Checks every connection waiting for a request against shutdown, its request deadline, and flood defences
+    fn ticked(&mut self) -> io::Result<()> {
+        let server_state = self.server_state;
+        let force = server_state.force_shutdown.load(Ordering::SeqCst);
+        let stopping = server_state.shutdown_requested.load(Ordering::SeqCst);
+        let pipeline = self.pipeline;
+        for connection in self.connections.iter_mut().flatten() {
+            if connection.closing || (connection.waiting != Waiting::Request && !force) {
+                continue;
+            }
+            let ctx = ConnectionCtx::new(
+                &connection.open.entry,
+                &connection.config,
+                server_state,
+                connection.peer_addr,
+                connection.local_addr,
+            );
+            if force {
+                connection.close();
+            } else if stopping && !unfinished_request(connection) {
+                let (stream, response) = (&mut connection.stream, &mut connection.response);
+                let _ = send_shutdown_notice(stream, &connection.open.entry, pipeline, server_state, &ctx, response);
+                connection.close();
+            } else if let Some(reason) = overdue(&connection.pending, server_state) {
+                let _ = refuse_slow_request(&mut connection.stream, server_state, &ctx, &reason);
+                connection.close();
+            } else if server_state.defending().is_some_and(|policy| connection.open.entry.idle() >= policy.timeout) {
+                let peer = &connection.open.entry.peer;
+                log::info!("Closing idle connection from {} while defending against a flood", peer);
+                connection.close();
+            }
+        }
+        self.tick()
+    }
+
+    /// Closes connection `index` and records how it ended
+    fn finish(&mut self, index: usize, result: io::Result<()>) {
+        let connection = self.connections[index].take().expect("finishing an open connection");
+        self.free.push(index);
+        let Connection { open, .. } = connection;
+        open.close(self.pipeline, self.server_state, result.as_ref().err());
+        connection_served(self.server_state, result);
+    }
+}
+
+/// Bytes of memory a connection holds for its read buffer, reply, and any message split across reads
+fn buffer_charge(connection: &Connection<'_>, server_state: &ServerState) -> usize {
+    server_state.buffers.buffer_size() + connection.response.capacity() + connection.framer.capacity()
+}
+
+/// Whether `connection` is partway through a request that a graceful shutdown should wait for
+///
+/// Like a blocking worker's, the wait ends once the client has sent nothing for its read timeout.
+fn unfinished_request(connection: &Connection<'_>) -> bool {
+    let idle_timeout = Duration::from_secs(connection.config.timeout_seconds.max(1) as u64);
+    connection.pending.is_some() && connection.open.entry.idle() < idle_timeout
+}
+
+/// A receive into or send from the `len` bytes at `buffer` on `fd`, for the connection at `index`
+fn entry(opcode: IoringOp, fd: RawFd, buffer: *mut u8, len: usize, index: usize) -> io_uring_sqe {
+    let mut sqe = io_uring_sqe { opcode, fd, ..Default::default() };
+    sqe.addr_or_splice_off_in.addr = io_uring_ptr::new(buffer.cast());
+    sqe.len.len = u32::try_from(len).unwrap_or(u32::MAX);
+    sqe.user_data = (index as u64).into();
+    sqe
+}
@@ -0,0 +1,63 @@
+//! The echo path allocates nothing per message once a connection is set up.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rustbucket::testing::TestServer;
+
+/// Counts every allocation in the process, the server's threads included
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Sends `count` copies of `message` and reads each reply into a buffer allocated up front
+fn echo(stream: &mut TcpStream, message: &[u8], count: usize) {
+    let mut reply = [0; 64];
+    for _ in 0..count {
+        stream.write_all(message).unwrap();
+        let mut len = 0;
+        while len < b"Echo: ".len() + message.len() {
+            len += stream.read(&mut reply[len..]).unwrap();
+        }
+        assert_eq!(&reply[b"Echo: ".len()..len], message);
+    }
+}
+
+#[test]
+fn echoed_messages_do_not_allocate() {
+    let server = TestServer::start().unwrap();
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    // Settle the connection in, growing whatever it grows on the first messages
+    echo(&mut stream, b"ping\n", 10);
+
+    // Messages that are not UTF-8 are passed along as they are, not decoded
+    for message in [&b"ping\n"[..], b"\xff\xfe ping\n"] {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        echo(&mut stream, message, 1000);
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert!(allocations < 10, "{} allocations for 1000 messages", allocations);
+    }
+}
@@ -0,0 +1,71 @@
+//! Embedding the server in an async application.
+
+use std::time::Duration;
+
+use rustbucket::config::MemorySource;
+use rustbucket::testing::{TestClient, TestDir, TestServer};
+use rustbucket::{AsyncRequestHandler, ConnectionCtx, ResponseWriter, RustbucketError, Server};
+
+/// Replies after awaiting work spawned onto the application's runtime
+struct Delayed {
+    runtime: tokio::runtime::Handle,
+}
+
+impl AsyncRequestHandler for Delayed {
+    async fn on_message(&self, _ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        let message = message.to_vec();
+        let reply = self
+            .runtime
+            .spawn(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                [b"Later: ".as_slice(), &message].concat()
+            })
+            .await
+            .unwrap();
+        response.write(&reply);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_handlers_can_await_the_application_runtime() {
+    let runtime = tokio::runtime::Handle::current();
+    let server = TestServer::start_with(|builder| builder.async_handler(Delayed { runtime })).unwrap();
+    let addr = server.addr();
+
+    let reply = tokio::task::spawn_blocking(move || TestClient::connect(addr).unwrap().request("hello\n").unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(reply, "Later: hello\n");
+}
+
+#[tokio::test]
+async fn finished_resolves_after_shutdown() {
+    let server = TestServer::start().unwrap();
+    let handle = server.handle().clone();
+
+    let finished = tokio::spawn(handle.finished());
+    server.handle().shutdown_graceful();
+
+    tokio::time::timeout(Duration::from_secs(5), finished).await.unwrap().unwrap();
+    assert!(server.handle().is_finished());
+}
+
+#[tokio::test]
+async fn run_async_reports_startup_failures() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dir = TestDir::new().unwrap();
+    let server = Server::builder()
+        .port(taken.local_addr().unwrap().port())
+        .handle_signals(false)
+        .disable_admin()
+        .log_path(dir.join("http.log"))
+        .pid_path(dir.join("rustbucket.pid"))
+        .config_source(MemorySource::default())
+        .build()
+        .unwrap();
+
+    let error = server.run_async().await.unwrap_err();
+
+    assert!(matches!(error, RustbucketError::Bind { .. }), "unexpected error: {}", error);
+}
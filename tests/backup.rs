@@ -0,0 +1,83 @@
+//! The `backup` and `restore` subcommands.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use rustbucket::kv::Store;
+use rustbucket::testing::TestDir;
+
+/// Runs `rustbucket` with `args` in `dir`
+fn rustbucket(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rustbucket")).args(args).current_dir(dir).output().unwrap()
+}
+
+/// A snapshot holding `greeting`, a log holding `counter`, and a config file
+fn populate(dir: &Path) {
+    let store = Store::open(dir.join("kv.snapshot")).unwrap().with_wal(dir.join("kv.wal")).unwrap();
+    store.set(b"greeting".to_vec(), b"hello".to_vec()).unwrap();
+    store.save().unwrap();
+    store.set(b"counter".to_vec(), b"1".to_vec()).unwrap();
+    fs::write(dir.join("config.dat"), b"config").unwrap();
+}
+
+fn open_store(dir: &Path) -> Store {
+    Store::open(dir.join("kv.snapshot")).unwrap().with_wal(dir.join("kv.wal")).unwrap()
+}
+
+#[test]
+fn restore_brings_back_the_backed_up_files() {
+    let dir = TestDir::new().unwrap();
+    populate(dir.path());
+    let output = rustbucket(dir.path(), &["backup", "backup.tar.zst"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Backed up 3 files"));
+
+    // Later changes, including a log the backup did not have, are undone
+    open_store(dir.path()).set(b"greeting".to_vec(), b"changed".to_vec()).unwrap();
+    fs::write(dir.join("config.dat"), b"changed").unwrap();
+
+    let output = rustbucket(dir.path(), &["restore", "backup.tar.zst", "--dry-run"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(dir.join("config.dat")).unwrap(), b"changed");
+
+    let output = rustbucket(dir.path(), &["--output", "json", "restore", "backup.tar.zst"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["restored"], true);
+    assert_eq!(report["files"].as_array().unwrap().len(), 3);
+
+    let store = open_store(dir.path());
+    assert_eq!(store.get(b"greeting").unwrap(), Some(b"hello".to_vec()));
+    assert_eq!(store.get(b"counter").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(fs::read(dir.join("config.dat")).unwrap(), b"config");
+}
+
+#[test]
+fn damaged_archives_are_refused_without_changing_anything() {
+    let dir = TestDir::new().unwrap();
+    populate(dir.path());
+    assert!(rustbucket(dir.path(), &["backup", "backup.tar.zst"]).status.success());
+    let archive = fs::read(dir.join("backup.tar.zst")).unwrap();
+    fs::write(dir.join("truncated.tar.zst"), &archive[..archive.len() / 2]).unwrap();
+    fs::write(dir.join("config.dat"), b"changed").unwrap();
+
+    let output = rustbucket(dir.path(), &["restore", "truncated.tar.zst"]);
+    assert!(!output.status.success());
+    assert_eq!(fs::read(dir.join("config.dat")).unwrap(), b"changed");
+
+    let output = rustbucket(dir.path(), &["restore", "missing.tar.zst"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn restore_refuses_while_the_server_runs() {
+    let dir = TestDir::new().unwrap();
+    populate(dir.path());
+    assert!(rustbucket(dir.path(), &["backup", "backup.tar.zst"]).status.success());
+    fs::write(dir.join("rustbucket.pid"), std::process::id().to_string()).unwrap();
+
+    let output = rustbucket(dir.path(), &["restore", "backup.tar.zst"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stop it before restoring"));
+}
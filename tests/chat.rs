@@ -0,0 +1,64 @@
+//! The chat room, end to end over TCP.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use rustbucket::chat::ChatHandler;
+use rustbucket::testing::{TestClient, TestServer};
+
+fn chat_server(room: Arc<ChatHandler>) -> TestServer {
+    TestServer::start_with(|builder| builder.handler(room)).unwrap()
+}
+
+/// Reads relayed lines until `count` have arrived
+fn read_lines(client: &mut TestClient, count: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    while lines.len() < count {
+        let reply = String::from_utf8(client.read_reply().unwrap()).unwrap();
+        assert!(!reply.is_empty(), "the server closed the connection");
+        lines.extend(reply.lines().map(str::to_string));
+    }
+    lines
+}
+
+#[test]
+fn lines_reach_everyone_else_with_the_senders_nickname() {
+    let room = Arc::new(ChatHandler::new());
+    let server = chat_server(Arc::clone(&room));
+    let mut alice = server.client().unwrap();
+    let mut bob = server.client().unwrap();
+
+    assert_eq!(alice.request("/nick alice\n").unwrap(), "* you joined as alice\n");
+    assert_eq!(bob.request("/nick bob\n").unwrap(), "* you joined as bob\n");
+    assert_eq!(read_lines(&mut alice, 1), ["* bob joined"]);
+    assert_eq!(room.members(), ["alice", "bob"]);
+
+    bob.stream().write_all(b"hello\nhow are you?\n").unwrap();
+    assert_eq!(read_lines(&mut alice, 2), ["bob: hello", "bob: how are you?"]);
+
+    assert_eq!(alice.request("/nick bob\n").unwrap(), "* the nickname bob is taken\n");
+    assert_eq!(alice.request("/nick al!ce\n").unwrap(), "* nicknames are 1 to 32 letters, digits, '-', or '_'\n");
+    assert_eq!(alice.request("/nick alicia\n").unwrap(), "* you are now alicia\n");
+    assert_eq!(read_lines(&mut bob, 1), ["* alice is now alicia"]);
+
+    bob.close();
+    assert_eq!(read_lines(&mut alice, 1), ["* bob left"]);
+}
+
+#[test]
+fn clients_join_as_guests_with_their_first_line() {
+    let server = chat_server(Arc::new(ChatHandler::new()));
+    let mut first = server.client().unwrap();
+    let mut second = server.client().unwrap();
+
+    let joined = first.request("hi\n").unwrap();
+    let nickname = joined.strip_prefix("* you joined as ").unwrap().trim_end().to_string();
+    assert!(nickname.starts_with("guest"), "{}", joined);
+
+    assert_eq!(second.request(&format!("/nick {}\n", nickname)).unwrap().lines().count(), 2);
+    assert_eq!(read_lines(&mut first, 1).len(), 1);
+    second.stream().write_all(b"anyone here?\n").unwrap();
+    let relayed = read_lines(&mut first, 1);
+    assert!(relayed[0].starts_with("guest") && relayed[0].ends_with(": anyone here?"), "{:?}", relayed);
+    assert_ne!(relayed[0], format!("{}: anyone here?", nickname));
+}
@@ -0,0 +1,155 @@
+//! The default echo protocol and custom handlers, end to end over TCP.
+
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::testing::TestServer;
+use rustbucket::{ConnectionCtx, RequestHandler, ResponseWriter};
+
+#[test]
+fn echoes_a_message() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+}
+
+#[test]
+fn echoes_every_message_on_a_connection() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+
+    for message in ["one\n", "two\n", "three\n"] {
+        assert_eq!(client.request(message).unwrap(), format!("Echo: {}", message));
+    }
+}
+
+#[test]
+fn serves_clients_concurrently() {
+    let server = TestServer::start_with(|builder| builder.threads(4)).unwrap();
+    let addr = server.addr();
+
+    let clients: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = rustbucket::testing::TestClient::connect(addr).unwrap();
+                client.request(&format!("client {}\n", i)).unwrap()
+            })
+        })
+        .collect();
+
+    for (i, client) in clients.into_iter().enumerate() {
+        assert_eq!(client.join().unwrap(), format!("Echo: client {}\n", i));
+    }
+}
+
+#[test]
+fn metrics_add_up_every_workers_share() {
+    let server = TestServer::start_with(|builder| builder.threads(4)).unwrap();
+    let addr = server.addr();
+
+    let clients: Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut client = rustbucket::testing::TestClient::connect(addr).unwrap();
+                for _ in 0..25 {
+                    assert_eq!(client.request("ping\n").unwrap(), "Echo: ping\n");
+                }
+                client.close();
+            })
+        })
+        .collect();
+    clients.into_iter().for_each(|client| client.join().unwrap());
+
+    let started = Instant::now();
+    while server.handle().metrics().connections_active > 0 && started.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let metrics = server.handle().metrics();
+    assert_eq!((metrics.connections_accepted, metrics.connections_active, metrics.connections_closed), (8, 0, 8));
+    assert_eq!(metrics.messages_received, 200);
+    assert_eq!((metrics.bytes_received, metrics.bytes_sent), (200 * 5, 200 * 11));
+}
+
+struct Shout;
+
+impl RequestHandler for Shout {
+    fn on_message(&self, _ctx: &ConnectionCtx, message: &[u8], response: &mut ResponseWriter) {
+        response.write(&message.to_ascii_uppercase());
+    }
+}
+
+#[test]
+fn replies_with_a_custom_handler() {
+    let server = TestServer::start_with(|builder| builder.handler(Shout)).unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("quiet please\n").unwrap(), "QUIET PLEASE\n");
+}
+
+struct Describe;
+
+impl RequestHandler for Describe {
+    fn on_message(&self, ctx: &ConnectionCtx, _message: &[u8], response: &mut ResponseWriter) {
+        let peer = ctx.peer_addr().unwrap();
+        let reply = format!("#{} message {} from {} timeout {}\n", ctx.id(), ctx.messages(), peer.ip(), ctx.config().timeout_seconds);
+        response.write(reply.as_bytes());
+    }
+}
+
+#[test]
+fn handlers_see_the_connection_context() {
+    let server = TestServer::start_with(|builder| builder.handler(Describe)).unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("a").unwrap(), "#1 message 1 from 127.0.0.1 timeout 30\n");
+    assert_eq!(client.request("b").unwrap(), "#1 message 2 from 127.0.0.1 timeout 30\n");
+    let mut second = server.client().unwrap();
+    assert_eq!(second.request("c").unwrap(), "#2 message 1 from 127.0.0.1 timeout 30\n");
+}
+
+#[test]
+fn logs_each_connection() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+    client.request("hello\n").unwrap();
+    client.close();
+    // Shutting down while the server still sees the client idle would send it a closing notice too
+    let started = Instant::now();
+    while server.handle().metrics().connections_active > 0 && started.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown();
+
+    let log = server.log();
+    assert!(log.contains("Connection #1 opened"), "log was:\n{}", log);
+    assert!(log.contains("(1 messages, 6 bytes in, 12 bytes out)"), "log was:\n{}", log);
+}
+
+/// Pushes as many numbered lines through the outbox as the message asks for
+struct Burst;
+
+impl RequestHandler for Burst {
+    fn on_message(&self, ctx: &ConnectionCtx, message: &[u8], _response: &mut ResponseWriter) {
+        let count: usize = String::from_utf8_lossy(message).trim().parse().unwrap();
+        let outbox = ctx.outbox();
+        for n in 0..count {
+            outbox.send(format!("line {}\n", n)).unwrap();
+        }
+    }
+}
+
+#[test]
+fn queued_outbox_messages_arrive_whole_and_in_order() {
+    let server = TestServer::start_with(|builder| builder.handler(Burst)).unwrap();
+    let client = server.client().unwrap();
+    let mut stream = client.stream();
+    stream.write_all(b"2000\n").unwrap();
+
+    // Sent faster than they can be written one by one, so they go out in batches
+    let expected: String = (0..2000).map(|n| format!("line {}\n", n)).collect();
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(String::from_utf8(received).unwrap(), expected);
+}
@@ -0,0 +1,118 @@
+//! The C interface, called the way a C program would.
+
+use std::ffi::{CStr, CString};
+use std::mem::{offset_of, size_of, MaybeUninit};
+use std::net::SocketAddr;
+use std::ptr;
+use std::thread;
+
+use rustbucket::ffi::{
+    rustbucket_config_init, rustbucket_last_error, rustbucket_start, rustbucket_stats, rustbucket_stop,
+    RustbucketConfig, RustbucketServer, RustbucketStats,
+};
+use rustbucket::testing::{TestClient, TestDir};
+
+/// A config for a server on a free port, keeping its files at `paths`
+fn config(paths: &[CString; 3]) -> RustbucketConfig {
+    let mut config = MaybeUninit::uninit();
+    unsafe { rustbucket_config_init(config.as_mut_ptr()) };
+    let mut config = unsafe { config.assume_init() };
+    config.port = 0;
+    config.admin_port = 0;
+    config.threads = 2;
+    config.log_path = paths[0].as_ptr();
+    config.config_path = paths[1].as_ptr();
+    config.pid_path = paths[2].as_ptr();
+    config
+}
+
+/// The log, config, and pidfile paths in `dir`
+fn paths(dir: &TestDir) -> [CString; 3] {
+    ["http.log", "config.dat", "rustbucket.pid"].map(|name| CString::new(dir.join(name).to_str().unwrap()).unwrap())
+}
+
+fn stats(server: *const RustbucketServer) -> RustbucketStats {
+    let mut stats = RustbucketStats { size: size_of::<RustbucketStats>() as u32, ..RustbucketStats::default() };
+    assert_eq!(unsafe { rustbucket_stats(server, &mut stats) }, 0);
+    stats
+}
+
+fn last_error() -> String {
+    let message = rustbucket_last_error();
+    assert!(!message.is_null());
+    unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+}
+
+#[test]
+fn a_started_server_serves_clients_and_reports_them_until_stopped() {
+    let dir = TestDir::new().unwrap();
+    let paths = paths(&dir);
+    let config = config(&paths);
+    let mut server = ptr::null_mut();
+
+    assert_eq!(unsafe { rustbucket_start(&config, &mut server) }, 0);
+    assert!(!server.is_null());
+    let port = stats(server).port;
+    assert_ne!(port, 0);
+
+    let mut client = TestClient::connect(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+    assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+    let stats = stats(server);
+    assert_eq!(stats.size as usize, size_of::<RustbucketStats>());
+    assert_eq!(stats.connections_accepted, 1);
+    assert_eq!(stats.messages_received, 1);
+    assert_eq!(stats.bytes_received, 6);
+
+    client.close();
+    assert_eq!(unsafe { rustbucket_stop(server, 1) }, 0);
+    assert!(dir.join("http.log").exists());
+}
+
+#[test]
+fn stats_from_an_older_header_fill_only_the_fields_it_knows() {
+    let dir = TestDir::new().unwrap();
+    let paths = paths(&dir);
+    let config = config(&paths);
+    let mut server = ptr::null_mut();
+    assert_eq!(unsafe { rustbucket_start(&config, &mut server) }, 0);
+
+    // A header that ended after `port`; what lies past it must be left alone
+    let known = offset_of!(RustbucketStats, connections_accepted);
+    let mut stats = RustbucketStats { size: known as u32, bytes_sent: u64::MAX, ..RustbucketStats::default() };
+    assert_eq!(unsafe { rustbucket_stats(server, &mut stats) }, 0);
+
+    assert_eq!(stats.size as usize, known);
+    assert_ne!(stats.port, 0);
+    assert_eq!(stats.bytes_sent, u64::MAX);
+    assert_eq!(unsafe { rustbucket_stop(server, 0) }, 0);
+}
+
+#[test]
+fn a_config_smaller_than_any_header_is_refused() {
+    let dir = TestDir::new().unwrap();
+    let paths = paths(&dir);
+    let mut config = config(&paths);
+    config.size = 4;
+    let mut server = ptr::null_mut();
+
+    assert_eq!(unsafe { rustbucket_start(&config, &mut server) }, 78);
+    assert!(server.is_null());
+    assert!(last_error().contains("incompatible header"), "{}", last_error());
+}
+
+#[test]
+fn null_arguments_are_reported_through_last_error() {
+    let mut server = ptr::null_mut();
+
+    assert_eq!(unsafe { rustbucket_start(ptr::null(), &mut server) }, 78);
+    assert!(last_error().contains("must not be NULL"), "{}", last_error());
+    assert_eq!(unsafe { rustbucket_stop(ptr::null_mut(), 1) }, 78);
+    assert!(last_error().contains("server must not be NULL"), "{}", last_error());
+}
+
+#[test]
+fn last_error_is_null_until_something_fails_on_the_thread() {
+    let fresh = thread::spawn(|| rustbucket_last_error().is_null()).join().unwrap();
+
+    assert!(fresh);
+}
@@ -0,0 +1,147 @@
+//! File uploads and downloads, end to end over TCP.
+
+use std::fs;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use rustbucket::files::FileHandler;
+use rustbucket::testing::{TestClient, TestDir, TestServer};
+
+fn file_server(dir: &TestDir, max_file_size: u64) -> TestServer {
+    let handler = FileHandler::new(dir.join("files")).unwrap().max_file_size(max_file_size);
+    TestServer::start_with(|builder| builder.handler(handler)).unwrap()
+}
+
+#[test]
+fn uploads_can_be_downloaded_again() {
+    let dir = TestDir::new().unwrap();
+    let server = file_server(&dir, 1024 * 1024);
+    let mut client = server.client().unwrap();
+    let contents: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    let checksum = format!("{:08x}", crc32fast::hash(&contents));
+
+    // The contents arrive over many reads, the first sharing one with the command
+    let mut upload = format!("PUT data.bin {}\n", contents.len()).into_bytes();
+    upload.extend_from_slice(&contents);
+    assert_eq!(client.send(&upload).unwrap(), format!("OK {}\n", checksum).as_bytes());
+    assert_eq!(fs::read(dir.join("files/data.bin")).unwrap(), contents);
+
+    client.stream().write_all(b"GETFILE data.bin\n").unwrap();
+    let mut download = Vec::new();
+    let header = format!("FILE {} {}\n", contents.len(), checksum);
+    while download.len() < header.len() + contents.len() {
+        download.extend(client.read_reply().unwrap());
+    }
+    assert_eq!(&download[..header.len()], header.as_bytes());
+    assert_eq!(&download[header.len()..], contents);
+
+    assert_eq!(client.request("GETFILE missing\n").unwrap(), "ERR no such file\n");
+}
+
+#[test]
+fn commands_may_be_split_across_reads() {
+    let dir = TestDir::new().unwrap();
+    let server = file_server(&dir, 1024);
+    let mut client = server.client().unwrap();
+
+    client.stream().write_all(b"PUT note").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    client.stream().write_all(b".txt 5 3610a686\nhel").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.request("lo").unwrap(), "OK 3610a686\n");
+    assert_eq!(client.request("PUT empty 0\n").unwrap(), "OK 00000000\n");
+    assert_eq!(fs::read(dir.join("files/empty")).unwrap(), b"");
+}
+
+#[test]
+fn the_command_acl_sees_whole_commands_and_not_uploads() {
+    let dir = TestDir::new().unwrap();
+    let server = file_server(&dir, 1024);
+    fs::write(server.paths().acl_file, "deny GETFILE\n").unwrap();
+    server.handle().reload().unwrap();
+    fs::write(dir.join("files/secret.txt"), "hidden").unwrap();
+    let mut client = server.client().unwrap();
+
+    // A denied command split across writes is still refused
+    client.stream().write_all(b"GETF").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.request("ILE secret.txt\n").unwrap(), "ERR GETFILE is not permitted\n");
+
+    // Contents that read like a denied command are only contents
+    let contents = "GETFILE secret.txt\n";
+    let checksum = format!("{:08x}", crc32fast::hash(contents.as_bytes()));
+    let upload = format!("PUT note.txt {}\n{}", contents.len(), contents);
+    assert_eq!(client.request(&upload).unwrap(), format!("OK {checksum}\n"));
+    assert_eq!(fs::read_to_string(dir.join("files/note.txt")).unwrap(), contents);
+    assert_eq!(server.handle().metrics().commands_denied, 1);
+}
+
+#[test]
+fn bad_names_sizes_and_checksums_are_refused() {
+    let dir = TestDir::new().unwrap();
+    let server = file_server(&dir, 8);
+    let mut client = server.client().unwrap();
+
+    // Refused contents are skipped, so the command after them is still understood
+    let mut replies = client.request("PUT ../escape 2\nhiGETFILE x\n").unwrap();
+    while replies.lines().count() < 2 {
+        replies.push_str(&String::from_utf8(client.read_reply().unwrap()).unwrap());
+    }
+    assert_eq!(replies, "ERR invalid file name\nERR no such file\n");
+    assert_eq!(client.request("PUT .hidden 2\nhi").unwrap(), "ERR invalid file name\n");
+    assert_eq!(client.request("PUT big 9\n123456789").unwrap(), "ERR file is larger than 8 bytes\n");
+    assert_eq!(
+        client.request("PUT bad 5 deadbeef\nhello").unwrap(),
+        "ERR checksum mismatch: expected deadbeef, received 3610a686\n"
+    );
+    assert_eq!(client.request("GETFILE ../Cargo.toml\n").unwrap(), "ERR invalid file name\n");
+
+    let stored: Vec<_> = fs::read_dir(dir.join("files")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert!(stored.is_empty(), "{:?}", stored);
+}
+
+#[test]
+fn interrupted_uploads_leave_nothing_behind() {
+    let dir = TestDir::new().unwrap();
+    let server = file_server(&dir, 1024);
+    let client = server.client().unwrap();
+
+    client.stream().write_all(b"PUT partial 100\nonly some of it").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    client.close();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(fs::read_dir(dir.join("files")).unwrap().count(), 0);
+}
+
+#[test]
+fn downloads_are_sent_whole_and_checksums_follow_changes() {
+    let dir = TestDir::new().unwrap();
+    let server = file_server(&dir, 1024);
+    let mut client = server.client().unwrap();
+
+    // Files placed in the directory by hand are served too, however large
+    let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i % 253) as u8).collect();
+    fs::write(dir.join("files/large.bin"), &contents).unwrap();
+    let mut expected = format!("FILE {} {:08x}\n", contents.len(), crc32fast::hash(&contents)).into_bytes();
+    expected.extend_from_slice(&contents);
+    assert!(download(&mut client, "large.bin", expected.len()) == expected, "downloaded contents differ");
+
+    // The connection is still in step for the next command
+    assert_eq!(client.request("PUT note 5\nhello").unwrap(), "OK 3610a686\n");
+    assert_eq!(download(&mut client, "note", 21), b"FILE 5 3610a686\nhello");
+    thread::sleep(Duration::from_millis(10));
+    fs::write(dir.join("files/note"), "changed").unwrap();
+    let changed = format!("FILE 7 {:08x}\nchanged", crc32fast::hash(b"changed"));
+    assert_eq!(download(&mut client, "note", changed.len()), changed.as_bytes());
+}
+
+/// Sends `GETFILE name` and reads a `len`-byte reply
+fn download(client: &mut TestClient, name: &str, len: usize) -> Vec<u8> {
+    client.stream().write_all(format!("GETFILE {}\n", name).as_bytes()).unwrap();
+    let mut reply = Vec::new();
+    while reply.len() < len {
+        reply.extend(client.read_reply().unwrap());
+    }
+    reply
+}
@@ -0,0 +1,85 @@
+//! Handler plugin for the plugin tests, written against `include/rustbucket_plugin.h`.
+//!
+//! Registers as `upper` and answers each message with the connection id and
+//! the message in upper case. A few extra exports let the tests see how often
+//! it was initialized and destroyed, and make it claim another interface version.
+
+use std::ffi::{c_char, c_int, c_void};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+#[repr(C)]
+pub struct Message {
+    size: u32,
+    connection_id: u64,
+    peer: *const c_char,
+    data: *const u8,
+    len: usize,
+}
+
+type WriteFn = unsafe extern "C" fn(response: *mut c_void, data: *const u8, len: usize);
+
+#[repr(C)]
+pub struct Plugin {
+    size: u32,
+    abi_version: u32,
+    name: *const c_char,
+    state: *mut c_void,
+    on_message: Option<unsafe extern "C" fn(*mut c_void, *const Message, WriteFn, *mut c_void)>,
+    destroy: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+static ABI_VERSION: AtomicU32 = AtomicU32::new(1);
+static INITS: AtomicU32 = AtomicU32::new(0);
+static DESTROYS: AtomicU32 = AtomicU32::new(0);
+
+/// What `state` points to: messages answered so far
+struct State {
+    answered: AtomicU64,
+}
+
+#[no_mangle]
+pub extern "C" fn upper_set_abi_version(version: u32) {
+    ABI_VERSION.store(version, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub extern "C" fn upper_inits() -> u32 {
+    INITS.load(Ordering::SeqCst)
+}
+
+#[no_mangle]
+pub extern "C" fn upper_destroys() -> u32 {
+    DESTROYS.load(Ordering::SeqCst)
+}
+
+/// # Safety
+/// `plugin` must point to a `rustbucket_plugin_t`.
+#[no_mangle]
+pub unsafe extern "C" fn rustbucket_plugin_init(plugin: *mut Plugin) -> c_int {
+    INITS.fetch_add(1, Ordering::SeqCst);
+    let plugin = unsafe { &mut *plugin };
+    plugin.abi_version = ABI_VERSION.load(Ordering::SeqCst);
+    plugin.name = c"upper".as_ptr();
+    plugin.state = Box::into_raw(Box::new(State { answered: AtomicU64::new(0) })).cast();
+    plugin.on_message = Some(on_message);
+    plugin.destroy = Some(destroy);
+    0
+}
+
+unsafe extern "C" fn on_message(state: *mut c_void, message: *const Message, write: WriteFn, response: *mut c_void) {
+    let state = unsafe { &*(state as *const State) };
+    let message = unsafe { &*message };
+    let data = unsafe { std::slice::from_raw_parts(message.data, message.len) };
+    state.answered.fetch_add(1, Ordering::Relaxed);
+
+    // Two pieces, to check they make up one reply
+    let prefix = format!("#{} ", message.connection_id);
+    unsafe { write(response, prefix.as_ptr(), prefix.len()) };
+    let upper = data.to_ascii_uppercase();
+    unsafe { write(response, upper.as_ptr(), upper.len()) };
+}
+
+unsafe extern "C" fn destroy(state: *mut c_void) {
+    drop(unsafe { Box::from_raw(state as *mut State) });
+    DESTROYS.fetch_add(1, Ordering::SeqCst);
+}
@@ -0,0 +1,77 @@
+//! Putting messages split across reads back together before they are handled.
+
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustbucket::kv::{KvHandler, Store};
+use rustbucket::protocol::Framing;
+use rustbucket::testing::TestServer;
+use rustbucket::RustbucketError;
+
+/// Gives the server time to read what was written so far on its own
+fn let_server_read() {
+    thread::sleep(Duration::from_millis(50));
+}
+
+#[test]
+fn line_framing_waits_for_whole_lines() {
+    let server = TestServer::start_with(|builder| builder.framing(Framing::Line)).unwrap();
+    let mut client = server.client().unwrap();
+
+    client.stream().write_all(b"hel").unwrap();
+    let_server_read();
+    assert_eq!(client.request("lo\nwor").unwrap(), "Echo: hello\n");
+    let_server_read();
+    assert_eq!(client.request("ld\nand more\n").unwrap(), "Echo: world\nand more\n");
+}
+
+#[test]
+fn without_line_framing_each_read_is_a_message() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("hel").unwrap(), "Echo: hel");
+    assert_eq!(client.request("lo\n").unwrap(), "Echo: lo\n");
+}
+
+#[test]
+fn an_unfinished_message_is_handled_when_the_client_closes_its_end() {
+    let server = TestServer::start_with(|builder| builder.framing(Framing::Line)).unwrap();
+    let mut client = server.client().unwrap();
+    client.stream().write_all(b"first\nno newline").unwrap();
+    assert_eq!(client.read_reply().unwrap(), b"Echo: first\n");
+
+    client.stream().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(client.read_reply().unwrap(), b"Echo: no newline");
+}
+
+#[test]
+fn a_message_past_the_largest_size_closes_the_connection() {
+    let server = TestServer::start_with(|builder| builder.framing(Framing::Line).max_message_size(256)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request(&"x".repeat(300)).unwrap(), "ERR message longer than 256 bytes\n");
+    assert!(client.is_closed_by_server());
+
+    let error = TestServer::start_with(|builder| builder.max_message_size(16)).err().unwrap();
+    assert!(matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("16")), "{}", error);
+}
+
+#[test]
+fn kv_values_longer_than_the_read_buffer_arrive_whole() {
+    let store = Arc::new(Store::new());
+    let server = TestServer::start_with(|builder| builder.handler(KvHandler::new(Arc::clone(&store)))).unwrap();
+    let mut client = server.client().unwrap();
+
+    let value = "v".repeat(10_000);
+    let command = format!("SET big ${}\n{}\n", value.len(), value);
+    // Reaches the server in several reads of the default 1 KiB
+    assert_eq!(client.request(&command).unwrap(), "OK\n");
+    assert_eq!(store.get(b"big").unwrap().as_deref(), Some(value.as_bytes()));
+
+    // A command line split across reads is run once, when its line is complete
+    client.stream().write_all(b"SET sma").unwrap();
+    let_server_read();
+    assert_eq!(client.request("ll 1\nGET small\n").unwrap(), "OK\n1\n");
+}
@@ -0,0 +1,41 @@
+//! Heap statistics kept by the counting allocator.
+
+use std::hint::black_box;
+
+use rustbucket::memory::{heap_stats, CountingAllocator};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::new(std::alloc::System);
+
+/// Bytes other threads of the test harness might allocate meanwhile
+const SLACK: usize = 64 * 1024;
+
+#[test]
+fn the_wrapped_allocator_is_named() {
+    assert_eq!(heap_stats().unwrap().allocator, "System");
+}
+
+#[test]
+fn allocations_and_frees_are_counted() {
+    let before = heap_stats().unwrap();
+    let block = black_box(vec![0u8; 4 * 1024 * 1024]);
+    let during = heap_stats().unwrap();
+    assert!(during.allocated >= before.allocated + block.len(), "{:?} then {:?}", before, during);
+    assert!(during.peak >= during.allocated);
+    assert!(during.allocations > before.allocations);
+    assert!(during.live() > 0);
+
+    drop(block);
+    let after = heap_stats().unwrap();
+    assert!(after.allocated < during.allocated - 4 * 1024 * 1024 + SLACK, "{:?} then {:?}", during, after);
+    assert!(after.peak >= during.allocated);
+    assert!(after.frees > during.frees);
+
+    // Growing an allocation from 1 MiB to 4 MiB counts the new size in place of the old
+    let mut grown = black_box(Vec::<u8>::with_capacity(1024 * 1024));
+    let before = heap_stats().unwrap();
+    grown.reserve_exact(4 * 1024 * 1024);
+    let after = heap_stats().unwrap();
+    assert!(after.allocated + SLACK >= before.allocated + 3 * 1024 * 1024, "{:?} then {:?}", before, after);
+    assert!(after.allocated <= before.allocated + 3 * 1024 * 1024 + SLACK, "{:?} then {:?}", before, after);
+}
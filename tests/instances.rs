@@ -0,0 +1,21 @@
+//! Several servers in one process, each with its own files.
+
+use rustbucket::testing::TestServer;
+
+#[test]
+fn servers_keep_separate_files() {
+    let first = TestServer::start().unwrap();
+    let second = TestServer::start().unwrap();
+    assert_ne!(first.paths(), second.paths());
+
+    first.client().unwrap().request("one\n").unwrap();
+    second.client().unwrap().request("two\n").unwrap();
+    first.shutdown();
+    second.shutdown();
+
+    assert!(first.log().contains("Connection #1 opened"));
+    assert!(second.log().contains("Connection #1 opened"));
+    assert!(first.config_path().exists());
+    assert!(second.config_path().exists());
+    assert!(!first.paths().pid_file.exists());
+}
@@ -0,0 +1,701 @@
+//! The KV store and its command protocol, end to end over TCP.
+
+use std::fs;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::kv::{BlobStore, KeyspaceInfo, KvHandler, ListEnd, Store, BLOB_THRESHOLD, MAX_PATTERN_LEN};
+use rustbucket::testing::{TestClient, TestDir, TestServer};
+use rustbucket::RustbucketError;
+
+fn kv_server(store: Arc<Store>) -> TestServer {
+    TestServer::start_with(|builder| builder.handler(KvHandler::new(store))).unwrap()
+}
+
+/// Reads pushed messages until `count` lines have arrived
+fn read_lines(client: &mut TestClient, count: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    while lines.len() < count {
+        let reply = String::from_utf8(client.read_reply().unwrap()).unwrap();
+        assert!(!reply.is_empty(), "the server closed the connection");
+        lines.extend(reply.lines().map(str::to_string));
+    }
+    lines
+}
+
+#[test]
+fn sets_gets_and_deletes_keys() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("SET greeting \"hello world\"\n").unwrap(), "OK\n");
+    assert_eq!(client.request("get greeting\n").unwrap(), "hello world\n");
+    assert_eq!(client.request("DEL greeting missing\n").unwrap(), "1\n");
+    assert_eq!(client.request("GET greeting\n").unwrap(), "(nil)\n");
+}
+
+#[test]
+fn answers_every_command_in_a_message() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("SET a 1\r\nSET b 2\r\nGET b\r\n").unwrap(), "OK\nOK\n2\n");
+}
+
+#[test]
+fn reports_malformed_commands() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("FROB x\n").unwrap(), "ERR unknown command 'FROB'\n");
+    assert_eq!(client.request("SET x\n").unwrap(), "ERR wrong number of arguments for 'SET'\n");
+    assert_eq!(client.request("SET \"x 1\n").unwrap(), "ERR unterminated quoted argument\n");
+    assert!(client.request("SAVE\n").unwrap().starts_with("ERR "));
+}
+
+#[test]
+fn pipelined_commands_with_long_quoted_values() {
+    let store = Arc::new(Store::new());
+    let server =
+        TestServer::start_with(|builder| builder.read_buffer_size(64 * 1024).handler(KvHandler::new(store))).unwrap();
+    let mut client = server.client().unwrap();
+
+    let value = |i: usize| format!("{} say \\\"hi\\\" \\\\ {}", i, "x".repeat(400));
+    let sets: String = (0..40).map(|i| format!("SET key{} \"{}\"\n", i, value(i))).collect();
+    assert_eq!(client.request(&sets).unwrap(), "OK\n".repeat(40));
+    let expected = format!("{} say \"hi\" \\ {}\n", 39, "x".repeat(400));
+    assert_eq!(client.request("GET key39\n").unwrap(), expected);
+
+    // A broken quoted argument costs only the line it is on
+    assert_eq!(client.request("SET a \"x\nSET b 2\nGET b\n").unwrap(), "ERR unterminated quoted argument\nOK\n2\n");
+    assert_eq!(client.request("SET a \"x\\\nGET b\n").unwrap(), "ERR unterminated quoted argument\n2\n");
+}
+
+#[test]
+fn saved_snapshots_are_loaded_on_open() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("kv.snapshot");
+    {
+        let server = kv_server(Arc::new(Store::open(&path).unwrap()));
+        let mut client = server.client().unwrap();
+        client.request("SET kept yes\n").unwrap();
+        assert_eq!(client.request("SAVE\n").unwrap(), "OK\n");
+        client.request("SET unsaved yes\n").unwrap();
+    }
+
+    let store = Store::open(&path).unwrap();
+    assert_eq!(store.get(b"kept").unwrap().as_deref(), Some(&b"yes"[..]));
+    assert_eq!(store.get(b"unsaved").unwrap(), None);
+}
+
+#[test]
+fn the_snapshot_task_saves_when_dropped() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("kv.snapshot");
+    let store = Arc::new(Store::open(&path).unwrap());
+    let snapshots = store.start_snapshots(Duration::ZERO);
+    store.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    drop(snapshots);
+
+    assert_eq!(Store::open(&path).unwrap().len(), 1);
+}
+
+#[test]
+fn corrupt_snapshots_are_refused() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("kv.snapshot");
+    let store = Store::open(&path).unwrap();
+    store.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    store.save().unwrap();
+
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 5;
+    bytes[last] ^= 0xff;
+    fs::write(&path, bytes).unwrap();
+
+    let error = Store::open(&path).unwrap_err();
+    assert!(matches!(error, RustbucketError::Storage { .. }));
+    assert_eq!(error.exit_code(), 74);
+}
+
+#[test]
+fn unsaved_changes_are_replayed_from_the_wal() {
+    let dir = TestDir::new().unwrap();
+    let open = || Store::open(dir.join("kv.snapshot")).unwrap().with_wal(dir.join("kv.wal")).unwrap();
+    {
+        let server = kv_server(Arc::new(open()));
+        let mut client = server.client().unwrap();
+        client.request("SET kept yes\nSET gone yes\nSAVE\n").unwrap();
+        client.request("SET unsaved yes\nDEL gone\n").unwrap();
+    }
+
+    let store = open();
+    assert_eq!(store.get(b"kept").unwrap().as_deref(), Some(&b"yes"[..]));
+    assert_eq!(store.get(b"unsaved").unwrap().as_deref(), Some(&b"yes"[..]));
+    assert_eq!(store.get(b"gone").unwrap(), None);
+}
+
+#[test]
+fn a_torn_wal_record_is_dropped() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("kv.wal");
+    {
+        let store = Store::new().with_wal(&path).unwrap();
+        store.set(b"first".to_vec(), b"1".to_vec()).unwrap();
+        store.set(b"second".to_vec(), b"2".to_vec()).unwrap();
+    }
+    let len = fs::metadata(&path).unwrap().len();
+    fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+    let store = Store::new().with_wal(&path).unwrap();
+    assert_eq!(store.get(b"first").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(store.get(b"second").unwrap(), None);
+
+    store.set(b"third".to_vec(), b"3".to_vec()).unwrap();
+    drop(store);
+    let store = Store::new().with_wal(&path).unwrap();
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get(b"third").unwrap().as_deref(), Some(&b"3"[..]));
+}
+
+#[test]
+fn saving_compacts_the_wal() {
+    let dir = TestDir::new().unwrap();
+    let wal = dir.join("kv.wal");
+    let store = Store::open(dir.join("kv.snapshot")).unwrap().with_wal(&wal).unwrap();
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec()).unwrap();
+    }
+    assert!(fs::metadata(&wal).unwrap().len() > 0);
+
+    store.save().unwrap();
+    assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+    store.set(b"after".to_vec(), b"save".to_vec()).unwrap();
+    drop(store);
+
+    let store = Store::open(dir.join("kv.snapshot")).unwrap().with_wal(&wal).unwrap();
+    assert_eq!(store.len(), 11);
+}
+
+#[test]
+fn keys_set_with_a_ttl_expire() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("SET short lived PX 50\nSET long lived EX 60\n").unwrap(), "OK\nOK\n");
+    assert_eq!(client.request("TTL long\nTTL short\n").unwrap(), "60\n0\n");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.request("GET short\nTTL short\n").unwrap(), "(nil)\n-2\n");
+    assert_eq!(client.request("SET x 1 EX 0\nSET x 1 EX\n").unwrap(), "ERR invalid expire time in 'SET'\nERR syntax error\n");
+}
+
+#[test]
+fn expire_and_persist_change_deadlines() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    client.request("SET key value\n").unwrap();
+    assert_eq!(client.request("TTL key\nEXPIRE key 30\nTTL key\n").unwrap(), "-1\n1\n30\n");
+    assert_eq!(client.request("PERSIST key\nPERSIST key\nTTL key\n").unwrap(), "1\n0\n-1\n");
+    assert_eq!(client.request("EXPIRE missing 30\nTTL missing\n").unwrap(), "0\n-2\n");
+}
+
+#[test]
+fn deadlines_past_what_the_clock_can_hold_are_refused() {
+    let store = Arc::new(Store::new());
+    let server = kv_server(Arc::clone(&store));
+    let mut client = server.client().unwrap();
+    let max = u64::MAX;
+
+    client.request("SET key value\n").unwrap();
+    assert_eq!(
+        client.request(&format!("SET key other EX {max}\nSET key other PX {max}\nEXPIRE key {max}\n")).unwrap(),
+        "ERR invalid expire time\nERR invalid expire time\nERR invalid expire time\n"
+    );
+    assert_eq!(client.request("GET key\nTTL key\n").unwrap(), "value\n-1\n");
+
+    // Inside a transaction too, without taking the server down with it
+    client.request("MULTI\n").unwrap();
+    client.request(&format!("SET key other EX {max}\nEXPIRE key {max}\nGET key\n")).unwrap();
+    assert_eq!(client.request("EXEC\n").unwrap(), "*3\nERR invalid expire time\nERR invalid expire time\nvalue\n");
+    assert_eq!(server.client().unwrap().request("GET key\n").unwrap(), "value\n");
+    assert!(matches!(
+        store.set_with_ttl(b"key".to_vec(), b"other".to_vec(), Duration::MAX),
+        Err(RustbucketError::InvalidExpireTime)
+    ));
+}
+
+#[test]
+fn subscribers_hear_about_removed_keys() {
+    let store = Arc::new(Store::new());
+    let expired = store.subscribe_expired();
+    store.set_with_ttl(b"brief".to_vec(), b"value".to_vec(), Duration::from_millis(10)).unwrap();
+    store.set(b"forever".to_vec(), b"value".to_vec()).unwrap();
+
+    let _expiry = store.start_expiry(Duration::from_millis(10));
+    assert_eq!(expired.recv_timeout(Duration::from_secs(5)).unwrap(), b"brief");
+    assert_eq!(store.len(), 1);
+}
+
+#[test]
+fn deadlines_survive_snapshots_and_the_wal() {
+    let dir = TestDir::new().unwrap();
+    let open = || Store::open(dir.join("kv.snapshot")).unwrap().with_wal(dir.join("kv.wal")).unwrap();
+    {
+        let store = open();
+        store.set_with_ttl(b"saved".to_vec(), b"value".to_vec(), Duration::from_secs(3600)).unwrap();
+        store.set_with_ttl(b"brief".to_vec(), b"value".to_vec(), Duration::from_millis(10)).unwrap();
+        store.save().unwrap();
+        store.set(b"logged".to_vec(), b"value".to_vec()).unwrap();
+        store.expire(b"logged", Duration::from_secs(3600)).unwrap();
+    }
+    thread::sleep(Duration::from_millis(20));
+
+    let store = open();
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get(b"brief").unwrap(), None);
+    for key in [&b"saved"[..], b"logged"] {
+        let left = store.ttl(key).flatten().unwrap();
+        assert!(left > Duration::from_secs(3590) && left <= Duration::from_secs(3600));
+    }
+}
+
+#[test]
+fn lists_push_pop_and_range() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("RPUSH queue b c\nLPUSH queue a\n").unwrap(), "2\n3\n");
+    assert_eq!(client.request("LRANGE queue 0 -1\n").unwrap(), "*3\na\nb\nc\n");
+    assert_eq!(client.request("LRANGE queue -2 100\n").unwrap(), "*2\nb\nc\n");
+    assert_eq!(client.request("LPOP queue\nRPOP queue 5\n").unwrap(), "a\n*2\nc\nb\n");
+    assert_eq!(client.request("LPOP queue\nLRANGE queue 0 -1\n").unwrap(), "(nil)\n*0\n");
+    assert_eq!(client.request("SET plain value\nLPUSH plain x\n").unwrap(), "OK\nERR the key holds a different kind of value\n");
+    assert_eq!(client.request("RPUSH list x\nGET list\n").unwrap(), "1\nERR the key holds a different kind of value\n");
+}
+
+#[test]
+fn blpop_waits_for_a_push() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut waiter = server.client().unwrap();
+    let mut pusher = server.client().unwrap();
+
+    assert_eq!(waiter.request("BLPOP empty 0.05\n").unwrap(), "(nil)\n");
+    let blocked = thread::spawn(move || waiter.request("BLPOP jobs other 5\n").unwrap());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pusher.request("RPUSH jobs first\n").unwrap(), "1\n");
+    assert_eq!(blocked.join().unwrap(), "*2\njobs\nfirst\n");
+}
+
+#[test]
+fn blpop_with_a_timeout_past_the_clock_waits_without_one() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut waiter = server.client().unwrap();
+    let mut pusher = server.client().unwrap();
+
+    pusher.request("RPUSH jobs ready\n").unwrap();
+    assert_eq!(waiter.request("BLPOP jobs 1e19\n").unwrap(), "*2\njobs\nready\n");
+    let blocked = thread::spawn(move || waiter.request("BLPOP jobs 1e19\n").unwrap());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pusher.request("RPUSH jobs later\n").unwrap(), "1\n");
+    assert_eq!(blocked.join().unwrap(), "*2\njobs\nlater\n");
+}
+
+#[test]
+fn lists_survive_snapshots_and_the_wal() {
+    let dir = TestDir::new().unwrap();
+    let open = || Store::open(dir.join("kv.snapshot")).unwrap().with_wal(dir.join("kv.wal")).unwrap();
+    let items = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+    {
+        let store = open();
+        store.push(b"saved", ListEnd::Back, &items(&["a", "b", "c"])).unwrap();
+        store.save().unwrap();
+        store.pop(b"saved", ListEnd::Front, 1).unwrap();
+        store.push(b"logged", ListEnd::Front, &items(&["x", "y"])).unwrap();
+    }
+
+    let store = open();
+    assert_eq!(store.range(b"saved", 0, -1).unwrap(), items(&["b", "c"]));
+    assert_eq!(store.range(b"logged", 0, -1).unwrap(), items(&["y", "x"]));
+}
+
+#[test]
+fn published_messages_reach_subscribers() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut subscriber = server.client().unwrap();
+    let mut publisher = server.client().unwrap();
+
+    assert_eq!(subscriber.request("SUBSCRIBE news sport\n").unwrap(), "*3\nsubscribe\nnews\n1\n*3\nsubscribe\nsport\n2\n");
+    assert_eq!(publisher.request("PUBLISH news \"hello there\"\nPUBLISH weather sunny\n").unwrap(), "1\n0\n");
+    assert_eq!(subscriber.read_reply().unwrap(), b"*3\nmessage\nnews\nhello there\n");
+
+    assert_eq!(subscriber.request("UNSUBSCRIBE news\n").unwrap(), "*3\nunsubscribe\nnews\n1\n");
+    assert_eq!(publisher.request("PUBLISH news ignored\n").unwrap(), "0\n");
+    assert_eq!(subscriber.request("UNSUBSCRIBE\n").unwrap(), "*3\nunsubscribe\nsport\n0\n");
+}
+
+#[test]
+fn closed_connections_are_unsubscribed() {
+    let store = Arc::new(Store::new());
+    let server = kv_server(Arc::clone(&store));
+    let mut subscriber = server.client().unwrap();
+    subscriber.request("SUBSCRIBE news\n").unwrap();
+    subscriber.close();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.pubsub().publish(b"news", b"anyone?") > 0 {
+        assert!(Instant::now() < deadline, "subscription outlived its connection");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn subscribers_that_stop_reading_are_disconnected() {
+    let store = Arc::new(Store::new());
+    let server = kv_server(Arc::clone(&store));
+    let mut subscriber = server.client().unwrap();
+    subscriber.request("SUBSCRIBE firehose\n").unwrap();
+
+    let message = vec![b'x'; 64 * 1024];
+    let mut published = 0;
+    while store.pubsub().publish(b"firehose", &message) > 0 {
+        published += 1;
+        assert!(published < 10_000, "the subscriber was never dropped");
+    }
+
+    // Whatever was queued before the overflow may still arrive, then the connection ends
+    loop {
+        match subscriber.read_reply() {
+            Ok(reply) if reply.is_empty() => break,
+            Ok(_) => continue,
+            Err(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn pattern_subscribers_receive_matching_channels() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut subscriber = server.client().unwrap();
+    let mut publisher = server.client().unwrap();
+
+    assert_eq!(
+        subscriber.request("PSUBSCRIBE events.* user.? log.[^d]*\n").unwrap(),
+        "*3\npsubscribe\nevents.*\n1\n*3\npsubscribe\nuser.?\n2\n*3\npsubscribe\nlog.[^d]*\n3\n"
+    );
+    let published = "PUBLISH events.login x\nPUBLISH user.1 y\nPUBLISH user.10 n\nPUBLISH log.debug n\nPUBLISH log.error z\n";
+    assert_eq!(publisher.request(published).unwrap(), "1\n1\n0\n0\n1\n");
+
+    assert_eq!(
+        read_lines(&mut subscriber, 3 * 5).join(" "),
+        "*4 pmessage events.* events.login x *4 pmessage user.? user.1 y *4 pmessage log.[^d]* log.error z"
+    );
+
+    assert_eq!(subscriber.request("PUNSUBSCRIBE events.*\n").unwrap(), "*3\npunsubscribe\nevents.*\n2\n");
+    assert_eq!(publisher.request("PUBLISH events.login x\n").unwrap(), "0\n");
+}
+
+#[test]
+fn channel_and_pattern_subscriptions_both_deliver() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut subscriber = server.client().unwrap();
+    let mut publisher = server.client().unwrap();
+
+    subscriber.request("SUBSCRIBE news\n").unwrap();
+    assert_eq!(subscriber.request("PSUBSCRIBE n*\n").unwrap(), "*3\npsubscribe\nn*\n2\n");
+    assert_eq!(publisher.request("PUBLISH news hi\n").unwrap(), "2\n");
+    let mut received = read_lines(&mut subscriber, 4 + 5);
+    received.sort();
+    assert_eq!(received, ["*3", "*4", "hi", "hi", "message", "n*", "news", "news", "pmessage"]);
+    assert_eq!(subscriber.request("PUNSUBSCRIBE\n").unwrap(), "*3\npunsubscribe\nn*\n1\n");
+}
+
+#[test]
+fn long_patterns_subscribe_and_unsubscribe_up_to_the_limit() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut subscriber = server.client().unwrap();
+    let mut publisher = server.client().unwrap();
+    let pattern = format!("{}*", "a".repeat(MAX_PATTERN_LEN - 1));
+
+    assert_eq!(
+        subscriber.request(&format!("PSUBSCRIBE {pattern}\n")).unwrap(),
+        format!("*3\npsubscribe\n{pattern}\n1\n")
+    );
+    assert_eq!(publisher.request(&format!("PUBLISH {pattern}b x\n")).unwrap(), "1\n");
+    read_lines(&mut subscriber, 5);
+    assert_eq!(
+        subscriber.request(&format!("PUNSUBSCRIBE {pattern}\n")).unwrap(),
+        format!("*3\npunsubscribe\n{pattern}\n0\n")
+    );
+    assert_eq!(publisher.request(&format!("PUBLISH {pattern}b x\n")).unwrap(), "0\n");
+
+    // Dropped again when the connection closes instead
+    subscriber.request(&format!("PSUBSCRIBE {pattern} {}\n", &pattern[1..])).unwrap();
+    subscriber.close();
+    let too_long = "a".repeat(60_000);
+    assert_eq!(
+        publisher.request(&format!("PSUBSCRIBE {too_long}\n")).unwrap(),
+        format!("ERR pattern longer than {MAX_PATTERN_LEN} bytes\n")
+    );
+    assert_eq!(publisher.request("PING\n").unwrap(), "PONG\n");
+}
+
+#[test]
+fn transactions_apply_queued_commands_on_exec() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+    let mut other = server.client().unwrap();
+
+    assert_eq!(client.request("MULTI\n").unwrap(), "OK\n");
+    assert_eq!(client.request("SET a 1\nRPUSH list x y\nGET a\nLPOP a\n").unwrap(), "QUEUED\nQUEUED\nQUEUED\nQUEUED\n");
+    assert_eq!(other.request("GET a\n").unwrap(), "(nil)\n");
+    assert_eq!(client.request("MULTI\n").unwrap(), "ERR MULTI calls can not be nested\n");
+    assert_eq!(
+        client.request("EXEC\n").unwrap(),
+        "*4\nOK\n2\n1\nERR the key holds a different kind of value\n"
+    );
+    assert_eq!(other.request("GET a\nLRANGE list 0 -1\n").unwrap(), "1\n*2\nx\ny\n");
+    assert_eq!(client.request("EXEC\n").unwrap(), "ERR EXEC without MULTI\n");
+}
+
+#[test]
+fn discarded_and_aborted_transactions_change_nothing() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    client.request("MULTI\n").unwrap();
+    client.request("SET a 1\n").unwrap();
+    assert_eq!(client.request("DISCARD\n").unwrap(), "OK\n");
+    assert_eq!(client.request("GET a\n").unwrap(), "(nil)\n");
+
+    client.request("MULTI\n").unwrap();
+    assert_eq!(client.request("SET a \"1\n").unwrap(), "ERR unterminated quoted argument\n");
+    client.request("SET b 2\n").unwrap();
+    assert_eq!(client.request("EXEC\n").unwrap(), "ERR transaction discarded because of previous errors\n");
+    assert_eq!(client.request("GET b\n").unwrap(), "(nil)\n");
+}
+
+#[test]
+fn blocking_pops_in_a_transaction_do_not_wait() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    client.request("MULTI\n").unwrap();
+    client.request("BLPOP list 0\n").unwrap();
+    assert_eq!(client.request("EXEC\n").unwrap(), "*1\n(nil)\n");
+}
+
+#[test]
+fn blob_stores_reuse_and_merge_freed_slots() {
+    let dir = TestDir::new().unwrap();
+    let blobs = BlobStore::create(dir.join("kv.blobs"), 16, 4).unwrap();
+
+    let first = blobs.store(&[1; 40]).unwrap();
+    assert_eq!(blobs.free(), 16);
+    let second = blobs.store(&[2; 10]).unwrap();
+    assert!(blobs.store(&[3; 1]).is_none(), "the store is full");
+    assert_eq!(&*first.bytes(), &[1; 40]);
+
+    drop(first);
+    drop(second);
+    assert_eq!(blobs.free(), blobs.capacity());
+    assert_eq!(&*blobs.store(&[4; 64]).unwrap().bytes(), &[4; 64]);
+}
+
+#[test]
+fn large_values_are_kept_in_the_blob_store() {
+    let dir = TestDir::new().unwrap();
+    let blobs = BlobStore::create(dir.join("kv.blobs"), 4096, 16).unwrap();
+    let store = Arc::new(Store::open(dir.join("kv.snapshot")).unwrap().with_blobs(blobs.clone()));
+    let large: Vec<u8> = (0..BLOB_THRESHOLD * 2).map(|i| (i % 251) as u8).collect();
+
+    store.set(b"small".to_vec(), b"x".to_vec()).unwrap();
+    store.set(b"large".to_vec(), large.clone()).unwrap();
+    assert_eq!(blobs.free(), blobs.capacity() - large.len());
+    assert_eq!(store.get(b"large").unwrap(), Some(large.clone()));
+    assert!(store.push(b"large", ListEnd::Back, &[b"item".to_vec()]).is_err());
+
+    let server = kv_server(Arc::clone(&store));
+    let mut client = server.client().unwrap();
+    client.stream().write_all(b"GET large\n").unwrap();
+    // The value holds newlines, so it comes back length-prefixed
+    let expected = [format!("${}\n", large.len()).as_bytes(), &large, b"\n"].concat();
+    let mut reply = Vec::new();
+    while reply.len() < expected.len() {
+        reply.extend(client.read_reply().unwrap());
+    }
+    assert_eq!(reply, expected);
+
+    // Values that no longer fit stay on the heap
+    store.set(b"overflow".to_vec(), vec![7; blobs.capacity()]).unwrap();
+    assert_eq!(store.get(b"overflow").unwrap().map(|value| value.len()), Some(blobs.capacity()));
+
+    store.save().unwrap();
+    store.delete(b"large").unwrap();
+    assert_eq!(blobs.free(), blobs.capacity());
+    let reopened = Store::open(dir.join("kv.snapshot")).unwrap();
+    assert_eq!(reopened.get(b"large").unwrap(), Some(large));
+}
+
+#[test]
+fn info_counts_keys_and_lookups() {
+    let store = Store::new();
+    store.set(b"greeting".to_vec(), b"hello".to_vec()).unwrap();
+    store.set_with_ttl(b"session".to_vec(), b"abc".to_vec(), Duration::from_secs(60)).unwrap();
+    store.push(b"jobs", ListEnd::Back, &[b"a".to_vec(), b"b".to_vec()]).unwrap();
+    store.get(b"greeting").unwrap();
+    store.get(b"greeting").unwrap();
+    store.get(b"missing").unwrap();
+
+    let info = store.info();
+    assert_eq!(
+        (info.keys, info.expiring, info.lists, info.hits, info.misses, info.blob_capacity),
+        (3, 1, 1, 2, 1, 0)
+    );
+    assert!(info.memory > "greetinghellosessionabcjobsab".len());
+    assert_eq!(KeyspaceInfo::default().hit_ratio(), None);
+    assert_eq!(KeyspaceInfo { hits: 3, misses: 1, ..KeyspaceInfo::default() }.hit_ratio(), Some(0.75));
+}
+
+#[test]
+fn info_reports_sections() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    client.request("SET a 1\nGET a\nGET b\n").unwrap();
+    let reply = client.request("INFO\n").unwrap();
+    for line in ["# Server", "keys:1", "keyspace_hits:1", "keyspace_misses:1", "hit_ratio:0.5000", "role:primary"] {
+        assert!(reply.lines().any(|reply| reply == line), "{:?} is missing from {:?}", line, reply);
+    }
+    #[cfg(target_os = "linux")]
+    assert!(reply.lines().any(|line| line.starts_with("used_memory_rss:")), "{:?}", reply);
+    assert_eq!(client.request("info replication\n").unwrap(), "*3\n# Replication\nrole:primary\nconnected_replicas:0\n");
+    assert_eq!(client.request("INFO cpu\n").unwrap(), "ERR unknown INFO section\n");
+}
+
+#[test]
+fn scan_visits_every_key_once() {
+    let store = Store::new();
+    for i in 0..100 {
+        store.set(format!("key:{}", i).into_bytes(), b"v".to_vec()).unwrap();
+    }
+    let (mut cursor, mut seen, mut calls) = (0, Vec::new(), 0);
+    loop {
+        let (next, keys) = store.scan(cursor, 7, None);
+        assert!(keys.len() <= 7);
+        seen.extend(keys);
+        calls += 1;
+        if next == 0 {
+            break;
+        }
+        // Changes between calls do not disturb the walk
+        store.set(format!("new:{}", calls).into_bytes(), b"v".to_vec()).unwrap();
+        cursor = next;
+    }
+    seen.retain(|key| key.starts_with(b"key:"));
+    seen.sort();
+    let mut expected: Vec<_> = (0..100).map(|i| format!("key:{}", i).into_bytes()).collect();
+    expected.sort();
+    assert_eq!(seen, expected);
+    assert!(calls >= 15);
+}
+
+#[test]
+fn scan_filters_keys_by_pattern() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+
+    client.request("SET user:1 a\nSET user:2 b\nSET order:1 c\n").unwrap();
+    let reply = client.request("SCAN 0 MATCH user:* COUNT 100\n").unwrap();
+    let mut lines: Vec<_> = reply.lines().collect();
+    assert_eq!(&lines[..2], ["*3", "0"]);
+    lines[2..].sort();
+    assert_eq!(&lines[2..], ["user:1", "user:2"]);
+
+    assert_eq!(client.request("SCAN x\n").unwrap(), "ERR invalid cursor\n");
+    assert_eq!(client.request("SCAN 0 COUNT 0\n").unwrap(), "ERR COUNT is not a positive integer\n");
+    assert_eq!(client.request("SCAN 0 MATCH\n").unwrap(), "ERR syntax error\n");
+}
+
+#[test]
+fn keyspace_events_are_published_when_enabled() {
+    let server = kv_server(Arc::new(Store::new().with_keyspace_events()));
+    let mut watcher = server.client().unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(watcher.request("SUBSCRIBE __keyspace__:jobs __keyevent__:del\n").unwrap().lines().count(), 8);
+    client.request("SET other 1\nRPUSH jobs a\nLPOP jobs\nDEL other\n").unwrap();
+    assert_eq!(
+        read_lines(&mut watcher, 20),
+        [
+            "*3", "message", "__keyspace__:jobs", "rpush",
+            "*3", "message", "__keyspace__:jobs", "lpop",
+            "*3", "message", "__keyspace__:jobs", "del",
+            "*3", "message", "__keyevent__:del", "jobs",
+            "*3", "message", "__keyevent__:del", "other",
+        ]
+    );
+
+    assert_eq!(
+        client.request("PUBLISH __keyevent__:del spoofed\n").unwrap(),
+        "ERR channels starting with __keyspace__ or __keyevent__ are reserved\n"
+    );
+}
+
+#[test]
+fn expired_keys_are_published() {
+    let store = Arc::new(Store::new().with_keyspace_events());
+    let server = kv_server(Arc::clone(&store));
+    let mut watcher = server.client().unwrap();
+    watcher.request("PSUBSCRIBE __keyevent__:*\n").unwrap();
+
+    store.set_with_ttl(b"session".to_vec(), b"abc".to_vec(), Duration::from_millis(10)).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    store.remove_expired();
+    assert_eq!(
+        read_lines(&mut watcher, 10),
+        [
+            "*4", "pmessage", "__keyevent__:*", "__keyevent__:set", "session",
+            "*4", "pmessage", "__keyevent__:*", "__keyevent__:expired", "session",
+        ]
+    );
+
+    // Without the flag nothing is published
+    let quiet = Store::new();
+    assert!(!quiet.keyspace_events());
+}
+
+#[test]
+fn values_are_binary_safe() {
+    let server = kv_server(Arc::new(Store::new()));
+    let mut client = server.client().unwrap();
+    let binary = b"line one\nline two\r\n\0\xff";
+
+    let set = [b"SET blob $21\n".as_slice(), binary, b"\nSET plain text\n"].concat();
+    assert_eq!(client.send(&set).unwrap(), b"OK\nOK\n");
+    assert_eq!(client.send(b"GET blob\nGET plain\n").unwrap(), [b"$21\n".as_slice(), binary, b"\ntext\n"].concat());
+
+    // Values that look like other replies are length-prefixed too
+    client.request("SET a \"(nil)\"\nSET b \"ERR not really\"\nRPUSH list \"*2\" \"$5\"\n").unwrap();
+    assert_eq!(client.request("GET a\nGET b\n").unwrap(), "$5\n(nil)\n$14\nERR not really\n");
+    assert_eq!(client.request("LRANGE list 0 -1\n").unwrap(), "*2\n$2\n*2\n$2\n$5\n");
+
+    assert_eq!(
+        client.request("SET key $2\nabc\nGET plain\n").unwrap(),
+        "ERR length-prefixed argument must be followed by a space or newline\ntext\n"
+    );
+
+    // A value split across reads waits for the rest of it
+    client.stream().write_all(b"SET key $10\nhalf ").unwrap();
+    assert_eq!(client.request("of it\nGET key\n").unwrap(), "OK\nhalf of it\n");
+    // One the client never finishes is answered as it closes its end
+    client.stream().write_all(b"SET key $10\nshort").unwrap();
+    client.stream().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(client.read_reply().unwrap(), b"ERR length-prefixed argument is cut short\n");
+}
@@ -0,0 +1,129 @@
+//! Logging through a memory-mapped ring buffer flushed in the background.
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::logging::{LogSink, RingSink, DEFAULT_RING_CAPACITY, MIN_RING_CAPACITY};
+use rustbucket::testing::{TestDir, TestServer};
+use rustbucket::RustbucketError;
+
+/// Waits for `path` to contain `text`, returning whether it did in time
+fn wait_for_log(path: &Path, text: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if fs::read_to_string(path).unwrap_or_default().contains(text) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn lines_reach_the_log_file_in_the_background() {
+    let dir = TestDir::new().unwrap();
+    let sink = RingSink::open(dir.join("http.log"), dir.join("log.ring"), MIN_RING_CAPACITY).unwrap();
+    sink.write("first line");
+    let batch: Vec<String> = (0..3).map(|i| format!("batch line {}", i)).collect();
+    sink.write_batch(&batch);
+    assert!(wait_for_log(&dir.join("http.log"), "batch line 2"));
+
+    // Enough lines to wrap around the ring several times, flushed often enough that none are dropped
+    for i in 0..500 {
+        sink.write(&format!("line {:03} of the second round", i));
+        if i % 50 == 49 {
+            sink.flush().unwrap();
+        }
+    }
+    drop(sink);
+    let log = fs::read_to_string(dir.join("http.log")).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 504, "{}", log);
+    assert!(lines[0].starts_with('[') && lines[0].ends_with("] first line"), "{}", lines[0]);
+    assert!(lines[1..4].iter().enumerate().all(|(i, line)| line.ends_with(&format!("] batch line {}", i))));
+    let second_round = lines[4..].iter().enumerate();
+    assert!(second_round.clone().all(|(i, line)| line.ends_with(&format!("] line {:03} of the second round", i))));
+}
+
+#[test]
+fn server_log_is_complete_once_shutdown_returns() {
+    let dir = TestDir::new().unwrap();
+    let sink = RingSink::open(dir.join("http.log"), dir.join("log.ring"), DEFAULT_RING_CAPACITY).unwrap();
+    let server = TestServer::start_with(|builder| builder.log_sink(sink)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+    client.close();
+
+    server.shutdown();
+    let log = fs::read_to_string(dir.join("http.log")).unwrap();
+    assert!(log.contains("Connection #1 opened"), "{}", log);
+    assert!(log.ends_with("Server shutdown complete\n"), "{}", log);
+}
+
+#[test]
+fn lines_left_in_the_ring_by_a_crash_are_recovered() {
+    let dir = TestDir::new().unwrap();
+    // A ring of 4096 bytes as a crashed sink would leave it: three lines not yet
+    // flushed, the last wrapping around to the start of the ring
+    let lines = b"[2026-01-01 00:00:00] one\n[2026-01-01 00:00:01] two\n[2026-01-01 00:00:02] three\n";
+    let (capacity, tail) = (4096u64, 4096u64 * 3 - 60);
+    let head = tail + lines.len() as u64;
+    let mut ring = vec![0; 64 + 4096];
+    ring[..8].copy_from_slice(b"RBLOGRG1");
+    ring[8..16].copy_from_slice(&capacity.to_le_bytes());
+    ring[16..24].copy_from_slice(&head.to_le_bytes());
+    ring[24..32].copy_from_slice(&tail.to_le_bytes());
+    ring[64 + 4096 - 60..].copy_from_slice(&lines[..60]);
+    ring[64..64 + lines.len() - 60].copy_from_slice(&lines[60..]);
+    fs::write(dir.join("log.ring"), ring).unwrap();
+    fs::write(dir.join("http.log"), "[2025-12-31 23:59:59] zero\n").unwrap();
+
+    let sink = RingSink::open(dir.join("http.log"), dir.join("log.ring"), MIN_RING_CAPACITY * 2).unwrap();
+    sink.write("after the restart");
+    drop(sink);
+    let log = fs::read_to_string(dir.join("http.log")).unwrap();
+    let expected = format!("[2025-12-31 23:59:59] zero\n{}", String::from_utf8_lossy(lines));
+    assert!(log.starts_with(&expected), "{}", log);
+    assert!(log.ends_with("] after the restart\n"), "{}", log);
+
+    // Nothing is recovered twice, and a file that is not a ring holds nothing to recover
+    fs::write(dir.join("other.ring"), "not a ring").unwrap();
+    drop(RingSink::open(dir.join("http.log"), dir.join("log.ring"), MIN_RING_CAPACITY).unwrap());
+    drop(RingSink::open(dir.join("http.log"), dir.join("other.ring"), MIN_RING_CAPACITY).unwrap());
+    assert_eq!(fs::read_to_string(dir.join("http.log")).unwrap(), log);
+}
+
+#[test]
+fn lines_that_do_not_fit_are_dropped_and_counted() {
+    let dir = TestDir::new().unwrap();
+    let sink = RingSink::open(dir.join("http.log"), dir.join("log.ring"), MIN_RING_CAPACITY).unwrap();
+    let batch: Vec<String> = (0..100).map(|i| format!("{:>100}", i)).collect();
+    sink.write_batch(&batch);
+    assert_eq!(sink.dropped(), 100);
+    sink.write("after the batch");
+    drop(sink);
+
+    let log = fs::read_to_string(dir.join("http.log")).unwrap();
+    assert!(log.contains("] after the batch\n"), "{}", log);
+    assert!(log.contains("] Log ring full: dropped 100 lines\n"), "{}", log);
+    assert!(!log.contains(batch[0].as_str()), "{}", log);
+
+    let error = RingSink::open(dir.join("http.log"), dir.join("log.ring"), MIN_RING_CAPACITY - 1).err().unwrap();
+    assert!(matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("4096")), "{}", error);
+}
+
+#[test]
+fn reopening_flushes_to_the_old_file_first() {
+    let dir = TestDir::new().unwrap();
+    let sink = RingSink::open(dir.join("http.log"), dir.join("log.ring"), MIN_RING_CAPACITY).unwrap();
+    sink.write("before rotation");
+    fs::rename(dir.join("http.log"), dir.join("http.log.1")).unwrap();
+    sink.reopen().unwrap();
+    sink.write("after rotation");
+    sink.flush().unwrap();
+
+    assert!(fs::read_to_string(dir.join("http.log.1")).unwrap().ends_with("] before rotation\n"));
+    assert!(fs::read_to_string(dir.join("http.log")).unwrap().ends_with("] after rotation\n"));
+}
@@ -0,0 +1,193 @@
+//! The memory budget: accounting, refusing writes, and shedding idle connections; and process memory.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustbucket::kv::{KvHandler, ListEnd, Store};
+use rustbucket::memory::{heap_stats, process_memory, MemoryBudget};
+use rustbucket::protocol::{MAX_READ_BUFFER_SIZE, MIN_READ_BUFFER_SIZE, READ_BUFFER_SIZE};
+use rustbucket::queue::MessageQueue;
+use rustbucket::testing::TestServer;
+use rustbucket::RustbucketError;
+
+#[test]
+fn kv_data_is_counted_and_released() {
+    let budget = Arc::new(MemoryBudget::unlimited());
+    let store = Store::new().with_memory_budget(Arc::clone(&budget));
+
+    store.set(b"greeting".to_vec(), b"hello".to_vec()).unwrap();
+    let after_set = budget.usage().kv;
+    assert!(after_set >= "greetinghello".len(), "{}", after_set);
+    store.set(b"greeting".to_vec(), b"hello, world".to_vec()).unwrap();
+    assert_eq!(budget.usage().kv, after_set + ", world".len());
+
+    store.push(b"list", ListEnd::Back, &[b"one".to_vec(), b"two".to_vec()]).unwrap();
+    store.pop(b"list", ListEnd::Front, 1).unwrap();
+    assert!(budget.usage().kv > after_set);
+    store.pop(b"list", ListEnd::Front, 1).unwrap();
+    store.delete(b"greeting").unwrap();
+    assert_eq!(budget.usage().kv, 0);
+
+    store.set_with_ttl(b"brief".to_vec(), b"value".to_vec(), Duration::from_millis(10)).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    store.remove_expired();
+    assert_eq!(budget.usage(), Default::default());
+}
+
+#[test]
+fn writes_over_the_budget_are_refused_until_memory_is_freed() {
+    let budget = Arc::new(MemoryBudget::new(1000));
+    let store = Store::new().with_memory_budget(Arc::clone(&budget));
+    store.set(b"big".to_vec(), vec![b'x'; 800]).unwrap();
+
+    let error = store.set(b"more".to_vec(), vec![b'y'; 200]).unwrap_err();
+    assert!(matches!(error, RustbucketError::OutOfMemory), "{}", error);
+    assert!(matches!(store.push(b"list", ListEnd::Back, &[vec![b'z'; 200]]), Err(RustbucketError::OutOfMemory)));
+    // Reads and deletes still work
+    assert_eq!(store.get(b"big").unwrap().map(|value| value.len()), Some(800));
+    assert!(store.delete(b"big").unwrap());
+    store.set(b"more".to_vec(), vec![b'y'; 200]).unwrap();
+    assert_eq!(budget.usage().rejected, 2);
+
+    let queue = MessageQueue::new().with_memory_budget(Arc::clone(&budget));
+    let id = queue.enqueue(b"jobs", &[b'j'; 500]).unwrap();
+    assert!(matches!(queue.enqueue(b"jobs", &[b'j'; 500]), Err(RustbucketError::OutOfMemory)));
+    let queued = budget.usage().queues;
+    assert!(queued >= 500, "{}", queued);
+    assert!(queue.ack(b"jobs", id).unwrap());
+    assert_eq!(budget.usage().queues, 0);
+}
+
+#[test]
+fn kv_clients_are_told_when_memory_runs_out() {
+    let budget = Arc::new(MemoryBudget::new(READ_BUFFER_SIZE + 2048));
+    let store = Arc::new(Store::new().with_memory_budget(Arc::clone(&budget)));
+    let server =
+        TestServer::start_with(|builder| builder.memory_budget(budget).handler(KvHandler::new(store))).unwrap();
+    let mut client = server.client().unwrap();
+
+    let value = "v".repeat(400);
+    let mut replies = Vec::new();
+    for key in 0..10 {
+        replies.push(client.request(&format!("SET key{} {}\n", key, value)).unwrap());
+    }
+    let stored = replies.iter().take_while(|reply| *reply == "OK\n").count();
+    assert!((1..10).contains(&stored), "{:?}", replies);
+    assert_eq!(replies[stored], "ERR out of memory: the server is over its memory budget\n");
+
+    assert_eq!(client.request("DEL key0\n").unwrap(), "1\n");
+    assert_eq!(client.request(&format!("SET again {}\n", value)).unwrap(), "OK\n");
+    let memory = server.handle().memory();
+    assert!(memory.buffers >= READ_BUFFER_SIZE, "{:?}", memory);
+    assert!(memory.total() <= READ_BUFFER_SIZE + 2048, "{:?}", memory);
+}
+
+#[test]
+fn idle_connections_are_shed_while_over_the_budget() {
+    // Room for one connection's buffers, not two
+    let budget = Arc::new(MemoryBudget::new(READ_BUFFER_SIZE + READ_BUFFER_SIZE / 2));
+    let server = TestServer::start_with(|builder| builder.memory_budget(budget)).unwrap();
+    let mut idle = server.client().unwrap();
+    let mut active = server.client().unwrap();
+
+    for _ in 0..8 {
+        assert_eq!(active.request("ping\n").unwrap(), "Echo: ping\n");
+        thread::sleep(Duration::from_millis(250));
+    }
+    assert!(idle.is_closed_by_server());
+    assert_eq!(active.request("still here\n").unwrap(), "Echo: still here\n");
+    assert_eq!(server.handle().memory().shed, 1);
+}
+
+/// Opens `count` connections one after another, each exchanging one message
+fn serve_one_after_another(server: &TestServer, count: usize) {
+    for _ in 0..count {
+        let mut client = server.client().unwrap();
+        assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+        client.close();
+        // Let the worker finish with the connection and give its buffers back
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn connections_reuse_pooled_buffers() {
+    let server = TestServer::start_with(|builder| builder.buffer_pool(4)).unwrap();
+    serve_one_after_another(&server, 10);
+    let buffers = server.handle().buffers();
+    assert_eq!(buffers.buffer_size, READ_BUFFER_SIZE);
+    assert_eq!(buffers.capacity, 4);
+    // A read buffer and a reply buffer for the first connection, reused by the rest
+    assert_eq!(buffers.allocated, 2, "{:?}", buffers);
+    assert_eq!(buffers.reused, 18, "{:?}", buffers);
+    assert_eq!(buffers.idle, 2);
+
+    // Without a pool, every connection allocates its own
+    let unpooled = TestServer::start_with(|builder| builder.buffer_pool(0)).unwrap();
+    serve_one_after_another(&unpooled, 10);
+    assert_eq!(unpooled.handle().buffers().allocated, 20);
+    assert_eq!(unpooled.handle().buffers().idle, 0);
+}
+
+#[test]
+fn reply_buffers_that_grew_large_are_not_kept() {
+    let store = Arc::new(Store::new());
+    store.set(b"large".to_vec(), vec![b'x'; 8 * READ_BUFFER_SIZE]).unwrap();
+    let server = TestServer::start_with(|builder| builder.buffer_pool(4).handler(KvHandler::new(store))).unwrap();
+
+    let mut client = server.client().unwrap();
+    client.send(b"GET large\n").unwrap();
+    client.close();
+    thread::sleep(Duration::from_millis(100));
+    // Only the read buffer went back to the pool
+    assert_eq!(server.handle().buffers().idle, 1);
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("GET missing\n").unwrap(), "(nil)\n");
+    assert_eq!(server.handle().buffers().allocated, 3);
+}
+
+#[test]
+fn read_buffer_size_is_configurable_within_bounds() {
+    let server = TestServer::start_with(|builder| builder.read_buffer_size(8192)).unwrap();
+    assert_eq!(server.handle().buffers().buffer_size, 8192);
+
+    // A message larger than the default buffer is read, and echoed, whole
+    let mut client = server.client().unwrap();
+    let message = format!("{}\n", "x".repeat(5000));
+    client.stream().write_all(message.as_bytes()).unwrap();
+    let mut reply = Vec::new();
+    while reply.len() < "Echo: ".len() + message.len() {
+        reply.extend(client.read_reply().unwrap());
+    }
+    assert_eq!(reply, format!("Echo: {}", message).as_bytes());
+
+    for size in [MIN_READ_BUFFER_SIZE - 1, MAX_READ_BUFFER_SIZE + 1] {
+        let error = TestServer::start_with(|builder| builder.read_buffer_size(size)).err().unwrap();
+        let refused = matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("read buffer"));
+        assert!(refused, "{}", error);
+    }
+}
+
+#[test]
+fn process_memory_is_read_from_the_operating_system() {
+    let memory = process_memory();
+    let peak = memory.peak_resident.unwrap();
+    #[cfg(target_os = "linux")]
+    {
+        let resident = memory.resident.unwrap();
+        assert!(resident > 0 && resident <= peak, "{:?}", memory);
+        assert!(memory.virtual_size.unwrap() >= resident, "{:?}", memory);
+
+        // Touching a few megabytes shows up in the resident size
+        let touched = vec![1u8; 16 * 1024 * 1024];
+        let grown = process_memory().resident.unwrap();
+        assert!(grown >= resident + 8 * 1024 * 1024, "{} then {}", resident, grown);
+        drop(touched);
+    }
+    assert!(peak > 0);
+
+    // Heap figures need the counting allocator, which this test does not install
+    assert_eq!(heap_stats(), None);
+}
@@ -0,0 +1,357 @@
+//! Built-in middleware layers and the server's protections against abusive clients.
+
+use std::fs;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::access::AccessList;
+use rustbucket::acl::CommandAcl;
+use rustbucket::bans::{BanList, BanPolicy, Offence};
+use rustbucket::flood::FloodPolicy;
+use rustbucket::kv::{KvHandler, Store};
+use rustbucket::middleware::{IpRateLimit, LockoutPolicy, TokenAuth};
+use rustbucket::secrets::Secret;
+use rustbucket::testing::{TestDir, TestServer};
+use rustbucket::RustbucketError;
+
+#[test]
+fn rate_limit_is_shared_by_connections_from_one_address() {
+    let server = TestServer::start_with(|builder| builder.middleware(IpRateLimit::new(0.001, 2))).unwrap();
+    let mut first = server.client().unwrap();
+    let mut second = server.client().unwrap();
+
+    assert_eq!(first.request("one\n").unwrap(), "Echo: one\n");
+    assert_eq!(second.request("two\n").unwrap(), "Echo: two\n");
+    assert_eq!(first.request("three\n").unwrap(), "ERR rate limit exceeded\n");
+    assert_eq!(second.request("four\n").unwrap(), "ERR rate limit exceeded\n");
+    assert_eq!(server.handle().metrics().requests_throttled, 2);
+}
+
+#[test]
+fn throttled_connections_are_closed_after_repeated_refusals() {
+    let server =
+        TestServer::start_with(|builder| builder.middleware(IpRateLimit::new(0.001, 1).close_after(2))).unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("one\n").unwrap(), "Echo: one\n");
+    assert_eq!(client.request("two\n").unwrap(), "ERR rate limit exceeded\n");
+    assert_eq!(client.request("three\n").unwrap(), "ERR rate limit exceeded; closing connection\n");
+    assert!(client.is_closed_by_server());
+}
+
+#[test]
+fn access_list_refuses_denied_clients_and_reloads() {
+    let server = TestServer::start().unwrap();
+    fs::write(server.paths().access_file, "deny 127.0.0.0/8 # loopback\n").unwrap();
+    server.handle().reload().unwrap();
+
+    let mut refused = server.client().unwrap();
+    assert!(refused.is_closed_by_server());
+    assert_eq!(server.handle().metrics().connections_denied, 1);
+
+    fs::write(server.paths().access_file, "deny 127.0.0.0/8\nallow 127.0.0.1\n").unwrap();
+    server.handle().reload().unwrap();
+    assert!(server.client().unwrap().is_closed_by_server());
+    fs::write(server.paths().access_file, "allow 10.0.0.0/8\nallow 127.0.0.1\ndeny 127.0.0.0/8\n").unwrap();
+    server.handle().reload().unwrap();
+    let mut allowed = server.client().unwrap();
+    assert_eq!(allowed.request("hello\n").unwrap(), "Echo: hello\n");
+
+    let access = server.handle().access_list();
+    let hits: Vec<(String, u64)> = access.rules().iter().map(|rule| (rule.to_string(), rule.hits())).collect();
+    // Hits carry over reloads for rules that stayed the same
+    let expected = [("allow 10.0.0.0/8", 0), ("allow 127.0.0.1", 1), ("deny 127.0.0.0/8", 2)];
+    assert_eq!(hits, expected.map(|(rule, hits)| (rule.to_string(), hits)));
+}
+
+#[test]
+fn access_list_rejects_malformed_rules() {
+    let server = TestServer::start().unwrap();
+    fs::write(server.paths().access_file, "allow 10.0.0.0/33\n").unwrap();
+    let error = server.handle().reload().unwrap_err();
+    assert!(matches!(error, RustbucketError::InvalidConfig(_)), "{}", error);
+    assert!(error.to_string().contains("access rule 1"), "{}", error);
+    assert_eq!(server.client().unwrap().request("still open\n").unwrap(), "Echo: still open\n");
+
+    assert!(AccessList::parse("permit 10.0.0.1").is_err());
+    assert!(AccessList::parse("allow ::1/129").is_err());
+    let list = AccessList::parse("\n# nothing but comments\nallow 2001:db8::/32\n").unwrap();
+    assert!(list.check("2001:db8::1".parse().unwrap()));
+    assert!(!list.check("10.0.0.1".parse().unwrap()));
+}
+
+#[test]
+fn connections_over_the_per_ip_cap_are_refused() {
+    let server = TestServer::start_with(|builder| builder.max_connections_per_ip(2)).unwrap();
+    let mut first = server.client().unwrap();
+    let mut second = server.client().unwrap();
+    assert_eq!(first.request("one\n").unwrap(), "Echo: one\n");
+    assert_eq!(second.request("two\n").unwrap(), "Echo: two\n");
+
+    let mut third = server.client().unwrap();
+    assert_eq!(third.read_reply().unwrap(), b"ERR too many connections from your address (limit 2)\n");
+    assert!(third.is_closed_by_server());
+    assert_eq!(server.handle().metrics().connections_over_ip_cap, 1);
+
+    // Closing a connection frees its place
+    first.close();
+    thread::sleep(Duration::from_millis(200));
+    let mut fourth = server.client().unwrap();
+    assert_eq!(fourth.request("four\n").unwrap(), "Echo: four\n");
+}
+
+#[test]
+fn repeated_offences_get_an_address_banned() {
+    let policy = BanPolicy { offences: 3, window: Duration::from_secs(60), duration: Duration::from_secs(60) };
+    let server =
+        TestServer::start_with(|builder| builder.auto_ban(policy).middleware(TokenAuth::new("s3cret"))).unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("AUTH guess1\n").unwrap(), "ERR invalid token\n");
+    assert_eq!(client.request("AUTH guess2\n").unwrap(), "ERR invalid token\n");
+    // The third strike bans the address and closes the connection after its reply
+    assert_eq!(client.request("AUTH guess3\n").unwrap(), "ERR invalid token\n");
+    assert!(client.is_closed_by_server());
+    assert!(server.client().unwrap().is_closed_by_server());
+    assert_eq!(server.handle().metrics().connections_banned, 1);
+}
+
+#[test]
+fn failed_auth_attempts_lock_an_address_out_for_longer_each_time() {
+    let policy = LockoutPolicy { failures: 2, initial: Duration::from_millis(200), max: Duration::from_secs(1) };
+    let server =
+        TestServer::start_with(|builder| builder.middleware(TokenAuth::new("s3cret").lockout(policy))).unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("AUTH guess1\n").unwrap(), "ERR invalid token\n");
+    assert_eq!(client.request("AUTH guess2\n").unwrap(), "ERR invalid token\n");
+    // Even the right token is refused during the lockout, from any connection
+    let locked = "ERR too many failed attempts; try again in 1s\n";
+    assert_eq!(client.request("AUTH s3cret\n").unwrap(), locked);
+    assert_eq!(server.client().unwrap().request("AUTH s3cret\n").unwrap(), locked);
+    thread::sleep(Duration::from_millis(250));
+    // The next failure doubles the lockout
+    assert_eq!(client.request("AUTH guess3\n").unwrap(), "ERR invalid token\n");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.request("AUTH s3cret\n").unwrap(), locked);
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.request("AUTH s3cret\n").unwrap(), "OK\n");
+
+    let metrics = server.handle().metrics();
+    assert_eq!((metrics.auth_failures, metrics.auth_lockouts), (3, 2));
+    let log = server.log();
+    assert!(log.contains("Audit: connection #1 from 127.0.0.1:"), "{}", log);
+    assert!(log.contains("failed to authenticate (2 in a row from its address)"), "{}", log);
+    assert!(log.contains("Audit: locked 127.0.0.1 out of AUTH for 200ms after 2 failures"), "{}", log);
+    assert!(log.contains("Audit: locked 127.0.0.1 out of AUTH for 400ms after 3 failures"), "{}", log);
+    assert!(log.contains("authenticated as default"), "{}", log);
+
+    // Authenticating forgives the address
+    let mut other = server.client().unwrap();
+    assert_eq!(other.request("AUTH guess4\n").unwrap(), "ERR invalid token\n");
+    assert_eq!(other.request("AUTH s3cret\n").unwrap(), "OK\n");
+}
+
+#[test]
+fn commands_pipelined_behind_auth_run_once_it_succeeds() {
+    let store = Arc::new(Store::new());
+    let server = TestServer::start_with(|builder| {
+        builder.middleware(TokenAuth::new("s3cret")).handler(KvHandler::new(Arc::clone(&store)))
+    })
+    .unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("AUTH guess\nSET a 1\n").unwrap(), "ERR invalid token\n");
+    assert_eq!(client.request("AUTH s3cret\nSET a 1\nGET a\n").unwrap(), "OK\nOK\n1\n");
+    assert_eq!(server.handle().metrics().auth_failures, 1);
+}
+
+#[test]
+fn secrets_compare_whole_values() {
+    let secret = Secret::new("s3cret");
+    assert!(secret.matches("s3cret"));
+    assert!(!secret.matches("s3creT"));
+    assert!(!secret.matches("s3cre"));
+    assert!(!secret.matches("s3crets"));
+    assert!(!secret.matches(""));
+    assert_eq!(secret, Secret::new("s3cret"));
+    assert_ne!(secret, Secret::new("s3creT"));
+}
+
+#[test]
+fn bans_can_be_lifted() {
+    let bans = BanList::new(BanPolicy { offences: 2, ..BanPolicy::default() });
+    let ip: IpAddr = "192.0.2.7".parse().unwrap();
+    assert!(!bans.record(ip, Offence::ProtocolError));
+    assert!(!bans.is_banned(ip));
+    assert!(bans.record(ip, Offence::RateLimited));
+    assert!(bans.is_banned(ip));
+    assert!(!bans.is_banned("192.0.2.8".parse().unwrap()));
+
+    let listed = bans.list();
+    assert_eq!(listed.len(), 1);
+    assert_eq!((listed[0].ip, listed[0].reason), (ip, Offence::RateLimited));
+    assert!(bans.lift(ip));
+    assert!(!bans.is_banned(ip));
+    assert!(!bans.lift(ip));
+
+    let disabled = BanList::disabled();
+    for _ in 0..100 {
+        assert!(!disabled.record(ip, Offence::AuthFailure));
+    }
+}
+
+#[test]
+fn auth_tokens_can_come_from_files_and_the_environment() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("token");
+    fs::write(&path, "from-a-file\n").unwrap();
+    #[cfg(unix)]
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    let token = Secret::from_file(&path).unwrap();
+    assert_eq!(token.expose(), "from-a-file");
+    assert_eq!(format!("{:?}", token), "Secret(<redacted>)");
+
+    let server = TestServer::start_with(|builder| builder.middleware(TokenAuth::new(token))).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("AUTH from-a-file\n").unwrap(), "OK\n");
+
+    std::env::set_var("RUSTBUCKET_TEST_TOKEN", " from-the-environment ");
+    assert_eq!(Secret::from_env("RUSTBUCKET_TEST_TOKEN").unwrap().expose(), "from-the-environment");
+
+    fs::write(&path, "\n").unwrap();
+    let empty = Secret::from_file(&path).unwrap_err();
+    assert!(matches!(empty, RustbucketError::InvalidConfig(_)), "{}", empty);
+    assert!(Secret::from_file(dir.join("missing")).is_err());
+    assert!(Secret::from_env("RUSTBUCKET_TEST_TOKEN_UNSET").is_err());
+}
+
+#[test]
+fn command_acl_limits_commands_by_identity_and_network() {
+    let auth = TokenAuth::new("admin-token").user("reader", "reader-token");
+    let store = Arc::new(Store::new());
+    let server = TestServer::start_with(|builder| builder.middleware(auth).handler(KvHandler::new(store))).unwrap();
+    let rules = "allow GET,exists user reader\ndeny * user reader\nallow DEL user default\ndeny del from 127.0.0.0/8\n";
+    fs::write(server.paths().acl_file, rules).unwrap();
+    server.handle().reload().unwrap();
+
+    let mut admin = server.client().unwrap();
+    assert_eq!(admin.request("AUTH admin-token\n").unwrap(), "OK\n");
+    assert_eq!(admin.request("SET greeting hello\n").unwrap(), "OK\n");
+    assert_eq!(admin.request("DEL greeting\n").unwrap(), "1\n");
+
+    let mut reader = server.client().unwrap();
+    assert_eq!(reader.request("AUTH reader-token\n").unwrap(), "OK\n");
+    assert_eq!(reader.request("get greeting\n").unwrap(), "(nil)\n");
+    assert_eq!(reader.request("set greeting hello\n").unwrap(), "ERR SET is not permitted\n");
+    assert_eq!(reader.request("DEL greeting\n").unwrap(), "ERR DEL is not permitted\n");
+    assert_eq!(server.handle().metrics().commands_denied, 2);
+
+    let acl = server.handle().command_acl();
+    let hits: Vec<(String, u64)> = acl.rules().iter().map(|rule| (rule.to_string(), rule.hits())).collect();
+    let expected = [
+        ("allow GET,EXISTS user reader", 1),
+        ("deny * user reader", 2),
+        ("allow DEL user default", 1),
+        ("deny DEL from 127.0.0.0/8", 0),
+    ];
+    assert_eq!(hits, expected.map(|(rule, hits)| (rule.to_string(), hits)));
+
+    assert!(CommandAcl::parse("permit GET").is_err());
+    assert!(CommandAcl::parse("allow GET from 10.0.0.0/33").is_err());
+    assert!(CommandAcl::parse("allow GET user").is_err());
+    assert!(CommandAcl::parse("deny * from 10.0.0.0/8 user ops").is_ok());
+}
+
+#[test]
+fn command_acl_checks_every_command_in_a_message() {
+    let store = Arc::new(Store::new());
+    let server = TestServer::start_with(|builder| builder.handler(KvHandler::new(Arc::clone(&store)))).unwrap();
+    fs::write(server.paths().acl_file, "deny DEL\n").unwrap();
+    server.handle().reload().unwrap();
+
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("SET y 1\n").unwrap(), "OK\n");
+    // A denied command pipelined behind an allowed one refuses the whole message
+    assert_eq!(client.request("GET y\nDEL y\n").unwrap(), "ERR DEL is not permitted\n");
+    assert_eq!(store.get(b"y").unwrap().as_deref(), Some(&b"1"[..]));
+    // Values are not mistaken for commands
+    assert_eq!(client.request("SET note $5\nDEL y\n").unwrap(), "OK\n");
+    assert_eq!(server.handle().metrics().commands_denied, 1);
+}
+
+/// A policy quick enough to test: floods are over 20 connections/s, sampled every 100ms
+fn flood_policy() -> FloodPolicy {
+    FloodPolicy {
+        min_rate: 20.0,
+        interval: Duration::from_millis(100),
+        calm: Duration::from_secs(1),
+        timeout: Duration::from_secs(1),
+        max_connections_per_ip: 2,
+        ..FloodPolicy::default()
+    }
+}
+
+/// Opens and drops connections faster than [`flood_policy`] allows, then waits for the server to notice
+fn flood(server: &TestServer) {
+    for _ in 0..50 {
+        drop(std::net::TcpStream::connect(server.addr()).unwrap());
+    }
+    let started = Instant::now();
+    while !server.handle().metrics().defensive_mode {
+        assert!(started.elapsed() < Duration::from_secs(5), "the flood went unnoticed");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn connection_floods_raise_defences_until_they_subside() {
+    let server = TestServer::start_with(|builder| builder.threads(4).flood_protection(flood_policy())).unwrap();
+    let mut first = server.client().unwrap();
+    let mut second = server.client().unwrap();
+    assert_eq!(first.request("one\n").unwrap(), "Echo: one\n");
+    assert_eq!(second.request("two\n").unwrap(), "Echo: two\n");
+
+    flood(&server);
+    assert_eq!(server.handle().metrics().floods, 1);
+    assert!(server.log().contains("Flood: "), "{}", server.log());
+
+    // The defensive cap counts the connections opened before the flood
+    let mut third = server.client().unwrap();
+    assert_eq!(third.read_reply().unwrap(), b"ERR too many connections from your address (limit 2)\n");
+    // Idle clients are dropped after the defensive timeout rather than the configured one
+    thread::sleep(Duration::from_millis(2500));
+    assert!(first.is_closed_by_server());
+    assert!(second.is_closed_by_server());
+
+    // Without new connections the flood is over once the calm period has passed
+    let started = Instant::now();
+    while server.handle().metrics().defensive_mode {
+        assert!(started.elapsed() < Duration::from_secs(5), "defensive mode was never lifted");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(server.log().contains("Flood: subsided"), "{}", server.log());
+    let clients: Vec<_> = (0..3).map(|_| server.client().unwrap()).collect();
+    for mut client in clients {
+        assert_eq!(client.request("back\n").unwrap(), "Echo: back\n");
+    }
+}
+
+#[test]
+fn defending_servers_refuse_connections_rather_than_queue_them() {
+    let policy = FloodPolicy { max_connections_per_ip: 100, calm: Duration::from_secs(60), ..flood_policy() };
+    let server = TestServer::start_with(|builder| builder.threads(1).flood_protection(policy)).unwrap();
+    // Occupies the only worker, so the flood queues up behind it
+    let mut busy = server.client().unwrap();
+    assert_eq!(busy.request("one\n").unwrap(), "Echo: one\n");
+
+    flood(&server);
+    let mut refused = server.client().unwrap();
+    assert_eq!(refused.read_reply().unwrap(), b"ERR server busy; try again later\n");
+    assert!(refused.is_closed_by_server());
+    assert!(server.handle().metrics().connections_over_queue >= 1);
+    assert!(server.log().contains("no worker free while defending against a flood"));
+}
@@ -0,0 +1,73 @@
+//! Text and binary connection modes, chosen by the server or switched with `MODE`.
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use rustbucket::protocol::ConnectionMode;
+use rustbucket::testing::TestServer;
+use rustbucket::{ConnectionCtx, RequestHandler, ResponseWriter};
+
+#[test]
+fn text_mode_refuses_messages_that_are_not_utf8() {
+    let server = TestServer::start_with(|builder| builder.connection_mode(ConnectionMode::Text)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.send(b"caf\xc3\xa9\n").unwrap(), "Echo: café\n".as_bytes());
+    assert_eq!(
+        client.send(b"ab\xffcd\n").unwrap(),
+        b"ERR invalid UTF-8 at byte 2 (0xff); send MODE BINARY to pass raw bytes\n"
+    );
+    assert_eq!(client.request("MODE\n").unwrap(), "TEXT\n");
+}
+
+#[test]
+fn binary_mode_passes_bytes_through_untouched() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("MODE\n").unwrap(), "BINARY\n");
+    assert_eq!(client.send(b"\xff\x00\xc3\n").unwrap(), b"Echo: \xff\x00\xc3\n");
+}
+
+#[test]
+fn clients_switch_their_own_connection() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("MODE TEXT\n").unwrap(), "OK\n");
+    assert!(client.send(b"\xff\n").unwrap().starts_with(b"ERR invalid UTF-8 at byte 0"));
+    // The rest of the message is handled in the mode asked for
+    assert_eq!(client.send(b"mode binary\n\xff\n").unwrap(), b"OK\nEcho: \xff\n");
+    assert_eq!(client.request("MODE HEX\n").unwrap(), "ERR unknown mode; use MODE TEXT or MODE BINARY\n");
+
+    // Other connections keep the server's mode
+    let mut other = server.client().unwrap();
+    assert_eq!(other.request("MODE\n").unwrap(), "BINARY\n");
+}
+
+#[test]
+fn text_mode_waits_for_characters_split_across_reads() {
+    let server = TestServer::start_with(|builder| builder.connection_mode(ConnectionMode::Text)).unwrap();
+    let mut client = server.client().unwrap();
+    client.stream().write_all(b"caf\xc3").unwrap();
+    assert_eq!(client.read_reply().unwrap(), b"Echo: caf");
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.send(b"\xa9\n").unwrap(), "Echo: é\n".as_bytes());
+}
+
+struct ReportsMode;
+
+impl RequestHandler for ReportsMode {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, _message: &[u8], response: &mut ResponseWriter) {
+        if ctx.mode() == ConnectionMode::Binary {
+            ctx.set_mode(ConnectionMode::Text);
+        }
+        response.write(format!("{:?}\n", ctx.mode()).as_bytes());
+    }
+}
+
+#[test]
+fn handlers_see_and_switch_the_mode() {
+    let server = TestServer::start_with(|builder| builder.handler(ReportsMode)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("anything\n").unwrap(), "Text\n");
+    assert_eq!(client.request("MODE\n").unwrap(), "TEXT\n");
+}
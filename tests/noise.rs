@@ -0,0 +1,134 @@
+//! Noise_XX encryption for client connections.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use rustbucket::chat::ChatHandler;
+use rustbucket::noise::{self, NoiseKey, NoiseSettings, NoiseStream};
+use rustbucket::testing::{TestDir, TestServer};
+use rustbucket::{ConnectionCtx, RequestHandler, ResponseWriter, RustbucketError};
+
+/// Answers every message with the identity the connection was given
+struct WhoAmI;
+
+impl RequestHandler for WhoAmI {
+    fn on_message(&self, ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        let identity = ctx.identity().unwrap_or("nobody");
+        response.write(format!("{} sent {}", identity, String::from_utf8_lossy(message)).as_bytes());
+    }
+}
+
+fn connect(server: &TestServer, key: &NoiseKey, server_key: &noise::PublicKey) -> std::io::Result<NoiseStream> {
+    let socket = TcpStream::connect(server.addr())?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    NoiseStream::connect(socket, key, server_key)
+}
+
+fn read_line(stream: &mut NoiseStream) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while line.last() != Some(&b'\n') {
+        stream.read_exact(&mut byte).unwrap();
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).unwrap()
+}
+
+#[test]
+fn authorized_clients_talk_to_the_server_they_expect() {
+    let server_key = NoiseKey::generate();
+    let alice = NoiseKey::generate();
+    let settings = NoiseSettings::new(server_key.clone()).authorize("alice", alice.public_key());
+    let server = TestServer::start_with(|builder| builder.noise(settings).handler(WhoAmI)).unwrap();
+    assert!(server.log().contains(&format!("Noise: public key {}", noise::to_hex(&server_key.public_key()))));
+
+    let mut stream = connect(&server, &alice, &server_key.public_key()).unwrap();
+    assert_eq!(stream.remote_key(), server_key.public_key());
+    stream.write_all(b"hello\n").unwrap();
+    assert_eq!(read_line(&mut stream), "alice sent hello\n");
+    // Messages larger than one Noise frame arrive whole
+    let large = format!("{}\n", "x".repeat(100_000));
+    stream.write_all(large.as_bytes()).unwrap();
+    let mut reply = Vec::new();
+    while reply.len() < "alice sent ".len() + large.len() {
+        let mut chunk = [0; 8192];
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0);
+        reply.extend_from_slice(&chunk[..n]);
+    }
+    assert!(reply.starts_with(b"alice sent xxx"));
+
+    // A client expecting another server's key gives up
+    let impostor = NoiseKey::generate();
+    let error = connect(&server, &alice, &impostor.public_key()).err().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied, "{}", error);
+}
+
+#[test]
+fn unauthorized_and_plain_clients_are_disconnected() {
+    let server_key = NoiseKey::generate();
+    let settings = NoiseSettings::new(server_key.clone()).authorize("alice", NoiseKey::generate().public_key());
+    let server = TestServer::start_with(|builder| builder.noise(settings)).unwrap();
+
+    let mallory = NoiseKey::generate();
+    // The handshake completes on the client's side before the server checks its key
+    if let Ok(mut stream) = connect(&server, &mallory, &server_key.public_key()) {
+        let _ = stream.write_all(b"hello\n");
+        let mut reply = [0; 64];
+        assert!(matches!(stream.read(&mut reply), Ok(0) | Err(_)));
+    }
+    let mut plain = server.client().unwrap();
+    assert!(plain.send(b"hello\n").is_err() || plain.is_closed_by_server());
+    let refusal = format!("client Noise key {} is not authorized", noise::to_hex(&mallory.public_key()));
+    assert!(server.log().contains(&refusal), "{}", server.log());
+}
+
+#[test]
+fn outbox_messages_are_encrypted_like_replies() {
+    let server_key = NoiseKey::generate();
+    let (alice, bob) = (NoiseKey::generate(), NoiseKey::generate());
+    let settings = NoiseSettings::new(server_key.clone())
+        .authorize("alice", alice.public_key())
+        .authorize("bob", bob.public_key());
+    let server = TestServer::start_with(|builder| builder.noise(settings).handler(ChatHandler::new())).unwrap();
+
+    let mut alice = connect(&server, &alice, &server_key.public_key()).unwrap();
+    let mut bob = connect(&server, &bob, &server_key.public_key()).unwrap();
+    alice.write_all(b"/nick alice\n").unwrap();
+    read_line(&mut alice);
+    bob.write_all(b"/nick bob\n").unwrap();
+    read_line(&mut bob);
+
+    alice.write_all(b"hi bob\n").unwrap();
+    let relayed = (0..5).map(|_| read_line(&mut bob)).find(|line| line.starts_with("alice: "));
+    assert_eq!(relayed.as_deref(), Some("alice: hi bob\n"));
+}
+
+#[test]
+fn keys_and_authorized_keys_are_read_from_files() {
+    let dir = TestDir::new().unwrap();
+    let key = NoiseKey::generate();
+    fs::write(dir.join("server.noise"), format!("{}\n", key.private_hex().as_str())).unwrap();
+    let loaded = NoiseKey::from_file(dir.join("server.noise")).unwrap();
+    assert_eq!(loaded.public_key(), key.public_key());
+    assert!(!format!("{:?}", loaded).contains(key.private_hex().as_str()));
+
+    let client = noise::to_hex(&NoiseKey::generate().public_key());
+    fs::write(dir.join("clients"), format!("# Who may connect\n{} alice\n\n", client)).unwrap();
+    let settings = NoiseSettings::new(loaded).load_authorized_keys(dir.join("clients")).unwrap();
+    assert_eq!(settings.authorized().len(), 1);
+    assert_eq!(settings.authorized()[0].0, "alice");
+    assert_eq!(noise::to_hex(&settings.authorized()[0].1), client);
+
+    fs::write(dir.join("bad"), "abc alice\n").unwrap();
+    let error = NoiseSettings::new(key.clone()).load_authorized_keys(dir.join("bad")).unwrap_err();
+    assert!(matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("line 1")), "{}", error);
+    fs::write(dir.join("short.noise"), "abcd\n").unwrap();
+    assert!(NoiseKey::from_file(dir.join("short.noise")).is_err());
+
+    // A server no client may connect to is a mistake
+    let error = TestServer::start_with(|builder| builder.noise(NoiseSettings::new(key))).err().unwrap();
+    assert!(matches!(error, RustbucketError::InvalidConfig(_)), "{}", error);
+}
@@ -0,0 +1,140 @@
+//! Discovering and loading handler plugins.
+
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use libloading::Library;
+use rustbucket::plugins::{self, Plugin};
+use rustbucket::testing::{TestDir, TestServer};
+use rustbucket::RustbucketError;
+
+/// Builds the plugin in `tests/fixtures/upper_plugin` once, returning the library
+fn upper_plugin() -> &'static Path {
+    static LIBRARY: OnceLock<PathBuf> = OnceLock::new();
+    LIBRARY.get_or_init(|| {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/upper_plugin/Cargo.toml");
+        let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("upper_plugin");
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--quiet", "--manifest-path"])
+            .arg(&manifest)
+            .arg("--target-dir")
+            .arg(&target)
+            .status()
+            .unwrap();
+        assert!(status.success(), "building the fixture plugin failed");
+        target.join("debug").join(format!("{}upper_plugin{}", DLL_PREFIX, DLL_SUFFIX))
+    })
+}
+
+/// Copies the fixture plugin into `dir` as `file_name`
+///
+/// Each copy is loaded as a library of its own, so its counters are only
+/// touched by the test that made it.
+fn install(dir: &Path, file_name: &str) -> PathBuf {
+    fs::create_dir_all(dir).unwrap();
+    let path = dir.join(file_name);
+    fs::copy(upper_plugin(), &path).unwrap();
+    path
+}
+
+/// Reads one of the fixture's counters from the copy at `path`
+fn counter(library: &Library, name: &[u8]) -> u32 {
+    unsafe { library.get::<unsafe extern "C" fn() -> u32>(name).unwrap()() }
+}
+
+#[test]
+fn a_missing_directory_holds_no_plugins() {
+    let dir = TestDir::new().unwrap();
+
+    assert!(plugins::discover(dir.join("plugins")).unwrap().is_empty());
+}
+
+#[test]
+fn files_that_are_not_libraries_are_skipped() {
+    let dir = TestDir::new().unwrap();
+    fs::write(dir.join("README.txt"), "not a plugin").unwrap();
+
+    assert!(plugins::discover(dir.path()).unwrap().is_empty());
+}
+
+#[test]
+fn a_library_that_cannot_be_loaded_is_an_error() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join(format!("broken.{}", std::env::consts::DLL_EXTENSION));
+    fs::write(&path, "not a shared library").unwrap();
+
+    let error = Plugin::load(&path).unwrap_err();
+    assert!(matches!(&error, RustbucketError::Plugin { path: failed, .. } if *failed == path));
+    assert_eq!(error.exit_code(), 78);
+    assert!(plugins::discover(dir.path()).is_err());
+}
+
+#[test]
+fn finding_an_unknown_plugin_names_the_directory() {
+    let dir = TestDir::new().unwrap();
+
+    let error = plugins::find(dir.path(), "upper").unwrap_err();
+    assert!(matches!(error, RustbucketError::InvalidConfig(_)));
+    assert!(error.to_string().contains("no plugin named upper"));
+}
+
+#[test]
+fn plugins_answer_through_the_write_callback_and_are_destroyed_on_unload() {
+    let dir = TestDir::new().unwrap();
+    let path = install(&dir.join("plugins"), &format!("{}upper{}", DLL_PREFIX, DLL_SUFFIX));
+    // Keeps the library loaded after the server lets go of it, to read its counters
+    let library = unsafe { Library::new(&path) }.unwrap();
+
+    let plugin = plugins::find(dir.join("plugins"), "upper").unwrap();
+    assert_eq!(plugin.path(), path);
+    let server = TestServer::start_with(|builder| builder.handler(plugin)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("hello\n").unwrap(), "#1 HELLO\n");
+    assert_eq!(client.request("again\n").unwrap(), "#1 AGAIN\n");
+    assert_eq!(counter(&library, b"upper_destroys\0"), 0);
+
+    drop(client);
+    server.shutdown();
+    drop(server);
+    assert_eq!(counter(&library, b"upper_inits\0"), 1);
+    assert_eq!(counter(&library, b"upper_destroys\0"), 1);
+}
+
+#[test]
+fn plugins_built_for_another_interface_are_destroyed_and_refused() {
+    let dir = TestDir::new().unwrap();
+    let path = install(dir.path(), &format!("future{}", DLL_SUFFIX));
+    let library = unsafe { Library::new(&path) }.unwrap();
+    unsafe { library.get::<unsafe extern "C" fn(u32)>(b"upper_set_abi_version\0").unwrap()(2) };
+
+    let error = Plugin::load(&path).unwrap_err();
+    assert!(error.to_string().contains("built for plugin interface version 2"), "{}", error);
+    assert_eq!(counter(&library, b"upper_destroys\0"), 1);
+}
+
+#[test]
+fn finding_a_plugin_loads_only_the_library_it_needs() {
+    let dir = TestDir::new().unwrap();
+    let other = install(dir.path(), &format!("another{}", DLL_SUFFIX));
+    install(dir.path(), &format!("{}upper{}", DLL_PREFIX, DLL_SUFFIX));
+    let other = unsafe { Library::new(&other) }.unwrap();
+
+    assert_eq!(plugins::find(dir.path(), "upper").unwrap().name(), "upper");
+    assert_eq!(counter(&other, b"upper_inits\0"), 0);
+}
+
+#[test]
+fn finding_a_plugin_skips_libraries_that_fail_to_load() {
+    let dir = TestDir::new().unwrap();
+    fs::write(dir.join(format!("broken{}", DLL_SUFFIX)), "not a shared library").unwrap();
+    // Not named after the plugin, so it is only found by looking through the directory
+    install(dir.path(), &format!("zz_plugin{}", DLL_SUFFIX));
+
+    assert_eq!(plugins::find(dir.path(), "upper").unwrap().name(), "upper");
+    let error = plugins::find(dir.path(), "lower").unwrap_err();
+    assert!(error.to_string().contains("broken"), "{}", error);
+    assert!(error.to_string().contains("upper"), "{}", error);
+}
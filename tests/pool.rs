@@ -0,0 +1,103 @@
+//! Sizing the worker pool: fixed, or growing and shrinking with the load.
+
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::server::PoolStats;
+use rustbucket::testing::TestServer;
+use rustbucket::{ConnectionCtx, RequestHandler, ResponseWriter, RustbucketError};
+
+/// Waits for the pool to reach a state `done` accepts, returning the last stats seen
+fn wait_for(server: &TestServer, done: impl Fn(&PoolStats) -> bool) -> PoolStats {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = server.handle().workers();
+        if done(&stats) || Instant::now() > deadline {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn a_scaling_pool_grows_under_load_and_shrinks_when_idle() {
+    let server = TestServer::start_with(|builder| {
+        builder.threads(1).max_threads(3).thread_idle_timeout(Duration::from_millis(200))
+    })
+    .unwrap();
+    let stats = server.handle().workers();
+    assert_eq!((stats.workers, stats.min_workers, stats.max_workers), (1, 1, 3));
+
+    // Every open connection holds a worker, so each of these needs one of its own
+    let mut clients: Vec<_> = (0..3).map(|_| server.client().unwrap()).collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        assert_eq!(client.request(&format!("client {}\n", i)).unwrap(), format!("Echo: client {}\n", i));
+    }
+    let stats = wait_for(&server, |stats| stats.active == 3);
+    assert_eq!((stats.workers, stats.active, stats.queued), (3, 3, 0));
+
+    // Past the maximum, connections wait for a worker
+    let mut waiting = server.client().unwrap();
+    let stats = wait_for(&server, |stats| stats.queued == 1);
+    assert_eq!((stats.workers, stats.queued), (3, 1));
+    clients.pop().unwrap().close();
+    assert_eq!(waiting.request("finally\n").unwrap(), "Echo: finally\n");
+
+    waiting.close();
+    clients.into_iter().for_each(|client| client.close());
+    let stats = wait_for(&server, |stats| stats.workers == 1);
+    assert_eq!((stats.workers, stats.active), (1, 0));
+
+    // Having shrunk, it grows again
+    let mut clients: Vec<_> = (0..2).map(|_| server.client().unwrap()).collect();
+    for client in &mut clients {
+        assert_eq!(client.request("again\n").unwrap(), "Echo: again\n");
+    }
+    assert_eq!(wait_for(&server, |stats| stats.active == 2).workers, 2);
+}
+
+#[test]
+fn a_fixed_pool_keeps_its_size() {
+    let server = TestServer::start_with(|builder| builder.threads(2)).unwrap();
+    let mut clients: Vec<_> = (0..2).map(|_| server.client().unwrap()).collect();
+    for client in &mut clients {
+        assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+    }
+    let mut waiting = server.client().unwrap();
+    let stats = wait_for(&server, |stats| stats.queued == 1);
+    assert_eq!((stats.workers, stats.min_workers, stats.max_workers, stats.queued), (2, 2, 2, 1));
+
+    clients.pop().unwrap().close();
+    assert_eq!(waiting.request("hello\n").unwrap(), "Echo: hello\n");
+    assert_eq!(server.handle().workers().workers, 2);
+}
+
+struct Panicking;
+
+impl RequestHandler for Panicking {
+    fn on_message(&self, _ctx: &ConnectionCtx<'_>, message: &[u8], response: &mut ResponseWriter) {
+        if message == b"boom\n" {
+            panic!("the handler gave up");
+        }
+        response.write(message);
+    }
+}
+
+#[test]
+fn a_worker_survives_a_panicking_job() {
+    let server = TestServer::start_with(|builder| builder.threads(1).handler(Panicking)).unwrap();
+    let client = server.client().unwrap();
+    client.stream().write_all(b"boom\n").unwrap();
+    assert_eq!(wait_for(&server, |stats| stats.panicked == 1).panicked, 1);
+
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("still here\n").unwrap(), "still here\n");
+    assert_eq!(server.handle().workers().workers, 1);
+}
+
+#[test]
+fn the_most_threads_must_be_at_least_the_fewest() {
+    let error = TestServer::start_with(|builder| builder.threads(4).max_threads(2)).err().unwrap();
+    assert!(matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("(2)")), "{}", error);
+}
@@ -0,0 +1,120 @@
+//! Message queues: delivery, redelivery, and the journal.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustbucket::queue::{Delivery, MessageQueue, QueueHandler};
+use rustbucket::testing::{TestDir, TestServer};
+
+fn queue_server(queue: Arc<MessageQueue>) -> TestServer {
+    TestServer::start_with(|builder| builder.handler(QueueHandler::new(queue))).unwrap()
+}
+
+#[test]
+fn messages_are_delivered_in_order_and_acknowledged() {
+    let server = queue_server(Arc::new(MessageQueue::new()));
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("ENQUEUE jobs \"resize photo.jpg\"\nENQUEUE jobs send-mail\n").unwrap(), "0\n1\n");
+    assert_eq!(client.request("DEQUEUE jobs\n").unwrap(), "*3\n0\n1\nresize photo.jpg\n");
+    assert_eq!(client.request("DEQUEUE jobs\n").unwrap(), "*3\n1\n1\nsend-mail\n");
+    assert_eq!(client.request("DEQUEUE jobs\nDEQUEUE other\n").unwrap(), "(nil)\n(nil)\n");
+    assert_eq!(client.request("ACK jobs 0\nACK jobs 0\nACK other 1\n").unwrap(), "1\n0\n0\n");
+    assert_eq!(client.request("ACK jobs x\n").unwrap(), "ERR message id is not an integer\n");
+    assert_eq!(client.request("DEQUEUE jobs 0\n").unwrap(), "ERR visibility timeout is not a positive number\n");
+}
+
+#[test]
+fn visibility_timeouts_are_capped() {
+    let queue = Arc::new(MessageQueue::new());
+    let server = queue_server(Arc::clone(&queue));
+    let mut client = server.client().unwrap();
+
+    client.request("ENQUEUE jobs a\nENQUEUE jobs b\n").unwrap();
+    assert_eq!(client.request("DEQUEUE jobs 1e19\n").unwrap(), "ERR visibility timeout is longer than 43200 seconds\n");
+    assert_eq!(client.request("DEQUEUE jobs 43200\n").unwrap(), "*3\n0\n1\na\n");
+
+    // The library caps it instead of overflowing the clock
+    assert_eq!(queue.dequeue(b"jobs", Duration::MAX).unwrap().id, 1);
+    assert_eq!(client.request("DEQUEUE jobs\nACK jobs 0\n").unwrap(), "(nil)\n1\n");
+}
+
+#[test]
+fn unacknowledged_messages_are_redelivered() {
+    let queue = MessageQueue::new();
+    queue.enqueue(b"jobs", b"first").unwrap();
+    queue.enqueue(b"jobs", b"second").unwrap();
+
+    let delivery = queue.dequeue(b"jobs", Duration::from_millis(50)).unwrap();
+    assert_eq!(delivery, Delivery { id: 0, body: b"first".to_vec(), deliveries: 1 });
+    thread::sleep(Duration::from_millis(100));
+
+    // The expired message goes back to the front of the queue
+    let again = queue.dequeue(b"jobs", Duration::from_secs(60)).unwrap();
+    assert_eq!((again.id, again.deliveries), (0, 2));
+    assert_eq!(queue.dequeue(b"jobs", Duration::from_secs(60)).unwrap().id, 1);
+    assert_eq!(queue.dequeue(b"jobs", Duration::from_secs(60)), None);
+    assert_eq!(queue.len(b"jobs"), 2);
+
+    assert!(queue.ack(b"jobs", 0).unwrap());
+    assert!(queue.ack(b"jobs", 1).unwrap());
+    assert!(queue.is_empty(b"jobs"));
+}
+
+#[test]
+fn unacknowledged_messages_survive_a_restart() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("queue.journal");
+    {
+        let queue = MessageQueue::open(&path).unwrap();
+        for body in ["a", "b", "c"] {
+            queue.enqueue(b"jobs", body.as_bytes()).unwrap();
+        }
+        queue.dequeue(b"jobs", Duration::from_secs(60)).unwrap();
+        queue.dequeue(b"jobs", Duration::from_secs(60)).unwrap();
+        queue.ack(b"jobs", 0).unwrap();
+    }
+    // A record torn in half by a crash is dropped
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[9, 0, 0]).unwrap();
+
+    let queue = MessageQueue::open(&path).unwrap();
+    assert_eq!(queue.len(b"jobs"), 2);
+    // Messages in flight when the queue closed are ready again
+    assert_eq!(queue.dequeue(b"jobs", Duration::from_secs(60)).unwrap().body, b"b");
+    assert_eq!(queue.dequeue(b"jobs", Duration::from_secs(60)).unwrap().body, b"c");
+    assert_eq!(queue.enqueue(b"jobs", b"d").unwrap(), 3);
+}
+
+#[test]
+fn the_journal_is_compacted_and_ids_are_never_reused() {
+    let dir = TestDir::new().unwrap();
+    let path = dir.join("queue.journal");
+    let last_id = {
+        let queue = MessageQueue::open(&path).unwrap();
+        queue.enqueue(b"jobs", b"kept").unwrap();
+        let mut last_id = 0;
+        for _ in 0..2000 {
+            last_id = queue.enqueue(b"jobs", b"done").unwrap();
+            queue.ack(b"jobs", last_id).unwrap();
+        }
+        last_id
+    };
+    assert!(fs::metadata(&path).unwrap().len() < 64 * 1024, "the journal was never compacted");
+
+    let queue = MessageQueue::open(&path).unwrap();
+    assert_eq!(queue.len(b"jobs"), 1);
+    assert_eq!(queue.enqueue(b"jobs", b"new").unwrap(), last_id + 1);
+}
+
+#[test]
+fn message_bodies_are_binary_safe() {
+    let server = queue_server(Arc::new(MessageQueue::new()));
+    let mut client = server.client().unwrap();
+    let body = b"\x00\x01\n\xfe";
+
+    assert_eq!(client.send(&[b"ENQUEUE jobs $4\n".as_slice(), body, b"\n"].concat()).unwrap(), b"0\n");
+    assert_eq!(client.send(b"DEQUEUE jobs\n").unwrap(), [b"*3\n0\n1\n$4\n".as_slice(), body, b"\n"].concat());
+}
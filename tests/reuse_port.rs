@@ -0,0 +1,59 @@
+//! Accepting on a listener per worker, bound with SO_REUSEPORT.
+#![cfg(target_os = "linux")]
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::testing::{TestClient, TestServer};
+
+#[test]
+fn every_worker_accepts_and_serves_its_own_connections() {
+    let server = TestServer::start_with(|builder| builder.threads(4).reuse_port(true)).unwrap();
+    let addr = server.addr();
+    // Binding the port without SO_REUSEPORT fails, as the workers' listeners hold it
+    assert!(TcpListener::bind(addr).is_err());
+
+    // Each client connects from a port of its own, so the kernel spreads them across the listeners
+    let clients: Vec<_> = (0..16)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = TestClient::connect(addr).unwrap();
+                let reply = client.request(&format!("client {}\n", i)).unwrap();
+                client.close();
+                reply
+            })
+        })
+        .collect();
+    for (i, client) in clients.into_iter().enumerate() {
+        assert_eq!(client.join().unwrap(), format!("Echo: client {}\n", i));
+    }
+
+    // The workers stay on their listeners rather than queueing jobs
+    let pool = server.handle().workers();
+    assert_eq!((pool.workers, pool.active, pool.queued), (4, 4, 0));
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("after\n").unwrap(), "Echo: after\n");
+    client.close();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.handle().metrics().connections_closed < 17 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let metrics = server.handle().metrics();
+    assert_eq!((metrics.connections_accepted, metrics.connections_closed), (17, 17));
+}
+
+#[test]
+fn shutdown_stops_every_worker() {
+    let server = TestServer::start_with(|builder| builder.threads(3).reuse_port(true)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+    client.close();
+
+    let handle = server.handle().clone();
+    handle.shutdown_graceful();
+    handle.wait();
+    assert!(handle.is_finished());
+    assert_eq!(handle.workers().active, 0);
+}
@@ -0,0 +1,158 @@
+//! Log rotation with the `rotate` command, and the server reopening its log afterwards.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use nix::sys::signal::{raise, Signal};
+use rustbucket::logging::{FileSink, LogSink};
+use rustbucket::testing::{TestDir, TestServer};
+
+/// Runs `rustbucket rotate` with `args` in `dir`
+fn rotate(dir: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rustbucket"))
+        .arg("rotate")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "rotate failed: {}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Waits for `path` to contain `text`, returning whether it did in time
+fn wait_for_log(path: &Path, text: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if fs::read_to_string(path).unwrap_or_default().contains(text) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn file_sink_reopens_after_the_log_is_moved() {
+    let dir = TestDir::new().unwrap();
+    let sink = FileSink::open(dir.join("http.log")).unwrap();
+    sink.write("before rotation");
+
+    fs::rename(dir.join("http.log"), dir.join("http.log.1")).unwrap();
+    sink.reopen().unwrap();
+    sink.write("after rotation");
+
+    let rotated = fs::read_to_string(dir.join("http.log.1")).unwrap();
+    let current = fs::read_to_string(dir.join("http.log")).unwrap();
+    assert!(rotated.contains("before rotation") && !rotated.contains("after rotation"));
+    assert!(current.contains("after rotation") && !current.contains("before rotation"));
+}
+
+#[test]
+fn rotate_moves_the_server_log_aside() {
+    let server = TestServer::start().unwrap();
+    server.client().unwrap().request("hello\n").unwrap();
+    server.shutdown();
+
+    rotate(server.dir(), &[]);
+
+    if cfg!(windows) {
+        assert_eq!(fs::read_to_string(server.log_path()).unwrap(), "");
+    } else {
+        assert!(!server.log_path().exists());
+    }
+    let rotated = fs::read_to_string(server.dir().join("http.log.1")).unwrap();
+    assert!(rotated.contains("Server started"));
+    assert!(rotated.contains("Server shutdown complete"));
+}
+
+#[test]
+fn rotate_keeps_only_the_requested_backups() {
+    let server = TestServer::start().unwrap();
+    server.shutdown();
+
+    for _ in 0..3 {
+        fs::write(server.log_path(), "line\n").unwrap();
+        rotate(server.dir(), &["--keep", "2", "--compress"]);
+    }
+
+    assert!(server.dir().join("http.log.1.gz").exists());
+    assert!(server.dir().join("http.log.2.gz").exists());
+    assert!(!server.dir().join("http.log.3.gz").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn sighup_reopens_the_log_after_rotation() {
+    // The only test here that installs signal handlers, since they are process-wide
+    let server = TestServer::start_with(|builder| builder.handle_signals(true)).unwrap();
+    assert!(wait_for_log(&server.log_path(), "Server started"));
+
+    rotate(server.dir(), &[]);
+    raise(Signal::SIGHUP).unwrap();
+
+    assert!(wait_for_log(&server.log_path(), "Reloaded configuration"));
+    server.client().unwrap().request("hello\n").unwrap();
+    assert!(wait_for_log(&server.log_path(), "Connection #1 opened"));
+    let rotated = fs::read_to_string(server.dir().join("http.log.1")).unwrap();
+    assert!(rotated.contains("Server started") && !rotated.contains("Connection #1"));
+}
+
+#[test]
+fn rotating_by_copy_needs_no_reload() {
+    let server = TestServer::start().unwrap();
+    assert!(wait_for_log(&server.log_path(), "Server started"));
+
+    rotate(server.dir(), &["--strategy", "copy-truncate"]);
+    server.client().unwrap().request("hello\n").unwrap();
+
+    assert!(wait_for_log(&server.log_path(), "Connection #1 opened"));
+    let current = fs::read_to_string(server.log_path()).unwrap();
+    let rotated = fs::read_to_string(server.dir().join("http.log.1")).unwrap();
+    assert!(rotated.contains("Server started") && !rotated.contains("Connection #1"));
+    assert!(!current.contains("Server started"));
+}
+
+#[test]
+fn rotating_by_copy_waits_for_a_writer_holding_the_lock() {
+    let dir = TestDir::new().unwrap();
+    let mut log = OpenOptions::new().create(true).append(true).open(dir.join("http.log")).unwrap();
+    log.lock().unwrap();
+    writeln!(log, "first").unwrap();
+
+    let rotating = thread::spawn({
+        let dir = dir.path().to_path_buf();
+        move || rotate(&dir, &["--strategy", "copy-truncate"])
+    });
+    thread::sleep(Duration::from_millis(200));
+    assert!(!dir.join("http.log.1").exists());
+    // Written before the rotation gets the lock, so it must reach the backup rather than be truncated away
+    writeln!(log, "second").unwrap();
+    log.unlock().unwrap();
+    rotating.join().unwrap();
+
+    assert_eq!(fs::read_to_string(dir.join("http.log.1")).unwrap(), "first\nsecond\n");
+    assert_eq!(fs::read_to_string(dir.join("http.log")).unwrap(), "");
+    writeln!(log, "third").unwrap();
+    assert_eq!(fs::read_to_string(dir.join("http.log")).unwrap(), "third\n");
+}
+
+#[cfg(windows)]
+#[test]
+fn file_sink_waits_for_a_rotation_holding_the_lock() {
+    let dir = TestDir::new().unwrap();
+    let sink = FileSink::open(dir.join("http.log")).unwrap();
+    let rotation = OpenOptions::new().write(true).open(dir.join("http.log")).unwrap();
+    rotation.lock().unwrap();
+
+    let writing = thread::spawn(move || sink.write("during rotation"));
+    thread::sleep(Duration::from_millis(200));
+    assert!(!writing.is_finished());
+    rotation.unlock().unwrap();
+    writing.join().unwrap();
+
+    assert!(fs::read_to_string(dir.join("http.log")).unwrap().contains("during rotation"));
+}
@@ -0,0 +1,48 @@
+//! Landlock and seccomp sandboxing. The syscall filter applies to the whole
+//! test process once installed, which is why these tests have a binary of
+//! their own.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use rustbucket::paths::Paths;
+use rustbucket::sandbox::{Enforcement, Sandbox};
+use rustbucket::testing::TestServer;
+
+#[test]
+fn sandbox_covers_the_directories_of_the_servers_files() {
+    let paths = Paths { log_file: PathBuf::from("/var/log/rustbucket/http.log"), ..Paths::in_dir("/srv/rustbucket") };
+    let sandbox = Sandbox::for_paths(&paths);
+    let writable = sandbox.writable_paths();
+    assert!(writable.contains(&PathBuf::from("/var/log/rustbucket")), "{:?}", writable);
+    assert!(writable.contains(&PathBuf::from("/srv/rustbucket")), "{:?}", writable);
+    assert!(writable.contains(&paths.storage_dir), "{:?}", writable);
+    // Files sharing a directory share its rule
+    assert_eq!(writable.len(), 3, "{:?}", writable);
+
+    let bare = Sandbox::for_paths(&Paths::default());
+    assert!(bare.writable_paths().contains(&PathBuf::from(".")), "{:?}", bare.writable_paths());
+}
+
+#[test]
+fn sandboxed_server_serves_clients_but_cannot_leave_its_files() {
+    let server = TestServer::start_with(|builder| builder.sandbox(Sandbox::default().writable(std::env::temp_dir())))
+        .unwrap();
+    let status = server.handle().sandbox_status().expect("the server was started with a sandbox");
+    assert_eq!(server.client().unwrap().request("hello\n").unwrap(), "Echo: hello\n");
+    assert!(server.log().contains("Sandbox: filesystem rules"), "{}", server.log());
+
+    if status.syscalls == Enforcement::Full {
+        let error = Command::new("true").status().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied, "{}", error);
+    }
+    // Landlock rules cover the thread that started the server, like the rest of its threads
+    if status.filesystem == Enforcement::Full {
+        fs::write(server.dir().join("allowed"), "inside the sandbox").unwrap();
+        let outside = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("sandbox-escape");
+        let error = fs::write(&outside, "outside the sandbox").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied, "{}", error);
+    }
+}
@@ -0,0 +1,114 @@
+//! Lua request scripts run as middleware.
+
+use std::fs;
+use std::path::PathBuf;
+
+use rustbucket::scripting::ScriptLayer;
+use rustbucket::testing::{TestDir, TestServer};
+use rustbucket::RustbucketError;
+
+/// Writes `source` to `name` in `dir`, returning its path
+fn script(dir: &TestDir, name: &str, source: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn scripted_server(layer: ScriptLayer) -> TestServer {
+    TestServer::start_with(|builder| builder.middleware(layer)).unwrap()
+}
+
+#[test]
+fn scripts_rewrite_requests_and_responses() {
+    let dir = TestDir::new().unwrap();
+    let path = script(
+        &dir,
+        "shout.lua",
+        r#"
+        function on_request(message, connection_id)
+            if message:sub(1, 1) == "!" then return message:sub(2) end
+        end
+        function on_response(message, response)
+            return response:upper()
+        end
+        "#,
+    );
+    let server = scripted_server(ScriptLayer::load(path).unwrap());
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("!hello\n").unwrap(), "ECHO: HELLO\n");
+    assert_eq!(client.request("again\n").unwrap(), "ECHO: AGAIN\n");
+}
+
+#[test]
+fn scripts_can_reject_requests() {
+    let dir = TestDir::new().unwrap();
+    let path = script(
+        &dir,
+        "validate.lua",
+        r#"
+        function on_request(message)
+            if #message > 10 then return false, "message too long" end
+            if message:find("secret") then return false end
+        end
+        "#,
+    );
+    let server = scripted_server(ScriptLayer::load(path).unwrap());
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("hi\n").unwrap(), "Echo: hi\n");
+    assert_eq!(client.request("a much longer message\n").unwrap(), "ERR message too long\n");
+    assert_eq!(client.request("secret\n").unwrap(), "ERR rejected by script\n");
+}
+
+#[test]
+fn scripts_are_sandboxed_and_limited() {
+    let dir = TestDir::new().unwrap();
+    let path = script(
+        &dir,
+        "sandbox.lua",
+        r#"
+        function on_request(message)
+            if message == "libs\n" then return type(io) .. type(os) .. type(pcall) .. type(require) end
+            if message == "spin\n" then while true do end end
+            if message == "hog\n" then return string.rep("x", 64 * 1024 * 1024) end
+        end
+        "#,
+    );
+    let layer = ScriptLayer::load(path).unwrap().memory_limit(1024 * 1024).instruction_limit(100_000);
+    let server = scripted_server(layer);
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("libs\n").unwrap(), "Echo: nilnilnilnil");
+    assert_eq!(client.request("spin\n").unwrap(), "ERR script failed\n");
+    assert_eq!(client.request("hog\n").unwrap(), "ERR script failed\n");
+    // The interpreter is still usable after hitting a limit
+    assert_eq!(client.request("ok\n").unwrap(), "Echo: ok\n");
+}
+
+#[test]
+fn scripts_that_do_not_load_are_errors() {
+    let dir = TestDir::new().unwrap();
+    let path = script(&dir, "broken.lua", "function on_request(");
+
+    let error = ScriptLayer::load(&path).err().unwrap();
+    assert!(matches!(&error, RustbucketError::Script { path: failed, .. } if *failed == path));
+    assert_eq!(error.exit_code(), 78);
+    assert!(ScriptLayer::load(dir.join("missing.lua")).is_err());
+}
+
+#[test]
+fn directories_load_lua_files_in_order() {
+    let dir = TestDir::new().unwrap();
+    assert!(ScriptLayer::load_dir(dir.join("scripts")).unwrap().is_empty());
+
+    script(&dir, "b.lua", "");
+    script(&dir, "a.lua", "");
+    script(&dir, "notes.txt", "not a script");
+    let names: Vec<_> = ScriptLayer::load_dir(dir.path())
+        .unwrap()
+        .iter()
+        .map(|layer| layer.path().file_name().unwrap().to_owned())
+        .collect();
+    assert_eq!(names, ["a.lua", "b.lua"]);
+}
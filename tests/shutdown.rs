@@ -0,0 +1,208 @@
+//! Graceful and immediate shutdown through a `ShutdownHandle`.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustbucket::config::MemorySource;
+use rustbucket::kv::{KvHandler, Store};
+use rustbucket::testing::TestServer;
+use rustbucket::Config;
+
+#[test]
+fn graceful_shutdown_without_clients_is_prompt() {
+    let server = TestServer::start().unwrap();
+
+    let started = Instant::now();
+    server.shutdown();
+
+    assert!(started.elapsed() < Duration::from_secs(1), "shutdown took {:?}", started.elapsed());
+    assert!(server.handle().is_finished());
+    assert!(!server.handle().was_forced());
+    assert!(server.log().contains("Server shutdown complete"));
+}
+
+#[test]
+fn graceful_shutdown_lets_requests_in_progress_finish() {
+    let server = TestServer::start().unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("half a ").unwrap(), "Echo: half a ");
+
+    server.handle().shutdown_graceful();
+
+    client.stream().write_all(b"request\n").unwrap();
+    let mut replies = String::new();
+    client.stream().read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "Echo: request\nServer closing\n");
+    server.handle().wait();
+    assert!(server.handle().is_finished());
+}
+
+#[test]
+fn graceful_shutdown_closes_idle_connections_at_once() {
+    // A long timeout, so waiting for it would stall the shutdown
+    let config = MemorySource::new(Config { timeout_seconds: 60, ..Config::new() });
+    let server = TestServer::start_with(|builder| builder.threads(3).config_source(config)).unwrap();
+    let mut clients: Vec<_> = (0..3).map(|_| server.client().unwrap()).collect();
+    for client in &mut clients {
+        client.request("hello\n").unwrap();
+    }
+
+    let started = Instant::now();
+    server.shutdown();
+
+    assert!(started.elapsed() < Duration::from_secs(2), "shutdown took {:?}", started.elapsed());
+    for client in &mut clients {
+        assert_eq!(client.read_reply().unwrap(), b"Server closing\n");
+        assert!(client.is_closed_by_server());
+    }
+}
+
+#[test]
+fn graceful_shutdown_tells_idle_clients_in_their_protocol() {
+    let handler = KvHandler::new(Arc::new(Store::new()));
+    let server = TestServer::start_with(|builder| builder.handler(handler)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("SET greeting hello\n").unwrap(), "OK\n");
+
+    server.handle().shutdown_graceful();
+
+    assert_eq!(client.read_reply().unwrap(), b"ERR server closing\n");
+    assert!(client.is_closed_by_server());
+}
+
+/// Starts a server with its admin interface on a free port, returned alongside it
+#[cfg(feature = "admin")]
+fn with_admin() -> (TestServer, u16) {
+    let admin_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    (TestServer::start_with(|builder| builder.admin_port(admin_port)).unwrap(), admin_port)
+}
+
+/// Asks the admin interface to drain the server, the way SIGTERM does
+#[cfg(feature = "admin")]
+fn drain(admin_port: u16) {
+    let mut admin = TcpStream::connect(("127.0.0.1", admin_port)).unwrap();
+    admin.write_all(b"POST /drain HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    admin.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 2"), "{}", response);
+}
+
+/// Waits up to two seconds for the server to finish, returning how long it took
+#[cfg(feature = "admin")]
+fn wait_briefly(server: &TestServer) -> Duration {
+    let started = Instant::now();
+    while !server.handle().is_finished() && started.elapsed() < Duration::from_secs(2) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server.handle().is_finished(), "still running after {:?}", started.elapsed());
+    started.elapsed()
+}
+
+#[cfg(feature = "admin")]
+#[test]
+fn a_drain_request_stops_the_server_without_another_client_connecting() {
+    let (server, admin_port) = with_admin();
+    drain(admin_port);
+
+    wait_briefly(&server);
+    assert!(!server.handle().was_forced());
+    assert_eq!(server.handle().metrics().connections_accepted, 0);
+}
+
+#[cfg(feature = "admin")]
+#[test]
+fn a_second_drain_request_forces_the_shutdown() {
+    let (server, admin_port) = with_admin();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("half a ").unwrap(), "Echo: half a ");
+
+    drain(admin_port);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!server.handle().is_finished(), "a request in progress should hold off a graceful shutdown");
+    drain(admin_port);
+
+    wait_briefly(&server);
+    assert!(server.handle().was_forced());
+    assert!(client.is_closed_by_server());
+}
+
+#[test]
+fn stops_accepting_after_shutdown() {
+    let server = TestServer::start().unwrap();
+    let addr = server.addr();
+
+    server.shutdown();
+
+    assert!(TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn removes_the_pidfile_on_shutdown() {
+    let server = TestServer::start().unwrap();
+    let pidfile = server.paths().pid_file;
+    assert!(pidfile.exists());
+
+    server.shutdown();
+
+    assert!(!pidfile.exists());
+}
+
+#[test]
+fn immediate_shutdown_closes_idle_connections() {
+    // A long timeout, so only the forced close can end the connection quickly
+    let config = MemorySource::new(Config { timeout_seconds: 60, ..Config::new() });
+    let server = TestServer::start_with(|builder| builder.config_source(config)).unwrap();
+    let mut client = server.client().unwrap();
+    client.request("hello\n").unwrap();
+
+    let started = Instant::now();
+    server.handle().shutdown_now();
+    server.handle().wait();
+
+    assert!(started.elapsed() < Duration::from_secs(5), "shutdown took {:?}", started.elapsed());
+    assert!(client.is_closed_by_server());
+    assert!(server.handle().was_forced());
+}
+
+#[test]
+fn every_clone_of_the_handle_sees_the_shutdown() {
+    let server = TestServer::start().unwrap();
+    let handle = server.handle().clone();
+
+    let waiter = std::thread::spawn(move || handle.wait());
+    server.handle().shutdown_graceful();
+
+    waiter.join().unwrap();
+    assert!(server.handle().is_finished());
+}
+
+#[test]
+fn shutdown_is_prompt_during_a_connection_storm() {
+    let server = TestServer::start_with(|builder| builder.threads(4)).unwrap();
+    let addr = server.addr();
+
+    // Clients connect and leave as fast as they can, so the listener always has more waiting
+    let storm: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let mut connected = 0;
+                while let Ok(stream) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+                    drop(stream);
+                    connected += 1;
+                }
+                connected
+            })
+        })
+        .collect();
+    std::thread::sleep(Duration::from_millis(300));
+    let accepted = server.handle().metrics().connections_accepted;
+    assert!(accepted > 0);
+
+    let started = Instant::now();
+    server.shutdown();
+    assert!(started.elapsed() < Duration::from_secs(2), "shutdown took {:?}", started.elapsed());
+    let connected: u64 = storm.into_iter().map(|client| client.join().unwrap()).sum();
+    assert!(connected >= server.handle().metrics().connections_accepted);
+}
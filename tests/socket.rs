@@ -0,0 +1,83 @@
+//! Socket options set on accepted connections.
+#![cfg(unix)]
+
+use std::io::{self, Read};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+use nix::libc::{self, c_int};
+use rustbucket::socket::{Keepalive, SocketOptions};
+use rustbucket::testing::{TestClient, TestServer};
+
+/// Reads an option back from `stream`, into `value`
+fn get_option<T>(stream: &TcpStream, level: c_int, name: c_int, mut value: T) -> T {
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    // SAFETY: the pointer and length describe `value`, which outlives the call
+    let result = unsafe { libc::getsockopt(stream.as_raw_fd(), level, name, (&mut value as *mut T).cast(), &mut len) };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    value
+}
+
+/// Reads until the server closes the connection, failing if it reset it instead
+fn read_to_close(client: &TestClient) -> io::Result<()> {
+    let mut buffer = [0; 256];
+    while (&mut client.stream()).read(&mut buffer)? > 0 {}
+    Ok(())
+}
+
+#[test]
+fn every_option_is_set_on_the_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let keepalive = Keepalive { idle: Duration::from_secs(30), interval: Duration::from_secs(5), retries: 3 };
+    let options = SocketOptions {
+        nodelay: true,
+        recv_buffer: Some(256 * 1024),
+        send_buffer: Some(512 * 1024),
+        linger: Some(Duration::from_secs(2)),
+        keepalive: Some(keepalive),
+    };
+    options.apply(&stream).unwrap();
+
+    assert!(stream.nodelay().unwrap());
+    // Linux reports double what was asked for
+    assert!(get_option(&stream, libc::SOL_SOCKET, libc::SO_RCVBUF, 0) >= 256 * 1024);
+    assert!(get_option(&stream, libc::SOL_SOCKET, libc::SO_SNDBUF, 0) >= 512 * 1024);
+    let linger = get_option(&stream, libc::SOL_SOCKET, libc::SO_LINGER, libc::linger { l_onoff: 0, l_linger: 0 });
+    assert_eq!((linger.l_onoff, linger.l_linger), (1, 2));
+    assert_eq!(get_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0), 1);
+    #[cfg(target_os = "linux")]
+    assert_eq!(get_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, 0), 30);
+    assert_eq!(get_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, 0), 5);
+    assert_eq!(get_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, 0), 3);
+
+    // Options left unset keep the kernel's defaults
+    let (plain, _) = {
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        listener.accept().unwrap()
+    };
+    SocketOptions::default().apply(&plain).unwrap();
+    assert!(!plain.nodelay().unwrap());
+    assert_eq!(get_option(&plain, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0), 0);
+}
+
+#[test]
+fn server_sets_the_options_on_its_clients() {
+    // With no time to linger, a connection the server closes is reset rather than closed cleanly
+    let options = SocketOptions { linger: Some(Duration::ZERO), ..SocketOptions::default() };
+    let deadline = Duration::from_millis(300);
+    let server = TestServer::start_with(|builder| builder.request_deadline(deadline).socket_options(options)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+    assert_eq!(client.request("partial").unwrap(), "Echo: partial");
+    let error = read_to_close(&client).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionReset, "{}", error);
+
+    let server = TestServer::start_with(|builder| builder.request_deadline(deadline)).unwrap();
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("partial").unwrap(), "Echo: partial");
+    read_to_close(&client).unwrap();
+}
@@ -0,0 +1,87 @@
+//! The per-connection read timeout from the configuration, and the limits on slow requests.
+
+use std::time::{Duration, Instant};
+
+use rustbucket::config::MemorySource;
+use rustbucket::testing::TestServer;
+use rustbucket::Config;
+
+fn with_timeout(timeout_seconds: u32) -> MemorySource {
+    MemorySource::new(Config { timeout_seconds, ..Config::new() })
+}
+
+#[test]
+fn idle_connections_notice_graceful_shutdown_within_the_timeout() {
+    let server = TestServer::start_with(|builder| builder.config_source(with_timeout(1))).unwrap();
+    let mut client = server.client().unwrap();
+    client.request("hello\n").unwrap();
+
+    let started = Instant::now();
+    server.shutdown();
+
+    assert!(started.elapsed() < Duration::from_secs(3), "shutdown took {:?}", started.elapsed());
+    assert_eq!(client.read_reply().unwrap(), b"Server closing\n");
+    assert!(client.is_closed_by_server());
+}
+
+#[test]
+fn idle_connections_stay_open_until_shutdown() {
+    let server = TestServer::start_with(|builder| builder.config_source(with_timeout(1))).unwrap();
+    let mut client = server.client().unwrap();
+
+    // Several timeouts pass without the connection being dropped
+    std::thread::sleep(Duration::from_millis(2500));
+
+    assert_eq!(client.request("still here\n").unwrap(), "Echo: still here\n");
+}
+
+#[test]
+fn a_zero_timeout_is_treated_as_one_second() {
+    let server = TestServer::start_with(|builder| builder.config_source(with_timeout(0))).unwrap();
+    let mut client = server.client().unwrap();
+
+    assert_eq!(client.request("hello\n").unwrap(), "Echo: hello\n");
+}
+
+#[test]
+fn requests_trickled_past_the_deadline_are_closed() {
+    let server = TestServer::start_with(|builder| builder.request_deadline(Duration::from_secs(1))).unwrap();
+    let mut client = server.client().unwrap();
+
+    // Each read resets the idle timeout, but not the deadline on the request as a whole
+    let started = Instant::now();
+    for part in ["GE", "T k", "ey"] {
+        assert_eq!(client.request(part).unwrap(), format!("Echo: {}", part));
+        std::thread::sleep(Duration::from_millis(400));
+    }
+    assert_eq!(client.read_reply().unwrap(), b"ERR request not completed within 1s\n");
+    assert!(client.is_closed_by_server());
+    assert!(started.elapsed() < Duration::from_secs(3), "closed after {:?}", started.elapsed());
+    assert_eq!(server.handle().metrics().slow_requests_closed, 1);
+
+    // Requests finished in time are not affected
+    let mut prompt = server.client().unwrap();
+    assert_eq!(prompt.request("par").unwrap(), "Echo: par");
+    assert_eq!(prompt.request("tial\n").unwrap(), "Echo: tial\n");
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(prompt.request("hello\n").unwrap(), "Echo: hello\n");
+}
+
+#[test]
+fn connections_starting_a_request_over_the_reading_cap_are_closed() {
+    let server = TestServer::start_with(|builder| builder.max_reading_connections(1)).unwrap();
+    let mut first = server.client().unwrap();
+    let mut second = server.client().unwrap();
+
+    assert_eq!(first.request("partial").unwrap(), "Echo: partial");
+    assert_eq!(second.request("whole\n").unwrap(), "Echo: whole\n");
+    assert_eq!(second.request("partial").unwrap(), "ERR too many requests in progress\n");
+    assert!(second.is_closed_by_server());
+    assert_eq!(server.handle().metrics().slow_requests_closed, 1);
+
+    // Finishing the request frees its place
+    assert_eq!(first.request(" done\n").unwrap(), "Echo:  done\n");
+    let mut third = server.client().unwrap();
+    assert_eq!(third.request("partial").unwrap(), "Echo: partial");
+    assert_eq!(third.request(" done\n").unwrap(), "Echo:  done\n");
+}
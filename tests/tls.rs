@@ -0,0 +1,200 @@
+//! TLS for client connections: negotiation limits and startup validation.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pkcs8::pkcs5::pbes2;
+use pkcs8::{LineEnding, PrivateKeyInfo};
+use rustbucket::chat::ChatHandler;
+use rustbucket::files::FileHandler;
+use rustbucket::testing::{TestDir, TestServer};
+use rustbucket::tls::{self, TlsSettings, TlsVersion};
+use rustbucket::RustbucketError;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ProtocolVersion, RootCertStore, StreamOwned, SupportedProtocolVersion};
+
+/// A self-signed certificate for `localhost` written to `dir`, and the settings serving it
+fn certificate(dir: &TestDir) -> (TlsSettings, CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(dir.join("server.crt"), certified.cert.pem()).unwrap();
+    fs::write(dir.join("server.key"), certified.signing_key.serialize_pem()).unwrap();
+    (TlsSettings::new(dir.join("server.crt"), dir.join("server.key")), certified.cert.der().clone())
+}
+
+type TlsClient = StreamOwned<ClientConnection, TcpStream>;
+
+fn connect(
+    server: &TestServer,
+    cert: &CertificateDer<'static>,
+    versions: &[&'static SupportedProtocolVersion],
+    alpn: &[&str],
+) -> TlsClient {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+    let session = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let socket = TcpStream::connect(server.addr()).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    StreamOwned::new(session, socket)
+}
+
+fn request(client: &mut TlsClient, message: &str) -> std::io::Result<String> {
+    client.write_all(message.as_bytes())?;
+    read_line(client)
+}
+
+fn read_line(client: &mut TlsClient) -> std::io::Result<String> {
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    while reply.last() != Some(&b'\n') {
+        client.read_exact(&mut byte)?;
+        reply.push(byte[0]);
+    }
+    Ok(String::from_utf8(reply).unwrap())
+}
+
+#[test]
+fn tls_clients_are_served_over_the_negotiated_protocol() {
+    let dir = TestDir::new().unwrap();
+    let (settings, cert) = certificate(&dir);
+    let server = TestServer::start_with(|builder| builder.tls(settings.alpn(["rustbucket/1", "echo"]))).unwrap();
+    assert!(server.log().contains("TLS: version 1.2 or later"), "{}", server.log());
+
+    let mut client = connect(&server, &cert, rustls::ALL_VERSIONS, &["h2", "echo", "rustbucket/1"]);
+    assert_eq!(request(&mut client, "hello\n").unwrap(), "Echo: hello\n");
+    assert_eq!(client.conn.alpn_protocol(), Some(&b"rustbucket/1"[..]));
+    assert_eq!(client.conn.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    // Plain TCP clients get nowhere
+    let mut plain = server.client().unwrap();
+    assert!(plain.request("hello\n").is_err() || plain.is_closed_by_server());
+}
+
+#[test]
+fn minimum_version_and_cipher_suites_limit_what_clients_negotiate() {
+    let dir = TestDir::new().unwrap();
+    let (settings, cert) = certificate(&dir);
+
+    let tls13_only = TestServer::start_with(|builder| builder.tls(settings.clone().min_version(TlsVersion::Tls13)))
+        .unwrap();
+    let mut old_client = connect(&tls13_only, &cert, &[&rustls::version::TLS12], &[]);
+    assert!(request(&mut old_client, "hello\n").is_err());
+    let mut client = connect(&tls13_only, &cert, rustls::ALL_VERSIONS, &[]);
+    assert_eq!(request(&mut client, "hello\n").unwrap(), "Echo: hello\n");
+
+    let suite = "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256";
+    let one_suite = TestServer::start_with(|builder| builder.tls(settings.cipher_suites([suite]))).unwrap();
+    let mut client = connect(&one_suite, &cert, rustls::ALL_VERSIONS, &[]);
+    assert_eq!(request(&mut client, "hello\n").unwrap(), "Echo: hello\n");
+    assert_eq!(client.conn.protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    assert_eq!(format!("{:?}", client.conn.negotiated_cipher_suite().unwrap().suite()), suite);
+}
+
+#[test]
+fn unusable_settings_stop_the_server_at_startup() {
+    let dir = TestDir::new().unwrap();
+    let (settings, _) = certificate(&dir);
+    let reason = |settings: TlsSettings| match settings.validate() {
+        Err(RustbucketError::Tls(reason)) => reason,
+        other => panic!("expected a TLS error, got {:?}", other),
+    };
+
+    assert!(settings.validate().is_ok());
+    let unknown = reason(settings.clone().cipher_suites(["TLS_RSA_WITH_RC4_128_SHA"]));
+    assert!(unknown.contains("unknown cipher suite TLS_RSA_WITH_RC4_128_SHA"), "{}", unknown);
+    assert!(unknown.contains("TLS13_AES_256_GCM_SHA384"), "{}", unknown);
+    assert!(tls::supported_cipher_suites().contains(&"TLS13_AES_128_GCM_SHA256".to_string()));
+
+    let old_suite =
+        settings.clone().min_version(TlsVersion::Tls13).cipher_suites(["tls_ecdhe_rsa_with_aes_128_gcm_sha256"]);
+    assert!(reason(old_suite).contains("TLS 1.3 or later"));
+    assert!(reason(settings.clone().alpn(["rustbucket/1", ""])).contains("ALPN protocol \"\""));
+    assert!(reason(settings.clone().alpn(["x".repeat(256)])).contains("1 to 255 bytes"));
+
+    let missing = reason(TlsSettings::new(dir.join("missing.crt"), &settings.key_file));
+    assert!(missing.contains("missing.crt"), "{}", missing);
+    fs::write(dir.join("empty.key"), "").unwrap();
+    assert!(reason(TlsSettings::new(&settings.cert_file, dir.join("empty.key"))).contains("empty.key"));
+
+    // A key that does not belong to the certificate
+    let other = TestDir::new().unwrap();
+    let (other_settings, _) = certificate(&other);
+    let mismatched = TlsSettings::new(&settings.cert_file, &other_settings.key_file);
+    let error = TestServer::start_with(|builder| builder.tls(mismatched)).err().unwrap();
+    assert!(matches!(error, RustbucketError::Tls(_)), "{:?}", error);
+    assert_eq!(error.exit_code(), 79);
+
+    assert_eq!("1.3".parse(), Ok(TlsVersion::Tls13));
+    assert!("1.1".parse::<TlsVersion>().is_err());
+}
+
+#[test]
+fn an_encrypted_key_is_served_with_its_passphrase() {
+    let dir = TestDir::new().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(dir.join("server.crt"), certified.cert.pem()).unwrap();
+    // PBKDF2 and AES-256-CBC, as `openssl pkcs8 -topk8 -v2 aes-256-cbc` writes it
+    let params = pbes2::Parameters::pbkdf2_sha256_aes256cbc(2048, b"rustbucket salt", &[7; 16]).unwrap();
+    let encrypted = PrivateKeyInfo::try_from(certified.signing_key.serialized_der())
+        .unwrap()
+        .encrypt_with_params(params, "correct horse")
+        .unwrap();
+    fs::write(dir.join("server.key"), &*encrypted.to_pem("ENCRYPTED PRIVATE KEY", LineEnding::LF).unwrap()).unwrap();
+    let settings = TlsSettings::new(dir.join("server.crt"), dir.join("server.key"));
+    let reason = |settings: TlsSettings| match settings.validate() {
+        Err(RustbucketError::Tls(reason)) => reason,
+        other => panic!("expected a TLS error, got {:?}", other),
+    };
+
+    assert!(reason(settings.clone()).contains("no passphrase was given"));
+    assert!(reason(settings.clone().key_passphrase("battery staple")).contains("is the passphrase right?"));
+
+    let settings = settings.key_passphrase("correct horse");
+    let server = TestServer::start_with(|builder| builder.tls(settings)).unwrap();
+    let mut client = connect(&server, certified.cert.der(), rustls::ALL_VERSIONS, &[]);
+    assert_eq!(request(&mut client, "hello\n").unwrap(), "Echo: hello\n");
+}
+
+#[test]
+fn outbox_messages_are_encrypted_like_replies() {
+    let dir = TestDir::new().unwrap();
+    let (settings, cert) = certificate(&dir);
+    let server = TestServer::start_with(|builder| builder.tls(settings).handler(ChatHandler::new())).unwrap();
+
+    let mut alice = connect(&server, &cert, rustls::ALL_VERSIONS, &[]);
+    let mut bob = connect(&server, &cert, rustls::ALL_VERSIONS, &[]);
+    alice.write_all(b"/nick alice\n").unwrap();
+    read_line(&mut alice).unwrap();
+    bob.write_all(b"/nick bob\n").unwrap();
+    read_line(&mut bob).unwrap();
+
+    alice.write_all(b"hi bob\n").unwrap();
+    let relayed = (0..5).map(|_| read_line(&mut bob).unwrap()).find(|line| line.starts_with("alice: "));
+    assert_eq!(relayed.as_deref(), Some("alice: hi bob\n"));
+}
+
+#[test]
+fn downloaded_files_are_encrypted_like_replies() {
+    let dir = TestDir::new().unwrap();
+    let (settings, cert) = certificate(&dir);
+    let handler = FileHandler::new(dir.join("files")).unwrap();
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("files/data.bin"), &contents).unwrap();
+    let server = TestServer::start_with(|builder| builder.tls(settings).handler(handler)).unwrap();
+
+    let mut client = connect(&server, &cert, rustls::ALL_VERSIONS, &[]);
+    let header = format!("FILE {} {:08x}\n", contents.len(), crc32fast::hash(&contents));
+    assert_eq!(request(&mut client, "GETFILE data.bin\n").unwrap(), header);
+    let mut download = vec![0; contents.len()];
+    client.read_exact(&mut download).unwrap();
+    assert!(download == contents, "downloaded contents differ");
+}
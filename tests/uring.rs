@@ -0,0 +1,161 @@
+//! Serving connections through io_uring, end to end over TCP.
+
+use std::fs;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustbucket::chat::ChatHandler;
+use rustbucket::files::FileHandler;
+use rustbucket::kv::{KvHandler, Store};
+use rustbucket::protocol::Framing;
+use rustbucket::testing::{TestClient, TestDir, TestServer};
+use rustbucket::ServerBuilder;
+
+fn uring_server(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> TestServer {
+    TestServer::start_with(|builder| configure(builder.io_uring(true))).unwrap()
+}
+
+/// Reads replies until `len` bytes have arrived
+fn read_bytes(client: &mut TestClient, len: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    while bytes.len() < len {
+        let reply = client.read_reply().unwrap();
+        assert!(!reply.is_empty(), "the server closed the connection");
+        bytes.extend(reply);
+    }
+    bytes
+}
+
+#[test]
+fn each_worker_serves_many_connections() {
+    let server = uring_server(|builder| builder.threads(2));
+    let mut clients: Vec<TestClient> = (0..50).map(|_| server.client().unwrap()).collect();
+    for round in 0..3 {
+        for (i, client) in clients.iter_mut().enumerate() {
+            let message = format!("client {} round {}\n", i, round);
+            assert_eq!(client.request(&message).unwrap(), format!("Echo: {}", message));
+        }
+    }
+    let long = format!("{}\n", "x".repeat(900));
+    assert_eq!(clients[0].request(&long).unwrap(), format!("Echo: {}", long));
+
+    clients.drain(..10).for_each(TestClient::close);
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("after\n").unwrap(), "Echo: after\n");
+    let metrics = server.handle().metrics();
+    assert_eq!(metrics.connections_accepted, 51);
+    assert_eq!(metrics.messages_received, 152);
+}
+
+#[test]
+fn commands_files_and_outbox_messages_are_served() {
+    let server = uring_server(|builder| builder.handler(KvHandler::new(Arc::new(Store::new()))));
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("SET a 1\nSET b 2\nGET b\n").unwrap(), "OK\nOK\n2\n");
+
+    let dir = TestDir::new().unwrap();
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::create_dir(dir.join("files")).unwrap();
+    fs::write(dir.join("files/data.bin"), &contents).unwrap();
+    let server = uring_server(|builder| builder.handler(FileHandler::new(dir.join("files")).unwrap()));
+    let mut client = server.client().unwrap();
+    client.stream().write_all(b"GETFILE data.bin\n").unwrap();
+    let header = format!("FILE {} {:08x}\n", contents.len(), crc32fast::hash(&contents));
+    let download = read_bytes(&mut client, header.len() + contents.len());
+    assert_eq!(&download[..header.len()], header.as_bytes());
+    assert_eq!(&download[header.len()..], contents);
+    // Contents arriving with their command are handled without waiting for another read
+    assert_eq!(client.request("PUT note.txt 5\nhello").unwrap(), "OK 3610a686\n");
+
+    let server = uring_server(|builder| builder.handler(ChatHandler::new()));
+    let mut alice = server.client().unwrap();
+    let mut bob = server.client().unwrap();
+    assert_eq!(alice.request("/nick alice\n").unwrap(), "* you joined as alice\n");
+    assert_eq!(bob.request("/nick bob\n").unwrap(), "* you joined as bob\n");
+    assert_eq!(read_bytes(&mut alice, 13), b"* bob joined\n");
+    bob.stream().write_all(b"hello\n").unwrap();
+    assert_eq!(read_bytes(&mut alice, 11), b"bob: hello\n");
+}
+
+#[test]
+fn messages_split_across_reads_are_put_back_together() {
+    let server = uring_server(|builder| builder.framing(Framing::Line));
+    let mut client = server.client().unwrap();
+    client.stream().write_all(b"hel").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.request("lo\nwor").unwrap(), "Echo: hello\n");
+    client.stream().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(client.read_reply().unwrap(), b"Echo: wor");
+
+    let server = uring_server(|builder| builder.handler(KvHandler::new(Arc::new(Store::new()))));
+    let mut client = server.client().unwrap();
+    let value = "v".repeat(10_000);
+    assert_eq!(client.request(&format!("SET big ${}\n{}\n", value.len(), value)).unwrap(), "OK\n");
+    client.stream().write_all(b"GET big\n").unwrap();
+    assert_eq!(read_bytes(&mut client, value.len() + 1), format!("{}\n", value).into_bytes());
+}
+
+#[test]
+fn slow_requests_are_cut_off_at_their_deadline() {
+    let server = uring_server(|builder| builder.request_deadline(Duration::from_millis(500)));
+    let mut client = server.client().unwrap();
+
+    let started = Instant::now();
+    assert_eq!(client.request("GET").unwrap(), "Echo: GET");
+    assert_eq!(client.read_reply().unwrap(), b"ERR request not completed within 500ms\n");
+    assert!(client.is_closed_by_server());
+    assert!(started.elapsed() < Duration::from_secs(2), "closed after {:?}", started.elapsed());
+    assert_eq!(server.handle().metrics().slow_requests_closed, 1);
+}
+
+#[test]
+fn graceful_shutdown_closes_idle_connections() {
+    let server = uring_server(|builder| builder);
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("before\n").unwrap(), "Echo: before\n");
+
+    let started = Instant::now();
+    server.shutdown();
+    assert!(started.elapsed() < Duration::from_secs(2), "shutdown took {:?}", started.elapsed());
+    assert_eq!(client.read_reply().unwrap(), b"Server closing\n");
+    assert!(client.is_closed_by_server());
+    assert!(server.log().contains("Server shutdown complete"));
+}
+
+#[test]
+fn graceful_shutdown_lets_requests_in_progress_finish() {
+    let server = uring_server(|builder| builder);
+    let mut client = server.client().unwrap();
+    assert_eq!(client.request("half a ").unwrap(), "Echo: half a ");
+
+    server.handle().shutdown_graceful();
+    std::thread::sleep(Duration::from_millis(300));
+
+    client.stream().write_all(b"request\n").unwrap();
+    assert_eq!(read_bytes(&mut client, 29), b"Echo: request\nServer closing\n");
+    assert!(client.is_closed_by_server());
+    server.handle().wait();
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn encrypted_connections_are_refused() {
+    use rustbucket::noise::{NoiseKey, NoiseSettings};
+    use rustbucket::RustbucketError;
+
+    let key = NoiseKey::generate();
+    let settings = NoiseSettings::new(key).authorize("alice", NoiseKey::generate().public_key());
+    let error = TestServer::start_with(|builder| builder.io_uring(true).noise(settings)).err().unwrap();
+    assert!(matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("TLS or Noise")), "{}", error);
+}
+
+#[test]
+fn listeners_per_worker_are_refused() {
+    use rustbucket::RustbucketError;
+
+    let error = TestServer::start_with(|builder| builder.io_uring(true).reuse_port(true)).err().unwrap();
+    assert!(matches!(&error, RustbucketError::InvalidConfig(reason) if reason.contains("--reuse-port")), "{}", error);
+}